@@ -8,6 +8,18 @@ use std::fmt::Display;
 
 pub const EARTH_CIRCUMFERENCE: f64 = 40_075_016.7;
 pub const EARTH_RADIUS: f64 = EARTH_CIRCUMFERENCE / 2.0 / PI;
+/// Web Mercator cannot represent latitudes beyond this value
+pub const MAX_LATITUDE: f64 = 85.051_128_779_806_59;
+
+/// Longitude and latitude of Web Mercator coordinates, which are clamped to the tiled area
+#[must_use]
+pub fn webmercator_to_wgs84(x: f64, y: f64) -> (f64, f64) {
+    let max = EARTH_CIRCUMFERENCE / 2.0;
+    let (x, y) = (x.clamp(-max, max), y.clamp(-max, max));
+    let lng = (x / EARTH_RADIUS).to_degrees();
+    let lat = (y / EARTH_RADIUS).sinh().atan().to_degrees();
+    (lng, lat)
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Format {
@@ -143,6 +155,7 @@ impl TileInfo {
             // Compressed prefixes assume MVT content
             v if v.starts_with(b"\x1f\x8b") => Self::new(Mvt, Gzip),
             v if v.starts_with(b"\x78\x9c") => Self::new(Mvt, Zlib),
+            v if v.starts_with(b"\x28\xb5\x2f\xfd") => Self::new(Mvt, Zstd),
             v if v.starts_with(b"\x89\x50\x4E\x47\x0D\x0A\x1A\x0A") => Self::new(Png, Internal),
             v if v.starts_with(b"\x47\x49\x46\x38\x39\x61") => Self::new(Gif, Internal),
             v if v.starts_with(b"\xFF\xD8\xFF") => Self::new(Jpeg, Internal),
@@ -188,8 +201,8 @@ impl Display for TileInfo {
 mod tests {
     use std::fs::read;
 
-    use Encoding::{Internal, Uncompressed};
-    use Format::{Jpeg, Json, Png, Webp};
+    use Encoding::{Internal, Uncompressed, Zstd};
+    use Format::{Jpeg, Json, Mvt, Png, Webp};
 
     use super::*;
 
@@ -218,6 +231,14 @@ mod tests {
         assert_eq!(TileInfo::detect(br"RIFF"), None);
     }
 
+    #[test]
+    fn test_data_format_zstd() {
+        assert_eq!(
+            TileInfo::detect(b"\x28\xb5\x2f\xfd\x00\x58"),
+            info(Mvt, Zstd)
+        );
+    }
+
     #[test]
     fn test_data_format_json() {
         assert_eq!(
@@ -225,4 +246,25 @@ mod tests {
             info(Json, Uncompressed)
         );
     }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Format::parse("JPG"), Some(Jpeg));
+        assert_eq!(Format::parse("pbf"), Some(Mvt));
+        assert_eq!(Format::parse("tiff"), None);
+        assert_eq!(Encoding::parse("Zstd"), Some(Zstd));
+        assert_eq!(Encoding::parse("none"), Some(Uncompressed));
+        assert_eq!(Encoding::parse("br"), None);
+    }
+
+    #[test]
+    fn test_webmercator_to_wgs84() {
+        let (lng, lat) = webmercator_to_wgs84(0.0, 0.0);
+        assert_eq!((lng, lat), (0.0, 0.0));
+        let (lng, lat) = webmercator_to_wgs84(EARTH_CIRCUMFERENCE / 2.0, EARTH_CIRCUMFERENCE / 2.0);
+        assert!((lng - 180.0).abs() < 1e-9);
+        assert!((lat - MAX_LATITUDE).abs() < 1e-9);
+        // Clamped to the tiled area
+        assert_eq!(webmercator_to_wgs84(-1e9, -1e9), (-lng, -lat));
+    }
 }
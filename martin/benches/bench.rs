@@ -48,7 +48,7 @@ impl Source for NullSource {
         _xyz: &TileCoord,
         _query: &Option<UrlQuery>,
     ) -> MartinResult<TileData> {
-        Ok(Vec::new())
+        Ok(TileData::new())
     }
 
     fn get_catalog_entry(&self) -> CatalogSourceEntry {
@@ -0,0 +1,40 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+#[cfg(feature = "libdeflate")]
+use martin::LibdeflateGzip;
+use martin::{Flate2Gzip, GzipBackend};
+
+/// Vector tiles of different sizes, made by repeating a test tile.
+/// Concatenated vector tiles are still valid vector tiles.
+fn sample_tiles() -> Vec<(usize, Vec<u8>)> {
+    let tile = include_bytes!("../../tests/expected/auto/cmp_0_0_0.pbf");
+    [1, 10, 100]
+        .into_iter()
+        .map(|count| {
+            let data = tile.repeat(count);
+            (data.len(), data)
+        })
+        .collect()
+}
+
+fn bench_backend<T: GzipBackend>(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("gzip {}", T::NAME));
+    for (size, data) in sample_tiles() {
+        let compressed = T::encode(&data).unwrap();
+        group.bench_with_input(BenchmarkId::new("encode", size), &data, |b, data| {
+            b.iter(|| T::encode(data).unwrap());
+        });
+        group.bench_with_input(BenchmarkId::new("decode", size), &compressed, |b, data| {
+            b.iter(|| T::decode(data).unwrap());
+        });
+    }
+    group.finish();
+}
+
+fn bench_gzip(c: &mut Criterion) {
+    bench_backend::<Flate2Gzip>(c);
+    #[cfg(feature = "libdeflate")]
+    bench_backend::<LibdeflateGzip>(c);
+}
+
+criterion_group!(benches, bench_gzip);
+criterion_main!(benches);
@@ -8,7 +8,11 @@ mod pg;
 pub use pg::{BoundsCalcType, PgArgs, DEFAULT_BOUNDS_TIMEOUT};
 
 mod root;
-pub use root::{Args, ExtraArgs, MetaArgs};
+pub(crate) use root::parse_file_args;
+pub use root::{
+    AdviseArgs, Args, BenchArgs, Command, ExtraArgs, FixtureArgs, InstallFunctionsArgs, MetaArgs,
+    MigrateConfigArgs, MigrateFrom, PgCommand, ReplayArgs, TestSourcesArgs,
+};
 
 mod srv;
 pub use srv::SrvArgs;
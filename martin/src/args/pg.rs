@@ -7,7 +7,7 @@ use serde::{Deserialize, Serialize};
 use crate::args::connections::Arguments;
 use crate::args::connections::State::{Ignore, Take};
 use crate::args::environment::Env;
-use crate::pg::{PgConfig, PgSslCerts, POOL_SIZE_DEFAULT};
+use crate::pg::{is_srv_conn_str, PgConfig, PgSslCerts, POOL_SIZE_DEFAULT};
 use crate::utils::{OptBoolObj, OptOneMany};
 
 // Must match the help string for BoundsType::Quick
@@ -25,7 +25,7 @@ pub enum BoundsCalcType {
     Skip,
 }
 
-#[derive(clap::Args, Debug, PartialEq, Default)]
+#[derive(clap::Args, Debug, Clone, PartialEq, Default)]
 #[command(about, version)]
 pub struct PgArgs {
     /// Specify how bounds should be computed for the spatial PG tables. [DEFAULT: quick]
@@ -58,14 +58,21 @@ impl PgArgs {
             .into_iter()
             .map(|s| PgConfig {
                 connection_string: Some(s),
+                id: None,
+                id_prefix: None,
                 ssl_certificates: certs.clone(),
                 default_srid,
                 auto_bounds: self.auto_bounds,
                 max_feature_count: self.max_feature_count,
                 pool_size: self.pool_size,
+                pool_autosize: None,
+                discovery_concurrency: None,
+                dns_refresh: None,
+                feature_count_header: None,
                 auto_publish: OptBoolObj::NoValue,
                 tables: None,
                 functions: None,
+                consistent_snapshot: false,
             })
             .collect();
 
@@ -194,7 +201,7 @@ impl PgArgs {
 
 #[must_use]
 fn is_postgresql_string(s: &str) -> bool {
-    s.starts_with("postgresql://") || s.starts_with("postgres://")
+    s.starts_with("postgresql://") || s.starts_with("postgres://") || is_srv_conn_str(s)
 }
 
 #[cfg(test)]
@@ -1,7 +1,10 @@
+use std::fmt::{Display, Formatter};
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use log::warn;
+use tilejson::Bounds;
+use url::Url;
 
 use crate::args::connections::Arguments;
 use crate::args::environment::Env;
@@ -13,9 +16,11 @@ use crate::file_config::FileConfigEnum;
 use crate::MartinError::ConfigAndConnectionsError;
 use crate::{MartinResult, OptOneMany};
 
-#[derive(Parser, Debug, PartialEq, Default)]
+#[derive(Parser, Debug, Clone, PartialEq, Default)]
 #[command(about, version)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     #[command(flatten)]
     pub meta: MetaArgs,
     #[command(flatten)]
@@ -33,11 +38,11 @@ pub struct MetaArgs {
     // config may need a   conflicts_with = "SourcesArgs"
     // see https://github.com/clap-rs/clap/discussions/4562
     /// Path to config file. If set, no tile source-related parameters are allowed.
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub config: Option<PathBuf>,
     /// Save resulting config to a file or use "-" to print to stdout.
     /// By default, only print if sources are auto-detected.
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub save_config: Option<PathBuf>,
     /// **Deprecated** Scan for new sources on sources list requests
     #[arg(short, long, hide = true)]
@@ -57,6 +62,171 @@ pub struct ExtraArgs {
     pub font: Vec<PathBuf>,
 }
 
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Initialize all sources, fetch one sample tile from each tile source, and print a pass/fail report.
+    /// Exits with an error if any of the sources fail.
+    #[command(name = "test-sources")]
+    TestSources(TestSourcesArgs),
+    /// Generate tiles from a source without saving them,
+    /// and report latency percentiles and throughput for each zoom level.
+    Bench(BenchArgs),
+    /// Re-issue tile requests recorded with `--record-requests` against the configured sources or a running instance,
+    /// and report latency percentiles and throughput for each zoom level.
+    Replay(ReplayArgs),
+    /// Generate an equivalent Martin config from the config of another tile server,
+    /// and print it or save it with `--save-config`.
+    #[command(name = "migrate-config")]
+    MigrateConfig(MigrateConfigArgs),
+    /// Generate a small synthetic tileset with points, lines, and polygons as MBTiles and PMTiles files,
+    /// and as SQL creating the same features in PostgreSQL, e.g. to test a deployment.
+    Fixture(FixtureArgs),
+    /// PostgreSQL tools
+    #[command(subcommand)]
+    Pg(PgCommand),
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum PgCommand {
+    /// Create PL/pgSQL functions generating the tiles of the selected table sources.
+    /// The functions can be published as function sources, and edited to tune the queries.
+    #[command(name = "install-functions")]
+    InstallFunctions(InstallFunctionsArgs),
+    /// Inspect the tables of the table sources, and report missing spatial indexes, untyped geometry columns,
+    /// geometries with unexpected SRIDs, and tables that would benefit from clustering, with the SQL to fix them.
+    /// A few tile queries of each table are run with `EXPLAIN ANALYZE`.
+    Advise(AdviseArgs),
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq, Default)]
+pub struct TestSourcesArgs {
+    /// Connection strings, e.g. postgres://... or /path/to/files
+    pub connection: Vec<String>,
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+pub struct BenchArgs {
+    /// Name of the source to benchmark. Use comma-separated names to benchmark a composite source.
+    #[arg(short, long)]
+    pub source: String,
+    /// List of zoom levels to benchmark
+    #[arg(short, long, alias = "zooms", value_delimiter = ',', required = true)]
+    pub zoom: Vec<u8>,
+    /// Bounds to generate tiles for. Can be specified multiple times. Defaults to the whole world.
+    #[arg(long, allow_hyphen_values = true)]
+    pub bbox: Vec<Bounds>,
+    /// Number of tiles to generate concurrently.
+    #[arg(long, default_value_t = 1)]
+    pub concurrency: usize,
+    /// Maximum number of tiles to generate for each zoom level.
+    #[arg(long)]
+    pub max_tiles: Option<u64>,
+    /// Optional query parameter (in URL query format) for the sources that support it (e.g. Postgres functions)
+    #[arg(long)]
+    pub url_query: Option<String>,
+    /// Connection strings, e.g. postgres://... or /path/to/files
+    pub connection: Vec<String>,
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+pub struct ReplayArgs {
+    /// Path to the file created with `--record-requests`, or to the directory created with `--record-samples`
+    #[arg(long, value_name = "FILE")]
+    pub recording: PathBuf,
+    /// Compare the responses with the ones recorded by `--record-samples`, and fail if any of them is different
+    #[arg(long)]
+    pub compare: bool,
+    /// Replay speed relative to the original pace, e.g. 2 to issue requests twice as fast.
+    /// Use 0 to issue requests as fast as possible.
+    #[arg(long, default_value_t = 1.0)]
+    pub speed: f64,
+    /// Maximum number of requests being processed at the same time.
+    #[arg(long, default_value_t = 100)]
+    pub concurrency: usize,
+    /// Send the requests to a running Martin instance at this URL, e.g. `http://localhost:3000`,
+    /// instead of initializing the configured sources
+    #[arg(long, value_name = "URL", conflicts_with = "connection")]
+    pub url: Option<Url>,
+    /// Connection strings, e.g. postgres://... or /path/to/files
+    pub connection: Vec<String>,
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+pub struct MigrateConfigArgs {
+    /// Tile server the config file belongs to
+    #[arg(long)]
+    pub from: MigrateFrom,
+    /// Config file of the other tile server, e.g. `config.json` of tileserver-gl
+    pub file: PathBuf,
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+pub struct FixtureArgs {
+    /// Directory to write `fixture.mbtiles`, `fixture.pmtiles`, and `fixture.sql` to, replacing existing files
+    pub output: PathBuf,
+    /// Highest zoom level of the generated tiles
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u8).range(0..=6))]
+    pub max_zoom: u8,
+    /// Schema of the PostgreSQL tables. The tables of the fixture are replaced if they exist.
+    #[arg(long, default_value = "martin_fixture")]
+    pub schema: String,
+    /// Also run `fixture.sql` in this database, e.g. `postgres://postgres@localhost/db`
+    #[arg(long, value_name = "CONNECTION")]
+    pub postgres: Option<String>,
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+pub struct InstallFunctionsArgs {
+    /// IDs of the table sources to create tile functions for
+    #[arg(short, long, value_delimiter = ',', required = true)]
+    pub source: Vec<String>,
+    /// Schema to create the functions in. Defaults to the schema of each table.
+    #[arg(long)]
+    pub schema: Option<String>,
+    /// Name of the functions, using the same variables and filters as `source_id_format`: {schema}, {table}, and {column}
+    #[arg(long, default_value = "{table}_tiles")]
+    pub name_format: String,
+    /// Also create a spatial index on the geometry column of the tables that have none
+    #[arg(long)]
+    pub create_indexes: bool,
+    /// Replace the existing functions with the same names
+    #[arg(long)]
+    pub replace: bool,
+    /// Print the SQL instead of running it
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Connection strings, e.g. postgres://...
+    pub connection: Vec<String>,
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+pub struct AdviseArgs {
+    /// IDs of the table sources to inspect. Defaults to all table sources.
+    #[arg(short, long, value_delimiter = ',')]
+    pub source: Vec<String>,
+    /// Connection strings, e.g. postgres://...
+    pub connection: Vec<String>,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrateFrom {
+    /// JSON config of tileserver-gl
+    #[value(name = "tileserver-gl")]
+    TileserverGl,
+    /// TOML config of t-rex
+    #[value(name = "t-rex")]
+    TRex,
+}
+
+impl Display for MigrateFrom {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TileserverGl => write!(f, "tileserver-gl"),
+            Self::TRex => write!(f, "t-rex"),
+        }
+    }
+}
+
 impl Args {
     pub fn merge_into_config<'a>(
         self,
@@ -69,13 +239,24 @@ impl Args {
         if env.has_unused_var("WATCH_MODE") {
             warn!("The WATCH_MODE env variable is no longer supported, and will be ignored");
         }
-        if self.meta.config.is_some() && !self.meta.connection.is_empty() {
-            return Err(ConfigAndConnectionsError(self.meta.connection));
+        let mut connection = self.meta.connection;
+        match self.command {
+            Some(Command::TestSources(cmd)) => connection.extend(cmd.connection),
+            Some(Command::Bench(cmd)) => connection.extend(cmd.connection),
+            Some(Command::Replay(cmd)) => connection.extend(cmd.connection),
+            Some(Command::Pg(PgCommand::InstallFunctions(cmd))) => {
+                connection.extend(cmd.connection);
+            }
+            Some(Command::Pg(PgCommand::Advise(cmd))) => connection.extend(cmd.connection),
+            Some(Command::MigrateConfig(_) | Command::Fixture(_)) | None => {}
+        }
+        if self.meta.config.is_some() && !connection.is_empty() {
+            return Err(ConfigAndConnectionsError(connection));
         }
 
         self.srv.merge_into_config(&mut config.srv);
 
-        let mut cli_strings = Arguments::new(self.meta.connection);
+        let mut cli_strings = Arguments::new(connection);
         let pg_args = self.pg.unwrap_or_default();
         if config.postgres.is_none() {
             config.postgres = pg_args.into_config(&mut cli_strings, env);
@@ -176,6 +357,59 @@ mod tests {
         assert_eq!(args, (cfg, meta));
     }
 
+    #[test]
+    fn cli_test_sources() {
+        let args = Args::parse_from(["martin", "test-sources", "--config", "c.yaml"]);
+        assert_eq!(
+            args.command,
+            Some(Command::TestSources(TestSourcesArgs::default()))
+        );
+        assert_eq!(args.meta.config, Some(PathBuf::from("c.yaml")));
+
+        let args = parse(&["martin", "test-sources", "postgres://connection"]).unwrap();
+        let cfg = Config {
+            postgres: OptOneMany::One(PgConfig {
+                connection_string: some("postgres://connection"),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(args, (cfg, MetaArgs::default()));
+    }
+
+    #[test]
+    fn cli_bench() {
+        let args = Args::try_parse_from([
+            "martin",
+            "bench",
+            "--source",
+            "points",
+            "-z",
+            "10,12",
+            "--bbox",
+            "-10,-20,30,40",
+            "--concurrency",
+            "8",
+            "/path/to/file.mbtiles",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.command,
+            Some(Command::Bench(BenchArgs {
+                source: "points".to_string(),
+                zoom: vec![10, 12],
+                bbox: vec![Bounds::new(-10.0, -20.0, 30.0, 40.0)],
+                concurrency: 8,
+                max_tiles: None,
+                url_query: None,
+                connection: vec!["/path/to/file.mbtiles".to_string()],
+            }))
+        );
+
+        let res = Args::try_parse_from(["martin", "bench", "--source", "points"]);
+        assert!(res.is_err(), "zoom levels are required");
+    }
+
     #[test]
     fn cli_bad_arguments() {
         for params in [
@@ -1,6 +1,10 @@
-use crate::srv::{SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT};
+use std::path::PathBuf;
 
-#[derive(clap::Args, Debug, PartialEq, Default)]
+use crate::srv::{
+    SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT, SAMPLES_PER_SOURCE_DEFAULT,
+};
+
+#[derive(clap::Args, Debug, Clone, PartialEq, Default)]
 #[command(about, version)]
 pub struct SrvArgs {
     #[arg(help = format!("Connection keep alive timeout. [DEFAULT: {}]", KEEP_ALIVE_DEFAULT), short, long)]
@@ -10,6 +14,15 @@ pub struct SrvArgs {
     /// Number of web server workers
     #[arg(short = 'W', long)]
     pub workers: Option<usize>,
+    /// Record all tile requests to a file, replacing its content, so they can be re-issued later with `martin replay`
+    #[arg(long, value_name = "FILE")]
+    pub record_requests: Option<PathBuf>,
+    /// Record the first tile requests of each source with their responses to this directory, one file per source,
+    /// so they can be compared with the responses of another version with `martin replay --compare`
+    #[arg(long, value_name = "DIR")]
+    pub record_samples: Option<PathBuf>,
+    #[arg(help = format!("Number of tile requests recorded for each source by --record-samples. [DEFAULT: {}]", SAMPLES_PER_SOURCE_DEFAULT), long, value_name = "COUNT")]
+    pub samples_per_source: Option<usize>,
 }
 
 impl SrvArgs {
@@ -24,5 +37,14 @@ impl SrvArgs {
         if self.workers.is_some() {
             srv_config.worker_processes = self.workers;
         }
+        if self.record_requests.is_some() {
+            srv_config.record_requests = self.record_requests;
+        }
+        if self.record_samples.is_some() {
+            srv_config.record_samples = self.record_samples;
+        }
+        if self.samples_per_source.is_some() {
+            srv_config.samples_per_source = self.samples_per_source;
+        }
     }
 }
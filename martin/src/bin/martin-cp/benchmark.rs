@@ -0,0 +1,402 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use futures::stream::{self, StreamExt as _};
+use futures::TryStreamExt as _;
+use log::{debug, info, warn};
+use martin::commands::percentile;
+use martin::srv::get_tile_content;
+use martin::{with_headers, MvtFilter, Source, Tile, TileCoord, TileRect};
+use martin_tile_utils::TileInfo;
+use size_format::SizeFormatterBinary;
+use tokio::time::Instant;
+
+use crate::filter::{transform_tile, Recompression};
+use crate::throttle::Throttle;
+use crate::{sources_for_zoom, CopyArgs, MartinCpResult};
+
+/// Number of tiles sampled at each zoom level by `--dry-run` and `--benchmark`, see `--sample-tiles`
+const SAMPLE_TILES_DEFAULT: u64 = 10;
+
+/// Tile count and size of the sampled tiles of a zoom level, see `--dry-run`
+#[derive(Debug, PartialEq)]
+struct ZoomEstimate {
+    zoom: u8,
+    count: u64,
+    sampled: u64,
+    sampled_bytes: u64,
+}
+
+impl ZoomEstimate {
+    fn average(&self) -> u64 {
+        self.sampled_bytes.checked_div(self.sampled).unwrap_or(0)
+    }
+
+    fn estimated_bytes(&self) -> u64 {
+        let total = u128::from(self.sampled_bytes) * u128::from(self.count);
+        (total.checked_div(u128::from(self.sampled)).unwrap_or(0))
+            .try_into()
+            .unwrap_or(u64::MAX)
+    }
+}
+
+/// Tiles that would be copied, and the output size estimated from a sample of them
+pub struct DryRun {
+    tiles: Vec<TileRect>,
+    zooms: Vec<ZoomEstimate>,
+}
+
+impl DryRun {
+    pub async fn estimate(
+        args: &CopyArgs,
+        tiles: Vec<TileRect>,
+        sources: &[&dyn Source],
+        info: TileInfo,
+        (filter, recompress): (Option<&MvtFilter>, Option<Recompression>),
+    ) -> MartinCpResult<Self> {
+        let per_zoom = args.sample_tiles.unwrap_or(SAMPLE_TILES_DEFAULT);
+        let concurrency = args.concurrency.clone().unwrap_or_default();
+        let query = args.url_query.as_deref();
+        let (accept_encoding, headers) = args.request_headers()?;
+        let encodings = Some(&accept_encoding);
+        let headers = &headers;
+        let throttle = &Throttle::new(args);
+
+        let mut zooms: BTreeMap<u8, Vec<TileRect>> = BTreeMap::new();
+        for rect in &tiles {
+            zooms.entry(rect.zoom).or_default().push(*rect);
+        }
+        let mut estimates = Vec::with_capacity(zooms.len());
+        for (zoom, rects) in zooms {
+            let count: u64 = rects.iter().map(TileRect::size).sum();
+            let sampled = per_zoom.min(count);
+            let sampled_bytes = stream::iter(sample_tiles(&rects, sampled))
+                .map(|xyz| async move {
+                    let sources = sources_for_zoom(sources, xyz.z);
+                    if sources.is_empty() {
+                        return Ok(0);
+                    }
+                    let permit = throttle.acquire(sources.len()).await;
+                    let tile = get_tile_content(&sources, info, &xyz, query, encodings);
+                    let tile = with_headers(headers.clone(), tile).await?;
+                    drop(permit);
+                    let size = transform_tile(tile, filter, recompress)?.len();
+                    MartinCpResult::Ok(size as u64)
+                })
+                .buffer_unordered(concurrency.for_zoom(zoom))
+                .try_fold(0, |total, size| async move { Ok(total + size) })
+                .await?;
+            estimates.push(ZoomEstimate {
+                zoom,
+                count,
+                sampled,
+                sampled_bytes,
+            });
+        }
+        Ok(Self {
+            tiles,
+            zooms: estimates,
+        })
+    }
+}
+
+impl Display for DryRun {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Tile ranges:")?;
+        for rect in &self.tiles {
+            writeln!(f, "    {rect}")?;
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            " {:^4} | {:^12} | {:^7} | {:^9} | {:^9}",
+            "Zoom", "Count", "Sampled", "Average", "Estimated"
+        )?;
+        for z in &self.zooms {
+            let avg = SizeFormatterBinary::new(z.average());
+            let estimated = SizeFormatterBinary::new(z.estimated_bytes());
+            writeln!(
+                f,
+                " {:>4} | {:>12} | {:>7} | {:>9} | {:>9}",
+                z.zoom,
+                z.count,
+                z.sampled,
+                format!("{avg:.1}B"),
+                format!("{estimated:.1}B"),
+            )?;
+        }
+        let count: u64 = self.zooms.iter().map(|v| v.count).sum();
+        let estimated = self
+            .zooms
+            .iter()
+            .fold(0_u64, |total, v| total.saturating_add(v.estimated_bytes()));
+        let estimated = SizeFormatterBinary::new(estimated);
+        writeln!(
+            f,
+            " {:>4} | {count:>12} | {:>7} | {:>9} | {:>9}",
+            "all",
+            "",
+            "",
+            format!("{estimated:.1}B"),
+        )
+    }
+}
+
+/// Generation latency and size of the sampled tiles of a zoom level, see `--benchmark`
+#[derive(Debug, PartialEq)]
+struct ZoomBenchmark {
+    zoom: u8,
+    /// Generation time of each sampled tile, sorted
+    durations: Vec<Duration>,
+    /// Size of each generated tile, as it would be stored
+    sizes: Vec<u64>,
+    errors: usize,
+}
+
+impl ZoomBenchmark {
+    fn new(zoom: u8, samples: Vec<(Duration, Option<u64>)>) -> Self {
+        let (mut durations, sizes): (Vec<_>, Vec<_>) = samples.into_iter().unzip();
+        durations.sort_unstable();
+        let sizes: Vec<_> = sizes.into_iter().flatten().collect();
+        Self {
+            zoom,
+            errors: durations.len() - sizes.len(),
+            durations,
+            sizes,
+        }
+    }
+
+    fn average(&self) -> u64 {
+        let total: u64 = self.sizes.iter().sum();
+        total.checked_div(self.sizes.len() as u64).unwrap_or(0)
+    }
+}
+
+/// Latency of the sampled tiles of each zoom level, without writing them
+pub struct Benchmark(Vec<ZoomBenchmark>);
+
+impl Benchmark {
+    pub async fn run(
+        args: &CopyArgs,
+        tiles: &[TileRect],
+        sources: &[&dyn Source],
+        info: TileInfo,
+        (filter, recompress): (Option<&MvtFilter>, Option<Recompression>),
+    ) -> MartinCpResult<Self> {
+        let per_zoom = args.sample_tiles.unwrap_or(SAMPLE_TILES_DEFAULT);
+        let concurrency = args.concurrency.clone().unwrap_or_default();
+        let query = args.url_query.as_deref();
+        let (accept_encoding, headers) = args.request_headers()?;
+        let encodings = Some(&accept_encoding);
+        let headers = &headers;
+        let throttle = &Throttle::new(args);
+
+        let mut zooms: BTreeMap<u8, Vec<TileRect>> = BTreeMap::new();
+        for rect in tiles {
+            zooms.entry(rect.zoom).or_default().push(*rect);
+        }
+        let mut results = Vec::with_capacity(zooms.len());
+        for (zoom, rects) in zooms {
+            let count: u64 = rects.iter().map(TileRect::size).sum();
+            let sampled = per_zoom.min(count);
+            info!("Generating {sampled} of the {count} tiles of zoom {zoom}");
+            let measured = stream::iter(sample_tiles(&rects, sampled))
+                .map(|xyz| async move {
+                    let sources = sources_for_zoom(sources, xyz.z);
+                    let permit = throttle.acquire(sources.len()).await;
+                    // The time waiting for the rate limit is not part of the latency
+                    let started = Instant::now();
+                    let tile = if sources.is_empty() {
+                        Ok(Tile::new(Vec::new(), info))
+                    } else {
+                        let tile = get_tile_content(&sources, info, &xyz, query, encodings);
+                        with_headers(headers.clone(), tile).await
+                    };
+                    let duration = started.elapsed();
+                    drop(permit);
+                    let size = match tile {
+                        Ok(tile) => transform_tile(tile, filter, recompress)
+                            .map(|data| data.len() as u64)
+                            .map_err(|e| e.to_string()),
+                        Err(e) => Err(e.to_string()),
+                    };
+                    match size {
+                        Ok(size) => (duration, Some(size)),
+                        Err(e) => {
+                            debug!("Unable to generate tile {xyz:#}: {e}");
+                            (duration, None)
+                        }
+                    }
+                })
+                .buffer_unordered(concurrency.for_zoom(zoom))
+                .collect::<Vec<_>>()
+                .await;
+            let result = ZoomBenchmark::new(zoom, measured);
+            if result.errors > 0 {
+                warn!(
+                    "Failed to generate {} tiles at zoom {zoom}, use RUST_LOG=debug to see the errors",
+                    result.errors
+                );
+            }
+            results.push(result);
+        }
+        Ok(Self(results))
+    }
+}
+
+impl Display for Benchmark {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            " {:^4} | {:^7} | {:^6} | {:^9} | {:^8} | {:^8} | {:^8} | {:^8}",
+            "Zoom", "Sampled", "Errors", "Average", "p50", "p95", "p99", "max"
+        )?;
+        for z in &self.0 {
+            let avg = SizeFormatterBinary::new(z.average());
+            let duration = |pct| format!("{:.1?}", percentile(&z.durations, pct));
+            writeln!(
+                f,
+                " {:>4} | {:>7} | {:>6} | {:>9} | {:>8} | {:>8} | {:>8} | {:>8}",
+                z.zoom,
+                z.durations.len(),
+                z.errors,
+                format!("{avg:.1}B"),
+                duration(50),
+                duration(95),
+                duration(99),
+                duration(100),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Tiles spread evenly over the tiles of the rectangles, which must be of the same zoom level
+fn sample_tiles(rects: &[TileRect], sampled: u64) -> impl Iterator<Item = TileCoord> + '_ {
+    let count: u64 = rects.iter().map(TileRect::size).sum();
+    (0..sampled).map(move |i| {
+        let index = u64::try_from(u128::from(i) * u128::from(count) / u128::from(sampled))
+            .unwrap_or(u64::MAX);
+        tile_at(rects, index)
+    })
+}
+
+/// Tile at the position in the order of [`iterate_tiles`], without iterating over the tiles before it
+fn tile_at(rects: &[TileRect], mut index: u64) -> TileCoord {
+    for rect in rects {
+        if index < rect.size() {
+            let height = u64::from(rect.max_y - rect.min_y + 1);
+            // Both offsets are within the rectangle, so they fit into u32
+            #[allow(clippy::cast_possible_truncation)]
+            return TileCoord {
+                z: rect.zoom,
+                x: rect.min_x + (index / height) as u32,
+                y: rect.min_y + (index % height) as u32,
+            };
+        }
+        index -= rect.size();
+    }
+    panic!("tile index is out of range");
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser as _;
+    use martin::{compute_tile_ranges, iterate_tiles};
+    use tilejson::Bounds;
+
+    use super::*;
+    use crate::CopierArgs;
+
+    #[test]
+    fn test_dry_run_estimate() {
+        let tiles = compute_tile_ranges(&[Bounds::MAX_TILED], &[0, 2]);
+        let all: Vec<_> = iterate_tiles(tiles.clone()).collect();
+        for (index, xyz) in all.iter().enumerate() {
+            assert_eq!(tile_at(&tiles, index as u64), *xyz);
+        }
+
+        let zoom = |count, sampled, sampled_bytes| ZoomEstimate {
+            zoom: 2,
+            count,
+            sampled,
+            sampled_bytes,
+        };
+        assert_eq!(zoom(16, 4, 1000).estimated_bytes(), 4000);
+        assert_eq!(zoom(16, 4, 1000).average(), 250);
+        assert_eq!(zoom(0, 0, 0).estimated_bytes(), 0);
+        assert_eq!(zoom(1 << 30, 10, 10_000).estimated_bytes(), 1000 << 30);
+    }
+
+    #[test]
+    fn test_benchmark() {
+        let ms = Duration::from_millis;
+        let measurements = vec![
+            (ms(30), Some(300)),
+            (ms(10), Some(100)),
+            (ms(50), None),
+            (ms(20), Some(0)),
+        ];
+        let zoom = ZoomBenchmark::new(3, measurements);
+        assert_eq!(zoom.durations, vec![ms(10), ms(20), ms(30), ms(50)]);
+        assert_eq!(zoom.errors, 1);
+        assert_eq!(zoom.average(), 133);
+        assert_eq!(ZoomBenchmark::new(3, vec![(ms(1), None)]).average(), 0);
+
+        let tiles = compute_tile_ranges(&[Bounds::MAX_TILED], &[2]);
+        let sampled: Vec<_> = sample_tiles(&tiles, 4).collect();
+        assert_eq!(sampled.len(), 4);
+        assert_eq!(sampled[0], TileCoord { z: 2, x: 0, y: 0 });
+        assert_eq!(sampled[1], TileCoord { z: 2, x: 1, y: 0 });
+
+        let parse = |args: &[&str]| {
+            let cmd = ["martin-cp", "-s", "a", "-o", "out.mbtiles", "-z", "2"];
+            CopierArgs::try_parse_from(cmd.iter().chain(args)).map(|v| v.copy)
+        };
+        let copy = parse(&["--benchmark", "--sample-tiles", "50"]).unwrap();
+        assert!(copy.benchmark);
+        assert_eq!(copy.sample_tiles, Some(50));
+        assert!(parse(&["--benchmark", "--dry-run"]).is_err());
+        assert!(parse(&["--benchmark", "--verify"]).is_err());
+        assert!(parse(&["--sample-tiles", "50"]).is_err());
+    }
+
+    #[test]
+    fn test_report_tables() {
+        let dry_run = DryRun {
+            tiles: compute_tile_ranges(&[Bounds::MAX_TILED], &[1]),
+            zooms: vec![ZoomEstimate {
+                zoom: 1,
+                count: 4,
+                sampled: 2,
+                sampled_bytes: 2048,
+            }],
+        };
+        let text = dry_run.to_string();
+        assert!(
+            text.starts_with("Tile ranges:\n    1: (0,0) - (1,1)\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("    1 |            4 |       2 |    1.0KiB |    4.0KiB\n"),
+            "{text}"
+        );
+        assert!(
+            text.ends_with("  all |            4 |         |           |    4.0KiB\n"),
+            "{text}"
+        );
+
+        let ms = Duration::from_millis;
+        let zoom = ZoomBenchmark::new(1, vec![(ms(20), Some(100)), (ms(10), None)]);
+        let text = Benchmark(vec![zoom]).to_string();
+        let rows: Vec<_> = text.lines().collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].starts_with(" Zoom | Sampled | Errors |"), "{text}");
+        assert!(
+            rows[1].starts_with("    1 |       2 |      1 |      100B |"),
+            "{text}"
+        );
+        assert!(rows[1].ends_with("|   20.0ms"), "{text}");
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash as _, Hasher as _};
+
+use log::{info, warn};
+use martin::{MartinError, TileRect};
+use mbtiles::sqlx::SqliteConnection;
+use mbtiles::Mbtiles;
+
+use crate::order::TileOrder;
+use crate::{CopyArgs, MartinCpResult};
+
+/// Metadata key storing the progress of a copy stopped by `--max-duration` or a signal
+pub const CHECKPOINT_KEY: &str = "martin-cp.checkpoint";
+
+/// Progress of a copy stopped by `--max-duration` or a signal, stored in the output file metadata
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Checkpoint {
+    /// Hash of the source, URL query, and tile ranges, so that a different copy does not use this checkpoint
+    pub job: String,
+    /// Number of tiles generated so far, in the order of [`TileOrder::iterate`]
+    pub done: u64,
+}
+
+impl Checkpoint {
+    pub fn job_id(args: &CopyArgs, tiles: &[TileRect]) -> String {
+        let mut hasher = DefaultHasher::new();
+        args.source.join(",").hash(&mut hasher);
+        args.url_query.hash(&mut hasher);
+        args.headers.hash(&mut hasher);
+        serde_json::to_string(tiles)
+            .unwrap_or_default()
+            .hash(&mut hasher);
+        // The default order is not hashed, so that the checkpoints of earlier versions are still used
+        if args.order != TileOrder::default() {
+            args.order.hash(&mut hasher);
+        }
+        format!("{:016x}", hasher.finish())
+    }
+
+    /// Number of tiles that were already generated by a previous run of the same copy
+    pub async fn load(
+        mbt: &Mbtiles,
+        conn: &mut SqliteConnection,
+        job: &str,
+    ) -> MartinCpResult<u64> {
+        let Some(value) = mbt.get_metadata_value(&mut *conn, CHECKPOINT_KEY).await? else {
+            info!("No progress was recorded in the output file, starting from the beginning");
+            return Ok(0);
+        };
+        match serde_json::from_str::<Self>(&value) {
+            Ok(checkpoint) if checkpoint.job == job => {
+                info!("Resuming the copy after {} tiles", checkpoint.done);
+                Ok(checkpoint.done)
+            }
+            _ => {
+                warn!("The recorded progress is for a different copy, starting from the beginning");
+                Ok(0)
+            }
+        }
+    }
+
+    pub async fn save(self, mbt: &Mbtiles, conn: &mut SqliteConnection) -> MartinCpResult<()> {
+        let value =
+            serde_json::to_string(&self).map_err(|e| MartinError::InternalError(e.into()))?;
+        mbt.set_metadata_value(&mut *conn, CHECKPOINT_KEY, value)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use clap::Parser as _;
+    use mbtiles::{init_mbtiles_schema, MbtType};
+    use tilejson::Bounds;
+
+    use super::*;
+    use crate::{start, CopierArgs, MartinCpError};
+
+    #[test]
+    fn test_job_id() {
+        let tiles = martin::compute_tile_ranges(&[Bounds::MAX_TILED], &[0, 1]);
+        let args = |source: &str, url_query: Option<&str>, order| CopyArgs {
+            source: vec![source.to_string()],
+            url_query: url_query.map(ToString::to_string),
+            order,
+            ..Default::default()
+        };
+        let job = Checkpoint::job_id(&args("a", None, TileOrder::default()), &tiles);
+        assert_eq!(job.len(), 16);
+        assert_eq!(
+            job,
+            Checkpoint::job_id(&args("a", None, TileOrder::default()), &tiles)
+        );
+        for other in [
+            args("b", None, TileOrder::default()),
+            args("a", Some("v=1"), TileOrder::default()),
+            args("a", None, TileOrder::ZoomAsc),
+            args("a", None, TileOrder::CenterOut),
+        ] {
+            assert_ne!(job, Checkpoint::job_id(&other, &tiles), "{other:?}");
+        }
+        let fewer = martin::compute_tile_ranges(&[Bounds::MAX_TILED], &[0]);
+        let same_args = args("a", None, TileOrder::default());
+        assert_ne!(job, Checkpoint::job_id(&same_args, &fewer));
+    }
+
+    #[actix_rt::test]
+    async fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!(
+            "martin-cp-checkpoint-{}.mbtiles",
+            std::process::id()
+        ));
+        let mbt = Mbtiles::new(&path).unwrap();
+        let mut conn = mbt.open_or_new().await.unwrap();
+        init_mbtiles_schema(&mut conn, MbtType::Flat).await.unwrap();
+        assert_eq!(Checkpoint::load(&mbt, &mut conn, "job").await.unwrap(), 0);
+
+        let checkpoint = Checkpoint {
+            job: "job".to_string(),
+            done: 42,
+        };
+        checkpoint.save(&mbt, &mut conn).await.unwrap();
+        let value = mbt.get_metadata_value(&mut conn, CHECKPOINT_KEY).await;
+        assert_eq!(value.unwrap().unwrap(), r#"{"job":"job","done":42}"#);
+        assert_eq!(Checkpoint::load(&mbt, &mut conn, "job").await.unwrap(), 42);
+        assert_eq!(Checkpoint::load(&mbt, &mut conn, "other").await.unwrap(), 0);
+        drop(conn);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_interrupted_copy() {
+        let source = "../tests/fixtures/mbtiles/world_cities.mbtiles";
+        let path = std::env::temp_dir().join(format!(
+            "martin-cp-interrupted-{}.mbtiles",
+            std::process::id()
+        ));
+        let copy = |extra: &[&str]| {
+            let mut args = vec![
+                "martin-cp",
+                "--source",
+                "world_cities",
+                "--output-file",
+                path.to_str().unwrap(),
+                "--mbtiles-type",
+                "flat",
+                "--max-zoom",
+                "6",
+            ];
+            args.extend_from_slice(extra);
+            args.push(source);
+            CopierArgs::try_parse_from(args).unwrap()
+        };
+        let zooms = [0, 1, 2, 3, 4, 5, 6];
+        let tiles = |mbt: Mbtiles| async move {
+            let mut conn = mbt.open_readonly().await.unwrap();
+            let tiles = mbt.get_tile_coords(&mut conn, MbtType::Flat, &zooms).await;
+            let checkpoint = mbt.get_metadata_value(&mut conn, CHECKPOINT_KEY).await;
+            let checkpoint = checkpoint
+                .unwrap()
+                .map(|v| serde_json::from_str(&v).unwrap());
+            (tiles.unwrap(), checkpoint)
+        };
+
+        // Stop the copy like the first SIGINT does, while it is slowed down by the rate limit
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let flag = interrupted.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            flag.store(true, Ordering::Relaxed);
+        });
+        let args = copy(&["--max-tiles-per-second", "100"]);
+        let err = start(args, &mut None, interrupted).await.unwrap_err();
+        assert!(matches!(err, MartinCpError::Interrupted), "{err}");
+
+        let (copied, checkpoint) = tiles(Mbtiles::new(&path).unwrap()).await;
+        let checkpoint: Checkpoint = checkpoint.expect("the progress is recorded");
+        let (all, _) = tiles(Mbtiles::new(source).unwrap()).await;
+        assert!(checkpoint.done > 0, "{checkpoint:?}");
+        assert!(!copied.is_empty(), "the tiles in progress are saved");
+        assert!(copied.len() < all.len(), "the copy is stopped early");
+        assert!(copied.len() as u64 <= checkpoint.done);
+        assert!(copied.is_subset(&all));
+
+        // Resuming generates the remaining tiles, and removes the checkpoint
+        start(copy(&["--resume"]), &mut None, Arc::default())
+            .await
+            .unwrap();
+        let (copied, checkpoint): (HashSet<_>, Option<Checkpoint>) =
+            tiles(Mbtiles::new(&path).unwrap()).await;
+        assert_eq!(copied, all);
+        assert_eq!(checkpoint, None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+/// Number of tiles generated at the same time, which may differ between zoom ranges
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Concurrency {
+    /// Used for the zoom levels without a range
+    pub default: usize,
+    /// Inclusive zoom ranges, and their concurrency
+    pub zooms: Vec<(u8, u8, usize)>,
+}
+
+impl Default for Concurrency {
+    fn default() -> Self {
+        Self {
+            default: 1,
+            zooms: Vec::new(),
+        }
+    }
+}
+
+impl Concurrency {
+    pub fn for_zoom(&self, zoom: u8) -> usize {
+        self.zooms
+            .iter()
+            .find(|(min, max, _)| (*min..=*max).contains(&zoom))
+            .map_or(self.default, |(_, _, v)| *v)
+    }
+
+    /// Highest concurrency of all zoom levels
+    pub fn max(&self) -> usize {
+        self.zooms
+            .iter()
+            .map(|(_, _, v)| *v)
+            .fold(self.default, usize::max)
+    }
+}
+
+/// Parse a concurrency like `8`, or a list like `8@z0-10,2@z11-14,1`, where the number without a zoom range is used for the other zoom levels
+pub fn parse_concurrency(s: &str) -> Result<Concurrency, String> {
+    let err = || format!("Invalid concurrency {s}, expected e.g. 8 or 8@z0-10,2@z11-14");
+    let count = |v: &str| v.parse::<usize>().ok().filter(|v| *v > 0).ok_or_else(err);
+    let mut result = Concurrency::default();
+    let mut default = None;
+    for part in s.split(',').map(str::trim) {
+        let Some((value, zooms)) = part.split_once('@') else {
+            if default.replace(count(part)?).is_some() {
+                return Err(err());
+            }
+            continue;
+        };
+        let zooms = zooms.strip_prefix('z').unwrap_or(zooms);
+        let (min, max) = zooms.split_once('-').unwrap_or((zooms, zooms));
+        let min: u8 = min.parse().map_err(|_| err())?;
+        let max: u8 = max.parse().map_err(|_| err())?;
+        let overlaps = result.zooms.iter().any(|(a, b, _)| min <= *b && *a <= max);
+        if min > max || overlaps {
+            return Err(err());
+        }
+        result.zooms.push((min, max, count(value)?));
+    }
+    result.default = default.unwrap_or(1);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_concurrency() {
+        let concurrency = parse_concurrency("8@z0-10, 2@z11-14,4").unwrap();
+        assert_eq!(concurrency.zooms, vec![(0, 10, 8), (11, 14, 2)]);
+        assert_eq!(
+            (0..17).map(|z| concurrency.for_zoom(z)).collect::<Vec<_>>(),
+            [8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 2, 2, 2, 2, 4, 4]
+        );
+        assert_eq!(concurrency.max(), 8);
+        let concurrency = parse_concurrency("3@12").unwrap();
+        assert_eq!((concurrency.for_zoom(12), concurrency.for_zoom(13)), (3, 1));
+        assert_eq!(parse_concurrency("6").unwrap().max(), 6);
+        for invalid in ["0", "8@z0-10,2@z10-14", "8@z5-2", "2,3", "8@zx", "@z1"] {
+            assert!(parse_concurrency(invalid).is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use std::fs::File;
+use std::io::{BufWriter, Write as _};
+use std::path::Path;
+
+use log::info;
+use martin::{MartinResult, TileCoord};
+use mbtiles::sqlx::SqliteConnection;
+use mbtiles::{calc_tile_hash, MbtType, Mbtiles};
+
+use crate::{MartinCpError, MartinCpResult, TileXyz, BATCH_SIZE_DEFAULT};
+
+/// How a generated tile differs from the compared file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TileChange {
+    /// The tile is not in the compared file
+    Added,
+    /// The tile has a different content
+    Modified,
+    /// The tile is empty, but it is in the compared file
+    Removed,
+}
+
+impl TileChange {
+    /// Problem of the compared file reported by `--verify`
+    fn verify_problem(self) -> &'static str {
+        match self {
+            Self::Added => "missing",
+            Self::Modified => "mismatch",
+            Self::Removed => "unexpected",
+        }
+    }
+}
+
+/// File that the generated tiles are compared with, see `--diff-with` and `--verify`
+pub struct TileDiff {
+    mbt: Mbtiles,
+    mbt_type: MbtType,
+    /// Connection to the compared file, or `None` if it is the output file
+    conn: Option<SqliteConnection>,
+    /// Only compare the tiles with the output file, and do not write anything
+    pub verify: bool,
+    /// Tiles whose content is not the same as in the compared file, including the removed ones
+    changed: Vec<(TileCoord, TileChange)>,
+    /// Tiles that are now empty, and must be deleted from the output file
+    removed: Vec<(u8, u32, u32)>,
+}
+
+impl TileDiff {
+    pub async fn open(path: &Path, output: &Mbtiles, output_type: MbtType) -> MartinCpResult<Self> {
+        let same_file = match (
+            path.canonicalize(),
+            Path::new(output.filepath()).canonicalize(),
+        ) {
+            (Ok(path), Ok(output)) => path == output,
+            _ => false,
+        };
+        let (mbt, mbt_type, conn) = if same_file {
+            (output.clone(), output_type, None)
+        } else {
+            let mbt = Mbtiles::new(path)?;
+            let mut conn = mbt.open_readonly().await?;
+            let mbt_type = mbt.detect_type(&mut conn).await?;
+            (mbt, mbt_type, Some(conn))
+        };
+        info!("Only writing the tiles that differ from {mbt}");
+        Ok(Self {
+            mbt,
+            mbt_type,
+            conn,
+            verify: false,
+            changed: Vec::new(),
+            removed: Vec::new(),
+        })
+    }
+
+    /// Compare the generated tiles with the output file without writing them, see `--verify`
+    pub fn verify(output: &Mbtiles, output_type: MbtType) -> Self {
+        info!("Comparing the generated tiles with {output} without writing them");
+        Self {
+            mbt: output.clone(),
+            mbt_type: output_type,
+            conn: None,
+            verify: true,
+            changed: Vec::new(),
+            removed: Vec::new(),
+        }
+    }
+
+    /// Compare the MD5 hash of the tile with the compared file, and record the tile if it has changed
+    pub async fn is_changed(
+        &mut self,
+        output: &mut SqliteConnection,
+        tile: &TileXyz,
+    ) -> MartinResult<bool> {
+        let conn = self.conn.as_mut().unwrap_or(output);
+        let TileCoord { z, x, y } = tile.xyz;
+        let old_hash = self.mbt.get_tile_hash(conn, self.mbt_type, z, x, y).await?;
+        let change = match old_hash {
+            None => (!tile.data.is_empty()).then_some(TileChange::Added),
+            Some(_) if tile.data.is_empty() => {
+                if self.conn.is_none() && !self.verify {
+                    self.removed.push((z, x, y));
+                }
+                Some(TileChange::Removed)
+            }
+            Some(old_hash) => (calc_tile_hash(conn, &tile.data).await? != old_hash)
+                .then_some(TileChange::Modified),
+        };
+        if let Some(change) = change {
+            self.changed.push((tile.xyz, change));
+        }
+        Ok(change.is_some())
+    }
+
+    /// Report the tiles that failed `--verify`, and fail if there are any
+    pub fn verified(self, report: Option<&Path>) -> MartinCpResult<()> {
+        let count = |change| self.changed.iter().filter(|v| v.1 == change).count();
+        let (missing, mismatched, unexpected) = (
+            count(TileChange::Added),
+            count(TileChange::Modified),
+            count(TileChange::Removed),
+        );
+        if let Some(path) = report {
+            let lines = self
+                .changed
+                .iter()
+                .map(|(xyz, change)| format!("{xyz:#}\t{}", change.verify_problem()));
+            write_tile_list(path, lines)?;
+        }
+        if self.changed.is_empty() {
+            info!("All generated tiles match {}", self.mbt);
+            Ok(())
+        } else {
+            Err(MartinCpError::VerifyFailed(missing, mismatched, unexpected))
+        }
+    }
+
+    /// Delete the removed tiles from the output file, and write the list of changed tiles
+    pub async fn finish(
+        self,
+        mbt: &Mbtiles,
+        conn: &mut SqliteConnection,
+        changed_tiles: Option<&Path>,
+    ) -> MartinCpResult<()> {
+        info!(
+            "{} tiles differ from {}, including {} removed tiles",
+            self.changed.len(),
+            self.mbt,
+            self.removed.len()
+        );
+        for batch in self.removed.chunks(BATCH_SIZE_DEFAULT as usize) {
+            mbt.delete_tiles(&mut *conn, self.mbt_type, batch).await?;
+        }
+        if let Some(path) = changed_tiles {
+            write_tile_list(path, self.changed.iter().map(|(xyz, _)| format!("{xyz:#}")))?;
+        }
+        Ok(())
+    }
+}
+
+/// Write one line per tile, e.g. its coordinates
+pub fn write_tile_list(path: &Path, lines: impl Iterator<Item = String>) -> MartinCpResult<()> {
+    let write = || -> std::io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        for line in lines {
+            writeln!(file, "{line}")?;
+        }
+        file.flush()
+    };
+    write().map_err(|e| MartinCpError::TileListWrite(e, path.to_path_buf()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use clap::Parser as _;
+    use martin_tile_utils::Encoding;
+    use mbtiles::CopyDuplicateMode;
+
+    use super::*;
+    use crate::{start, CopierArgs};
+
+    #[actix_rt::test]
+    async fn test_tile_diff() {
+        let fixture = |v: &str| PathBuf::from(format!("../tests/fixtures/mbtiles/{v}.mbtiles"));
+        let output = Mbtiles::new(fixture("world_cities_modified")).unwrap();
+        let mut conn = output.open_readonly().await.unwrap();
+        let original = fixture("world_cities");
+        let mut diff = TileDiff::open(&original, &output, MbtType::Flat)
+            .await
+            .unwrap();
+        let tile = |z, x, y, data: &Option<Vec<u8>>| TileXyz {
+            xyz: TileCoord { z, x, y },
+            data: data.clone().unwrap_or_default().into(),
+            encoding: Encoding::Uncompressed,
+        };
+
+        let data = Mbtiles::new(&original).unwrap();
+        let mut data_conn = data.open_readonly().await.unwrap();
+        let same = data.get_tile(&mut data_conn, 0, 0, 0).await.unwrap();
+        let modified = output.get_tile(&mut conn, 0, 0, 0).await.unwrap();
+        assert!(same.is_some() && same != modified);
+        assert!(!diff
+            .is_changed(&mut conn, &tile(0, 0, 0, &same))
+            .await
+            .unwrap());
+        assert!(diff
+            .is_changed(&mut conn, &tile(0, 0, 0, &modified))
+            .await
+            .unwrap());
+        assert!(!diff
+            .is_changed(&mut conn, &tile(6, 0, 0, &None))
+            .await
+            .unwrap());
+        assert!(diff
+            .is_changed(&mut conn, &tile(1, 1, 0, &None))
+            .await
+            .unwrap());
+        assert_eq!(
+            diff.changed,
+            vec![
+                (TileCoord { z: 0, x: 0, y: 0 }, TileChange::Removed),
+                (TileCoord { z: 1, x: 1, y: 0 }, TileChange::Removed)
+            ]
+        );
+        assert!(diff.removed.is_empty(), "only removed from the output file");
+
+        // Verify the original file against generated tiles
+        let mut verify = TileDiff::verify(&data, MbtType::Flat);
+        let other = Some(b"other".to_vec());
+        for (z, data) in [(0, &same), (0, &other), (6, &same)] {
+            let tile = tile(z, 0, 0, data);
+            verify.is_changed(&mut data_conn, &tile).await.unwrap();
+        }
+        let tile = tile(1, 1, 0, &None);
+        verify.is_changed(&mut data_conn, &tile).await.unwrap();
+        assert!(verify.removed.is_empty(), "nothing is deleted by --verify");
+        let dir = std::env::temp_dir().join(format!("martin-cp-verify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let report = dir.join("report.tsv");
+        let err = verify.verified(Some(&report)).unwrap_err();
+        assert!(matches!(err, MartinCpError::VerifyFailed(1, 1, 1)), "{err}");
+        assert_eq!(
+            std::fs::read_to_string(&report).unwrap(),
+            "0/0/0\tmismatch\n6/0/0\tmissing\n1/1/0\tunexpected\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_verify_extract() {
+        let source = "../tests/fixtures/mbtiles/world_cities.mbtiles";
+        let dir =
+            std::env::temp_dir().join(format!("martin-cp-verify-extract-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("extract.mbtiles");
+        let report = dir.join("report.tsv");
+        let copy = |extra: &[&str]| {
+            let mut args = vec![
+                "martin-cp",
+                "--source",
+                "world_cities",
+                "--output-file",
+                path.to_str().unwrap(),
+                "--max-zoom",
+                "2",
+            ];
+            args.extend_from_slice(extra);
+            args.push(source);
+            let args = CopierArgs::try_parse_from(args).unwrap();
+            async move { start(args, &mut None, Arc::default()).await }
+        };
+        copy(&["--mbtiles-type", "flat"]).await.unwrap();
+        copy(&["--verify"]).await.unwrap();
+
+        // Remove one tile and change another one of the extract
+        let mbt = Mbtiles::new(&path).unwrap();
+        let mut conn = mbt.open().await.unwrap();
+        mbt.delete_tiles(&mut conn, MbtType::Flat, &[(0, 0, 0)])
+            .await
+            .unwrap();
+        let changed = [(1, 1, 0, b"changed".to_vec())];
+        mbt.insert_tiles(
+            &mut conn,
+            MbtType::Flat,
+            CopyDuplicateMode::Override,
+            &changed,
+        )
+        .await
+        .unwrap();
+        drop(conn);
+
+        let verify_report = format!("--verify-report={}", report.display());
+        let err = copy(&["--verify", &verify_report]).await.unwrap_err();
+        assert!(matches!(err, MartinCpError::VerifyFailed(1, 1, 0)), "{err}");
+        // The tiles are generated concurrently, so the report is not in any particular order
+        let report = std::fs::read_to_string(&report).unwrap();
+        let mut lines: Vec<_> = report.lines().collect();
+        lines.sort_unstable();
+        assert_eq!(lines, ["0/0/0\tmissing", "1/1/0\tmismatch"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
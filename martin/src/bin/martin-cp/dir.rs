@@ -0,0 +1,179 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use log::{debug, info};
+use martin::TileCoord;
+use martin_tile_utils::{Format, TileInfo};
+use tokio::sync::mpsc::Receiver;
+use tokio::time::Instant;
+
+use crate::{
+    MartinCpError, MartinCpResult, Progress, TileXyz, PROGRESS_REPORT_AFTER, PROGRESS_REPORT_EVERY,
+};
+
+/// File extension of the tiles of an `--output-dir`
+pub fn tile_extension(format: Format) -> &'static str {
+    match format {
+        Format::Gif => "gif",
+        Format::Jpeg => "jpg",
+        Format::Json => "json",
+        Format::Mvt => "pbf",
+        Format::Png => "png",
+        Format::Webp => "webp",
+    }
+}
+
+/// Relative URL of the tiles of an `--output-dir`, used as the `tiles` of its `metadata.json`
+pub fn url_template(format: Format) -> String {
+    format!("{{z}}/{{x}}/{{y}}.{}", tile_extension(format))
+}
+
+/// Directory of `{z}/{x}/{y}.{ext}` tile files, see `--output-dir`
+pub struct TileDir {
+    root: PathBuf,
+    extension: &'static str,
+}
+
+impl TileDir {
+    pub fn new(root: &Path, tile_info: TileInfo) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            extension: tile_extension(tile_info.format),
+        }
+    }
+
+    fn tile_path(&self, xyz: TileCoord) -> PathBuf {
+        self.root
+            .join(xyz.z.to_string())
+            .join(xyz.x.to_string())
+            .join(format!("{}.{}", xyz.y, self.extension))
+    }
+
+    /// Write each non-empty generated tile to its own file, replacing the existing one
+    pub async fn save_tiles(
+        &self,
+        rx: &mut Receiver<TileXyz>,
+        progress: &Progress,
+    ) -> MartinCpResult<()> {
+        let mut last_reported = Instant::now();
+        while let Some(tile) = rx.recv().await {
+            debug!("Generated tile {tile:?}");
+            let done = if tile.data.is_empty() {
+                progress.empty.fetch_add(1, Ordering::Relaxed)
+            } else {
+                self.write_tile(tile.xyz, &tile.data).await?;
+                progress.non_empty.fetch_add(1, Ordering::Relaxed)
+            };
+            if done % PROGRESS_REPORT_AFTER == (PROGRESS_REPORT_AFTER - 1)
+                && last_reported.elapsed() > PROGRESS_REPORT_EVERY
+            {
+                progress.report(false);
+                last_reported = Instant::now();
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn write_tile(&self, xyz: TileCoord, data: &[u8]) -> MartinCpResult<()> {
+        let path = self.tile_path(xyz);
+        let write = async {
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::write(&path, data).await
+        };
+        write
+            .await
+            .map_err(|e| MartinCpError::OutputDirWrite(e, path))
+    }
+
+    pub async fn read_tile(&self, xyz: TileCoord) -> MartinCpResult<Option<Vec<u8>>> {
+        let path = self.tile_path(xyz);
+        match tokio::fs::read(&path).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(MartinCpError::OutputDirRead(e, path)),
+        }
+    }
+
+    pub async fn write_metadata(&self, json: &[u8]) -> MartinCpResult<()> {
+        let path = self.root.join("metadata.json");
+        info!("Writing the TileJSON to {}", path.display());
+        let write = async {
+            tokio::fs::create_dir_all(&self.root).await?;
+            tokio::fs::write(&path, json).await
+        };
+        write
+            .await
+            .map_err(|e| MartinCpError::OutputDirWrite(e, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser as _;
+    use martin::TileData;
+    use martin_tile_utils::Encoding;
+    use tilejson::TileJSON;
+    use tokio::sync::mpsc::channel;
+
+    use super::*;
+    use crate::{CopierArgs, Output};
+
+    #[actix_rt::test]
+    async fn test_output_dir() {
+        let root = std::env::temp_dir().join(format!("martin-cp-dir-{}", std::process::id()));
+        let dir = TileDir::new(&root, TileInfo::new(Format::Png, Encoding::Internal));
+        assert_eq!(url_template(Format::Png), "{z}/{x}/{y}.png");
+        let (tx, mut rx) = channel(4);
+        let progress = Progress::new(&[], 0);
+        tx.send(TileXyz {
+            xyz: TileCoord { z: 3, x: 2, y: 1 },
+            data: TileData::from_static(b"png"),
+            encoding: Encoding::Internal,
+        })
+        .await
+        .unwrap();
+        tx.send(TileXyz {
+            xyz: TileCoord { z: 3, x: 2, y: 2 },
+            data: TileData::new(),
+            encoding: Encoding::Internal,
+        })
+        .await
+        .unwrap();
+        drop(tx);
+        dir.save_tiles(&mut rx, &progress).await.unwrap();
+        assert_eq!(std::fs::read(root.join("3/2/1.png")).unwrap(), b"png");
+        assert!(!root.join("3/2/2.png").exists(), "empty tiles are skipped");
+        assert_eq!(progress.non_empty.load(Ordering::Relaxed), 1);
+        assert_eq!(progress.empty.load(Ordering::Relaxed), 1);
+
+        let tj = tilejson::tilejson! { tiles: vec![url_template(Format::Png)] };
+        Output::Dir(dir).write_metadata(&tj).await.unwrap();
+        let written = std::fs::read(root.join("metadata.json")).unwrap();
+        assert_eq!(serde_json::from_slice::<TileJSON>(&written).unwrap(), tj);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let args = CopierArgs::try_parse_from([
+            "martin-cp",
+            "-s",
+            "a",
+            "--output-dir",
+            "tiles",
+            "x.mbtiles",
+        ]);
+        assert_eq!(args.unwrap().copy.output_name(), "tiles");
+        for conflict in ["--resume", "--verify", "--output-file=a.mbtiles"] {
+            let args = [
+                "martin-cp",
+                "-s",
+                "a",
+                "--output-dir",
+                "tiles",
+                conflict,
+                "x.mbtiles",
+            ];
+            assert!(CopierArgs::try_parse_from(args).is_err(), "{conflict}");
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::info;
+use martin::derived::downsample_png;
+use martin::{iterate_tiles, TileCoord, TileRect};
+use tokio::time::Instant;
+
+use crate::{
+    CopyArgs, MartinCpError, MartinCpResult, Output, Progress, BATCH_SIZE_DEFAULT,
+    PROGRESS_REPORT_AFTER, PROGRESS_REPORT_EVERY,
+};
+
+/// Make the tiles of the lower zoom levels from their children, see `--downsample-from`.
+/// The zoom levels are made from the highest one down, and each one is written before the next one reads it.
+pub async fn downsample_tiles(
+    output: &mut Output,
+    mut tiles: Vec<TileRect>,
+    args: &CopyArgs,
+    progress: &Progress,
+    interrupted: &AtomicBool,
+) -> MartinCpResult<()> {
+    let batch_size = args.batch_size.unwrap_or(BATCH_SIZE_DEFAULT) as usize;
+    let mut last_reported = Instant::now();
+    tiles.sort_by_key(|v| std::cmp::Reverse(v.zoom));
+    let mut zooms: Vec<_> = tiles.iter().map(|v| v.zoom).collect();
+    zooms.dedup();
+    info!("Downsampling the tiles of zoom levels {zooms:?}");
+    for zoom in zooms {
+        let rects = tiles.iter().filter(|v| v.zoom == zoom).copied().collect();
+        let mut batch = Vec::with_capacity(batch_size);
+        for xyz in iterate_tiles(rects) {
+            if interrupted.load(Ordering::Relaxed) {
+                return Err(MartinCpError::Interrupted);
+            }
+            let mut children = [None, None, None, None];
+            for (child, (dx, dy)) in children.iter_mut().zip([(0, 0), (1, 0), (0, 1), (1, 1)]) {
+                let child_xyz = TileCoord {
+                    z: xyz.z + 1,
+                    x: xyz.x * 2 + dx,
+                    y: xyz.y * 2 + dy,
+                };
+                *child = output.read_tile(child_xyz).await?;
+            }
+            let children = [
+                children[0].as_deref(),
+                children[1].as_deref(),
+                children[2].as_deref(),
+                children[3].as_deref(),
+            ];
+            let done =
+                match downsample_png(children).map_err(|e| MartinCpError::Downsample(xyz, e))? {
+                    Some(data) => {
+                        batch.push((xyz.z, xyz.x, xyz.y, data));
+                        if batch.len() >= batch_size {
+                            output.write_tiles(&batch, args.on_duplicate).await?;
+                            batch.clear();
+                        }
+                        progress.non_empty.fetch_add(1, Ordering::Relaxed)
+                    }
+                    None => progress.empty.fetch_add(1, Ordering::Relaxed),
+                };
+            if done % PROGRESS_REPORT_AFTER == (PROGRESS_REPORT_AFTER - 1)
+                && last_reported.elapsed() > PROGRESS_REPORT_EVERY
+            {
+                progress.report(false);
+                last_reported = Instant::now();
+            }
+        }
+        if !batch.is_empty() {
+            output.write_tiles(&batch, args.on_duplicate).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use clap::Parser as _;
+    use martin_tile_utils::{Encoding, Format, TileInfo};
+    use mbtiles::Mbtiles;
+
+    use super::*;
+    use crate::dir::TileDir;
+    use crate::{start, CopierArgs};
+
+    #[actix_rt::test]
+    async fn test_build_overviews() {
+        let source = "../tests/fixtures/mbtiles/geography-class-png.mbtiles";
+        let path = std::env::temp_dir().join(format!(
+            "martin-cp-overviews-{}.mbtiles",
+            std::process::id()
+        ));
+        let args = CopierArgs::try_parse_from([
+            "martin-cp",
+            "--source",
+            "geography-class-png",
+            "--output-file",
+            path.to_str().unwrap(),
+            "--min-zoom",
+            "0",
+            "--max-zoom",
+            "1",
+            "--build-overviews",
+            source,
+        ])
+        .unwrap();
+        start(args, &mut None, Arc::default()).await.unwrap();
+
+        let mbt = Mbtiles::new(&path).unwrap();
+        let mut conn = mbt.open_readonly().await.unwrap();
+        let mut children = Vec::new();
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            // The tiles of the highest zoom level are copied from the source
+            let tile = mbt.get_tile(&mut conn, 1, x, y).await.unwrap();
+            children.push(tile.expect("the zoom 1 tiles are copied"));
+        }
+        let children = [0, 1, 2, 3].map(|idx| Some(children[idx].as_slice()));
+        let expected = downsample_png(children).unwrap().unwrap();
+        let overview = mbt.get_tile(&mut conn, 0, 0, 0).await.unwrap().unwrap();
+        assert_eq!(
+            overview, expected,
+            "the zoom 0 tile is built from its children"
+        );
+
+        let src = Mbtiles::new(source).unwrap();
+        let mut src_conn = src.open_readonly().await.unwrap();
+        let original = src.get_tile(&mut src_conn, 0, 0, 0).await.unwrap().unwrap();
+        assert_ne!(
+            overview, original,
+            "the zoom 0 tile is not copied from the source"
+        );
+        drop(conn);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_downsample_dir() {
+        let source = Mbtiles::new("../tests/fixtures/mbtiles/geography-class-png.mbtiles").unwrap();
+        let mut conn = source.open_readonly().await.unwrap();
+        let child = source.get_tile(&mut conn, 1, 1, 0).await.unwrap().unwrap();
+
+        let root =
+            std::env::temp_dir().join(format!("martin-cp-downsample-{}", std::process::id()));
+        let dir = TileDir::new(&root, TileInfo::new(Format::Png, Encoding::Internal));
+        dir.write_tile(TileCoord { z: 1, x: 1, y: 0 }, &child)
+            .await
+            .unwrap();
+        let mut output = Output::Dir(dir);
+        let args = CopyArgs::default();
+        let progress = Progress::new(&[], 0);
+        // The tile at zoom 0 has one child, and the tile at zoom 1 has none
+        let tiles = vec![TileRect::new(0, 0, 0, 0, 0), TileRect::new(1, 0, 0, 0, 0)];
+        let interrupted = AtomicBool::new(false);
+        downsample_tiles(&mut output, tiles.clone(), &args, &progress, &interrupted)
+            .await
+            .unwrap();
+        let expected = downsample_png([None, Some(child.as_slice()), None, None])
+            .unwrap()
+            .unwrap();
+        assert_eq!(std::fs::read(root.join("0/0/0.png")).unwrap(), expected);
+        assert!(
+            !root.join("1/0/0.png").exists(),
+            "empty tiles are not written"
+        );
+        assert_eq!(progress.non_empty.load(Ordering::Relaxed), 1);
+        assert_eq!(progress.empty.load(Ordering::Relaxed), 1);
+
+        interrupted.store(true, Ordering::Relaxed);
+        let err = downsample_tiles(&mut output, tiles, &args, &progress, &interrupted).await;
+        assert!(matches!(err, Err(MartinCpError::Interrupted)));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+/// Parse a duration like `2h`, `90m`, `1h30m`, or `45s`. A number without a unit is in seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let err = || format!("Invalid duration {s}, expected e.g. 2h, 90m, 1h30m, or 45s");
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'd' => 24 * 60 * 60,
+            'h' => 60 * 60,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(err()),
+        };
+        let value: u64 = number.parse().map_err(|_| err())?;
+        total = value
+            .checked_mul(unit)
+            .and_then(|v| total.checked_add(v))
+            .ok_or_else(err)?;
+        number.clear();
+    }
+    if number.is_empty() && !s.is_empty() {
+        Ok(Duration::from_secs(total))
+    } else {
+        Err(err())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("1d10s"), Ok(Duration::from_secs(86410)));
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("0"), Ok(Duration::ZERO));
+        assert_eq!(parse_duration("0s"), Ok(Duration::ZERO));
+        assert!(parse_duration("99999999999999999d").is_err());
+        assert!(parse_duration("18446744073709551615s1s").is_err());
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("2x").is_err());
+        assert!(parse_duration("1h30").is_err());
+    }
+}
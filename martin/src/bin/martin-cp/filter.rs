@@ -0,0 +1,233 @@
+use std::borrow::Cow;
+use std::ops::RangeInclusive;
+
+use clap::ValueEnum;
+use log::info;
+use martin::{
+    decode_brotli, decode_gzip, decode_zstd, encode_brotli, encode_brotli_level, encode_gzip,
+    encode_gzip_level, encode_zstd, MartinError, MartinResult, MvtFilter, Tile, TileData,
+};
+use martin_tile_utils::{Encoding, Format, TileInfo};
+
+use crate::{CopyArgs, MartinCpError, MartinCpResult};
+
+/// Encoding of the saved tiles, see `--recompress`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Recompress {
+    Gzip,
+    Brotli,
+    Zstd,
+    /// Store the tiles uncompressed
+    Identity,
+}
+
+impl Recompress {
+    /// Encoding of the recompressed tiles
+    pub fn encoding(self) -> Encoding {
+        match self {
+            Self::Gzip => Encoding::Gzip,
+            Self::Brotli => Encoding::Brotli,
+            Self::Zstd => Encoding::Zstd,
+            Self::Identity => Encoding::Uncompressed,
+        }
+    }
+
+    /// Default and valid compression levels
+    fn levels(self) -> (i32, RangeInclusive<i32>) {
+        match self {
+            Self::Gzip => (6, 0..=9),
+            Self::Brotli => (11, 0..=11),
+            Self::Zstd => (zstd::DEFAULT_COMPRESSION_LEVEL, 1..=22),
+            Self::Identity => (0, 0..=0),
+        }
+    }
+}
+
+/// Re-encoding of the tiles with `--recompress`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Recompression {
+    pub encoding: Recompress,
+    pub level: i32,
+}
+
+impl Recompression {
+    fn encode(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        // The level is within the range of the encoding, so it is never negative
+        let level = self.level.unsigned_abs();
+        match self.encoding {
+            Recompress::Gzip => encode_gzip_level(data, level),
+            Recompress::Brotli => encode_brotli_level(data, level),
+            Recompress::Zstd => encode_zstd(data, self.level),
+            Recompress::Identity => Ok(data.to_vec()),
+        }
+    }
+}
+
+impl CopyArgs {
+    /// Filter of the `--drop-layer` and `--keep-properties` options, which are only valid for vector tiles
+    pub(crate) fn mvt_filter(&self, tile_info: TileInfo) -> MartinCpResult<Option<MvtFilter>> {
+        let filter = MvtFilter {
+            drop_layers: self.drop_layer.clone(),
+            keep_properties: self.keep_properties.clone(),
+        };
+        if filter.is_empty() {
+            Ok(None)
+        } else if tile_info.format == Format::Mvt {
+            info!("Filtering vector tiles: {filter:?}");
+            Ok(Some(filter))
+        } else {
+            Err(MartinCpError::FilterNotSupported(tile_info))
+        }
+    }
+
+    /// Re-encoding of the `--recompress` options, which are not valid for tiles with their own compression
+    pub(crate) fn recompression(
+        &self,
+        tile_info: TileInfo,
+    ) -> MartinCpResult<Option<Recompression>> {
+        let Some(encoding) = self.recompress else {
+            return Ok(None);
+        };
+        if tile_info.encoding == Encoding::Internal {
+            return Err(MartinCpError::RecompressNotSupported(tile_info));
+        }
+        let (default, levels) = encoding.levels();
+        let level = self.recompress_level.unwrap_or(default);
+        if !levels.contains(&level) {
+            return Err(MartinCpError::InvalidRecompressLevel(
+                level, encoding, levels,
+            ));
+        }
+        info!("Re-encoding the tiles with {encoding:?} level {level}");
+        Ok(Some(Recompression { encoding, level }))
+    }
+}
+
+/// Decompress the tile if needed, remove the filtered content, and compress it again,
+/// with the `--recompress` encoding if set, or with the encoding of the source otherwise
+pub fn transform_tile(
+    tile: Tile,
+    filter: Option<&MvtFilter>,
+    recompress: Option<Recompression>,
+) -> MartinResult<TileData> {
+    if tile.data.is_empty() || (filter.is_none() && recompress.is_none()) {
+        return Ok(tile.data);
+    }
+    let encoding = tile.info.encoding;
+    let decoded = match encoding {
+        Encoding::Uncompressed | Encoding::Internal => Ok(Cow::Borrowed(tile.data.as_ref())),
+        Encoding::Gzip => decode_gzip(&tile.data).map(Cow::Owned),
+        Encoding::Brotli => decode_brotli(&tile.data).map(Cow::Owned),
+        Encoding::Zstd => decode_zstd(&tile.data).map(Cow::Owned),
+        Encoding::Zlib => Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "zlib encoding is not supported",
+        )),
+    };
+    let data = decoded
+        .map_err(|e| e.to_string())
+        .and_then(|v| match filter {
+            Some(filter) => filter.apply(&v).map(Cow::Owned),
+            None => Ok(v),
+        })
+        .and_then(|v| {
+            let encoded = match (recompress, encoding) {
+                (Some(recompress), _) => recompress.encode(&v),
+                (None, Encoding::Gzip) => encode_gzip(&v),
+                (None, Encoding::Brotli) => encode_brotli(&v),
+                (None, Encoding::Zstd) => encode_zstd(&v, zstd::DEFAULT_COMPRESSION_LEVEL),
+                (None, _) => Ok(v.into_owned()),
+            };
+            encoded.map_err(|e| e.to_string())
+        });
+    data.map(TileData::from)
+        .map_err(|e| MartinError::InternalError(format!("Unable to transform tile: {e}").into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use mbtiles::Mbtiles;
+
+    use super::*;
+
+    #[test]
+    fn test_recompress() {
+        let mvt = TileInfo::new(Format::Mvt, Encoding::Gzip);
+        let with = |recompress, recompress_level| CopyArgs {
+            recompress,
+            recompress_level,
+            ..Default::default()
+        };
+        assert_eq!(with(None, None).recompression(mvt).unwrap(), None);
+        let zstd = with(Some(Recompress::Zstd), None)
+            .recompression(mvt)
+            .unwrap();
+        assert_eq!(
+            zstd,
+            Some(Recompression {
+                encoding: Recompress::Zstd,
+                level: 3
+            })
+        );
+        assert!(with(Some(Recompress::Gzip), Some(10))
+            .recompression(mvt)
+            .is_err());
+        let png = TileInfo::new(Format::Png, Encoding::Internal);
+        assert!(with(Some(Recompress::Gzip), None)
+            .recompression(png)
+            .is_err());
+
+        let data = b"not really a vector tile".repeat(100);
+        let gzipped = TileData::from(encode_gzip(&data).unwrap());
+        let tile = || Tile::new(gzipped.clone(), mvt);
+        let recompressed = transform_tile(tile(), None, zstd).unwrap();
+        assert_eq!(
+            TileInfo::detect(&recompressed),
+            Some(mvt.encoding(Encoding::Zstd))
+        );
+        assert_eq!(decode_zstd(&recompressed).unwrap(), data);
+        let identity = Some(Recompression {
+            encoding: Recompress::Identity,
+            level: 0,
+        });
+        assert_eq!(transform_tile(tile(), None, identity).unwrap(), data);
+        assert_eq!(transform_tile(tile(), None, None).unwrap(), gzipped);
+    }
+
+    #[actix_rt::test]
+    async fn test_filter_tiles() {
+        let mvt = TileInfo::new(Format::Mvt, Encoding::Gzip);
+        let with = |drop_layer: &[&str], keep_properties: Option<Vec<String>>| CopyArgs {
+            drop_layer: drop_layer.iter().map(ToString::to_string).collect(),
+            keep_properties,
+            ..Default::default()
+        };
+        assert!(with(&[], None).mvt_filter(mvt).unwrap().is_none());
+        let png = TileInfo::new(Format::Png, Encoding::Internal);
+        let err = with(&["cities"], None).mvt_filter(png).unwrap_err();
+        assert!(matches!(err, MartinCpError::FilterNotSupported(_)), "{err}");
+
+        let mbt = Mbtiles::new("../tests/fixtures/mbtiles/world_cities.mbtiles").unwrap();
+        let mut conn = mbt.open_readonly().await.unwrap();
+        let data = mbt.get_tile(&mut conn, 0, 0, 0).await.unwrap().unwrap();
+        let tile = || Tile::new(TileData::from(data.clone()), mvt);
+        let contains = |data: &[u8], text: &[u8]| data.windows(text.len()).any(|v| v == text);
+        let original = decode_gzip(&data).unwrap();
+        assert!(contains(&original, b"cities") && contains(&original, b"name"));
+
+        let filter = with(&["cities"], None).mvt_filter(mvt).unwrap();
+        let dropped = transform_tile(tile(), filter.as_ref(), None).unwrap();
+        assert!(
+            decode_gzip(&dropped).unwrap().is_empty(),
+            "the only layer is dropped"
+        );
+
+        let filter = with(&["roads"], Some(Vec::new())).mvt_filter(mvt).unwrap();
+        let trimmed = transform_tile(tile(), filter.as_ref(), None).unwrap();
+        let trimmed = decode_gzip(&trimmed).unwrap();
+        assert!(contains(&trimmed, b"cities"), "other layers are kept");
+        assert!(!contains(&trimmed, b"name"), "the properties are removed");
+        assert!(!contains(&trimmed, b"Paris"), "the values are removed");
+    }
+}
@@ -0,0 +1,1930 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Debug, Display, Formatter};
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::iter;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr as _;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use actix_http::error::ParseError;
+use actix_http::test::TestRequest;
+use actix_http::HttpMessage as _;
+use actix_web::http::header::{
+    AcceptEncoding, Header as _, HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING,
+};
+use clap::{Parser, ValueEnum};
+use futures::stream::{self, StreamExt};
+use futures::{TryFutureExt as _, TryStreamExt};
+use log::{debug, error, info, log_enabled, warn};
+use martin::args::{Args, ExtraArgs, MetaArgs, OsEnv, PgArgs, SrvArgs};
+use martin::derived::DerivedError;
+use martin::remote::RemoteSource;
+use martin::srv::{get_tile_content, merge_tilejson, RESERVED_KEYWORDS};
+use martin::{
+    read_config, with_headers, Config, GeoMask, IdResolver, MartinError, MartinResult, MvtFilter,
+    ServerState, Source, TileCoord, TileData, TileRect,
+};
+use martin_tile_utils::{webmercator_to_wgs84, Encoding, Format, TileInfo};
+use mbtiles::sqlx::SqliteConnection;
+use mbtiles::{
+    init_mbtiles_schema, is_empty_database, CopyDuplicateMode, MbtType, MbtTypeCli, Mbtiles,
+    WriteLock,
+};
+use tilejson::{Bounds, TileJSON, VectorLayer};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+use tokio::try_join;
+
+use crate::benchmark::{Benchmark, DryRun};
+use crate::checkpoint::{Checkpoint, CHECKPOINT_KEY};
+use crate::concurrency::{parse_concurrency, Concurrency};
+use crate::diff::{write_tile_list, TileDiff};
+use crate::dir::{url_template, TileDir};
+use crate::downsample::downsample_tiles;
+use crate::duration::parse_duration;
+use crate::filter::{transform_tile, Recompress};
+use crate::notify::{notification, notify};
+use crate::order::{remaining_tiles, TileOrder};
+use crate::retry::with_retries;
+use crate::s3::S3Output;
+use crate::throttle::{parse_rate, Throttle};
+
+mod benchmark;
+mod checkpoint;
+mod concurrency;
+mod diff;
+mod dir;
+mod downsample;
+mod duration;
+mod filter;
+mod notify;
+mod order;
+mod retry;
+mod s3;
+mod throttle;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const COMMIT_INTERVAL_DEFAULT: Duration = Duration::from_secs(60);
+const PROGRESS_REPORT_AFTER: u64 = 100;
+const PROGRESS_REPORT_EVERY: Duration = Duration::from_secs(2);
+const BATCH_SIZE_DEFAULT: u32 = 1000;
+const QUEUE_SIZE_DEFAULT: u32 = 500;
+const RETRY_DELAY_DEFAULT: Duration = Duration::from_secs(1);
+
+#[derive(Parser, Debug, PartialEq, Default)]
+#[command(
+    about = "A tool to bulk copy tiles from any Martin-supported sources into an mbtiles file",
+    version
+)]
+pub struct CopierArgs {
+    #[command(flatten)]
+    pub copy: CopyArgs,
+    #[command(flatten)]
+    pub meta: MetaArgs,
+    #[command(flatten)]
+    pub pg: Option<PgArgs>,
+}
+
+#[serde_with::serde_as]
+#[derive(clap::Args, Debug, PartialEq, Default, serde::Deserialize, serde::Serialize)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct CopyArgs {
+    /// Name of the source to copy from. Can be used multiple times, or with a comma-separated list,
+    /// to combine the tiles of several sources with the same format into one file.
+    /// An `http://` or `https://` URL with `{z}`, `{x}`, and `{y}` placeholders, or of a TileJSON document,
+    /// copies the tiles of a remote tile server.
+    #[arg(short, long, value_delimiter = ',', required = true)]
+    pub source: Vec<String>,
+    /// Path to the mbtiles file to copy to.
+    #[arg(short, long, required_unless_present("output_dir"))]
+    pub output_file: Option<PathBuf>,
+    /// Write the tiles as `{z}/{x}/{y}.{ext}` files in this directory instead of an mbtiles file,
+    /// e.g. to deploy them to a static web server. The tiles keep their `--encoding`.
+    /// Use an `s3://bucket/prefix` URL to upload them to S3-compatible object storage instead.
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all([
+            "output_file",
+            "mbt_type",
+            "skip_existing",
+            "resume",
+            "diff_with",
+            "verify",
+            "skip_agg_tiles_hash"
+        ])
+    )]
+    pub output_dir: Option<PathBuf>,
+    /// Do not write the `metadata.json` file with the TileJSON of the tiles to the `--output-dir`.
+    #[arg(long, requires("output_dir"))]
+    pub skip_metadata_json: bool,
+    /// Number of tiles uploaded at the same time to an `s3://` output directory. [default: 16]
+    #[arg(long, value_name = "COUNT", requires("output_dir"), value_parser = clap::value_parser!(u32).range(1..))]
+    pub upload_concurrency: Option<u32>,
+    /// `Content-Type` of the tiles uploaded to an `s3://` output directory. [default: the type of the tile format]
+    #[arg(long, value_name = "TYPE", requires("output_dir"))]
+    pub content_type: Option<String>,
+    /// `Cache-Control` of the tiles uploaded to an `s3://` output directory, e.g. `public, max-age=86400`.
+    #[arg(long, value_name = "VALUE", requires("output_dir"))]
+    pub cache_control: Option<String>,
+    /// Output format of the new destination file. Ignored if the file exists. Defaults to 'normalized'.
+    #[arg(
+        long = "mbtiles-type",
+        alias = "dst-type",
+        value_name = "SCHEMA",
+        value_enum
+    )]
+    pub mbt_type: Option<MbtTypeCli>,
+    /// Optional query parameter (in URL query format) for the sources that support it (e.g. Postgres functions)
+    #[arg(long)]
+    pub url_query: Option<String>,
+    /// Add an HTTP header to the request used to generate the tiles, for the sources that vary on headers.
+    /// Must be set as "Name: value". Can be specified multiple times.
+    #[arg(long = "header", value_name = "NAME: VALUE", value_parser = parse_header)]
+    pub headers: Vec<(String, String)>,
+    /// Optional accepted encoding parameter as if the browser sent it in the HTTP request.
+    /// If set to multiple values like `gzip,br`, martin-cp will use the first encoding,
+    /// or re-encode if the tile is already encoded and that encoding is not listed.  
+    /// Use `identity` to disable compression. Ignored for non-encodable tiles like PNG and JPEG.
+    #[arg(long, alias = "encodings", default_value = "gzip")]
+    pub encoding: String,
+    /// Decode the tiles from whatever encoding the source returns, and compress them with this algorithm
+    /// before they are saved. Unlike `--encoding`, all tiles are re-encoded, even if they are already compressed.
+    #[arg(long, value_enum, value_name = "ENCODING")]
+    pub recompress: Option<Recompress>,
+    /// Compression level of `--recompress`: 0-9 for gzip [default: 6], 0-11 for brotli [default: 11],
+    /// or 1-22 for zstd [default: 3]. Higher levels make smaller tiles, but take longer to compress.
+    #[arg(long, value_name = "LEVEL", requires = "recompress")]
+    pub recompress_level: Option<i32>,
+    /// Specify the behaviour when generated tile already exists in the destination file.
+    #[arg(long, value_enum, default_value_t = CopyDuplicateMode::default())]
+    pub on_duplicate: CopyDuplicateMode,
+    /// Do not generate the tiles that already exist in the destination file, e.g. to fill in the gaps of a previous copy.
+    /// Empty tiles are not stored, so they are generated again.
+    #[arg(long, conflicts_with("diff_with"))]
+    pub skip_existing: bool,
+    /// Generate the tiles one zoom level at a time, and skip the tiles inside of an empty tile of a lower zoom level
+    /// without querying the sources. Only use it if the sources never have data in a tile that is empty at a lower zoom.
+    #[arg(long, conflicts_with("diff_with"))]
+    pub prune_empty: bool,
+    /// Generate the PNG tiles of the zoom levels below this one from their four children in the output,
+    /// scaled down to the tile size, instead of querying the sources. The tiles of this zoom level and above
+    /// are generated first, then each lower zoom level from the one above it.
+    #[arg(
+        long,
+        value_name = "ZOOM",
+        conflicts_with_all(["resume", "diff_with", "verify", "sampling"])
+    )]
+    pub downsample_from: Option<u8>,
+    /// Only query the sources for the PNG tiles of the highest copied zoom level, and build the overviews
+    /// of all lower zoom levels from them, same as `--downsample-from` with that zoom level.
+    #[arg(
+        long,
+        conflicts_with_all(["downsample_from", "resume", "diff_with", "verify", "sampling"])
+    )]
+    pub build_overviews: bool,
+    /// Order in which the tiles are generated, so that a partial copy, e.g. one stopped by `--max-duration`,
+    /// already has the most useful tiles.
+    #[arg(long, value_enum, default_value_t = TileOrder::default())]
+    pub order: TileOrder,
+    /// Number of concurrent connections to use. Use e.g. `8@z0-10,2@z11-14` to choose it per zoom range,
+    /// with a plain number for the other zoom levels, which otherwise use 1.
+    #[arg(long, default_value = "1", value_parser = parse_concurrency)]
+    pub concurrency: Option<Concurrency>,
+    /// Number of tiles written to the output file in a single transaction. [default: 1000]
+    #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u32).range(1..))]
+    pub batch_size: Option<u32>,
+    /// Number of generated tiles that may wait to be written before the generation pauses. [default: 500]
+    #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u32).range(1..))]
+    pub queue_size: Option<u32>,
+    /// Write the tiles generated so far at least this often, even if the batch is not full, e.g. `10s`. [default: 60s]
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub commit_interval: Option<Duration>,
+    /// Start generating at most this many tiles per second, e.g. `200` or `0.5`, to avoid overloading the database.
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    pub max_tiles_per_second: Option<f64>,
+    /// Run at most this many source queries at the same time. A tile of several combined sources runs one query per source.
+    #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_concurrent_queries: Option<u32>,
+    /// Bounds to copy. Can be specified multiple times. Overlapping regions will be handled correctly.
+    /// [default: the `bounds` of the source TileJSON, or the whole world]
+    #[arg(long)]
+    pub bbox: Vec<Bounds>,
+    /// Coordinate reference system of the `--bbox` values, either `EPSG:4326` for longitudes and latitudes,
+    /// or `EPSG:3857` for the meters of web map extents. Other systems are not supported.
+    #[arg(long, value_enum, value_name = "CRS", ignore_case = true, default_value_t = BboxCrs::default(), requires = "bbox")]
+    pub bbox_crs: BboxCrs,
+    /// GeoJSON file with the polygons to copy, e.g. country or region boundaries, instead of rectangular bounds.
+    /// Only the tiles that intersect the polygons are copied.
+    #[arg(long, value_name = "FILE", conflicts_with("bbox"))]
+    pub geojson_mask: Option<PathBuf>,
+    /// Minimum zoom level to copy [default: the `minzoom` of the source TileJSON, or 0]
+    #[arg(long, alias = "minzoom", conflicts_with("zoom_levels"))]
+    pub min_zoom: Option<u8>,
+    /// Maximum zoom level to copy [default: the `maxzoom` of the source TileJSON]
+    #[arg(long, alias = "maxzoom", conflicts_with("zoom_levels"))]
+    pub max_zoom: Option<u8>,
+    /// List of zoom levels to copy
+    #[arg(short, long, alias = "zooms", value_delimiter = ',')]
+    pub zoom_levels: Vec<u8>,
+    /// File with the tiles to copy instead of the bounds and zoom levels, one `z/x/y` per line,
+    /// e.g. the tiles invalidated by a data change. Empty lines and lines starting with `#` are ignored.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all(["bbox", "geojson_mask", "min_zoom", "max_zoom", "zoom_levels"])
+    )]
+    pub tile_list: Option<PathBuf>,
+    /// Skip generating a global hash for mbtiles validation. By default, `martin-cp` will compute and update `agg_tiles_hash` metadata value.
+    #[arg(long)]
+    pub skip_agg_tiles_hash: bool,
+    /// Set additional metadata values. Must be set as "key=value" pairs. Can be specified multiple times.
+    #[arg(long, value_name="KEY=VALUE", value_parser = parse_key_value)]
+    pub set_meta: Vec<(String, String)>,
+    /// Name of the tileset in the TileJSON of a new output, instead of the one merged from the sources
+    #[arg(long)]
+    pub name: Option<String>,
+    /// Description of the tileset in the TileJSON of a new output
+    #[arg(long)]
+    pub description: Option<String>,
+    /// Attribution of the tileset in the TileJSON of a new output, e.g. `© OpenStreetMap contributors`
+    #[arg(long)]
+    pub attribution: Option<String>,
+    /// Merge a JSON object into a vector layer of the TileJSON of a new output, e.g. `roads={"description": "Road network"}`.
+    /// The layer is added if the sources have no layer with that ID. Can be specified multiple times.
+    #[arg(long, value_name = "LAYER=JSON", value_parser = parse_vector_layer)]
+    pub set_vector_layer: Vec<(String, serde_json::Value)>,
+    /// Remove this layer from the vector tiles as they are copied. Can be specified multiple times.
+    #[arg(long, value_name = "LAYER")]
+    pub drop_layer: Vec<String>,
+    /// Remove all feature properties from the vector tiles except these.
+    #[arg(long, value_name = "PROPERTIES", value_delimiter = ',')]
+    pub keep_properties: Option<Vec<String>>,
+    /// Stop generating new tiles after this time, e.g. `2h`, `90m`, or `1h30m`. The tiles generated so far are saved,
+    /// the metadata is updated, and the progress is recorded so that the copy can be continued with `--resume`.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub max_duration: Option<Duration>,
+    /// Continue a copy that was stopped by `--max-duration` or interrupted, skipping the tiles it has already generated.
+    /// Ignored if the output file has no progress recorded for the same source, query, and tiles.
+    #[arg(long)]
+    pub resume: bool,
+    /// Read all tiles from a single Postgres snapshot, so that the copy is consistent
+    /// even if the database is modified while the tiles are being generated.
+    #[arg(long)]
+    pub consistent_snapshot: bool,
+    /// Write to the output file even if it is locked by another process, e.g. after that process crashed.
+    #[arg(long)]
+    pub force: bool,
+    /// Compare the generated tiles with this MBTiles file, which may be the output file itself,
+    /// and only write the tiles whose content has changed.
+    #[arg(long, value_name = "FILE")]
+    pub diff_with: Option<PathBuf>,
+    /// Write the coordinates of the tiles that differ from the `--diff-with` file to this file, one `z/x/y` per line.
+    #[arg(long, value_name = "FILE", requires("diff_with"))]
+    pub changed_tiles: Option<PathBuf>,
+    /// Instead of writing the tiles, compare them with the ones stored in the output file, and fail if any tile
+    /// is missing, has a different content, or is stored although the source has no data for it.
+    /// The tiles must be generated with the same options as the output file, e.g. the same `--encoding`.
+    #[arg(long, conflicts_with_all(["diff_with", "skip_existing", "resume", "sampling"]))]
+    pub verify: bool,
+    /// Write the tiles that failed `--verify` to this file, one `z/x/y` per line, followed by a tab and
+    /// `missing`, `mismatch`, or `unexpected`. The file can be used as the `--tile-list` of a copy that fixes them.
+    #[arg(long, value_name = "FILE", requires("verify"))]
+    pub verify_report: Option<PathBuf>,
+    /// Retry each tile that fails to generate this many times, e.g. after a database timeout or a network error.
+    #[arg(long, default_value = "0")]
+    pub retries: u32,
+    /// Time to wait before the first retry of a tile, doubled after each failed retry. [default: 1s]
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub retry_delay: Option<Duration>,
+    /// Skip the tiles that still fail after all retries instead of stopping the copy,
+    /// and write their `z/x/y` coordinates and errors to this file, one tile per line.
+    #[arg(long, value_name = "FILE")]
+    pub failed_tiles_log: Option<PathBuf>,
+    /// Print the tile ranges, the number of tiles of each zoom level, and the estimated size of the output,
+    /// then exit without writing anything.
+    #[arg(long, group = "sampling")]
+    pub dry_run: bool,
+    /// Generate a sample of the tiles of each zoom level without writing them, then print the p50, p95, and p99
+    /// generation latency and the average size of the tiles of each zoom level, e.g. to tune the database indexes.
+    #[arg(long, group = "sampling")]
+    pub benchmark: bool,
+    /// Number of tiles generated at each zoom level with `--dry-run` or `--benchmark`. [default: 10]
+    #[arg(long, value_name = "COUNT", requires("sampling"))]
+    pub sample_tiles: Option<u64>,
+    /// Format of the progress reports. `json` writes one object per report to stderr or to `--progress-file`.
+    #[arg(long, value_enum, default_value_t = ProgressFormat::default())]
+    pub progress_format: ProgressFormat,
+    /// Write the JSON progress reports to this file instead of stderr.
+    #[arg(long, value_name = "FILE")]
+    pub progress_file: Option<PathBuf>,
+    /// Send a JSON summary of the copy with a `POST` request to this URL once it has finished, stopped, or failed,
+    /// e.g. to a Slack or another webhook.
+    #[arg(long, value_name = "URL")]
+    pub notify_url: Option<String>,
+}
+
+/// Coordinate reference system of the bounds, see `--bbox-crs`
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, serde::Deserialize, serde::Serialize,
+)]
+pub enum BboxCrs {
+    /// Longitudes and latitudes
+    #[default]
+    #[value(name = "EPSG:4326", alias = "WGS84")]
+    #[serde(rename = "EPSG:4326")]
+    Epsg4326,
+    /// Web Mercator meters, as used by most web maps
+    #[value(name = "EPSG:3857", aliases = ["EPSG:900913", "EPSG:3785"])]
+    #[serde(rename = "EPSG:3857")]
+    Epsg3857,
+}
+
+impl BboxCrs {
+    /// Convert the bounds to longitudes and latitudes
+    fn to_wgs84(self, bounds: Bounds) -> Bounds {
+        match self {
+            Self::Epsg4326 => bounds,
+            Self::Epsg3857 => {
+                let (left, bottom) = webmercator_to_wgs84(bounds.left, bounds.bottom);
+                let (right, top) = webmercator_to_wgs84(bounds.right, bounds.top);
+                Bounds::new(left, bottom, right, top)
+            }
+        }
+    }
+}
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, serde::Deserialize, serde::Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgressFormat {
+    /// Log lines for humans
+    #[default]
+    Text,
+    /// Newline-delimited JSON records
+    Json,
+}
+
+impl CopyArgs {
+    /// Output of the JSON progress reports, or `None` if the progress is logged as text
+    fn progress_output(&self) -> MartinCpResult<Option<Box<dyn Write + Send>>> {
+        if self.progress_format == ProgressFormat::Text {
+            return Ok(None);
+        }
+        Ok(Some(match &self.progress_file {
+            Some(path) => {
+                let file = File::create(path)
+                    .map_err(|e| MartinCpError::ProgressWrite(e, path.clone()))?;
+                Box::new(LineWriter::new(file))
+            }
+            None => Box::new(std::io::stderr()),
+        }))
+    }
+
+    /// Output file or directory, as shown in the logs and notifications
+    fn output_name(&self) -> String {
+        self.output_dir
+            .as_ref()
+            .or(self.output_file.as_ref())
+            .map(|v| v.display().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Headers of the synthetic request used to generate the tiles, as if a browser sent them
+    fn request_headers(&self) -> MartinCpResult<(AcceptEncoding, HeaderMap)> {
+        let mut req = TestRequest::default();
+        req.insert_header((ACCEPT_ENCODING, self.encoding.as_str()));
+        for header in &self.headers {
+            req.insert_header(header.clone());
+        }
+        let req = req.finish();
+        Ok((AcceptEncoding::parse(&req)?, req.headers().clone()))
+    }
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    let mut parts = s.splitn(2, '=');
+    let key = parts.next().unwrap();
+    let value = parts
+        .next()
+        .ok_or_else(|| format!("Invalid key=value pair: {s}"))?;
+    if key.is_empty() || value.is_empty() {
+        Err(format!("Invalid key=value pair: {s}"))
+    } else {
+        Ok((key.to_string(), value.to_string()))
+    }
+}
+
+/// Parse a `--set-vector-layer` value, and check that it can be merged into a layer
+fn parse_vector_layer(s: &str) -> Result<(String, serde_json::Value), String> {
+    let err = |e: &str| format!("Invalid vector layer {s}, expected LAYER=JSON object: {e}");
+    let (id, json) = s.split_once('=').ok_or_else(|| err("missing ="))?;
+    let value: serde_json::Value = serde_json::from_str(json).map_err(|e| err(&e.to_string()))?;
+    if id.is_empty() || !value.is_object() {
+        return Err(err("the value must be a JSON object"));
+    }
+    set_vector_layer(&mut Vec::new(), id, &value).map_err(|e| err(&e))?;
+    Ok((id.to_string(), value))
+}
+
+/// Merge the JSON object into the vector layer with the ID, or add a layer with its fields
+fn set_vector_layer(
+    layers: &mut Vec<VectorLayer>,
+    id: &str,
+    value: &serde_json::Value,
+) -> Result<(), String> {
+    let index = layers.iter().position(|v| v.id == id);
+    let mut layer = match index {
+        Some(i) => serde_json::to_value(&layers[i]).map_err(|e| e.to_string())?,
+        None => serde_json::json!({ "fields": {} }),
+    };
+    if let (Some(layer), Some(value)) = (layer.as_object_mut(), value.as_object()) {
+        layer.extend(value.clone());
+        layer.insert("id".to_string(), id.into());
+    }
+    let layer: VectorLayer = serde_json::from_value(layer).map_err(|e| e.to_string())?;
+    match index {
+        Some(i) => layers[i] = layer,
+        None => layers.push(layer),
+    }
+    Ok(())
+}
+
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("Invalid header, expected \"Name: value\": {s}"))?;
+    let name = name.trim();
+    let value = value.trim();
+    if HeaderName::from_str(name).is_err() || HeaderValue::from_str(value).is_err() {
+        Err(format!("Invalid header, expected \"Name: value\": {s}"))
+    } else {
+        Ok((name.to_string(), value.to_string()))
+    }
+}
+
+/// Tiles that still failed after all retries, see `--failed-tiles-log`
+struct FailedTiles {
+    path: PathBuf,
+    tiles: Mutex<Vec<String>>,
+}
+
+impl FailedTiles {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            tiles: Mutex::default(),
+        }
+    }
+
+    fn add(&self, xyz: TileCoord, error: &impl Display) {
+        let mut tiles = self.tiles.lock().unwrap_or_else(PoisonError::into_inner);
+        tiles.push(format!("{xyz:#}\t{error}"));
+    }
+
+    fn write(&self) -> MartinCpResult<()> {
+        let tiles = self.tiles.lock().unwrap_or_else(PoisonError::into_inner);
+        if !tiles.is_empty() {
+            warn!(
+                "{} tiles could not be generated, see {}",
+                tiles.len(),
+                self.path.display()
+            );
+        }
+        write_tile_list(&self.path, tiles.iter().cloned())
+    }
+}
+
+/// Run the copy, and set the final progress once all tiles have been generated.
+/// The copy stops early once `interrupted` is set, which is done by the first SIGINT or SIGTERM.
+async fn start(
+    copy_args: CopierArgs,
+    summary: &mut Option<serde_json::Value>,
+    interrupted: Arc<AtomicBool>,
+) -> MartinCpResult<()> {
+    info!("Martin-CP tile copier v{VERSION}");
+
+    let env = OsEnv::default();
+    let save_config = copy_args.meta.save_config.clone();
+    let mut config = if let Some(ref cfg_filename) = copy_args.meta.config {
+        info!("Using {}", cfg_filename.display());
+        read_config(cfg_filename, &env)?
+    } else {
+        info!("Config file is not specified, auto-detecting sources");
+        Config::default()
+    };
+
+    let args = Args {
+        command: None,
+        meta: copy_args.meta,
+        extras: ExtraArgs::default(),
+        srv: SrvArgs::default(),
+        pg: copy_args.pg,
+    };
+
+    args.merge_into_config(&mut config, &env)?;
+    if copy_args.copy.consistent_snapshot {
+        for pg in config.postgres.iter_mut() {
+            pg.consistent_snapshot = true;
+        }
+    }
+    let mut remote = Vec::new();
+    for url in &copy_args.copy.source {
+        if url.starts_with("http://") || url.starts_with("https://") {
+            info!("Copying the tiles of {url}");
+            remote.push(Box::new(RemoteSource::new(url).await?) as Box<dyn Source>);
+        }
+    }
+    match config.finalize() {
+        // Remote sources do not need any other source to be configured
+        Err(MartinError::NoSources) if !remote.is_empty() => {}
+        res => {
+            res?;
+        }
+    }
+    let mut sources = config.resolve(IdResolver::new(RESERVED_KEYWORDS)).await?;
+    sources.tiles.extend(remote);
+
+    if let Some(file_name) = save_config {
+        config.save_to_file(file_name)?;
+    } else {
+        info!("Use --save-config to save or print configuration.");
+    }
+
+    run_tile_copy(copy_args.copy, sources, summary, interrupted).await
+}
+
+/// Use the zoom levels and the bounds of the source TileJSON for the options that were not set
+fn apply_tilejson_defaults(args: &mut CopyArgs, tj: &TileJSON) -> MartinCpResult<()> {
+    if args.tile_list.is_some() {
+        return Ok(());
+    }
+    if args.zoom_levels.is_empty() {
+        let max_zoom = if let Some(v) = args.max_zoom {
+            v
+        } else {
+            let max_zoom = tj.maxzoom.ok_or(MartinCpError::NoMaxZoom)?;
+            info!("Copying up to zoom {max_zoom}, the maxzoom of the source");
+            args.max_zoom = Some(max_zoom);
+            max_zoom
+        };
+        if args.min_zoom.is_none() {
+            if let Some(min_zoom) = tj.minzoom.filter(|v| *v > 0 && *v <= max_zoom) {
+                info!("Copying from zoom {min_zoom}, the minzoom of the source");
+                args.min_zoom = Some(min_zoom);
+            }
+        }
+    }
+    if args.bbox.is_empty() && args.geojson_mask.is_none() {
+        if let Some(b) = tj.bounds {
+            let world = Bounds::MAX_TILED;
+            let bounds = Bounds::new(
+                b.left.max(world.left),
+                b.bottom.max(world.bottom),
+                b.right.min(world.right),
+                b.top.min(world.top),
+            );
+            if bounds != world && bounds.left < bounds.right && bounds.bottom < bounds.top {
+                info!("Copying the tiles within {bounds}, the bounds of the source");
+                args.bbox = vec![bounds];
+            }
+        }
+    }
+    Ok(())
+}
+
+fn compute_tile_ranges(args: &CopyArgs, mask: Option<&GeoMask>) -> Vec<TileRect> {
+    let zooms = if let Some(max_zoom) = args.max_zoom {
+        let min_zoom = args.min_zoom.unwrap_or(0);
+        (min_zoom..=max_zoom).collect::<Vec<_>>()
+    } else {
+        args.zoom_levels.clone()
+    };
+    match mask {
+        Some(mask) => mask.tile_ranges(&zooms),
+        None => martin::compute_tile_ranges(&args.bbox, &zooms),
+    }
+}
+
+/// Read the tiles of `--tile-list`, see [`parse_tile_list`]
+fn read_tile_list(path: &Path) -> MartinCpResult<Vec<TileRect>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| MartinCpError::TileListRead(e, path.to_path_buf()))?;
+    let tiles = parse_tile_list(&text)
+        .map_err(|(e, line)| MartinCpError::TileListParse(e, path.to_path_buf(), line))?;
+    info!("Copying {} tiles listed in {}", tiles.len(), path.display());
+    Ok(merge_tiles(tiles))
+}
+
+/// Parse one `z/x/y` per line. Anything after a tab is ignored, so that the `--failed-tiles-log` can be used.
+/// Returns the error and its line number if a line is not a valid tile.
+fn parse_tile_list(text: &str) -> Result<Vec<TileCoord>, (String, usize)> {
+    let mut tiles = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.split('\t').next().unwrap_or_default().trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let err = || (format!("invalid tile `{line}`, expected z/x/y"), idx + 1);
+        let mut parts = line.split('/');
+        let (Some(z), Some(x), Some(y), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(err());
+        };
+        let (Ok(z), Ok(x), Ok(y)) = (z.parse::<u8>(), x.parse::<u32>(), y.parse::<u32>()) else {
+            return Err(err());
+        };
+        if z > 30 || x >> z > 0 || y >> z > 0 {
+            return Err((format!("tile {line} is out of range"), idx + 1));
+        }
+        tiles.push(TileCoord { z, x, y });
+    }
+    Ok(tiles)
+}
+
+/// Remove the duplicate tiles, and combine the adjacent tiles of each row into a single range
+fn merge_tiles(mut tiles: Vec<TileCoord>) -> Vec<TileRect> {
+    tiles.sort_unstable_by_key(|v| (v.z, v.y, v.x));
+    tiles.dedup();
+    let mut result: Vec<TileRect> = Vec::new();
+    for TileCoord { z, x, y } in tiles {
+        match result.last_mut() {
+            Some(last) if last.zoom == z && last.min_y == y && last.max_x + 1 == x => {
+                last.max_x = x;
+            }
+            _ => result.push(TileRect::new(z, x, y, x, y)),
+        }
+    }
+    result
+}
+
+struct TileXyz {
+    xyz: TileCoord,
+    data: TileData,
+    /// Compression of the data, as the tiles may be re-encoded by `get_tile_content` or `--recompress`
+    encoding: Encoding,
+}
+
+impl Debug for TileXyz {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} - {} bytes", self.xyz, self.data.len())
+    }
+}
+
+struct Progress {
+    // needed to compute elapsed time
+    start_time: Instant,
+    total: u64,
+    empty: AtomicU64,
+    non_empty: AtomicU64,
+    /// Tiles that are the same as in the `--diff-with` file, and were not written
+    unchanged: AtomicU64,
+    /// Tiles that still failed after all retries, and were skipped
+    failed: AtomicU64,
+    /// Tiles that were already in the output file, and were not generated, see `--skip-existing`
+    existing: AtomicU64,
+    /// Tiles inside of an empty tile of a lower zoom level, which were not generated, see `--prune-empty`
+    pruned: AtomicU64,
+    /// Where to write the JSON reports, or `None` to log them as text, see `--progress-format`
+    json: Option<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl Progress {
+    /// Track the progress of generating the tiles, except the `skipped` ones generated by a previous run
+    pub fn new(tiles: &[TileRect], skipped: u64) -> Self {
+        let total = tiles
+            .iter()
+            .map(TileRect::size)
+            .sum::<u64>()
+            .saturating_sub(skipped);
+        Progress {
+            start_time: Instant::now(),
+            total,
+            empty: AtomicU64::default(),
+            non_empty: AtomicU64::default(),
+            unchanged: AtomicU64::default(),
+            failed: AtomicU64::default(),
+            existing: AtomicU64::default(),
+            pruned: AtomicU64::default(),
+            json: None,
+        }
+    }
+
+    pub fn done(&self) -> u64 {
+        self.empty.load(Ordering::Relaxed)
+            + self.non_empty.load(Ordering::Relaxed)
+            + self.failed.load(Ordering::Relaxed)
+            + self.existing.load(Ordering::Relaxed)
+            + self.pruned.load(Ordering::Relaxed)
+    }
+
+    /// Number of tiles done per second, and the estimated time left, unless no tiles are done yet
+    #[allow(clippy::cast_precision_loss)]
+    fn speed_and_eta(&self) -> (f32, Option<Duration>) {
+        let elapsed_s = self.start_time.elapsed().as_secs_f32();
+        let done = self.done();
+        let speed = if elapsed_s > 0.0 {
+            done as f32 / elapsed_s
+        } else {
+            0.0
+        };
+        let left = self.total.saturating_sub(done);
+        let eta = match (left, done) {
+            (0, _) => Some(Duration::ZERO),
+            (_, 0) => None,
+            _ => Some(Duration::from_secs_f32(
+                elapsed_s * left as f32 / done as f32,
+            )),
+        };
+        (speed, eta)
+    }
+
+    /// Progress as a JSON record, `last` is set on the record reported once the copy has ended
+    fn to_json(&self, last: bool) -> serde_json::Value {
+        let (speed, eta) = self.speed_and_eta();
+        let round = |v: f64| (v * 1000.0).round() / 1000.0;
+        serde_json::json!({
+            "elapsed": round(self.start_time.elapsed().as_secs_f64()),
+            "total": self.total,
+            "done": self.done(),
+            "non_empty": self.non_empty.load(Ordering::Relaxed),
+            "empty": self.empty.load(Ordering::Relaxed),
+            "unchanged": self.unchanged.load(Ordering::Relaxed),
+            "failed": self.failed.load(Ordering::Relaxed),
+            "existing": self.existing.load(Ordering::Relaxed),
+            "pruned": self.pruned.load(Ordering::Relaxed),
+            "speed": round(f64::from(speed)),
+            "eta": eta.map(|v| round(v.as_secs_f64())),
+            "last": last,
+        })
+    }
+
+    /// Log the progress, or write it as a JSON line with `--progress-format json`
+    fn report(&self, last: bool) {
+        let Some(out) = &self.json else {
+            info!("{self}");
+            return;
+        };
+        let mut out = out.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(e) = writeln!(out, "{}", self.to_json(last)) {
+            warn!("Unable to write the progress: {e}");
+        }
+    }
+}
+
+type MartinCpResult<T> = Result<T, MartinCpError>;
+
+#[derive(Debug, thiserror::Error)]
+enum MartinCpError {
+    #[error(transparent)]
+    Martin(#[from] MartinError),
+    #[error("Unable to parse encodings argument: {0}")]
+    EncodingParse(#[from] ParseError),
+    #[error(transparent)]
+    Actix(#[from] actix_web::Error),
+    #[error(transparent)]
+    Mbt(#[from] mbtiles::MbtError),
+    #[error("Layer and property filtering is only supported for vector tiles, but the source has {0} tiles")]
+    FilterNotSupported(TileInfo),
+    #[error("Re-encoding is not supported for {0} tiles, which have their own compression")]
+    RecompressNotSupported(TileInfo),
+    #[error("Compression level {0} is not valid for {1:?}, it must be within {2:?}")]
+    InvalidRecompressLevel(i32, Recompress, RangeInclusive<i32>),
+    #[error("Unable to write the list of tiles to {}: {0}", .1.display())]
+    TileListWrite(std::io::Error, PathBuf),
+    #[error("Unable to read the list of tiles from {}: {0}", .1.display())]
+    TileListRead(std::io::Error, PathBuf),
+    #[error("Unable to parse the list of tiles {}, line {2}: {0}", .1.display())]
+    TileListParse(String, PathBuf, usize),
+    #[error("Unable to write the progress to {}: {0}", .1.display())]
+    ProgressWrite(std::io::Error, PathBuf),
+    #[error("Unable to write {}: {0}", .1.display())]
+    OutputDirWrite(std::io::Error, PathBuf),
+    #[error("Unable to read {}: {0}", .1.display())]
+    OutputDirRead(std::io::Error, PathBuf),
+    #[error("The copy was interrupted, run it again with --resume to continue")]
+    Interrupted,
+    #[error("{0} tiles are missing from the output file, {1} have a different content, and {2} are stored although the source has no data for them")]
+    VerifyFailed(usize, usize, usize),
+    #[error("The source TileJSON has no maxzoom, set it with --max-zoom or --zoom-levels")]
+    NoMaxZoom,
+    #[error("Downsampling is only supported for PNG tiles, but the source has {0} tiles")]
+    DownsampleNotSupported(TileInfo),
+    #[error("Unable to downsample tile {0:#}: {1}")]
+    Downsample(TileCoord, DerivedError),
+    #[error(
+        "Downsampling reads the tiles of the output, which is not supported for s3:// outputs"
+    )]
+    DownsampleNotSupportedForS3,
+    #[error("--prune-empty needs the tiles of the lower zoom levels first, use --order zoom-asc or list the zoom levels in ascending order")]
+    PruneNotSupported,
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let elapsed = self.start_time.elapsed();
+        let non_empty = self.non_empty.load(Ordering::Relaxed);
+        let empty = self.empty.load(Ordering::Relaxed);
+        let failed = self.failed.load(Ordering::Relaxed);
+        let done = self.done();
+        let percent = done * 100 / self.total.max(1);
+        let (speed, eta) = self.speed_and_eta();
+        write!(
+            f,
+            "[{elapsed:.1?}] {percent:.2}% @ {speed:.1}/s | ✓ {non_empty} □ {empty}"
+        )?;
+        let unchanged = self.unchanged.load(Ordering::Relaxed);
+        if unchanged > 0 {
+            write!(f, " = {unchanged}")?;
+        }
+        if failed > 0 {
+            write!(f, " ✗ {failed}")?;
+        }
+        let existing = self.existing.load(Ordering::Relaxed);
+        if existing > 0 {
+            write!(f, " ↷ {existing}")?;
+        }
+        let pruned = self.pruned.load(Ordering::Relaxed);
+        if pruned > 0 {
+            write!(f, " ✂ {pruned}")?;
+        }
+
+        match (self.total == done, eta) {
+            (true, _) => write!(f, " | done"),
+            (false, Some(left)) => write!(f, " | {left:.0?} left"),
+            (false, None) => write!(f, " | ??? left"),
+        }
+    }
+}
+
+#[allow(clippy::too_many_lines)]
+async fn run_tile_copy(
+    mut args: CopyArgs,
+    state: ServerState,
+    summary: &mut Option<serde_json::Value>,
+    interrupted: Arc<AtomicBool>,
+) -> MartinCpResult<()> {
+    let source_ids = args.source.join(",");
+    let (sources, _use_url_query, info) = state.tiles.get_sources(&source_ids, None)?;
+    let sources = sources.as_slice();
+    if args.bbox_crs != BboxCrs::Epsg4326 {
+        args.bbox = args
+            .bbox
+            .iter()
+            .map(|v| args.bbox_crs.to_wgs84(*v))
+            .collect();
+        for bbox in &args.bbox {
+            info!("Copying the tiles within {bbox} in longitudes and latitudes");
+        }
+    }
+    apply_tilejson_defaults(&mut args, &merge_tilejson(sources, String::new()))?;
+    let concurrency = args.concurrency.clone().unwrap_or_default();
+    // Each zoom level with its own concurrency waits for a permit, while the overall limit is the highest one
+    let zoom_permits: Option<Vec<_>> = (!concurrency.zooms.is_empty()).then(|| {
+        (0..=u8::MAX)
+            .map(|z| Semaphore::new(concurrency.for_zoom(z)))
+            .collect()
+    });
+    let zoom_permits = zoom_permits.as_deref();
+    let tile_info = sources.first().unwrap().get_tile_info();
+    let queue_size = args.queue_size.unwrap_or(QUEUE_SIZE_DEFAULT);
+    let (tx, mut rx) = channel::<TileXyz>(queue_size as usize);
+    let mask = args
+        .geojson_mask
+        .as_deref()
+        .map(GeoMask::read)
+        .transpose()?;
+    let tiles = match &args.tile_list {
+        Some(path) => read_tile_list(path)?,
+        None => compute_tile_ranges(&args, mask.as_ref()),
+    };
+    if args.build_overviews {
+        args.downsample_from = tiles.iter().map(|v| v.zoom).max();
+        if let Some(zoom) = args.downsample_from {
+            info!("Building the overviews below zoom {zoom} from its tiles");
+        }
+    }
+    let filter = args.mvt_filter(tile_info)?;
+    let filter = filter.as_ref();
+    let recompress = args.recompression(tile_info)?;
+    if args.prune_empty && !args.order.lowest_zoom_first(&tiles) {
+        return Err(MartinCpError::PruneNotSupported);
+    }
+    if args.downsample_from.is_some() && tile_info.format != Format::Png {
+        return Err(MartinCpError::DownsampleNotSupported(tile_info));
+    }
+    if args.dry_run {
+        let estimate = DryRun::estimate(&args, tiles, sources, info, (filter, recompress)).await?;
+        print!("{estimate}");
+        return Ok(());
+    }
+    if args.benchmark {
+        let benchmark = Benchmark::run(&args, &tiles, sources, info, (filter, recompress)).await?;
+        print!("{benchmark}");
+        return Ok(());
+    }
+    let mut output = match (&args.output_dir, &args.output_file) {
+        (Some(dir), _) => match dir.to_str().filter(|v| v.starts_with("s3://")) {
+            Some(url) => Output::S3(S3Output::new(url, &args, tile_info, &OsEnv::default())?),
+            None => Output::Dir(TileDir::new(dir, tile_info)),
+        },
+        (None, Some(output_file)) => {
+            Output::Mbtiles(MbtOutput::open(&args, output_file, sources, tile_info, filter).await?)
+        }
+        (None, None) => unreachable!("clap requires --output-file or --output-dir"),
+    };
+    if args.downsample_from.is_some() && matches!(output, Output::S3(_)) {
+        return Err(MartinCpError::DownsampleNotSupportedForS3);
+    }
+    let query = args.url_query.as_deref();
+    let (accept_encoding, headers) = args.request_headers()?;
+    let encodings = Some(&accept_encoding);
+    let headers = &headers;
+
+    let job = Checkpoint::job_id(&args, &tiles);
+    let (mut diff, skipped, existing) = match &mut output {
+        Output::Mbtiles(out) => {
+            let MbtOutput {
+                mbt,
+                conn,
+                mbt_type,
+                ..
+            } = out;
+            let diff = match &args.diff_with {
+                Some(path) => Some(TileDiff::open(path, mbt, *mbt_type).await?),
+                None if args.verify => Some(TileDiff::verify(mbt, *mbt_type)),
+                None => None,
+            };
+            let skipped = if args.resume {
+                Checkpoint::load(mbt, conn, &job).await?
+            } else {
+                0
+            };
+            let existing = if args.skip_existing {
+                let mut zooms = tiles.iter().map(|v| v.zoom).collect::<Vec<_>>();
+                zooms.sort_unstable();
+                zooms.dedup();
+                let existing = mbt.get_tile_coords(conn, *mbt_type, &zooms).await?;
+                info!(
+                    "Skipping the existing tiles, the output file has {} tiles at the copied zoom levels",
+                    existing.len()
+                );
+                existing
+            } else {
+                HashSet::new()
+            };
+            (diff, skipped, existing)
+        }
+        Output::Dir(_) | Output::S3(_) => (None, 0, HashSet::new()),
+    };
+    let existing = &existing;
+    let retries = args.retries;
+    let retry_delay = args.retry_delay.unwrap_or(RETRY_DELAY_DEFAULT);
+    let throttle = &Throttle::new(&args);
+    let failed = args.failed_tiles_log.clone().map(FailedTiles::new);
+    let failed = failed.as_ref();
+    let deadline = args.max_duration.map(|v| Instant::now() + v);
+    stop_on_signal(interrupted.clone());
+    let interrupted = &*interrupted;
+    let stopped = &AtomicBool::new(false);
+    let mut progress = Progress::new(&tiles, skipped);
+    progress.json = args.progress_output()?.map(Mutex::new);
+    let progress_ref = &progress;
+    info!(
+        "Copying {} {tile_info} tiles from {} to {}",
+        progress.total,
+        source_ids,
+        args.output_name()
+    );
+
+    let zooms = tiles.iter().map(|v| v.zoom);
+    let copied_zooms = (zooms.clone().min(), zooms.max());
+    let (tiles, downsampled): (Vec<_>, Vec<_>) = tiles
+        .into_iter()
+        .partition(|v| args.downsample_from.map_or(true, |z| v.zoom >= z));
+    let mut pruner = args.prune_empty.then(|| Pruner::new(&tiles));
+    let order = args.order;
+    try_join!(
+        async move {
+            let mut tiles =
+                remaining_tiles(tiles, order, skipped, (deadline, interrupted, stopped)).peekable();
+            // Pruning needs all tiles of a zoom level before the next one starts, otherwise all tiles are streamed at once
+            while let Some(first) = tiles.peek().copied() {
+                if let Some(pruner) = &mut pruner {
+                    pruner.start_zoom(first.z, &sources_for_zoom(sources, first.z));
+                }
+                let zoom_pruner = pruner.as_ref();
+                let group =
+                    iter::from_fn(|| tiles.next_if(|v| zoom_pruner.is_none() || v.z == first.z))
+                        .filter(|xyz| {
+                            if zoom_pruner.map_or(false, |p| p.is_pruned(*xyz)) {
+                                progress_ref.pruned.fetch_add(1, Ordering::Relaxed);
+                                return false;
+                            }
+                            let exists = existing.contains(&(xyz.z, xyz.x, xyz.y));
+                            if exists {
+                                progress_ref.existing.fetch_add(1, Ordering::Relaxed);
+                                if let Some(pruner) = zoom_pruner {
+                                    pruner.add(*xyz);
+                                }
+                            }
+                            !exists
+                        });
+                stream::iter(group)
+                    .map(MartinResult::Ok)
+                    .try_for_each_concurrent(concurrency.max(), |xyz| {
+                        let tx = tx.clone();
+                        async move {
+                            let _zoom_permit = match zoom_permits {
+                                Some(permits) => Some(
+                                    permits[usize::from(xyz.z)]
+                                        .acquire()
+                                        .await
+                                        .expect("the semaphore is never closed"),
+                                ),
+                                None => None,
+                            };
+                            let sources = sources_for_zoom(sources, xyz.z);
+                            if sources.is_empty() {
+                                let data = TileData::new();
+                                return send_tile(&tx, xyz, data, Encoding::Uncompressed).await;
+                            }
+                            let sources = sources.as_slice();
+                            let tile = with_retries(xyz, retries, retry_delay, || async move {
+                                let _permit = throttle.acquire(sources.len()).await;
+                                let tile = get_tile_content(sources, info, &xyz, query, encodings);
+                                with_headers(headers.clone(), tile).await
+                            });
+                            let tile = match (tile.await, failed) {
+                                (Ok(tile), _) => tile,
+                                (Err(e), Some(failed)) => {
+                                    warn!("Skipping tile {xyz:#} after {retries} retries: {e}");
+                                    progress_ref.failed.fetch_add(1, Ordering::Relaxed);
+                                    failed.add(xyz, &e);
+                                    // The tile may have data, so its children are generated
+                                    if let Some(pruner) = zoom_pruner {
+                                        pruner.add(xyz);
+                                    }
+                                    return Ok(());
+                                }
+                                (Err(e), None) => return Err(e.into()),
+                            };
+                            let encoding =
+                                recompress.map_or(tile.info.encoding, |v| v.encoding.encoding());
+                            let data = transform_tile(tile, filter, recompress)?;
+                            if !data.is_empty() {
+                                if let Some(pruner) = zoom_pruner {
+                                    pruner.add(xyz);
+                                }
+                            }
+                            send_tile(&tx, xyz, data, encoding).await
+                        }
+                    })
+                    .await?;
+                if let Some(pruner) = &mut pruner {
+                    pruner.finish_zoom();
+                }
+            }
+            MartinResult::Ok(())
+        }
+        .err_into(),
+        async {
+            match &mut output {
+                Output::Mbtiles(out) => {
+                    save_tiles(
+                        &mut rx,
+                        &out.mbt,
+                        &mut out.conn,
+                        (out.mbt_type, args.on_duplicate),
+                        (
+                            args.batch_size.unwrap_or(BATCH_SIZE_DEFAULT) as usize,
+                            args.commit_interval.unwrap_or(COMMIT_INTERVAL_DEFAULT),
+                        ),
+                        &progress,
+                        diff.as_mut(),
+                    )
+                    .await?;
+                    Ok(())
+                }
+                Output::Dir(dir) => dir.save_tiles(&mut rx, &progress).await,
+                Output::S3(s3) => s3.save_tiles(&mut rx, &progress).await,
+            }
+        }
+    )?;
+
+    if !downsampled.is_empty() {
+        if stopped.load(Ordering::Relaxed) {
+            info!("Skipping the downsampling, as not all tiles of the higher zoom levels were generated");
+        } else {
+            downsample_tiles(&mut output, downsampled, &args, &progress, interrupted).await?;
+        }
+    }
+
+    progress.report(true);
+    *summary = Some(progress.to_json(true));
+    if let Some(failed) = failed {
+        failed.write()?;
+    }
+    match &mut output {
+        Output::Mbtiles(out) => {
+            if let Some(diff) = diff {
+                if diff.verify {
+                    return diff.verified(args.verify_report.as_deref());
+                }
+                diff.finish(&out.mbt, &mut out.conn, args.changed_tiles.as_deref())
+                    .await?;
+            }
+            let checkpoint = stopped.load(Ordering::Relaxed).then(|| Checkpoint {
+                job,
+                done: skipped + progress.done(),
+            });
+            finish_copy(args, &out.mbt, &mut out.conn, &progress, checkpoint).await?;
+        }
+        output => {
+            if stopped.load(Ordering::Relaxed) {
+                info!(
+                    "Stopped before all tiles were generated, {} tiles are left",
+                    progress.total - progress.done()
+                );
+            }
+            if !args.skip_metadata_json {
+                let mut tj = output_tilejson(&args, sources, tile_info, filter);
+                tj.tiles = vec![url_template(tile_info.format)];
+                (tj.minzoom, tj.maxzoom) = copied_zooms;
+                for (key, value) in args.set_meta {
+                    tj.other.insert(key, serde_json::Value::String(value));
+                }
+                output.write_metadata(&tj).await?;
+            }
+        }
+    }
+    if interrupted.load(Ordering::Relaxed) {
+        Err(MartinCpError::Interrupted)
+    } else {
+        Ok(())
+    }
+}
+
+/// Stop generating new tiles on the first SIGINT or SIGTERM, so that the tiles in progress are saved
+/// and the progress is recorded, and exit immediately on the second one
+fn stop_on_signal(interrupted: Arc<AtomicBool>) {
+    tokio::spawn(async move {
+        loop {
+            match shutdown_signal().await {
+                Ok(name) if !interrupted.swap(true, Ordering::Relaxed) => {
+                    warn!("Received {name}, saving the tiles in progress before exiting. Send it again to exit immediately.");
+                }
+                Ok(name) => {
+                    error!("Received {name} again, exiting without saving the tiles in progress");
+                    std::process::exit(130);
+                }
+                Err(e) => {
+                    warn!("Unable to listen for shutdown signals: {e}");
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// Wait for SIGINT or SIGTERM, and return the name of the received signal
+async fn shutdown_signal() -> std::io::Result<&'static str> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            v = tokio::signal::ctrl_c() => v.map(|()| "SIGINT"),
+            _ = terminate.recv() => Ok("SIGTERM"),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.map(|()| "Ctrl+C")
+}
+
+/// Non-empty tiles of a zoom level whose tiles were all generated
+struct ParentZoom {
+    zoom: u8,
+    /// Ids of the sources that have tiles at this zoom level
+    sources: Vec<String>,
+    non_empty: HashSet<(u32, u32)>,
+}
+
+/// Non-empty tiles of the last generated zoom level, used to skip the tiles inside of empty tiles, see `--prune-empty`
+struct Pruner {
+    /// Number of tiles of each zoom level
+    totals: BTreeMap<u8, u64>,
+    /// The last zoom level whose tiles were all generated
+    parent: Option<ParentZoom>,
+    zoom: u8,
+    sources: Vec<String>,
+    /// Tiles of the current zoom level that were generated or skipped
+    visited: AtomicU64,
+    non_empty: Mutex<HashSet<(u32, u32)>>,
+}
+
+impl Pruner {
+    fn new(tiles: &[TileRect]) -> Self {
+        let mut totals = BTreeMap::new();
+        for rect in tiles {
+            *totals.entry(rect.zoom).or_default() += rect.size();
+        }
+        Self {
+            totals,
+            parent: None,
+            zoom: 0,
+            sources: Vec::new(),
+            visited: AtomicU64::default(),
+            non_empty: Mutex::default(),
+        }
+    }
+
+    /// Start generating the tiles of a zoom level from the given sources
+    fn start_zoom(&mut self, zoom: u8, sources: &[&dyn Source]) {
+        self.zoom = zoom;
+        self.sources = sources.iter().map(|v| v.get_id().to_string()).collect();
+        *self.visited.get_mut() = 0;
+        self.non_empty
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// True if the tile is inside of an empty tile of the parent zoom level.
+    /// A source that has no tiles at the parent zoom level may still have data in them, so nothing is pruned then.
+    fn is_pruned(&self, xyz: TileCoord) -> bool {
+        self.visited.fetch_add(1, Ordering::Relaxed);
+        let Some(parent) = &self.parent else {
+            return false;
+        };
+        let Some(shift) = xyz.z.checked_sub(parent.zoom) else {
+            return false;
+        };
+        self.sources.iter().all(|v| parent.sources.contains(v))
+            && !parent.non_empty.contains(&(xyz.x >> shift, xyz.y >> shift))
+    }
+
+    /// Record a tile of the current zoom level that has data
+    fn add(&self, xyz: TileCoord) {
+        self.non_empty
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert((xyz.x, xyz.y));
+    }
+
+    /// Use the current zoom level as the parent of the next ones if all of its tiles were visited,
+    /// i.e. none of them were generated by a previous run, see `--resume`
+    fn finish_zoom(&mut self) {
+        let total = self.totals.get(&self.zoom).copied().unwrap_or_default();
+        if *self.visited.get_mut() == total {
+            let non_empty = self
+                .non_empty
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner);
+            self.parent = Some(ParentZoom {
+                zoom: self.zoom,
+                sources: std::mem::take(&mut self.sources),
+                non_empty: std::mem::take(non_empty),
+            });
+        }
+    }
+}
+
+/// Sources that have tiles at the zoom level. A tile is empty if none of the sources have it.
+fn sources_for_zoom<'a>(sources: &[&'a dyn Source], zoom: u8) -> Vec<&'a dyn Source> {
+    sources
+        .iter()
+        .copied()
+        .filter(|src| src.is_valid_zoom(zoom))
+        .collect()
+}
+
+async fn send_tile(
+    tx: &Sender<TileXyz>,
+    xyz: TileCoord,
+    data: TileData,
+    encoding: Encoding,
+) -> MartinResult<()> {
+    tx.send(TileXyz {
+        xyz,
+        data,
+        encoding,
+    })
+    .await
+    .map_err(|e| MartinError::InternalError(e.into()))
+}
+
+/// Write the generated tiles to the output file in batches, except the ones that are the same as in the `--diff-with` file.
+/// A batch is written once it has `batch_size` tiles, or `commit_interval` after the previous one.
+async fn save_tiles(
+    rx: &mut Receiver<TileXyz>,
+    mbt: &Mbtiles,
+    conn: &mut SqliteConnection,
+    (mbt_type, on_duplicate): (MbtType, CopyDuplicateMode),
+    (batch_size, commit_interval): (usize, Duration),
+    progress: &Progress,
+    mut diff: Option<&mut TileDiff>,
+) -> MartinResult<()> {
+    let mut last_saved = Instant::now();
+    let mut last_reported = Instant::now();
+    let mut batch = Vec::with_capacity(batch_size);
+    while let Some(tile) = rx.recv().await {
+        debug!("Generated tile {tile:?}");
+        let changed = match &mut diff {
+            Some(diff) => diff.is_changed(conn, &tile).await?,
+            None => true,
+        };
+        let write = changed && !diff.as_ref().map_or(false, |v| v.verify);
+        if !changed {
+            progress.unchanged.fetch_add(1, Ordering::Relaxed);
+        }
+        let done = if tile.data.is_empty() {
+            progress.empty.fetch_add(1, Ordering::Relaxed)
+        } else {
+            if write {
+                batch.push((tile.xyz.z, tile.xyz.x, tile.xyz.y, tile.data.into()));
+            }
+            if batch.len() >= batch_size || last_saved.elapsed() > commit_interval {
+                mbt.insert_tiles(conn, mbt_type, on_duplicate, &batch)
+                    .await?;
+                batch.clear();
+                last_saved = Instant::now();
+            }
+            progress.non_empty.fetch_add(1, Ordering::Relaxed)
+        };
+        if done % PROGRESS_REPORT_AFTER == (PROGRESS_REPORT_AFTER - 1)
+            && last_reported.elapsed() > PROGRESS_REPORT_EVERY
+        {
+            progress.report(false);
+            last_reported = Instant::now();
+        }
+    }
+    if !batch.is_empty() {
+        mbt.insert_tiles(conn, mbt_type, on_duplicate, &batch)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Where the copied tiles are written
+enum Output {
+    Mbtiles(MbtOutput),
+    Dir(TileDir),
+    S3(S3Output),
+}
+
+/// The output mbtiles file, locked for writing unless it is only verified
+struct MbtOutput {
+    mbt: Mbtiles,
+    conn: SqliteConnection,
+    mbt_type: MbtType,
+    _lock: Option<WriteLock>,
+}
+
+impl MbtOutput {
+    async fn open(
+        args: &CopyArgs,
+        path: &Path,
+        sources: &[&dyn Source],
+        tile_info: TileInfo,
+        filter: Option<&MvtFilter>,
+    ) -> MartinCpResult<Self> {
+        let mbt = Mbtiles::new(path)?;
+        let (lock, conn, mbt_type) = if args.verify {
+            let mut conn = mbt.open_readonly().await?;
+            let mbt_type = mbt.detect_type(&mut conn).await?;
+            (None, conn, mbt_type)
+        } else {
+            let lock = mbt.lock_for_writing(args.force)?;
+            let mut conn = mbt.open_or_new().await?;
+            let mbt_type = init_schema(&mbt, &mut conn, args, sources, tile_info, filter).await?;
+            (Some(lock), conn, mbt_type)
+        };
+        Ok(Self {
+            mbt,
+            conn,
+            mbt_type,
+            _lock: lock,
+        })
+    }
+}
+
+impl Output {
+    async fn read_tile(&mut self, xyz: TileCoord) -> MartinCpResult<Option<Vec<u8>>> {
+        match self {
+            Self::Mbtiles(out) => Ok(out.mbt.get_tile(&mut out.conn, xyz.z, xyz.x, xyz.y).await?),
+            Self::Dir(dir) => dir.read_tile(xyz).await,
+            Self::S3(_) => Err(MartinCpError::DownsampleNotSupportedForS3),
+        }
+    }
+
+    async fn write_tiles(
+        &mut self,
+        tiles: &[(u8, u32, u32, Vec<u8>)],
+        on_duplicate: CopyDuplicateMode,
+    ) -> MartinCpResult<()> {
+        match self {
+            Self::Mbtiles(out) => {
+                out.mbt
+                    .insert_tiles(&mut out.conn, out.mbt_type, on_duplicate, tiles)
+                    .await?;
+            }
+            Self::Dir(dir) => {
+                for (z, x, y, data) in tiles {
+                    dir.write_tile(
+                        TileCoord {
+                            z: *z,
+                            x: *x,
+                            y: *y,
+                        },
+                        data,
+                    )
+                    .await?;
+                }
+            }
+            Self::S3(_) => Err(MartinCpError::DownsampleNotSupportedForS3)?,
+        }
+        Ok(())
+    }
+
+    /// Write the `metadata.json` of a directory output
+    async fn write_metadata(&self, tilejson: &TileJSON) -> MartinCpResult<()> {
+        let json = serde_json::to_vec_pretty(tilejson).expect("TileJSON is serializable");
+        match self {
+            Self::Mbtiles(_) => {
+                unreachable!("the metadata of an mbtiles file is written when it is created")
+            }
+            Self::Dir(dir) => dir.write_metadata(&json).await,
+            Self::S3(s3) => {
+                info!(
+                    "Uploading the TileJSON to {}",
+                    s3.bucket.object_name("metadata.json")
+                );
+                let headers = [("Content-Type", "application/json")];
+                s3.bucket
+                    .put_object("metadata.json", &json, &headers)
+                    .await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Record the progress if the copy was stopped early, and update the metadata
+async fn finish_copy(
+    args: CopyArgs,
+    mbt: &Mbtiles,
+    conn: &mut SqliteConnection,
+    progress: &Progress,
+    checkpoint: Option<Checkpoint>,
+) -> MartinCpResult<()> {
+    if let Some(checkpoint) = checkpoint {
+        info!(
+            "Stopped before all tiles were generated, {} tiles are left. Run again with --resume to continue.",
+            progress.total - progress.done()
+        );
+        checkpoint.save(mbt, &mut *conn).await?;
+    } else {
+        mbt.delete_metadata_value(&mut *conn, CHECKPOINT_KEY)
+            .await?;
+    }
+
+    for (key, value) in args.set_meta {
+        info!("Setting metadata key={key} value={value}");
+        mbt.set_metadata_value(&mut *conn, &key, value).await?;
+    }
+
+    if !args.skip_agg_tiles_hash {
+        if progress.non_empty.load(Ordering::Relaxed) == 0 {
+            info!("No tiles were copied, skipping agg_tiles_hash computation");
+        } else {
+            info!("Computing agg_tiles_hash value...");
+            mbt.update_agg_tiles_hash(&mut *conn).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn init_schema(
+    mbt: &Mbtiles,
+    conn: &mut SqliteConnection,
+    args: &CopyArgs,
+    sources: &[&dyn Source],
+    tile_info: TileInfo,
+    filter: Option<&MvtFilter>,
+) -> Result<MbtType, MartinError> {
+    Ok(if is_empty_database(&mut *conn).await? {
+        let mbt_type = match args.mbt_type.unwrap_or(MbtTypeCli::Normalized) {
+            MbtTypeCli::Flat => MbtType::Flat,
+            MbtTypeCli::FlatWithHash => MbtType::FlatWithHash,
+            MbtTypeCli::Normalized => MbtType::Normalized { hash_view: true },
+        };
+        init_mbtiles_schema(&mut *conn, mbt_type).await?;
+        let tj = output_tilejson(args, sources, tile_info, filter);
+        mbt.insert_metadata(&mut *conn, &tj).await?;
+        mbt_type
+    } else {
+        if args.name.is_some()
+            || args.description.is_some()
+            || args.attribution.is_some()
+            || !args.set_vector_layer.is_empty()
+        {
+            warn!("The TileJSON of an existing file is not changed, use --set-meta to change its metadata values");
+        }
+        mbt.detect_type(&mut *conn).await?
+    })
+}
+
+/// `TileJSON` of the copied tiles, with the layers removed by the filter, the tile format,
+/// and the values set by the arguments
+fn output_tilejson(
+    args: &CopyArgs,
+    sources: &[&dyn Source],
+    tile_info: TileInfo,
+    filter: Option<&MvtFilter>,
+) -> TileJSON {
+    let mut tj = merge_tilejson(sources, String::new());
+    if let (Some(filter), Some(layers)) = (filter, &mut tj.vector_layers) {
+        filter.apply_vector_layers(layers);
+    }
+    for (field, value) in [
+        (&mut tj.name, &args.name),
+        (&mut tj.description, &args.description),
+        (&mut tj.attribution, &args.attribution),
+    ] {
+        if value.is_some() {
+            field.clone_from(value);
+        }
+    }
+    for (id, value) in &args.set_vector_layer {
+        let layers = tj.vector_layers.get_or_insert_with(Vec::new);
+        if let Err(e) = set_vector_layer(layers, id, value) {
+            warn!("Unable to set the vector layer {id}: {e}");
+        }
+    }
+    tj.other.insert(
+        "format".to_string(),
+        serde_json::Value::String(tile_info.format.to_string()),
+    );
+    tj.other.insert(
+        "generator".to_string(),
+        serde_json::Value::String(format!("martin-cp v{VERSION}")),
+    );
+    tj
+}
+
+#[actix_web::main]
+async fn main() {
+    let env = env_logger::Env::default().default_filter_or("martin_cp=info");
+    env_logger::Builder::from_env(env).init();
+
+    let args = CopierArgs::parse();
+    let notify_url = args.copy.notify_url.clone();
+    let job = (args.copy.source.join(","), args.copy.output_name());
+    let mut summary = None;
+    let result = start(args, &mut summary, Arc::default()).await;
+    if let Some(url) = notify_url {
+        notify(
+            &url,
+            &notification(&job.0, &job.1, &result, summary.as_ref()),
+        )
+        .await;
+    }
+    result.unwrap_or_else(|e| on_error(e));
+}
+
+fn on_error<E: Display>(e: E) -> ! {
+    // Ensure the message is printed, even if the logging is disabled
+    if log_enabled!(log::Level::Error) {
+        error!("{e}");
+    } else {
+        eprintln!("{e}");
+    }
+    std::process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use insta::assert_yaml_snapshot;
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_existing_tiles() {
+        let mbt = Mbtiles::new("../tests/fixtures/mbtiles/world_cities.mbtiles").unwrap();
+        let mut conn = mbt.open_readonly().await.unwrap();
+        let existing = mbt
+            .get_tile_coords(&mut conn, MbtType::Flat, &[0, 2])
+            .await
+            .unwrap();
+        assert_eq!(existing.len(), 8);
+        assert!(existing.contains(&(0, 0, 0)));
+        // Rows are flipped to XYZ
+        assert!(existing.contains(&(2, 0, 1)));
+        assert!(!existing.contains(&(2, 0, 2)));
+        assert!(!existing.contains(&(1, 0, 0)));
+    }
+
+    #[test]
+    fn test_progress_json() {
+        let tiles = compute_tile_ranges(&args(&[Bounds::MAX_TILED], &[0, 1]), None);
+        let progress = Progress::new(&tiles, 0);
+        let record = progress.to_json(false);
+        assert_eq!(record["total"], 5);
+        assert_eq!(record["done"], 0);
+        assert!(record["eta"].is_null());
+
+        progress.non_empty.fetch_add(2, Ordering::Relaxed);
+        progress.empty.fetch_add(1, Ordering::Relaxed);
+        progress.pruned.fetch_add(2, Ordering::Relaxed);
+        let record = progress.to_json(true);
+        assert_eq!(record["done"], 5);
+        assert_eq!(record["empty"], 1);
+        assert_eq!(record["eta"], 0.0);
+        assert_eq!(record["last"], true);
+        assert!(progress.to_string().ends_with(" | done"));
+    }
+
+    #[test]
+    fn test_pruner() {
+        let tiles = compute_tile_ranges(&args(&[Bounds::MAX_TILED], &[0, 1, 3]), None);
+        let xyz = |z, x, y| TileCoord { z, x, y };
+        let mut pruner = Pruner::new(&tiles);
+        pruner.start_zoom(0, &[]);
+        assert!(!pruner.is_pruned(xyz(0, 0, 0)));
+        pruner.add(xyz(0, 0, 0));
+        pruner.finish_zoom();
+
+        pruner.start_zoom(1, &[]);
+        for (x, y) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+            assert!(!pruner.is_pruned(xyz(1, x, y)));
+        }
+        pruner.add(xyz(1, 1, 0));
+        pruner.finish_zoom();
+
+        // Zoom 2 is not copied, so the tiles of zoom 3 are compared with their ancestors at zoom 1
+        pruner.start_zoom(3, &[]);
+        assert!(!pruner.is_pruned(xyz(3, 4, 0)));
+        assert!(!pruner.is_pruned(xyz(3, 7, 3)));
+        assert!(pruner.is_pruned(xyz(3, 3, 0)));
+        assert!(pruner.is_pruned(xyz(3, 4, 4)));
+
+        // A partially visited zoom level does not replace the parent
+        pruner.add(xyz(3, 0, 0));
+        pruner.finish_zoom();
+        assert_eq!(pruner.parent.as_ref().unwrap().zoom, 1);
+    }
+
+    #[test]
+    fn test_parse_sources() {
+        let args = CopierArgs::try_parse_from([
+            "martin-cp",
+            "--source",
+            "a,b",
+            "--source",
+            "c",
+            "--output-file",
+            "out.mbtiles",
+            "--max-zoom",
+            "2",
+        ])
+        .unwrap();
+        assert_eq!(args.copy.source, vec!["a", "b", "c"]);
+        assert!(CopierArgs::try_parse_from(["martin-cp", "-o", "out.mbtiles", "-z", "2"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_write_tuning() {
+        let parse = |args: &[&str]| {
+            let cmd = ["martin-cp", "-s", "a", "-o", "out.mbtiles", "-z", "2"];
+            CopierArgs::try_parse_from(cmd.iter().chain(args)).map(|v| v.copy)
+        };
+        let args = parse(&[
+            "--batch-size",
+            "5000",
+            "--queue-size",
+            "10000",
+            "--commit-interval",
+            "10s",
+        ])
+        .unwrap();
+        assert_eq!(args.batch_size, Some(5000));
+        assert_eq!(args.queue_size, Some(10000));
+        assert_eq!(args.commit_interval, Some(Duration::from_secs(10)));
+        assert!(parse(&["--batch-size", "0"]).is_err());
+        assert!(parse(&["--queue-size", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_set_vector_layer() {
+        let (id, value) =
+            parse_vector_layer(r#"roads={"description": "Roads", "maxzoom": 12}"#).unwrap();
+        assert_eq!(id, "roads");
+        let mut layers = vec![VectorLayer::new(
+            "roads".to_string(),
+            [("kind".to_string(), "String".to_string())].into(),
+        )];
+        set_vector_layer(&mut layers, &id, &value).unwrap();
+        let (id, value) = parse_vector_layer(r#"water={"fields": {"name": "String"}}"#).unwrap();
+        set_vector_layer(&mut layers, &id, &value).unwrap();
+        assert_eq!(
+            serde_json::to_value(&layers).unwrap(),
+            serde_json::json!([
+                {"id": "roads", "fields": {"kind": "String"}, "description": "Roads", "maxzoom": 12},
+                {"id": "water", "fields": {"name": "String"}}
+            ])
+        );
+        for invalid in [
+            "roads",
+            "roads=[]",
+            "={}",
+            r#"roads={"fields": 1}"#,
+            "roads={",
+        ] {
+            assert!(parse_vector_layer(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(
+            parse_header("X-Tenant: acme"),
+            Ok(("X-Tenant".to_string(), "acme".to_string()))
+        );
+        assert_eq!(
+            parse_header("x-url:http://a/b"),
+            Ok(("x-url".to_string(), "http://a/b".to_string()))
+        );
+        assert!(parse_header("X-Tenant").is_err());
+        assert!(parse_header("Bad Name: acme").is_err());
+        assert!(parse_header(": acme").is_err());
+    }
+
+    #[test]
+    fn test_tile_list() {
+        let text =
+            "# changed tiles\n3/2/1\n\n3/4/1\n3/3/1\n0/0/0\t(error message)\n3/3/1\n 3/2/2 \n";
+        let tiles = parse_tile_list(text).unwrap();
+        assert_eq!(tiles.len(), 6);
+        assert_yaml_snapshot!(merge_tiles(tiles), @r###"
+        ---
+        - "0: (0,0) - (0,0)"
+        - "3: (2,1) - (4,1)"
+        - "3: (2,2) - (2,2)"
+        "###);
+
+        assert_eq!(
+            parse_tile_list("1/0/0\n1/2/0\n"),
+            Err(("tile 1/2/0 is out of range".to_string(), 2))
+        );
+        assert!(parse_tile_list("1/0").is_err());
+        assert!(parse_tile_list("1/0/0/0").is_err());
+        assert!(parse_tile_list("1/0/0.pbf").is_err());
+        assert!(parse_tile_list("31/0/0").is_err());
+    }
+
+    #[test]
+    fn test_compute_tile_ranges() {
+        let world = Bounds::MAX_TILED;
+        let bbox_ca = Bounds::from_str("-124.482,32.5288,-114.1307,42.0095").unwrap();
+        let bbox_ca_south = Bounds::from_str("-118.6681,32.5288,-114.1307,34.8233").unwrap();
+        let bbox_mi = Bounds::from_str("-86.6271,41.6811,-82.3095,45.8058").unwrap();
+        let bbox_usa = Bounds::from_str("-124.8489,24.3963,-66.8854,49.3843").unwrap();
+
+        assert_yaml_snapshot!(compute_tile_ranges(&args(&[world], &[0]), None), @r###"
+        ---
+        - "0: (0,0) - (0,0)"
+        "###);
+
+        assert_yaml_snapshot!(compute_tile_ranges(&args(&[world], &[3,7]), None), @r###"
+        ---
+        - "3: (0,0) - (7,7)"
+        - "7: (0,0) - (127,127)"
+        "###);
+
+        assert_yaml_snapshot!(compute_tile_ranges(&arg_minmax(&[world], 2, 4), None), @r###"
+        ---
+        - "2: (0,0) - (3,3)"
+        - "3: (0,0) - (7,7)"
+        - "4: (0,0) - (15,15)"
+        "###);
+
+        assert_yaml_snapshot!(compute_tile_ranges(&args(&[world], &[14]), None), @r###"
+        ---
+        - "14: (0,0) - (16383,16383)"
+        "###);
+
+        assert_yaml_snapshot!(compute_tile_ranges(&args(&[bbox_usa], &[14]), None), @r###"
+        ---
+        - "14: (2509,5599) - (5147,7046)"
+        "###);
+
+        assert_yaml_snapshot!(compute_tile_ranges(&args(&[bbox_usa, bbox_mi, bbox_ca], &[14]), None), @r###"
+        ---
+        - "14: (2509,5599) - (5147,7046)"
+        "###);
+
+        assert_yaml_snapshot!(compute_tile_ranges(&args(&[bbox_ca_south, bbox_mi, bbox_ca], &[14]), None), @r###"
+        ---
+        - "14: (2791,6499) - (2997,6624)"
+        - "14: (4249,5841) - (4446,6101)"
+        - "14: (2526,6081) - (2790,6624)"
+        - "14: (2791,6081) - (2997,6498)"
+        "###);
+    }
+
+    #[test]
+    fn test_bbox_crs() {
+        let parse = |args: &[&str]| {
+            let cmd = ["martin-cp", "-s", "a", "-o", "out.mbtiles", "-z", "2"];
+            CopierArgs::try_parse_from(cmd.iter().chain(args)).map(|v| v.copy)
+        };
+        let args = parse(&["--bbox", "0,0,1,1"]).unwrap();
+        assert_eq!(args.bbox_crs, BboxCrs::Epsg4326);
+        let args = parse(&["--bbox", "0,0,1,1", "--bbox-crs", "epsg:900913"]).unwrap();
+        assert_eq!(args.bbox_crs, BboxCrs::Epsg3857);
+        assert!(parse(&["--bbox-crs", "EPSG:3857"]).is_err());
+        assert!(parse(&["--bbox", "0,0,1,1", "--bbox-crs", "EPSG:2056"]).is_err());
+
+        let bounds = BboxCrs::Epsg3857.to_wgs84(Bounds::new(
+            -20_037_508.34,
+            -30_000_000.0,
+            1_113_194.91,
+            6_446_275.84,
+        ));
+        let expected = Bounds::new(-180.0, Bounds::MAX_TILED.bottom, 10.0, 50.0);
+        let values = |b: Bounds| [b.left, b.bottom, b.right, b.top];
+        for (value, expected) in values(bounds).iter().zip(values(expected)) {
+            assert!((value - expected).abs() < 1e-6, "{bounds} != {expected}");
+        }
+    }
+
+    #[test]
+    fn test_tilejson_defaults() {
+        let tj = tilejson::tilejson! {
+            tiles: vec![],
+            minzoom: 2,
+            maxzoom: 8,
+            bounds: Bounds::new(-180.0, -90.0, 10.0, 20.0),
+        };
+        let mut copy = CopyArgs::default();
+        apply_tilejson_defaults(&mut copy, &tj).unwrap();
+        assert_eq!((copy.min_zoom, copy.max_zoom), (Some(2), Some(8)));
+        assert_eq!(
+            copy.bbox,
+            vec![Bounds::new(-180.0, Bounds::MAX_TILED.bottom, 10.0, 20.0)]
+        );
+
+        // The options that were set are kept, and a minzoom above them is ignored
+        let mut copy = arg_minmax(&[Bounds::MAX_TILED], 0, 1);
+        apply_tilejson_defaults(&mut copy, &tj).unwrap();
+        assert_eq!((copy.min_zoom, copy.max_zoom), (Some(0), Some(1)));
+        assert_eq!(copy.bbox, vec![Bounds::MAX_TILED]);
+        let mut copy = CopyArgs {
+            max_zoom: Some(1),
+            ..Default::default()
+        };
+        apply_tilejson_defaults(&mut copy, &tj).unwrap();
+        assert_eq!((copy.min_zoom, copy.max_zoom), (None, Some(1)));
+        let mut copy = args(&[], &[3, 5]);
+        apply_tilejson_defaults(&mut copy, &tj).unwrap();
+        assert_eq!((copy.min_zoom, copy.max_zoom), (None, None));
+
+        let world =
+            tilejson::tilejson! { tiles: vec![], bounds: Bounds::new(-180.0, -90.0, 180.0, 90.0) };
+        assert!(matches!(
+            apply_tilejson_defaults(&mut CopyArgs::default(), &world),
+            Err(MartinCpError::NoMaxZoom)
+        ));
+        let mut copy = arg_minmax(&[], 0, 4);
+        apply_tilejson_defaults(&mut copy, &world).unwrap();
+        assert!(copy.bbox.is_empty(), "the whole world is the default");
+    }
+
+    fn args(bbox: &[Bounds], zooms: &[u8]) -> CopyArgs {
+        CopyArgs {
+            bbox: bbox.to_vec(),
+            zoom_levels: zooms.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn arg_minmax(bbox: &[Bounds], min_zoom: u8, max_zoom: u8) -> CopyArgs {
+        CopyArgs {
+            bbox: bbox.to_vec(),
+            min_zoom: Some(min_zoom),
+            max_zoom: Some(max_zoom),
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::time::Duration;
+
+use log::{info, warn};
+use martin::post_json;
+
+use crate::{MartinCpError, MartinCpResult};
+
+/// Maximum time to send the `--notify-url` notification, so that an unreachable webhook does not block the exit
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// JSON summary sent to `--notify-url`, with a `text` field shown by chat webhooks such as Slack
+pub fn notification(
+    source: &str,
+    output: &str,
+    result: &MartinCpResult<()>,
+    progress: Option<&serde_json::Value>,
+) -> serde_json::Value {
+    let count = |key| progress.and_then(|v| v[key].as_u64());
+    let (done, total) = (count("done"), count("total"));
+    let (status, text) = match (result, done, total) {
+        (Err(MartinCpError::Interrupted), _, _) => (
+            "interrupted",
+            format!("martin-cp was interrupted while copying {source} to {output}"),
+        ),
+        (Err(e), _, _) => (
+            "failed",
+            format!("martin-cp failed to copy {source} to {output}: {e}"),
+        ),
+        (Ok(()), Some(done), Some(total)) if done < total => (
+            "stopped",
+            format!("martin-cp stopped after copying {done} of {total} tiles of {source} to {output}, run it again with --resume to continue"),
+        ),
+        (Ok(()), Some(done), _) => (
+            "completed",
+            format!("martin-cp copied {done} tiles of {source} to {output}"),
+        ),
+        (Ok(()), None, _) => ("completed", format!("martin-cp finished with {source}")),
+    };
+    serde_json::json!({
+        "status": status,
+        "text": text,
+        "source": source,
+        "output": output,
+        "error": result.as_ref().err().map(ToString::to_string),
+        "progress": progress,
+    })
+}
+
+/// Post the notification to the webhook, only logging the errors so that they do not change the exit status
+pub async fn notify(url: &str, notification: &serde_json::Value) {
+    match tokio::time::timeout(NOTIFY_TIMEOUT, post_json(url, notification)).await {
+        Ok(Ok(status)) if status < 300 => info!("Sent the notification to {url}"),
+        Ok(Ok(status)) => warn!("The notification to {url} was rejected with status {status}"),
+        Ok(Err(e)) => warn!("{e}"),
+        Err(_) => warn!("Unable to send the notification to {url} within {NOTIFY_TIMEOUT:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use clap::Parser as _;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{start, CopierArgs};
+
+    #[test]
+    fn test_notification() {
+        let progress = serde_json::json!({"done": 5, "total": 8, "failed": 0});
+        let status = |result, progress| {
+            let v = notification("points", "out.mbtiles", &result, progress);
+            (v["status"].as_str().unwrap().to_string(), v)
+        };
+        let (name, v) = status(Ok(()), Some(&progress));
+        assert_eq!(name, "stopped");
+        assert_eq!(v["progress"]["done"], 5);
+        assert_eq!(v["error"], serde_json::Value::Null);
+        let done = serde_json::json!({"done": 8, "total": 8});
+        let (name, v) = status(Ok(()), Some(&done));
+        assert_eq!(name, "completed");
+        assert_eq!(
+            v["text"],
+            "martin-cp copied 8 tiles of points to out.mbtiles"
+        );
+        assert_eq!(status(Ok(()), None).0, "completed");
+        assert_eq!(
+            status(Err(MartinCpError::Interrupted), Some(&progress)).0,
+            "interrupted"
+        );
+        let (name, v) = status(Err(MartinCpError::NoMaxZoom), None);
+        assert_eq!(name, "failed");
+        assert_eq!(v["error"], MartinCpError::NoMaxZoom.to_string());
+    }
+
+    #[actix_rt::test]
+    async fn test_notify_on_completion() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hooks/copy", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"}") {
+                let len = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let path =
+            std::env::temp_dir().join(format!("martin-cp-notify-{}.mbtiles", std::process::id()));
+        let args = CopierArgs::try_parse_from([
+            "martin-cp",
+            "--source",
+            "world_cities",
+            "--output-file",
+            path.to_str().unwrap(),
+            "--max-zoom",
+            "1",
+            "--notify-url",
+            &url,
+            "../tests/fixtures/mbtiles/world_cities.mbtiles",
+        ])
+        .unwrap();
+        let job = (args.copy.source.join(","), args.copy.output_name());
+        let mut summary = None;
+        let result = start(args, &mut summary, Arc::default()).await;
+        assert!(result.is_ok());
+        notify(
+            &url,
+            &notification(&job.0, &job.1, &result, summary.as_ref()),
+        )
+        .await;
+        std::fs::remove_file(&path).unwrap();
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /hooks/copy HTTP/1.1\r\n"));
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["status"], "completed");
+        assert_eq!(body["source"], "world_cities");
+        assert_eq!(body["output"], path.display().to_string());
+        assert_eq!(body["progress"]["done"], body["progress"]["total"]);
+        assert_eq!(body["progress"]["non_empty"], 5);
+        assert_eq!(body["progress"]["last"], true);
+    }
+}
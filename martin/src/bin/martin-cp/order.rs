@@ -0,0 +1,228 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use clap::ValueEnum;
+use martin::{iterate_tiles, TileCoord, TileRect};
+use tokio::time::Instant;
+
+/// Order of the generated tiles, see `--order`
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    ValueEnum,
+    serde::Deserialize,
+    serde::Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum TileOrder {
+    /// The tiles in the order of the zoom levels and bounding boxes given, or of the zoom levels of `--tile-list`
+    #[default]
+    Input,
+    /// All tiles of a zoom level before the next one, starting with the lowest zoom
+    ZoomAsc,
+    /// All tiles of a zoom level before the next one, starting with the highest zoom
+    ZoomDesc,
+    /// Lowest zoom first, and the tiles of each zoom level in rings around the center of the copied area
+    CenterOut,
+}
+
+impl TileOrder {
+    /// Iterate over the tiles in this order. The order only depends on the tile ranges, so that `--resume` can skip the tiles done before.
+    pub fn iterate(self, mut tiles: Vec<TileRect>) -> Box<dyn Iterator<Item = TileCoord>> {
+        match self {
+            Self::Input => Box::new(iterate_tiles(tiles)),
+            Self::ZoomAsc => {
+                tiles.sort_by_key(|v| v.zoom);
+                Box::new(iterate_tiles(tiles))
+            }
+            Self::ZoomDesc => {
+                tiles.sort_by_key(|v| std::cmp::Reverse(v.zoom));
+                Box::new(iterate_tiles(tiles))
+            }
+            Self::CenterOut => {
+                let mut zooms: BTreeMap<u8, Vec<TileRect>> = BTreeMap::new();
+                for rect in tiles {
+                    zooms.entry(rect.zoom).or_default().push(rect);
+                }
+                Box::new(zooms.into_values().flat_map(center_out))
+            }
+        }
+    }
+
+    /// Whether all tiles of a zoom level are iterated before those of the higher ones, as needed by `--prune-empty`
+    pub fn lowest_zoom_first(self, tiles: &[TileRect]) -> bool {
+        match self {
+            Self::Input => tiles.windows(2).all(|v| v[0].zoom <= v[1].zoom),
+            Self::ZoomAsc | Self::CenterOut => true,
+            Self::ZoomDesc => false,
+        }
+    }
+}
+
+/// Tiles of a single zoom level in square rings around the center of their extent.
+/// Only the parts of each ring that intersect the ranges are kept, so the tiles of a zoom level are never all in memory.
+fn center_out(rects: Vec<TileRect>) -> impl Iterator<Item = TileCoord> {
+    let zoom = rects.first().map_or(0, |v| v.zoom);
+    let min_x = rects.iter().map(|v| i64::from(v.min_x)).min().unwrap_or(0);
+    let max_x = rects.iter().map(|v| i64::from(v.max_x)).max().unwrap_or(-1);
+    let min_y = rects.iter().map(|v| i64::from(v.min_y)).min().unwrap_or(0);
+    let max_y = rects.iter().map(|v| i64::from(v.max_y)).max().unwrap_or(-1);
+    let (cx, cy) = ((min_x + max_x) / 2, (min_y + max_y) / 2);
+    let radius = (max_x - cx).max(max_y - cy);
+    (0..=radius).flat_map(move |d| {
+        // Rows at the top and the bottom of the ring, then the columns on its sides without the corners
+        let sides = if d == 0 {
+            vec![(true, cy, cx, cx)]
+        } else {
+            vec![
+                (true, cy - d, cx - d, cx + d),
+                (false, cx + d, cy - d + 1, cy + d - 1),
+                (true, cy + d, cx - d, cx + d),
+                (false, cx - d, cy - d + 1, cy + d - 1),
+            ]
+        };
+        sides
+            .into_iter()
+            .flat_map(|(is_row, fixed, start, end)| {
+                let mut segments: Vec<_> = rects
+                    .iter()
+                    .filter_map(|r| {
+                        let (fixed_range, min, max) = if is_row {
+                            (r.min_y..=r.max_y, r.min_x, r.max_x)
+                        } else {
+                            (r.min_x..=r.max_x, r.min_y, r.max_y)
+                        };
+                        let fixed = u32::try_from(fixed).ok()?;
+                        let start = u32::try_from(start.max(i64::from(min))).ok()?;
+                        let end = u32::try_from(end.min(i64::from(max))).ok()?;
+                        (fixed_range.contains(&fixed) && start <= end)
+                            .then_some((start, end, fixed))
+                    })
+                    .collect();
+                segments.sort_unstable();
+                segments.into_iter().map(move |v| (is_row, v))
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flat_map(move |(is_row, (start, end, fixed))| {
+                (start..=end).map(move |v| {
+                    let (x, y) = if is_row { (v, fixed) } else { (fixed, v) };
+                    TileCoord { z: zoom, x, y }
+                })
+            })
+    })
+}
+
+/// Iterate over the tiles that were not generated by a previous run, until the deadline passes.
+/// Tiles are taken in order, so when the iteration stops, all the taken tiles are generated
+/// before the copy ends, and a later run can skip them.
+pub fn remaining_tiles<'a>(
+    tiles: Vec<TileRect>,
+    order: TileOrder,
+    skipped: u64,
+    (deadline, interrupted, stopped): (Option<Instant>, &'a AtomicBool, &'a AtomicBool),
+) -> impl Iterator<Item = TileCoord> + 'a {
+    order
+        .iterate(tiles)
+        .skip(usize::try_from(skipped).unwrap_or(usize::MAX))
+        .take_while(move |_| {
+            let expired = interrupted.load(Ordering::Relaxed)
+                || deadline.map_or(false, |v| Instant::now() >= v);
+            if expired {
+                stopped.store(true, Ordering::Relaxed);
+            }
+            !expired
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use martin::compute_tile_ranges;
+    use tilejson::Bounds;
+
+    use super::*;
+
+    #[test]
+    fn test_remaining_tiles() {
+        let tiles = || compute_tile_ranges(&[Bounds::MAX_TILED], &[0, 1]);
+        let remaining_from = |skipped, deadline, interrupted, stopped| {
+            remaining_tiles(
+                tiles(),
+                TileOrder::ZoomAsc,
+                skipped,
+                (deadline, interrupted, stopped),
+            )
+        };
+        let interrupted = AtomicBool::new(false);
+        let stopped = AtomicBool::new(false);
+        let remaining: Vec<_> = remaining_from(2, None, &interrupted, &stopped).collect();
+        assert_eq!(remaining.len(), 3);
+        assert_eq!(remaining[0], TileCoord { z: 1, x: 0, y: 1 });
+        assert!(!stopped.load(Ordering::Relaxed));
+
+        let deadline = Some(Instant::now());
+        assert_eq!(
+            remaining_from(0, deadline, &interrupted, &stopped).count(),
+            0
+        );
+        assert!(stopped.load(Ordering::Relaxed));
+
+        let stopped = AtomicBool::new(false);
+        assert_eq!(
+            remaining_from(5, deadline, &interrupted, &stopped).count(),
+            0
+        );
+        assert!(
+            !stopped.load(Ordering::Relaxed),
+            "all tiles were already done"
+        );
+
+        let interrupted = AtomicBool::new(true);
+        assert_eq!(remaining_from(0, None, &interrupted, &stopped).count(), 0);
+        assert!(stopped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_tile_order() {
+        let tiles = compute_tile_ranges(&[Bounds::MAX_TILED], &[2, 0, 1]);
+        let zooms = |order: TileOrder| {
+            let mut zooms: Vec<_> = order.iterate(tiles.clone()).map(|v| v.z).collect();
+            zooms.dedup();
+            zooms
+        };
+        assert_eq!(TileOrder::default(), TileOrder::Input);
+        assert_eq!(zooms(TileOrder::Input), vec![2, 0, 1]);
+        assert_eq!(zooms(TileOrder::ZoomAsc), vec![0, 1, 2]);
+        assert_eq!(zooms(TileOrder::ZoomDesc), vec![2, 1, 0]);
+        assert_eq!(zooms(TileOrder::CenterOut), vec![0, 1, 2]);
+        assert!(!TileOrder::Input.lowest_zoom_first(&tiles));
+        assert!(TileOrder::ZoomAsc.lowest_zoom_first(&tiles));
+        assert!(!TileOrder::ZoomDesc.lowest_zoom_first(&tiles));
+        let ascending = compute_tile_ranges(&[Bounds::MAX_TILED], &[0, 1, 2]);
+        assert!(TileOrder::Input.lowest_zoom_first(&ascending));
+
+        // Two separate areas, and a single row of tiles, at zoom 4
+        let tiles = vec![
+            TileRect::new(4, 2, 3, 6, 9),
+            TileRect::new(4, 9, 5, 12, 6),
+            TileRect::new(4, 0, 15, 15, 15),
+        ];
+        let mut all: Vec<_> = iterate_tiles(tiles.clone()).collect();
+        let ordered: Vec<_> = TileOrder::CenterOut.iterate(tiles).collect();
+        // The center 7/9 is between the areas, so the first tiles are in the ring around it
+        assert_eq!(ordered[0], TileCoord { z: 4, x: 6, y: 8 });
+        let distance = |v: &TileCoord| (i64::from(v.x) - 7).abs().max((i64::from(v.y) - 9).abs());
+        assert!(ordered
+            .windows(2)
+            .all(|v| distance(&v[0]) <= distance(&v[1])));
+        let mut sorted = ordered.clone();
+        sorted.sort_unstable_by_key(|v| (v.x, v.y));
+        all.sort_unstable_by_key(|v| (v.x, v.y));
+        assert_eq!(sorted, all, "each tile is generated once");
+    }
+}
@@ -0,0 +1,58 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+
+use log::warn;
+use martin::TileCoord;
+
+/// Run the tile request until it succeeds, retrying it with exponential backoff
+pub async fn with_retries<T, E, F, Fut>(
+    xyz: TileCoord,
+    retries: u32,
+    delay: Duration,
+    mut request: F,
+) -> Result<T, E>
+where
+    E: Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 0;
+    loop {
+        match request().await {
+            Err(e) if attempt < retries => {
+                let wait = delay.saturating_mul(1 << attempt.min(16));
+                warn!("Unable to generate tile {xyz:#}, retrying in {wait:?}: {e}");
+                tokio::time::sleep(wait).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_with_retries() {
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let attempts = AtomicU64::new(0);
+        let request = || async {
+            match attempts.fetch_add(1, Ordering::Relaxed) {
+                0 | 1 => Err("timeout"),
+                v => Ok(v),
+            }
+        };
+        assert_eq!(with_retries(xyz, 2, Duration::ZERO, request).await, Ok(2));
+        attempts.store(0, Ordering::Relaxed);
+        assert_eq!(
+            with_retries(xyz, 1, Duration::ZERO, request).await,
+            Err("timeout")
+        );
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+    }
+}
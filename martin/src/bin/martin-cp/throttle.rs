@@ -0,0 +1,92 @@
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::Instant;
+
+use crate::CopyArgs;
+
+/// Parse a positive number of tiles per second
+pub fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if v.is_finite() && v > 0.0 => Ok(v),
+        _ => Err(format!(
+            "Invalid rate {s}, expected a positive number of tiles per second"
+        )),
+    }
+}
+
+/// Limits the load on the sources, see `--max-tiles-per-second` and `--max-concurrent-queries`
+pub struct Throttle {
+    /// Time between the starts of two tiles
+    interval: Option<Duration>,
+    /// When the next tile may start
+    next: Mutex<Instant>,
+    queries: Option<(Semaphore, u32)>,
+}
+
+impl Throttle {
+    pub fn new(args: &CopyArgs) -> Self {
+        Self {
+            interval: args
+                .max_tiles_per_second
+                .map(|v| Duration::from_secs_f64(1.0 / v)),
+            next: Mutex::new(Instant::now()),
+            queries: args
+                .max_concurrent_queries
+                .map(|v| (Semaphore::new(v as usize), v)),
+        }
+    }
+
+    /// Wait until a tile of the given number of sources may be generated.
+    /// The returned permit must be kept until the queries of the tile are done.
+    pub async fn acquire(&self, sources: usize) -> Option<SemaphorePermit<'_>> {
+        if let Some(interval) = self.interval {
+            let start = {
+                let mut next = self.next.lock().unwrap_or_else(PoisonError::into_inner);
+                let start = (*next).max(Instant::now());
+                *next = start + interval;
+                start
+            };
+            tokio::time::sleep_until(start).await;
+        }
+        let (semaphore, max) = self.queries.as_ref()?;
+        let permits = u32::try_from(sources).unwrap_or(u32::MAX).clamp(1, *max);
+        let permit = semaphore.acquire_many(permits).await;
+        Some(permit.expect("the semaphore is never closed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_throttle() {
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+        let throttle = Throttle::new(&CopyArgs {
+            max_tiles_per_second: Some(parse_rate("50").unwrap()),
+            max_concurrent_queries: Some(3),
+            ..Default::default()
+        });
+        let started = Instant::now();
+        for _ in 0..5 {
+            drop(throttle.acquire(1).await);
+        }
+        assert!(started.elapsed() >= Duration::from_millis(80));
+
+        let (semaphore, _) = throttle.queries.as_ref().unwrap();
+        let composite = throttle.acquire(2).await;
+        assert_eq!(semaphore.available_permits(), 1);
+        // A tile of more sources than allowed queries still runs once all permits are available
+        drop(composite);
+        let all = throttle.acquire(10).await;
+        assert_eq!(semaphore.available_permits(), 0);
+        drop(all);
+        assert!(Throttle::new(&CopyArgs::default())
+            .acquire(1)
+            .await
+            .is_none());
+    }
+}
@@ -1,17 +1,28 @@
 use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 use actix_web::dev::Server;
 use clap::Parser;
-use log::{error, info, log_enabled};
-use martin::args::{Args, OsEnv};
-use martin::srv::{new_server, RESERVED_KEYWORDS};
-use martin::{read_config, Config, IdResolver, MartinResult};
+use log::{error, info, log_enabled, warn};
+use martin::args::{
+    AdviseArgs, Args, BenchArgs, Command, FixtureArgs, InstallFunctionsArgs, MigrateConfigArgs,
+    OsEnv, PgCommand, ReplayArgs,
+};
+use martin::commands::{
+    advise_tables, bench_source, generate_fixture, install_functions, migrate_config,
+    replay_requests, test_sources, ReplayTarget,
+};
+use martin::srv::{
+    new_server, read_recording, ConfigLoader, ConfigStaging, SourceLogger, SrvConfig,
+    RESERVED_KEYWORDS,
+};
+use martin::MartinError::{ReplayChanged, SourceTestsFailed};
+use martin::{read_config, Config, IdResolver, MartinResult, ServerState};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-async fn start(args: Args) -> MartinResult<Server> {
-    info!("Starting Martin v{VERSION}");
-
+fn load_config(args: Args) -> MartinResult<(Config, Option<PathBuf>)> {
     let env = OsEnv::default();
     let save_config = args.meta.save_config.clone();
     let mut config = if let Some(ref cfg_filename) = args.meta.config {
@@ -24,31 +35,224 @@ async fn start(args: Args) -> MartinResult<Server> {
 
     args.merge_into_config(&mut config, &env)?;
     config.finalize()?;
-    let sources = config.resolve(IdResolver::new(RESERVED_KEYWORDS)).await?;
+    Ok((config, save_config))
+}
 
+fn save_resolved_config(config: &Config, save_config: Option<PathBuf>) -> MartinResult<()> {
     if let Some(file_name) = save_config {
-        config.save_to_file(file_name)?;
+        config.save_to_file(file_name)
     } else {
         info!("Use --save-config to save or print Martin configuration.");
+        Ok(())
     }
+}
+
+async fn init_sources(args: Args) -> MartinResult<(Config, ServerState)> {
+    let (mut config, save_config) = load_config(args)?;
+    let sources = config.resolve(IdResolver::new(RESERVED_KEYWORDS)).await?;
+    save_resolved_config(&config, save_config)?;
+    Ok((config, sources))
+}
+
+/// Load the configuration with the same arguments again, for `POST /_/config/stage`
+fn config_loader(args: Args, srv: SrvConfig) -> ConfigLoader {
+    Arc::new(move || {
+        let (args, srv) = (args.clone(), srv.clone());
+        Box::pin(async move {
+            let (mut config, _) = load_config(args)?;
+            if config.srv != srv {
+                warn!(
+                    "The server settings of the staged configuration are only used after a restart"
+                );
+            }
+            config.resolve(IdResolver::new(RESERVED_KEYWORDS)).await
+        })
+    })
+}
+
+async fn start(args: Args) -> MartinResult<Server> {
+    info!("Starting Martin v{VERSION}");
 
-    let (server, listen_addresses) = new_server(config.srv, sources)?;
+    let loader_args = args.clone();
+    let (mut config, save_config) = load_config(args)?;
+    let idr = IdResolver::new(RESERVED_KEYWORDS);
+    let srv = config.srv.clone();
+    let sources = if let Some(ready_when) = srv.ready_when {
+        let (sources, background) = config.resolve_in_background(idr, ready_when)?;
+        actix_rt::spawn(async move {
+            match background.await {
+                Ok(config) => {
+                    info!("Source initialization has finished");
+                    if let Err(e) = save_resolved_config(&config, save_config) {
+                        error!("{e}");
+                    }
+                }
+                Err(e) => error!("Source initialization has stopped: {e}"),
+            }
+        });
+        if let Some(readiness) = &sources.readiness {
+            info!("Waiting for {ready_when} of the sources to initialize");
+            readiness.wait().await?;
+        }
+        sources
+    } else {
+        let sources = config.resolve(idr).await?;
+        save_resolved_config(&config, save_config)?;
+        sources
+    };
+    let mut sources = sources;
+    if srv.config_staging.unwrap_or_default() {
+        let loader = config_loader(loader_args, srv.clone());
+        sources.staging = Some(Arc::new(ConfigStaging::new(loader)));
+    }
+    let (server, listen_addresses) = new_server(srv, sources)?;
     info!("Martin has been started on {listen_addresses}.");
     info!("Use http://{listen_addresses}/catalog to get the list of available sources.");
 
     Ok(server)
 }
 
+async fn run_source_tests(args: Args) -> MartinResult<()> {
+    info!("Testing Martin v{VERSION} sources");
+
+    let (_, sources) = init_sources(args).await?;
+    let report = test_sources(&sources.tiles).await;
+    println!("{report}");
+
+    match report.failed() {
+        0 => Ok(()),
+        failed => Err(SourceTestsFailed(failed, report.0.len())),
+    }
+}
+
+async fn run_bench(args: Args, bench: BenchArgs) -> MartinResult<()> {
+    info!("Benchmarking Martin v{VERSION} source {}", bench.source);
+
+    let (_, sources) = init_sources(args).await?;
+    let report = bench_source(&sources.tiles, &bench).await?;
+    println!("{report}");
+
+    Ok(())
+}
+
+async fn run_replay(args: Args, replay: ReplayArgs) -> MartinResult<()> {
+    info!("Replaying requests with Martin v{VERSION}");
+
+    let requests = read_recording(&replay.recording)?;
+    let total = requests.len();
+    if replay.compare && requests.iter().all(|r| r.response.is_none()) {
+        warn!("The recording has no responses to compare, record it with --record-samples");
+    }
+    let (report, changed) = if let Some(url) = &replay.url {
+        replay_requests(ReplayTarget::Url(url), requests, &replay).await
+    } else {
+        let (_, sources) = init_sources(args).await?;
+        replay_requests(ReplayTarget::Sources(&sources.tiles), requests, &replay).await
+    };
+    println!("{report}");
+
+    if changed > 0 {
+        return Err(ReplayChanged(changed, total));
+    }
+    Ok(())
+}
+
+fn run_migrate_config(args: Args, migrate: &MigrateConfigArgs) -> MartinResult<()> {
+    info!(
+        "Migrating {} config {} to Martin v{VERSION}",
+        migrate.from,
+        migrate.file.display()
+    );
+
+    let migration = migrate_config(migrate.from, &migrate.file)?;
+    for warning in &migration.warnings {
+        warn!("{warning}");
+    }
+    let save_config = args.meta.save_config.unwrap_or_else(|| "-".into());
+    migration.config.save_to_file(save_config)
+}
+
+async fn run_fixture(fixture: &FixtureArgs) -> MartinResult<()> {
+    info!("Generating the test fixture with Martin v{VERSION}");
+
+    for file in generate_fixture(fixture).await? {
+        info!("Wrote {}", file.display());
+    }
+
+    Ok(())
+}
+
+async fn run_install_functions(args: Args, install: InstallFunctionsArgs) -> MartinResult<()> {
+    info!("Generating tile functions with Martin v{VERSION}");
+
+    let (mut config, _) = load_config(args)?;
+    let functions = install_functions(&mut config, &install).await?;
+    if install.dry_run {
+        for func in &functions {
+            println!("{}", func.sql);
+        }
+    } else {
+        info!("Publish the functions as function sources, and remove their tables from the published sources.");
+    }
+
+    Ok(())
+}
+
+async fn run_advise(args: Args, advise: AdviseArgs) -> MartinResult<()> {
+    info!("Inspecting tables with Martin v{VERSION}");
+
+    let (mut config, _) = load_config(args)?;
+    let report = advise_tables(&mut config, &advise).await?;
+    print!("{report}");
+    info!(
+        "Found {} issues, review the suggested SQL before running it.",
+        report.issues()
+    );
+
+    Ok(())
+}
+
 #[actix_web::main]
 async fn main() {
-    let env = env_logger::Env::default().default_filter_or("martin=info");
-    env_logger::Builder::from_env(env).init();
-
-    start(Args::parse())
-        .await
-        .unwrap_or_else(|e| on_error(e))
-        .await
-        .unwrap_or_else(|e| on_error(e));
+    SourceLogger::init("martin=info");
+
+    let args = Args::parse();
+    match args.command.clone() {
+        Some(Command::Bench(bench)) => {
+            run_bench(args, bench).await.unwrap_or_else(|e| on_error(e));
+        }
+        Some(Command::Replay(replay)) => {
+            run_replay(args, replay)
+                .await
+                .unwrap_or_else(|e| on_error(e));
+        }
+        Some(Command::MigrateConfig(migrate)) => {
+            run_migrate_config(args, &migrate).unwrap_or_else(|e| on_error(e));
+        }
+        Some(Command::Fixture(fixture)) => {
+            run_fixture(&fixture).await.unwrap_or_else(|e| on_error(e));
+        }
+        Some(Command::Pg(PgCommand::InstallFunctions(install))) => {
+            run_install_functions(args, install)
+                .await
+                .unwrap_or_else(|e| on_error(e));
+        }
+        Some(Command::Pg(PgCommand::Advise(advise))) => {
+            run_advise(args, advise)
+                .await
+                .unwrap_or_else(|e| on_error(e));
+        }
+        Some(Command::TestSources(_)) => {
+            run_source_tests(args).await.unwrap_or_else(|e| on_error(e));
+        }
+        None => {
+            start(args)
+                .await
+                .unwrap_or_else(|e| on_error(e))
+                .await
+                .unwrap_or_else(|e| on_error(e));
+        }
+    }
 }
 
 fn on_error<E: Display>(e: E) -> ! {
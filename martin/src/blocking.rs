@@ -0,0 +1,118 @@
+//! Synchronous access to the tiles of `MBTiles` and `PMTiles` files, for the applications that do not use Tokio,
+//! e.g. CLI utilities, or plugins running in another async runtime.
+//!
+//! ```no_run
+//! use martin::blocking::TileReader;
+//! use martin::TileCoord;
+//!
+//! let reader = TileReader::open(["tiles/world_cities.mbtiles"]).unwrap();
+//! let tile = reader.get_tile("world_cities", TileCoord { z: 0, x: 0, y: 0 }, None).unwrap();
+//! println!("{} bytes of {}", tile.data.len(), tile.info);
+//! ```
+
+use std::path::Path;
+
+use tilejson::TileJSON;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::args::{parse_file_args, Arguments};
+use crate::srv::{get_tile_content, merge_tilejson, RESERVED_KEYWORDS};
+use crate::{Config, IdResolver, MartinError, MartinResult, Tile, TileCoord, TileSources};
+
+/// Tile sources of files, read with a private single-threaded runtime.
+/// Its methods block the current thread, so they must not be called from within a Tokio runtime.
+pub struct TileReader {
+    // Declared first so that the sources are dropped while the runtime still exists
+    sources: TileSources,
+    runtime: Runtime,
+}
+
+impl TileReader {
+    /// Open the tile sources of the given files, and of the `.mbtiles` and `.pmtiles` files in the given directories.
+    /// The source IDs are the file names without the extension, as if the files were passed to `martin`.
+    pub fn open<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>) -> MartinResult<Self> {
+        let mut args = Arguments::new(
+            paths
+                .into_iter()
+                .map(|v| v.as_ref().to_string_lossy().into_owned())
+                .collect(),
+        );
+        let mut config = Config {
+            pmtiles: parse_file_args(&mut args, "pmtiles"),
+            mbtiles: parse_file_args(&mut args, "mbtiles"),
+            ..Default::default()
+        };
+        args.check()?;
+        config.finalize()?;
+
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| MartinError::InternalError(e.into()))?;
+        let state = runtime.block_on(config.resolve(IdResolver::new(RESERVED_KEYWORDS)))?;
+        Ok(Self {
+            sources: state.tiles,
+            runtime,
+        })
+    }
+
+    /// Sorted IDs of the tile sources
+    #[must_use]
+    pub fn source_ids(&self) -> Vec<String> {
+        let mut ids: Vec<_> = self.sources.get_catalog().into_keys().collect();
+        ids.sort();
+        ids
+    }
+
+    /// `TileJSON` of one or more comma-separated sources, without the tile URLs
+    pub fn get_tilejson(&self, source_ids: &str) -> MartinResult<TileJSON> {
+        let (sources, _, _) = self.sources.get_sources(source_ids, None)?;
+        let mut tilejson = merge_tilejson(&sources, String::new());
+        tilejson.tiles.clear();
+        Ok(tilejson)
+    }
+
+    /// Get a tile of one or more comma-separated sources, merged the same way as by the server.
+    /// The tile is returned with the encoding it is stored with, and is empty if it does not exist.
+    pub fn get_tile(
+        &self,
+        source_ids: &str,
+        xyz: TileCoord,
+        query: Option<&str>,
+    ) -> MartinResult<Tile> {
+        let (sources, use_url_query, info) = self.sources.get_sources(source_ids, Some(xyz.z))?;
+        let query = if use_url_query { query } else { None };
+        let tile = self
+            .runtime
+            .block_on(get_tile_content(&sources, info, &xyz, query, None))?;
+        Ok(tile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_tiles() {
+        let reader = TileReader::open(["../tests/fixtures/mbtiles/world_cities.mbtiles"]).unwrap();
+        assert_eq!(reader.source_ids(), vec!["world_cities"]);
+        let tilejson = reader.get_tilejson("world_cities").unwrap();
+        assert!(tilejson.tiles.is_empty());
+        assert!(tilejson.vector_layers.is_some());
+
+        let tile = reader
+            .get_tile("world_cities", TileCoord { z: 0, x: 0, y: 0 }, None)
+            .unwrap();
+        assert!(!tile.data.is_empty());
+        let missing = reader
+            .get_tile("world_cities", TileCoord { z: 6, x: 0, y: 0 }, None)
+            .unwrap();
+        assert!(missing.data.is_empty());
+        assert!(reader
+            .get_tile("unknown", TileCoord { z: 0, x: 0, y: 0 }, None)
+            .is_err());
+
+        assert!(TileReader::open(["../tests/fixtures/missing.mbtiles"]).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use std::fmt::{Display, Formatter};
+
+use crate::args::AdviseArgs;
+use crate::pg::PgError::NotATableSource;
+use crate::pg::{table_advice, TableAdvice};
+use crate::srv::RESERVED_KEYWORDS;
+use crate::{Config, IdResolver, MartinResult};
+
+/// Issues found in the tables of all PostgreSQL connections
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableAdviceReport(pub Vec<TableAdvice>);
+
+impl TableAdviceReport {
+    /// Number of issues found in all tables
+    #[must_use]
+    pub fn issues(&self) -> usize {
+        self.0.iter().map(|v| v.issues.len()).sum()
+    }
+}
+
+impl Display for TableAdviceReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for advice in &self.0 {
+            writeln!(f, "{} ({})", advice.source_id, advice.table)?;
+            for s in &advice.samples {
+                let scan = if s.seq_scan { ", sequential scan" } else { "" };
+                writeln!(
+                    f,
+                    "  tile {:#}: {:.1}ms, {} rows, {} pages{scan}",
+                    s.xyz, s.time, s.rows, s.pages
+                )?;
+            }
+            if advice.issues.is_empty() {
+                writeln!(f, "  no issues found")?;
+            }
+            for issue in &advice.issues {
+                writeln!(f, "  ! {}", issue.problem)?;
+                if let Some(fix) = &issue.fix {
+                    writeln!(f, "    {fix};")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Inspect the tables of the table sources listed in the args, or of all table sources if none are listed
+pub async fn advise_tables(
+    config: &mut Config,
+    args: &AdviseArgs,
+) -> MartinResult<TableAdviceReport> {
+    let idr = IdResolver::new(RESERVED_KEYWORDS)
+        .with_rules(&config.source_ids.clone().unwrap_or_default());
+    let mut result = Vec::new();
+    for pg in config.postgres.iter_mut() {
+        result.extend(table_advice(pg, idr.clone(), &args.source).await?);
+    }
+
+    for id in &args.source {
+        if !result.iter().any(|v| &v.source_id == id) {
+            Err(NotATableSource(id.clone()))?;
+        }
+    }
+    Ok(TableAdviceReport(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pg::{TableIssue, TileSample};
+    use crate::TileCoord;
+
+    #[test]
+    fn display_report() {
+        let report = TableAdviceReport(vec![
+            TableAdvice {
+                source_id: "points".to_string(),
+                table: "public.points.geom".to_string(),
+                samples: vec![TileSample {
+                    xyz: TileCoord { z: 8, x: 10, y: 20 },
+                    time: 12.34,
+                    rows: 100.0,
+                    pages: 50.0,
+                    seq_scan: true,
+                }],
+                issues: vec![TableIssue {
+                    problem: "Missing spatial index".to_string(),
+                    fix: Some("CREATE INDEX".to_string()),
+                }],
+            },
+            TableAdvice {
+                source_id: "lines".to_string(),
+                table: "public.lines.geom".to_string(),
+                samples: vec![],
+                issues: vec![],
+            },
+        ]);
+        assert_eq!(report.issues(), 1);
+        insta::assert_snapshot!(report.to_string(), @r###"
+        points (public.points.geom)
+          tile 8/10/20: 12.3ms, 100 rows, 50 pages, sequential scan
+          ! Missing spatial index
+            CREATE INDEX;
+        lines (public.lines.geom)
+          no issues found
+        "###);
+    }
+}
@@ -0,0 +1,229 @@
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+use futures::stream::{self, StreamExt};
+use log::{debug, info, warn};
+
+use crate::args::BenchArgs;
+use crate::source::TileSources;
+use crate::srv::get_tile_content;
+use crate::{compute_tile_ranges, iterate_tiles, MartinResult, TileRect};
+
+/// Benchmark results of a single zoom level
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoomBench {
+    pub zoom: u8,
+    pub tiles: usize,
+    pub empty: usize,
+    pub errors: usize,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// Result of generating a single tile
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Sample {
+    pub(crate) duration: Duration,
+    /// Tile size in bytes, or `None` if the tile could not be generated
+    pub(crate) size: Option<usize>,
+}
+
+impl ZoomBench {
+    pub(crate) fn new(zoom: u8, samples: &[Sample], elapsed: Duration) -> Self {
+        let mut durations = samples.iter().map(|s| s.duration).collect::<Vec<_>>();
+        durations.sort_unstable();
+        Self {
+            zoom,
+            tiles: samples.len(),
+            empty: samples.iter().filter(|s| s.size == Some(0)).count(),
+            errors: samples.iter().filter(|s| s.size.is_none()).count(),
+            bytes: samples
+                .iter()
+                .filter_map(|s| s.size)
+                .map(|v| v as u64)
+                .sum(),
+            elapsed,
+            p50: percentile(&durations, 50),
+            p90: percentile(&durations, 90),
+            p99: percentile(&durations, 99),
+            max: durations.last().copied().unwrap_or_default(),
+        }
+    }
+
+    /// Number of tiles generated per second
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.tiles as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Average size of the non-empty tiles in bytes
+    #[must_use]
+    pub fn avg_size(&self) -> u64 {
+        match (self.tiles - self.empty - self.errors) as u64 {
+            0 => 0,
+            count => self.bytes / count,
+        }
+    }
+}
+
+/// Nearest-rank percentile of the sorted durations
+#[must_use]
+pub fn percentile(sorted: &[Duration], pct: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (sorted.len() * pct + 99) / 100;
+    sorted[rank.max(1) - 1]
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BenchReport(pub Vec<ZoomBench>);
+
+impl Display for BenchReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            " Zoom |    Tiles |  Empty | Errors |  Avg size |      p50 |      p90 |      p99 |      max |  Tiles/s"
+        )?;
+        for z in &self.0 {
+            writeln!(
+                f,
+                " {:>4} | {:>8} | {:>6} | {:>6} | {:>9} | {:>8} | {:>8} | {:>8} | {:>8} | {:>8.1}",
+                z.zoom,
+                z.tiles,
+                z.empty,
+                z.errors,
+                format!("{}B", z.avg_size()),
+                format!("{:.1?}", z.p50),
+                format!("{:.1?}", z.p90),
+                format!("{:.1?}", z.p99),
+                format!("{:.1?}", z.max),
+                z.throughput(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Generate tiles from a source at each of the requested zoom levels without saving them,
+/// measuring how long each tile takes.
+pub async fn bench_source(sources: &TileSources, args: &BenchArgs) -> MartinResult<BenchReport> {
+    let concurrency = args.concurrency.max(1);
+    let query = args.url_query.as_deref();
+    let mut results = Vec::new();
+
+    for zoom in &args.zoom {
+        let (srcs, _, info) = sources.get_sources(&args.source, Some(*zoom))?;
+        if srcs.is_empty() {
+            warn!(
+                "Source {} does not support zoom {zoom}, skipping",
+                args.source
+            );
+            continue;
+        }
+        let srcs = srcs.as_slice();
+
+        let ranges = compute_tile_ranges(&args.bbox, &[*zoom]);
+        let total = ranges.iter().map(TileRect::size).sum::<u64>();
+        let limit = args.max_tiles.map_or(total, |max| total.min(max));
+        info!(
+            "Generating {limit} of {total} {info} tiles from {} at zoom {zoom}",
+            args.source
+        );
+
+        let tiles = iterate_tiles(ranges).take(usize::try_from(limit).unwrap_or(usize::MAX));
+        let start = Instant::now();
+        let samples = stream::iter(tiles)
+            .map(|xyz| async move {
+                let tile_start = Instant::now();
+                let tile = get_tile_content(srcs, info, &xyz, query, None).await;
+                let duration = tile_start.elapsed();
+                let size = match tile {
+                    Ok(tile) => Some(tile.data.len()),
+                    Err(e) => {
+                        debug!("Unable to generate tile {xyz}: {e}");
+                        None
+                    }
+                };
+                Sample { duration, size }
+            })
+            .buffer_unordered(concurrency)
+            .collect::<Vec<_>>()
+            .await;
+
+        let result = ZoomBench::new(*zoom, &samples, start.elapsed());
+        if result.errors > 0 {
+            warn!(
+                "Failed to generate {} tiles at zoom {zoom}, use RUST_LOG=martin=debug to see the errors",
+                result.errors
+            );
+        }
+        results.push(result);
+    }
+
+    Ok(BenchReport(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(v: u64) -> Duration {
+        Duration::from_millis(v)
+    }
+
+    #[test]
+    fn percentiles() {
+        let durations = (1..=100).map(ms).collect::<Vec<_>>();
+        assert_eq!(percentile(&durations, 50), ms(50));
+        assert_eq!(percentile(&durations, 90), ms(90));
+        assert_eq!(percentile(&durations, 99), ms(99));
+
+        assert_eq!(percentile(&[ms(7)], 50), ms(7));
+        assert_eq!(percentile(&[ms(1), ms(2), ms(3)], 50), ms(2));
+        assert_eq!(percentile(&[ms(1), ms(2), ms(3)], 99), ms(3));
+        assert_eq!(percentile(&[], 50), Duration::ZERO);
+    }
+
+    #[test]
+    fn zoom_stats() {
+        let sample = |duration, size| Sample {
+            duration: ms(duration),
+            size,
+        };
+        let samples = [
+            sample(40, Some(100)),
+            sample(10, Some(0)),
+            sample(30, None),
+            sample(20, Some(300)),
+        ];
+        let res = ZoomBench::new(5, &samples, ms(500));
+        assert_eq!(
+            res,
+            ZoomBench {
+                zoom: 5,
+                tiles: 4,
+                empty: 1,
+                errors: 1,
+                bytes: 400,
+                elapsed: ms(500),
+                p50: ms(20),
+                p90: ms(40),
+                p99: ms(40),
+                max: ms(40),
+            }
+        );
+        assert_eq!(res.avg_size(), 200);
+        assert!((res.throughput() - 8.0).abs() < f64::EPSILON);
+    }
+}
@@ -0,0 +1,414 @@
+use std::f64::consts::PI;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::info;
+use mbtiles::{init_mbtiles_schema, CopyDuplicateMode, MbtType, Mbtiles};
+use postgres_protocol::escape::{escape_identifier, escape_literal};
+use serde_json::{json, Map, Value};
+use tilejson::Bounds;
+
+use crate::args::FixtureArgs;
+use crate::file_config::FileError::IoError;
+use crate::pg::PgError::PostgresError;
+use crate::pg::{PgConfig, PgPool};
+use crate::pmtiles::write_vector_pmtiles;
+use crate::utils::{mvt_encode, MvtFeature, MvtLayer};
+use crate::{encode_gzip, MartinResult, TileCoord};
+
+const EXTENT: u32 = 4096;
+/// Features this many pixels outside of a tile are included in it, like the default buffer of table sources
+const BUFFER: f64 = 64.0;
+
+/// A layer of the fixture, with the lowest zoom level that has its features
+struct FixtureLayer {
+    name: &'static str,
+    geom_type: &'static str,
+    min_zoom: u8,
+    features: Vec<FixtureFeature>,
+}
+
+struct FixtureFeature {
+    id: u64,
+    name: String,
+    rank: u64,
+    /// Longitude and latitude of the point or the vertices, polygon rings are closed
+    coords: Vec<(f64, f64)>,
+}
+
+impl FixtureFeature {
+    fn new(id: u64, layer: &str, coords: Vec<(f64, f64)>) -> Self {
+        Self {
+            id,
+            name: format!("{layer} {id}"),
+            rank: id % 5,
+            coords,
+        }
+    }
+}
+
+/// Points on a grid across the world, meridian zigzag lines from zoom 1, and squares from zoom 2
+fn fixture_layers() -> Vec<FixtureLayer> {
+    let points = (0..5)
+        .flat_map(|lat| {
+            (0..12).map(move |lon| {
+                (
+                    -165.0 + 30.0 * f64::from(lon),
+                    -60.0 + 30.0 * f64::from(lat),
+                )
+            })
+        })
+        .zip(1..)
+        .map(|(coord, id)| FixtureFeature::new(id, "point", vec![coord]))
+        .collect();
+    let lines = (0..6)
+        .zip(1..)
+        .map(|(i, id)| {
+            let lon = -150.0 + 60.0 * f64::from(i);
+            let coords = (0..15)
+                .map(|j| {
+                    let offset = if j % 2 == 0 { 5.0 } else { -5.0 };
+                    (lon + offset, -70.0 + 10.0 * f64::from(j))
+                })
+                .collect();
+            FixtureFeature::new(id, "line", coords)
+        })
+        .collect();
+    let polygons = (0..3)
+        .flat_map(|lat| {
+            (0..4).map(move |lon| {
+                (
+                    -135.0 + 90.0 * f64::from(lon),
+                    -45.0 + 45.0 * f64::from(lat),
+                )
+            })
+        })
+        .zip(1..)
+        .map(|((lon, lat), id)| {
+            let (w, s, e, n) = (lon - 10.0, lat - 10.0, lon + 10.0, lat + 10.0);
+            // Clockwise in tile coordinates, as the MVT spec requires for exterior rings
+            let ring = vec![(w, s), (w, n), (e, n), (e, s), (w, s)];
+            FixtureFeature::new(id, "polygon", ring)
+        })
+        .collect();
+    vec![
+        FixtureLayer {
+            name: "points",
+            geom_type: "Point",
+            min_zoom: 0,
+            features: points,
+        },
+        FixtureLayer {
+            name: "lines",
+            geom_type: "LineString",
+            min_zoom: 1,
+            features: lines,
+        },
+        FixtureLayer {
+            name: "polygons",
+            geom_type: "Polygon",
+            min_zoom: 2,
+            features: polygons,
+        },
+    ]
+}
+
+/// Write the fixture tiles as MBTiles and PMTiles files, and the SQL creating the same features in PostgreSQL,
+/// and run the SQL if a database is given. Returns the paths of the written files.
+pub async fn generate_fixture(args: &FixtureArgs) -> MartinResult<Vec<PathBuf>> {
+    let dir = &args.output;
+    fs::create_dir_all(dir).map_err(|e| IoError(e, dir.clone()))?;
+    let layers = fixture_layers();
+    let bounds = fixture_bounds(&layers);
+    let tiles = fixture_tiles(&layers, args.max_zoom).map_err(|e| IoError(e, dir.clone()))?;
+    info!(
+        "Generated {} tiles of the fixture up to zoom {}",
+        tiles.len(),
+        args.max_zoom
+    );
+    let vector_layers = vector_layers(&layers, args.max_zoom);
+
+    let mbtiles = dir.join("fixture.mbtiles");
+    write_mbtiles(&mbtiles, &tiles, &vector_layers, bounds, args.max_zoom).await?;
+
+    let pmtiles = dir.join("fixture.pmtiles");
+    let metadata = json!({
+        "name": "fixture",
+        "description": "Synthetic points, lines, and polygons generated by martin fixture",
+        "vector_layers": vector_layers,
+    });
+    write_vector_pmtiles(&pmtiles, &tiles, &metadata.to_string(), bounds)?;
+
+    let sql_file = dir.join("fixture.sql");
+    let sql = fixture_sql(&layers, &args.schema);
+    fs::write(&sql_file, &sql).map_err(|e| IoError(e, sql_file.clone()))?;
+
+    if let Some(connection) = &args.postgres {
+        let config = PgConfig {
+            connection_string: Some(connection.clone()),
+            ..Default::default()
+        };
+        let pool = PgPool::new(&config).await?;
+        pool.get()
+            .await?
+            .batch_execute(&sql)
+            .await
+            .map_err(|e| PostgresError(e, "creating the fixture tables"))?;
+        info!("Created the fixture tables in the {} schema", args.schema);
+    }
+    Ok(vec![mbtiles, pmtiles, sql_file])
+}
+
+async fn write_mbtiles(
+    path: &Path,
+    tiles: &[(TileCoord, Vec<u8>)],
+    vector_layers: &Value,
+    bounds: Bounds,
+    max_zoom: u8,
+) -> MartinResult<()> {
+    if path.exists() {
+        fs::remove_file(path).map_err(|e| IoError(e, path.to_path_buf()))?;
+    }
+    let mbt = Mbtiles::new(path)?;
+    let mut conn = mbt.open_or_new().await?;
+    init_mbtiles_schema(&mut conn, MbtType::Flat).await?;
+    let batch: Vec<_> = tiles
+        .iter()
+        .map(|(xyz, data)| (xyz.z, xyz.x, xyz.y, data.clone()))
+        .collect();
+    mbt.insert_tiles(
+        &mut conn,
+        MbtType::Flat,
+        CopyDuplicateMode::Override,
+        &batch,
+    )
+    .await?;
+    for (key, value) in [
+        ("name", "fixture".to_string()),
+        ("format", "pbf".to_string()),
+        ("minzoom", "0".to_string()),
+        ("maxzoom", max_zoom.to_string()),
+        ("bounds", bounds.to_string()),
+        (
+            "json",
+            json!({ "vector_layers": vector_layers }).to_string(),
+        ),
+    ] {
+        mbt.set_metadata_value(&mut conn, key, value).await?;
+    }
+    Ok(())
+}
+
+/// All non-empty tiles up to the zoom level, as gzip-compressed vector tiles
+fn fixture_tiles(
+    layers: &[FixtureLayer],
+    max_zoom: u8,
+) -> std::io::Result<Vec<(TileCoord, Vec<u8>)>> {
+    let mut tiles = Vec::new();
+    for z in 0..=max_zoom {
+        for x in 0..1_u32 << z {
+            for y in 0..1_u32 << z {
+                let xyz = TileCoord { z, x, y };
+                let tile: Vec<_> = layers
+                    .iter()
+                    .filter(|v| v.min_zoom <= z)
+                    .filter_map(|v| tile_layer(v, xyz))
+                    .collect();
+                if !tile.is_empty() {
+                    tiles.push((xyz, encode_gzip(&mvt_encode(&tile))?));
+                }
+            }
+        }
+    }
+    Ok(tiles)
+}
+
+/// Features of the layer within the buffer of the tile, or `None` if there are none
+fn tile_layer(layer: &FixtureLayer, xyz: TileCoord) -> Option<MvtLayer> {
+    let geom_type = match layer.geom_type {
+        "Point" => 1,
+        "LineString" => 2,
+        _ => 3,
+    };
+    let features: Vec<_> = layer
+        .features
+        .iter()
+        .filter_map(|feature| {
+            let coords: Vec<_> = feature.coords.iter().map(|v| project(*v, xyz)).collect();
+            let inside =
+                |(min, max): (f64, f64)| min <= f64::from(EXTENT) + BUFFER && max >= -BUFFER;
+            let xs = min_max(coords.iter().map(|v| v.0));
+            let ys = min_max(coords.iter().map(|v| v.1));
+            if !inside(xs) || !inside(ys) {
+                return None;
+            }
+            #[allow(clippy::cast_possible_truncation)]
+            let coords = coords
+                .iter()
+                .map(|(x, y)| (x.round() as i64, y.round() as i64))
+                .collect();
+            let properties = Map::from_iter([
+                ("name".to_string(), Value::from(feature.name.as_str())),
+                ("rank".to_string(), Value::from(feature.rank)),
+            ]);
+            Some(MvtFeature {
+                id: Some(feature.id),
+                geom_type,
+                properties,
+                parts: vec![coords],
+            })
+        })
+        .collect();
+    (!features.is_empty()).then(|| MvtLayer {
+        name: layer.name.to_string(),
+        extent: EXTENT,
+        features,
+    })
+}
+
+/// Web Mercator position of a longitude and latitude in the coordinates of the tile
+fn project((lon, lat): (f64, f64), xyz: TileCoord) -> (f64, f64) {
+    let size = f64::from(EXTENT) * f64::from(1_u32 << xyz.z);
+    let x = (lon + 180.0) / 360.0 * size;
+    let y = (1.0 - lat.to_radians().tan().asinh() / PI) / 2.0 * size;
+    (x - f64::from(xyz.x * EXTENT), y - f64::from(xyz.y * EXTENT))
+}
+
+fn min_max(values: impl Iterator<Item = f64>) -> (f64, f64) {
+    values.fold((f64::MAX, f64::MIN), |(min, max), v| {
+        (min.min(v), max.max(v))
+    })
+}
+
+fn fixture_bounds(layers: &[FixtureLayer]) -> Bounds {
+    let coords = || {
+        layers
+            .iter()
+            .flat_map(|v| &v.features)
+            .flat_map(|v| &v.coords)
+    };
+    let (left, right) = min_max(coords().map(|v| v.0));
+    let (bottom, top) = min_max(coords().map(|v| v.1));
+    Bounds::new(left, bottom, right, top)
+}
+
+fn vector_layers(layers: &[FixtureLayer], max_zoom: u8) -> Value {
+    layers
+        .iter()
+        .map(|v| {
+            json!({
+                "id": v.name,
+                "fields": { "name": "String", "rank": "Number" },
+                "minzoom": v.min_zoom,
+                "maxzoom": max_zoom,
+            })
+        })
+        .collect()
+}
+
+/// SQL replacing the fixture tables in the schema, with the same features as the tiles at all zoom levels
+fn fixture_sql(layers: &[FixtureLayer], schema: &str) -> String {
+    let schema = escape_identifier(schema);
+    let mut sql = format!(
+        "-- Synthetic tables generated by martin fixture\nCREATE SCHEMA IF NOT EXISTS {schema};\n"
+    );
+    for layer in layers {
+        let table = format!("{schema}.{}", escape_identifier(layer.name));
+        writeln!(
+            sql,
+            "
+DROP TABLE IF EXISTS {table};
+CREATE TABLE {table} (
+  id bigint PRIMARY KEY,
+  name text NOT NULL,
+  rank integer NOT NULL,
+  geom geometry({}, 4326) NOT NULL
+);",
+            layer.geom_type
+        )
+        .unwrap();
+        let rows: Vec<_> = layer
+            .features
+            .iter()
+            .map(|v| {
+                let coords: Vec<_> = v.coords.iter().map(|(x, y)| format!("{x} {y}")).collect();
+                let wkt = match layer.geom_type {
+                    "Point" => format!("POINT({})", coords.join(",")),
+                    "LineString" => format!("LINESTRING({})", coords.join(",")),
+                    _ => format!("POLYGON(({}))", coords.join(",")),
+                };
+                format!(
+                    "  ({}, {}, {}, ST_GeomFromText('{wkt}', 4326))",
+                    v.id,
+                    escape_literal(&v.name),
+                    v.rank
+                )
+            })
+            .collect();
+        writeln!(
+            sql,
+            "INSERT INTO {table} (id, name, rank, geom) VALUES\n{};",
+            rows.join(",\n")
+        )
+        .unwrap();
+        writeln!(sql, "CREATE INDEX ON {table} USING GIST (geom);").unwrap();
+    }
+    sql
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode_gzip;
+    use crate::file_config::FileConfigSource;
+    use crate::mbtiles::MbtSource;
+    use crate::pmtiles::PmtSource;
+    use crate::utils::mvt_decode;
+
+    #[actix_rt::test]
+    async fn generate_fixture_files() {
+        let dir = std::env::temp_dir().join(format!("martin-fixture-{}", std::process::id()));
+        let args = FixtureArgs {
+            output: dir.clone(),
+            max_zoom: 3,
+            schema: "fixture".to_string(),
+            postgres: None,
+        };
+        let files = generate_fixture(&args).await.unwrap();
+        assert_eq!(files.len(), 3);
+
+        let cfg = |name: &str| FileConfigSource {
+            path: dir.join(name),
+            tile_size: None,
+        };
+        let mbt = MbtSource::new_box("a".to_string(), cfg("fixture.mbtiles"))
+            .await
+            .unwrap();
+        let pmt = PmtSource::new_box("b".to_string(), cfg("fixture.pmtiles"))
+            .await
+            .unwrap();
+        for xyz in [(0, 0, 0), (2, 1, 1), (3, 6, 2)] {
+            let xyz = TileCoord {
+                z: xyz.0,
+                x: xyz.1,
+                y: xyz.2,
+            };
+            let tile = mbt.get_tile(&xyz, &None).await.unwrap();
+            assert_eq!(tile, pmt.get_tile(&xyz, &None).await.unwrap());
+            let layers = mvt_decode(&decode_gzip(&tile).unwrap()).unwrap();
+            let names: Vec<_> = layers.iter().map(|v| v.name.as_str()).collect();
+            let expected = ["points", "lines", "polygons"];
+            assert_eq!(names, expected[..names.len()], "tile {xyz}");
+            assert_eq!(names.len(), 1 + usize::from(xyz.z.min(2)), "tile {xyz}");
+        }
+        let tilejson = pmt.get_tilejson();
+        assert_eq!(tilejson.vector_layers.as_ref().unwrap().len(), 3);
+        assert_eq!(mbt.get_tilejson().maxzoom, Some(3));
+
+        let sql = fs::read_to_string(dir.join("fixture.sql")).unwrap();
+        assert!(sql.contains("CREATE TABLE \"fixture\".\"polygons\" ("));
+        assert!(sql.contains("(1, 'point 1', 1, ST_GeomFromText('POINT(-165 -60)', 4326))"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
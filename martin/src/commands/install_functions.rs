@@ -0,0 +1,45 @@
+use log::info;
+
+use crate::args::InstallFunctionsArgs;
+use crate::pg::PgError::NotATableSource;
+use crate::pg::{install_tile_functions, table_tile_functions, TileFunction};
+use crate::srv::RESERVED_KEYWORDS;
+use crate::{Config, IdResolver, MartinResult};
+
+/// Generate the tile functions of the table sources listed in the args, and create them in their databases
+/// unless it is a dry run. Nothing is created if any of the sources is not a PostgreSQL table source.
+pub async fn install_functions(
+    config: &mut Config,
+    args: &InstallFunctionsArgs,
+) -> MartinResult<Vec<TileFunction>> {
+    let idr = IdResolver::new(RESERVED_KEYWORDS)
+        .with_rules(&config.source_ids.clone().unwrap_or_default());
+    let mut connections = Vec::new();
+    for pg in config.postgres.iter_mut() {
+        connections.push(table_tile_functions(pg, idr.clone(), args).await?);
+    }
+
+    for id in &args.source {
+        if !connections
+            .iter()
+            .any(|(_, funcs)| funcs.iter().any(|f| &f.source_id == id))
+        {
+            Err(NotATableSource(id.clone()))?;
+        }
+    }
+
+    let mut result = Vec::new();
+    for (pool, functions) in connections {
+        if !args.dry_run && !functions.is_empty() {
+            install_tile_functions(&pool, &functions).await?;
+            for func in &functions {
+                info!(
+                    "Created function {}.{} for source {}",
+                    func.schema, func.name, func.source_id
+                );
+            }
+        }
+        result.extend(functions);
+    }
+    Ok(result)
+}
@@ -0,0 +1,586 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use serde_json::Value;
+use tilejson::Bounds;
+
+use crate::args::MigrateFrom;
+use crate::config::UnrecognizedValues;
+use crate::file_config::{FileConfigEnum, FileConfigSrc};
+use crate::pg::{PgConfig, TableInfo};
+use crate::MartinError::{ConfigLoadError, MigrationParseError};
+use crate::{Config, MartinResult, OptOneMany};
+
+/// Martin configuration generated from the configuration of another tile server
+#[derive(Debug, Default)]
+pub struct ConfigMigration {
+    pub config: Config,
+    /// Settings that could not be migrated automatically, and need to be reviewed by hand
+    pub warnings: Vec<String>,
+}
+
+/// Generate an equivalent Martin configuration from a tileserver-gl `config.json` or a t-rex TOML file.
+/// Paths are resolved the same way the other server resolves them, so they are relative
+/// to the current directory if `file` is a relative path.
+pub fn migrate_config(from: MigrateFrom, file: &Path) -> MartinResult<ConfigMigration> {
+    let content = fs::read_to_string(file).map_err(|e| ConfigLoadError(e, file.to_path_buf()))?;
+    let parse_error = |e: String| MigrationParseError(e, from, file.to_path_buf());
+    match from {
+        MigrateFrom::TileserverGl => {
+            let cfg = serde_json::from_str(&content).map_err(|e| parse_error(e.to_string()))?;
+            let dir = file.parent().unwrap_or_else(|| Path::new(""));
+            Ok(migrate_tileserver_gl(cfg, dir))
+        }
+        MigrateFrom::TRex => {
+            let cfg = toml::from_str(&content).map_err(|e| parse_error(e.to_string()))?;
+            Ok(migrate_t_rex(cfg))
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct TsglConfig {
+    options: TsglOptions,
+    styles: BTreeMap<String, TsglStyle>,
+    data: BTreeMap<String, TsglData>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct TsglOptions {
+    paths: TsglPaths,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct TsglPaths {
+    root: PathBuf,
+    fonts: PathBuf,
+    sprites: PathBuf,
+    styles: PathBuf,
+    mbtiles: PathBuf,
+    pmtiles: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+struct TsglStyle {
+    style: PathBuf,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct TsglData {
+    mbtiles: Option<PathBuf>,
+    pmtiles: Option<PathBuf>,
+}
+
+fn migrate_tileserver_gl(cfg: TsglConfig, config_dir: &Path) -> ConfigMigration {
+    let mut res = ConfigMigration::default();
+    let paths = &cfg.options.paths;
+    let root = config_dir.join(&paths.root);
+
+    let mut mbtiles = BTreeMap::new();
+    let mut pmtiles = BTreeMap::new();
+    for (id, data) in cfg.data {
+        match (data.mbtiles, data.pmtiles) {
+            (Some(file), _) => {
+                mbtiles.insert(
+                    id,
+                    FileConfigSrc::Path(root.join(&paths.mbtiles).join(file)),
+                );
+            }
+            (None, Some(file)) => {
+                let path = if file.starts_with("http://") || file.starts_with("https://") {
+                    file
+                } else {
+                    root.join(&paths.pmtiles).join(file)
+                };
+                pmtiles.insert(id, FileConfigSrc::Path(path));
+            }
+            (None, None) => res.warnings.push(format!(
+                "Data source {id} has neither an mbtiles nor a pmtiles file, and was skipped"
+            )),
+        }
+    }
+    res.config.mbtiles = FileConfigEnum::new_extended(vec![], mbtiles, UnrecognizedValues::new());
+    res.config.pmtiles = FileConfigEnum::new_extended(vec![], pmtiles, UnrecognizedValues::new());
+
+    let fonts = root.join(&paths.fonts);
+    if has_files(&fonts, &["ttf", "otf", "ttc"]) {
+        res.config.fonts = OptOneMany::One(fonts);
+    } else if fonts.is_dir() {
+        res.warnings.push(format!(
+            "Font directory {} has no TTF or OTF files. Martin generates glyphs from the original font files instead of serving pre-built PBF glyphs, add them with `fonts`",
+            fonts.display()
+        ));
+    }
+
+    let sprites = root.join(&paths.sprites);
+    let sprite_dirs = sprite_dirs(&sprites);
+    if sprite_dirs.is_empty() {
+        if sprites.is_dir() {
+            res.warnings.push(format!(
+                "Sprite directory {} has no SVG files. Martin generates sprite sheets from SVG icons instead of serving pre-built sheets, add their directories with `sprites`",
+                sprites.display()
+            ));
+        }
+    } else {
+        res.config.sprites = FileConfigEnum::new(sprite_dirs);
+    }
+
+    for (id, style) in cfg.styles {
+        let path = root.join(&paths.styles).join(&style.style);
+        res.warnings.push(style_warning(&id, &path));
+    }
+    res
+}
+
+/// True if the directory contains a file with any of the extensions, looking into subdirectories as well
+fn has_files(dir: &Path, extensions: &[&str]) -> bool {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let path = entry.path();
+        if path.is_dir() {
+            has_files(&path, extensions)
+        } else {
+            path.extension()
+                .and_then(|v| v.to_str())
+                .map_or(false, |v| extensions.contains(&v.to_lowercase().as_str()))
+        }
+    })
+}
+
+/// The sprite directory itself if it has SVG files, or each of its subdirectories with SVG files
+fn sprite_dirs(dir: &Path) -> Vec<PathBuf> {
+    let svg = |path: &Path| path.extension().map_or(false, |v| v == "svg");
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+    let entries: Vec<_> = entries.flatten().map(|v| v.path()).collect();
+    if entries.iter().any(|v| svg(v)) {
+        return vec![dir.to_path_buf()];
+    }
+    let mut dirs: Vec<_> = entries
+        .into_iter()
+        .filter(|v| v.is_dir() && has_files(v, &["svg"]))
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+/// Martin does not host styles, so explain which URLs of the style need to point to Martin
+fn style_warning(id: &str, path: &Path) -> String {
+    let mut msg = format!(
+        "Style {id} ({}) must be hosted separately. Point its URLs to Martin:",
+        path.display()
+    );
+    let style = fs::read_to_string(path)
+        .ok()
+        .and_then(|v| serde_json::from_str::<Value>(&v).ok());
+    let sources = style
+        .as_ref()
+        .and_then(|v| v.get("sources"))
+        .and_then(Value::as_object);
+    for url in sources
+        .into_iter()
+        .flat_map(|v| v.values())
+        .filter_map(|v| v.get("url").and_then(Value::as_str))
+    {
+        let source = url
+            .strip_prefix("mbtiles://")
+            .or_else(|| url.strip_prefix("pmtiles://"));
+        if let Some(source) = source {
+            let source = source.trim_start_matches('{').trim_end_matches('}');
+            let _ = write!(msg, " {url} -> <martin>/{source},");
+        }
+    }
+    msg.push_str(
+        " sprite -> <martin>/sprite/<sprite_id>, glyphs -> <martin>/font/{fontstack}/{range}",
+    );
+    msg
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct TrexConfig {
+    datasource: Vec<TrexDatasource>,
+    tileset: Vec<TrexTileset>,
+    webserver: Option<TrexWebserver>,
+    cache: Option<toml::Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct TrexDatasource {
+    name: Option<String>,
+    dbconn: Option<String>,
+    path: Option<String>,
+    default: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct TrexTileset {
+    name: String,
+    minzoom: Option<u8>,
+    maxzoom: Option<u8>,
+    extent: Option<TrexExtent>,
+    layer: Vec<TrexLayer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TrexExtent {
+    minx: f64,
+    miny: f64,
+    maxx: f64,
+    maxy: f64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct TrexLayer {
+    name: String,
+    datasource: Option<String>,
+    table_name: Option<String>,
+    geometry_field: Option<String>,
+    geometry_type: Option<String>,
+    srid: Option<i32>,
+    fid_field: Option<String>,
+    buffer_size: Option<u32>,
+    tile_size: Option<u32>,
+    minzoom: Option<u8>,
+    maxzoom: Option<u8>,
+    query: Vec<toml::Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct TrexWebserver {
+    bind: Option<String>,
+    port: Option<u16>,
+}
+
+fn migrate_t_rex(cfg: TrexConfig) -> ConfigMigration {
+    let mut res = ConfigMigration::default();
+
+    // Layers without a datasource use the default one, or the first one
+    let default_ds = cfg
+        .datasource
+        .iter()
+        .position(|v| v.default == Some(true))
+        .unwrap_or_default();
+    // PostGIS configs in the same order as the datasources, or None for GDAL datasources
+    let mut postgres: Vec<Option<PgConfig>> = Vec::new();
+    for ds in &cfg.datasource {
+        if let Some(dbconn) = &ds.dbconn {
+            postgres.push(Some(PgConfig {
+                connection_string: Some(trex_env_vars(dbconn)),
+                ..Default::default()
+            }));
+        } else {
+            postgres.push(None);
+            res.warnings.push(format!(
+                "GDAL datasource {} ({}) is not supported, convert it to PostGIS, MBTiles, or PMTiles",
+                ds.name.as_deref().unwrap_or("<unnamed>"),
+                ds.path.as_deref().unwrap_or_default()
+            ));
+        }
+    }
+
+    for tileset in &cfg.tileset {
+        let mut ids = Vec::new();
+        for layer in &tileset.layer {
+            let ds = match &layer.datasource {
+                Some(name) => cfg
+                    .datasource
+                    .iter()
+                    .position(|v| v.name.as_ref() == Some(name)),
+                None => Some(default_ds),
+            };
+            let Some(pg) = ds
+                .and_then(|i| postgres.get_mut(i))
+                .and_then(Option::as_mut)
+            else {
+                res.warnings.push(format!(
+                    "Layer {} of tileset {} does not use a PostGIS datasource, and was skipped",
+                    layer.name, tileset.name
+                ));
+                continue;
+            };
+            if let Some(table) = trex_table(tileset, layer, &mut res.warnings) {
+                let tables = pg.tables.get_or_insert_with(BTreeMap::new);
+                let id = if tileset.layer.len() == 1 {
+                    tileset.name.clone()
+                } else if tables.contains_key(&layer.name) {
+                    format!("{}_{}", tileset.name, layer.name)
+                } else {
+                    layer.name.clone()
+                };
+                tables.insert(id.clone(), table);
+                ids.push(id);
+            }
+        }
+        if ids.len() > 1 {
+            res.warnings.push(format!(
+                "Tileset {} is served as the composite source {}, e.g. /{}/{{z}}/{{x}}/{{y}}",
+                tileset.name,
+                ids.join(","),
+                ids.join(",")
+            ));
+        }
+    }
+    res.config.postgres = OptOneMany::new(postgres.into_iter().flatten());
+
+    if let Some(web) = cfg.webserver {
+        if web.bind.is_some() || web.port.is_some() {
+            let bind = web.bind.as_deref().unwrap_or("0.0.0.0");
+            res.config.srv.listen_addresses = Some(format!("{bind}:{}", web.port.unwrap_or(3000)));
+        }
+    }
+    if cfg.cache.is_some() {
+        res.warnings.push(
+            "Martin does not store tiles in a cache directory, pre-generate them with martin-cp or use a caching proxy instead".to_string(),
+        );
+    }
+    res
+}
+
+/// Table source of a t-rex layer, or `None` if the layer cannot be served as a table
+fn trex_table(
+    tileset: &TrexTileset,
+    layer: &TrexLayer,
+    warnings: &mut Vec<String>,
+) -> Option<TableInfo> {
+    let name = format!("Layer {} of tileset {}", layer.name, tileset.name);
+    if !layer.query.is_empty() {
+        warnings.push(format!(
+            "{name} uses custom SQL queries, and was skipped. Create a view or a function source with the same SQL"
+        ));
+        return None;
+    }
+    let (Some(table_name), Some(geometry_column), Some(srid)) =
+        (&layer.table_name, &layer.geometry_field, layer.srid)
+    else {
+        warnings.push(format!(
+            "{name} needs table_name, geometry_field, and srid to be migrated, and was skipped. Martin can also publish tables automatically"
+        ));
+        return None;
+    };
+    let (schema, table) = table_name.split_once('.').unwrap_or(("public", table_name));
+    Some(TableInfo {
+        layer_id: Some(layer.name.clone()),
+        schema: schema.to_string(),
+        table: table.to_string(),
+        srid,
+        geometry_column: geometry_column.clone(),
+        id_column: layer.fid_field.clone(),
+        minzoom: layer.minzoom.or(tileset.minzoom),
+        maxzoom: layer.maxzoom.or(tileset.maxzoom),
+        bounds: tileset
+            .extent
+            .as_ref()
+            .map(|e| Bounds::new(e.minx, e.miny, e.maxx, e.maxy)),
+        extent: layer.tile_size,
+        buffer: layer.buffer_size,
+        geometry_type: layer.geometry_type.clone(),
+        ..Default::default()
+    })
+}
+
+/// Convert t-rex `{{ env.NAME }}` templates to Martin `${NAME}` variables
+fn trex_env_vars(value: &str) -> String {
+    let mut res = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        let inner = rest[start + 2..start + end].trim();
+        res.push_str(&rest[..start]);
+        match inner.strip_prefix("env.") {
+            Some(var) => {
+                let _ = write!(res, "${{{}}}", var.trim());
+            }
+            None => res.push_str(&rest[start..start + end + 2]),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    res.push_str(rest);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    /// The generated config must be readable by Martin
+    fn round_trip(config: &Config) -> String {
+        let yaml = serde_yaml::to_string(config).unwrap();
+        assert_eq!(&serde_yaml::from_str::<Config>(&yaml).unwrap(), config);
+        yaml
+    }
+
+    #[test]
+    fn tileserver_gl() {
+        let dir = std::env::temp_dir().join(format!("martin-migrate-{}", std::process::id()));
+        for sub in ["data", "fonts/Noto", "sprites/icons", "styles"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        fs::write(dir.join("fonts/Noto/NotoSans.ttf"), "").unwrap();
+        fs::write(dir.join("sprites/icons/marker.svg"), "").unwrap();
+        fs::write(
+            dir.join("styles/basic.json"),
+            r#"{"sources": {"omt": {"type": "vector", "url": "mbtiles://{openmaptiles}"}}}"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("config.json"),
+            indoc! {r#"
+                {
+                  "options": {"paths": {"fonts": "fonts", "sprites": "sprites", "styles": "styles", "mbtiles": "data"}},
+                  "styles": {"basic": {"style": "basic.json"}},
+                  "data": {
+                    "openmaptiles": {"mbtiles": "zurich.mbtiles"},
+                    "terrain": {"pmtiles": "https://example.com/terrain.pmtiles"},
+                    "broken": {}
+                  }
+                }
+            "#},
+        )
+        .unwrap();
+
+        let res = migrate_config(MigrateFrom::TileserverGl, &dir.join("config.json")).unwrap();
+        let yaml = round_trip(&res.config);
+        let dir_str = dir.display();
+        assert_eq!(
+            yaml,
+            format!(
+                indoc! {"
+                    pmtiles:
+                      sources:
+                        terrain: https://example.com/terrain.pmtiles
+                    mbtiles:
+                      sources:
+                        openmaptiles: {0}/data/zurich.mbtiles
+                    sprites: {0}/sprites/icons
+                    fonts: {0}/fonts
+                "},
+                dir_str
+            )
+        );
+        assert_eq!(res.warnings.len(), 2, "{:?}", res.warnings);
+        assert!(res.warnings[0].contains("broken"));
+        assert!(res.warnings[1].contains("mbtiles://{openmaptiles} -> <martin>/openmaptiles"));
+
+        // Pre-built glyphs and sprite sheets cannot be served by Martin
+        fs::remove_dir_all(dir.join("fonts/Noto")).unwrap();
+        fs::remove_dir_all(dir.join("sprites/icons")).unwrap();
+        let res = migrate_config(MigrateFrom::TileserverGl, &dir.join("config.json")).unwrap();
+        assert!(res.config.fonts.is_none());
+        assert!(res.config.sprites.is_none());
+        assert_eq!(res.warnings.len(), 4, "{:?}", res.warnings);
+
+        fs::write(dir.join("bad.json"), "[").unwrap();
+        assert!(matches!(
+            migrate_config(MigrateFrom::TileserverGl, &dir.join("bad.json")),
+            Err(MigrationParseError(..))
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn t_rex() {
+        let cfg = toml::from_str(indoc! {r#"
+            [[datasource]]
+            name = "db"
+            dbconn = "postgresql://{{ env.DB_USER }}@localhost/osm"
+
+            [[datasource]]
+            name = "shapes"
+            path = "natural_earth.shp"
+
+            [[tileset]]
+            name = "osm"
+            minzoom = 0
+            maxzoom = 14
+
+            [[tileset.layer]]
+            name = "points"
+            table_name = "osm.places"
+            geometry_field = "geom"
+            geometry_type = "POINT"
+            srid = 3857
+            fid_field = "id"
+            buffer_size = 10
+
+            [[tileset.layer]]
+            name = "roads"
+            geometry_field = "geom"
+            [[tileset.layer.query]]
+            sql = "SELECT geom FROM roads"
+
+            [[tileset.layer]]
+            name = "coast"
+            datasource = "shapes"
+
+            [[tileset]]
+            name = "lakes"
+            [[tileset.layer]]
+            name = "water"
+            table_name = "lakes"
+            geometry_field = "wkb_geometry"
+            srid = 4326
+            maxzoom = 8
+
+            [webserver]
+            bind = "127.0.0.1"
+            port = 6767
+        "#})
+        .unwrap();
+        let res = migrate_t_rex(cfg);
+        assert_eq!(
+            round_trip(&res.config),
+            indoc! {"
+                listen_addresses: 127.0.0.1:6767
+                postgres:
+                  connection_string: postgresql://${DB_USER}@localhost/osm
+                  tables:
+                    lakes:
+                      layer_id: water
+                      schema: public
+                      table: lakes
+                      srid: 4326
+                      geometry_column: wkb_geometry
+                      maxzoom: 8
+                    points:
+                      layer_id: points
+                      schema: osm
+                      table: places
+                      srid: 3857
+                      geometry_column: geom
+                      id_column: id
+                      minzoom: 0
+                      maxzoom: 14
+                      buffer: 10
+                      geometry_type: POINT
+            "}
+        );
+        assert_eq!(res.warnings.len(), 3, "{:?}", res.warnings);
+        assert!(res.warnings[0].contains("GDAL datasource shapes"));
+        assert!(res.warnings[1].contains("Layer roads"));
+        assert!(res.warnings[2].contains("Layer coast"));
+
+        assert_eq!(
+            trex_env_vars("{{env.A}}:{{ env.B }}@{{ other }}"),
+            "${A}:${B}@{{ other }}"
+        );
+    }
+}
@@ -0,0 +1,20 @@
+mod advise;
+pub use advise::{advise_tables, TableAdviceReport};
+
+mod bench;
+pub use bench::{bench_source, percentile, BenchReport, ZoomBench};
+
+mod fixture;
+pub use fixture::generate_fixture;
+
+mod install_functions;
+pub use install_functions::install_functions;
+
+mod migrate;
+pub use migrate::{migrate_config, ConfigMigration};
+
+mod replay;
+pub use replay::{replay_requests, ReplayTarget};
+
+mod test_sources;
+pub use test_sources::{sample_tile, test_sources, SourceTest, SourceTestReport, TestStatus};
@@ -0,0 +1,335 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use actix_http::test::TestRequest;
+use actix_web::body::{BodySize, MessageBody as _};
+use actix_web::http::header::{
+    AcceptEncoding, Header as _, HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING,
+};
+use futures::stream::{self, StreamExt};
+use log::{debug, info, warn};
+use tokio::time::{sleep_until, Instant};
+use url::Url;
+
+use crate::args::ReplayArgs;
+use crate::commands::bench::{BenchReport, Sample, ZoomBench};
+use crate::source::TileSources;
+use crate::srv::{get_tile_response, RecordedRequest, RecordedResponse};
+use crate::utils::send_request;
+
+/// Where the recorded requests are re-issued
+#[derive(Clone, Copy)]
+pub enum ReplayTarget<'a> {
+    /// The sources initialized from the configuration, without starting a server
+    Sources(&'a TileSources),
+    /// A running Martin instance, e.g. a test deployment, given by its base URL
+    Url(&'a Url),
+}
+
+/// Duration of the request, size of the response if it succeeded, and summary of the response
+type Replayed = (Duration, Option<usize>, Option<RecordedResponse>);
+
+/// Re-issue the recorded requests against the given target, keeping the original pace
+/// adjusted by the replay speed, and measure how long each tile takes.
+/// With `--compare`, also returns the number of responses that differ from the recorded ones.
+pub async fn replay_requests(
+    target: ReplayTarget<'_>,
+    requests: Vec<RecordedRequest>,
+    args: &ReplayArgs,
+) -> (BenchReport, usize) {
+    let speed = args.speed;
+    let total = requests.len();
+    info!("Replaying {total} tile requests at {speed}x speed");
+
+    // The recording may start long before the first request was made
+    let first_ms = requests.iter().map(|r| r.time_ms).min().unwrap_or_default();
+    let start = Instant::now();
+    let samples = stream::iter(requests)
+        .map(|req| async move {
+            if let Some(offset) = replay_offset(req.time_ms, first_ms, speed) {
+                sleep_until(start + offset).await;
+            }
+            (req.z, replay_request(target, &req, args.compare).await)
+        })
+        .buffer_unordered(args.concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    let elapsed = start.elapsed();
+
+    let mut zooms = BTreeMap::<u8, Vec<Sample>>::new();
+    let mut changed = 0;
+    for (zoom, (sample, same)) in samples {
+        zooms.entry(zoom).or_default().push(sample);
+        changed += usize::from(!same);
+    }
+    let report = BenchReport(
+        zooms
+            .into_iter()
+            .map(|(zoom, samples)| ZoomBench::new(zoom, &samples, elapsed))
+            .collect(),
+    );
+
+    let errors = report.0.iter().map(|z| z.errors).sum::<usize>();
+    if errors > 0 {
+        warn!("{errors} of {total} requests failed, use RUST_LOG=martin=debug to see the errors");
+    }
+    info!("Replayed {total} tile requests in {elapsed:.1?}");
+    (report, changed)
+}
+
+/// When to issue a request recorded at `time_ms`, relative to the start of the replay,
+/// or `None` to issue it right away because the speed is 0
+fn replay_offset(time_ms: u64, first_ms: u64, speed: f64) -> Option<Duration> {
+    #[allow(clippy::cast_precision_loss)]
+    let recorded = time_ms.saturating_sub(first_ms) as f64 / 1000.0;
+    (speed > 0.0).then(|| Duration::from_secs_f64(recorded / speed))
+}
+
+/// Replay a single request, and check if its response is the same as the recorded one
+async fn replay_request(
+    target: ReplayTarget<'_>,
+    req: &RecordedRequest,
+    compare: bool,
+) -> (Sample, bool) {
+    let expected = req.response.as_ref().filter(|_| compare);
+    let (duration, size, actual) = match target {
+        ReplayTarget::Sources(sources) => replay_to_sources(sources, req, expected.is_some()).await,
+        ReplayTarget::Url(url) => replay_to_url(url, req).await,
+    };
+    let same = match expected {
+        Some(expected) if actual.as_ref() != Some(expected) => {
+            warn!(
+                "The response to {}/{:#} differs from the recorded one: {actual:?} instead of {expected:?}",
+                req.source,
+                req.xyz()
+            );
+            false
+        }
+        _ => true,
+    };
+    (Sample { duration, size }, same)
+}
+
+/// Get the tile from the sources the same way the server would
+async fn replay_to_sources(
+    sources: &TileSources,
+    req: &RecordedRequest,
+    compare: bool,
+) -> Replayed {
+    let encodings = req.encoding.as_deref().and_then(|v| {
+        let req = TestRequest::default()
+            .insert_header((ACCEPT_ENCODING, v))
+            .finish();
+        AcceptEncoding::parse(&req).ok()
+    });
+    let query = req.query.as_deref().unwrap_or_default();
+
+    let tile_start = Instant::now();
+    let response = get_tile_response(sources, req.xyz(), &req.source, query, encodings).await;
+    let duration = tile_start.elapsed();
+
+    match response {
+        Ok(response) => {
+            let size = match response.body().size() {
+                BodySize::Sized(size) => usize::try_from(size).ok(),
+                BodySize::None | BodySize::Stream => Some(0),
+            };
+            let actual = if compare {
+                let status = response.status().as_u16();
+                let (response, body) = response.into_parts();
+                let body = body.try_into_bytes().ok();
+                body.map(|v| RecordedResponse::new(status, response.headers(), &v))
+            } else {
+                None
+            };
+            (duration, size, actual)
+        }
+        Err(e) => {
+            debug!("Unable to replay {}/{:#}: {e}", req.source, req.xyz());
+            (duration, None, None)
+        }
+    }
+}
+
+/// Request the tile from a running instance with the recorded query and `Accept-Encoding` header
+async fn replay_to_url(base: &Url, req: &RecordedRequest) -> Replayed {
+    let url = tile_url(base, req);
+    let headers: Vec<_> = req
+        .encoding
+        .as_deref()
+        .map(|v| ("Accept-Encoding", v))
+        .into_iter()
+        .collect();
+
+    let tile_start = Instant::now();
+    let response = send_request("GET", &url, &headers, &[]).await;
+    let duration = tile_start.elapsed();
+
+    match response {
+        Ok(response) => {
+            let mut headers = HeaderMap::new();
+            for (name, value) in &response.headers {
+                if let (Ok(name), Ok(value)) = (
+                    HeaderName::from_bytes(name.as_bytes()),
+                    HeaderValue::from_str(value),
+                ) {
+                    headers.append(name, value);
+                }
+            }
+            let actual = RecordedResponse::new(response.status, &headers, &response.body);
+            // Error responses are counted as failed requests, but can still be compared
+            let size = if response.status < 400 {
+                Some(response.body.len())
+            } else {
+                debug!("Unable to replay {url}: status {}", response.status);
+                None
+            };
+            (duration, size, Some(actual))
+        }
+        Err(e) => {
+            debug!("Unable to replay {url}: {e}");
+            (duration, None, None)
+        }
+    }
+}
+
+/// URL of the recorded tile, appended to the path of the base URL, e.g. `http://host/tiles/points/1/2/3?a=b`
+fn tile_url(base: &Url, req: &RecordedRequest) -> Url {
+    let mut url = base.clone();
+    if let Ok(mut path) = url.path_segments_mut() {
+        path.pop_if_empty().extend([
+            req.source.clone(),
+            req.z.to_string(),
+            req.x.to_string(),
+            req.y.to_string(),
+        ]);
+    }
+    url.set_query(req.query.as_deref());
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn request(time_ms: u64, source: &str, query: Option<&str>) -> RecordedRequest {
+        RecordedRequest {
+            time_ms,
+            source: source.to_string(),
+            z: 3,
+            x: 2,
+            y: 1,
+            query: query.map(ToString::to_string),
+            encoding: None,
+            response: None,
+        }
+    }
+
+    #[test]
+    fn replay_offsets() {
+        let ms = Duration::from_millis;
+        assert_eq!(replay_offset(1500, 500, 1.0), Some(ms(1000)));
+        assert_eq!(replay_offset(1500, 500, 4.0), Some(ms(250)));
+        assert_eq!(replay_offset(1500, 500, 0.5), Some(ms(2000)));
+        assert_eq!(replay_offset(500, 500, 2.0), Some(ms(0)));
+        assert_eq!(replay_offset(1500, 500, 0.0), None);
+    }
+
+    #[test]
+    fn tile_urls() {
+        let url = |base: &str, req| tile_url(&Url::parse(base).unwrap(), &req).to_string();
+        assert_eq!(
+            url("http://localhost:3000", request(0, "points", None)),
+            "http://localhost:3000/points/3/2/1"
+        );
+        assert_eq!(
+            url(
+                "https://example.com/tiles/",
+                request(0, "points,lines", Some("a=1&b=2"))
+            ),
+            "https://example.com/tiles/points,lines/3/2/1?a=1&b=2"
+        );
+        assert_eq!(
+            url("http://localhost:3000/?old=1", request(0, "a b", None)),
+            "http://localhost:3000/a%20b/3/2/1"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn replay_to_running_instance() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let paths = Arc::new(Mutex::new(Vec::new()));
+        let server_paths = paths.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let paths = server_paths.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    let mut request = Vec::new();
+                    while let Ok(len @ 1..) = stream.read(&mut buf).await {
+                        request.extend_from_slice(&buf[..len]);
+                        if !request.ends_with(b"\r\n\r\n") {
+                            continue;
+                        }
+                        let text = String::from_utf8(std::mem::take(&mut request)).unwrap();
+                        let path = text.split(' ').nth(1).unwrap().to_string();
+                        let response = if path.starts_with("/missing/") {
+                            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"
+                        } else {
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/x-protobuf\r\nContent-Length: 4\r\n\r\ntile"
+                        };
+                        paths.lock().unwrap().push(path);
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            actix_web::http::header::CONTENT_TYPE,
+            HeaderValue::from_static("application/x-protobuf"),
+        );
+        let tile = RecordedResponse::new(200, &headers, b"tile");
+        let mut other = tile.clone();
+        other.hash = "0".to_string();
+        let mut requests = vec![
+            request(1000, "points", Some("a=1")),
+            request(1200, "points", None),
+            request(1400, "missing", None),
+        ];
+        requests[0].response = Some(tile);
+        requests[1].response = Some(other);
+        let args = ReplayArgs {
+            recording: PathBuf::new(),
+            compare: true,
+            speed: 2.0,
+            concurrency: 1,
+            url: Some(url.clone()),
+            connection: Vec::new(),
+        };
+
+        let (report, changed) = replay_requests(ReplayTarget::Url(&url), requests, &args).await;
+        // Only the second response has a different hash, the third one was not recorded
+        assert_eq!(changed, 1);
+        let zoom = &report.0[0];
+        assert_eq!(
+            (zoom.zoom, zoom.tiles, zoom.errors, zoom.bytes),
+            (3, 3, 1, 8)
+        );
+        // The last request is made 400ms after the first one in the recording, replayed twice as fast
+        assert!(zoom.elapsed >= Duration::from_millis(200));
+        assert_eq!(
+            *paths.lock().unwrap(),
+            ["/points/3/2/1?a=1", "/points/3/2/1", "/missing/3/2/1"]
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+use martin_tile_utils::{Encoding, Format, TileInfo, MAX_LATITUDE};
+use tilejson::{Bounds, TileJSON};
+
+use crate::source::{Source, TileSources};
+use crate::utils::{decode_brotli, decode_gzip, decode_zstd, mvt_feature_counts};
+use crate::{tile_index, TileCoord};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TestStatus {
+    /// The tile was generated and successfully decoded
+    Passed(String),
+    /// The source returned no data for the sample tile
+    Empty,
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceTest {
+    pub id: String,
+    pub xyz: TileCoord,
+    pub info: TileInfo,
+    pub size: usize,
+    pub duration: Duration,
+    pub status: TestStatus,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceTestReport(pub Vec<SourceTest>);
+
+impl SourceTestReport {
+    #[must_use]
+    pub fn failed(&self) -> usize {
+        self.0
+            .iter()
+            .filter(|t| matches!(t.status, TestStatus::Failed(_)))
+            .count()
+    }
+}
+
+impl Display for SourceTestReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let width = self.0.iter().map(|t| t.id.len()).max().unwrap_or(0).max(6);
+        let fmt_width = self
+            .0
+            .iter()
+            .map(|t| t.info.to_string().len())
+            .max()
+            .unwrap_or(0)
+            .max(6);
+        writeln!(
+            f,
+            " {:<width$} | {:^13} | {:^fmt_width$} | {:>9} | {:>8} | Result",
+            "Source", "Tile", "Format", "Size", "Time"
+        )?;
+        for t in &self.0 {
+            let status = match &t.status {
+                TestStatus::Passed(v) => format!("PASS {v}"),
+                TestStatus::Empty => "PASS empty tile".to_string(),
+                TestStatus::Failed(e) => format!("FAIL {e}"),
+            };
+            writeln!(
+                f,
+                " {:<width$} | {:>13} | {:<fmt_width$} | {:>9} | {:>8} | {status}",
+                t.id,
+                format!("{:#}", t.xyz),
+                t.info.to_string(),
+                format!("{}B", t.size),
+                format!("{:.1?}", t.duration),
+            )?;
+        }
+        let failed = self.failed();
+        write!(f, "{} passed, {failed} failed", self.0.len() - failed)
+    }
+}
+
+/// Fetch one sample tile from each source, and check that it can be decoded
+pub async fn test_sources(sources: &TileSources) -> SourceTestReport {
+    let mut results = Vec::new();
+    for id in sources.get_catalog().into_keys() {
+        let Ok(src) = sources.get_source(&id) else {
+            continue;
+        };
+        results.push(test_source(src).await);
+    }
+    SourceTestReport(results)
+}
+
+async fn test_source(src: &dyn Source) -> SourceTest {
+    let xyz = sample_tile(src.get_tilejson());
+    let info = src.get_tile_info();
+    let start = Instant::now();
+    let tile = src.get_tile(&xyz, &None).await;
+    let duration = start.elapsed();
+    let (size, status) = match tile {
+        Ok(data) if data.is_empty() => (0, TestStatus::Empty),
+        Ok(data) => (
+            data.len(),
+            match validate_tile(&data, info) {
+                Ok(v) => TestStatus::Passed(v),
+                Err(e) => TestStatus::Failed(e),
+            },
+        ),
+        Err(e) => (0, TestStatus::Failed(e.to_string())),
+    };
+    SourceTest {
+        id: src.get_id().to_string(),
+        xyz,
+        info,
+        size,
+        duration,
+        status,
+    }
+}
+
+/// Pick the tile at the center of the source bounds, one zoom level above the minimum zoom
+#[must_use]
+pub fn sample_tile(tj: &TileJSON) -> TileCoord {
+    let min = tj.minzoom.unwrap_or(0);
+    let z = tj
+        .maxzoom
+        .map_or(min.saturating_add(1), |max| min.saturating_add(1).min(max))
+        .min(30);
+    let b = tj.bounds.unwrap_or(Bounds::MAX_TILED);
+    let lon = (b.left + b.right) / 2.0;
+    let lat = ((b.bottom + b.top) / 2.0).clamp(-MAX_LATITUDE, MAX_LATITUDE);
+    let (x, y) = tile_index(lon, lat, z);
+    TileCoord { z, x, y }
+}
+
+/// Decompress the tile and verify that its content matches the expected format
+fn validate_tile(data: &[u8], info: TileInfo) -> Result<String, String> {
+    let data = match info.encoding {
+        Encoding::Uncompressed | Encoding::Internal => data.to_vec(),
+        Encoding::Gzip => decode_gzip(data).map_err(|e| format!("invalid gzip data: {e}"))?,
+        Encoding::Brotli => decode_brotli(data).map_err(|e| format!("invalid brotli data: {e}"))?,
+        Encoding::Zstd => decode_zstd(data).map_err(|e| format!("invalid zstd data: {e}"))?,
+        Encoding::Zlib => {
+            return Ok(format!("{info} content was not verified"));
+        }
+    };
+    match info.format {
+        Format::Mvt => mvt_feature_counts(&data).map(|v| format!("{} layers", v.len())),
+        Format::Json => serde_json::from_slice::<serde_json::Value>(&data)
+            .map(|_| "valid JSON".to_string())
+            .map_err(|e| format!("invalid JSON: {e}")),
+        fmt => match TileInfo::detect(&data) {
+            Some(detected) if detected.format == fmt => Ok(format!("valid {fmt}")),
+            Some(detected) => Err(format!("expected {fmt}, but got {}", detected.format)),
+            None => Err(format!("not a valid {fmt} image")),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tilejson::tilejson;
+
+    use super::*;
+    use crate::utils::encode_gzip;
+
+    #[test]
+    fn sample_tile_coords() {
+        let mut tj = tilejson! { tiles: vec![] };
+        let xyz = sample_tile(&tj);
+        assert_eq!(format!("{xyz:#}"), "1/1/0");
+
+        tj.minzoom = Some(14);
+        tj.maxzoom = Some(14);
+        tj.bounds = Some(Bounds::new(-180.0, -90.0, 180.0, 90.0));
+        let xyz = sample_tile(&tj);
+        assert_eq!(format!("{xyz:#}"), "14/8192/8192");
+
+        tj.minzoom = Some(2);
+        tj.maxzoom = None;
+        tj.bounds = Some(Bounds::new(-124.8489, 24.3963, -66.8854, 49.3843));
+        let xyz = sample_tile(&tj);
+        assert_eq!(format!("{xyz:#}"), "3/1/3");
+    }
+
+    #[test]
+    fn validate_mvt() {
+        // A tile with one layer named "a"
+        let layer = [0x1A, 0x05, 0x0A, 0x01, b'a', 0x78, 0x02];
+        let info = TileInfo::new(Format::Mvt, Encoding::Uncompressed);
+        assert_eq!(validate_tile(&layer, info), Ok("1 layers".to_string()));
+
+        let info = TileInfo::new(Format::Mvt, Encoding::Gzip);
+        let gzip = encode_gzip(&layer).unwrap();
+        assert_eq!(validate_tile(&gzip, info), Ok("1 layers".to_string()));
+        assert!(validate_tile(&layer, info).is_err());
+
+        let info = TileInfo::new(Format::Mvt, Encoding::Uncompressed);
+        assert!(validate_tile(&layer[..4], info).is_err());
+        assert!(validate_tile(&[0x1A, 0x02, 0x78, 0x02], info).is_err());
+        assert!(validate_tile(b"not a tile", info).is_err());
+    }
+
+    #[test]
+    fn validate_images() {
+        let png = b"\x89\x50\x4E\x47\x0D\x0A\x1A\x0A";
+        let info = TileInfo::from(Format::Png);
+        assert_eq!(validate_tile(png, info), Ok("valid png".to_string()));
+        let info = TileInfo::from(Format::Jpeg);
+        assert_eq!(
+            validate_tile(png, info),
+            Err("expected jpeg, but got png".to_string())
+        );
+        assert!(validate_tile(b"foo", info).is_err());
+    }
+}
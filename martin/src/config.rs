@@ -1,26 +1,37 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::File;
 use std::future::Future;
 use std::io::prelude::*;
+use std::mem;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::Arc;
 
-use futures::future::try_join_all;
-use log::info;
+use futures::future::{try_join_all, LocalBoxFuture};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt as _;
+use log::{error, info};
 use serde::{Deserialize, Serialize};
 use subst::VariableMap;
+use tokio::task::JoinHandle;
 
-use crate::file_config::{resolve_files, FileConfigEnum};
+use crate::derived::{resolve_derived, DerivedConfig};
+use crate::file_config::{list_files, resolve_files, FileConfigEnum};
 use crate::fonts::FontSources;
 use crate::mbtiles::MbtSource;
 use crate::pg::PgConfig;
+use crate::pg::PgError::DuplicateConnectionId;
 use crate::pmtiles::PmtSource;
 use crate::source::{TileInfoSources, TileSources};
 use crate::sprites::SpriteSources;
-use crate::srv::SrvConfig;
-use crate::MartinError::{ConfigLoadError, ConfigParseError, ConfigWriteError, NoSources};
-use crate::{IdResolver, MartinResult, OptOneMany};
+use crate::srv::{ConfigStaging, InitState, Readiness, ReadyWhen, SrvConfig};
+use crate::variants::{resolve_variants, VariantConfig};
+use crate::MartinError::{
+    ConfigLoadError, ConfigParseError, ConfigWriteError, DuplicateSourceIds, InvalidSourceIds,
+    NoSources,
+};
+use crate::{DuplicateIdStrategy, IdResolver, IdRules, MartinResult, OptOneMany};
 
 pub type UnrecognizedValues = HashMap<String, serde_yaml::Value>;
 
@@ -28,6 +39,10 @@ pub struct ServerState {
     pub tiles: TileSources,
     pub sprites: SpriteSources,
     pub fonts: FontSources,
+    /// Progress of the tile sources that are initialized in the background, see [`Config::resolve_in_background`]
+    pub readiness: Option<Arc<Readiness>>,
+    /// Switchover to a configuration loaded while the server is running, see [`SrvConfig::config_staging`](crate::srv::SrvConfig::config_staging)
+    pub staging: Option<Arc<ConfigStaging>>,
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
@@ -50,6 +65,18 @@ pub struct Config {
     #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
     pub fonts: OptOneMany<PathBuf>,
 
+    #[serde(default, skip_serializing_if = "DerivedConfig::is_empty")]
+    pub derived: DerivedConfig,
+
+    #[serde(default, skip_serializing_if = "VariantConfig::is_empty")]
+    pub variants: VariantConfig,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_duplicate_id: Option<DuplicateIdStrategy>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_ids: Option<IdRules>,
+
     #[serde(flatten)]
     pub unrecognized: UnrecognizedValues,
 }
@@ -63,6 +90,16 @@ impl Config {
         for pg in self.postgres.iter_mut() {
             res.extend(pg.finalize()?);
         }
+        let mut pg_ids = HashSet::new();
+        for id in self.postgres.iter().filter_map(|v| v.id.as_ref()) {
+            if !pg_ids.insert(id) {
+                return Err(DuplicateConnectionId(id.clone()).into());
+            }
+        }
+
+        if let Some(rules) = &self.source_ids {
+            rules.validate().map_err(InvalidSourceIds)?;
+        }
 
         res.extend(self.pmtiles.finalize("pmtiles.")?);
         res.extend(self.mbtiles.finalize("mbtiles.")?);
@@ -88,10 +125,110 @@ impl Config {
             tiles: self.resolve_tile_sources(idr).await?,
             sprites: SpriteSources::resolve(&mut self.sprites)?,
             fonts: FontSources::resolve(&mut self.fonts)?,
+            readiness: None,
+            staging: None,
         })
     }
 
+    /// Resolve the sprites and fonts, and start resolving the tile sources in a background task.
+    /// Each source file and each PostgreSQL connection is initialized separately, and a failure
+    /// only affects its own sources. The tile sources of the returned state are added as they
+    /// become ready, and the task returns the config with all the resolved sources.
+    pub fn resolve_in_background(
+        mut self,
+        idr: IdResolver,
+        ready_when: ReadyWhen,
+    ) -> MartinResult<(ServerState, JoinHandle<Self>)> {
+        let sprites = SpriteSources::resolve(&mut self.sprites)?;
+        let fonts = FontSources::resolve(&mut self.fonts)?;
+        let idr = idr
+            .with_strategy(self.on_duplicate_id.unwrap_or_default())
+            .with_rules(&self.source_ids.clone().unwrap_or_default());
+
+        let mut files = list_files(&mut self.pmtiles, &idr, "pmtiles", &mut PmtSource::new_box);
+        files.extend(list_files(
+            &mut self.mbtiles,
+            &idr,
+            "mbtiles",
+            &mut MbtSource::new_box,
+        ));
+        let pg_count = self.postgres.as_slice().len();
+        let pg_units: Vec<_> = self
+            .postgres
+            .iter()
+            .enumerate()
+            .map(|(i, pg)| match (&pg.id, pg_count) {
+                (Some(id), _) => format!("postgres.{id}"),
+                (None, 1) => "postgres".to_string(),
+                (None, _) => format!("postgres.{}", i + 1),
+            })
+            .collect();
+        let mut units = pg_units.clone();
+        units.extend(files.iter().map(|(name, _)| name.clone()));
+        if !self.derived.is_empty() {
+            units.push("derived".to_string());
+        }
+        if !self.variants.is_empty() {
+            units.push("variants".to_string());
+        }
+        let readiness = Arc::new(Readiness::new(ready_when, units));
+        let tiles = TileSources::default().with_id_resolver(&idr);
+
+        let task = {
+            let readiness = readiness.clone();
+            let tiles = tiles.clone();
+            async move {
+                let mut postgres = mem::take(&mut self.postgres);
+                let mut pending: FuturesUnordered<
+                    LocalBoxFuture<'_, (String, MartinResult<TileInfoSources>)>,
+                > = FuturesUnordered::new();
+                for (unit, pg) in pg_units.into_iter().zip(postgres.iter_mut()) {
+                    let idr = idr.clone();
+                    pending.push(Box::pin(async move { (unit, pg.resolve(idr).await) }));
+                }
+                for (unit, file) in files {
+                    pending.push(Box::pin(async move {
+                        (unit, file.await.map(|v| vec![v]).map_err(Into::into))
+                    }));
+                }
+                while let Some((unit, result)) = pending.next().await {
+                    add_late_sources(&tiles, &readiness, &unit, result);
+                }
+                drop(pending);
+                self.postgres = postgres;
+
+                if !self.derived.is_empty() {
+                    let derived = resolve_derived(&self.derived, &tiles, &idr);
+                    add_late_sources(&tiles, &readiness, "derived", derived.map_err(Into::into));
+                }
+                if !self.variants.is_empty() {
+                    let variants = resolve_variants(&self.variants, &tiles, &idr);
+                    add_late_sources(&tiles, &readiness, "variants", variants.map_err(Into::into));
+                }
+                // The server is already running, so duplicate IDs can no longer stop it
+                if let Err(e) = check_duplicate_ids(&idr) {
+                    error!("{e}");
+                }
+                idr.report();
+                readiness.finish();
+                self
+            }
+        };
+
+        let state = ServerState {
+            tiles,
+            sprites,
+            fonts,
+            readiness: Some(readiness),
+            staging: None,
+        };
+        Ok((state, actix_rt::spawn(task)))
+    }
+
     async fn resolve_tile_sources(&mut self, idr: IdResolver) -> MartinResult<TileSources> {
+        let idr = idr
+            .with_strategy(self.on_duplicate_id.unwrap_or_default())
+            .with_rules(&self.source_ids.clone().unwrap_or_default());
         let new_pmt_src = &mut PmtSource::new_box;
         let new_mbt_src = &mut MbtSource::new_box;
         let mut sources: Vec<Pin<Box<dyn Future<Output = MartinResult<TileInfoSources>>>>> =
@@ -111,7 +248,17 @@ impl Config {
             sources.push(Box::pin(val));
         }
 
-        Ok(TileSources::new(try_join_all(sources).await?))
+        let mut tiles = TileSources::new(try_join_all(sources).await?).with_id_resolver(&idr);
+        if !self.derived.is_empty() {
+            tiles.extend(resolve_derived(&self.derived, &tiles, &idr)?);
+        }
+        if !self.variants.is_empty() {
+            tiles.extend(resolve_variants(&self.variants, &tiles, &idr)?);
+        }
+
+        check_duplicate_ids(&idr)?;
+        idr.report();
+        Ok(tiles)
     }
 
     pub fn save_to_file(&self, file_name: PathBuf) -> MartinResult<()> {
@@ -135,6 +282,47 @@ impl Config {
     }
 }
 
+/// Fail if several sources use the same ID, unless the resolver is allowed to rename them
+fn check_duplicate_ids(idr: &IdResolver) -> MartinResult<()> {
+    if idr.strategy() == DuplicateIdStrategy::Error {
+        let duplicates = idr
+            .renamed()
+            .into_iter()
+            .filter(|v| v.conflict)
+            .map(|v| format!("{} ({})", v.name, v.unique_name))
+            .collect::<Vec<_>>();
+        if !duplicates.is_empty() {
+            return Err(DuplicateSourceIds(duplicates));
+        }
+    }
+    Ok(())
+}
+
+/// Make the sources of a unit initialized in the background available, or record why it failed
+fn add_late_sources(
+    tiles: &TileSources,
+    readiness: &Readiness,
+    unit: &str,
+    result: MartinResult<TileInfoSources>,
+) {
+    match result {
+        Ok(sources) => {
+            info!("Initialized {} source(s) of {unit}", sources.len());
+            tiles.add_late(sources);
+            readiness.set(unit, InitState::Ready);
+        }
+        Err(e) => {
+            error!("Unable to initialize {unit}: {e}");
+            readiness.set(
+                unit,
+                InitState::Failed {
+                    error: e.to_string(),
+                },
+            );
+        }
+    }
+}
+
 pub fn copy_unrecognized_config(
     result: &mut UnrecognizedValues,
     prefix: &str,
@@ -0,0 +1,420 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use log::info;
+use martin_tile_utils::{Encoding, Format, TileInfo};
+use png::ColorType;
+use serde::{Deserialize, Serialize};
+use tilejson::TileJSON;
+
+use crate::derived::raster::{encode_png, Color, ColorRamp, Dem, Hillshade};
+use crate::derived::vector::{decode_mvt, encode_mvt, Rollup, MAX_ROLLUP_LEVELS};
+use crate::derived::DerivedError::{
+    EmptyColorRamp, InvalidRollupZoom, UnknownBaseSource, UnsupportedBaseFormat,
+    UnsupportedRollupFormat,
+};
+use crate::source::{Source, TileData, TileInfoSource, TileInfoSources, TileSources, UrlQuery};
+use crate::{IdResolver, MartinResult, TileCoord};
+
+mod raster;
+pub use raster::{downsample_png, upscale_png, DemEncoding};
+mod vector;
+
+pub type DerivedResult<T> = Result<T, DerivedError>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum DerivedError {
+    #[error("Derived source {0} is based on source {1}, which does not exist")]
+    UnknownBaseSource(String, String),
+
+    #[error("Derived source {0} requires a PNG-encoded DEM source, but source {1} has {2} tiles")]
+    UnsupportedBaseFormat(String, String, TileInfo),
+
+    #[error("Derived source {0} requires an uncompressed or gzip-compressed vector tile source, but source {1} has {2} tiles")]
+    UnsupportedRollupFormat(String, String, TileInfo),
+
+    #[error(
+        "Derived source {0} rolls up zoom {1}, which is not one of the zoom levels of source {2}"
+    )]
+    InvalidRollupZoom(String, u8, String),
+
+    #[error("Derived source {0} must have at least one color ramp stop")]
+    EmptyColorRamp(String),
+
+    #[error("Invalid color {0}, expected #rrggbb or #rrggbbaa")]
+    InvalidColor(String),
+
+    #[error("Unable to decode raster tile: {0}")]
+    RasterDecodeError(String),
+
+    #[error("Unable to encode derived tile: {0}")]
+    RasterEncodeError(String),
+
+    #[error("Unable to decode vector tile: {0}")]
+    VectorDecodeError(String),
+
+    #[error("Unable to encode vector tile: {0}")]
+    VectorEncodeError(String),
+}
+
+/// Derived sources keyed by their source ID
+pub type DerivedConfig = BTreeMap<String, DerivedSourceConfig>;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DerivedSourceConfig {
+    /// ID of the tile source to derive tiles from
+    pub source: String,
+    /// How elevation is encoded in the DEM tiles of the raster operations
+    #[serde(default)]
+    pub dem_encoding: DemEncoding,
+    /// Operation to apply to the base tiles
+    #[serde(flatten)]
+    pub operation: DerivedOperation,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "operation", rename_all = "kebab-case")]
+pub enum DerivedOperation {
+    Hillshade {
+        /// Direction of the light source in degrees clockwise from north [default: 315]
+        azimuth: Option<f64>,
+        /// Angle of the light source above the horizon in degrees [default: 45]
+        altitude: Option<f64>,
+        /// Vertical exaggeration factor [default: 1]
+        z_factor: Option<f64>,
+    },
+    ColorRelief {
+        /// Elevation to color mapping, linearly interpolated between stops
+        ramp: Vec<ColorRampStop>,
+    },
+    Rollup {
+        /// Zoom level of the vector tiles merged into the tiles of the lower zoom levels
+        from_zoom: u8,
+        /// Vertices closer than this to the previous vertex are dropped, in tile extent units [default: 8]
+        tolerance: Option<f64>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ColorRampStop {
+    pub elevation: f64,
+    pub color: String,
+}
+
+#[derive(Clone, Debug)]
+enum Operation {
+    Hillshade(Hillshade),
+    ColorRelief(ColorRamp),
+    Rollup(Rollup),
+}
+
+impl Operation {
+    fn new(id: &str, cfg: &DerivedOperation) -> DerivedResult<Self> {
+        Ok(match cfg {
+            DerivedOperation::Hillshade {
+                azimuth,
+                altitude,
+                z_factor,
+            } => Self::Hillshade(Hillshade {
+                azimuth: azimuth.unwrap_or(315.0),
+                altitude: altitude.unwrap_or(45.0),
+                z_factor: z_factor.unwrap_or(1.0),
+            }),
+            DerivedOperation::ColorRelief { ramp } => {
+                if ramp.is_empty() {
+                    return Err(EmptyColorRamp(id.to_string()));
+                }
+                let stops = ramp
+                    .iter()
+                    .map(|s| Ok((s.elevation, Color::parse(&s.color)?)))
+                    .collect::<DerivedResult<_>>()?;
+                Self::ColorRelief(ColorRamp::new(stops))
+            }
+            DerivedOperation::Rollup {
+                from_zoom,
+                tolerance,
+            } => Self::Rollup(Rollup {
+                from_zoom: *from_zoom,
+                tolerance: tolerance.unwrap_or(8.0),
+            }),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Hillshade(_) => "Hillshade",
+            Self::ColorRelief(_) => "Color relief",
+            Self::Rollup(_) => "Rollup",
+        }
+    }
+}
+
+/// Create derived sources on top of the already resolved tile sources
+pub fn resolve_derived(
+    config: &DerivedConfig,
+    sources: &TileSources,
+    idr: &IdResolver,
+) -> DerivedResult<TileInfoSources> {
+    let mut results = TileInfoSources::new();
+    for (id, cfg) in config {
+        let base = sources
+            .get_source(&cfg.source)
+            .map_err(|_| UnknownBaseSource(id.clone(), cfg.source.clone()))?;
+        let info = base.get_tile_info();
+        let operation = Operation::new(id, &cfg.operation)?;
+        if let Operation::Rollup(rollup) = &operation {
+            if info.format != Format::Mvt
+                || !matches!(info.encoding, Encoding::Uncompressed | Encoding::Gzip)
+            {
+                return Err(UnsupportedRollupFormat(
+                    id.clone(),
+                    cfg.source.clone(),
+                    info,
+                ));
+            }
+            if !base.is_valid_zoom(rollup.from_zoom) {
+                return Err(InvalidRollupZoom(
+                    id.clone(),
+                    rollup.from_zoom,
+                    cfg.source.clone(),
+                ));
+            }
+        } else if info.format != Format::Png {
+            return Err(UnsupportedBaseFormat(id.clone(), cfg.source.clone(), info));
+        }
+        let Some(id) = idr.resolve(id, format!("derived.{}.{id}", cfg.source)) else {
+            continue;
+        };
+        let kind = match operation {
+            Operation::Rollup(_) => "vector",
+            _ => "DEM",
+        };
+        info!(
+            "Configured {} source {id} from {kind} source {}",
+            operation.name().to_lowercase(),
+            cfg.source
+        );
+        results.push(Box::new(DerivedSource::new(
+            id,
+            base.clone_source(),
+            cfg.dem_encoding,
+            operation,
+        )));
+    }
+    Ok(results)
+}
+
+#[derive(Clone, Debug)]
+pub struct DerivedSource {
+    id: String,
+    tilejson: TileJSON,
+    base: TileInfoSource,
+    dem_encoding: DemEncoding,
+    operation: Operation,
+}
+
+impl DerivedSource {
+    fn new(
+        id: String,
+        base: TileInfoSource,
+        dem_encoding: DemEncoding,
+        operation: Operation,
+    ) -> Self {
+        let mut tilejson = base.get_tilejson().clone();
+        if let Operation::Rollup(rollup) = &operation {
+            // Tiles of too many zoom levels below would merge too many base tiles
+            let minzoom = rollup.from_zoom.saturating_sub(MAX_ROLLUP_LEVELS);
+            tilejson.minzoom = Some(tilejson.minzoom.unwrap_or(0).max(minzoom));
+        } else {
+            tilejson.vector_layers = None;
+        }
+        tilejson.description = Some(format!("{} of {}", operation.name(), base.get_id()));
+        Self {
+            id,
+            tilejson,
+            base,
+            dem_encoding,
+            operation,
+        }
+    }
+}
+
+#[async_trait]
+impl Source for DerivedSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        match self.operation {
+            Operation::Rollup(_) => self.base.get_tile_info(),
+            _ => Format::Png.into(),
+        }
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.base.support_url_query()
+    }
+
+    fn vary_header(&self) -> Option<&str> {
+        self.base.vary_header()
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: &TileCoord,
+        url_query: &Option<UrlQuery>,
+    ) -> MartinResult<TileData> {
+        if let Operation::Rollup(rollup) = &self.operation {
+            // Merge the base tiles at the rollup zoom level covering the tile, or get the base tile at higher zoom levels
+            let Some(children) = rollup.children(*xyz) else {
+                return self.base.get_tile(xyz, url_query).await;
+            };
+            let encoding = self.base.get_tile_info().encoding;
+            // Martin errors cannot be sent between threads, so the base tiles are fetched one by one
+            let mut tiles = Vec::with_capacity(children.len());
+            for child in children {
+                let data = self.base.get_tile(&child, url_query).await?;
+                if !data.is_empty() {
+                    tiles.push((child, decode_mvt(&data, encoding)?));
+                }
+            }
+            let layers = rollup.merge(*xyz, &tiles);
+            if layers.is_empty() {
+                return Ok(TileData::new());
+            }
+            return Ok(encode_mvt(&layers, encoding)?.into());
+        }
+        let data = self.base.get_tile(xyz, url_query).await?;
+        if data.is_empty() {
+            return Ok(data);
+        }
+        let dem = Dem::from_png(&data, self.dem_encoding)?;
+        let (pixels, color) = match &self.operation {
+            Operation::Hillshade(hs) => (hs.render(&dem, *xyz), ColorType::Grayscale),
+            Operation::ColorRelief(ramp) => (ramp.render(&dem), ColorType::Rgba),
+            Operation::Rollup(_) => unreachable!("rollup tiles are not rendered from a DEM"),
+        };
+        Ok(encode_png(&pixels, dem.width, dem.height, color)?.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+    use crate::config::tests::parse_cfg;
+
+    #[test]
+    fn parse_derived_config() {
+        let cfg = parse_cfg(indoc! {"
+            derived:
+              shade:
+                source: dem
+                operation: hillshade
+                azimuth: 270
+              relief:
+                source: dem
+                dem_encoding: terrarium
+                operation: color-relief
+                ramp:
+                  - elevation: 0
+                    color: '#1a9850'
+                  - elevation: 2000
+                    color: '#ffffff'
+              roads-rollup:
+                source: roads
+                operation: rollup
+                from_zoom: 10
+        "});
+        assert!(cfg.unrecognized.is_empty());
+        assert_eq!(
+            cfg.derived,
+            DerivedConfig::from([
+                (
+                    "shade".to_string(),
+                    DerivedSourceConfig {
+                        source: "dem".to_string(),
+                        dem_encoding: DemEncoding::Mapbox,
+                        operation: DerivedOperation::Hillshade {
+                            azimuth: Some(270.0),
+                            altitude: None,
+                            z_factor: None,
+                        },
+                    }
+                ),
+                (
+                    "relief".to_string(),
+                    DerivedSourceConfig {
+                        source: "dem".to_string(),
+                        dem_encoding: DemEncoding::Terrarium,
+                        operation: DerivedOperation::ColorRelief {
+                            ramp: vec![
+                                ColorRampStop {
+                                    elevation: 0.0,
+                                    color: "#1a9850".to_string(),
+                                },
+                                ColorRampStop {
+                                    elevation: 2000.0,
+                                    color: "#ffffff".to_string(),
+                                },
+                            ],
+                        },
+                    }
+                ),
+                (
+                    "roads-rollup".to_string(),
+                    DerivedSourceConfig {
+                        source: "roads".to_string(),
+                        dem_encoding: DemEncoding::Mapbox,
+                        operation: DerivedOperation::Rollup {
+                            from_zoom: 10,
+                            tolerance: None,
+                        },
+                    }
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn resolve_errors() {
+        let cfg = |operation| {
+            DerivedConfig::from([(
+                "shade".to_string(),
+                DerivedSourceConfig {
+                    source: "dem".to_string(),
+                    dem_encoding: DemEncoding::default(),
+                    operation,
+                },
+            )])
+        };
+        let hillshade = DerivedOperation::Hillshade {
+            azimuth: None,
+            altitude: None,
+            z_factor: None,
+        };
+        let res = resolve_derived(
+            &cfg(hillshade),
+            &TileSources::default(),
+            &IdResolver::default(),
+        );
+        assert!(matches!(res, Err(UnknownBaseSource(..))));
+
+        let relief = DerivedOperation::ColorRelief { ramp: vec![] };
+        assert!(matches!(
+            Operation::new("relief", &relief),
+            Err(EmptyColorRamp(..))
+        ));
+    }
+}
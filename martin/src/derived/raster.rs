@@ -0,0 +1,482 @@
+#![allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss,
+    clippy::many_single_char_names,
+    clippy::similar_names
+)]
+
+use std::io::Cursor;
+
+use martin_tile_utils::EARTH_CIRCUMFERENCE;
+use png::{BitDepth, ColorType, Decoder, Encoder, Transformations};
+use serde::{Deserialize, Serialize};
+
+use crate::derived::DerivedError::{InvalidColor, RasterDecodeError, RasterEncodeError};
+use crate::derived::DerivedResult;
+use crate::TileCoord;
+
+/// How elevation values are packed into the RGB channels of a DEM tile
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DemEncoding {
+    /// Mapbox Terrain-RGB: `-10000 + (R * 256 * 256 + G * 256 + B) * 0.1`
+    #[default]
+    Mapbox,
+    /// Mapzen Terrarium: `(R * 256 + G + B / 256) - 32768`
+    Terrarium,
+}
+
+impl DemEncoding {
+    #[must_use]
+    pub fn decode(self, r: u8, g: u8, b: u8) -> f64 {
+        let (r, g, b) = (f64::from(r), f64::from(g), f64::from(b));
+        match self {
+            Self::Mapbox => -10000.0 + (r * 65536.0 + g * 256.0 + b) * 0.1,
+            Self::Terrarium => r * 256.0 + g + b / 256.0 - 32768.0,
+        }
+    }
+}
+
+/// Elevation values of a single DEM tile, in meters, row by row
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dem {
+    pub width: usize,
+    pub height: usize,
+    pub values: Vec<f64>,
+}
+
+impl Dem {
+    pub fn from_png(data: &[u8], encoding: DemEncoding) -> DerivedResult<Self> {
+        let mut decoder = Decoder::new(Cursor::new(data));
+        decoder.set_transformations(Transformations::normalize_to_color8());
+        let mut reader = decoder
+            .read_info()
+            .map_err(|e| RasterDecodeError(e.to_string()))?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader
+            .next_frame(&mut buf)
+            .map_err(|e| RasterDecodeError(e.to_string()))?;
+        let channels = match info.color_type {
+            ColorType::Rgb => 3,
+            ColorType::Rgba => 4,
+            v => Err(RasterDecodeError(format!(
+                "DEM tiles must be RGB or RGBA images, but got {v:?}"
+            )))?,
+        };
+
+        let width = info.width as usize;
+        let height = info.height as usize;
+        let values = buf[..info.buffer_size()]
+            .chunks_exact(channels)
+            .map(|px| encoding.decode(px[0], px[1], px[2]))
+            .collect();
+
+        Ok(Self {
+            width,
+            height,
+            values,
+        })
+    }
+
+    fn get(&self, x: usize, y: usize) -> f64 {
+        self.values[y * self.width + x]
+    }
+}
+
+/// Hillshade parameters, in degrees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hillshade {
+    pub azimuth: f64,
+    pub altitude: f64,
+    pub z_factor: f64,
+}
+
+impl Hillshade {
+    /// Compute a grayscale hillshade image using Horn's method.
+    /// Pixel size is computed for each row because Web Mercator pixels shrink towards the poles.
+    #[must_use]
+    pub fn render(&self, dem: &Dem, xyz: TileCoord) -> Vec<u8> {
+        let zenith = (90.0 - self.altitude).to_radians();
+        let azimuth = (360.0 - self.azimuth + 90.0).rem_euclid(360.0).to_radians();
+        let tiles = f64::from(1_u32 << xyz.z);
+        let mut result = Vec::with_capacity(dem.width * dem.height);
+
+        for row in 0..dem.height {
+            let lat = tile_row_lat(xyz.y, row, dem.height, tiles);
+            let cell = EARTH_CIRCUMFERENCE * lat.cos() / (tiles * dem.width as f64);
+            // Edge pixels reuse their own values instead of the missing neighbors
+            let (up, down) = (row.saturating_sub(1), (row + 1).min(dem.height - 1));
+            for col in 0..dem.width {
+                let (left, right) = (col.saturating_sub(1), (col + 1).min(dem.width - 1));
+                let a = dem.get(left, up);
+                let b = dem.get(col, up);
+                let c = dem.get(right, up);
+                let d = dem.get(left, row);
+                let f = dem.get(right, row);
+                let g = dem.get(left, down);
+                let h = dem.get(col, down);
+                let i = dem.get(right, down);
+
+                let dzdx = ((c + 2.0 * f + i) - (a + 2.0 * d + g)) / (8.0 * cell);
+                let dzdy = ((g + 2.0 * h + i) - (a + 2.0 * b + c)) / (8.0 * cell);
+                let slope = (self.z_factor * dzdx.hypot(dzdy)).atan();
+                let aspect = dzdy.atan2(-dzdx);
+                let shade = zenith.cos() * slope.cos()
+                    + zenith.sin() * slope.sin() * (azimuth - aspect).cos();
+                result.push((255.0 * shade).clamp(0.0, 255.0).round() as u8);
+            }
+        }
+
+        result
+    }
+}
+
+/// Latitude (in radians) of the center of a pixel row within a tile
+fn tile_row_lat(tile_y: u32, row: usize, height: usize, tiles: f64) -> f64 {
+    let y = (f64::from(tile_y) + (row as f64 + 0.5) / height as f64) / tiles;
+    (std::f64::consts::PI * (1.0 - 2.0 * y)).sinh().atan()
+}
+
+/// A single RGBA color, parsed from `#rrggbb` or `#rrggbbaa` strings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color([u8; 4]);
+
+impl Color {
+    pub fn parse(value: &str) -> DerivedResult<Self> {
+        let err = || InvalidColor(value.to_string());
+        let hex = value.strip_prefix('#').ok_or_else(err)?;
+        if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+            return Err(err());
+        }
+        let mut rgba = [255; 4];
+        for (idx, val) in rgba.iter_mut().enumerate().take(hex.len() / 2) {
+            *val = u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16).map_err(|_| err())?;
+        }
+        Ok(Self(rgba))
+    }
+}
+
+/// A sorted list of elevation stops, linearly interpolated between neighbors
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorRamp(Vec<(f64, Color)>);
+
+impl ColorRamp {
+    #[must_use]
+    pub fn new(mut stops: Vec<(f64, Color)>) -> Self {
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self(stops)
+    }
+
+    #[must_use]
+    pub fn color_at(&self, elevation: f64) -> Color {
+        let idx = self.0.partition_point(|(e, _)| *e <= elevation);
+        if idx == 0 {
+            return self.0.first().map_or(Color([0; 4]), |v| v.1);
+        }
+        if idx == self.0.len() {
+            return self.0[idx - 1].1;
+        }
+        let (e1, Color(c1)) = self.0[idx - 1];
+        let (e2, Color(c2)) = self.0[idx];
+        let t = (elevation - e1) / (e2 - e1);
+        let mut rgba = [0; 4];
+        for (i, val) in rgba.iter_mut().enumerate() {
+            let (v1, v2) = (f64::from(c1[i]), f64::from(c2[i]));
+            *val = (v1 + (v2 - v1) * t).round() as u8;
+        }
+        Color(rgba)
+    }
+
+    #[must_use]
+    pub fn render(&self, dem: &Dem) -> Vec<u8> {
+        dem.values
+            .iter()
+            .flat_map(|v| self.color_at(*v).0)
+            .collect()
+    }
+}
+
+/// Enlarge a PNG image by an integer factor, repeating each pixel
+pub fn upscale_png(data: &[u8], factor: usize) -> DerivedResult<Vec<u8>> {
+    let mut decoder = Decoder::new(Cursor::new(data));
+    decoder.set_transformations(Transformations::normalize_to_color8());
+    let mut reader = decoder
+        .read_info()
+        .map_err(|e| RasterDecodeError(e.to_string()))?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut buf)
+        .map_err(|e| RasterDecodeError(e.to_string()))?;
+
+    let channels = info.color_type.samples();
+    let width = info.width as usize;
+    let height = info.height as usize;
+    let mut result = Vec::with_capacity(width * height * channels * factor * factor);
+    for row in buf[..info.buffer_size()].chunks_exact(width * channels) {
+        let mut scaled = Vec::with_capacity(row.len() * factor);
+        for px in row.chunks_exact(channels) {
+            for _ in 0..factor {
+                scaled.extend_from_slice(px);
+            }
+        }
+        for _ in 0..factor {
+            result.extend_from_slice(&scaled);
+        }
+    }
+    encode_png(&result, width * factor, height * factor, info.color_type)
+}
+
+/// Make a PNG tile from its four children, in the top-left, top-right, bottom-left, bottom-right order,
+/// by averaging each 2x2 block of their pixels. Missing children are transparent,
+/// and `None` is returned if all of them are missing.
+pub fn downsample_png(children: [Option<&[u8]>; 4]) -> DerivedResult<Option<Vec<u8>>> {
+    let mut size = None;
+    let mut result = Vec::new();
+    for (idx, child) in children.into_iter().enumerate() {
+        let Some(child) = child else { continue };
+        let (width, height, rgba) = decode_rgba(child)?;
+        if *size.get_or_insert((width, height)) != (width, height) {
+            return Err(RasterDecodeError(
+                "the child tiles have different sizes".to_string(),
+            ));
+        }
+        if width % 2 != 0 || height % 2 != 0 {
+            return Err(RasterDecodeError(format!(
+                "a {width}x{height} tile cannot be halved"
+            )));
+        }
+        if result.is_empty() {
+            result = vec![0; width * height * 4];
+        }
+        let (left, top) = ((idx % 2) * width / 2, (idx / 2) * height / 2);
+        for y in 0..height / 2 {
+            for x in 0..width / 2 {
+                let mut color = [0_u32; 3];
+                let mut alpha = 0;
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let px = &rgba[((2 * y + dy) * width + 2 * x + dx) * 4..][..4];
+                    let a = u32::from(px[3]);
+                    for (sum, v) in color.iter_mut().zip(px) {
+                        *sum += u32::from(*v) * a;
+                    }
+                    alpha += a;
+                }
+                let out = &mut result[((top + y) * width + left + x) * 4..][..4];
+                // Colors are weighted by their alpha, so transparent pixels do not darken the edges
+                for (v, sum) in out.iter_mut().zip(color) {
+                    *v = (sum + alpha / 2).checked_div(alpha).unwrap_or(0) as u8;
+                }
+                out[3] = ((alpha + 2) / 4) as u8;
+            }
+        }
+    }
+    let Some((width, height)) = size else {
+        return Ok(None);
+    };
+    encode_png(&result, width, height, ColorType::Rgba).map(Some)
+}
+
+/// Decode a PNG image of any color type to 8-bit RGBA pixels
+fn decode_rgba(data: &[u8]) -> DerivedResult<(usize, usize, Vec<u8>)> {
+    let mut decoder = Decoder::new(Cursor::new(data));
+    decoder.set_transformations(Transformations::normalize_to_color8());
+    let mut reader = decoder
+        .read_info()
+        .map_err(|e| RasterDecodeError(e.to_string()))?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut buf)
+        .map_err(|e| RasterDecodeError(e.to_string()))?;
+    let buf = &buf[..info.buffer_size()];
+    let rgba = match info.color_type {
+        ColorType::Rgba => buf.to_vec(),
+        ColorType::Rgb => buf
+            .chunks_exact(3)
+            .flat_map(|px| [px[0], px[1], px[2], 255])
+            .collect(),
+        ColorType::GrayscaleAlpha => buf
+            .chunks_exact(2)
+            .flat_map(|px| [px[0], px[0], px[0], px[1]])
+            .collect(),
+        ColorType::Grayscale => buf.iter().flat_map(|v| [*v, *v, *v, 255]).collect(),
+        ColorType::Indexed => Err(RasterDecodeError(
+            "indexed colors were not expanded".to_string(),
+        ))?,
+    };
+    Ok((info.width as usize, info.height as usize, rgba))
+}
+
+/// Encode raw pixel data as an 8-bit PNG image
+pub fn encode_png(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    color: ColorType,
+) -> DerivedResult<Vec<u8>> {
+    let mut result = Vec::new();
+    let width = u32::try_from(width).map_err(|e| RasterEncodeError(e.to_string()))?;
+    let height = u32::try_from(height).map_err(|e| RasterEncodeError(e.to_string()))?;
+    let mut encoder = Encoder::new(&mut result, width, height);
+    encoder.set_color(color);
+    encoder.set_depth(BitDepth::Eight);
+    let mut writer = encoder
+        .write_header()
+        .map_err(|e| RasterEncodeError(e.to_string()))?;
+    writer
+        .write_image_data(data)
+        .map_err(|e| RasterEncodeError(e.to_string()))?;
+    writer
+        .finish()
+        .map_err(|e| RasterEncodeError(e.to_string()))?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    fn flat_dem(elevation: f64) -> Dem {
+        Dem {
+            width: 4,
+            height: 4,
+            values: vec![elevation; 16],
+        }
+    }
+
+    #[test]
+    fn decode_elevation() {
+        assert_relative_eq!(DemEncoding::Mapbox.decode(1, 134, 160), 0.0);
+        assert_relative_eq!(DemEncoding::Terrarium.decode(128, 0, 0), 0.0);
+        assert_relative_eq!(DemEncoding::Terrarium.decode(128, 100, 128), 100.5);
+    }
+
+    #[test]
+    fn png_upscale() {
+        let data = [1, 2, 3, 4];
+        let png = encode_png(&data, 2, 2, ColorType::Grayscale).unwrap();
+        let png = upscale_png(&png, 2).unwrap();
+
+        let mut reader = Decoder::new(Cursor::new(png)).read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        assert_eq!((info.width, info.height), (4, 4));
+        assert_eq!(
+            &buf[..info.buffer_size()],
+            &[1, 1, 2, 2, 1, 1, 2, 2, 3, 3, 4, 4, 3, 3, 4, 4]
+        );
+    }
+
+    #[test]
+    fn png_downsample() {
+        let gray = encode_png(&[0, 100, 200, 255], 2, 2, ColorType::Grayscale).unwrap();
+        let red = encode_png(&[255, 0, 0, 255].repeat(4), 2, 2, ColorType::Rgba).unwrap();
+        // Only its two opaque pixels are averaged
+        let half = [0, 0, 255, 0, 0, 0, 255, 255, 0, 0, 255, 0, 0, 0, 255, 255];
+        let half = encode_png(&half, 2, 2, ColorType::Rgba).unwrap();
+        let png = downsample_png([Some(&gray), Some(&red), None, Some(&half)])
+            .unwrap()
+            .unwrap();
+
+        let (width, height, rgba) = decode_rgba(&png).unwrap();
+        assert_eq!((width, height), (2, 2));
+        assert_eq!(
+            rgba,
+            [139, 139, 139, 255, 255, 0, 0, 255, 0, 0, 0, 0, 0, 0, 255, 128]
+        );
+        assert_eq!(downsample_png([None; 4]).unwrap(), None);
+        let small = encode_png(&[0], 1, 1, ColorType::Grayscale).unwrap();
+        assert!(downsample_png([Some(&gray), Some(&small), None, None]).is_err());
+    }
+
+    #[test]
+    fn png_round_trip() {
+        // Terrarium encoding of 0m, 1m, 2m, 3m
+        let rgb = [128, 0, 0, 128, 1, 0, 128, 2, 0, 128, 3, 0];
+        let png = encode_png(&rgb, 2, 2, ColorType::Rgb).unwrap();
+        let dem = Dem::from_png(&png, DemEncoding::Terrarium).unwrap();
+        assert_eq!(
+            dem,
+            Dem {
+                width: 2,
+                height: 2,
+                values: vec![0.0, 1.0, 2.0, 3.0],
+            }
+        );
+
+        let gray = encode_png(&[0, 0, 0, 0], 2, 2, ColorType::Grayscale).unwrap();
+        assert!(Dem::from_png(&gray, DemEncoding::Terrarium).is_err());
+    }
+
+    #[test]
+    fn hillshade_flat() {
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let hs = Hillshade {
+            azimuth: 315.0,
+            altitude: 45.0,
+            z_factor: 1.0,
+        };
+        // A flat surface is lit by sin(altitude), regardless of the azimuth
+        let res = hs.render(&flat_dem(100.0), xyz);
+        assert_eq!(res, vec![180; 16]);
+
+        let hs = Hillshade {
+            altitude: 90.0,
+            ..hs
+        };
+        assert_eq!(hs.render(&flat_dem(0.0), xyz), vec![255; 16]);
+    }
+
+    #[test]
+    fn hillshade_slope_direction() {
+        // Elevation grows to the east, so a light from the west is brighter than from the east
+        let dem = Dem {
+            width: 3,
+            height: 1,
+            values: vec![0.0, 5000.0, 10000.0],
+        };
+        let xyz = TileCoord {
+            z: 10,
+            x: 0,
+            y: 512,
+        };
+        let west = Hillshade {
+            azimuth: 270.0,
+            altitude: 45.0,
+            z_factor: 1.0,
+        };
+        let east = Hillshade {
+            azimuth: 90.0,
+            ..west
+        };
+        assert!(west.render(&dem, xyz)[1] > east.render(&dem, xyz)[1]);
+    }
+
+    #[test]
+    fn parse_colors() {
+        assert_eq!(Color::parse("#ff0080").unwrap(), Color([255, 0, 128, 255]));
+        assert_eq!(Color::parse("#ff008010").unwrap(), Color([255, 0, 128, 16]));
+        assert!(Color::parse("ff0080").is_err());
+        assert!(Color::parse("#ff00").is_err());
+        assert!(Color::parse("#gg0000").is_err());
+    }
+
+    #[test]
+    fn color_ramp() {
+        let ramp = ColorRamp::new(vec![
+            (1000.0, Color([255, 255, 255, 255])),
+            (0.0, Color([0, 0, 0, 255])),
+        ]);
+        assert_eq!(ramp.color_at(-50.0), Color([0, 0, 0, 255]));
+        assert_eq!(ramp.color_at(0.0), Color([0, 0, 0, 255]));
+        assert_eq!(ramp.color_at(500.0), Color([128, 128, 128, 255]));
+        assert_eq!(ramp.color_at(1000.0), Color([255, 255, 255, 255]));
+        assert_eq!(ramp.color_at(5000.0), Color([255, 255, 255, 255]));
+
+        let res = ramp.render(&flat_dem(250.0));
+        assert_eq!(res.len(), 64);
+        assert_eq!(&res[..4], &[64, 64, 64, 255]);
+    }
+}
@@ -0,0 +1,283 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::collections::HashMap;
+
+use martin_tile_utils::Encoding;
+
+use crate::derived::DerivedError::{VectorDecodeError, VectorEncodeError};
+use crate::derived::DerivedResult;
+use crate::utils::{mvt_decode, mvt_encode, MvtFeature, MvtLayer};
+use crate::{decode_gzip, encode_gzip, TileCoord};
+
+/// Highest number of zoom levels merged into one tile, i.e. one tile is made of at most `4^4 = 256` base tiles
+pub const MAX_ROLLUP_LEVELS: u8 = 4;
+
+/// Builds the low zoom tiles of a vector source by merging the features of its tiles at a higher zoom level
+#[derive(Clone, Debug)]
+pub struct Rollup {
+    pub from_zoom: u8,
+    /// Vertices closer than this to the previous vertex are dropped, in tile extent units
+    pub tolerance: f64,
+}
+
+impl Rollup {
+    /// Tiles at `from_zoom` covering the tile, or `None` if the tile is served by the base source as is
+    #[must_use]
+    pub fn children(&self, xyz: TileCoord) -> Option<Vec<TileCoord>> {
+        let levels = self.from_zoom.checked_sub(xyz.z).filter(|v| *v > 0)?;
+        let size = 1_u32 << levels;
+        let mut result = Vec::with_capacity((size * size) as usize);
+        for y in 0..size {
+            for x in 0..size {
+                result.push(TileCoord {
+                    z: self.from_zoom,
+                    x: xyz.x * size + x,
+                    y: xyz.y * size + y,
+                });
+            }
+        }
+        Some(result)
+    }
+
+    /// Merge the layers of the descendant tiles into the layers of the tile.
+    /// Features with the same ID, geometry type, and properties are merged into one multi-geometry,
+    /// and the geometries are simplified to the resolution of the tile.
+    #[must_use]
+    pub fn merge(&self, xyz: TileCoord, tiles: &[(TileCoord, Vec<MvtLayer>)]) -> Vec<MvtLayer> {
+        let mut layers: Vec<MvtLayer> = Vec::new();
+        let mut merged: HashMap<(usize, u64, u64), Vec<usize>> = HashMap::new();
+        for (child, child_layers) in tiles {
+            let size = f64::from(1_u32 << (child.z - xyz.z));
+            let dx = f64::from(child.x - (xyz.x << (child.z - xyz.z)));
+            let dy = f64::from(child.y - (xyz.y << (child.z - xyz.z)));
+            for child_layer in child_layers {
+                let layer_idx = layers
+                    .iter()
+                    .position(|v| v.name == child_layer.name)
+                    .unwrap_or_else(|| {
+                        layers.push(MvtLayer {
+                            name: child_layer.name.clone(),
+                            extent: child_layer.extent,
+                            features: Vec::new(),
+                        });
+                        layers.len() - 1
+                    });
+                let layer = &mut layers[layer_idx];
+                let child_extent = f64::from(child_layer.extent);
+                let scale = f64::from(layer.extent) / child_extent / size;
+                let transform = |(x, y): (i64, i64)| {
+                    (
+                        ((dx * child_extent + x as f64) * scale).round() as i64,
+                        ((dy * child_extent + y as f64) * scale).round() as i64,
+                    )
+                };
+                for feature in &child_layer.features {
+                    let Some(feature) = self.transform_feature(feature, transform) else {
+                        continue;
+                    };
+                    let Some(id) = feature.id else {
+                        layer.features.push(feature);
+                        continue;
+                    };
+                    let same = merged
+                        .entry((layer_idx, id, feature.geom_type))
+                        .or_default();
+                    if let Some(idx) = same
+                        .iter()
+                        .find(|v| layer.features[**v].properties == feature.properties)
+                    {
+                        layer.features[*idx].parts.extend(feature.parts);
+                    } else {
+                        same.push(layer.features.len());
+                        layer.features.push(feature);
+                    }
+                }
+            }
+        }
+        layers.retain(|v| !v.features.is_empty());
+        layers
+    }
+
+    /// Move the geometry of a descendant tile feature into the tile, dropping the lines and rings that collapse
+    fn transform_feature(
+        &self,
+        feature: &MvtFeature,
+        transform: impl Fn((i64, i64)) -> (i64, i64),
+    ) -> Option<MvtFeature> {
+        let mut parts = Vec::with_capacity(feature.parts.len());
+        // Holes of a dropped polygon are dropped as well
+        let mut keep_holes = true;
+        for part in &feature.parts {
+            let part: Vec<_> = part.iter().copied().map(&transform).collect();
+            match feature.geom_type {
+                2 => {
+                    let line = simplify(&part, self.tolerance);
+                    if line.len() >= 2 {
+                        parts.push(line);
+                    }
+                }
+                3 => {
+                    let exterior = ring_area(&part) > 0;
+                    if !exterior && !keep_holes {
+                        continue;
+                    }
+                    let ring = simplify(&part, self.tolerance);
+                    let valid = ring.len() >= 4 && (ring_area(&ring) > 0) == exterior;
+                    if exterior {
+                        keep_holes = valid;
+                    }
+                    if valid {
+                        parts.push(ring);
+                    }
+                }
+                _ => parts.push(part),
+            }
+        }
+        if parts.is_empty() {
+            return None;
+        }
+        Some(MvtFeature {
+            parts,
+            ..feature.clone()
+        })
+    }
+}
+
+/// Drop the vertices closer than the tolerance to the previous kept vertex, always keeping the last one
+fn simplify(part: &[(i64, i64)], tolerance: f64) -> Vec<(i64, i64)> {
+    let mut result: Vec<(i64, i64)> = Vec::with_capacity(part.len());
+    for (idx, &point) in part.iter().enumerate() {
+        if let Some(&(x, y)) = result.last() {
+            let distance = ((point.0 - x) as f64).hypot((point.1 - y) as f64);
+            if point == (x, y) || (distance < tolerance && idx + 1 < part.len()) {
+                continue;
+            }
+        }
+        result.push(point);
+    }
+    result
+}
+
+/// Twice the signed area of a closed ring, positive for the exterior rings of the MVT spec
+fn ring_area(ring: &[(i64, i64)]) -> i64 {
+    ring.windows(2)
+        .map(|v| v[0].0 * v[1].1 - v[1].0 * v[0].1)
+        .sum()
+}
+
+/// Decode a vector tile that is either uncompressed or gzip-compressed
+pub fn decode_mvt(data: &[u8], encoding: Encoding) -> DerivedResult<Vec<MvtLayer>> {
+    let data = if encoding == Encoding::Gzip {
+        decode_gzip(data).map_err(|e| VectorDecodeError(e.to_string()))?
+    } else {
+        data.to_vec()
+    };
+    mvt_decode(&data).map_err(VectorDecodeError)
+}
+
+/// Encode the layers as a vector tile with the given encoding, the reverse of [`decode_mvt`]
+pub fn encode_mvt(layers: &[MvtLayer], encoding: Encoding) -> DerivedResult<Vec<u8>> {
+    let data = mvt_encode(layers);
+    if encoding == Encoding::Gzip {
+        encode_gzip(&data).map_err(|e| VectorEncodeError(e.to_string()))
+    } else {
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Map};
+
+    use super::*;
+
+    fn feature(id: Option<u64>, geom_type: u64, parts: Vec<Vec<(i64, i64)>>) -> MvtFeature {
+        MvtFeature {
+            id,
+            geom_type,
+            properties: Map::from_iter([("name".to_string(), json!("a"))]),
+            parts,
+        }
+    }
+
+    fn layer(features: Vec<MvtFeature>) -> Vec<MvtLayer> {
+        vec![MvtLayer {
+            name: "roads".to_string(),
+            extent: 4096,
+            features,
+        }]
+    }
+
+    #[test]
+    fn rollup_tiles() {
+        let rollup = Rollup {
+            from_zoom: 3,
+            tolerance: 8.0,
+        };
+        let xyz = TileCoord { z: 2, x: 1, y: 2 };
+        assert_eq!(rollup.children(TileCoord { z: 3, x: 0, y: 0 }), None);
+        assert_eq!(
+            rollup.children(xyz).unwrap(),
+            vec![
+                TileCoord { z: 3, x: 2, y: 4 },
+                TileCoord { z: 3, x: 3, y: 4 },
+                TileCoord { z: 3, x: 2, y: 5 },
+                TileCoord { z: 3, x: 3, y: 5 },
+            ]
+        );
+        assert_eq!(
+            rollup
+                .children(TileCoord { z: 0, x: 0, y: 0 })
+                .unwrap()
+                .len(),
+            64
+        );
+
+        let square = vec![(0, 0), (4000, 0), (4000, 4000), (0, 4000), (0, 0)];
+        let tiles = [
+            (
+                TileCoord { z: 3, x: 2, y: 4 },
+                layer(vec![
+                    feature(Some(1), 2, vec![vec![(0, 0), (10, 10), (4096, 4096)]]),
+                    feature(None, 1, vec![vec![(2048, 2048)]]),
+                    // Collapses to a single vertex
+                    feature(Some(2), 3, vec![vec![(0, 0), (4, 0), (4, 4), (0, 0)]]),
+                ]),
+            ),
+            (
+                TileCoord { z: 3, x: 3, y: 5 },
+                layer(vec![
+                    feature(Some(1), 2, vec![vec![(0, 0), (4096, 0)]]),
+                    feature(Some(3), 3, vec![square]),
+                ]),
+            ),
+        ];
+        assert_eq!(
+            rollup.merge(xyz, &tiles),
+            layer(vec![
+                feature(
+                    Some(1),
+                    2,
+                    vec![vec![(0, 0), (2048, 2048)], vec![(2048, 2048), (4096, 2048)]]
+                ),
+                feature(None, 1, vec![vec![(1024, 1024)]]),
+                feature(
+                    Some(3),
+                    3,
+                    vec![vec![
+                        (2048, 2048),
+                        (4048, 2048),
+                        (4048, 4048),
+                        (2048, 4048),
+                        (2048, 2048)
+                    ]]
+                ),
+            ])
+        );
+        assert!(rollup.merge(xyz, &[]).is_empty());
+
+        let data = encode_mvt(&tiles[0].1, Encoding::Gzip).unwrap();
+        assert_eq!(decode_mvt(&data, Encoding::Gzip).unwrap(), tiles[0].1);
+        assert!(decode_mvt(&data, Encoding::Uncompressed).is_err());
+    }
+}
@@ -2,7 +2,9 @@ use std::collections::{BTreeMap, HashSet};
 use std::future::Future;
 use std::mem;
 use std::path::PathBuf;
+use std::pin::Pin;
 
+use futures::future::ready;
 use futures::TryFutureExt;
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
@@ -162,21 +164,42 @@ impl FileConfigSrc {
         let path = self.get_path();
         path.canonicalize().map_err(|e| IoError(e, path.clone()))
     }
+
+    #[must_use]
+    pub fn into_source(self) -> FileConfigSource {
+        match self {
+            Self::Path(path) => FileConfigSource {
+                path,
+                ..Default::default()
+            },
+            Self::Obj(o) => o,
+        }
+    }
 }
 
+#[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct FileConfigSource {
     pub path: PathBuf,
+    /// Width and height of the tiles in pixels, advertised in the TileJSON as `tileSize`
+    pub tile_size: Option<u16>,
 }
 
+/// A source file that is still to be opened, named by its source ID or its path.
+/// Configuration errors are reported when the file is opened, so that other files are not affected.
+pub type PendingFile = (
+    String,
+    Pin<Box<dyn Future<Output = FileResult<Box<dyn Source>>>>>,
+);
+
 pub async fn resolve_files<Fut>(
     config: &mut FileConfigEnum,
     idr: IdResolver,
     extension: &str,
-    new_source: &mut impl FnMut(String, PathBuf) -> Fut,
+    new_source: &mut impl FnMut(String, FileConfigSource) -> Fut,
 ) -> MartinResult<TileInfoSources>
 where
-    Fut: Future<Output = Result<Box<dyn Source>, FileError>>,
+    Fut: Future<Output = Result<Box<dyn Source>, FileError>> + 'static,
 {
     resolve_int(config, idr, extension, new_source)
         .map_err(crate::MartinError::from)
@@ -187,39 +210,62 @@ async fn resolve_int<Fut>(
     config: &mut FileConfigEnum,
     idr: IdResolver,
     extension: &str,
-    new_source: &mut impl FnMut(String, PathBuf) -> Fut,
+    new_source: &mut impl FnMut(String, FileConfigSource) -> Fut,
 ) -> FileResult<TileInfoSources>
 where
-    Fut: Future<Output = Result<Box<dyn Source>, FileError>>,
+    Fut: Future<Output = Result<Box<dyn Source>, FileError>> + 'static,
+{
+    let mut results = TileInfoSources::default();
+    for (_, source) in list_files(config, &idr, extension, new_source) {
+        results.push(source.await?);
+    }
+    Ok(results)
+}
+
+/// Find all configured source files, and replace the config with the list of found files
+pub fn list_files<Fut>(
+    config: &mut FileConfigEnum,
+    idr: &IdResolver,
+    extension: &str,
+    new_source: &mut impl FnMut(String, FileConfigSource) -> Fut,
+) -> Vec<PendingFile>
+where
+    Fut: Future<Output = Result<Box<dyn Source>, FileError>> + 'static,
 {
     let Some(cfg) = config.extract_file_config() else {
-        return Ok(TileInfoSources::default());
+        return Vec::new();
     };
 
-    let mut results = TileInfoSources::default();
+    let mut results: Vec<PendingFile> = Vec::new();
     let mut configs = BTreeMap::new();
     let mut files = HashSet::new();
     let mut directories = Vec::new();
+    let failed =
+        |name: String, err: FileError| -> PendingFile { (name, Box::pin(ready(Err(err)))) };
 
     if let Some(sources) = cfg.sources {
         for (id, source) in sources {
-            let can = source.abs_path()?;
+            let can = match source.abs_path() {
+                Ok(can) => can,
+                Err(e) => {
+                    results.push(failed(id, e));
+                    continue;
+                }
+            };
             if !can.is_file() {
-                // todo: maybe warn instead?
-                return Err(InvalidSourceFilePath(id.to_string(), can));
+                results.push(failed(id.clone(), InvalidSourceFilePath(id, can)));
+                continue;
             }
 
             let dup = !files.insert(can.clone());
             let dup = if dup { "duplicate " } else { "" };
-            let id = idr.resolve(&id, can.to_string_lossy().to_string());
+            let Some(id) = idr.resolve(&id, can.to_string_lossy().to_string()) else {
+                continue;
+            };
             info!("Configured {dup}source {id} from {}", can.display());
             configs.insert(id.clone(), source.clone());
 
-            let path = match source {
-                FileConfigSrc::Obj(pmt) => pmt.path,
-                FileConfigSrc::Path(path) => path,
-            };
-            results.push(new_source(id, path).await?);
+            results.push((id.clone(), Box::pin(new_source(id, source.into_source()))));
         }
     }
 
@@ -228,21 +274,37 @@ where
         let dir_files = if is_dir {
             // directories will be kept in the config just in case there are new files
             directories.push(path.clone());
-            path.read_dir()
-                .map_err(|e| IoError(e, path.clone()))?
-                .filter_map(Result::ok)
-                .filter(|f| {
-                    f.path().extension().filter(|e| *e == extension).is_some() && f.path().is_file()
-                })
-                .map(|f| f.path())
-                .collect()
+            match path.read_dir() {
+                Ok(dir) => dir
+                    .filter_map(Result::ok)
+                    .filter(|f| {
+                        f.path().extension().map_or(false, |e| e == extension) && f.path().is_file()
+                    })
+                    .map(|f| f.path())
+                    .collect(),
+                Err(e) => {
+                    results.push(failed(path.display().to_string(), IoError(e, path)));
+                    continue;
+                }
+            }
         } else if path.is_file() {
             vec![path]
         } else {
-            return Err(InvalidFilePath(path.canonicalize().unwrap_or(path)));
+            let name = path.display().to_string();
+            results.push(failed(
+                name,
+                InvalidFilePath(path.canonicalize().unwrap_or(path)),
+            ));
+            continue;
         };
         for path in dir_files {
-            let can = path.canonicalize().map_err(|e| IoError(e, path.clone()))?;
+            let can = match path.canonicalize() {
+                Ok(can) => can,
+                Err(e) => {
+                    results.push(failed(path.display().to_string(), IoError(e, path)));
+                    continue;
+                }
+            };
             if files.contains(&can) {
                 if !is_dir {
                     warn!("Ignoring duplicate MBTiles path: {}", can.display());
@@ -254,22 +316,21 @@ where
                 |s| s.to_string_lossy().to_string(),
             );
             let source = FileConfigSrc::Path(path);
-            let id = idr.resolve(&id, can.to_string_lossy().to_string());
+            let Some(id) = idr.resolve(&id, can.to_string_lossy().to_string()) else {
+                files.insert(can);
+                continue;
+            };
             info!("Configured source {id} from {}", can.display());
             files.insert(can);
             configs.insert(id.clone(), source.clone());
 
-            let path = match source {
-                FileConfigSrc::Obj(pmt) => pmt.path,
-                FileConfigSrc::Path(path) => path,
-            };
-            results.push(new_source(id, path).await?);
+            results.push((id.clone(), Box::pin(new_source(id, source.into_source()))));
         }
     }
 
     *config = FileConfigEnum::new_extended(directories, configs, cfg.unrecognized);
 
-    Ok(results)
+    results
 }
 
 #[cfg(test)]
@@ -291,6 +352,7 @@ mod tests {
                 pm-src1: /tmp/file.ext
                 pm-src2:
                   path: /tmp/file.ext
+                  tile_size: 512
         "})
         .unwrap();
         let res = cfg.finalize("").unwrap();
@@ -317,6 +379,7 @@ mod tests {
                     "pm-src2".to_string(),
                     FileConfigSrc::Obj(FileConfigSource {
                         path: PathBuf::from("/tmp/file.ext"),
+                        tile_size: Some(512),
                     })
                 )
             ]))
@@ -5,22 +5,35 @@ mod config;
 pub use config::{read_config, Config, ServerState};
 
 mod source;
-pub use source::{CatalogSourceEntry, Source, Tile, TileData, TileSources, UrlQuery};
+pub use source::{
+    request_header, with_headers, CatalogSourceEntry, FeatureFilter, FieldStats, Source,
+    SourceFieldStats, Tile, TileData, TileSources, UrlQuery,
+};
 
 mod utils;
+#[cfg(feature = "libdeflate")]
+pub use utils::LibdeflateGzip;
 pub use utils::{
-    append_rect, decode_brotli, decode_gzip, IdResolver, MartinError, MartinResult, OptBoolObj,
-    OptOneMany, TileCoord, TileRect,
+    append_rect, compute_tile_ranges, decode_brotli, decode_gzip, decode_zstd, encode_brotli,
+    encode_brotli_level, encode_gzip, encode_gzip_level, encode_source_id, encode_zstd,
+    iterate_tiles, post_json, tile_index, DefaultGzip, DuplicateIdStrategy, Flate2Gzip, GeoMask,
+    GzipBackend, IdResolver, IdRules, MartinError, MartinResult, MvtFilter, OptBoolObj, OptOneMany,
+    RenamedId, S3Bucket, TileCoord, TileRect,
 };
 
 pub mod args;
+pub mod blocking;
+pub mod commands;
+pub mod derived;
 pub mod file_config;
 pub mod fonts;
 pub mod mbtiles;
 pub mod pg;
 pub mod pmtiles;
+pub mod remote;
 pub mod sprites;
 pub mod srv;
+pub mod variants;
 
 #[cfg(test)]
 #[path = "utils/test_utils.rs"]
@@ -10,8 +10,8 @@ use mbtiles::MbtilesPool;
 use tilejson::TileJSON;
 
 use crate::file_config::FileError::{AquireConnError, InvalidMetadata, IoError};
-use crate::file_config::FileResult;
-use crate::source::{TileData, UrlQuery};
+use crate::file_config::{FileConfigSource, FileResult};
+use crate::source::{TileData, UrlQuery, TILE_SIZE_KEY};
 use crate::{MartinResult, Source, TileCoord};
 
 #[derive(Clone)]
@@ -34,8 +34,14 @@ impl Debug for MbtSource {
 }
 
 impl MbtSource {
-    pub async fn new_box(id: String, path: PathBuf) -> FileResult<Box<dyn Source>> {
-        Ok(Box::new(MbtSource::new(id, path).await?))
+    pub async fn new_box(id: String, cfg: FileConfigSource) -> FileResult<Box<dyn Source>> {
+        let mut src = MbtSource::new(id, cfg.path).await?;
+        if let Some(tile_size) = cfg.tile_size {
+            src.tilejson
+                .other
+                .insert(TILE_SIZE_KEY.to_string(), tile_size.into());
+        }
+        Ok(Box::new(src))
     }
 
     async fn new(id: String, path: PathBuf) -> FileResult<Self> {
@@ -92,7 +98,7 @@ impl Source for MbtSource {
             .await
             .map_err(|_| AquireConnError(self.id.clone()))?
         {
-            Ok(tile)
+            Ok(TileData::from(tile))
         } else {
             trace!(
                 "Couldn't find tile data in {}/{}/{} of {}",
@@ -101,7 +107,7 @@ impl Source for MbtSource {
                 xyz.y,
                 &self.id
             );
-            Ok(Vec::new())
+            Ok(TileData::new())
         }
     }
 }
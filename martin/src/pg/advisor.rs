@@ -0,0 +1,431 @@
+use std::collections::BTreeSet;
+
+use deadpool_postgres::tokio_postgres::SimpleQueryMessage;
+use deadpool_postgres::Object;
+use itertools::Itertools as _;
+use martin_tile_utils::MAX_LATITUDE;
+use postgres_protocol::escape::escape_identifier;
+use serde_json::Value;
+
+use crate::pg::config::{PgConfig, PgInfo};
+use crate::pg::config_table::TableInfo;
+use crate::pg::table_source::{spatial_index_sql, tile_query};
+use crate::pg::PgError::PostgresError;
+use crate::pg::PgResult;
+use crate::utils::IdResolver;
+use crate::{tile_index, MartinResult, TileCoord};
+
+/// Zoom levels of the tile queries that are explained, limited to the zoom range of the source
+const SAMPLE_ZOOMS: [u8; 3] = [8, 11, 14];
+/// Number of geometries checked for their type and SRID
+const SAMPLE_GEOMETRIES: u32 = 10_000;
+/// Tile queries reading this many times more table pages than a clustered table would need are reported
+const SCATTER_RATIO: f64 = 4.0;
+/// Tiles with fewer features are too small to tell if the table is clustered
+const SCATTER_MIN_ROWS: f64 = 100.0;
+
+/// Problem of a table source that makes its tiles slower or incomplete
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableIssue {
+    pub problem: String,
+    /// SQL that should fix the problem, to be reviewed before it is run
+    pub fix: Option<String>,
+}
+
+/// Result of an `EXPLAIN ANALYZE` of the tile query
+#[derive(Clone, Debug, PartialEq)]
+pub struct TileSample {
+    pub xyz: TileCoord,
+    /// Execution time in milliseconds
+    pub time: f64,
+    /// Rows read from the table
+    pub rows: f64,
+    /// Pages read to find and fetch the rows, including the index pages
+    pub pages: f64,
+    /// The whole table was scanned
+    pub seq_scan: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableAdvice {
+    pub source_id: String,
+    /// Table and geometry column of the source
+    pub table: String,
+    pub samples: Vec<TileSample>,
+    pub issues: Vec<TableIssue>,
+}
+
+/// Resolve the sources of the connection, and inspect the tables of the given table sources, or of all of them
+pub async fn table_advice(
+    config: &mut PgConfig,
+    idr: IdResolver,
+    sources: &[String],
+) -> MartinResult<Vec<TableAdvice>> {
+    let pool = config.resolve_for_maintenance(idr).await?;
+    let conn = pool.get().await?;
+    let mut result = Vec::new();
+    for (id, info) in config.tables.iter().flatten() {
+        if sources.is_empty() || sources.contains(id) {
+            let query = tile_query(
+                id,
+                info,
+                pool.supports_tile_margin(),
+                config.max_feature_count,
+            );
+            result.push(advise_table(&conn, id, info, &query).await?);
+        }
+    }
+    Ok(result)
+}
+
+async fn advise_table(
+    conn: &Object,
+    source_id: &str,
+    info: &TableInfo,
+    query: &str,
+) -> PgResult<TableAdvice> {
+    let table = format!(
+        "{}.{}",
+        escape_identifier(&info.schema),
+        escape_identifier(&info.table)
+    );
+    let column = escape_identifier(&info.geometry_column);
+    let srid = info.srid;
+    let is_view = info.is_view == Some(true);
+    let mut issues = Vec::new();
+
+    if !is_view && info.geometry_index == Some(false) {
+        issues.push(TableIssue {
+            problem: format!(
+                "Column {} has no spatial index, so every tile reads the whole table",
+                info.geometry_column
+            ),
+            fix: Some(spatial_index_sql(info)),
+        });
+    }
+
+    let rows = conn
+        .query(
+            &format!(
+                "SELECT DISTINCT GeometryType({column}) AS type, ST_SRID({column}) AS srid
+                 FROM (SELECT {column} FROM {table} WHERE {column} IS NOT NULL LIMIT {SAMPLE_GEOMETRIES}) AS sample"
+            ),
+            &[],
+        )
+        .await
+        .map_err(|e| PostgresError(e, "sampling the geometries of a table"))?;
+    let types: BTreeSet<String> = rows.iter().filter_map(|r| r.get("type")).collect();
+    let other_srids: BTreeSet<i32> = rows
+        .iter()
+        .map(|r| r.get("srid"))
+        .filter(|v| *v != srid)
+        .collect();
+    if !other_srids.is_empty() {
+        issues.push(TableIssue {
+            problem: format!(
+                "Some geometries have SRID {} instead of {srid}, so they are missing from the tiles or are misplaced",
+                other_srids.iter().join(", ")
+            ),
+            fix: (!is_view).then(|| format!(
+                "UPDATE {table} SET {column} = CASE ST_SRID({column}) WHEN 0 THEN ST_SetSRID({column}, {srid}) ELSE ST_Transform({column}, {srid}) END WHERE ST_SRID({column}) <> {srid}"
+            )),
+        });
+    }
+
+    let tuples_per_page = if is_view {
+        None
+    } else {
+        check_column_type(conn, info, &table, &types, &other_srids, &mut issues).await?;
+        table_density(conn, &table, &mut issues).await?
+    };
+
+    let samples = explain_tiles(conn, info, &table, &column, query).await?;
+    if let Some(sample) = samples.iter().find(|v| v.seq_scan) {
+        if info.geometry_index == Some(true) {
+            issues.push(TableIssue {
+                problem: format!(
+                    "Tile {:#} reads the whole table, even though column {} has a spatial index",
+                    sample.xyz, info.geometry_column
+                ),
+                fix: Some(format!("ANALYZE {table}")),
+            });
+        }
+    }
+    if let Some(tuples_per_page) = tuples_per_page {
+        let scattered = samples
+            .iter()
+            .filter_map(|v| scatter_ratio(v, tuples_per_page).map(|ratio| (v, ratio)))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((sample, ratio)) = scattered {
+            issues.push(cluster_issue(conn, info, &table, sample, ratio).await?);
+        }
+    }
+
+    Ok(TableAdvice {
+        source_id: source_id.to_string(),
+        table: info.format_id(),
+        samples,
+        issues,
+    })
+}
+
+/// Suggest clustering the table by its spatial index, so that the features of a tile are stored in fewer pages
+async fn cluster_issue(
+    conn: &Object,
+    info: &TableInfo,
+    table: &str,
+    sample: &TileSample,
+    ratio: f64,
+) -> PgResult<TableIssue> {
+    let index = conn
+        .query_opt(
+            "SELECT i.relname AS name FROM pg_index AS x
+             JOIN pg_class AS i ON i.oid = x.indexrelid
+             JOIN pg_am AS am ON am.oid = i.relam
+             JOIN pg_attribute AS a ON a.attrelid = x.indrelid AND a.attnum = ANY (x.indkey)
+             WHERE x.indrelid = to_regclass($1) AND a.attname = $2 AND am.amname = 'gist'
+             LIMIT 1",
+            &[&table, &info.geometry_column],
+        )
+        .await
+        .map_err(|e| PostgresError(e, "querying the spatial index of a table"))?;
+    let fix = if let Some(row) = index {
+        format!(
+            "CLUSTER {table} USING {}",
+            escape_identifier(row.get("name"))
+        )
+    } else {
+        let index = format!("{}_{}_idx", info.table, info.geometry_column);
+        format!(
+            "{}; CLUSTER {table} USING {}",
+            spatial_index_sql(info),
+            escape_identifier(&index)
+        )
+    };
+    Ok(TableIssue {
+        problem: format!(
+            "Tile {:#} reads {} pages for {} features, {ratio:.0} times as many as if nearby features were stored together",
+            sample.xyz, sample.pages, sample.rows
+        ),
+        fix: Some(fix),
+    })
+}
+
+/// Report a geometry column without a type modifier, e.g. `geometry` instead of `geometry(Point, 4326)`
+async fn check_column_type(
+    conn: &Object,
+    info: &TableInfo,
+    table: &str,
+    types: &BTreeSet<String>,
+    other_srids: &BTreeSet<i32>,
+    issues: &mut Vec<TableIssue>,
+) -> PgResult<()> {
+    let column = escape_identifier(&info.geometry_column);
+    let srid = info.srid;
+    let typ = conn
+        .query_opt(
+            "SELECT a.atttypmod AS typmod, format_type(a.atttypid, a.atttypmod) AS type
+             FROM pg_attribute AS a WHERE a.attrelid = to_regclass($1) AND a.attname = $2",
+            &[&table, &info.geometry_column],
+        )
+        .await
+        .map_err(|e| PostgresError(e, "querying the geometry column type"))?;
+    if let Some(typ) = typ {
+        let column_type: String = typ.get("type");
+        if typ.get::<_, i32>("typmod") == -1 && column_type == "geometry" {
+            let geometry_type = match types.iter().exactly_one() {
+                Ok(v) => v.as_str(),
+                Err(_) => "Geometry",
+            };
+            issues.push(TableIssue {
+                problem: format!(
+                    "Column {} is declared as {column_type} without a geometry type and SRID, so the geometries are not checked when they are written",
+                    info.geometry_column
+                ),
+                fix: (other_srids.is_empty() && srid != 0).then(|| format!(
+                    "ALTER TABLE {table} ALTER COLUMN {column} TYPE geometry({geometry_type}, {srid}) USING ST_SetSRID({column}, {srid})"
+                )),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Average number of rows per table page, or `None` if the table has no statistics
+async fn table_density(
+    conn: &Object,
+    table: &str,
+    issues: &mut Vec<TableIssue>,
+) -> PgResult<Option<f64>> {
+    let stats = conn
+        .query_opt(
+            "SELECT reltuples, relpages FROM pg_class WHERE oid = to_regclass($1)",
+            &[&table],
+        )
+        .await
+        .map_err(|e| PostgresError(e, "querying the table statistics"))?;
+    let Some(row) = stats else {
+        return Ok(None);
+    };
+    let tuples: f32 = row.get("reltuples");
+    let pages: i32 = row.get("relpages");
+    if tuples < 0.0 {
+        issues.push(TableIssue {
+            problem:
+                "The table has never been analyzed, so the planner may not use its spatial index"
+                    .to_string(),
+            fix: Some(format!("ANALYZE {table}")),
+        });
+    }
+    Ok((tuples > 0.0 && pages > 0).then(|| f64::from(tuples) / f64::from(pages)))
+}
+
+/// Run `EXPLAIN ANALYZE` of the tile query for a few tiles around a feature of the table
+async fn explain_tiles(
+    conn: &Object,
+    info: &TableInfo,
+    table: &str,
+    column: &str,
+    query: &str,
+) -> PgResult<Vec<TileSample>> {
+    let srid = info.srid;
+    if srid == 0 {
+        return Ok(Vec::new());
+    }
+    let point = conn
+        .query_opt(
+            &format!(
+                "SELECT ST_X(p) AS lon, ST_Y(p) AS lat FROM (
+                   SELECT ST_Transform(ST_Centroid({column}), 4326) AS p FROM {table}
+                   WHERE {column} IS NOT NULL AND NOT ST_IsEmpty({column}) AND ST_SRID({column}) = {srid}
+                   LIMIT 1
+                 ) AS sample"
+            ),
+            &[],
+        )
+        .await
+        .map_err(|e| PostgresError(e, "finding a feature of a table"))?;
+    let Some(point) = point else {
+        return Ok(Vec::new());
+    };
+    let lon: f64 = point.get("lon");
+    let lat = point
+        .get::<_, f64>("lat")
+        .clamp(-MAX_LATITUDE, MAX_LATITUDE);
+
+    let min = info.minzoom.unwrap_or(0);
+    let max = info.maxzoom.unwrap_or(30).max(min);
+    let mut samples = Vec::new();
+    for z in SAMPLE_ZOOMS.map(|z| z.clamp(min, max)).into_iter().dedup() {
+        let (x, y) = tile_index(lon, lat, z);
+        let sql = query
+            .trim_end_matches(';')
+            .replace("$1", &z.to_string())
+            .replace("$2", &x.to_string())
+            .replace("$3", &y.to_string());
+        let messages = conn
+            .simple_query(&format!("EXPLAIN (ANALYZE, BUFFERS, FORMAT JSON) {sql}"))
+            .await
+            .map_err(|e| PostgresError(e, "explaining a tile query"))?;
+        let plan = messages.iter().find_map(|m| match m {
+            SimpleQueryMessage::Row(row) => row.get(0),
+            _ => None,
+        });
+        if let Some(plan) = plan.and_then(|v| serde_json::from_str::<Value>(v).ok()) {
+            samples.push(parse_plan(TileCoord { z, x, y }, &plan, &info.table));
+        }
+    }
+    Ok(samples)
+}
+
+/// Read the time and the reads of the table from the JSON output of `EXPLAIN (ANALYZE, BUFFERS)`
+fn parse_plan(xyz: TileCoord, explain: &Value, relation: &str) -> TileSample {
+    let mut sample = TileSample {
+        xyz,
+        time: explain[0]["Execution Time"].as_f64().unwrap_or_default(),
+        rows: 0.0,
+        pages: 0.0,
+        seq_scan: false,
+    };
+    add_scans(&explain[0]["Plan"], relation, &mut sample);
+    sample
+}
+
+fn add_scans(node: &Value, relation: &str, sample: &mut TileSample) {
+    if node["Relation Name"].as_str() == Some(relation) {
+        let number = |key: &str| node[key].as_f64().unwrap_or_default();
+        sample.rows += number("Actual Rows") * number("Actual Loops").max(1.0);
+        sample.pages += number("Shared Hit Blocks") + number("Shared Read Blocks");
+        sample.seq_scan |= node["Node Type"].as_str() == Some("Seq Scan");
+    }
+    for child in node["Plans"].as_array().into_iter().flatten() {
+        add_scans(child, relation, sample);
+    }
+}
+
+/// How many times more pages the tile query read than the rows would fill, if it is above the reporting threshold
+fn scatter_ratio(sample: &TileSample, tuples_per_page: f64) -> Option<f64> {
+    if sample.seq_scan || sample.rows < SCATTER_MIN_ROWS {
+        return None;
+    }
+    let ratio = sample.pages / (sample.rows / tuples_per_page).ceil().max(1.0);
+    (ratio >= SCATTER_RATIO).then_some(ratio)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn tile_query_plan() {
+        let plan = json!([{
+            "Plan": {
+                "Node Type": "Aggregate",
+                "Shared Hit Blocks": 130,
+                "Plans": [{
+                    "Node Type": "Bitmap Heap Scan",
+                    "Relation Name": "points",
+                    "Actual Rows": 200,
+                    "Actual Loops": 1,
+                    "Shared Hit Blocks": 100,
+                    "Shared Read Blocks": 20,
+                    "Plans": [{
+                        "Node Type": "Bitmap Index Scan",
+                        "Index Name": "points_geom_idx",
+                        "Shared Hit Blocks": 5
+                    }]
+                }, {
+                    "Node Type": "Seq Scan",
+                    "Relation Name": "countries",
+                    "Actual Rows": 5,
+                    "Actual Loops": 1,
+                    "Shared Hit Blocks": 10
+                }]
+            },
+            "Execution Time": 12.5
+        }]);
+        let xyz = TileCoord { z: 8, x: 1, y: 2 };
+        let sample = parse_plan(xyz, &plan, "points");
+        assert_eq!(
+            sample,
+            TileSample {
+                xyz,
+                time: 12.5,
+                rows: 200.0,
+                pages: 120.0,
+                seq_scan: false,
+            }
+        );
+        // 200 rows fill 4 pages of 50 rows, but were read from 120 pages
+        assert_eq!(scatter_ratio(&sample, 50.0), Some(30.0));
+        assert_eq!(scatter_ratio(&sample, 2.0), None);
+        assert!(parse_plan(xyz, &plan, "countries").seq_scan);
+        let small = TileSample {
+            rows: 10.0,
+            ..sample
+        };
+        assert_eq!(scatter_ratio(&small, 50.0), None);
+    }
+}
@@ -11,6 +11,9 @@ use crate::config::{copy_unrecognized_config, UnrecognizedValues};
 use crate::pg::config_function::FuncInfoSources;
 use crate::pg::config_table::TableInfoSources;
 use crate::pg::configurator::PgBuilder;
+use crate::pg::pool::PgPool;
+use crate::pg::utils::{validate_source_id_format, FUNCTION_ID_VARS, TABLE_ID_VARS};
+use crate::pg::PgError::InvalidSourceIdFormat;
 use crate::pg::PgResult;
 use crate::source::TileInfoSources;
 use crate::utils::{on_slow, IdResolver, OptBoolObj, OptOneMany};
@@ -18,6 +21,7 @@ use crate::MartinResult;
 
 pub trait PgInfo {
     fn format_id(&self) -> String;
+    fn schema(&self) -> &str;
     fn to_tilejson(&self, source_id: String) -> TileJSON;
 }
 
@@ -39,16 +43,45 @@ pub struct PgSslCerts {
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct PgConfig {
     pub connection_string: Option<String>,
+    /// Name of the connection, used in the logs, at `/status`, and by the `prefix-with-pool-id` duplicate ID strategy.
+    /// Must be unique if several databases are configured [default: the database name]
+    pub id: Option<String>,
+    /// Prepended to the IDs of the auto-published sources of this connection, e.g. `sales.` for `sales.orders`
+    pub id_prefix: Option<String>,
     #[serde(flatten)]
     pub ssl_certificates: PgSslCerts,
     pub default_srid: Option<i32>,
     pub auto_bounds: Option<BoundsCalcType>,
     pub max_feature_count: Option<usize>,
     pub pool_size: Option<usize>,
+    /// Adjust the pool size at runtime to the measured query concurrency
+    pub pool_autosize: Option<PoolAutosizeConfig>,
+    /// Maximum number of tables to introspect at the same time (bounds, SRID, and fields) [default: pool size]
+    pub discovery_concurrency: Option<usize>,
+    /// Resolve the database hosts again this often, in seconds, and reconnect if their addresses changed.
+    /// Use 0 to disable [default: 60 for `postgres+srv://` connection strings, disabled otherwise]
+    pub dns_refresh: Option<u64>,
+    /// Add `X-Feature-Count` headers with the number of features to the tile responses [default: false]
+    pub feature_count_header: Option<bool>,
     #[serde(default, skip_serializing_if = "OptBoolObj::is_none")]
     pub auto_publish: OptBoolObj<PgCfgPublish>,
     pub tables: Option<TableInfoSources>,
     pub functions: Option<FuncInfoSources>,
+    /// Run all queries in a single read-only repeatable-read snapshot, so that the data
+    /// does not change while it is being read. Cannot be set in the config file.
+    #[serde(skip)]
+    pub consistent_snapshot: bool,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolAutosizeConfig {
+    /// The pool never shrinks below this size [default: 2]
+    pub min_size: Option<usize>,
+    /// The pool never grows above this size [default: 100]
+    pub max_size: Option<usize>,
+    /// How often to adjust the pool size, in seconds [default: 30]
+    pub interval: Option<u64>,
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
@@ -79,6 +112,8 @@ pub struct PgCfgPublishTables {
     pub clip_geom: Option<bool>,
     pub buffer: Option<u32>,
     pub extent: Option<u32>,
+    /// Repair invalid geometries with `ST_MakeValid` before encoding them [default: false]
+    pub make_valid: Option<bool>,
 }
 
 #[serde_with::skip_serializing_none]
@@ -105,6 +140,22 @@ impl PgConfig {
                 copy_unrecognized_config(&mut res, &format!("functions.{k}."), &v.unrecognized);
             }
         }
+        if let OptBoolObj::Object(publish) = &self.auto_publish {
+            if let OptBoolObj::Object(PgCfgPublishTables {
+                source_id_format: Some(v),
+                ..
+            }) = &publish.tables
+            {
+                validate_source_id_format(v, TABLE_ID_VARS).map_err(InvalidSourceIdFormat)?;
+            }
+            if let OptBoolObj::Object(PgCfgPublishFuncs {
+                source_id_format: Some(v),
+                ..
+            }) = &publish.functions
+            {
+                validate_source_id_format(v, FUNCTION_ID_VARS).map_err(InvalidSourceIdFormat)?;
+            }
+        }
         if self.tables.is_none() && self.functions.is_none() && self.auto_publish.is_none() {
             self.auto_publish = OptBoolObj::Bool(true);
         }
@@ -134,6 +185,22 @@ impl PgConfig {
         tables.extend(funcs);
         Ok(tables)
     }
+
+    /// Resolve the sources without calculating their bounds, and open a pool for maintenance queries,
+    /// e.g. to inspect the tables or create functions. The pool does not use the consistent snapshot,
+    /// so it can modify the database.
+    pub(crate) async fn resolve_for_maintenance(
+        &mut self,
+        id_resolver: IdResolver,
+    ) -> MartinResult<PgPool> {
+        self.auto_bounds = Some(BoundsCalcType::Skip);
+        self.resolve(id_resolver).await?;
+        Ok(PgPool::new(&PgConfig {
+            consistent_snapshot: false,
+            ..self.clone()
+        })
+        .await?)
+    }
 }
 
 #[cfg(test)]
@@ -176,6 +243,8 @@ mod tests {
             postgres:
               - connection_string: 'postgres://postgres@localhost:5432/db'
               - connection_string: 'postgresql://postgres@localhost:5433/db'
+                id: sales
+                id_prefix: sales.
         "},
             &Config {
                 postgres: Many(vec![
@@ -186,6 +255,8 @@ mod tests {
                     },
                     PgConfig {
                         connection_string: some("postgresql://postgres@localhost:5433/db"),
+                        id: some("sales"),
+                        id_prefix: some("sales."),
                         auto_publish: OptBoolObj::Bool(true),
                         ..Default::default()
                     },
@@ -193,6 +264,15 @@ mod tests {
                 ..Default::default()
             },
         );
+
+        let mut config = crate::config::tests::parse_cfg(indoc! {"
+            postgres:
+              - connection_string: 'postgres://postgres@localhost:5432/db'
+                id: sales
+              - connection_string: 'postgresql://postgres@localhost:5433/db'
+                id: sales
+        "});
+        assert!(config.finalize().is_err());
     }
 
     #[test]
@@ -203,6 +283,11 @@ mod tests {
               connection_string: 'postgres://postgres@localhost:5432/db'
               default_srid: 4326
               pool_size: 20
+              pool_autosize:
+                min_size: 4
+                interval: 60
+              discovery_concurrency: 8
+              dns_refresh: 30
               max_feature_count: 100
 
               tables:
@@ -229,12 +314,20 @@ mod tests {
                   minzoom: 0
                   maxzoom: 30
                   bounds: [-180.0, -90.0, 180.0, 90.0]
+                  margin: 0.125
         "},
             &Config {
                 postgres: One(PgConfig {
                     connection_string: some("postgres://postgres@localhost:5432/db"),
                     default_srid: Some(4326),
                     pool_size: Some(20),
+                    pool_autosize: Some(PoolAutosizeConfig {
+                        min_size: Some(4),
+                        max_size: None,
+                        interval: Some(60),
+                    }),
+                    discovery_concurrency: Some(8),
+                    dns_refresh: Some(30),
                     max_feature_count: Some(100),
                     tables: Some(BTreeMap::from([(
                         "table_source".to_string(),
@@ -259,13 +352,16 @@ mod tests {
                     )])),
                     functions: Some(BTreeMap::from([(
                         "function_zxy_query".to_string(),
-                        FunctionInfo::new_extended(
-                            "public".to_string(),
-                            "function_zxy_query".to_string(),
-                            0,
-                            30,
-                            Bounds::MAX,
-                        ),
+                        FunctionInfo {
+                            margin: Some(0.125),
+                            ..FunctionInfo::new_extended(
+                                "public".to_string(),
+                                "function_zxy_query".to_string(),
+                                0,
+                                30,
+                                Bounds::MAX,
+                            )
+                        },
                     )])),
                     ..Default::default()
                 }),
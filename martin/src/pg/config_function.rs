@@ -1,9 +1,12 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 use tilejson::{Bounds, TileJSON};
 
 use crate::config::UnrecognizedValues;
 use crate::pg::config::PgInfo;
 use crate::pg::utils::{patch_json, InfoMap};
+use crate::source::UrlQuery;
 
 pub type FuncInfoSources = InfoMap<FunctionInfo>;
 
@@ -28,6 +31,15 @@ pub struct FunctionInfo {
     /// Values may be integers or floating point numbers.
     pub bounds: Option<Bounds>,
 
+    /// Size of the area around the tile, as a fraction of the tile width, that the function
+    /// should include (e.g. to cluster or label features near the tile edges).
+    /// Passed as the last function parameter, which must be named `margin` and be `double precision` or `numeric`.
+    pub margin: Option<f64>,
+
+    /// Restrict which URL query parameters are passed to the function, and set their defaults.
+    /// Only used if the function has the `query json` parameter.
+    pub url_query: Option<UrlQueryConfig>,
+
     /// TileJSON provided by the SQL function comment. Not serialized.
     #[serde(skip)]
     pub tilejson: Option<serde_json::Value>,
@@ -36,6 +48,58 @@ pub struct FunctionInfo {
     pub unrecognized: UnrecognizedValues,
 }
 
+/// What to do with URL query parameters that are not listed in [`UrlQueryConfig::params`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownQueryParams {
+    /// Ignore unknown parameters, and do not pass them to the function
+    #[default]
+    Drop,
+    /// Respond with `400 Bad Request` if the request has any unknown parameters
+    Reject,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UrlQueryConfig {
+    /// Parameters passed to the function, with an optional default value
+    /// used when the parameter is missing from the request.
+    /// If not set, all parameters are passed as is.
+    pub params: Option<BTreeMap<String, Option<String>>>,
+    /// What to do with the parameters not listed in `params`
+    pub unknown: Option<UnknownQueryParams>,
+}
+
+impl UrlQueryConfig {
+    /// Remove unknown parameters and fill in the defaults, so that equivalent requests
+    /// produce the same function input. Returns the unknown parameter names if they are rejected.
+    pub fn normalize(&self, query: &UrlQuery) -> Result<UrlQuery, Vec<String>> {
+        let Some(params) = &self.params else {
+            return Ok(query.clone());
+        };
+        if self.unknown.unwrap_or_default() == UnknownQueryParams::Reject {
+            let mut unknown: Vec<_> = query
+                .keys()
+                .filter(|k| !params.contains_key(*k))
+                .cloned()
+                .collect();
+            if !unknown.is_empty() {
+                unknown.sort();
+                return Err(unknown);
+            }
+        }
+        Ok(params
+            .iter()
+            .filter_map(|(k, default)| {
+                query
+                    .get(k)
+                    .or(default.as_ref())
+                    .map(|v| (k.clone(), v.clone()))
+            })
+            .collect())
+    }
+}
+
 impl FunctionInfo {
     #[must_use]
     pub fn new(schema: String, function: String, tilejson: Option<serde_json::Value>) -> Self {
@@ -71,6 +135,10 @@ impl PgInfo for FunctionInfo {
         format!("{}.{}", self.schema, self.function)
     }
 
+    fn schema(&self) -> &str {
+        &self.schema
+    }
+
     fn to_tilejson(&self, source_id: String) -> TileJSON {
         let mut tilejson = tilejson::tilejson! {
             tiles: vec![],  // tile source is required, but not yet known
@@ -83,3 +151,38 @@ impl PgInfo for FunctionInfo {
         patch_json(tilejson, &self.tilejson)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_url_query() {
+        let query = |v: &[(&str, &str)]| -> UrlQuery {
+            v.iter()
+                .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
+                .collect()
+        };
+        let mut cfg = UrlQueryConfig::default();
+        let q = query(&[("a", "1"), ("junk", "x")]);
+        assert_eq!(cfg.normalize(&q), Ok(q.clone()));
+
+        cfg.params = Some(BTreeMap::from([
+            ("a".to_string(), None),
+            ("b".to_string(), Some("2".to_string())),
+        ]));
+        assert_eq!(cfg.normalize(&q), Ok(query(&[("a", "1"), ("b", "2")])));
+        assert_eq!(
+            cfg.normalize(&query(&[("b", "3")])),
+            Ok(query(&[("b", "3")]))
+        );
+        assert_eq!(cfg.normalize(&query(&[])), Ok(query(&[("b", "2")])));
+
+        cfg.unknown = Some(UnknownQueryParams::Reject);
+        assert_eq!(cfg.normalize(&q), Err(vec!["junk".to_string()]));
+        assert_eq!(
+            cfg.normalize(&query(&[("a", "1")])),
+            Ok(query(&[("a", "1"), ("b", "2")]))
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::{BTreeMap, HashMap};
 
+use postgres_protocol::escape::escape_literal;
 use serde::{Deserialize, Serialize};
 use tilejson::{Bounds, TileJSON, VectorLayer};
 
@@ -38,6 +39,9 @@ pub struct TableInfo {
     /// Feature id column name
     pub id_column: Option<String>,
 
+    /// Date or timestamp column used by the `datetime` filter of the `/collections/{id}/items` endpoint
+    pub datetime_column: Option<String>,
+
     /// An integer specifying the minimum zoom level
     pub minzoom: Option<u8>,
 
@@ -59,12 +63,22 @@ pub struct TableInfo {
     /// Boolean to control if geometries should be clipped or encoded as is
     pub clip_geom: Option<bool>,
 
+    /// Repair invalid geometries with `ST_MakeValid` before encoding them.
+    /// Invalid geometries may otherwise fail the whole tile.
+    pub make_valid: Option<bool>,
+
     /// Geometry type
     pub geometry_type: Option<String>,
 
     /// List of columns, that should be encoded as tile properties
     pub properties: Option<BTreeMap<String, String>>,
 
+    /// How to encode `jsonb`, `json`, and `hstore` properties, by property name
+    pub json_properties: Option<BTreeMap<String, JsonProperty>>,
+
+    /// Convert properties to a different type, by property name
+    pub property_casts: Option<BTreeMap<String, PropertyCast>>,
+
     /// Mapping of properties to the actual table columns
     #[serde(skip)]
     pub prop_mapping: HashMap<String, String>,
@@ -77,11 +91,109 @@ pub struct TableInfo {
     pub tilejson: Option<serde_json::Value>,
 }
 
+/// How a `jsonb`, `json`, or `hstore` column is stored in the tile
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonPropertyMode {
+    /// Store each top-level key as a separate property
+    #[default]
+    Flatten,
+    /// Store the whole document as a single JSON string property
+    String,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JsonProperty {
+    pub mode: Option<JsonPropertyMode>,
+    /// When flattening, prepend this string to each key, e.g. `tag:` to get `tag:name` properties
+    pub prefix: Option<String>,
+    /// Top-level keys to remove from the document
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_keys: Vec<String>,
+}
+
+impl JsonProperty {
+    /// SQL expression that converts the escaped column to the configured representation
+    #[must_use]
+    pub fn to_sql(&self, column: &str) -> String {
+        let excluded = self
+            .exclude_keys
+            .iter()
+            .map(|v| escape_literal(v))
+            .collect::<Vec<_>>()
+            .join(", ");
+        match self.mode.unwrap_or_default() {
+            JsonPropertyMode::String if excluded.is_empty() => format!("{column}::jsonb::text"),
+            JsonPropertyMode::String => format!("({column}::jsonb - ARRAY[{excluded}])::text"),
+            JsonPropertyMode::Flatten => {
+                let key = match &self.prefix {
+                    Some(prefix) if !prefix.is_empty() => {
+                        format!("{} || key", escape_literal(prefix))
+                    }
+                    _ => "key".to_string(),
+                };
+                let filter = if excluded.is_empty() {
+                    String::new()
+                } else {
+                    format!(" WHERE key <> ALL(ARRAY[{excluded}])")
+                };
+                format!("(SELECT jsonb_object_agg({key}, value) FROM jsonb_each({column}::jsonb){filter})")
+            }
+        }
+    }
+}
+
+/// Type a property value is converted to before it is stored in the tile
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PropertyCastType {
+    /// 64-bit floating point number, optionally rounded to `precision` decimal digits
+    Float,
+    /// 64-bit integer, with booleans stored as `0` and `1`
+    Int,
+    /// Postgres text representation of the value
+    String,
+    /// ISO 8601 string for dates and timestamps, e.g. `2023-01-31T12:34:56+00:00`
+    Iso8601,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PropertyCast {
+    #[serde(rename = "type")]
+    pub cast: PropertyCastType,
+    /// Number of decimal digits to keep, only used with the `float` type
+    pub precision: Option<u8>,
+}
+
+impl PropertyCast {
+    /// SQL expression that converts the escaped column of the given Postgres type
+    #[must_use]
+    pub fn to_sql(&self, column: &str, pg_type: Option<&str>) -> String {
+        match (self.cast, self.precision) {
+            (PropertyCastType::Float, Some(digits)) => {
+                format!("round({column}::numeric, {digits})::float8")
+            }
+            (PropertyCastType::Float, None) => format!("{column}::float8"),
+            // There is no direct cast from boolean to bigint
+            (PropertyCastType::Int, _) if pg_type == Some("bool") => format!("{column}::int4"),
+            (PropertyCastType::Int, _) => format!("{column}::int8"),
+            (PropertyCastType::String, _) => format!("{column}::text"),
+            (PropertyCastType::Iso8601, _) => format!("to_json({column}) #>> '{{}}'"),
+        }
+    }
+}
+
 impl PgInfo for TableInfo {
     fn format_id(&self) -> String {
         format!("{}.{}.{}", self.schema, self.table, self.geometry_column)
     }
 
+    fn schema(&self) -> &str {
+        &self.schema
+    }
+
     fn to_tilejson(&self, source_id: String) -> TileJSON {
         let mut tilejson = tilejson::tilejson! {
             tiles: vec![],  // tile source is required, but not yet known
@@ -103,3 +215,63 @@ impl PgInfo for TableInfo {
         patch_json(tilejson, &self.tilejson)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_property_sql() {
+        let prop = |mode, prefix: Option<&str>, exclude_keys: &[&str]| JsonProperty {
+            mode: Some(mode),
+            prefix: prefix.map(ToString::to_string),
+            exclude_keys: exclude_keys.iter().map(ToString::to_string).collect(),
+        };
+        assert_eq!(
+            prop(JsonPropertyMode::String, None, &[]).to_sql(r#""tags""#),
+            r#""tags"::jsonb::text"#
+        );
+        assert_eq!(
+            prop(JsonPropertyMode::String, None, &["a", "b'c"]).to_sql(r#""tags""#),
+            r#"("tags"::jsonb - ARRAY['a', 'b''c'])::text"#
+        );
+        assert_eq!(
+            prop(JsonPropertyMode::Flatten, Some("tag:"), &[]).to_sql(r#""tags""#),
+            r#"(SELECT jsonb_object_agg('tag:' || key, value) FROM jsonb_each("tags"::jsonb))"#
+        );
+        assert_eq!(
+            prop(JsonPropertyMode::Flatten, None, &["a"]).to_sql(r#""tags""#),
+            r#"(SELECT jsonb_object_agg(key, value) FROM jsonb_each("tags"::jsonb) WHERE key <> ALL(ARRAY['a']))"#
+        );
+    }
+
+    #[test]
+    fn property_cast_sql() {
+        let cast = |cast, precision| PropertyCast { cast, precision };
+        let col = r#""val""#;
+        assert_eq!(
+            cast(PropertyCastType::Float, Some(2)).to_sql(col, Some("numeric")),
+            r#"round("val"::numeric, 2)::float8"#
+        );
+        assert_eq!(
+            cast(PropertyCastType::Float, None).to_sql(col, None),
+            r#""val"::float8"#
+        );
+        assert_eq!(
+            cast(PropertyCastType::Int, None).to_sql(col, Some("bool")),
+            r#""val"::int4"#
+        );
+        assert_eq!(
+            cast(PropertyCastType::Int, None).to_sql(col, Some("numeric")),
+            r#""val"::int8"#
+        );
+        assert_eq!(
+            cast(PropertyCastType::String, None).to_sql(col, None),
+            r#""val"::text"#
+        );
+        assert_eq!(
+            cast(PropertyCastType::Iso8601, None).to_sql(col, Some("timestamptz")),
+            r#"to_json("val") #>> '{}'"#
+        );
+    }
+}
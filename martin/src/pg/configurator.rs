@@ -1,7 +1,8 @@
 use std::cmp::Ordering;
 use std::collections::HashSet;
+use std::time::{Duration, Instant};
 
-use futures::future::join_all;
+use futures::stream::{self, StreamExt};
 use itertools::Itertools;
 use log::{debug, error, info, warn};
 
@@ -15,14 +16,17 @@ use crate::pg::pool::PgPool;
 use crate::pg::table_source::{
     calc_srid, merge_table_info, query_available_tables, table_to_query,
 };
-use crate::pg::utils::{find_info, find_kv_ignore_case, normalize_key, InfoMap};
+use crate::pg::utils::{find_info, find_kv_ignore_case, format_source_id, normalize_key, InfoMap};
 use crate::pg::PgError::InvalidTableExtent;
-use crate::pg::{PgCfgPublish, PgCfgPublishFuncs, PgResult};
+use crate::pg::{PgCfgPublish, PgCfgPublishFuncs, PgResult, POOL_SIZE_DEFAULT};
 use crate::source::TileInfoSources;
-use crate::utils::IdResolver;
 use crate::utils::OptOneMany::NoVals;
+use crate::utils::{IdPrefixes, IdResolver};
 use crate::OptBoolObj::{Bool, NoValue, Object};
 
+/// How often to report table discovery progress
+const PROGRESS_REPORT_EVERY: Duration = Duration::from_secs(5);
+
 pub type SqlFuncInfoMapMap = InfoMap<InfoMap<(PgSqlInfo, FunctionInfo)>>;
 pub type SqlTableInfoMapMapMap = InfoMap<InfoMap<InfoMap<TableInfo>>>;
 
@@ -43,6 +47,7 @@ pub struct PgBuilderTables {
     clip_geom: Option<bool>,
     buffer: Option<u32>,
     extent: Option<u32>,
+    make_valid: Option<bool>,
 }
 
 #[derive(Debug)]
@@ -51,6 +56,9 @@ pub struct PgBuilder {
     default_srid: Option<i32>,
     auto_bounds: BoundsCalcType,
     max_feature_count: Option<usize>,
+    discovery_concurrency: usize,
+    feature_count_header: bool,
+    id_prefix: String,
     auto_functions: Option<PgBuilderFuncs>,
     auto_tables: Option<PgBuilderTables>,
     id_resolver: IdResolver,
@@ -89,6 +97,13 @@ impl PgBuilder {
             default_srid: config.default_srid,
             auto_bounds: config.auto_bounds.unwrap_or_default(),
             max_feature_count: config.max_feature_count,
+            discovery_concurrency: config
+                .discovery_concurrency
+                .or(config.pool_size)
+                .unwrap_or(POOL_SIZE_DEFAULT)
+                .max(1),
+            feature_count_header: config.feature_count_header.unwrap_or_default(),
+            id_prefix: config.id_prefix.clone().unwrap_or_default(),
             id_resolver,
             tables: config.tables.clone().unwrap_or_default(),
             functions: config.functions.clone().unwrap_or_default(),
@@ -139,12 +154,13 @@ impl PgBuilder {
             let dup = !used.insert((&cfg_inf.schema, &cfg_inf.table, &cfg_inf.geometry_column));
             let dup = if dup { "duplicate " } else { "" };
 
-            let id2 = self.resolve_id(id, cfg_inf);
+            let Some(id2) = self.resolve_id(id, cfg_inf) else {
+                continue;
+            };
             let Some(merged_inf) = merge_table_info(self.default_srid, &id2, cfg_inf, db_inf)
             else {
                 continue;
             };
-            warn_on_rename(id, &id2, "Table");
             info!("Configured {dup}source {id2} from {}", summary(&merged_inf));
             pending.push(table_to_query(
                 id2,
@@ -178,12 +194,22 @@ impl PgBuilder {
                         if used.contains(&(schema.as_str(), table.as_str(), geom_column.as_str())) {
                             continue;
                         }
-                        let source_id = auto_tables
-                            .source_id_format
-                            .replace("{schema}", &schema)
-                            .replace("{table}", &table)
-                            .replace("{column}", &geom_column);
-                        let id2 = self.resolve_id(&source_id, &db_inf);
+                        let vars = [
+                            ("schema", schema.as_str()),
+                            ("table", table.as_str()),
+                            ("column", geom_column.as_str()),
+                        ];
+                        let source_id = match format_source_id(&auto_tables.source_id_format, &vars)
+                        {
+                            Ok(v) => format!("{}{v}", self.id_prefix),
+                            Err(e) => {
+                                warn!("Unable to create a source ID for {schema}.{table}.{geom_column}: {e}");
+                                continue;
+                            }
+                        };
+                        let Some(id2) = self.resolve_id(&source_id, &db_inf) else {
+                            continue;
+                        };
                         let Some(srid) =
                             calc_srid(&db_inf.format_id(), &id2, db_inf.srid, 0, self.default_srid)
                         else {
@@ -206,8 +232,20 @@ impl PgBuilder {
 
         let mut res = TileInfoSources::default();
         let mut info_map = TableInfoSources::new();
-        let pending = join_all(pending).await;
-        for src in pending {
+        let total = pending.len();
+        let start = Instant::now();
+        let mut last_reported = start;
+        let mut done = 0;
+        let mut pending = stream::iter(pending).buffered(self.discovery_concurrency);
+        while let Some(src) = pending.next().await {
+            done += 1;
+            if last_reported.elapsed() > PROGRESS_REPORT_EVERY {
+                info!(
+                    "Initialized {done} of {total} table sources in PostgreSQL database '{}'",
+                    self.get_id()
+                );
+                last_reported = Instant::now();
+            }
             match src {
                 Err(v) => {
                     error!("Failed to create a source: {v}");
@@ -221,6 +259,12 @@ impl PgBuilder {
             }
         }
 
+        debug!(
+            "Initialized {total} table sources in PostgreSQL database '{}' in {:.1?}",
+            self.get_id(),
+            start.elapsed()
+        );
+
         Ok((res, info_map))
     }
 
@@ -244,12 +288,32 @@ impl PgBuilder {
             };
 
             let merged_inf = merge_func_info(cfg_inf, db_inf);
+            let mut pg_sql = pg_sql.clone();
+            match (merged_inf.margin, pg_sql.margin) {
+                (Some(margin), Some(_)) => pg_sql.margin = Some(margin),
+                (Some(_), None) => warn!(
+                    "Function {} has no margin parameter, ignoring the margin configured for source {id}",
+                    pg_sql.signature
+                ),
+                (None, _) => {}
+            }
+            if merged_inf.url_query.is_some() {
+                if pg_sql.use_url_query {
+                    pg_sql.url_query.clone_from(&merged_inf.url_query);
+                } else {
+                    warn!(
+                        "Function {} has no query parameter, ignoring the url_query configured for source {id}",
+                        pg_sql.signature
+                    );
+                }
+            }
 
             let dup = !used.insert((&cfg_inf.schema, func_name));
             let dup = if dup { "duplicate " } else { "" };
-            let id2 = self.resolve_id(id, &merged_inf);
+            let Some(id2) = self.resolve_id(id, &merged_inf) else {
+                continue;
+            };
             self.add_func_src(&mut res, id2.clone(), &merged_inf, pg_sql.clone());
-            warn_on_rename(id, &id2, "Function");
             let signature = &pg_sql.signature;
             info!("Configured {dup}source {id2} from the function {signature}");
             debug!("{id2} query: {}", pg_sql.query);
@@ -278,11 +342,17 @@ impl PgBuilder {
                     if used.contains(&(schema.as_str(), func.as_str())) {
                         continue;
                     }
-                    let source_id = auto_funcs
-                        .source_id_format
-                        .replace("{schema}", &schema)
-                        .replace("{function}", &func);
-                    let id2 = self.resolve_id(&source_id, &db_inf);
+                    let vars = [("schema", schema.as_str()), ("function", func.as_str())];
+                    let source_id = match format_source_id(&auto_funcs.source_id_format, &vars) {
+                        Ok(v) => format!("{}{v}", self.id_prefix),
+                        Err(e) => {
+                            warn!("Unable to create a source ID for {schema}.{func}: {e}");
+                            continue;
+                        }
+                    };
+                    let Some(id2) = self.resolve_id(&source_id, &db_inf) else {
+                        continue;
+                    };
                     self.add_func_src(&mut res, id2.clone(), &db_inf, pg_sql.clone());
                     info!("Discovered source {id2} from function {}", pg_sql.signature);
                     debug!("{id2} query: {}", pg_sql.query);
@@ -293,9 +363,14 @@ impl PgBuilder {
         Ok((res, info_map))
     }
 
-    fn resolve_id<T: PgInfo>(&self, id: &str, src_inf: &T) -> String {
+    fn resolve_id<T: PgInfo>(&self, id: &str, src_inf: &T) -> Option<String> {
         let signature = format!("{}.{}", self.pool.get_id(), src_inf.format_id());
-        self.id_resolver.resolve(id, signature)
+        let prefixes = IdPrefixes {
+            schema: Some(src_inf.schema()),
+            pool_id: Some(self.pool.get_id()),
+        };
+        self.id_resolver
+            .resolve_with_prefixes(id, signature, prefixes)
     }
 
     fn add_func_src(
@@ -306,7 +381,8 @@ impl PgBuilder {
         sql: PgSqlInfo,
     ) {
         let tilejson = info.to_tilejson(id.clone());
-        let source = PgSource::new(id, sql, tilejson, self.pool.clone());
+        let source = PgSource::new(id, sql, tilejson, self.pool.clone())
+            .with_feature_count(self.feature_count_header);
         sources.push(Box::new(source));
     }
 }
@@ -315,6 +391,9 @@ fn update_auto_fields(id: &str, inf: &mut TableInfo, auto_tables: &PgBuilderTabl
     if inf.clip_geom.is_none() {
         inf.clip_geom = auto_tables.clip_geom;
     }
+    if inf.make_valid.is_none() {
+        inf.make_valid = auto_tables.make_valid;
+    }
     if inf.buffer.is_none() {
         inf.buffer = auto_tables.buffer;
     }
@@ -387,6 +466,7 @@ fn calc_auto(config: &PgConfig) -> (Option<PgBuilderTables>, Option<PgBuilderFun
                 clip_geom: v.clip_geom,
                 buffer: v.buffer,
                 extent: v.extent,
+                make_valid: v.make_valid,
             }
         } else {
             PgBuilderTables {
@@ -448,12 +528,6 @@ fn use_auto_publish(config: &PgConfig, for_functions: bool) -> bool {
     }
 }
 
-fn warn_on_rename(old_id: &String, new_id: &String, typ: &str) {
-    if old_id != new_id {
-        warn!("{typ} source {old_id} was renamed to {new_id} due to ID conflict");
-    }
-}
-
 fn summary(info: &TableInfo) -> String {
     let relkind = match info.is_view {
         Some(true) => "view",
@@ -46,12 +46,35 @@ pub enum PgError {
     #[error("Unable to parse connection string {1}: {0}")]
     BadConnectionString(#[source] TokioPgError, String),
 
+    #[error("Invalid postgres+srv:// connection string {0}, it must have a single SRV record name without a port instead of the hosts")]
+    BadSrvConnectionString(String),
+
+    #[error("Unable to resolve database host {1}: {0}")]
+    HostLookupError(#[source] io::Error, String),
+
+    #[error("Martin was built without the dns-srv feature, so the SRV record {0} cannot be resolved. Rebuild Martin with `--features dns-srv`, or list the database hosts in the connection string")]
+    SrvNotSupported(String),
+
     #[error("Unable to parse PostGIS version {1}: {0}")]
     BadPostgisVersion(#[source] semver::Error, String),
 
     #[error("PostGIS version {0} is too old, minimum required is {1}")]
     PostgisTooOld(Version, Version),
 
+    #[error(
+        "Several PostgreSQL connections use the id {0}, each connection must have a unique id"
+    )]
+    DuplicateConnectionId(String),
+
+    #[error("Invalid auto_publish source_id_format: {0}")]
+    InvalidSourceIdFormat(String),
+
+    #[error("Invalid name format of the tile functions: {0}")]
+    InvalidFunctionNameFormat(String),
+
+    #[error("Source {0} is not a table source of the PostgreSQL connections")]
+    NotATableSource(String),
+
     #[error("Invalid extent setting in source {0} for table {1}: extent=0")]
     InvalidTableExtent(String, String),
 
@@ -61,6 +84,12 @@ pub enum PgError {
     #[error(r#"Unable to get tile {2:#} from {1}: {0}"#)]
     GetTileError(#[source] TokioPgError, String, TileCoord),
 
+    #[error("Unable to get features from {1}: {0}")]
+    GetFeaturesError(#[source] TokioPgError, String),
+
+    #[error("Source {0} does not support URL query parameters {1}")]
+    InvalidUrlQuery(String, String),
+
     #[error(r#"Unable to get tile {2:#} with {:?} params from {1}: {0}"#, query_to_json(.3))]
     GetTileWithQueryError(#[source] TokioPgError, String, TileCoord, UrlQuery),
 }
@@ -46,8 +46,12 @@ pub async fn query_available_function(pool: &PgPool) -> PgResult<SqlFuncInfoMapM
                 None
             };
 
-            assert!(input_types.len() >= 3 && input_types.len() <= 4);
+            assert!(input_types.len() >= 3 && input_types.len() <= 5);
             assert_eq!(input_types.len(), input_names.len());
+            // The optional query parameter is always 4th, and the optional margin is always the last one
+            let has_query_params =
+                matches!(input_types.get(3).map(String::as_str), Some("json" | "jsonb"));
+            let has_margin = has_margin(&input_names, &input_types);
             match (&output_record_names, &output_record_types) {
                 (Some(n), Some(t)) if n.len() == 1 && n.len() == t.len() => {
                     assert_eq!(t, &["bytea"]);
@@ -99,14 +103,17 @@ pub async fn query_available_function(pool: &PgPool) -> PgResult<SqlFuncInfoMapM
                 .insert(
                     function.clone(),
                     (
-                        PgSqlInfo::new(
-                            query,
-                            input_types.len() == 4,
-                            format!(
-                                "{schema}.{function}({}) -> {ret_inf}",
-                                input_types.join(", ")
-                            ),
-                        ),
+                        PgSqlInfo {
+                            margin: has_margin.then_some(0.0),
+                            ..PgSqlInfo::new(
+                                query,
+                                has_query_params,
+                                format!(
+                                    "{schema}.{function}({}) -> {ret_inf}",
+                                    input_types.join(", ")
+                                ),
+                            )
+                        },
                         FunctionInfo::new(schema, function, tilejson)
                     ),
                 )
@@ -126,6 +133,18 @@ pub fn merge_func_info(cfg_inf: &FunctionInfo, db_inf: &FunctionInfo) -> Functio
     }
 }
 
+/// The last of the optional parameters is a number named `margin`
+fn has_margin(input_names: &[String], input_types: &[String]) -> bool {
+    input_names.len() > 3
+        && input_names
+            .last()
+            .map_or(false, |v| v.eq_ignore_ascii_case("margin"))
+        && matches!(
+            input_types.last().map(String::as_str),
+            Some("double precision" | "numeric")
+        )
+}
+
 fn jsonb_to_vec(jsonb: &Option<Value>) -> Option<Vec<String>> {
     jsonb.as_ref().map(|json| {
         json.as_array()
@@ -135,3 +154,28 @@ fn jsonb_to_vec(jsonb: &Option<Value>) -> Option<Vec<String>> {
             .collect()
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn margin_parameter() {
+        let names = strings(&["z", "x", "y", "Margin"]);
+        let margin = |typ| has_margin(&names, &strings(&["integer", "integer", "integer", typ]));
+        assert!(margin("double precision"));
+        assert!(margin("numeric"));
+        assert!(!margin("text"));
+        assert!(!margin("jsonb"));
+
+        let names = strings(&["z", "x", "y", "query", "margin"]);
+        let types = strings(&["integer", "integer", "integer", "json", "numeric"]);
+        assert!(has_margin(&names, &types));
+        assert!(!has_margin(&names[..4], &types[..4]));
+        assert!(!has_margin(&names[..3], &strings(&["integer"; 3])));
+    }
+}
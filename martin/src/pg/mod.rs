@@ -1,18 +1,29 @@
+mod advisor;
 mod config;
 mod config_function;
 mod config_table;
 mod configurator;
+mod discovery;
 mod errors;
 mod function_source;
 mod pg_source;
 mod pool;
 mod table_source;
+mod tile_functions;
 mod tls;
 mod utils;
 
-pub use config::{PgCfgPublish, PgCfgPublishFuncs, PgCfgPublishTables, PgConfig, PgSslCerts};
-pub use config_function::FunctionInfo;
+pub use advisor::{table_advice, TableAdvice, TableIssue, TileSample};
+pub use config::{
+    PgCfgPublish, PgCfgPublishFuncs, PgCfgPublishTables, PgConfig, PgSslCerts, PoolAutosizeConfig,
+};
+pub use config_function::{FunctionInfo, UnknownQueryParams, UrlQueryConfig};
 pub use config_table::TableInfo;
+pub use discovery::is_srv_conn_str;
 pub use errors::{PgError, PgResult};
 pub use function_source::query_available_function;
-pub use pool::{PgPool, POOL_SIZE_DEFAULT};
+pub use pool::{
+    PgPool, DNS_REFRESH_DEFAULT, POOL_AUTOSIZE_INTERVAL_DEFAULT, POOL_AUTOSIZE_MAX_DEFAULT,
+    POOL_AUTOSIZE_MIN_DEFAULT, POOL_SIZE_DEFAULT,
+};
+pub use tile_functions::{install_tile_functions, table_tile_functions, TileFunction};
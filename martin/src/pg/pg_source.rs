@@ -1,17 +1,29 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Instant;
 
 use async_trait::async_trait;
 use deadpool_postgres::tokio_postgres::types::{ToSql, Type};
+use deadpool_postgres::{GenericClient, Object};
 use log::debug;
 use martin_tile_utils::Encoding::Uncompressed;
 use martin_tile_utils::Format::Mvt;
 use martin_tile_utils::TileInfo;
 use tilejson::TileJSON;
+use tokio::sync::OnceCell;
 
+use crate::pg::config_function::UrlQueryConfig;
 use crate::pg::pool::PgPool;
 use crate::pg::utils::query_to_json;
-use crate::pg::PgError::{GetTileError, GetTileWithQueryError, PrepareQueryError};
-use crate::source::{Source, TileData, UrlQuery};
+use crate::pg::PgError::{
+    GetFeaturesError, GetTileError, GetTileWithQueryError, InvalidUrlQuery, PostgresError,
+    PrepareQueryError,
+};
+use crate::source::{
+    record_feature_count, record_timing, remaining_deadline, FeatureFilter, FieldStats, Source,
+    SourceFieldStats, TileData, UrlQuery,
+};
+use crate::utils::mvt_feature_counts;
 use crate::{MartinResult, TileCoord};
 
 #[derive(Clone, Debug)]
@@ -20,6 +32,9 @@ pub struct PgSource {
     info: PgSqlInfo,
     pool: PgPool,
     tilejson: TileJSON,
+    feature_count: bool,
+    /// Computed on the first request, as it may require scanning the whole table
+    field_stats: Arc<OnceCell<SourceFieldStats>>,
 }
 
 impl PgSource {
@@ -30,50 +45,101 @@ impl PgSource {
             info,
             pool,
             tilejson,
+            feature_count: false,
+            field_stats: Arc::new(OnceCell::new()),
         }
     }
-}
-
-#[async_trait]
-impl Source for PgSource {
-    fn get_id(&self) -> &str {
-        &self.id
-    }
 
-    fn get_tilejson(&self) -> &TileJSON {
-        &self.tilejson
+    /// Report the number of features in each tile response
+    #[must_use]
+    pub fn with_feature_count(mut self, feature_count: bool) -> Self {
+        self.feature_count = feature_count;
+        self
     }
 
-    fn get_tile_info(&self) -> TileInfo {
-        TileInfo::new(Mvt, Uncompressed)
+    async fn query_field_stats(
+        &self,
+        queries: &FieldStatsQueries,
+    ) -> MartinResult<SourceFieldStats> {
+        let conn = self.pool.get().await?;
+        let mut fields = BTreeMap::new();
+        for (field, sql) in &queries.fields {
+            debug!("SQL: {sql}");
+            let row = conn
+                .query_one(sql.as_str(), &[])
+                .await
+                .map_err(|e| PostgresError(e, "computing field statistics"))?;
+            let stats = FieldStats {
+                min: row.get(0),
+                max: row.get(1),
+                samples: row.get::<_, Option<Vec<String>>>(2).unwrap_or_default(),
+            };
+            fields.insert(field.clone(), stats);
+        }
+        Ok(BTreeMap::from([(queries.layer.clone(), fields)]))
     }
 
-    fn clone_source(&self) -> Box<dyn Source> {
-        Box::new(self.clone())
+    async fn query_features(
+        &self,
+        query: &FeaturesQuery,
+        filter: &FeatureFilter,
+    ) -> MartinResult<Vec<serde_json::Value>> {
+        let (sql, params) = query.to_sql(filter);
+        let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(|v| v.as_ref() as _).collect();
+        debug!("SQL: {sql} {params:?}");
+        let conn = self.pool.get().await?;
+        let rows = conn
+            .query(sql.as_str(), &params)
+            .await
+            .map_err(|e| GetFeaturesError(e, self.id.clone()))?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
     }
 
-    fn support_url_query(&self) -> bool {
-        self.info.use_url_query
+    /// Query the tile, limiting its run time to the deadline of the request, if any
+    async fn query_with_deadline(
+        &self,
+        conn: &mut Object,
+        xyz: &TileCoord,
+        url_query: &UrlQuery,
+    ) -> MartinResult<TileData> {
+        // Connections of a consistent snapshot are already inside a transaction
+        let timeout = remaining_deadline().filter(|_| !self.pool.uses_snapshot());
+        let Some(timeout) = timeout else {
+            return self.query_tile(&*conn, xyz, url_query).await;
+        };
+        let tx = conn
+            .transaction()
+            .await
+            .map_err(|e| PostgresError(e, "starting a tile query transaction"))?;
+        // A zero timeout would disable it, so always allow at least a millisecond
+        let ms = timeout.as_millis().max(1);
+        tx.batch_execute(&format!("SET LOCAL statement_timeout = {ms}"))
+            .await
+            .map_err(|e| PostgresError(e, "setting the statement timeout"))?;
+        let tile = self.query_tile(&tx, xyz, url_query).await?;
+        tx.commit()
+            .await
+            .map_err(|e| PostgresError(e, "finishing a tile query transaction"))?;
+        Ok(tile)
     }
 
-    async fn get_tile(
+    async fn query_tile(
         &self,
+        client: &impl GenericClient,
         xyz: &TileCoord,
-        url_query: &Option<UrlQuery>,
+        url_query: &UrlQuery,
     ) -> MartinResult<TileData> {
-        let empty_query = HashMap::new();
-        let url_query = url_query.as_ref().unwrap_or(&empty_query);
-        let conn = self.pool.get().await?;
-
-        let param_types: &[Type] = if self.support_url_query() {
-            &[Type::INT2, Type::INT8, Type::INT8, Type::JSON]
-        } else {
-            &[Type::INT2, Type::INT8, Type::INT8]
-        };
+        let mut param_types = vec![Type::INT2, Type::INT8, Type::INT8];
+        if self.support_url_query() {
+            param_types.push(Type::JSON);
+        }
+        if self.info.margin.is_some() {
+            param_types.push(Type::FLOAT8);
+        }
 
         let query = &self.info.query;
-        let prep_query = conn
-            .prepare_typed_cached(query, param_types)
+        let prep_query = client
+            .prepare_typed_cached(query, &param_types)
             .await
             .map_err(|e| {
                 PrepareQueryError(
@@ -84,27 +150,43 @@ impl Source for PgSource {
                 )
             })?;
 
-        let tile = if self.support_url_query() {
-            let json = query_to_json(url_query);
-            debug!("SQL: {query} [{xyz}, {json:?}]");
-            let params: &[&(dyn ToSql + Sync)] = &[
-                &i16::from(xyz.z),
-                &i64::from(xyz.x),
-                &i64::from(xyz.y),
-                &json,
-            ];
-            conn.query_opt(&prep_query, params).await
-        } else {
-            debug!("SQL: {query} [{xyz}]");
-            conn.query_opt(
-                &prep_query,
-                &[&i16::from(xyz.z), &i64::from(xyz.x), &i64::from(xyz.y)],
-            )
-            .await
-        };
+        let (z, x, y) = (i16::from(xyz.z), i64::from(xyz.x), i64::from(xyz.y));
+        let json = self.support_url_query().then(|| query_to_json(url_query));
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&z, &x, &y];
+        if let Some(json) = &json {
+            params.push(json);
+        }
+        if let Some(margin) = &self.info.margin {
+            params.push(margin);
+        }
+        match (&json, self.info.margin) {
+            (Some(json), Some(margin)) => debug!("SQL: {query} [{xyz}, {json:?}, {margin}]"),
+            (Some(json), None) => debug!("SQL: {query} [{xyz}, {json:?}]"),
+            (None, Some(margin)) => debug!("SQL: {query} [{xyz}, {margin}]"),
+            (None, None) => debug!("SQL: {query} [{xyz}]"),
+        }
+        let tile = client.query_opt(&prep_query, &params).await;
+
+        if let (Some(layer), Ok(Some(row))) = (&self.info.counted_layer, &tile) {
+            if self.feature_count {
+                let count: i64 = row.get(1);
+                record_feature_count(layer, usize::try_from(count).unwrap_or_default());
+            }
+        }
+        if self.info.counts_repaired {
+            if let Ok(Some(row)) = &tile {
+                let repaired: i64 = row.get(row.len() - 1);
+                if repaired > 0 {
+                    debug!(
+                        "Repaired {repaired} invalid geometries in tile {xyz:#} of {}",
+                        self.id
+                    );
+                }
+            }
+        }
 
         let tile = tile
-            .map(|row| row.and_then(|r| r.get::<_, Option<TileData>>(0)))
+            .map(|row| row.and_then(|r| r.get::<_, Option<Vec<u8>>>(0)))
             .map_err(|e| {
                 if self.support_url_query() {
                     GetTileWithQueryError(e, self.id.to_string(), *xyz, url_query.clone())
@@ -112,17 +194,194 @@ impl Source for PgSource {
                     GetTileError(e, self.id.to_string(), *xyz)
                 }
             })?
+            .map(TileData::from)
             .unwrap_or_default();
+        if self.feature_count && self.info.counted_layer.is_none() {
+            record_tile_feature_counts(&self.id, xyz, &tile);
+        }
 
         Ok(tile)
     }
 }
 
+/// Count the features of a tile returned by a function, as its query cannot count them
+fn record_tile_feature_counts(id: &str, xyz: &TileCoord, tile: &[u8]) {
+    match mvt_feature_counts(tile) {
+        Ok(layers) => {
+            for (layer, count) in layers {
+                record_feature_count(&layer, count);
+            }
+        }
+        Err(e) => debug!("Unable to count the features of tile {xyz:#} of {id}: {e}"),
+    }
+}
+
+#[async_trait]
+impl Source for PgSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        TileInfo::new(Mvt, Uncompressed)
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.info.use_url_query
+    }
+
+    fn include_feature_count(&self) -> bool {
+        self.feature_count
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: &TileCoord,
+        url_query: &Option<UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let empty_query = HashMap::new();
+        let url_query = url_query.as_ref().unwrap_or(&empty_query);
+        let normalized;
+        let url_query = match &self.info.url_query {
+            Some(cfg) if self.support_url_query() => {
+                normalized = cfg
+                    .normalize(url_query)
+                    .map_err(|params| InvalidUrlQuery(self.id.clone(), params.join(", ")))?;
+                &normalized
+            }
+            _ => url_query,
+        };
+        let started = Instant::now();
+        let conn = self.pool.get().await?;
+        record_timing("queue", started.elapsed());
+        let mut conn = self.pool.cancel_on_drop(conn);
+        let started = Instant::now();
+        let tile = self.query_with_deadline(&mut conn, xyz, url_query).await;
+        self.pool.record_query(started.elapsed());
+        conn.disarm();
+        tile
+    }
+
+    async fn get_field_stats(&self) -> MartinResult<Option<SourceFieldStats>> {
+        let Some(queries) = &self.info.field_stats else {
+            return Ok(None);
+        };
+        let stats = self
+            .field_stats
+            .get_or_try_init(|| self.query_field_stats(queries))
+            .await?;
+        Ok(Some(stats.clone()))
+    }
+
+    fn supports_features(&self) -> bool {
+        self.info.features.is_some()
+    }
+
+    async fn get_features(
+        &self,
+        filter: &FeatureFilter,
+    ) -> MartinResult<Option<Vec<serde_json::Value>>> {
+        match &self.info.features {
+            Some(query) => Ok(Some(self.query_features(query, filter).await?)),
+            None => Ok(None),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct PgSqlInfo {
     pub query: String,
     pub use_url_query: bool,
     pub signature: String,
+    /// Tile margin to pass to the function as the last parameter, if the function accepts one
+    pub margin: Option<f64>,
+    /// Allowed URL query parameters and their defaults, if configured
+    pub url_query: Option<UrlQueryConfig>,
+    /// Name of the layer whose number of features the query returns as the second column
+    pub counted_layer: Option<String>,
+    /// The query returns the number of repaired invalid geometries as the last column
+    pub counts_repaired: bool,
+    /// Queries computing the value statistics of the source fields, if supported
+    pub field_stats: Option<FieldStatsQueries>,
+    /// Query returning the individual features of the source, if supported
+    pub features: Option<FeaturesQuery>,
+}
+
+/// Queries computing the value statistics of each field of a vector layer. Each query returns
+/// the min and max values as text, and an array of distinct text values.
+#[derive(Clone, Debug, Default)]
+pub struct FieldStatsQueries {
+    pub layer: String,
+    /// Field name and its query
+    pub fields: Vec<(String, String)>,
+}
+
+/// Owned parameters of a [`FeaturesQuery`]
+pub type SqlParams = Vec<Box<dyn ToSql + Sync + Send>>;
+
+/// Parts of a query returning the features of a table as `GeoJSON` objects
+#[derive(Clone, Debug, Default)]
+pub struct FeaturesQuery {
+    /// Columns of the outer query, building a feature from each row `t` of the inner query
+    pub select: String,
+    /// Inner query selecting the geometry in WGS84 and the properties, without any `WHERE` clause
+    pub from: String,
+    /// Escaped geometry column, used for the `bbox` filter
+    pub geometry_column: String,
+    pub srid: i32,
+    /// Escaped date or timestamp column, used for the `datetime` filter
+    pub datetime_column: Option<String>,
+    /// Escaped column to sort by, so that pages do not overlap
+    pub order_by: Option<String>,
+}
+
+impl FeaturesQuery {
+    /// SQL query of the filter, and its parameters
+    #[must_use]
+    pub fn to_sql(&self, filter: &FeatureFilter) -> (String, SqlParams) {
+        let mut conditions = Vec::new();
+        let mut params: SqlParams = Vec::new();
+        if let Some(bbox) = filter.bbox {
+            params.extend([bbox.left, bbox.bottom, bbox.right, bbox.top].map(|v| Box::new(v) as _));
+            conditions.push(format!(
+                "{} && ST_Transform(ST_MakeEnvelope($1::float8, $2::float8, $3::float8, $4::float8, 4326), {})",
+                self.geometry_column, self.srid
+            ));
+        }
+        if let (Some(column), Some((start, end))) = (&self.datetime_column, &filter.datetime) {
+            for (value, op) in [(start, ">="), (end, "<=")] {
+                if let Some(value) = value {
+                    params.push(Box::new(value.clone()));
+                    conditions.push(format!(
+                        "{column} {op} ${}::text::timestamptz",
+                        params.len()
+                    ));
+                }
+            }
+        }
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!(" WHERE {}", conditions.join(" AND "))
+        };
+        let order_by = self
+            .order_by
+            .as_ref()
+            .map_or(String::new(), |v| format!(" ORDER BY {v}"));
+        let sql = format!(
+            "SELECT {} FROM ({}{where_clause}{order_by} LIMIT {} OFFSET {}) AS t",
+            self.select, self.from, filter.limit, filter.offset
+        );
+        (sql, params)
+    }
 }
 
 impl PgSqlInfo {
@@ -132,6 +391,34 @@ impl PgSqlInfo {
             query,
             use_url_query: has_query_params,
             signature,
+            margin: None,
+            url_query: None,
+            counted_layer: None,
+            counts_repaired: false,
+            field_stats: None,
+            features: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::with_feature_counts;
+
+    #[actix_rt::test]
+    async fn function_tile_feature_counts() {
+        // Layer "a" with two empty features, and layer "bc" with none
+        let tile = [
+            0x1A, 0x09, 0x0A, 0x01, b'a', 0x12, 0x00, 0x12, 0x00, 0x78, 0x02, //
+            0x1A, 0x06, 0x0A, 0x02, b'b', b'c', 0x78, 0x02,
+        ];
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let ((), counts) =
+            with_feature_counts(async { record_tile_feature_counts("fn", &xyz, &tile) }).await;
+        assert_eq!(counts, vec![("a".to_string(), 2), ("bc".to_string(), 0)]);
+        let ((), counts) =
+            with_feature_counts(async { record_tile_feature_counts("fn", &xyz, &tile[..5]) }).await;
+        assert!(counts.is_empty(), "an invalid tile is not counted");
+    }
+}
@@ -1,9 +1,18 @@
-use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, RecyclingMethod};
-use log::{info, warn};
+use std::fmt::{Debug, Formatter};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+
+use deadpool_postgres::tokio_postgres::NoTls;
+use deadpool_postgres::{Hook, HookError, Manager, ManagerConfig, Object, Pool, RecyclingMethod};
+use log::{debug, info, warn};
 use postgres::config::SslMode;
 use semver::Version;
+use tokio_postgres_rustls::MakeRustlsConnect;
 
-use crate::pg::config::PgConfig;
+use crate::pg::config::{PgConfig, PoolAutosizeConfig};
+use crate::pg::discovery::{is_srv_conn_str, resolve_addresses, resolve_srv};
 use crate::pg::tls::{make_connector, parse_conn_str, SslModeOverride};
 use crate::pg::PgError::{
     BadPostgisVersion, PostgisTooOld, PostgresError, PostgresPoolBuildError, PostgresPoolConnError,
@@ -11,6 +20,14 @@ use crate::pg::PgError::{
 use crate::pg::PgResult;
 
 pub const POOL_SIZE_DEFAULT: usize = 20;
+pub const POOL_AUTOSIZE_MIN_DEFAULT: usize = 2;
+pub const POOL_AUTOSIZE_MAX_DEFAULT: usize = 100;
+pub const POOL_AUTOSIZE_INTERVAL_DEFAULT: u64 = 30;
+/// How often the servers of a `postgres+srv://` connection string are resolved again, in seconds
+pub const DNS_REFRESH_DEFAULT: u64 = 60;
+
+/// Keep this many more connections than the measured concurrency, as a fraction of it
+const POOL_AUTOSIZE_HEADROOM: f64 = 0.5;
 
 // We require ST_TileEnvelope that was added in PostGIS 3.0.0
 // See https://postgis.net/docs/ST_TileEnvelope.html
@@ -21,17 +38,127 @@ const RECOMMENDED_POSTGIS_VER: Version = Version::new(3, 1, 0);
 #[derive(Clone, Debug)]
 pub struct PgPool {
     id: String,
-    pool: Pool,
+    // Replaced with a new pool when the addresses of the database hosts change
+    pool: Arc<RwLock<Pool>>,
     // When true, we can use margin parameter in ST_TileEnvelope
     margin: bool,
+    // Connection keeping the exported snapshot alive, if all queries must use the same snapshot
+    snapshot: Option<Arc<Object>>,
+    // Used to open a new connection when a running query must be cancelled
+    connector: Connector,
+    // Total time spent running queries since the last pool size adjustment, in microseconds
+    busy_micros: Option<Arc<AtomicU64>>,
+}
+
+#[derive(Clone)]
+enum Connector {
+    NoTls,
+    Rustls(MakeRustlsConnect),
+}
+
+impl Debug for Connector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoTls => write!(f, "NoTls"),
+            Self::Rustls(_) => write!(f, "Rustls"),
+        }
+    }
+}
+
+/// Owns a connection, and cancels the query running on it if dropped before [`CancelGuard::disarm`] is called,
+/// e.g. when the client disconnects and the tile request future is dropped.
+/// An abandoned connection is detached from the pool, so that the cancel request
+/// cannot arrive after another tile request started using the connection.
+#[must_use]
+pub struct CancelGuard {
+    conn: Option<Object>,
+    connector: Connector,
+}
+
+impl CancelGuard {
+    /// The query has finished, so the connection goes back to the pool
+    pub fn disarm(mut self) {
+        self.conn = None;
+    }
+}
+
+impl Deref for CancelGuard {
+    type Target = Object;
+
+    fn deref(&self) -> &Object {
+        self.conn
+            .as_ref()
+            .expect("connection is only taken when dropped")
+    }
+}
+
+impl DerefMut for CancelGuard {
+    fn deref_mut(&mut self) -> &mut Object {
+        self.conn
+            .as_mut()
+            .expect("connection is only taken when dropped")
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        let Some(conn) = self.conn.take() else {
+            return;
+        };
+        let token = conn.cancel_token();
+        let client = Object::take(conn);
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let connector = self.connector.clone();
+        runtime.spawn(async move {
+            let result = match connector {
+                Connector::NoTls => token.cancel_query(NoTls).await,
+                Connector::Rustls(tls) => token.cancel_query(tls).await,
+            };
+            match result {
+                Ok(()) => debug!("Cancelled the query of an abandoned tile request"),
+                Err(e) => warn!("Unable to cancel the query of an abandoned tile request: {e}"),
+            }
+            // Closing the connection only once the query is cancelled
+            drop(client);
+        });
+    }
 }
 
 impl PgPool {
     pub async fn new(config: &PgConfig) -> PgResult<Self> {
-        let (id, mgr) = Self::parse_config(config)?;
+        let conn_str = resolve_srv(config.connection_string.as_ref().unwrap()).await?;
+        let (id, mgr, connector) = Self::parse_config(config, &conn_str)?;
 
-        let pool = Pool::builder(mgr)
-            .max_size(config.pool_size.unwrap_or(POOL_SIZE_DEFAULT))
+        let autosize = config.pool_autosize.as_ref().map(AutosizeLimits::new);
+        let pool_size = match &autosize {
+            // Each worker may run one query at a time per connection, so start from the worker count
+            Some(limits) => limits.clamp(config.pool_size.unwrap_or(2 * num_cpus::get())),
+            None => config.pool_size.unwrap_or(POOL_SIZE_DEFAULT),
+        };
+        let mut builder = Pool::builder(mgr).max_size(pool_size);
+        let snapshot = if config.consistent_snapshot {
+            let (snapshot_id, conn) = Self::export_snapshot(config, &conn_str, &id).await?;
+            info!("Using snapshot {snapshot_id} for all queries to {id}");
+            builder = builder
+                .post_create(Hook::async_fn(move |client, _| {
+                    let sql = format!("BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY; SET TRANSACTION SNAPSHOT '{snapshot_id}'");
+                    Box::pin(async move {
+                        client.batch_execute(&sql).await.map_err(HookError::Backend)
+                    })
+                }))
+                // A failed query aborts the transaction, so the connection must be replaced
+                .pre_recycle(Hook::async_fn(|client, _| {
+                    Box::pin(async move {
+                        client.batch_execute("SELECT 1").await.map_err(HookError::Backend)
+                    })
+                }));
+            Some(Arc::new(conn))
+        } else {
+            None
+        };
+        let pool = builder
             .build()
             .map_err(|e| PostgresPoolBuildError(e, id.clone()))?;
 
@@ -61,26 +188,106 @@ SELECT
         }
 
         let margin = version >= RECOMMENDED_POSTGIS_VER;
-        Ok(Self { id, pool, margin })
+        let pool = Arc::new(RwLock::new(pool));
+        if let Some(interval) = Self::dns_refresh(config) {
+            if snapshot.is_some() {
+                warn!("The hosts of {id} are not resolved again, because all queries must use the same snapshot");
+            } else {
+                let (pg_cfg, _) = parse_conn_str(&conn_str)?;
+                let addresses = resolve_addresses(&pg_cfg).await?;
+                info!(
+                    "Resolving the hosts of {id} every {}s, currently {}",
+                    interval.as_secs(),
+                    addresses.join(", ")
+                );
+                tokio::spawn(refresh_hosts(
+                    pool.clone(),
+                    config.clone(),
+                    id.clone(),
+                    interval,
+                    addresses,
+                ));
+            }
+        }
+        let busy_micros = autosize.map(|limits| {
+            let busy_micros = Arc::new(AtomicU64::new(0));
+            info!(
+                "Autosizing the connection pool of {id} between {} and {} connections, starting with {pool_size}",
+                limits.min_size, limits.max_size
+            );
+            tokio::spawn(autosize_pool(
+                pool.clone(),
+                id.clone(),
+                busy_micros.clone(),
+                limits,
+            ));
+            busy_micros
+        });
+        Ok(Self {
+            id,
+            pool,
+            margin,
+            snapshot,
+            connector,
+            busy_micros,
+        })
     }
 
-    fn parse_config(config: &PgConfig) -> PgResult<(String, Manager)> {
-        let conn_str = config.connection_string.as_ref().unwrap().as_str();
+    /// Start a repeatable read transaction on a dedicated connection, and export its snapshot
+    /// so that other connections can see the same data. The snapshot is only valid
+    /// while the returned connection is kept open.
+    async fn export_snapshot(
+        config: &PgConfig,
+        conn_str: &str,
+        id: &str,
+    ) -> PgResult<(String, Object)> {
+        let (_, mgr, _) = Self::parse_config(config, conn_str)?;
+        let pool = Pool::builder(mgr)
+            .max_size(1)
+            .build()
+            .map_err(|e| PostgresPoolBuildError(e, id.to_string()))?;
+        let conn = get_conn(&pool, id).await?;
+        conn.batch_execute("BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY")
+            .await
+            .map_err(|e| PostgresError(e, "starting a snapshot transaction"))?;
+        let snapshot_id: String = conn
+            .query_one("SELECT pg_export_snapshot()", &[])
+            .await
+            .map(|row| row.get(0))
+            .map_err(|e| PostgresError(e, "exporting a snapshot"))?;
+        Ok((snapshot_id, conn))
+    }
+
+    /// How often to resolve the hosts again, if at all
+    fn dns_refresh(config: &PgConfig) -> Option<Duration> {
+        let default = match &config.connection_string {
+            Some(v) if is_srv_conn_str(v) => DNS_REFRESH_DEFAULT,
+            _ => 0,
+        };
+        Some(config.dns_refresh.unwrap_or(default))
+            .filter(|v| *v > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// Parse the connection string, with the SRV record already resolved
+    fn parse_config(config: &PgConfig, conn_str: &str) -> PgResult<(String, Manager, Connector)> {
         let (pg_cfg, ssl_mode) = parse_conn_str(conn_str)?;
 
-        let id = pg_cfg.get_dbname().map_or_else(
-            || format!("{:?}", pg_cfg.get_hosts()[0]),
-            ToString::to_string,
-        );
+        let id = config.id.clone().unwrap_or_else(|| {
+            pg_cfg.get_dbname().map_or_else(
+                || format!("{:?}", pg_cfg.get_hosts()[0]),
+                ToString::to_string,
+            )
+        });
 
         let mgr_config = ManagerConfig {
             recycling_method: RecyclingMethod::Fast,
         };
 
-        let mgr = if pg_cfg.get_ssl_mode() == SslMode::Disable {
+        let (mgr, connector) = if pg_cfg.get_ssl_mode() == SslMode::Disable {
             info!("Connecting without SSL support: {pg_cfg:?}");
-            let connector = deadpool_postgres::tokio_postgres::NoTls {};
-            Manager::from_config(pg_cfg, connector, mgr_config)
+            let mgr = Manager::from_config(pg_cfg, NoTls {}, mgr_config);
+            (mgr, Connector::NoTls)
         } else {
             match ssl_mode {
                 SslModeOverride::Unmodified(_) => {
@@ -94,14 +301,31 @@ SELECT
                 }
             };
             let connector = make_connector(&config.ssl_certificates, ssl_mode)?;
-            Manager::from_config(pg_cfg, connector, mgr_config)
+            let mgr = Manager::from_config(pg_cfg, connector.clone(), mgr_config);
+            (mgr, Connector::Rustls(connector))
         };
 
-        Ok((id, mgr))
+        Ok((id, mgr, connector))
     }
 
     pub async fn get(&self) -> PgResult<Object> {
-        get_conn(&self.pool, self.id.as_str()).await
+        get_conn(&current_pool(&self.pool), self.id.as_str()).await
+    }
+
+    /// Record how long a connection was used by a query, to measure the concurrency if the pool is autosized
+    pub fn record_query(&self, duration: Duration) {
+        if let Some(busy_micros) = &self.busy_micros {
+            let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+            busy_micros.fetch_add(micros, Ordering::Relaxed);
+        }
+    }
+
+    /// Cancel the query running on the connection unless the returned guard is disarmed
+    pub fn cancel_on_drop(&self, conn: Object) -> CancelGuard {
+        CancelGuard {
+            conn: Some(conn),
+            connector: self.connector.clone(),
+        }
     }
 
     #[must_use]
@@ -113,6 +337,140 @@ SELECT
     pub fn supports_tile_margin(&self) -> bool {
         self.margin
     }
+
+    /// True if all queries see the same database snapshot
+    #[must_use]
+    pub fn uses_snapshot(&self) -> bool {
+        self.snapshot.is_some()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct AutosizeLimits {
+    min_size: usize,
+    max_size: usize,
+    interval: Duration,
+}
+
+impl AutosizeLimits {
+    fn new(config: &PoolAutosizeConfig) -> Self {
+        let min_size = config.min_size.unwrap_or(POOL_AUTOSIZE_MIN_DEFAULT).max(1);
+        Self {
+            min_size,
+            max_size: config
+                .max_size
+                .unwrap_or(POOL_AUTOSIZE_MAX_DEFAULT)
+                .max(min_size),
+            interval: Duration::from_secs(
+                config
+                    .interval
+                    .unwrap_or(POOL_AUTOSIZE_INTERVAL_DEFAULT)
+                    .max(1),
+            ),
+        }
+    }
+
+    fn clamp(&self, size: usize) -> usize {
+        size.clamp(self.min_size, self.max_size)
+    }
+
+    /// Compute the next pool size from the total query time during the last interval.
+    /// The average number of concurrent queries is the busy time divided by the interval (Little's law).
+    /// The size moves towards that number plus some headroom, but by at most a quarter
+    /// of the current size per step, so that short bursts do not cause large swings.
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    fn next_size(&self, current: usize, busy: Duration) -> usize {
+        let concurrency = busy.as_secs_f64() / self.interval.as_secs_f64();
+        let target = self.clamp((concurrency * (1.0 + POOL_AUTOSIZE_HEADROOM)).ceil() as usize);
+        let step = (current / 4).max(1);
+        if target > current {
+            current + step.min(target - current)
+        } else {
+            current - step.min(current - target)
+        }
+    }
+}
+
+async fn autosize_pool(
+    pool: Arc<RwLock<Pool>>,
+    id: String,
+    busy_micros: Arc<AtomicU64>,
+    limits: AutosizeLimits,
+) {
+    let mut interval = tokio::time::interval(limits.interval);
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let busy = Duration::from_micros(busy_micros.swap(0, Ordering::Relaxed));
+        let pool = current_pool(&pool);
+        let current = pool.status().max_size;
+        let size = limits.next_size(current, busy);
+        if size != current {
+            info!("Resizing the connection pool of {id} from {current} to {size} connections");
+            pool.resize(size);
+        }
+    }
+}
+
+/// Periodically resolve the database hosts, and replace the pool with a new one if their addresses changed.
+/// Connections of the old pool that are still in use are closed once their queries finish.
+async fn refresh_hosts(
+    pool: Arc<RwLock<Pool>>,
+    config: PgConfig,
+    id: String,
+    interval: Duration,
+    mut addresses: Vec<String>,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let conn_str = match resolve_srv(config.connection_string.as_ref().unwrap()).await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Unable to resolve the hosts of {id}, keeping the current connections: {e}");
+                continue;
+            }
+        };
+        let resolved = match parse_conn_str(&conn_str) {
+            Ok((pg_cfg, _)) => resolve_addresses(&pg_cfg).await,
+            Err(e) => Err(e),
+        };
+        let new_addresses = match resolved {
+            Ok(v) if v == addresses => continue,
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Unable to resolve the hosts of {id}, keeping the current connections: {e}");
+                continue;
+            }
+        };
+        info!(
+            "The addresses of {id} changed from {} to {}, reconnecting",
+            addresses.join(", "),
+            new_addresses.join(", ")
+        );
+        let size = current_pool(&pool).status().max_size;
+        let new_pool = PgPool::parse_config(&config, &conn_str).and_then(|(_, mgr, _)| {
+            Pool::builder(mgr)
+                .max_size(size)
+                .build()
+                .map_err(|e| PostgresPoolBuildError(e, id.clone()))
+        });
+        match new_pool {
+            Ok(new_pool) => {
+                *pool.write().unwrap_or_else(PoisonError::into_inner) = new_pool;
+                addresses = new_addresses;
+            }
+            Err(e) => warn!("Unable to reconnect to {id}, keeping the current connections: {e}"),
+        }
+    }
+}
+
+fn current_pool(pool: &RwLock<Pool>) -> Pool {
+    pool.read().unwrap_or_else(PoisonError::into_inner).clone()
 }
 
 async fn get_conn(pool: &Pool, id: &str) -> PgResult<Object> {
@@ -120,3 +478,38 @@ async fn get_conn(pool: &Pool, id: &str) -> PgResult<Object> {
         .await
         .map_err(|e| PostgresPoolConnError(e, id.to_string()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn autosize_next_size() {
+        let limits = AutosizeLimits::new(&PoolAutosizeConfig {
+            min_size: Some(2),
+            max_size: Some(40),
+            interval: Some(10),
+        });
+        let busy = |secs: u64| Duration::from_secs(secs);
+
+        // 8 concurrent queries on average need 12 connections, reached in steps of a quarter
+        assert_eq!(limits.next_size(4, busy(80)), 5);
+        assert_eq!(limits.next_size(10, busy(80)), 12);
+        assert_eq!(limits.next_size(12, busy(80)), 12);
+        assert_eq!(limits.next_size(20, busy(80)), 15);
+        // Idle pools shrink to the minimum, saturated pools grow to the maximum
+        assert_eq!(limits.next_size(3, busy(0)), 2);
+        assert_eq!(limits.next_size(2, busy(0)), 2);
+        assert_eq!(limits.next_size(39, busy(1000)), 40);
+
+        assert_eq!(limits.clamp(1), 2);
+        assert_eq!(limits.clamp(50), 40);
+        let limits = AutosizeLimits::new(&PoolAutosizeConfig {
+            min_size: Some(0),
+            max_size: Some(0),
+            interval: None,
+        });
+        assert_eq!((limits.min_size, limits.max_size), (1, 1));
+        assert_eq!(limits.interval, Duration::from_secs(30));
+    }
+}
@@ -1,7 +1,8 @@
 -- Find SQL functions that match these criteria:
---     * The function must have 3 or 4 input parameters,
+--     * The function must have 3 to 5 input parameters,
 --       first 3 must be integers and named z (or zoom), x, y (in that order),
---       with the optional JSON parameter as the 4th parameter (any name).
+--       with the optional JSON parameter as the 4th parameter (any name),
+--       and the optional double precision or numeric parameter named margin as the last one.
 --     * The function output must be either a single bytea value or a table,
 --       with the table row being either [bytea] or [bytea, text] (in that order).
 --     * If the output is a two-column row, the second column will be used as etag (usually the MD5 hash)
@@ -55,15 +56,20 @@ FROM information_schema.routines
          JOIN inputs ON routines.specific_name = inputs.specific_name
          LEFT JOIN outputs ON routines.specific_name = outputs.specific_name
          LEFT JOIN comments ON comments.schema = routines.specific_schema AND comments.name = routines.routine_name
-WHERE jsonb_array_length(input_names) IN (3, 4) -- 3 or 4 input parameters
+WHERE jsonb_array_length(input_names) IN (3, 4, 5) -- 3 to 5 input parameters
   AND lower(input_names ->> 0) IN ('z', 'zoom') -- the first int param is either z or zoom
   AND input_types ->> 0 = 'integer'
   AND lower(input_names ->> 1) = 'x'            -- the second int param is x
   AND input_types ->> 1 = 'integer'
   AND lower(input_names ->> 2) = 'y'            -- the third param is y
   AND input_types ->> 2 = 'integer'
-  -- the 4th optional parameter can be any name, and must be either json or jsonb
-  AND (input_types ->> 3 = 'json' OR input_types ->> 3 = 'jsonb' OR (input_types ->> 3) IS NULL)
+  -- the 4th optional parameter can be any name, and must be either json or jsonb,
+  -- unless it is the last parameter named margin
+  AND (input_types ->> 3 = 'json' OR input_types ->> 3 = 'jsonb' OR (input_types ->> 3) IS NULL
+    OR (jsonb_array_length(input_names) = 4 AND lower(input_names ->> 3) = 'margin' AND input_types ->> 3 IN ('double precision', 'numeric')))
+  -- the 5th optional parameter must be named margin, and follow the json parameter
+  AND ((input_types ->> 4) IS NULL
+    OR (lower(input_names ->> 4) = 'margin' AND input_types ->> 4 IN ('double precision', 'numeric')))
   -- the output must be either a single bytea value or a table, with the table row being either [bytea] or [bytea, text]
   AND (
         (data_type = 'bytea' AND out_params IS NULL)
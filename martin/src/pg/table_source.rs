@@ -10,9 +10,9 @@ use tokio::time::timeout;
 
 use crate::args::{BoundsCalcType, DEFAULT_BOUNDS_TIMEOUT};
 use crate::pg::config::PgInfo;
-use crate::pg::config_table::TableInfo;
+use crate::pg::config_table::{JsonPropertyMode, PropertyCastType, TableInfo};
 use crate::pg::configurator::SqlTableInfoMapMapMap;
-use crate::pg::pg_source::PgSqlInfo;
+use crate::pg::pg_source::{FeaturesQuery, FieldStatsQueries, PgSqlInfo};
 use crate::pg::pool::PgPool;
 use crate::pg::utils::{json_to_hashmap, normalize_key, polygon_to_bbox};
 use crate::pg::PgError::PostgresError;
@@ -21,6 +21,8 @@ use crate::pg::PgResult;
 static DEFAULT_EXTENT: u32 = 4096;
 static DEFAULT_BUFFER: u32 = 64;
 static DEFAULT_CLIP_GEOM: bool = true;
+/// Number of distinct values to report for each field in the source schema
+const FIELD_STATS_SAMPLES: usize = 10;
 
 pub async fn query_available_tables(pool: &PgPool) -> PgResult<SqlTableInfoMapMapMap> {
     let conn = pool.get().await?;
@@ -82,6 +84,24 @@ pub async fn query_available_tables(pool: &PgPool) -> PgResult<SqlTableInfoMapMa
     Ok(res)
 }
 
+/// Select the property, converting it as configured in `json_properties` or `property_casts`
+fn property_to_sql(info: &TableInfo, props: &BTreeMap<String, String>, field: &str) -> String {
+    let column = info.prop_mapping.get(field).map_or(field, String::as_str);
+    let column = escape_identifier(column);
+    let json = info.json_properties.as_ref().and_then(|v| v.get(field));
+    let cast = info.property_casts.as_ref().and_then(|v| v.get(field));
+    let sql = match (json.filter(|_| is_json_type(props.get(field))), cast) {
+        (Some(json), _) => json.to_sql(&column),
+        (None, Some(cast)) => cast.to_sql(&column, props.get(field).map(String::as_str)),
+        (None, None) => return escape_with_alias(&info.prop_mapping, field),
+    };
+    format!(", {sql} AS {}", escape_identifier(field))
+}
+
+fn is_json_type(typ: Option<&String>) -> bool {
+    matches!(typ.map(String::as_str), Some("json" | "jsonb" | "hstore"))
+}
+
 fn escape_with_alias(mapping: &HashMap<String, String>, field: &str) -> String {
     let column = mapping.get(field).map_or(field, |v| v.as_str());
     if field == column {
@@ -129,30 +149,60 @@ pub async fn table_to_query(
         }
     }
 
+    let query = tile_query(&id, &info, pool.supports_tile_margin(), max_feature_count);
+    let columns = tile_columns(&info);
+    let make_valid = info.make_valid.unwrap_or_default();
+
+    let sql_info = PgSqlInfo {
+        counted_layer: Some(info.layer_id.clone().unwrap_or_else(|| id.clone())),
+        counts_repaired: make_valid,
+        field_stats: Some(field_stats_queries(&info, &id, &schema, &table)),
+        features: Some(features_query(&info, &schema, &table, &columns)),
+        ..PgSqlInfo::new(query, false, info.format_id())
+    };
+    Ok((id, sql_info, info))
+}
+
+/// The id and property columns of the tiles, each prefixed with a comma
+fn tile_columns(info: &TableInfo) -> String {
     let properties = if let Some(props) = &info.properties {
         props
             .keys()
-            .map(|column| escape_with_alias(&info.prop_mapping, column))
+            .map(|field| property_to_sql(info, props, field))
             .collect::<String>()
     } else {
         String::new()
     };
+    let id_field = info.id_column.as_ref().map_or(String::new(), |id_column| {
+        escape_with_alias(&info.prop_mapping, id_column)
+    });
+    format!("{id_field}{properties}")
+}
 
-    let (id_name, id_field) = if let Some(id_column) = &info.id_column {
-        (
-            format!(", {}", escape_literal(id_column)),
-            escape_with_alias(&info.prop_mapping, id_column),
-        )
-    } else {
-        (String::new(), String::new())
-    };
+/// Query generating the tile of the table, with the `z`, `x`, and `y` values as the `$1`, `$2`, and `$3` parameters.
+/// The second column is the number of features in the tile. The tile margin requires PostGIS v3.1+.
+#[must_use]
+pub fn tile_query(
+    id: &str,
+    info: &TableInfo,
+    tile_margin: bool,
+    max_feature_count: Option<usize>,
+) -> String {
+    let schema = escape_identifier(&info.schema);
+    let table = escape_identifier(&info.table);
+    let geometry_column = escape_identifier(&info.geometry_column);
+    let srid = info.srid;
+    let id_name = info
+        .id_column
+        .as_ref()
+        .map_or(String::new(), |v| format!(", {}", escape_literal(v)));
 
     let extent = info.extent.unwrap_or(DEFAULT_EXTENT);
     let buffer = info.buffer.unwrap_or(DEFAULT_BUFFER);
 
     let bbox_search = if buffer == 0 {
         "ST_TileEnvelope($1::integer, $2::integer, $3::integer)".to_string()
-    } else if pool.supports_tile_margin() {
+    } else if tile_margin {
         let margin = f64::from(buffer) / f64::from(extent);
         format!("ST_TileEnvelope($1::integer, $2::integer, $3::integer, margin => {margin})")
     } else {
@@ -166,12 +216,27 @@ pub async fn table_to_query(
     };
 
     let limit_clause = max_feature_count.map_or(String::new(), |v| format!("LIMIT {v}"));
-    let layer_id = escape_literal(info.layer_id.as_ref().unwrap_or(&id));
+    let layer_id = escape_literal(info.layer_id.as_deref().unwrap_or(id));
     let clip_geom = info.clip_geom.unwrap_or(DEFAULT_CLIP_GEOM);
-    let query = format!(
-        r#"
+    let make_valid = info.make_valid.unwrap_or_default();
+    let columns = tile_columns(info);
+    if make_valid {
+        make_valid_query(
+            info,
+            &columns,
+            &format!(
+                "{schema}.{table} WHERE {geometry_column} && ST_Transform({bbox_search}, {srid}) {limit_clause}"
+            ),
+            &geometry_column,
+            &format!("ST_AsMVT(tile, {layer_id}, {extent}, 'geom'{id_name}), count(geom)"),
+            &format!("{extent}, {buffer}, {clip_geom}"),
+        )
+    } else {
+        format!(
+            r"
 SELECT
-  ST_AsMVT(tile, {layer_id}, {extent}, 'geom'{id_name})
+  ST_AsMVT(tile, {layer_id}, {extent}, 'geom'{id_name}),
+  count(geom)
 FROM (
   SELECT
     ST_AsMVTGeom(
@@ -179,19 +244,156 @@ FROM (
         ST_TileEnvelope($1::integer, $2::integer, $3::integer),
         {extent}, {buffer}, {clip_geom}
     ) AS geom
-    {id_field}{properties}
+    {columns}
   FROM
     {schema}.{table}
   WHERE
     {geometry_column} && ST_Transform({bbox_search}, {srid})
   {limit_clause}
 ) AS tile;
-"#
+"
+        )
+        .trim()
+        .to_string()
+    }
+}
+
+/// Statement creating a GIST index on the geometry column, unless an index with the same name already exists
+#[must_use]
+pub fn spatial_index_sql(info: &TableInfo) -> String {
+    let index = format!("{}_{}_idx", info.table, info.geometry_column);
+    format!(
+        "CREATE INDEX IF NOT EXISTS {} ON {}.{} USING GIST ({})",
+        escape_identifier(&index),
+        escape_identifier(&info.schema),
+        escape_identifier(&info.table),
+        escape_identifier(&info.geometry_column)
     )
-    .trim()
-    .to_string();
+}
 
-    Ok((id, PgSqlInfo::new(query, false, info.format_id()), info))
+/// Query returning the table rows as `GeoJSON` features, with the same properties as the tiles
+fn features_query(info: &TableInfo, schema: &str, table: &str, columns: &str) -> FeaturesQuery {
+    let geometry_column = escape_identifier(&info.geometry_column);
+    let column = |v: &String| escape_identifier(info.prop_mapping.get(v).unwrap_or(v));
+    let id = info.id_column.as_ref().map_or(String::new(), |v| {
+        format!("'id', t.{}, ", escape_identifier(v))
+    });
+    FeaturesQuery {
+        select: format!(
+            "json_build_object('type', 'Feature', {id}'geometry', ST_AsGeoJSON(t.geom)::json, 'properties', to_jsonb(t) - 'geom')"
+        ),
+        from: format!(
+            "SELECT ST_Transform(ST_CurveToLine({geometry_column}), 4326) AS geom{columns} FROM {schema}.{table}"
+        ),
+        geometry_column,
+        srid: info.srid,
+        datetime_column: info.datetime_column.as_ref().map(column),
+        order_by: info.id_column.as_ref().map(column),
+    }
+}
+
+/// Queries returning the min and max values, and a few distinct values of each table property
+fn field_stats_queries(info: &TableInfo, id: &str, schema: &str, table: &str) -> FieldStatsQueries {
+    let from = format!("{schema}.{table}");
+    let mut fields = Vec::new();
+    for (field, typ) in info.properties.iter().flatten() {
+        // These properties are reshaped, so the column values would not match the tile values
+        let is_flattened = info
+            .json_properties
+            .as_ref()
+            .and_then(|v| v.get(field))
+            .map_or(false, |v| {
+                v.mode.unwrap_or_default() == JsonPropertyMode::Flatten
+            });
+        if is_flattened && is_json_type(Some(typ)) {
+            continue;
+        }
+        let column = info.prop_mapping.get(field).unwrap_or(field);
+        let column = escape_identifier(column);
+        let min_max = if is_ordered_type(typ) {
+            format!("min({column})::text, max({column})::text")
+        } else {
+            "NULL::text, NULL::text".to_string()
+        };
+        let sql = format!(
+            "SELECT {min_max}, ARRAY(SELECT DISTINCT {column}::text FROM {from} WHERE {column} IS NOT NULL LIMIT {FIELD_STATS_SAMPLES}) FROM {from}"
+        );
+        fields.push((field.clone(), sql));
+    }
+    FieldStatsQueries {
+        layer: info.layer_id.as_deref().unwrap_or(id).to_string(),
+        fields,
+    }
+}
+
+/// Types for which min and max values are meaningful
+fn is_ordered_type(typ: &str) -> bool {
+    matches!(
+        typ,
+        "int2"
+            | "int4"
+            | "int8"
+            | "float4"
+            | "float8"
+            | "numeric"
+            | "text"
+            | "varchar"
+            | "bpchar"
+            | "date"
+            | "time"
+            | "timestamp"
+            | "timestamptz"
+    )
+}
+
+/// Same as the regular table query, but repairs invalid geometries before encoding them,
+/// and also returns the number of repaired geometries as the last column.
+fn make_valid_query(
+    info: &TableInfo,
+    properties: &str,
+    from: &str,
+    geometry_column: &str,
+    as_mvt: &str,
+    mvt_geom_params: &str,
+) -> String {
+    let mut columns = String::new();
+    for field in info
+        .id_column
+        .iter()
+        .chain(info.properties.iter().flat_map(BTreeMap::keys))
+    {
+        columns.push_str(", ");
+        columns.push_str(&escape_identifier(field));
+    }
+    format!(
+        r"
+WITH features AS (
+  SELECT
+    NOT ST_IsValid({geometry_column}) AS martin_invalid,
+    ST_MakeValid({geometry_column}) AS martin_geom
+    {properties}
+  FROM {from}
+)
+SELECT
+  mvt.*,
+  (SELECT count(*) FROM features WHERE martin_invalid)
+FROM (
+  SELECT {as_mvt}
+  FROM (
+    SELECT
+      ST_AsMVTGeom(
+          ST_Transform(ST_CurveToLine(martin_geom), 3857),
+          ST_TileEnvelope($1::integer, $2::integer, $3::integer),
+          {mvt_geom_params}
+      ) AS geom
+      {columns}
+    FROM features
+  ) AS tile
+) AS mvt;
+"
+    )
+    .trim()
+    .to_string()
 }
 
 async fn calc_bounds(
@@ -261,6 +463,11 @@ pub fn merge_table_info(
         inf.prop_mapping.insert(id_column.clone(), prop);
     }
 
+    if let Some(datetime_column) = &cfg_inf.datetime_column {
+        let prop = normalize_key(props, datetime_column.as_str(), "datetime_column", new_id)?;
+        inf.prop_mapping.insert(datetime_column.clone(), prop);
+    }
+
     if let Some(p) = &cfg_inf.properties {
         for key in p.keys() {
             let prop = normalize_key(props, key.as_str(), "property", new_id)?;
@@ -268,6 +475,34 @@ pub fn merge_table_info(
         }
     }
 
+    if let Some(json_props) = &cfg_inf.json_properties {
+        let published = inf.properties.as_ref().unwrap_or(&empty);
+        for key in json_props.keys() {
+            let column = inf.prop_mapping.get(key).unwrap_or(key);
+            match props.get(column) {
+                _ if !published.contains_key(key) => warn!(
+                    "Source {new_id} does not publish property {key}, ignoring its json_properties settings"
+                ),
+                typ if is_json_type(typ) => {}
+                typ => warn!(
+                    "Property {key} of source {new_id} has type {}, ignoring its json_properties settings",
+                    typ.map_or("unknown", String::as_str)
+                ),
+            }
+        }
+    }
+
+    if let Some(casts) = &cfg_inf.property_casts {
+        let published = inf.properties.as_ref().unwrap_or(&empty);
+        for (key, cast) in casts {
+            if !published.contains_key(key) {
+                warn!("Source {new_id} does not publish property {key}, ignoring its property_casts settings");
+            } else if cast.precision.is_some() && cast.cast != PropertyCastType::Float {
+                warn!("Property {key} of source {new_id} is not cast to float, ignoring its precision");
+            }
+        }
+    }
+
     Some(inf)
 }
 
@@ -298,3 +533,76 @@ pub fn calc_srid(
         (_, cfg, _) => Some(cfg),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pg::config_table::JsonProperty;
+    use crate::source::FeatureFilter;
+
+    #[test]
+    fn field_stats_sql() {
+        let info = TableInfo {
+            layer_id: Some("places".to_string()),
+            properties: Some(BTreeMap::from([
+                ("name".to_string(), "text".to_string()),
+                ("geog".to_string(), "geography".to_string()),
+                ("tags".to_string(), "jsonb".to_string()),
+            ])),
+            prop_mapping: HashMap::from([("name".to_string(), "Name".to_string())]),
+            json_properties: Some(BTreeMap::from([(
+                "tags".to_string(),
+                JsonProperty::default(),
+            )])),
+            ..TableInfo::default()
+        };
+        let queries = field_stats_queries(&info, "points", r#""public""#, r#""points""#);
+        assert_eq!(queries.layer, "places");
+        assert_eq!(
+            queries.fields,
+            vec![
+                (
+                    "geog".to_string(),
+                    r#"SELECT NULL::text, NULL::text, ARRAY(SELECT DISTINCT "geog"::text FROM "public"."points" WHERE "geog" IS NOT NULL LIMIT 10) FROM "public"."points""#.to_string()
+                ),
+                (
+                    "name".to_string(),
+                    r#"SELECT min("Name")::text, max("Name")::text, ARRAY(SELECT DISTINCT "Name"::text FROM "public"."points" WHERE "Name" IS NOT NULL LIMIT 10) FROM "public"."points""#.to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn features_sql() {
+        let info = TableInfo {
+            geometry_column: "geom".to_string(),
+            srid: 3857,
+            id_column: Some("gid".to_string()),
+            datetime_column: Some("updated".to_string()),
+            prop_mapping: HashMap::from([("updated".to_string(), "Updated".to_string())]),
+            ..TableInfo::default()
+        };
+        let query = features_query(&info, r#""public""#, r#""points""#, r#", "gid""#);
+        let filter = FeatureFilter {
+            limit: 10,
+            offset: 20,
+            ..FeatureFilter::default()
+        };
+        let (sql, params) = query.to_sql(&filter);
+        assert!(params.is_empty());
+        assert_eq!(
+            sql,
+            r#"SELECT json_build_object('type', 'Feature', 'id', t."gid", 'geometry', ST_AsGeoJSON(t.geom)::json, 'properties', to_jsonb(t) - 'geom') FROM (SELECT ST_Transform(ST_CurveToLine("geom"), 4326) AS geom, "gid" FROM "public"."points" ORDER BY "gid" LIMIT 10 OFFSET 20) AS t"#
+        );
+
+        let filter = FeatureFilter {
+            bbox: Some(Bounds::new(-10.0, -20.0, 10.0, 20.0)),
+            datetime: Some((None, Some("2020-01-01".to_string()))),
+            ..filter
+        };
+        let (sql, params) = query.to_sql(&filter);
+        assert_eq!(params.len(), 5);
+        assert!(sql.contains(r#" WHERE "geom" && ST_Transform(ST_MakeEnvelope($1::float8, $2::float8, $3::float8, $4::float8, 4326), 3857) AND "Updated" <= $5::text::timestamptz ORDER BY"#), "{sql}");
+    }
+}
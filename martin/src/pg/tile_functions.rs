@@ -0,0 +1,238 @@
+use std::fmt::Write as _;
+
+use postgres_protocol::escape::{escape_identifier, escape_literal};
+use serde_json::json;
+
+use crate::args::InstallFunctionsArgs;
+use crate::pg::config::{PgConfig, PgInfo};
+use crate::pg::config_table::TableInfo;
+use crate::pg::pool::PgPool;
+use crate::pg::table_source::{spatial_index_sql, tile_query};
+use crate::pg::utils::{format_source_id, validate_source_id_format, TABLE_ID_VARS};
+use crate::pg::PgError::{InvalidFunctionNameFormat, PostgresError};
+use crate::pg::PgResult;
+use crate::utils::IdResolver;
+use crate::MartinResult;
+
+/// PL/pgSQL function generating the same tiles as a table source
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TileFunction {
+    pub source_id: String,
+    pub schema: String,
+    pub name: String,
+    /// Statements creating the function, and the spatial index of the table if requested
+    pub sql: String,
+}
+
+/// Resolve the sources of the connection, and generate the tile functions of its table sources listed in the args.
+/// The returned pool can be used to install the functions.
+pub async fn table_tile_functions(
+    config: &mut PgConfig,
+    idr: IdResolver,
+    args: &InstallFunctionsArgs,
+) -> MartinResult<(PgPool, Vec<TileFunction>)> {
+    validate_source_id_format(&args.name_format, TABLE_ID_VARS)
+        .map_err(InvalidFunctionNameFormat)?;
+    let pool = config.resolve_for_maintenance(idr).await?;
+
+    let mut functions = Vec::new();
+    for (id, info) in config.tables.iter().flatten() {
+        if args.source.contains(id) {
+            functions.push(tile_function(
+                id,
+                info,
+                args,
+                pool.supports_tile_margin(),
+                config.max_feature_count,
+            )?);
+        }
+    }
+    Ok((pool, functions))
+}
+
+/// Create the functions in a single transaction
+pub async fn install_tile_functions(pool: &PgPool, functions: &[TileFunction]) -> PgResult<()> {
+    let mut conn = pool.get().await?;
+    let tx = conn
+        .transaction()
+        .await
+        .map_err(|e| PostgresError(e, "starting a transaction"))?;
+    for function in functions {
+        tx.batch_execute(&function.sql)
+            .await
+            .map_err(|e| PostgresError(e, "creating a tile function"))?;
+    }
+    tx.commit()
+        .await
+        .map_err(|e| PostgresError(e, "committing the tile functions"))
+}
+
+fn tile_function(
+    source_id: &str,
+    info: &TableInfo,
+    args: &InstallFunctionsArgs,
+    tile_margin: bool,
+    max_feature_count: Option<usize>,
+) -> PgResult<TileFunction> {
+    let vars = [
+        ("schema", info.schema.as_str()),
+        ("table", info.table.as_str()),
+        ("column", info.geometry_column.as_str()),
+    ];
+    let name = format_source_id(&args.name_format, &vars).map_err(InvalidFunctionNameFormat)?;
+    let schema = args.schema.clone().unwrap_or_else(|| info.schema.clone());
+    let function = format!(
+        "{}.{}",
+        escape_identifier(&schema),
+        escape_identifier(&name)
+    );
+
+    let mut sql = format!(
+        "-- Tile function of the source {source_id}, generated from {}\n",
+        info.format_id()
+    );
+    if args.create_indexes && info.geometry_index != Some(true) && info.is_view != Some(true) {
+        writeln!(sql, "{};", spatial_index_sql(info)).unwrap();
+    }
+
+    // PL/pgSQL prepares the query once per connection, and reuses its plan for all tiles
+    let create = if args.replace {
+        "CREATE OR REPLACE FUNCTION"
+    } else {
+        "CREATE FUNCTION"
+    };
+    let query = tile_query(source_id, info, tile_margin, max_feature_count);
+    // Skip the blank lines left by the unused clauses, so that the function is easier to edit
+    let query = query
+        .trim_end_matches(';')
+        .lines()
+        .filter(|v| !v.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    write!(
+        sql,
+        r"{create} {function}(z integer, x integer, y integer)
+RETURNS bytea
+LANGUAGE plpgsql STABLE STRICT PARALLEL SAFE
+AS $martin$
+BEGIN
+  RETURN (
+    SELECT q.mvt FROM (
+{query}
+    ) AS q(mvt)
+  );
+END
+$martin$;
+"
+    )
+    .unwrap();
+
+    let layer = info.layer_id.as_deref().unwrap_or(source_id);
+    let tilejson = json!({
+        "description": info.format_id(),
+        "vector_layers": [{
+            "id": layer,
+            "fields": info.properties.clone().unwrap_or_default(),
+        }],
+    });
+    write!(
+        sql,
+        "COMMENT ON FUNCTION {function}(integer, integer, integer) IS {};",
+        escape_literal(&tilejson.to_string())
+    )
+    .unwrap();
+
+    Ok(TileFunction {
+        source_id: source_id.to_string(),
+        schema,
+        name,
+        sql,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use clap::Parser as _;
+
+    use super::*;
+    use crate::args::{Args, Command, PgCommand};
+
+    fn install_args(args: &[&str]) -> InstallFunctionsArgs {
+        let cmd = ["martin", "pg", "install-functions", "--source", "points"];
+        match Args::try_parse_from(cmd.iter().chain(args))
+            .unwrap()
+            .command
+        {
+            Some(Command::Pg(PgCommand::InstallFunctions(args))) => args,
+            v => panic!("unexpected command {v:?}"),
+        }
+    }
+
+    #[test]
+    fn generate_tile_function() {
+        let info = TableInfo {
+            schema: "public".to_string(),
+            table: "points".to_string(),
+            geometry_column: "geom".to_string(),
+            srid: 4326,
+            geometry_index: Some(false),
+            id_column: Some("gid".to_string()),
+            properties: Some(BTreeMap::from([("name".to_string(), "text".to_string())])),
+            ..Default::default()
+        };
+        let args = install_args(&["--schema", "tiles", "--create-indexes", "--replace"]);
+        let func = tile_function("points", &info, &args, true, None).unwrap();
+        assert_eq!(func.schema, "tiles");
+        assert_eq!(func.name, "points_tiles");
+        insta::assert_snapshot!(func.sql, @r###"
+        -- Tile function of the source points, generated from public.points.geom
+        CREATE INDEX IF NOT EXISTS "points_geom_idx" ON "public"."points" USING GIST ("geom");
+        CREATE OR REPLACE FUNCTION "tiles"."points_tiles"(z integer, x integer, y integer)
+        RETURNS bytea
+        LANGUAGE plpgsql STABLE STRICT PARALLEL SAFE
+        AS $martin$
+        BEGIN
+          RETURN (
+            SELECT q.mvt FROM (
+        SELECT
+          ST_AsMVT(tile, 'points', 4096, 'geom', 'gid'),
+          count(geom)
+        FROM (
+          SELECT
+            ST_AsMVTGeom(
+                ST_Transform(ST_CurveToLine("geom"), 3857),
+                ST_TileEnvelope($1::integer, $2::integer, $3::integer),
+                4096, 64, true
+            ) AS geom
+            , "gid", "name"
+          FROM
+            "public"."points"
+          WHERE
+            "geom" && ST_Transform(ST_TileEnvelope($1::integer, $2::integer, $3::integer, margin => 0.015625), 4326)
+        ) AS tile
+            ) AS q(mvt)
+          );
+        END
+        $martin$;
+        COMMENT ON FUNCTION "tiles"."points_tiles"(integer, integer, integer) IS '{"description":"public.points.geom","vector_layers":[{"fields":{"name":"text"},"id":"points"}]}';
+        "###);
+
+        // Indexed tables only get the function
+        let info = TableInfo {
+            geometry_index: Some(true),
+            ..info
+        };
+        let args = install_args(&["--name-format", "{schema}_{table|upper}"]);
+        let func = tile_function("points", &info, &args, true, None).unwrap();
+        assert_eq!(func.name, "public_POINTS");
+        assert!(func
+            .sql
+            .contains("\nCREATE FUNCTION \"public\".\"public_POINTS\"("));
+        assert!(!func.sql.contains("CREATE INDEX"));
+
+        let args = install_args(&["--name-format", "{function}"]);
+        assert!(tile_function("points", &info, &args, true, None).is_err());
+    }
+}
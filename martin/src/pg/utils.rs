@@ -149,3 +149,91 @@ pub fn find_kv_ignore_case<'a, T>(
         Err(multiple)
     }
 }
+
+/// Variables available in the `source_id_format` of auto-published tables
+pub const TABLE_ID_VARS: &[&str] = &["schema", "table", "column"];
+/// Variables available in the `source_id_format` of auto-published functions
+pub const FUNCTION_ID_VARS: &[&str] = &["schema", "function"];
+
+/// Render a source ID template like `{schema}_{table|lower}`.
+/// Each `{...}` placeholder contains a variable name, optionally followed by `|`-separated filters:
+/// `lower`, `upper`, and `snake` (lowercase, with all non-alphanumeric characters replaced by `_`).
+pub fn format_source_id(template: &str, vars: &[(&str, &str)]) -> Result<String, String> {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let Some(len) = rest[start..].find('}') else {
+            return Err(format!("unclosed '{{' in '{template}'"));
+        };
+        let mut parts = rest[start + 1..start + len].split('|').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let Some((_, value)) = vars.iter().find(|(k, _)| *k == name) else {
+            let names = vars
+                .iter()
+                .map(|(k, _)| format!("{{{k}}}"))
+                .collect::<Vec<_>>();
+            return Err(format!(
+                "unknown variable '{name}' in '{template}', expected one of {}",
+                names.join(", ")
+            ));
+        };
+        let mut value = (*value).to_string();
+        for filter in parts {
+            value = match filter {
+                "lower" => value.to_lowercase(),
+                "upper" => value.to_uppercase(),
+                "snake" => value
+                    .chars()
+                    .map(|c| {
+                        if c.is_alphanumeric() {
+                            c.to_ascii_lowercase()
+                        } else {
+                            '_'
+                        }
+                    })
+                    .collect(),
+                _ => {
+                    return Err(format!(
+                        "unknown filter '{filter}' in '{template}', expected lower, upper, or snake"
+                    ))
+                }
+            };
+        }
+        result.push_str(&value);
+        rest = &rest[start + len + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Check that the template only uses known variables and filters
+pub fn validate_source_id_format(template: &str, vars: &[&str]) -> Result<(), String> {
+    let vars = vars.iter().map(|v| (*v, *v)).collect::<Vec<_>>();
+    format_source_id(template, &vars).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_id_templates() {
+        let vars = [
+            ("schema", "Public"),
+            ("table", "My Table"),
+            ("column", "geom"),
+        ];
+        let fmt = |v| format_source_id(v, &vars);
+        assert_eq!(fmt("{table}").unwrap(), "My Table");
+        assert_eq!(fmt("{schema}_{table}").unwrap(), "Public_My Table");
+        assert_eq!(fmt("{ schema | lower }.{column}").unwrap(), "public.geom");
+        assert_eq!(fmt("{table|upper}").unwrap(), "MY TABLE");
+        assert_eq!(fmt("t_{table|snake}").unwrap(), "t_my_table");
+        assert_eq!(fmt("{table|snake|upper}").unwrap(), "MY_TABLE");
+        assert_eq!(fmt("no vars").unwrap(), "no vars");
+        assert!(fmt("{function}").is_err());
+        assert!(fmt("{table|reverse}").is_err());
+        assert!(fmt("{table").is_err());
+    }
+}
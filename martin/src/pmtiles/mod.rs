@@ -1,21 +1,66 @@
 use std::fmt::{Debug, Formatter};
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use bytes::Bytes;
+use fmmap::tokio::{AsyncMmapFile, AsyncMmapFileExt as _, AsyncOptions};
 use log::{trace, warn};
 use martin_tile_utils::{Encoding, Format, TileInfo};
-use pmtiles::async_reader::AsyncPmTilesReader;
-use pmtiles::mmap::MmapBackend;
-use pmtiles::{Compression, TileType};
+use pmtiles::async_reader::{AsyncBackend, AsyncPmTilesReader};
+use pmtiles::{Compression, PmtError, PmtResult, TileType};
 use tilejson::TileJSON;
 
 use crate::file_config::FileError::{InvalidMetadata, IoError};
-use crate::file_config::FileResult;
-use crate::source::{Source, TileData, UrlQuery};
+use crate::file_config::{FileConfigSource, FileResult};
+use crate::source::{Source, TileData, UrlQuery, TILE_SIZE_KEY};
 use crate::{MartinResult, TileCoord};
 
+mod writer;
+pub(crate) use writer::write_vector_pmtiles;
+
+/// Memory-mapped file that can be shared by the [`Bytes`] of its tiles
+struct MappedFile(AsyncMmapFile);
+
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+}
+
+/// PMTiles reader backend that returns the tile data without copying it out of the memory-mapped file,
+/// so that large tiles are only read from the file while they are being sent, see
+/// [`SrvConfig::stream_threshold`](crate::srv::SrvConfig::stream_threshold)
+struct MmapBackend {
+    file: Bytes,
+}
+
+impl MmapBackend {
+    async fn try_from(path: &Path) -> Result<Self, fmmap::error::Error> {
+        let file = AsyncMmapFile::open_with_options(path, AsyncOptions::new().read(true)).await?;
+        Ok(Self {
+            file: Bytes::from_owner(MappedFile(file)),
+        })
+    }
+}
+
+#[async_trait]
+impl AsyncBackend for MmapBackend {
+    async fn read_exact(&self, offset: usize, length: usize) -> PmtResult<Bytes> {
+        match offset.checked_add(length) {
+            Some(end) if end <= self.file.len() => Ok(self.file.slice(offset..end)),
+            _ => Err(PmtError::Reading(io::ErrorKind::UnexpectedEof.into())),
+        }
+    }
+
+    async fn read(&self, offset: usize, length: usize) -> PmtResult<Bytes> {
+        let start = offset.min(self.file.len());
+        let end = offset.saturating_add(length).min(self.file.len());
+        Ok(self.file.slice(start..end))
+    }
+}
+
 #[derive(Clone)]
 pub struct PmtSource {
     id: String,
@@ -32,8 +77,14 @@ impl Debug for PmtSource {
 }
 
 impl PmtSource {
-    pub async fn new_box(id: String, path: PathBuf) -> FileResult<Box<dyn Source>> {
-        Ok(Box::new(PmtSource::new(id, path).await?))
+    pub async fn new_box(id: String, cfg: FileConfigSource) -> FileResult<Box<dyn Source>> {
+        let mut src = PmtSource::new(id, cfg.path).await?;
+        if let Some(tile_size) = cfg.tile_size {
+            src.tilejson
+                .other
+                .insert(TILE_SIZE_KEY.to_string(), tile_size.into());
+        }
+        Ok(Box::new(src))
     }
 
     async fn new(id: String, path: PathBuf) -> FileResult<Self> {
@@ -134,13 +185,12 @@ impl Source for PmtSource {
         xyz: &TileCoord,
         _url_query: &Option<UrlQuery>,
     ) -> MartinResult<TileData> {
-        // TODO: optimize to return Bytes
         if let Some(t) = self
             .pmtiles
             .get_tile(xyz.z, u64::from(xyz.x), u64::from(xyz.y))
             .await
         {
-            Ok(t.to_vec())
+            Ok(t)
         } else {
             trace!(
                 "Couldn't find tile data in {}/{}/{} of {}",
@@ -149,7 +199,7 @@ impl Source for PmtSource {
                 xyz.y,
                 &self.id
             );
-            Ok(Vec::new())
+            Ok(TileData::new())
         }
     }
 }
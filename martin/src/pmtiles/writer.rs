@@ -0,0 +1,143 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use tilejson::Bounds;
+
+use crate::file_config::FileError::IoError;
+use crate::file_config::FileResult;
+use crate::utils::write_varint;
+use crate::{encode_gzip, TileCoord};
+
+const HEADER_SIZE: usize = 127;
+/// Readers fetch the header and the root directory with a single request of this size
+const MAX_ROOT_END: usize = 16_384;
+
+/// Write gzip-compressed vector tiles and their JSON metadata to a PMTiles v3 archive.
+/// All tiles are listed in the root directory, so the archive is limited to a few thousand tiles.
+pub(crate) fn write_vector_pmtiles(
+    path: &Path,
+    tiles: &[(TileCoord, Vec<u8>)],
+    metadata: &str,
+    bounds: Bounds,
+) -> FileResult<()> {
+    let io_err = |e| IoError(e, path.to_path_buf());
+    let mut entries: Vec<_> = tiles
+        .iter()
+        .map(|(xyz, data)| (tile_id(*xyz), data))
+        .collect();
+    entries.sort_unstable_by_key(|(id, _)| *id);
+
+    // Tile ids are delta-encoded, and each tile directly follows the previous one
+    let mut dir = Vec::new();
+    write_varint(&mut dir, entries.len() as u64);
+    let mut last_id = 0;
+    for (id, _) in &entries {
+        write_varint(&mut dir, id - last_id);
+        last_id = *id;
+    }
+    for _ in &entries {
+        write_varint(&mut dir, 1);
+    }
+    for (_, data) in &entries {
+        write_varint(&mut dir, data.len() as u64);
+    }
+    for i in 0..entries.len() {
+        write_varint(&mut dir, u64::from(i == 0));
+    }
+    let dir = encode_gzip(&dir).map_err(io_err)?;
+    if HEADER_SIZE + dir.len() > MAX_ROOT_END {
+        Err(io_err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} tiles do not fit into the root directory", tiles.len()),
+        )))?;
+    }
+    let metadata = encode_gzip(metadata.as_bytes()).map_err(io_err)?;
+
+    let root_offset = HEADER_SIZE as u64;
+    let metadata_offset = root_offset + dir.len() as u64;
+    let data_offset = metadata_offset + metadata.len() as u64;
+    let data_length = entries.iter().map(|(_, v)| v.len() as u64).sum::<u64>();
+    let min_zoom = tiles.iter().map(|(xyz, _)| xyz.z).min().unwrap_or_default();
+    let max_zoom = tiles.iter().map(|(xyz, _)| xyz.z).max().unwrap_or_default();
+
+    let mut file = Vec::with_capacity(HEADER_SIZE);
+    file.extend_from_slice(b"PMTiles");
+    file.push(3);
+    for value in [
+        root_offset,
+        dir.len() as u64,
+        metadata_offset,
+        metadata.len() as u64,
+        // no leaf directories
+        data_offset,
+        0,
+        data_offset,
+        data_length,
+        // addressed tiles, tile entries, and tile contents
+        entries.len() as u64,
+        entries.len() as u64,
+        entries.len() as u64,
+    ] {
+        file.extend_from_slice(&value.to_le_bytes());
+    }
+    // clustered, gzip directories, gzip tiles, MVT
+    file.extend_from_slice(&[1, 2, 2, 1, min_zoom, max_zoom]);
+    for coord in [bounds.left, bounds.bottom, bounds.right, bounds.top] {
+        file.extend_from_slice(&coordinate(coord));
+    }
+    file.push(min_zoom);
+    file.extend_from_slice(&coordinate((bounds.left + bounds.right) / 2.0));
+    file.extend_from_slice(&coordinate((bounds.bottom + bounds.top) / 2.0));
+
+    file.extend_from_slice(&dir);
+    file.extend_from_slice(&metadata);
+    for (_, data) in entries {
+        file.extend_from_slice(data);
+    }
+    fs::write(path, file).map_err(io_err)
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn coordinate(value: f64) -> [u8; 4] {
+    ((value * 10_000_000.0).round() as i32).to_le_bytes()
+}
+
+/// Position of the tile on the Hilbert curve of its zoom level, after the tiles of all lower zoom levels
+fn tile_id(xyz: TileCoord) -> u64 {
+    let mut id = ((1_u64 << (2 * xyz.z)) - 1) / 3;
+    let (mut x, mut y) = (u64::from(xyz.x), u64::from(xyz.y));
+    let mut size = 1_u64 << xyz.z;
+    while size > 1 {
+        size /= 2;
+        let rx = u64::from(x & size > 0);
+        let ry = u64::from(y & size > 0);
+        id += size * size * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = size - 1 - (x & (size - 1));
+                y = size - 1 - (y & (size - 1));
+            }
+            (x, y) = (y, x);
+        }
+        x &= size - 1;
+        y &= size - 1;
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hilbert_tile_ids() {
+        let id = |z, x, y| tile_id(TileCoord { z, x, y });
+        assert_eq!(id(0, 0, 0), 0);
+        assert_eq!(id(1, 0, 0), 1);
+        assert_eq!(id(1, 1, 0), 4);
+        assert_eq!(id(2, 1, 3), 11);
+        assert_eq!(id(3, 3, 0), 26);
+        assert_eq!(id(3, 7, 0), 84);
+    }
+}
@@ -0,0 +1,222 @@
+//! Tile source that fetches the tiles of another tile server, e.g. to copy them with `martin-cp`.
+
+use async_trait::async_trait;
+use log::trace;
+use martin_tile_utils::{Format, TileInfo};
+use tilejson::{tilejson, TileJSON};
+use url::Url;
+
+use crate::source::{TileData, UrlQuery};
+use crate::utils::send_request;
+use crate::MartinError::RemoteSourceError;
+use crate::{decode_brotli, decode_gzip, decode_zstd, MartinResult, Source, TileCoord};
+
+/// Tiles of a `{z}/{x}/{y}` URL template, or of the first tile URL of a `TileJSON` URL.
+/// The tiles are decoded from their HTTP `Content-Encoding`.
+#[derive(Clone, Debug)]
+pub struct RemoteSource {
+    id: String,
+    template: String,
+    tilejson: TileJSON,
+    tile_info: TileInfo,
+}
+
+impl RemoteSource {
+    /// Create a source from a URL with `{z}`, `{x}`, and `{y}` placeholders, or from the URL of a `TileJSON` document.
+    /// The URL is used as the source ID.
+    pub async fn new(url: &str) -> MartinResult<Self> {
+        let err = |e: String| RemoteSourceError(url.to_string(), e);
+        let (template, tilejson) = if url.contains("{z}") {
+            (url.to_string(), tilejson! { tiles: vec![] })
+        } else {
+            let response = get(url).await?;
+            if !(200..300).contains(&response.0) {
+                Err(err(format!("the server returned status {}", response.0)))?;
+            }
+            let tilejson: TileJSON =
+                serde_json::from_slice(&response.1).map_err(|e| err(e.to_string()))?;
+            let template = tilejson
+                .tiles
+                .first()
+                .ok_or_else(|| err("the TileJSON has no tile URLs".to_string()))?;
+            if !template.starts_with("http://") && !template.starts_with("https://") {
+                Err(err(format!("the tile URL {template} is not absolute")))?;
+            }
+            (template.clone(), tilejson)
+        };
+        if !["{z}", "{x}", "{y}"].iter().all(|v| template.contains(v)) {
+            Err(err(format!(
+                "the tile URL {template} must have {{z}}, {{x}}, and {{y}} placeholders"
+            )))?;
+        }
+        let format = template_format(&template)
+            .or_else(|| {
+                tilejson
+                    .other
+                    .get("format")
+                    .and_then(|v| v.as_str())
+                    .and_then(Format::parse)
+            })
+            .ok_or_else(|| {
+                err(format!(
+                    "the tile format is unknown, the tile URL {template} must end with a file extension like .pbf or .png"
+                ))
+            })?;
+        Ok(Self {
+            id: url.to_string(),
+            template,
+            tilejson,
+            tile_info: TileInfo::from(format),
+        })
+    }
+
+    #[must_use]
+    pub fn tile_url(&self, xyz: TileCoord) -> String {
+        self.template
+            .replace("{z}", &xyz.z.to_string())
+            .replace("{x}", &xyz.x.to_string())
+            .replace("{y}", &xyz.y.to_string())
+    }
+}
+
+/// Format of the tile file extension in the path of the URL template
+fn template_format(template: &str) -> Option<Format> {
+    let path = template.split(['?', '#']).next()?;
+    let (_, ext) = path.rsplit_once('/')?.1.rsplit_once('.')?;
+    Format::parse(ext)
+}
+
+/// Get the status and the decoded body of the URL
+async fn get(url: &str) -> MartinResult<(u16, Vec<u8>)> {
+    let err = |e: String| RemoteSourceError(url.to_string(), e);
+    let parsed = Url::parse(url).map_err(|e| err(e.to_string()))?;
+    let headers = [("Accept-Encoding", "gzip, br, zstd")];
+    let response = send_request("GET", &parsed, &headers, &[])
+        .await
+        .map_err(err)?;
+    let body = match response.header("content-encoding") {
+        None | Some("identity") => Ok(response.body),
+        Some("gzip") => decode_gzip(&response.body),
+        Some("br") => decode_brotli(&response.body),
+        Some("zstd") => decode_zstd(&response.body),
+        Some(v) => Err(err(format!("the {v} content encoding is not supported")))?,
+    };
+    Ok((response.status, body.map_err(|e| err(e.to_string()))?))
+}
+
+#[async_trait]
+impl Source for RemoteSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.tile_info
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: &TileCoord,
+        _url_query: &Option<UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let url = self.tile_url(*xyz);
+        let (status, mut body) = get(&url).await?;
+        match status {
+            200..=299 => {}
+            404 => {
+                trace!("Couldn't find tile {xyz} of {}", self.id);
+                return Ok(TileData::new());
+            }
+            status => Err(RemoteSourceError(
+                url.clone(),
+                format!("the server returned status {status}"),
+            ))?,
+        }
+        // Vector tiles are often stored compressed, and served without a Content-Encoding
+        if !self.tile_info.format.is_detectable() && body.starts_with(b"\x1f\x8b") {
+            body = decode_gzip(&body).map_err(|e| RemoteSourceError(url, e.to_string()))?;
+        }
+        Ok(TileData::from(body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::encode_gzip;
+
+    #[actix_rt::test]
+    async fn remote_tiles() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let tilejson = format!(
+            r#"{{"tilejson":"3.0.0","tiles":["{base}/{{z}}/{{x}}/{{y}}?key=1"],"format":"pbf","maxzoom":4}}"#
+        );
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let len = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..len]);
+                }
+                let request = String::from_utf8(request).unwrap();
+                let path = request.split(' ').nth(1).unwrap();
+                let (head, body) = match path {
+                    "/tiles.json" => ("200 OK", tilejson.clone().into_bytes()),
+                    "/0/0/0?key=1" => (
+                        "200 OK\r\nContent-Encoding: gzip",
+                        encode_gzip(b"tile 0").unwrap(),
+                    ),
+                    "/1/0/1?key=1" => ("200 OK", encode_gzip(b"tile 1").unwrap()),
+                    "/2/0/0?key=1" => ("500 Internal Server Error", Vec::new()),
+                    _ => ("404 Not Found", Vec::new()),
+                };
+                let head = format!("HTTP/1.1 {head}\r\nContent-Length: {}\r\n\r\n", body.len());
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(&body).await.unwrap();
+            }
+        });
+
+        let src = RemoteSource::new(&format!("{base}/tiles.json"))
+            .await
+            .unwrap();
+        assert_eq!(src.get_tile_info(), TileInfo::from(Format::Mvt));
+        assert_eq!(src.get_tilejson().maxzoom, Some(4));
+        let tile = |z, x, y| {
+            let src = src.clone();
+            async move { src.get_tile(&TileCoord { z, x, y }, &None).await }
+        };
+        assert_eq!(tile(0, 0, 0).await.unwrap(), b"tile 0".as_slice());
+        assert_eq!(tile(1, 0, 1).await.unwrap(), b"tile 1".as_slice());
+        assert!(tile(1, 1, 1).await.unwrap().is_empty());
+        assert!(tile(2, 0, 0).await.is_err());
+
+        let src = RemoteSource::new(&format!("{base}/{{z}}/{{x}}/{{y}}.png"))
+            .await
+            .unwrap();
+        assert_eq!(src.get_tile_info(), TileInfo::from(Format::Png));
+        assert_eq!(
+            src.tile_url(TileCoord { z: 3, x: 1, y: 2 }),
+            format!("{base}/3/1/2.png")
+        );
+        assert!(RemoteSource::new(&format!("{base}/{{z}}/{{x}}/{{y}}"))
+            .await
+            .is_err());
+        assert!(RemoteSource::new(&format!("{base}/missing.json"))
+            .await
+            .is_err());
+    }
+}
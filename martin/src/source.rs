@@ -1,52 +1,332 @@
+use std::cell::RefCell;
 use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
+use std::future::Future;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, Instant};
 
 use actix_web::error::ErrorNotFound;
+use actix_web::http::header::HeaderMap;
 use async_trait::async_trait;
+use bytes::Bytes;
 use log::debug;
 use martin_tile_utils::TileInfo;
 use serde::{Deserialize, Serialize};
-use tilejson::TileJSON;
+use tilejson::{Bounds, TileJSON};
 
-use crate::{MartinResult, TileCoord};
+use crate::{IdResolver, MartinResult, TileCoord};
 
-pub type TileData = Vec<u8>;
+/// Tile content. Cloning is cheap, and sources can return slices of a shared or memory-mapped buffer without copying.
+pub type TileData = Bytes;
 pub type UrlQuery = HashMap<String, String>;
 
+/// The TileJSON key that stores the size of the tiles in pixels
+pub const TILE_SIZE_KEY: &str = "tileSize";
+/// Tile size in pixels used when a source does not specify it
+pub const DEFAULT_TILE_SIZE: u16 = 256;
+
+/// Total duration of each phase of a request, in the order the phases were first recorded
+pub type RequestTimings = Vec<(&'static str, Duration)>;
+
+/// Name and number of features of each layer of a vector tile, in the order the layers were recorded
+pub type FeatureCounts = Vec<(String, usize)>;
+
+tokio::task_local! {
+    /// Time by which the tile request being processed must complete
+    static REQUEST_DEADLINE: Instant;
+    /// Phases of the tile request being processed, see [`record_timing`]
+    static REQUEST_TIMINGS: RefCell<RequestTimings>;
+    /// Features of the tile request being processed, see [`record_feature_count`]
+    static REQUEST_FEATURE_COUNTS: RefCell<FeatureCounts>;
+    /// HTTP headers of the tile request being processed, see [`request_header`]
+    static REQUEST_HEADERS: HeaderMap;
+}
+
+/// Run the tile request with its HTTP headers, which sources can read with [`request_header`]
+pub async fn with_headers<F: Future>(headers: HeaderMap, fut: F) -> F::Output {
+    REQUEST_HEADERS.scope(headers, fut).await
+}
+
+/// Value of an HTTP header of the current tile request, e.g. for sources that serve
+/// different content to different tenants. Returns `None` if the header is missing or not valid text.
+#[must_use]
+pub fn request_header(name: &str) -> Option<String> {
+    REQUEST_HEADERS
+        .try_with(|headers| {
+            let value = headers.get(name)?.to_str().ok()?;
+            Some(value.to_string())
+        })
+        .ok()
+        .flatten()
+}
+
+/// Run the tile request, and return its result together with the phases recorded with [`record_timing`]
+pub async fn with_timings<F: Future>(fut: F) -> (F::Output, RequestTimings) {
+    REQUEST_TIMINGS
+        .scope(RefCell::new(Vec::new()), async {
+            let output = fut.await;
+            (output, REQUEST_TIMINGS.with(RefCell::take))
+        })
+        .await
+}
+
+/// Add the duration of a phase of the current tile request, if it collects timings.
+/// Durations of the same phase are summed, e.g. when several sources are queried.
+pub fn record_timing(phase: &'static str, duration: Duration) {
+    let _ = REQUEST_TIMINGS.try_with(|timings| {
+        let mut timings = timings.borrow_mut();
+        match timings.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += duration,
+            None => timings.push((phase, duration)),
+        }
+    });
+}
+
+/// Run the tile request, and return its result together with the layers recorded with [`record_feature_count`]
+pub async fn with_feature_counts<F: Future>(fut: F) -> (F::Output, FeatureCounts) {
+    REQUEST_FEATURE_COUNTS
+        .scope(RefCell::new(Vec::new()), async {
+            let output = fut.await;
+            (output, REQUEST_FEATURE_COUNTS.with(RefCell::take))
+        })
+        .await
+}
+
+/// Add the number of features of a layer of the current tile request, if it collects them.
+/// Counts of the same layer are summed, e.g. when a layer is split across several sources.
+pub fn record_feature_count(layer: &str, count: usize) {
+    let _ = REQUEST_FEATURE_COUNTS.try_with(|counts| {
+        let mut counts = counts.borrow_mut();
+        match counts.iter_mut().find(|(name, _)| name == layer) {
+            Some((_, total)) => *total += count,
+            None => counts.push((layer.to_string(), count)),
+        }
+    });
+}
+
+/// Run the tile request with a deadline, which sources can check with [`remaining_deadline`]
+pub async fn with_deadline<F: Future>(deadline: Instant, fut: F) -> F::Output {
+    REQUEST_DEADLINE.scope(deadline, fut).await
+}
+
+/// Time left until the deadline of the current tile request, if it has one
+#[must_use]
+pub fn remaining_deadline() -> Option<Duration> {
+    REQUEST_DEADLINE
+        .try_with(|deadline| deadline.saturating_duration_since(Instant::now()))
+        .ok()
+}
+
+/// Value statistics of each field of each vector layer, see [`Source::get_field_stats`]
+pub type SourceFieldStats = BTreeMap<String, BTreeMap<String, FieldStats>>;
+
+/// Value statistics of a single vector tile field
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldStats {
+    pub min: Option<String>,
+    pub max: Option<String>,
+    /// A few distinct values of the field
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub samples: Vec<String>,
+}
+
+/// Filters and paging of a feature request, see [`Source::get_features`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeatureFilter {
+    /// Only return features intersecting this WGS84 bounding box
+    pub bbox: Option<Bounds>,
+    /// Only return features with a datetime between the start and the end, both inclusive.
+    /// Either end may be open. Sources without a datetime ignore this filter.
+    pub datetime: Option<(Option<String>, Option<String>)>,
+    pub limit: usize,
+    pub offset: usize,
+}
+
 pub type TileInfoSource = Box<dyn Source>;
 
 pub type TileInfoSources = Vec<TileInfoSource>;
 
-#[derive(Default, Clone)]
-pub struct TileSources(HashMap<String, Box<dyn Source>>);
 pub type TileCatalog = BTreeMap<String, CatalogSourceEntry>;
 
+/// Transformation applied to each source, see [`TileSources::wrap`]
+type SourceWrapper = Arc<dyn Fn(Box<dyn Source>) -> Box<dyn Source> + Send + Sync>;
+
+#[derive(Default, Clone)]
+pub struct TileSources {
+    sources: HashMap<String, Box<dyn Source>>,
+    /// Sources added while the server is already running, shared by all clones
+    late: Arc<LateSources>,
+    /// Resolver of the source IDs, whose renamed IDs are listed in the catalog
+    id_resolver: Option<IdResolver>,
+}
+
+#[derive(Default)]
+struct LateSources {
+    /// Late sources are never removed, so they live until the process exits
+    sources: RwLock<HashMap<String, &'static dyn Source>>,
+    wrapper: RwLock<Option<SourceWrapper>>,
+}
+
 impl TileSources {
     #[must_use]
     pub fn new(sources: Vec<TileInfoSources>) -> Self {
-        Self(
-            sources
+        Self {
+            sources: sources
                 .into_iter()
                 .flatten()
                 .map(|src| (src.get_id().to_string(), src))
                 .collect(),
-        )
+            late: Arc::default(),
+            id_resolver: None,
+        }
+    }
+
+    /// List the IDs renamed by the resolver in the catalog, including the ones of the sources added later
+    #[must_use]
+    pub fn with_id_resolver(mut self, id_resolver: &IdResolver) -> Self {
+        self.id_resolver = Some(id_resolver.clone());
+        self
+    }
+
+    /// Add more sources, e.g. the ones that depend on already resolved sources
+    pub fn extend(&mut self, sources: TileInfoSources) {
+        self.sources.extend(
+            sources
+                .into_iter()
+                .map(|src| (src.get_id().to_string(), src)),
+        );
+    }
+
+    /// Add sources that finished initializing after the server has started.
+    /// They become visible to all clones of these sources, and are wrapped like the others.
+    pub fn add_late(&self, sources: TileInfoSources) {
+        let wrapper = self
+            .late
+            .wrapper
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let mut late = self
+            .late
+            .sources
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        for src in sources {
+            let src = match &wrapper {
+                Some(wrap) => wrap(src),
+                None => src,
+            };
+            let src: &'static dyn Source = Box::leak(src);
+            late.insert(src.get_id().to_string(), src);
+        }
+    }
+
+    /// Replace every source with the result of `f`, e.g. to add monitoring.
+    /// Sources added later with [`TileSources::add_late`] are wrapped as well.
+    #[must_use]
+    pub fn wrap(
+        self,
+        f: impl Fn(Box<dyn Source>) -> Box<dyn Source> + Send + Sync + 'static,
+    ) -> Self {
+        let sources = self
+            .sources
+            .into_iter()
+            .map(|(id, src)| (id, f(src)))
+            .collect();
+        for src in self
+            .late
+            .sources
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values_mut()
+        {
+            // The unwrapped source is not freed, as it may still be in use
+            *src = Box::leak(f(src.clone_source()));
+        }
+        let f: SourceWrapper = Arc::new(f);
+        let mut wrapper = self
+            .late
+            .wrapper
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        *wrapper = Some(match wrapper.take() {
+            Some(previous) => Arc::new(move |src| f(previous(src))),
+            None => f,
+        });
+        drop(wrapper);
+        Self {
+            sources,
+            late: self.late,
+            id_resolver: self.id_resolver,
+        }
+    }
+
+    /// Wrap other sources like these ones, e.g. the sources of a new configuration that replace them
+    #[must_use]
+    pub fn wrap_like(&self, sources: Self) -> Self {
+        let wrapper = self
+            .late
+            .wrapper
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        match wrapper {
+            Some(wrap) => sources.wrap(move |src| wrap(src)),
+            None => sources,
+        }
+    }
+
+    /// Sources added with [`TileSources::add_late`] so far
+    fn late_sources(&self) -> Vec<&'static dyn Source> {
+        self.late
+            .sources
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .copied()
+            .collect()
+    }
+
+    /// All sources, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &dyn Source> + '_ {
+        self.sources
+            .values()
+            .map(AsRef::as_ref)
+            .chain(self.late_sources())
     }
 
     #[must_use]
     pub fn get_catalog(&self) -> TileCatalog {
-        self.0
+        self.iter()
+            .map(|src| (src.get_id().to_string(), src.get_catalog_entry()))
+            .collect()
+    }
+
+    /// IDs of the sources whose requested names had to be normalized, keyed by the requested name.
+    /// Names that conflicted with other sources are not listed, as several sources requested them.
+    #[must_use]
+    pub fn get_source_ids(&self) -> BTreeMap<String, String> {
+        self.id_resolver
             .iter()
-            .map(|(id, src)| (id.to_string(), src.get_catalog_entry()))
+            .flat_map(IdResolver::renamed)
+            .filter(|v| !v.conflict)
+            .filter_map(|v| Some((v.name, v.new_name?)))
+            .filter(|(_, id)| self.get_source(id).is_ok())
             .collect()
     }
 
     pub fn get_source(&self, id: &str) -> actix_web::Result<&dyn Source> {
-        Ok(self
-            .0
+        if let Some(src) = self.sources.get(id) {
+            return Ok(src.as_ref());
+        }
+        self.late
+            .sources
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
             .get(id)
-            .ok_or_else(|| ErrorNotFound(format!("Source {id} does not exist")))?
-            .as_ref())
+            .copied()
+            .ok_or_else(|| ErrorNotFound(format!("Source {id} does not exist")))
     }
 
     /// Get a list of sources, and the tile info for the merged sources.
@@ -100,7 +380,7 @@ impl TileSources {
 }
 
 #[async_trait]
-pub trait Source: Send + Debug {
+pub trait Source: Send + Sync + Debug {
     fn get_id(&self) -> &str;
 
     fn get_tilejson(&self) -> &TileJSON;
@@ -113,8 +393,49 @@ pub trait Source: Send + Debug {
         false
     }
 
+    /// Width and height of the tiles in pixels, as advertised by the `tileSize` TileJSON value
+    fn get_tile_size(&self) -> u16 {
+        self.get_tilejson()
+            .other
+            .get(TILE_SIZE_KEY)
+            .and_then(serde_json::Value::as_u64)
+            .and_then(|v| u16::try_from(v).ok())
+            .unwrap_or(DEFAULT_TILE_SIZE)
+    }
+
+    /// If true, the source computes the number of features of its tiles, and records them
+    /// with [`record_feature_count`] for the `X-Feature-Count` headers of the tile responses
+    fn include_feature_count(&self) -> bool {
+        false
+    }
+
+    /// HTTP request header that changes the content of the tiles, e.g. to pick a variant,
+    /// so that the tiles are cached separately for each value of the header
+    fn vary_header(&self) -> Option<&str> {
+        None
+    }
+
     async fn get_tile(&self, xyz: &TileCoord, query: &Option<UrlQuery>) -> MartinResult<TileData>;
 
+    /// Value statistics of the fields of each vector layer, or `None` if the source cannot compute them.
+    /// This may be slow, so sources should only compute it on demand.
+    async fn get_field_stats(&self) -> MartinResult<Option<SourceFieldStats>> {
+        Ok(None)
+    }
+
+    /// True if the source can return its individual features with [`Source::get_features`]
+    fn supports_features(&self) -> bool {
+        false
+    }
+
+    /// Features matching the filter as `GeoJSON` objects, or `None` if the source cannot return them
+    async fn get_features(
+        &self,
+        _filter: &FeatureFilter,
+    ) -> MartinResult<Option<Vec<serde_json::Value>>> {
+        Ok(None)
+    }
+
     fn is_valid_zoom(&self, zoom: u8) -> bool {
         let tj = self.get_tilejson();
         tj.minzoom.map_or(true, |minzoom| zoom >= minzoom)
@@ -161,6 +482,63 @@ mod tests {
         assert_eq!(format!("{xyz}"), "1,2,3");
         assert_eq!(format!("{xyz:#}"), "1/2/3");
     }
+
+    #[actix_rt::test]
+    async fn request_deadline() {
+        assert_eq!(remaining_deadline(), None);
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let remaining = with_deadline(deadline, async { remaining_deadline() })
+            .await
+            .unwrap();
+        assert!(remaining <= Duration::from_secs(60));
+        assert!(remaining > Duration::from_secs(50));
+        let expired = with_deadline(Instant::now(), async { remaining_deadline() }).await;
+        assert_eq!(expired, Some(Duration::ZERO));
+    }
+
+    #[actix_rt::test]
+    async fn request_headers() {
+        assert_eq!(request_header("x-tenant"), None);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-tenant".parse().unwrap(),
+            actix_web::http::header::HeaderValue::from_static("acme"),
+        );
+        let tenant = with_headers(headers, async { request_header("X-Tenant") }).await;
+        assert_eq!(tenant.as_deref(), Some("acme"));
+        let other = with_headers(HeaderMap::new(), async { request_header("x-tenant") }).await;
+        assert_eq!(other, None);
+    }
+
+    #[actix_rt::test]
+    async fn request_timings() {
+        record_timing("ignored", Duration::from_millis(1));
+        let ms = Duration::from_millis;
+        let (value, timings) = with_timings(async {
+            record_timing("fetch", ms(2));
+            record_timing("merge", ms(1));
+            record_timing("fetch", ms(3));
+            42
+        })
+        .await;
+        assert_eq!(value, 42);
+        assert_eq!(timings, vec![("fetch", ms(5)), ("merge", ms(1))]);
+    }
+
+    #[actix_rt::test]
+    async fn request_feature_counts() {
+        record_feature_count("ignored", 1);
+        let ((), counts) = with_feature_counts(async {
+            record_feature_count("roads", 2);
+            record_feature_count("points", 0);
+            record_feature_count("roads", 3);
+        })
+        .await;
+        assert_eq!(
+            counts,
+            vec![("roads".to_string(), 5), ("points".to_string(), 0)]
+        );
+    }
 }
 
 pub struct Tile {
@@ -170,7 +548,10 @@ pub struct Tile {
 
 impl Tile {
     #[must_use]
-    pub fn new(data: TileData, info: TileInfo) -> Self {
-        Self { data, info }
+    pub fn new(data: impl Into<TileData>, info: TileInfo) -> Self {
+        Self {
+            data: data.into(),
+            info,
+        }
     }
 }
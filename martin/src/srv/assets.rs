@@ -0,0 +1,117 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+use actix_web::http::header::{CacheControl, CacheDirective, ETag, EntityTag, IfNoneMatch};
+use actix_web::web::Data;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+
+/// Sprites and fonts may be used this many seconds before clients must revalidate them
+pub const ASSET_MAX_AGE_DEFAULT: u64 = 3600;
+
+/// Responses to versioned URLs never change, so they are cached for a year
+const IMMUTABLE_MAX_AGE: u32 = 365 * 24 * 60 * 60;
+
+/// Cache lifetime of sprites and fonts, see [`crate::srv::SrvConfig::asset_max_age`]
+pub struct AssetMaxAge(pub u64);
+
+/// Entity tag of the content, derived from its hash
+#[must_use]
+pub fn asset_etag(data: &[u8]) -> EntityTag {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(data);
+    // The compression middleware may change the encoding of the same content, so the tag is weak
+    EntityTag::new_weak(format!("{:016x}", hasher.finish()))
+}
+
+/// Respond with a static asset such as a sprite sheet or a font range, or with `304 Not Modified`
+/// if the client already has the same content. Requests with a `v` query parameter use versioned URLs,
+/// which must change whenever the content changes, so their responses are marked immutable.
+#[must_use]
+pub fn asset_response(req: &HttpRequest, content_type: &str, data: Vec<u8>) -> HttpResponse {
+    let etag = asset_etag(&data);
+    let versioned = req
+        .query_string()
+        .split('&')
+        .any(|v| v.split('=').next() == Some("v"));
+    let cache_control = if versioned {
+        vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(IMMUTABLE_MAX_AGE),
+            CacheDirective::Extension("immutable".to_string(), None),
+        ]
+    } else {
+        let max_age = req
+            .app_data::<Data<AssetMaxAge>>()
+            .map_or(ASSET_MAX_AGE_DEFAULT, |v| v.0);
+        vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(u32::try_from(max_age).unwrap_or(u32::MAX)),
+        ]
+    };
+
+    let not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|v| v.weak_eq(&etag)),
+        None => false,
+    };
+    let mut response = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response
+        .insert_header(ETag(etag))
+        .insert_header(CacheControl(cache_control));
+    if not_modified {
+        response.finish()
+    } else {
+        response.content_type(content_type).body(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{CACHE_CONTROL, ETAG, IF_NONE_MATCH};
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn header(resp: &HttpResponse, name: actix_web::http::header::HeaderName) -> &str {
+        resp.headers().get(name).unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn asset_caching() {
+        let data = b"sprite".to_vec();
+        let etag = asset_etag(&data).to_string();
+        assert_eq!(etag, asset_etag(b"sprite").to_string());
+        assert_ne!(etag, asset_etag(b"other").to_string());
+
+        let req = TestRequest::default().to_http_request();
+        let resp = asset_response(&req, "image/png", data.clone());
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(header(&resp, ETAG), etag);
+        assert_eq!(header(&resp, CACHE_CONTROL), "public, max-age=3600");
+
+        let req = TestRequest::default()
+            .insert_header((IF_NONE_MATCH, etag.clone()))
+            .app_data(Data::new(AssetMaxAge(60)))
+            .to_http_request();
+        let resp = asset_response(&req, "image/png", data.clone());
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(header(&resp, ETAG), etag);
+        assert_eq!(header(&resp, CACHE_CONTROL), "public, max-age=60");
+
+        let req = TestRequest::default()
+            .insert_header((IF_NONE_MATCH, "W/\"0000000000000000\""))
+            .uri("/sprite/src.png?v=2")
+            .to_http_request();
+        let resp = asset_response(&req, "image/png", data);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            header(&resp, CACHE_CONTROL),
+            "public, max-age=31536000, immutable"
+        );
+    }
+}
@@ -1,7 +1,17 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
 use serde::{Deserialize, Serialize};
 
+use crate::srv::{
+    CacheRoutingConfig, EmptyTileCacheConfig, EndpointsConfig, EventsConfig, FaultConfig, LogLevel,
+    MemoryBudgetConfig, QuotasConfig, ReadyWhen, SourceErrorsConfig, TileScheme,
+};
+
 pub const KEEP_ALIVE_DEFAULT: u64 = 75;
 pub const LISTEN_ADDRESSES_DEFAULT: &str = "0.0.0.0:3000";
+pub const POST_BODY_LIMIT_DEFAULT: usize = 64 * 1024;
+pub const STREAM_THRESHOLD_DEFAULT: usize = 1024 * 1024;
 
 #[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
@@ -9,6 +19,57 @@ pub struct SrvConfig {
     pub keep_alive: Option<u64>,
     pub listen_addresses: Option<String>,
     pub worker_processes: Option<usize>,
+    /// Maximum time to generate a tile, in seconds. The remaining time is also used as the PostgreSQL `statement_timeout`.
+    pub request_timeout: Option<u64>,
+    /// Add a `Server-Timing` header with the duration of each phase to tile responses
+    pub server_timing: Option<bool>,
+    /// Maximum size of the JSON body of `POST` tile requests, in bytes
+    pub post_body_limit: Option<usize>,
+    /// Send the tiles larger than this in small chunks instead of a single buffer, in bytes
+    pub stream_threshold: Option<usize>,
+    /// How long clients may use sprites and fonts before revalidating them with their `ETag`, in seconds
+    pub asset_max_age: Option<u64>,
+    /// Tile row numbering scheme of some sources, e.g. `tms` for legacy clients.
+    /// The `?scheme` URL parameter takes precedence over it.
+    pub tile_schemes: Option<BTreeMap<String, TileScheme>>,
+    /// Record all tile requests to this file, replacing its content, so they can be re-issued with `martin replay`
+    pub record_requests: Option<PathBuf>,
+    /// Record the first distinct tile requests of each source with a summary of their responses to this directory,
+    /// one `<source>.jsonl` file per source, to compare them with `martin replay --compare`
+    pub record_samples: Option<PathBuf>,
+    /// Number of requests recorded for each source by `record_samples` [default: 10]
+    pub samples_per_source: Option<usize>,
+    /// Remember empty tiles for a while, and respond with `204 No Content` without querying the source again
+    pub empty_tile_cache: Option<EmptyTileCacheConfig>,
+    /// Track the errors of each source, report them at `/status`, and optionally stop querying failing sources
+    pub source_errors: Option<SourceErrorsConfig>,
+    /// Publish an event for each served tile, empty tile cache lookup, and source error to NATS or Kafka
+    pub events: Option<EventsConfig>,
+    /// Start serving once this percentage of the source files and PostgreSQL connections is initialized, e.g. `90%`.
+    /// The others keep initializing in the background, and are reported at `/status` until they are ready.
+    pub ready_when: Option<ReadyWhen>,
+    /// Log level of individual sources, used instead of the global level while they process requests.
+    /// Setting it also enables the `/_/log` endpoints to change the levels while the server is running.
+    pub log_levels: Option<BTreeMap<String, LogLevel>>,
+    /// Endpoint groups to disable, e.g. to only serve tiles in hardened deployments
+    pub endpoints: Option<EndpointsConfig>,
+    /// Limit the approximate memory used by tiles in flight and by the caches. Tile requests wait
+    /// for memory to be freed when the budget is used up, and low priority ones are rejected.
+    pub memory_budget: Option<MemoryBudgetConfig>,
+    /// Delay and fail the tile requests of some sources to test timeouts and the circuit breaker.
+    /// Only used if the `MARTIN_FAULT_INJECTION` environment variable is set to `true`.
+    pub fault_injection: Option<BTreeMap<String, FaultConfig>>,
+    /// Limit the tiles each API key may request per day, and the zoom levels available without an API key
+    pub quotas: Option<QuotasConfig>,
+    /// Add a hash of the tile key, and optionally the server chosen by rendezvous hashing, to tile responses,
+    /// so that load balancers can send the requests of the same tile to the same server of a fleet
+    pub cache_routing: Option<CacheRoutingConfig>,
+    /// Enable `POST /_/config/stage` to load the configuration again while the server is running,
+    /// and switch to its sources once all of them are initialized
+    pub config_staging: Option<bool>,
+    /// Enable the `/_/mirror` endpoints to send a copy of some requests to a replica for a limited time,
+    /// e.g. to warm up its caches before it receives real traffic
+    pub request_mirroring: Option<bool>,
 }
 
 #[cfg(test)]
@@ -16,22 +77,153 @@ mod tests {
     use indoc::indoc;
 
     use super::*;
+    use crate::srv::{
+        CircuitBreakerConfig, DisabledStatus, EmptyTileLimits, EmptyTileRefreshConfig, NatsConfig,
+    };
     use crate::test_utils::some;
+    use crate::utils::CronSchedule;
 
     #[test]
+    #[allow(clippy::too_many_lines)]
     fn parse_empty_config() {
         assert_eq!(
             serde_yaml::from_str::<SrvConfig>(indoc! {"
                 keep_alive: 75
                 listen_addresses: '0.0.0.0:3000'
                 worker_processes: 8
+                request_timeout: 10
+                server_timing: true
+                post_body_limit: 1024
+                stream_threshold: 4096
+                asset_max_age: 600
+                tile_schemes:
+                  legacy: tms
+                record_requests: /tmp/requests.jsonl
+                record_samples: /tmp/samples
+                samples_per_source: 20
+                empty_tile_cache:
+                  ttl: 600
+                  sources:
+                    points:
+                      max_entries: 1000
+                      purge: 0 3 * * *
+                      depends_on: [points_table]
+                  refresh:
+                    min_hits: 10
+                source_errors:
+                  window: 30
+                  circuit_breaker:
+                    error_rate: 0.25
+                events:
+                  nats:
+                    url: nats://localhost:4222
+                    subject: tiles
+                ready_when: 90%
+                log_levels:
+                  points: debug
+                endpoints:
+                  catalog: false
+                  disabled_status: 403
+                memory_budget:
+                  limit: 268435456
+                  max_wait: 500
             "})
             .unwrap(),
             SrvConfig {
                 keep_alive: Some(75),
                 listen_addresses: some("0.0.0.0:3000"),
                 worker_processes: Some(8),
+                request_timeout: Some(10),
+                server_timing: Some(true),
+                post_body_limit: Some(1024),
+                stream_threshold: Some(4096),
+                asset_max_age: Some(600),
+                tile_schemes: Some([("legacy".to_string(), TileScheme::Tms)].into()),
+                record_requests: Some(PathBuf::from("/tmp/requests.jsonl")),
+                record_samples: Some(PathBuf::from("/tmp/samples")),
+                samples_per_source: Some(20),
+                empty_tile_cache: Some(EmptyTileCacheConfig {
+                    defaults: EmptyTileLimits {
+                        ttl: Some(600),
+                        max_entries: None,
+                        purge: None,
+                        depends_on: None,
+                    },
+                    sources: [(
+                        "points".to_string(),
+                        EmptyTileLimits {
+                            ttl: None,
+                            max_entries: Some(1000),
+                            purge: Some(CronSchedule::parse("0 3 * * *").unwrap()),
+                            depends_on: Some(vec!["points_table".to_string()]),
+                        }
+                    )]
+                    .into_iter()
+                    .collect(),
+                    refresh: Some(EmptyTileRefreshConfig {
+                        min_hits: Some(10),
+                        ..Default::default()
+                    }),
+                }),
+                source_errors: Some(SourceErrorsConfig {
+                    window: Some(30),
+                    circuit_breaker: Some(CircuitBreakerConfig {
+                        error_rate: Some(0.25),
+                        ..Default::default()
+                    }),
+                }),
+                events: Some(EventsConfig {
+                    nats: Some(NatsConfig {
+                        url: "nats://localhost:4222".to_string(),
+                        subject: some("tiles"),
+                    }),
+                    ..Default::default()
+                }),
+                ready_when: Some(ReadyWhen::parse("90%").unwrap()),
+                log_levels: Some([("points".to_string(), LogLevel::Debug)].into()),
+                endpoints: Some(EndpointsConfig {
+                    catalog: Some(false),
+                    disabled_status: Some(DisabledStatus::Forbidden),
+                    ..Default::default()
+                }),
+                memory_budget: Some(MemoryBudgetConfig {
+                    limit: 268_435_456,
+                    max_wait: Some(500),
+                    ..Default::default()
+                }),
+                fault_injection: None,
+                quotas: None,
+                cache_routing: None,
+                config_staging: None,
+                request_mirroring: None,
             }
         );
     }
+
+    #[test]
+    fn parse_fault_injection() {
+        let cfg = serde_yaml::from_str::<SrvConfig>(indoc! {"
+            fault_injection:
+              points:
+                latency: 200
+                jitter: 50
+                error_rate: 0.1
+        "})
+        .unwrap();
+        assert_eq!(
+            cfg.fault_injection,
+            Some(
+                [(
+                    "points".to_string(),
+                    FaultConfig {
+                        latency: Some(200),
+                        jitter: Some(50),
+                        error_rate: Some(0.1),
+                        seed: None,
+                    }
+                )]
+                .into()
+            )
+        );
+    }
 }
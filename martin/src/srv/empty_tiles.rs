@@ -0,0 +1,622 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::http::StatusCode;
+use actix_web::web::Data;
+use futures::{stream, StreamExt as _};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::source::TileSources;
+use crate::srv::server::get_tile_response;
+use crate::srv::MemoryBudget;
+use crate::utils::CronSchedule;
+use crate::TileCoord;
+
+pub const EMPTY_TILE_TTL_DEFAULT: u64 = 300;
+pub const EMPTY_TILE_MAX_ENTRIES_DEFAULT: usize = 100_000;
+pub const REFRESH_MIN_HITS_DEFAULT: u64 = 3;
+pub const REFRESH_BEFORE_EXPIRY_DEFAULT: u64 = 30;
+pub const REFRESH_CONCURRENCY_DEFAULT: usize = 2;
+pub const REFRESH_INTERVAL_DEFAULT: u64 = 10;
+
+/// Limits of the empty tile cache of a single source
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct EmptyTileLimits {
+    /// How long to remember that a tile is empty, in seconds
+    pub ttl: Option<u64>,
+    /// Maximum number of empty tiles to remember for the source
+    pub max_entries: Option<usize>,
+    /// Forget all empty tiles of the source on this cron schedule, e.g. `0 3 * * *` after a nightly data import
+    pub purge: Option<CronSchedule>,
+    /// Sources this source is derived from, e.g. a clustering function reading the table of another source.
+    /// Invalidating the empty tiles of any of them also invalidates this source.
+    /// Composite sources like `points,lines` always depend on each of their parts.
+    pub depends_on: Option<Vec<String>>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct EmptyTileCacheConfig {
+    /// Default limits for all sources
+    #[serde(flatten)]
+    pub defaults: EmptyTileLimits,
+    /// Per-source limits, overriding the defaults. Use `max_entries: 0` to disable caching for a source.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sources: BTreeMap<String, EmptyTileLimits>,
+    /// Re-check frequently requested empty tiles in the background shortly before they expire,
+    /// so that their requests keep being answered from the cache
+    pub refresh: Option<EmptyTileRefreshConfig>,
+}
+
+/// Background refresh of the hottest entries of the empty tile cache
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct EmptyTileRefreshConfig {
+    /// Only refresh tiles that were requested at least this many times since they were cached, defaults to 3
+    pub min_hits: Option<u64>,
+    /// Refresh tiles that expire within this many seconds, defaults to 30
+    pub before_expiry: Option<u64>,
+    /// Maximum number of tiles refreshed at the same time, defaults to 2
+    pub concurrency: Option<usize>,
+    /// How often to look for tiles to refresh, in seconds, defaults to 10
+    pub interval: Option<u64>,
+}
+
+type TileKey = (TileCoord, String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    expires: Instant,
+    /// Number of requests answered from this entry
+    hits: u64,
+}
+
+#[derive(Debug, Default)]
+struct SourceEntries {
+    /// Tile -> expiration and usage of the entry
+    expires: HashMap<TileKey, Entry>,
+    /// Tiles in the order they were added, used to evict the oldest entries first
+    order: VecDeque<(TileKey, Instant)>,
+    /// Approximate memory used by the entries
+    bytes: usize,
+}
+
+/// Approximate memory used by an entry, which is stored both in the map and in the queue
+fn entry_bytes(key: &TileKey) -> usize {
+    size_of::<(TileKey, Entry)>() + size_of::<(TileKey, Instant)>() + 2 * key.1.len()
+}
+
+/// Remembers which tiles were empty, so that repeated requests for them
+/// can be answered with `204 No Content` without querying the source.
+#[derive(Debug)]
+pub struct EmptyTileCache {
+    config: EmptyTileCacheConfig,
+    sources: Mutex<HashMap<String, SourceEntries>>,
+    memory: Option<Arc<MemoryBudget>>,
+}
+
+impl EmptyTileCache {
+    #[must_use]
+    pub fn new(config: EmptyTileCacheConfig) -> Self {
+        Self {
+            config,
+            sources: Mutex::new(HashMap::new()),
+            memory: None,
+        }
+    }
+
+    /// Count the entries against the memory budget, and stop growing the cache while memory is short
+    #[must_use]
+    pub fn with_memory_budget(mut self, memory: Option<Arc<MemoryBudget>>) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    fn limits(&self, source_ids: &str) -> (Duration, usize) {
+        let src = self.config.sources.get(source_ids);
+        let defaults = &self.config.defaults;
+        let ttl = src
+            .and_then(|v| v.ttl)
+            .or(defaults.ttl)
+            .unwrap_or(EMPTY_TILE_TTL_DEFAULT);
+        let max_entries = src
+            .and_then(|v| v.max_entries)
+            .or(defaults.max_entries)
+            .unwrap_or(EMPTY_TILE_MAX_ENTRIES_DEFAULT);
+        (Duration::from_secs(ttl), max_entries)
+    }
+
+    fn purge_schedule(&self, source_ids: &str) -> Option<&CronSchedule> {
+        let src = self.config.sources.get(source_ids);
+        src.and_then(|v| v.purge.as_ref())
+            .or(self.config.defaults.purge.as_ref())
+    }
+
+    /// True if any source is purged on a schedule
+    #[must_use]
+    pub fn has_purge_schedule(&self) -> bool {
+        self.config.defaults.purge.is_some()
+            || self.config.sources.values().any(|v| v.purge.is_some())
+    }
+
+    /// Forget the empty tiles of all sources whose purge schedule fires in the minute of `unix_secs`,
+    /// and of the sources depending on them. Returns the IDs of the purged sources.
+    pub fn purge_scheduled(&self, unix_secs: u64) -> Vec<String> {
+        let mut sources = self.sources.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut due: Vec<_> = sources
+            .keys()
+            .filter(|v| {
+                self.purge_schedule(v)
+                    .map_or(false, |v| v.matches(unix_secs))
+            })
+            .cloned()
+            .collect();
+        due.sort_unstable();
+        self.remove_with_dependents(&mut sources, due)
+    }
+
+    /// Forget the empty tiles of a source and of all sources depending on it,
+    /// and return the IDs of the sources that had any empty tiles cached
+    pub fn invalidate(&self, source_id: &str) -> Vec<String> {
+        let mut sources = self.sources.lock().unwrap_or_else(PoisonError::into_inner);
+        self.remove_with_dependents(&mut sources, vec![source_id.to_string()])
+    }
+
+    /// Forget all empty tiles, e.g. after the sources have been replaced
+    pub fn clear(&self) {
+        let mut sources = self.sources.lock().unwrap_or_else(PoisonError::into_inner);
+        for (_, entries) in sources.drain() {
+            if let Some(memory) = &self.memory {
+                memory.shrink(entries.bytes);
+            }
+        }
+    }
+
+    /// True if `source_ids` is a composite source containing `base`, or declares a dependency on it
+    fn depends_on(&self, source_ids: &str, base: &str) -> bool {
+        source_ids.split(',').any(|v| v == base)
+            || self
+                .config
+                .sources
+                .get(source_ids)
+                .and_then(|v| v.depends_on.as_ref())
+                .map_or(false, |v| v.iter().any(|v| v == base))
+    }
+
+    fn remove_with_dependents(
+        &self,
+        sources: &mut HashMap<String, SourceEntries>,
+        mut invalidated: Vec<String>,
+    ) -> Vec<String> {
+        // Dependencies may be chained through sources that have nothing cached themselves
+        let candidates: BTreeSet<_> = sources
+            .keys()
+            .chain(self.config.sources.keys())
+            .cloned()
+            .collect();
+        let mut i = 0;
+        while let Some(base) = invalidated.get(i).cloned() {
+            for src in &candidates {
+                if !invalidated.contains(src) && self.depends_on(src, &base) {
+                    debug!(
+                        "Invalidating the empty tile cache of {src} because it depends on {base}"
+                    );
+                    invalidated.push(src.clone());
+                }
+            }
+            i += 1;
+        }
+        invalidated.retain(|v| match sources.remove(v) {
+            Some(entries) => {
+                if let Some(memory) = &self.memory {
+                    memory.shrink(entries.bytes);
+                }
+                true
+            }
+            None => false,
+        });
+        invalidated
+    }
+
+    /// True if the tile was recently found to be empty
+    #[must_use]
+    pub fn is_empty(&self, source_ids: &str, xyz: TileCoord, query: &str) -> bool {
+        let mut sources = self.sources.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(entry) = sources
+            .get_mut(source_ids)
+            .and_then(|v| v.expires.get_mut(&(xyz, query.to_string())))
+        else {
+            return false;
+        };
+        if entry.expires <= Instant::now() {
+            return false;
+        }
+        entry.hits += 1;
+        true
+    }
+
+    /// Tiles that expire within `within` and were requested at least `min_hits` times, the most requested first
+    fn due_for_refresh(&self, within: Duration, min_hits: u64) -> Vec<(String, TileKey)> {
+        let now = Instant::now();
+        let sources = self.sources.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut due: Vec<_> = sources
+            .iter()
+            .flat_map(|(source_ids, entries)| {
+                // POST requests are only identified by the hash of their body, which cannot be re-sent
+                entries
+                    .expires
+                    .iter()
+                    .filter(move |(key, entry)| {
+                        entry.hits >= min_hits
+                            && entry.expires > now
+                            && entry.expires <= now + within
+                            && !key.1.starts_with("body=")
+                    })
+                    .map(move |(key, entry)| (entry.hits, source_ids.clone(), key.clone()))
+            })
+            .collect();
+        due.sort_unstable_by_key(|v| std::cmp::Reverse(v.0));
+        due.into_iter().map(|(_, src, key)| (src, key)).collect()
+    }
+
+    /// Forget a tile that is no longer empty
+    fn remove(&self, source_ids: &str, key: &TileKey) {
+        let mut sources = self.sources.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(entries) = sources.get_mut(source_ids) {
+            entries.expires.remove(key);
+        }
+    }
+
+    /// Remember that the tile is empty, evicting expired and the oldest entries if needed
+    pub fn insert(&self, source_ids: &str, xyz: TileCoord, query: &str) {
+        let (ttl, max_entries) = self.limits(source_ids);
+        if max_entries == 0 {
+            return;
+        }
+        let now = Instant::now();
+        // Replace the oldest entry instead of adding one while memory is short
+        let mut make_room = self
+            .memory
+            .as_ref()
+            .map_or(false, |v| v.is_under_pressure());
+        let mut sources = self.sources.lock().unwrap_or_else(PoisonError::into_inner);
+        let entries = sources.entry(source_ids.to_string()).or_default();
+        let mut freed = 0;
+        while let Some((key, expires)) = entries.order.front() {
+            if *expires > now && entries.order.len() < max_entries && !make_room {
+                break;
+            }
+            make_room = false;
+            // The tile may have been added again after this entry expired
+            if entries.expires.get(key).map(|v| v.expires) == Some(*expires) {
+                entries.expires.remove(key);
+            }
+            freed += entry_bytes(key);
+            entries.order.pop_front();
+        }
+        let key = (xyz, query.to_string());
+        let expires = now + ttl;
+        let added = entry_bytes(&key);
+        entries.bytes = entries.bytes + added - freed;
+        entries
+            .expires
+            .insert(key.clone(), Entry { expires, hits: 0 });
+        entries.order.push_back((key, expires));
+        if let Some(memory) = &self.memory {
+            memory.grow(added);
+            memory.shrink(freed);
+        }
+    }
+}
+
+/// Purge the empty tiles of each source on its schedule, checking the schedules at the start of every minute
+pub async fn purge_empty_tiles(cache: Data<EmptyTileCache>) {
+    let mut last_minute = 0;
+    loop {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |v| v.as_secs());
+        let minute = now / 60;
+        if minute != last_minute {
+            last_minute = minute;
+            for source_ids in cache.purge_scheduled(now) {
+                info!("Purged the empty tile cache of source {source_ids} on schedule");
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(60 - now % 60)).await;
+    }
+}
+
+/// Periodically re-query the hottest empty tiles before they expire, keeping them in the cache while they stay empty.
+/// Tiles that are no longer empty are removed from the cache, so that the next request gets the new content.
+pub async fn refresh_empty_tiles(
+    cache: Data<EmptyTileCache>,
+    sources: TileSources,
+    config: EmptyTileRefreshConfig,
+) {
+    let min_hits = config.min_hits.unwrap_or(REFRESH_MIN_HITS_DEFAULT);
+    let within = Duration::from_secs(
+        config
+            .before_expiry
+            .unwrap_or(REFRESH_BEFORE_EXPIRY_DEFAULT),
+    );
+    let concurrency = config
+        .concurrency
+        .unwrap_or(REFRESH_CONCURRENCY_DEFAULT)
+        .max(1);
+    let interval = config.interval.unwrap_or(REFRESH_INTERVAL_DEFAULT).max(1);
+    let mut interval = tokio::time::interval(Duration::from_secs(interval));
+    loop {
+        interval.tick().await;
+        let due = cache.due_for_refresh(within, min_hits);
+        if due.is_empty() {
+            continue;
+        }
+        debug!("Refreshing {} frequently requested empty tiles", due.len());
+        stream::iter(due)
+            .for_each_concurrent(concurrency, |(source_ids, key)| {
+                let (cache, sources) = (&cache, &sources);
+                async move {
+                    let (xyz, query) = &key;
+                    match get_tile_response(sources, *xyz, &source_ids, query, None).await {
+                        Ok(v) if v.status() == StatusCode::NO_CONTENT => {
+                            cache.insert(&source_ids, *xyz, query);
+                        }
+                        Ok(_) => cache.remove(&source_ids, &key),
+                        Err(e) => warn!("Unable to refresh empty tile {source_ids}/{xyz:#}: {e}"),
+                    }
+                }
+            })
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xyz(x: u32) -> TileCoord {
+        TileCoord { z: 10, x, y: 1 }
+    }
+
+    #[test]
+    fn empty_tile_cache() {
+        let cache = EmptyTileCache::new(EmptyTileCacheConfig {
+            defaults: EmptyTileLimits {
+                ttl: None,
+                max_entries: Some(2),
+                purge: None,
+                depends_on: None,
+            },
+            sources: BTreeMap::from([
+                (
+                    "expired".to_string(),
+                    EmptyTileLimits {
+                        ttl: Some(0),
+                        max_entries: None,
+                        purge: None,
+                        depends_on: None,
+                    },
+                ),
+                (
+                    "disabled".to_string(),
+                    EmptyTileLimits {
+                        ttl: None,
+                        max_entries: Some(0),
+                        purge: None,
+                        depends_on: None,
+                    },
+                ),
+            ]),
+            refresh: None,
+        });
+
+        cache.insert("a", xyz(1), "");
+        assert!(cache.is_empty("a", xyz(1), ""));
+        assert!(!cache.is_empty("a", xyz(1), "foo=bar"));
+        assert!(!cache.is_empty("b", xyz(1), ""));
+
+        cache.insert("a", xyz(2), "");
+        cache.insert("a", xyz(3), "");
+        assert!(!cache.is_empty("a", xyz(1), ""), "oldest entry is evicted");
+        assert!(cache.is_empty("a", xyz(2), ""));
+        assert!(cache.is_empty("a", xyz(3), ""));
+
+        cache.insert("expired", xyz(1), "");
+        assert!(!cache.is_empty("expired", xyz(1), ""));
+        cache.insert("disabled", xyz(1), "");
+        assert!(!cache.is_empty("disabled", xyz(1), ""));
+    }
+
+    #[test]
+    fn memory_budget() {
+        let memory = Arc::new(MemoryBudget::new(&crate::srv::MemoryBudgetConfig {
+            limit: 100 * entry_bytes(&(xyz(1), String::new())),
+            shed_above: Some(5),
+            ..Default::default()
+        }));
+        let cache = EmptyTileCache::new(EmptyTileCacheConfig::default())
+            .with_memory_budget(Some(memory.clone()));
+        cache.insert("a", xyz(1), "");
+        cache.insert("a", xyz(2), "foo=bar");
+        assert_eq!(
+            memory.used(),
+            entry_bytes(&(xyz(1), String::new())) + entry_bytes(&(xyz(2), "foo=bar".to_string()))
+        );
+        cache.invalidate("a");
+        assert_eq!(memory.used(), 0);
+
+        // The cache stops growing once memory is short
+        for x in 0..10 {
+            cache.insert("a", xyz(x), "");
+        }
+        assert_eq!(memory.used(), 5 * entry_bytes(&(xyz(1), String::new())));
+        assert!(cache.is_empty("a", xyz(9), ""));
+        assert!(!cache.is_empty("a", xyz(4), ""));
+    }
+
+    #[test]
+    fn hot_tiles_refresh() {
+        let cache = EmptyTileCache::new(EmptyTileCacheConfig {
+            defaults: EmptyTileLimits {
+                ttl: Some(20),
+                max_entries: None,
+                purge: None,
+                depends_on: None,
+            },
+            sources: BTreeMap::from([(
+                "long".to_string(),
+                EmptyTileLimits {
+                    ttl: Some(600),
+                    max_entries: None,
+                    purge: None,
+                    depends_on: None,
+                },
+            )]),
+            refresh: None,
+        });
+        let hit = |src: &str, x: u32, query: &str, count: usize| {
+            for _ in 0..count {
+                assert!(cache.is_empty(src, xyz(x), query));
+            }
+        };
+        cache.insert("a", xyz(1), "");
+        cache.insert("a", xyz(2), "");
+        cache.insert("a", xyz(3), "body=0123456789abcdef");
+        cache.insert("long", xyz(1), "");
+        hit("a", 1, "", 2);
+        hit("a", 2, "", 5);
+        hit("a", 3, "body=0123456789abcdef", 5);
+        hit("long", 1, "", 5);
+
+        let within = Duration::from_secs(30);
+        let key = |x: u32| (xyz(x), String::new());
+        // The hottest tiles come first, rarely used, POST, and long-lived entries are skipped
+        assert_eq!(
+            cache.due_for_refresh(within, 2),
+            vec![("a".to_string(), key(2)), ("a".to_string(), key(1))]
+        );
+        assert_eq!(
+            cache.due_for_refresh(within, 3),
+            vec![("a".to_string(), key(2))]
+        );
+
+        // Refreshed entries start counting again
+        cache.insert("a", xyz(2), "");
+        assert_eq!(
+            cache.due_for_refresh(within, 2),
+            vec![("a".to_string(), key(1))]
+        );
+        cache.remove("a", &key(1));
+        assert!(!cache.is_empty("a", xyz(1), ""));
+        assert!(cache.due_for_refresh(within, 2).is_empty());
+    }
+
+    #[test]
+    fn scheduled_purge() {
+        let schedule = |v: &str| Some(CronSchedule::parse(v).unwrap());
+        let cache = EmptyTileCache::new(EmptyTileCacheConfig {
+            defaults: EmptyTileLimits {
+                purge: schedule("0 3 * * *"),
+                ..Default::default()
+            },
+            sources: BTreeMap::from([
+                (
+                    "hourly".to_string(),
+                    EmptyTileLimits {
+                        purge: schedule("@hourly"),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "later".to_string(),
+                    EmptyTileLimits {
+                        purge: schedule("0 4 * * *"),
+                        ..Default::default()
+                    },
+                ),
+            ]),
+            refresh: None,
+        });
+        assert!(cache.has_purge_schedule());
+        for src in ["a", "hourly", "later"] {
+            cache.insert(src, xyz(1), "");
+        }
+        // 2024-02-29 02:00 UTC
+        let night = 1_709_172_000;
+        assert_eq!(cache.purge_scheduled(night + 60), Vec::<String>::new());
+        assert_eq!(cache.purge_scheduled(night), vec!["hourly".to_string()]);
+        assert!(!cache.is_empty("hourly", xyz(1), ""));
+        assert_eq!(cache.purge_scheduled(night + 3600), vec!["a".to_string()]);
+        assert!(!cache.is_empty("a", xyz(1), ""));
+        assert!(cache.is_empty("later", xyz(1), ""));
+
+        let cache = EmptyTileCache::new(EmptyTileCacheConfig::default());
+        assert!(!cache.has_purge_schedule());
+    }
+
+    #[test]
+    fn cascading_invalidation() {
+        let depends_on = |v: &[&str]| EmptyTileLimits {
+            depends_on: Some(v.iter().map(ToString::to_string).collect()),
+            ..Default::default()
+        };
+        let cache = EmptyTileCache::new(EmptyTileCacheConfig {
+            defaults: EmptyTileLimits::default(),
+            sources: BTreeMap::from([
+                ("clusters".to_string(), depends_on(&["points"])),
+                ("heatmap".to_string(), depends_on(&["clusters"])),
+                (
+                    "points".to_string(),
+                    EmptyTileLimits {
+                        purge: Some(CronSchedule::parse("@hourly").unwrap()),
+                        ..Default::default()
+                    },
+                ),
+            ]),
+            refresh: None,
+        });
+        let all = [
+            "points",
+            "clusters",
+            "heatmap",
+            "lines",
+            "points,lines",
+            "heatmap,lines",
+        ];
+        let insert_all = || {
+            for src in all {
+                cache.insert(src, xyz(1), "");
+            }
+        };
+        insert_all();
+        assert_eq!(
+            cache.invalidate("lines"),
+            vec!["lines", "heatmap,lines", "points,lines"]
+        );
+        assert!(cache.is_empty("points", xyz(1), ""));
+        assert_eq!(
+            cache.invalidate("points"),
+            vec!["points", "clusters", "heatmap"]
+        );
+        assert!(cache.invalidate("points").is_empty());
+        assert!(!cache.is_empty("heatmap", xyz(1), ""));
+
+        insert_all();
+        assert_eq!(
+            cache.purge_scheduled(1_709_172_000),
+            vec![
+                "points",
+                "clusters",
+                "points,lines",
+                "heatmap",
+                "heatmap,lines"
+            ]
+        );
+        assert!(cache.is_empty("lines", xyz(1), ""));
+        assert!(cache.invalidate("unknown").is_empty());
+    }
+}
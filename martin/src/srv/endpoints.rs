@@ -0,0 +1,139 @@
+use std::fmt::{Display, Formatter};
+
+use actix_web::error::{ErrorForbidden, ErrorNotFound};
+use actix_web::web::Data;
+use actix_web::HttpRequest;
+use serde::{Deserialize, Serialize};
+
+/// Groups of endpoints that can be disabled with the `endpoints` config
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EndpointGroup {
+    /// `/catalog`
+    Catalog,
+    /// `/{source_ids}` and `/tilejson`
+    TileJson,
+    /// `/sprite/…`
+    Sprites,
+    /// `/font/…`
+    Fonts,
+    /// `/`
+    WebUi,
+    /// `/status`, `/_/log`, `/_/config`, and `/_/mirror`
+    Admin,
+}
+
+impl Display for EndpointGroup {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Catalog => "catalog",
+            Self::TileJson => "tilejson",
+            Self::Sprites => "sprites",
+            Self::Fonts => "fonts",
+            Self::WebUi => "webui",
+            Self::Admin => "admin",
+        })
+    }
+}
+
+/// Status code of the responses of disabled endpoints
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "u16", into = "u16")]
+pub enum DisabledStatus {
+    #[default]
+    NotFound,
+    Forbidden,
+}
+
+impl TryFrom<u16> for DisabledStatus {
+    type Error = String;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            404 => Ok(Self::NotFound),
+            403 => Ok(Self::Forbidden),
+            _ => Err(format!(
+                "Disabled endpoints must respond with 404 or 403, got {value}"
+            )),
+        }
+    }
+}
+
+impl From<DisabledStatus> for u16 {
+    fn from(value: DisabledStatus) -> Self {
+        match value {
+            DisabledStatus::NotFound => 404,
+            DisabledStatus::Forbidden => 403,
+        }
+    }
+}
+
+/// Endpoint groups to serve, all of them by default. Tiles and `/health` are always served.
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EndpointsConfig {
+    pub catalog: Option<bool>,
+    pub tilejson: Option<bool>,
+    pub sprites: Option<bool>,
+    pub fonts: Option<bool>,
+    pub webui: Option<bool>,
+    pub admin: Option<bool>,
+    /// Respond to the requests of disabled endpoints with `404 Not Found` or `403 Forbidden` [default: 404]
+    pub disabled_status: Option<DisabledStatus>,
+}
+
+impl EndpointsConfig {
+    #[must_use]
+    pub fn is_enabled(&self, group: EndpointGroup) -> bool {
+        let enabled = match group {
+            EndpointGroup::Catalog => self.catalog,
+            EndpointGroup::TileJson => self.tilejson,
+            EndpointGroup::Sprites => self.sprites,
+            EndpointGroup::Fonts => self.fonts,
+            EndpointGroup::WebUi => self.webui,
+            EndpointGroup::Admin => self.admin,
+        };
+        enabled.unwrap_or(true)
+    }
+
+    /// Fail the request if the endpoint group is disabled in the `endpoints` config of the server
+    pub fn check(req: &HttpRequest, group: EndpointGroup) -> actix_web::Result<()> {
+        let Some(config) = req.app_data::<Data<Self>>() else {
+            return Ok(());
+        };
+        if config.is_enabled(group) {
+            return Ok(());
+        }
+        let message = format!("The {group} endpoints are disabled");
+        Err(match config.disabled_status.unwrap_or_default() {
+            DisabledStatus::NotFound => ErrorNotFound(message),
+            DisabledStatus::Forbidden => ErrorForbidden(message),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn disabled_endpoints() {
+        let config: EndpointsConfig =
+            serde_yaml::from_str("catalog: false\nadmin: true\ndisabled_status: 403").unwrap();
+        assert!(!config.is_enabled(EndpointGroup::Catalog));
+        assert!(config.is_enabled(EndpointGroup::Admin));
+        assert!(config.is_enabled(EndpointGroup::Fonts));
+        assert!(serde_yaml::from_str::<EndpointsConfig>("disabled_status: 500").is_err());
+
+        let req = TestRequest::default()
+            .app_data(Data::new(config))
+            .to_http_request();
+        let err = EndpointsConfig::check(&req, EndpointGroup::Catalog).unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), 403);
+        EndpointsConfig::check(&req, EndpointGroup::Sprites).unwrap();
+        // Everything is enabled without the config
+        let req = TestRequest::default().to_http_request();
+        EndpointsConfig::check(&req, EndpointGroup::Catalog).unwrap();
+    }
+}
@@ -0,0 +1,525 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::{MartinResult, TileCoord};
+
+/// Number of events that may wait to be published before new events are dropped
+pub const EVENTS_BUFFER_SIZE_DEFAULT: usize = 10_000;
+pub const NATS_SUBJECT_DEFAULT: &str = "martin.events";
+pub const KAFKA_TOPIC_DEFAULT: &str = "martin-events";
+
+/// Events are published in batches of at most this many events
+const BATCH_SIZE: usize = 100;
+/// Wait this long before reconnecting to a sink that failed
+#[cfg(any(feature = "nats", feature = "kafka"))]
+const RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct EventsConfig {
+    /// Publish the events to this NATS server. Requires the `nats` feature.
+    pub nats: Option<NatsConfig>,
+    /// Publish the events to this Kafka cluster. Requires the `kafka` feature.
+    pub kafka: Option<KafkaConfig>,
+    /// Number of events that may wait to be published, defaults to 10000. When the sinks cannot keep up, new events are dropped.
+    pub buffer_size: Option<usize>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct NatsConfig {
+    /// Server URL, e.g. `nats://localhost:4222`
+    pub url: String,
+    /// Subject of the published messages, defaults to `martin.events`
+    pub subject: Option<String>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct KafkaConfig {
+    /// Bootstrap brokers, e.g. `localhost:9092`
+    pub brokers: Vec<String>,
+    /// Topic of the published records, defaults to `martin-events`. The topic must exist.
+    pub topic: Option<String>,
+    /// Partition of the topic to publish to, defaults to 0
+    pub partition: Option<i32>,
+}
+
+/// A structured event about a tile request, published as a JSON object
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TileEvent {
+    /// Milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    /// Source ID, or comma-separated list of source IDs for composite sources
+    pub source: String,
+    pub z: u8,
+    pub x: u32,
+    pub y: u32,
+    /// URL query string without the leading `?`
+    pub query: Option<String>,
+    #[serde(flatten)]
+    pub kind: TileEventKind,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TileEventKind {
+    /// A tile was returned to the client. Empty tiles have the `204` status.
+    TileServed {
+        status: u16,
+        /// Size of the response body, in bytes
+        size: u64,
+        /// `Content-Encoding` of the response
+        encoding: Option<String>,
+        duration_ms: f64,
+    },
+    /// An empty tile was answered from the empty tile cache without querying the source
+    CacheHit { cache: String },
+    /// The tile was not in the empty tile cache, so the source was queried
+    CacheMiss { cache: String },
+    /// The request was invalid, e.g. an unknown source or a zoom level out of range
+    RequestError { status: u16, error: String },
+    /// The tile could not be generated
+    SourceError { status: u16, error: String },
+}
+
+impl TileEvent {
+    #[must_use]
+    pub fn new(source: &str, xyz: TileCoord, query: &str, kind: TileEventKind) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |v| u64::try_from(v.as_millis()).unwrap_or(u64::MAX));
+        Self {
+            timestamp_ms,
+            source: source.to_string(),
+            z: xyz.z,
+            x: xyz.x,
+            y: xyz.y,
+            query: (!query.is_empty()).then(|| query.to_string()),
+            kind,
+        }
+    }
+}
+
+/// A message broker that events are published to
+#[async_trait]
+pub trait EventPublisher: Send {
+    /// Name used in logs
+    fn name(&self) -> String;
+
+    /// Publish a batch of JSON-encoded events
+    async fn publish(&mut self, events: &[Bytes]) -> Result<(), String>;
+}
+
+/// Publishes events to the configured sinks in the background, so that tile requests never wait for them
+#[derive(Debug)]
+pub struct EventSink {
+    sender: Sender<TileEvent>,
+    dropped: AtomicU64,
+}
+
+impl EventSink {
+    /// Start publishing events to the configured sinks. Must be called within a Tokio runtime.
+    pub fn new(config: &EventsConfig) -> MartinResult<Self> {
+        let mut publishers: Vec<Box<dyn EventPublisher>> = Vec::new();
+        if let Some(cfg) = &config.nats {
+            publishers.push(nats_publisher(cfg)?);
+        }
+        if let Some(cfg) = &config.kafka {
+            publishers.push(kafka_publisher(cfg)?);
+        }
+        for publisher in &publishers {
+            info!("Publishing tile events to {}", publisher.name());
+        }
+        let buffer_size = config.buffer_size.unwrap_or(EVENTS_BUFFER_SIZE_DEFAULT);
+        Ok(Self::with_publishers(publishers, buffer_size))
+    }
+
+    #[must_use]
+    pub fn with_publishers(publishers: Vec<Box<dyn EventPublisher>>, buffer_size: usize) -> Self {
+        let (sender, receiver) = channel(buffer_size.max(1));
+        tokio::spawn(publish_events(receiver, publishers));
+        Self {
+            sender,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue an event for publishing, or drop it if too many events are waiting
+    pub fn emit(&self, event: TileEvent) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(event) {
+            // Only warn occasionally to avoid flooding the log while the sinks are too slow
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed);
+            if dropped % 10_000 == 0 {
+                warn!(
+                    "Event sinks cannot keep up, {} events were dropped",
+                    dropped + 1
+                );
+            }
+        }
+    }
+
+    /// Number of events dropped because the sinks could not keep up
+    #[must_use]
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+async fn publish_events(
+    mut receiver: Receiver<TileEvent>,
+    mut publishers: Vec<Box<dyn EventPublisher>>,
+) {
+    while let Some(event) = receiver.recv().await {
+        let mut events = vec![event];
+        while events.len() < BATCH_SIZE {
+            match receiver.try_recv() {
+                Ok(event) => events.push(event),
+                Err(_) => break,
+            }
+        }
+        let batch: Vec<Bytes> = events
+            .iter()
+            .map(|v| Bytes::from(serde_json::to_vec(v).expect("event is serializable")))
+            .collect();
+        for publisher in &mut publishers {
+            if let Err(e) = publisher.publish(&batch).await {
+                warn!(
+                    "Unable to publish {} events to {}: {e}",
+                    batch.len(),
+                    publisher.name()
+                );
+            }
+        }
+    }
+}
+
+#[cfg(feature = "nats")]
+#[allow(clippy::unnecessary_wraps)] // same signature as without the feature
+fn nats_publisher(cfg: &NatsConfig) -> MartinResult<Box<dyn EventPublisher>> {
+    Ok(Box::new(nats::NatsPublisher::new(cfg)))
+}
+
+#[cfg(not(feature = "nats"))]
+fn nats_publisher(_cfg: &NatsConfig) -> MartinResult<Box<dyn EventPublisher>> {
+    Err(crate::MartinError::EventSinkNotSupported("nats"))
+}
+
+#[cfg(feature = "kafka")]
+#[allow(clippy::unnecessary_wraps)] // same signature as without the feature
+fn kafka_publisher(cfg: &KafkaConfig) -> MartinResult<Box<dyn EventPublisher>> {
+    Ok(Box::new(kafka::KafkaPublisher::new(cfg)))
+}
+
+#[cfg(not(feature = "kafka"))]
+fn kafka_publisher(_cfg: &KafkaConfig) -> MartinResult<Box<dyn EventPublisher>> {
+    Err(crate::MartinError::EventSinkNotSupported("kafka"))
+}
+
+/// Connects lazily, and reconnects after a delay if the connection fails,
+/// so that a broker outage only drops events instead of stopping the server
+#[cfg(any(feature = "nats", feature = "kafka"))]
+#[derive(Debug)]
+struct Reconnect<T> {
+    client: Option<T>,
+    failed_at: Option<std::time::Instant>,
+}
+
+#[cfg(any(feature = "nats", feature = "kafka"))]
+impl<T> Default for Reconnect<T> {
+    fn default() -> Self {
+        Self {
+            client: None,
+            failed_at: None,
+        }
+    }
+}
+
+#[cfg(any(feature = "nats", feature = "kafka"))]
+impl<T> Reconnect<T> {
+    /// Return the connected client, connecting first if needed. While waiting to reconnect
+    /// after a failure, return `None`, so that the events are dropped without logging an error for each batch.
+    async fn get<F, Fut>(&mut self, connect: F) -> Result<Option<&mut T>, String>
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<T, String>>,
+    {
+        if self.client.is_none() {
+            if self
+                .failed_at
+                .map_or(false, |v| v.elapsed() < RECONNECT_DELAY)
+            {
+                return Ok(None);
+            }
+            match connect().await {
+                Ok(client) => {
+                    self.client = Some(client);
+                    self.failed_at = None;
+                }
+                Err(e) => {
+                    self.failed_at = Some(std::time::Instant::now());
+                    return Err(format!("unable to connect: {e}"));
+                }
+            }
+        }
+        Ok(self.client.as_mut())
+    }
+
+    /// Drop the client after an error, so that a later batch reconnects
+    fn reset(&mut self) {
+        self.client = None;
+        self.failed_at = Some(std::time::Instant::now());
+    }
+}
+
+#[cfg(feature = "nats")]
+mod nats {
+    use async_trait::async_trait;
+    use bytes::Bytes;
+
+    use super::{EventPublisher, NatsConfig, Reconnect, NATS_SUBJECT_DEFAULT};
+
+    pub struct NatsPublisher {
+        url: String,
+        subject: String,
+        client: Reconnect<async_nats::Client>,
+    }
+
+    impl NatsPublisher {
+        pub fn new(cfg: &NatsConfig) -> Self {
+            Self {
+                url: cfg.url.clone(),
+                subject: cfg
+                    .subject
+                    .clone()
+                    .unwrap_or_else(|| NATS_SUBJECT_DEFAULT.to_string()),
+                client: Reconnect::default(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl EventPublisher for NatsPublisher {
+        fn name(&self) -> String {
+            format!("NATS {} subject {}", self.url, self.subject)
+        }
+
+        async fn publish(&mut self, events: &[Bytes]) -> Result<(), String> {
+            let url = self.url.clone();
+            let client = self
+                .client
+                .get(|| async move { async_nats::connect(url).await.map_err(|e| e.to_string()) })
+                .await?;
+            let Some(client) = client else {
+                return Ok(());
+            };
+            let mut result = Ok(());
+            for event in events {
+                if let Err(e) = client.publish(self.subject.clone(), event.clone()).await {
+                    result = Err(e.to_string());
+                    break;
+                }
+            }
+            if result.is_ok() {
+                result = client.flush().await.map_err(|e| e.to_string());
+            }
+            if result.is_err() {
+                self.client.reset();
+            }
+            result
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+mod kafka {
+    use std::collections::BTreeMap;
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use rskafka::client::partition::{Compression, PartitionClient, UnknownTopicHandling};
+    use rskafka::client::ClientBuilder;
+    use rskafka::record::Record;
+
+    use super::{EventPublisher, KafkaConfig, Reconnect, KAFKA_TOPIC_DEFAULT};
+
+    pub struct KafkaPublisher {
+        brokers: Vec<String>,
+        topic: String,
+        partition: i32,
+        client: Reconnect<PartitionClient>,
+    }
+
+    impl KafkaPublisher {
+        pub fn new(cfg: &KafkaConfig) -> Self {
+            Self {
+                brokers: cfg.brokers.clone(),
+                topic: cfg
+                    .topic
+                    .clone()
+                    .unwrap_or_else(|| KAFKA_TOPIC_DEFAULT.to_string()),
+                partition: cfg.partition.unwrap_or_default(),
+                client: Reconnect::default(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl EventPublisher for KafkaPublisher {
+        fn name(&self) -> String {
+            format!(
+                "Kafka {} topic {} partition {}",
+                self.brokers.join(","),
+                self.topic,
+                self.partition
+            )
+        }
+
+        async fn publish(&mut self, events: &[Bytes]) -> Result<(), String> {
+            let (brokers, topic, partition) =
+                (self.brokers.clone(), self.topic.clone(), self.partition);
+            let client = self
+                .client
+                .get(|| async move {
+                    let client = ClientBuilder::new(brokers)
+                        .build()
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    client
+                        .partition_client(topic, partition, UnknownTopicHandling::Error)
+                        .await
+                        .map_err(|e| e.to_string())
+                })
+                .await?;
+            let Some(client) = client else {
+                return Ok(());
+            };
+            let records = events
+                .iter()
+                .map(|v| Record {
+                    key: None,
+                    value: Some(v.to_vec()),
+                    headers: BTreeMap::new(),
+                    timestamp: chrono::Utc::now(),
+                })
+                .collect();
+            let result = client
+                .produce(records, Compression::NoCompression)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string());
+            if result.is_err() {
+                self.client.reset();
+            }
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::*;
+
+    struct TestPublisher(Arc<Mutex<Vec<Bytes>>>);
+
+    #[async_trait]
+    impl EventPublisher for TestPublisher {
+        fn name(&self) -> String {
+            "test".to_string()
+        }
+
+        async fn publish(&mut self, events: &[Bytes]) -> Result<(), String> {
+            self.0.lock().unwrap().extend_from_slice(events);
+            Ok(())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn publish_events() {
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let sink = EventSink::with_publishers(vec![Box::new(TestPublisher(published.clone()))], 10);
+        let xyz = TileCoord { z: 1, x: 2, y: 3 };
+        sink.emit(TileEvent::new(
+            "points",
+            xyz,
+            "",
+            TileEventKind::CacheMiss {
+                cache: "empty_tiles".to_string(),
+            },
+        ));
+        sink.emit(TileEvent::new(
+            "a,b",
+            xyz,
+            "foo=1",
+            TileEventKind::SourceError {
+                status: 500,
+                error: "boom".to_string(),
+            },
+        ));
+        for _ in 0..100 {
+            if published.lock().unwrap().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let events: Vec<serde_json::Value> = published
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|v| serde_json::from_slice(v).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["type"], "cache_miss");
+        assert_eq!(events[0]["source"], "points");
+        assert_eq!(events[0]["cache"], "empty_tiles");
+        assert!(events[0].get("query").is_none());
+        assert!(events[0]["timestamp_ms"].as_u64().unwrap() > 0);
+        assert_eq!(events[1]["type"], "source_error");
+        assert_eq!(events[1]["query"], "foo=1");
+        assert_eq!(events[1]["status"], 500);
+        assert_eq!(sink.dropped(), 0);
+    }
+
+    #[test]
+    fn parse_config() {
+        let cfg: EventsConfig = serde_yaml::from_str(
+            "
+nats:
+  url: nats://localhost:4222
+kafka:
+  brokers: [localhost:9092]
+  topic: tiles
+buffer_size: 100
+",
+        )
+        .unwrap();
+        assert_eq!(
+            cfg,
+            EventsConfig {
+                nats: Some(NatsConfig {
+                    url: "nats://localhost:4222".to_string(),
+                    subject: None,
+                }),
+                kafka: Some(KafkaConfig {
+                    brokers: vec!["localhost:9092".to_string()],
+                    topic: Some("tiles".to_string()),
+                    partition: None,
+                }),
+                buffer_size: Some(100),
+            }
+        );
+    }
+}
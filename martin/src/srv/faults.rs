@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use log::warn;
+use martin_tile_utils::TileInfo;
+use serde::{Deserialize, Serialize};
+use tilejson::TileJSON;
+
+use crate::source::{
+    CatalogSourceEntry, FeatureFilter, Source, SourceFieldStats, TileData, UrlQuery,
+};
+use crate::{MartinError, MartinResult, TileCoord};
+
+/// Fault injection is ignored unless this environment variable is set to `true`,
+/// so that a test config cannot degrade a production server by accident
+pub const FAULT_INJECTION_ENV: &str = "MARTIN_FAULT_INJECTION";
+
+/// Faults injected into the tile requests of a single source
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct FaultConfig {
+    /// Delay added to each tile request, in milliseconds
+    pub latency: Option<u64>,
+    /// Maximum random delay added on top of `latency`, in milliseconds
+    pub jitter: Option<u64>,
+    /// Fraction of tile requests that fail without querying the source, from 0 to 1
+    pub error_rate: Option<f64>,
+    /// Seed of the random generator, to make the injected faults reproducible
+    pub seed: Option<u64>,
+}
+
+/// Check whether fault injection is allowed by the environment
+#[must_use]
+pub fn fault_injection_enabled() -> bool {
+    std::env::var(FAULT_INJECTION_ENV).is_ok_and(|v| v.eq_ignore_ascii_case("true") || v == "1")
+}
+
+/// Faults of a single source, and the state of its random generator
+#[derive(Debug)]
+pub struct SourceFaults {
+    latency: Duration,
+    jitter: u64,
+    error_rate: f64,
+    rng: AtomicU64,
+}
+
+impl SourceFaults {
+    #[must_use]
+    pub fn new(config: &FaultConfig) -> Self {
+        let seed = config.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |v| v.as_secs() ^ u64::from(v.subsec_nanos()))
+        });
+        Self {
+            latency: Duration::from_millis(config.latency.unwrap_or_default()),
+            jitter: config.jitter.unwrap_or_default(),
+            error_rate: config.error_rate.unwrap_or_default().clamp(0.0, 1.0),
+            rng: AtomicU64::new(seed),
+        }
+    }
+
+    /// Wrap the source so that its tile requests are delayed and failed as configured
+    #[must_use]
+    pub fn wrap(faults: &Arc<Self>, source: Box<dyn Source>) -> Box<dyn Source> {
+        Box::new(FaultySource {
+            source,
+            faults: faults.clone(),
+        })
+    }
+
+    /// Delay of the next request, and whether it should fail
+    pub fn next_fault(&self) -> (Duration, bool) {
+        let mut delay = self.latency;
+        if self.jitter > 0 {
+            delay += Duration::from_millis(self.next_random() % (self.jitter + 1));
+        }
+        #[allow(clippy::cast_precision_loss)]
+        let fail = self.error_rate > 0.0
+            && ((self.next_random() >> 11) as f64 / (1_u64 << 53) as f64) < self.error_rate;
+        (delay, fail)
+    }
+
+    /// Next value of a splitmix64 generator, which is good enough to pick the failing requests
+    fn next_random(&self) -> u64 {
+        let mut z = self
+            .rng
+            .fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+            .wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Faults of each source if enabled with the `fault_injection` config and the environment variable
+#[derive(Debug, Default)]
+pub struct FaultInjection {
+    sources: BTreeMap<String, Arc<SourceFaults>>,
+}
+
+impl FaultInjection {
+    /// Create the faults of the configured sources, or `None` if fault injection is not allowed
+    #[must_use]
+    pub fn new(config: &BTreeMap<String, FaultConfig>) -> Option<Self> {
+        if !fault_injection_enabled() {
+            warn!("Ignoring the fault_injection config because {FAULT_INJECTION_ENV} is not set to true");
+            return None;
+        }
+        for (id, cfg) in config {
+            warn!("Injecting faults into source {id}: {cfg:?}");
+        }
+        Some(Self {
+            sources: config
+                .iter()
+                .map(|(id, cfg)| (id.clone(), Arc::new(SourceFaults::new(cfg))))
+                .collect(),
+        })
+    }
+
+    /// Wrap the source if it has faults configured, and return it unchanged otherwise
+    #[must_use]
+    pub fn wrap(&self, source: Box<dyn Source>) -> Box<dyn Source> {
+        match self.sources.get(source.get_id()) {
+            Some(faults) => SourceFaults::wrap(faults, source),
+            None => source,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FaultySource {
+    source: Box<dyn Source>,
+    faults: Arc<SourceFaults>,
+}
+
+#[async_trait]
+impl Source for FaultySource {
+    fn get_id(&self) -> &str {
+        self.source.get_id()
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        self.source.get_tilejson()
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.source.get_tile_info()
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.source.support_url_query()
+    }
+
+    fn get_tile_size(&self) -> u16 {
+        self.source.get_tile_size()
+    }
+
+    fn include_feature_count(&self) -> bool {
+        self.source.include_feature_count()
+    }
+
+    fn vary_header(&self) -> Option<&str> {
+        self.source.vary_header()
+    }
+
+    async fn get_tile(&self, xyz: &TileCoord, query: &Option<UrlQuery>) -> MartinResult<TileData> {
+        let (delay, fail) = self.faults.next_fault();
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        if fail {
+            return Err(MartinError::InjectedFault(self.get_id().to_string()));
+        }
+        self.source.get_tile(xyz, query).await
+    }
+
+    async fn get_field_stats(&self) -> MartinResult<Option<SourceFieldStats>> {
+        self.source.get_field_stats().await
+    }
+
+    fn supports_features(&self) -> bool {
+        self.source.supports_features()
+    }
+
+    async fn get_features(
+        &self,
+        filter: &FeatureFilter,
+    ) -> MartinResult<Option<Vec<serde_json::Value>>> {
+        self.source.get_features(filter).await
+    }
+
+    fn is_valid_zoom(&self, zoom: u8) -> bool {
+        self.source.is_valid_zoom(zoom)
+    }
+
+    fn get_catalog_entry(&self) -> CatalogSourceEntry {
+        self.source.get_catalog_entry()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn injected_faults() {
+        let faults = SourceFaults::new(&FaultConfig {
+            latency: Some(100),
+            jitter: Some(50),
+            error_rate: Some(0.25),
+            seed: Some(42),
+        });
+        let samples: Vec<_> = (0..1000).map(|_| faults.next_fault()).collect();
+        let failed = samples.iter().filter(|(_, fail)| *fail).count();
+        assert!((200..300).contains(&failed), "{failed} failed requests");
+        assert!(samples
+            .iter()
+            .all(|(d, _)| (100..=150).contains(&d.as_millis())));
+        assert!(samples.iter().any(|(d, _)| d.as_millis() > 140));
+
+        let same = SourceFaults::new(&FaultConfig {
+            latency: Some(100),
+            jitter: Some(50),
+            error_rate: Some(0.25),
+            seed: Some(42),
+        });
+        assert_eq!(
+            same.next_fault(),
+            samples[0],
+            "the seed makes faults reproducible"
+        );
+
+        let none = SourceFaults::new(&FaultConfig::default());
+        assert!((0..100).all(|_| none.next_fault() == (Duration::ZERO, false)));
+        let all = SourceFaults::new(&FaultConfig {
+            error_rate: Some(1.0),
+            ..Default::default()
+        });
+        assert!((0..100).all(|_| all.next_fault().1));
+    }
+}
@@ -0,0 +1,289 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tilejson::Bounds;
+
+use crate::source::{FeatureFilter, Source};
+use crate::utils::encode_source_id;
+
+/// Number of features returned by the `/collections/{id}/items` endpoint without a `limit` parameter
+pub const FEATURES_LIMIT_DEFAULT: usize = 10;
+/// Larger `limit` values are reduced to this value
+pub const FEATURES_LIMIT_MAX: usize = 10_000;
+
+/// Query parameters of the `/collections/{id}/items` endpoint
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ItemsQuery {
+    /// `minx,miny,maxx,maxy` in WGS84, or the same with min and max heights
+    pub bbox: Option<String>,
+    /// A date-time, or an interval `start/end` where either end may be `..` or empty
+    pub datetime: Option<String>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+impl ItemsQuery {
+    /// Validate the parameters, returning an error message if they are invalid
+    pub fn to_filter(&self) -> Result<FeatureFilter, String> {
+        Ok(FeatureFilter {
+            bbox: self.bbox.as_deref().map(parse_bbox).transpose()?,
+            datetime: self.datetime.as_deref().map(parse_datetime).transpose()?,
+            limit: self
+                .limit
+                .unwrap_or(FEATURES_LIMIT_DEFAULT)
+                .min(FEATURES_LIMIT_MAX),
+            offset: self.offset.unwrap_or_default(),
+        })
+    }
+}
+
+fn parse_bbox(bbox: &str) -> Result<Bounds, String> {
+    let values = bbox
+        .split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid bbox {bbox}: {e}"))?;
+    match values.as_slice() {
+        [left, bottom, right, top] | [left, bottom, _, right, top, _] => {
+            Ok(Bounds::new(*left, *bottom, *right, *top))
+        }
+        _ => Err(format!("Invalid bbox {bbox}: expected 4 or 6 numbers")),
+    }
+}
+
+fn parse_datetime(datetime: &str) -> Result<(Option<String>, Option<String>), String> {
+    static RE_DATETIME: OnceLock<Regex> = OnceLock::new();
+    let re = RE_DATETIME.get_or_init(|| {
+        Regex::new(r"^\d{4}-\d{2}-\d{2}([Tt ]\d{2}:\d{2}:\d{2}(\.\d+)?([Zz]|[+-]\d{2}:\d{2})?)?$")
+            .unwrap()
+    });
+    let parse = |v: &str| match v {
+        "" | ".." => Ok(None),
+        v if re.is_match(v) => Ok(Some(v.to_string())),
+        v => Err(format!(
+            "Invalid datetime {v}: expected an RFC 3339 date or date-time"
+        )),
+    };
+    let interval = if let Some((start, end)) = datetime.split_once('/') {
+        (parse(start)?, parse(end)?)
+    } else {
+        let instant = parse(datetime)?;
+        (instant.clone(), instant)
+    };
+    if interval == (None, None) {
+        return Err(format!(
+            "Invalid datetime {datetime}: at least one end must be set"
+        ));
+    }
+    Ok(interval)
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Link {
+    pub href: String,
+    pub rel: String,
+    #[serde(rename = "type")]
+    pub typ: Option<String>,
+}
+
+impl Link {
+    #[must_use]
+    pub fn new(href: String, rel: &str, typ: &str) -> Self {
+        Self {
+            href,
+            rel: rel.to_string(),
+            typ: Some(typ.to_string()),
+        }
+    }
+}
+
+/// Response of the `/collections` endpoint
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Collections {
+    pub collections: Vec<Collection>,
+    pub links: Vec<Link>,
+}
+
+/// A source that can return its individual features, as described by OGC API Features
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Collection {
+    pub id: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub extent: Option<Extent>,
+    pub item_type: String,
+    pub links: Vec<Link>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Extent {
+    pub spatial: SpatialExtent,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SpatialExtent {
+    pub bbox: Vec<Bounds>,
+}
+
+impl Collection {
+    /// Describe the source, with links relative to `base_url`, the URL of the `/collections` endpoint
+    #[must_use]
+    pub fn new(src: &dyn Source, base_url: &str) -> Self {
+        let id = src.get_id();
+        let tj = src.get_tilejson();
+        let url = format!("{base_url}/{}", encode_source_id(id));
+        Self {
+            id: id.to_string(),
+            title: tj.name.clone(),
+            description: tj.description.clone(),
+            extent: tj.bounds.map(|v| Extent {
+                spatial: SpatialExtent { bbox: vec![v] },
+            }),
+            item_type: "feature".to_string(),
+            links: vec![
+                Link::new(url.clone(), "self", "application/json"),
+                Link::new(format!("{url}/items"), "items", "application/geo+json"),
+            ],
+        }
+    }
+}
+
+/// Response of the `/collections/{id}/items` endpoint
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ItemsResponse {
+    #[serde(rename = "type")]
+    pub typ: String,
+    pub features: Vec<Value>,
+    pub number_returned: usize,
+    pub links: Vec<Link>,
+}
+
+impl ItemsResponse {
+    /// Build a page of features. `features` may have one more item than the `limit`
+    /// of the filter, which is removed and indicates that there is a next page.
+    /// `query_string` is the query of the request, which is kept in the links except for the paging parameters.
+    #[must_use]
+    pub fn new(
+        mut features: Vec<Value>,
+        filter: &FeatureFilter,
+        url: &str,
+        query_string: &str,
+    ) -> Self {
+        let has_next = features.len() > filter.limit;
+        features.truncate(filter.limit);
+        let params: Vec<_> = query_string
+            .split('&')
+            .filter(|v| {
+                let key = v.split('=').next().unwrap_or_default();
+                !v.is_empty() && key != "limit" && key != "offset"
+            })
+            .collect();
+        let page_url = |offset: usize| {
+            let mut query = params.clone();
+            let limit = format!("limit={}", filter.limit);
+            let offset = format!("offset={offset}");
+            query.push(&limit);
+            query.push(&offset);
+            format!("{url}?{}", query.join("&"))
+        };
+        let mut links = vec![Link::new(
+            page_url(filter.offset),
+            "self",
+            "application/geo+json",
+        )];
+        if has_next {
+            let href = page_url(filter.offset + filter.limit);
+            links.push(Link::new(href, "next", "application/geo+json"));
+        }
+        Self {
+            typ: "FeatureCollection".to_string(),
+            number_returned: features.len(),
+            features,
+            links,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn items_filter() {
+        let query = ItemsQuery {
+            bbox: Some("-10,-20.5,10,20".to_string()),
+            datetime: Some("2020-01-01/..".to_string()),
+            limit: Some(100_000),
+            offset: None,
+        };
+        assert_eq!(
+            query.to_filter(),
+            Ok(FeatureFilter {
+                bbox: Some(Bounds::new(-10.0, -20.5, 10.0, 20.0)),
+                datetime: Some((Some("2020-01-01".to_string()), None)),
+                limit: FEATURES_LIMIT_MAX,
+                offset: 0,
+            })
+        );
+
+        assert_eq!(
+            parse_bbox("1,2,0,3,4,100"),
+            Ok(Bounds::new(1.0, 2.0, 3.0, 4.0))
+        );
+        assert!(parse_bbox("1,2,3").is_err());
+        assert!(parse_bbox("1,2,3,a").is_err());
+
+        let instant = "2020-01-01T12:00:00.5+02:00".to_string();
+        assert_eq!(
+            parse_datetime(&instant),
+            Ok((Some(instant.clone()), Some(instant)))
+        );
+        assert_eq!(
+            parse_datetime("/2020-01-01T00:00:00Z"),
+            Ok((None, Some("2020-01-01T00:00:00Z".to_string())))
+        );
+        assert!(parse_datetime("../..").is_err());
+        assert!(parse_datetime("yesterday").is_err());
+        assert!(parse_datetime("2020-01-01'; DROP TABLE x").is_err());
+    }
+
+    #[test]
+    fn items_paging() {
+        let filter = FeatureFilter {
+            limit: 2,
+            offset: 4,
+            ..FeatureFilter::default()
+        };
+        let features = vec![json!({"id": 1}), json!({"id": 2}), json!({"id": 3})];
+        let url = "http://localhost:3000/collections/points/items";
+        let res = ItemsResponse::new(features, &filter, url, "offset=4&bbox=1,2,3,4");
+        assert_eq!(res.number_returned, 2);
+        assert_eq!(
+            res.links,
+            vec![
+                Link::new(
+                    format!("{url}?bbox=1,2,3,4&limit=2&offset=4"),
+                    "self",
+                    "application/geo+json"
+                ),
+                Link::new(
+                    format!("{url}?bbox=1,2,3,4&limit=2&offset=6"),
+                    "next",
+                    "application/geo+json"
+                ),
+            ]
+        );
+
+        let res = ItemsResponse::new(vec![json!({"id": 1})], &filter, url, "");
+        assert_eq!(res.links.len(), 1);
+        assert_eq!(res.links[0].href, format!("{url}?limit=2&offset=4"));
+    }
+}
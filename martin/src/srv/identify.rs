@@ -0,0 +1,296 @@
+use std::f64::consts::PI;
+
+use martin_tile_utils::MAX_LATITUDE;
+use serde_json::{json, Value};
+
+use crate::utils::{MvtFeature, MvtLayer};
+use crate::TileCoord;
+
+/// Default search radius of the feature query, in pixels of a 256px tile
+pub const IDENTIFY_RADIUS_DEFAULT: f64 = 5.0;
+
+/// Position of a point within its tile as a fraction of the tile size, from 0 to 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TilePosition {
+    pub xyz: TileCoord,
+    pub x: f64,
+    pub y: f64,
+}
+
+impl TilePosition {
+    /// Find the tile containing the point, or `None` if the coordinates are out of range
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn new(lon: f64, lat: f64, zoom: u8) -> Option<Self> {
+        if !(-180.0..=180.0).contains(&lon) || !(-MAX_LATITUDE..=MAX_LATITUDE).contains(&lat) {
+            return None;
+        }
+        if zoom > 30 {
+            return None;
+        }
+        let n = f64::from(1_u32 << zoom);
+        let lat = lat.to_radians();
+        let fx = (lon + 180.0) / 360.0 * n;
+        let fy = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0 * n;
+        // Points on the east and south edges of the world belong to the last tile
+        let (x, y) = (fx.floor().min(n - 1.0), fy.floor().min(n - 1.0));
+        Some(Self {
+            xyz: TileCoord {
+                z: zoom,
+                x: x as u32,
+                y: y as u32,
+            },
+            x: fx - x,
+            y: fy - y,
+        })
+    }
+}
+
+/// Find the features within `radius` pixels of the position, and return them as a `GeoJSON` feature collection.
+/// Each feature has an additional `layer` member with the name of its vector tile layer.
+#[must_use]
+pub fn identify(layers: &[MvtLayer], pos: TilePosition, radius: f64) -> Value {
+    let mut features = Vec::new();
+    for layer in layers {
+        let extent = f64::from(layer.extent);
+        let point = (pos.x * extent, pos.y * extent);
+        let tolerance = radius / 256.0 * extent;
+        for feature in &layer.features {
+            if is_hit(feature, point, tolerance) {
+                features.push(to_feature(layer, feature, pos.xyz));
+            }
+        }
+    }
+    json!({ "type": "FeatureCollection", "features": features })
+}
+
+/// Convert all features of a decoded vector tile to a `GeoJSON` feature collection with WGS84 coordinates.
+/// Each feature has an additional `layer` member with the name of its vector tile layer.
+#[must_use]
+pub fn tile_to_geojson(layers: &[MvtLayer], xyz: TileCoord) -> Value {
+    let features: Vec<_> = layers
+        .iter()
+        .flat_map(|layer| layer.features.iter().map(|f| to_feature(layer, f, xyz)))
+        .collect();
+    json!({ "type": "FeatureCollection", "features": features })
+}
+
+fn to_feature(layer: &MvtLayer, feature: &MvtFeature, xyz: TileCoord) -> Value {
+    let mut value = json!({
+        "type": "Feature",
+        "layer": layer.name,
+        "geometry": to_geometry(feature, xyz, f64::from(layer.extent)),
+        "properties": feature.properties,
+    });
+    if let Some(id) = feature.id {
+        value["id"] = Value::from(id);
+    }
+    value
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn to_f64(p: (i64, i64)) -> (f64, f64) {
+    (p.0 as f64, p.1 as f64)
+}
+
+fn is_hit(feature: &MvtFeature, point: (f64, f64), tolerance: f64) -> bool {
+    match feature.geom_type {
+        1 => feature
+            .parts
+            .iter()
+            .flatten()
+            .any(|p| distance(point, to_f64(*p), to_f64(*p)) <= tolerance),
+        2 => feature.parts.iter().any(|line| {
+            line.windows(2)
+                .any(|s| distance(point, to_f64(s[0]), to_f64(s[1])) <= tolerance)
+        }),
+        3 => {
+            // Even-odd rule over all rings handles both holes and multi-polygons
+            let mut inside = false;
+            for ring in &feature.parts {
+                for s in ring.windows(2) {
+                    let ((x1, y1), (x2, y2)) = (to_f64(s[0]), to_f64(s[1]));
+                    if (y1 > point.1) != (y2 > point.1)
+                        && point.0 < (x2 - x1) * (point.1 - y1) / (y2 - y1) + x1
+                    {
+                        inside = !inside;
+                    }
+                }
+            }
+            inside
+        }
+        _ => false,
+    }
+}
+
+/// Distance from the point to the segment between `a` and `b`
+fn distance(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let len = dx * dx + dy * dy;
+    let t = if len == 0.0 {
+        0.0
+    } else {
+        (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / len).clamp(0.0, 1.0)
+    };
+    (p.0 - a.0 - t * dx).hypot(p.1 - a.1 - t * dy)
+}
+
+fn to_geometry(feature: &MvtFeature, xyz: TileCoord, extent: f64) -> Value {
+    let n = f64::from(1_u32 << xyz.z);
+    let coord = |p: &(i64, i64)| {
+        let (x, y) = to_f64(*p);
+        let lon = (f64::from(xyz.x) + x / extent) / n * 360.0 - 180.0;
+        let lat = (PI * (1.0 - 2.0 * (f64::from(xyz.y) + y / extent) / n))
+            .sinh()
+            .atan()
+            .to_degrees();
+        json!([lon, lat])
+    };
+    let line = |part: &Vec<(i64, i64)>| part.iter().map(coord).collect::<Vec<_>>();
+    match feature.geom_type {
+        1 => {
+            let points: Vec<_> = feature.parts.iter().flatten().map(coord).collect();
+            if points.len() == 1 {
+                json!({ "type": "Point", "coordinates": points[0] })
+            } else {
+                json!({ "type": "MultiPoint", "coordinates": points })
+            }
+        }
+        2 => {
+            let lines: Vec<_> = feature.parts.iter().map(line).collect();
+            if lines.len() == 1 {
+                json!({ "type": "LineString", "coordinates": lines[0] })
+            } else {
+                json!({ "type": "MultiLineString", "coordinates": lines })
+            }
+        }
+        3 => {
+            // Per the MVT spec, each exterior ring has a positive area, and is followed by its holes
+            let mut polygons: Vec<Vec<Vec<Value>>> = Vec::new();
+            for ring in &feature.parts {
+                match polygons.last_mut() {
+                    Some(polygon) if ring_area(ring) < 0.0 => polygon.push(line(ring)),
+                    _ => polygons.push(vec![line(ring)]),
+                }
+            }
+            if polygons.len() == 1 {
+                json!({ "type": "Polygon", "coordinates": polygons[0] })
+            } else {
+                json!({ "type": "MultiPolygon", "coordinates": polygons })
+            }
+        }
+        _ => Value::Null,
+    }
+}
+
+fn ring_area(ring: &[(i64, i64)]) -> f64 {
+    ring.windows(2)
+        .map(|s| {
+            let ((x1, y1), (x2, y2)) = (to_f64(s[0]), to_f64(s[1]));
+            x1 * y2 - x2 * y1
+        })
+        .sum::<f64>()
+        / 2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Map;
+
+    use super::*;
+
+    fn feature(geom_type: u64, parts: Vec<Vec<(i64, i64)>>) -> MvtFeature {
+        MvtFeature {
+            id: Some(7),
+            geom_type,
+            properties: Map::from_iter([("name".to_string(), Value::from("a"))]),
+            parts,
+        }
+    }
+
+    #[test]
+    fn tile_position() {
+        let pos = TilePosition::new(0.0, 0.0, 1).unwrap();
+        assert_eq!(pos.xyz, TileCoord { z: 1, x: 1, y: 1 });
+        assert!(pos.x.abs() < 1e-9 && pos.y.abs() < 1e-9);
+        let pos = TilePosition::new(180.0, -MAX_LATITUDE, 2).unwrap();
+        assert_eq!(pos.xyz, TileCoord { z: 2, x: 3, y: 3 });
+        assert!(TilePosition::new(0.0, 89.0, 2).is_none());
+        assert!(TilePosition::new(181.0, 0.0, 2).is_none());
+    }
+
+    #[test]
+    fn identify_features() {
+        let square = vec![(0, 0), (100, 0), (100, 100), (0, 100), (0, 0)];
+        let hole = vec![(40, 40), (40, 60), (60, 60), (60, 40), (40, 40)];
+        let layer = MvtLayer {
+            name: "l".to_string(),
+            extent: 256,
+            features: vec![
+                feature(1, vec![vec![(10, 10)]]),
+                feature(2, vec![vec![(0, 20), (100, 20)]]),
+                feature(3, vec![square, hole]),
+            ],
+        };
+        let layers = &[layer];
+        let pos = |x: f64, y: f64| TilePosition {
+            xyz: TileCoord { z: 0, x: 0, y: 0 },
+            x: x / 256.0,
+            y: y / 256.0,
+        };
+        let types = |v: Value| -> Vec<String> {
+            v["features"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|f| f["geometry"]["type"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            types(identify(layers, pos(12.0, 12.0), 5.0)),
+            vec!["Point", "Polygon"]
+        );
+        assert_eq!(
+            types(identify(layers, pos(70.0, 22.0), 5.0)),
+            vec!["LineString", "Polygon"]
+        );
+        assert!(types(identify(layers, pos(50.0, 50.0), 5.0)).is_empty());
+        assert!(types(identify(layers, pos(200.0, 200.0), 5.0)).is_empty());
+
+        let result = identify(layers, pos(10.0, 10.0), 1.0);
+        let point = &result["features"][0];
+        assert_eq!(point["id"], 7);
+        assert_eq!(point["layer"], "l");
+        assert_eq!(point["properties"]["name"], "a");
+        let coords = point["geometry"]["coordinates"].as_array().unwrap();
+        assert!((coords[0].as_f64().unwrap() + 165.9375).abs() < 1e-6);
+    }
+
+    #[test]
+    fn tile_geojson() {
+        let layers = [
+            MvtLayer {
+                name: "a".to_string(),
+                extent: 4096,
+                features: vec![feature(1, vec![vec![(0, 0)], vec![(4096, 4096)]])],
+            },
+            MvtLayer {
+                name: "b".to_string(),
+                extent: 4096,
+                features: vec![feature(2, vec![vec![(0, 2048), (4096, 2048)]])],
+            },
+        ];
+        let result = tile_to_geojson(&layers, TileCoord { z: 1, x: 1, y: 0 });
+        let features = result["features"].as_array().unwrap();
+        assert_eq!(features.len(), 2);
+        assert_eq!(features[0]["layer"], "a");
+        assert_eq!(features[0]["geometry"]["type"], "MultiPoint");
+        let coords = &features[0]["geometry"]["coordinates"];
+        assert_eq!(coords[0][0], 0.0);
+        assert_eq!(coords[1][0], 180.0);
+        assert!(coords[1][1].as_f64().unwrap().abs() < 1e-9);
+        assert_eq!(features[1]["layer"], "b");
+        assert_eq!(features[1]["geometry"]["type"], "LineString");
+    }
+}
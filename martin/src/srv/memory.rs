@@ -0,0 +1,271 @@
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::http::header::HeaderMap;
+use actix_web::web::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+pub const MEMORY_MAX_WAIT_DEFAULT: u64 = 1000;
+pub const MEMORY_SHED_ABOVE_DEFAULT: u8 = 90;
+pub const MEMORY_TILE_ESTIMATE_DEFAULT: usize = 64 * 1024;
+
+/// Requests with this or a larger urgency in their `Priority` header (RFC 9218) are low priority
+const LOW_PRIORITY_URGENCY: u8 = 5;
+
+/// Approximate limit of the memory used by tile data of the requests in flight and by the caches
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct MemoryBudgetConfig {
+    /// Maximum number of bytes of tile data and cache entries
+    pub limit: usize,
+    /// How long, in milliseconds, a tile request waits for memory to be freed before failing with `503`, defaults to 1000
+    pub max_wait: Option<u64>,
+    /// Reject low priority tile requests right away once this percentage of the budget is used, defaults to 90
+    pub shed_above: Option<u8>,
+    /// Number of bytes reserved for a tile while it is being generated, defaults to 64 KiB
+    pub tile_estimate: Option<usize>,
+}
+
+/// Accounting of the memory used by tiles and caches, see [`MemoryBudgetConfig`]
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    max_wait: Duration,
+    shed_above: usize,
+    tile_estimate: usize,
+    used: AtomicUsize,
+    /// Number of tiles holding a [`MemoryReservation`]
+    tiles: AtomicUsize,
+    freed: Notify,
+}
+
+impl MemoryBudget {
+    #[must_use]
+    pub fn new(config: &MemoryBudgetConfig) -> Self {
+        let percent = usize::from(
+            config
+                .shed_above
+                .unwrap_or(MEMORY_SHED_ABOVE_DEFAULT)
+                .min(100),
+        );
+        let limit = config.limit;
+        Self {
+            limit,
+            max_wait: Duration::from_millis(config.max_wait.unwrap_or(MEMORY_MAX_WAIT_DEFAULT)),
+            shed_above: limit / 100 * percent + limit % 100 * percent / 100,
+            tile_estimate: config.tile_estimate.unwrap_or(MEMORY_TILE_ESTIMATE_DEFAULT),
+            used: AtomicUsize::new(0),
+            tiles: AtomicUsize::new(0),
+            freed: Notify::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// True once low priority work should be skipped to keep memory for the other requests
+    #[must_use]
+    pub fn is_under_pressure(&self) -> bool {
+        self.used() >= self.shed_above
+    }
+
+    /// Count memory that is already in use, even if it exceeds the budget
+    pub fn grow(&self, bytes: usize) {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn shrink(&self, bytes: usize) {
+        if bytes > 0 {
+            self.used.fetch_sub(bytes, Ordering::Relaxed);
+            self.freed.notify_waiters();
+        }
+    }
+
+    /// Count the bytes of a tile if they fit into the budget. A tile is allowed to exceed the budget
+    /// if no other tile is in flight, otherwise a full cache or a tile larger than the budget would block all requests.
+    fn try_grow(&self, bytes: usize) -> bool {
+        let alone = self.tiles.load(Ordering::Relaxed) == 0;
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                (alone || used + bytes <= self.limit).then_some(used + bytes)
+            })
+            .is_ok()
+    }
+
+    /// Reserve memory for a tile that is about to be generated, waiting for other requests to free theirs
+    /// if the budget is exhausted. Low priority requests are rejected instead once the budget is nearly used up.
+    pub async fn reserve_tile(
+        self: &Arc<Self>,
+        low_priority: bool,
+    ) -> Result<MemoryReservation, String> {
+        let bytes = self.tile_estimate;
+        let deadline = Instant::now() + self.max_wait;
+        loop {
+            let freed = self.freed.notified();
+            tokio::pin!(freed);
+            // Register before checking, so that memory freed in between is not missed
+            freed.as_mut().enable();
+            if low_priority && self.is_under_pressure() {
+                return Err("Not enough memory for low priority requests".to_string());
+            }
+            if self.try_grow(bytes) {
+                self.tiles.fetch_add(1, Ordering::Relaxed);
+                return Ok(MemoryReservation {
+                    budget: self.clone(),
+                    bytes,
+                });
+            }
+            if tokio::time::timeout_at(deadline, freed).await.is_err() {
+                return Err(format!(
+                    "Not enough memory to generate the tile within {:?}",
+                    self.max_wait
+                ));
+            }
+        }
+    }
+}
+
+/// True if the `Priority` header of the request has a low urgency, e.g. `u=7` for background prefetching
+#[must_use]
+pub fn is_low_priority(headers: &HeaderMap) -> bool {
+    headers
+        .get("priority")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            v.split(',')
+                .find_map(|param| param.trim().strip_prefix("u="))
+                .and_then(|v| v.trim().parse::<u8>().ok())
+        })
+        .map_or(false, |urgency| urgency >= LOW_PRIORITY_URGENCY)
+}
+
+/// Memory counted against the [`MemoryBudget`] until it is dropped
+#[derive(Debug)]
+pub struct MemoryReservation {
+    budget: Arc<MemoryBudget>,
+    bytes: usize,
+}
+
+impl MemoryReservation {
+    /// Replace the estimate with the actual size, e.g. once the tile is generated
+    pub fn resize(&mut self, bytes: usize) {
+        if bytes > self.bytes {
+            self.budget.grow(bytes - self.bytes);
+        } else {
+            self.budget.shrink(self.bytes - bytes);
+        }
+        self.bytes = bytes;
+    }
+
+    /// Keep the memory reserved until the body has been sent
+    #[must_use]
+    pub fn attach(mut self, body: BoxBody) -> BoxBody {
+        if let BodySize::Sized(size) = body.size() {
+            self.resize(usize::try_from(size).unwrap_or(usize::MAX));
+        }
+        BoxBody::new(ReservedBody {
+            body,
+            _reservation: self,
+        })
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.budget.tiles.fetch_sub(1, Ordering::Relaxed);
+        self.budget.shrink(self.bytes);
+    }
+}
+
+struct ReservedBody {
+    body: BoxBody,
+    _reservation: MemoryReservation,
+}
+
+impl MessageBody for ReservedBody {
+    type Error = <BoxBody as MessageBody>::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Pin::new(&mut self.get_mut().body).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn budget(limit: usize) -> Arc<MemoryBudget> {
+        Arc::new(MemoryBudget::new(&MemoryBudgetConfig {
+            limit,
+            max_wait: Some(50),
+            shed_above: Some(50),
+            tile_estimate: Some(10),
+        }))
+    }
+
+    #[actix_rt::test]
+    async fn reserve_memory() {
+        let budget = budget(25);
+        let mut first = budget.reserve_tile(false).await.unwrap();
+        assert!(!budget.is_under_pressure());
+        let second = budget.reserve_tile(false).await.unwrap();
+        assert_eq!(budget.used(), 20);
+        assert!(budget.is_under_pressure());
+        assert!(budget.reserve_tile(true).await.is_err());
+        // Waits for the memory of another request
+        assert!(budget.reserve_tile(false).await.is_err());
+        let waiting = actix_rt::spawn({
+            let budget = budget.clone();
+            async move { budget.reserve_tile(false).await.map(|v| v.bytes) }
+        });
+        actix_rt::task::yield_now().await;
+        drop(second);
+        assert_eq!(waiting.await.unwrap(), Ok(10));
+
+        first.resize(100);
+        assert_eq!(budget.used(), 100);
+        let body = first.attach(BoxBody::new("tile"));
+        assert_eq!(budget.used(), 4);
+        drop(body);
+        assert_eq!(budget.used(), 0);
+        // A single tile may exceed the budget, even if the caches use all of it
+        budget.grow(30);
+        let mut tile = budget.reserve_tile(false).await.unwrap();
+        tile.resize(1000);
+        assert!(budget.reserve_tile(false).await.is_err());
+        drop(tile);
+        assert_eq!(budget.used(), 30);
+    }
+
+    #[test]
+    fn low_priority() {
+        let priority = |v: &str| {
+            let req = TestRequest::default()
+                .insert_header(("Priority", v))
+                .to_http_request();
+            is_low_priority(req.headers())
+        };
+        assert!(priority("u=7"));
+        assert!(priority("i, u=5"));
+        assert!(!priority("u=1"));
+        assert!(!priority("i"));
+        assert!(!is_low_priority(&HeaderMap::new()));
+    }
+}
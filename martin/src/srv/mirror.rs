@@ -0,0 +1,295 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::ACCEPT_ENCODING;
+use actix_web::http::Method;
+use actix_web::HttpRequest;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::utils::send_request;
+
+pub const MIRROR_DURATION_DEFAULT: u64 = 600;
+pub const MIRROR_MAX_IN_FLIGHT: usize = 64;
+/// Header of the mirrored requests, so that a replica which mirrors its own traffic does not send them back
+pub const MIRROR_HEADER: &str = "X-Martin-Mirror";
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Body of `POST /_/mirror`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct MirrorRequest {
+    /// Base URL of the replica, e.g. `http://replica:3000`
+    pub url: String,
+    /// Percentage of the requests to mirror [default: 100]
+    pub percent: Option<u8>,
+    /// How long to mirror the requests, in seconds [default: 600]
+    pub duration: Option<u64>,
+}
+
+/// Replica receiving the requests, and the counters of the current or last mirroring window
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MirrorStatus {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<u8>,
+    /// Seconds until the mirroring stops
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<u64>,
+    /// Requests sent to the replica
+    pub mirrored: u64,
+    /// Requests not sent because too many mirrored requests were still in flight
+    pub dropped: u64,
+    /// Mirrored requests that failed, timed out, or got a server error
+    pub failed: u64,
+}
+
+struct MirrorTarget {
+    url: Url,
+    percent: u8,
+    until: Instant,
+}
+
+#[derive(Debug, Default)]
+struct MirrorStats {
+    in_flight: AtomicUsize,
+    mirrored: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+#[derive(Default)]
+struct MirrorState {
+    target: Option<MirrorTarget>,
+    stats: Arc<MirrorStats>,
+}
+
+/// Sends a copy of some of the `GET` requests to a replica for a limited time, without waiting for its responses,
+/// e.g. so that the caches of a new replica are warm before it receives real traffic.
+/// Mirroring is started and stopped by the `/_/mirror` endpoints.
+#[derive(Default)]
+pub struct TrafficMirror {
+    state: Mutex<MirrorState>,
+    requests: AtomicU64,
+}
+
+impl TrafficMirror {
+    /// Mirror the requests to the replica of the request, replacing the previous one if any
+    pub fn start(&self, request: &MirrorRequest) -> Result<MirrorStatus, String> {
+        let url = Url::parse(&request.url)
+            .ok()
+            .filter(|v| matches!(v.scheme(), "http" | "https") && v.has_host())
+            .ok_or_else(|| format!("Invalid mirror URL {}", request.url))?;
+        let percent = request.percent.unwrap_or(100);
+        if !(1..=100).contains(&percent) {
+            return Err(format!(
+                "The mirrored percentage must be between 1 and 100, got {percent}"
+            ));
+        }
+        let duration = request.duration.unwrap_or(MIRROR_DURATION_DEFAULT);
+        info!("Mirroring {percent}% of the requests to {url} for {duration}s");
+        *self.lock() = MirrorState {
+            target: Some(MirrorTarget {
+                url,
+                percent,
+                until: Instant::now() + Duration::from_secs(duration),
+            }),
+            stats: Arc::default(),
+        };
+        Ok(self.status())
+    }
+
+    pub fn stop(&self) {
+        if let Some(target) = self.lock().target.take() {
+            info!("Stopped mirroring the requests to {}", target.url);
+        }
+    }
+
+    #[must_use]
+    pub fn status(&self) -> MirrorStatus {
+        let mut state = self.lock();
+        Self::expire(&mut state);
+        let target = state.target.as_ref();
+        let counters = &state.stats;
+        MirrorStatus {
+            active: target.is_some(),
+            url: target.map(|v| v.url.to_string()),
+            percent: target.map(|v| v.percent),
+            remaining: target.map(|v| v.until.saturating_duration_since(Instant::now()).as_secs()),
+            mirrored: counters.mirrored.load(Ordering::Relaxed),
+            dropped: counters.dropped.load(Ordering::Relaxed),
+            failed: counters.failed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Send a copy of the request to the replica in the background, if it is a `GET` request selected for mirroring.
+    /// The `/_/…` endpoints and the requests mirrored by another server are never mirrored.
+    pub fn mirror(&self, req: &HttpRequest) {
+        if req.method() != Method::GET
+            || req.path().starts_with("/_/")
+            || req.headers().contains_key(MIRROR_HEADER)
+        {
+            return;
+        }
+        let path = req
+            .uri()
+            .path_and_query()
+            .map_or(req.path(), |v| v.as_str());
+        let Some((url, stats)) = self.select(path) else {
+            return;
+        };
+        if stats.in_flight.fetch_add(1, Ordering::Relaxed) >= MIRROR_MAX_IN_FLIGHT {
+            stats.in_flight.fetch_sub(1, Ordering::Relaxed);
+            stats.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let accept_encoding = req
+            .headers()
+            .get(ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .map(ToString::to_string);
+        actix_web::rt::spawn(async move {
+            let mut headers = vec![(MIRROR_HEADER, "1")];
+            if let Some(value) = &accept_encoding {
+                headers.push(("Accept-Encoding", value));
+            }
+            let request = send_request("GET", &url, &headers, &[]);
+            let error = match tokio::time::timeout(MIRROR_TIMEOUT, request).await {
+                Ok(Ok(response)) if response.status < 500 => None,
+                Ok(Ok(response)) => Some(format!("status {}", response.status)),
+                Ok(Err(e)) => Some(e),
+                Err(_) => Some("timeout".to_string()),
+            };
+            if let Some(error) = error {
+                debug!("Mirrored request {url} failed: {error}");
+                stats.failed.fetch_add(1, Ordering::Relaxed);
+            }
+            stats.mirrored.fetch_add(1, Ordering::Relaxed);
+            stats.in_flight.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+
+    /// URL of the replica for the request if it is selected, spreading the selected requests evenly
+    fn select(&self, path_and_query: &str) -> Option<(Url, Arc<MirrorStats>)> {
+        let mut state = self.lock();
+        Self::expire(&mut state);
+        let target = state.target.as_ref()?;
+        let count = self.requests.fetch_add(1, Ordering::Relaxed);
+        let percent = u64::from(target.percent);
+        if (count + 1) * percent / 100 == count * percent / 100 {
+            return None;
+        }
+        // Joining an absolute path would drop the path prefix of the replica URL
+        let base = target.url.as_str().trim_end_matches('/');
+        let url = Url::parse(&format!("{base}{path_and_query}")).ok()?;
+        Some((url, state.stats.clone()))
+    }
+
+    /// Stop mirroring once the time window has ended
+    fn expire(state: &mut MirrorState) {
+        if state
+            .target
+            .as_ref()
+            .map_or(false, |v| v.until <= Instant::now())
+        {
+            if let Some(target) = state.target.take() {
+                info!(
+                    "Stopped mirroring the requests to {}, the time window has ended",
+                    target.url
+                );
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MirrorState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn mirror_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/replica/", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let len = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let mirror = TrafficMirror::default();
+        let tile = TestRequest::get()
+            .uri("/points/1/0/1?year=2024")
+            .insert_header((ACCEPT_ENCODING, "gzip"));
+        mirror.mirror(&tile.to_http_request());
+        assert_eq!(mirror.status(), MirrorStatus::default());
+
+        let start = |percent, duration| {
+            mirror.start(&MirrorRequest {
+                url: url.clone(),
+                percent,
+                duration,
+            })
+        };
+        let status = start(None, None).unwrap();
+        assert!(status.active);
+        assert_eq!(status.remaining, Some(MIRROR_DURATION_DEFAULT - 1));
+        mirror.mirror(&TestRequest::post().uri("/points/1/0/1").to_http_request());
+        mirror.mirror(&TestRequest::get().uri("/_/mirror").to_http_request());
+        let mirrored = TestRequest::get().insert_header((MIRROR_HEADER, "1"));
+        mirror.mirror(&mirrored.to_http_request());
+        let tile = TestRequest::get()
+            .uri("/points/1/0/1?year=2024")
+            .insert_header((ACCEPT_ENCODING, "gzip"));
+        mirror.mirror(&tile.to_http_request());
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("GET /replica/points/1/0/1?year=2024 HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nx-martin-mirror: 1\r\n"));
+        assert!(request.contains("\r\naccept-encoding: gzip\r\n"));
+        while mirror.status().mirrored == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(mirror.status().failed, 0);
+
+        start(Some(25), None).unwrap();
+        let selected = (0..100).filter(|_| mirror.select("/").is_some()).count();
+        assert_eq!(selected, 25);
+        start(Some(100), Some(0)).unwrap();
+        assert!(mirror.select("/").is_none());
+        assert!(!mirror.status().active);
+        start(None, None).unwrap();
+        mirror.stop();
+        assert!(!mirror.status().active);
+
+        assert!(start(Some(0), None).is_err());
+        assert!(start(Some(101), None).is_err());
+        for url in ["ftp://replica/", "replica:3000", "http://"] {
+            let request = MirrorRequest {
+                url: url.to_string(),
+                percent: None,
+                duration: None,
+            };
+            assert!(mirror.start(&request).is_err(), "{url}");
+        }
+    }
+}
@@ -1,8 +1,97 @@
+mod assets;
+pub use assets::{asset_etag, asset_response, AssetMaxAge, ASSET_MAX_AGE_DEFAULT};
+
 mod config;
-pub use config::{SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT};
+pub use config::{
+    SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT, POST_BODY_LIMIT_DEFAULT,
+    STREAM_THRESHOLD_DEFAULT,
+};
+
+mod empty_tiles;
+pub use empty_tiles::{
+    purge_empty_tiles, refresh_empty_tiles, EmptyTileCache, EmptyTileCacheConfig, EmptyTileLimits,
+    EmptyTileRefreshConfig, EMPTY_TILE_MAX_ENTRIES_DEFAULT, EMPTY_TILE_TTL_DEFAULT,
+    REFRESH_BEFORE_EXPIRY_DEFAULT, REFRESH_CONCURRENCY_DEFAULT, REFRESH_INTERVAL_DEFAULT,
+    REFRESH_MIN_HITS_DEFAULT,
+};
+
+mod endpoints;
+pub use endpoints::{DisabledStatus, EndpointGroup, EndpointsConfig};
+
+mod events;
+pub use events::{
+    EventPublisher, EventSink, EventsConfig, KafkaConfig, NatsConfig, TileEvent, TileEventKind,
+    EVENTS_BUFFER_SIZE_DEFAULT, KAFKA_TOPIC_DEFAULT, NATS_SUBJECT_DEFAULT,
+};
+
+mod features;
+pub use features::{
+    Collection, Collections, Extent, ItemsQuery, ItemsResponse, Link, SpatialExtent,
+    FEATURES_LIMIT_DEFAULT, FEATURES_LIMIT_MAX,
+};
+
+mod identify;
+pub use identify::{identify, tile_to_geojson, TilePosition, IDENTIFY_RADIUS_DEFAULT};
+
+mod memory;
+pub use memory::{
+    is_low_priority, MemoryBudget, MemoryBudgetConfig, MemoryReservation, MEMORY_MAX_WAIT_DEFAULT,
+    MEMORY_SHED_ABOVE_DEFAULT, MEMORY_TILE_ESTIMATE_DEFAULT,
+};
+
+mod faults;
+pub use faults::{
+    fault_injection_enabled, FaultConfig, FaultInjection, SourceFaults, FAULT_INJECTION_ENV,
+};
+
+mod quotas;
+pub use quotas::{
+    QuotaLimits, Quotas, QuotasConfig, API_KEY_HEADER_DEFAULT, API_KEY_PARAM_DEFAULT,
+};
+
+mod mirror;
+pub use mirror::{
+    MirrorRequest, MirrorStatus, TrafficMirror, MIRROR_DURATION_DEFAULT, MIRROR_HEADER,
+    MIRROR_MAX_IN_FLIGHT,
+};
+
+mod readiness;
+pub use readiness::{InitState, Readiness, ReadyWhen};
+
+mod recorder;
+pub use recorder::{
+    read_recording, RecordedRequest, RecordedResponse, RequestRecorder, SampleRecorder,
+    SAMPLES_PER_SOURCE_DEFAULT,
+};
+
+mod routing;
+pub use routing::{
+    CacheRouting, CacheRoutingConfig, TILE_HASH_HEADER_DEFAULT, TILE_NODE_HEADER_DEFAULT,
+};
+
+mod schema;
+pub use schema::{get_schema, FieldSchema, LayerSchema, SourceSchema};
 
 mod server;
+mod source_errors;
+pub use source_errors::{
+    CircuitBreakerConfig, CircuitState, ErrorClass, SourceErrors, SourceErrorsConfig, SourceStatus,
+    COOLDOWN_DEFAULT, ERROR_RATE_DEFAULT, ERROR_WINDOW_DEFAULT, MIN_REQUESTS_DEFAULT,
+};
+
+mod source_log;
+pub use source_log::{LogLevel, SourceLogLevels, SourceLogger};
+
+mod staging;
+pub use staging::{ConfigLoader, ConfigStaging, StagingState, StagingStatus};
+
+mod style;
+pub use style::{validate_style, StyleValidation};
+
+mod validation;
+pub use validation::{InvalidTile, MAX_ZOOM};
+
 pub use server::{
     get_tile_content, get_tile_response, merge_tilejson, new_server, router, Catalog, TileRequest,
-    RESERVED_KEYWORDS,
+    TileScheme, TileSchemes, RESERVED_KEYWORDS,
 };
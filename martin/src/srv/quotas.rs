@@ -0,0 +1,241 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::error::{ErrorForbidden, ErrorPaymentRequired};
+use actix_web::web::{Data, Query};
+use actix_web::HttpRequest;
+use serde::{Deserialize, Serialize};
+
+pub const API_KEY_HEADER_DEFAULT: &str = "X-Api-Key";
+pub const API_KEY_PARAM_DEFAULT: &str = "api_key";
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Usage limits of a single source
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct QuotaLimits {
+    /// Maximum number of tiles each API key may request per UTC day, then `402 Payment Required`
+    pub max_tiles_per_day: Option<u64>,
+    /// Maximum zoom level of the tiles requested without an API key, then `403 Forbidden`
+    pub anonymous_max_zoom: Option<u8>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct QuotasConfig {
+    /// Default limits for all sources
+    #[serde(flatten)]
+    pub defaults: QuotaLimits,
+    /// Per-source limits, overriding the defaults
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sources: BTreeMap<String, QuotaLimits>,
+    /// Accepted API keys. Requests with any other key get `403 Forbidden`.
+    /// If not set, all keys are accepted, e.g. when they are already validated by a gateway.
+    pub api_keys: Option<Vec<String>>,
+    /// Request header with the API key [default: `X-Api-Key`]
+    pub api_key_header: Option<String>,
+    /// URL query parameter with the API key, used if the header is not set [default: `api_key`]
+    pub api_key_param: Option<String>,
+}
+
+/// Tiles requested by each API key from each source during the current day
+#[derive(Debug, Default)]
+struct DailyUsage {
+    day: u64,
+    tiles: HashMap<(String, String), u64>,
+}
+
+/// Enforces the usage limits of the sources, e.g. to comply with the license of commercial data.
+/// The counters are kept in memory, so they are shared by all workers but reset when the server restarts.
+#[derive(Debug)]
+pub struct Quotas {
+    config: QuotasConfig,
+    api_keys: Option<HashSet<String>>,
+    usage: Mutex<DailyUsage>,
+}
+
+impl Quotas {
+    #[must_use]
+    pub fn new(config: QuotasConfig) -> Self {
+        Self {
+            api_keys: config
+                .api_keys
+                .as_ref()
+                .map(|v| v.iter().cloned().collect()),
+            config,
+            usage: Mutex::default(),
+        }
+    }
+
+    fn limits(&self, source_id: &str) -> QuotaLimits {
+        let src = self.config.sources.get(source_id);
+        let defaults = &self.config.defaults;
+        QuotaLimits {
+            max_tiles_per_day: src
+                .and_then(|v| v.max_tiles_per_day)
+                .or(defaults.max_tiles_per_day),
+            anonymous_max_zoom: src
+                .and_then(|v| v.anonymous_max_zoom)
+                .or(defaults.anonymous_max_zoom),
+        }
+    }
+
+    /// API key of the request, from the header or from the URL query
+    fn api_key(&self, req: &HttpRequest) -> Option<String> {
+        let header = self
+            .config
+            .api_key_header
+            .as_deref()
+            .unwrap_or(API_KEY_HEADER_DEFAULT);
+        if let Some(key) = req.headers().get(header).and_then(|v| v.to_str().ok()) {
+            return Some(key.to_string());
+        }
+        let param = self
+            .config
+            .api_key_param
+            .as_deref()
+            .unwrap_or(API_KEY_PARAM_DEFAULT);
+        Query::<HashMap<String, String>>::from_query(req.query_string())
+            .ok()?
+            .into_inner()
+            .remove(param)
+    }
+
+    /// Check the limits of each source of a tile request on the given day, and count the tile if it is allowed
+    pub fn check_tile(
+        &self,
+        api_key: Option<&str>,
+        source_ids: &str,
+        zoom: u8,
+        day: u64,
+    ) -> actix_web::Result<()> {
+        let sources: Vec<_> = source_ids
+            .split(',')
+            .map(|id| (id, self.limits(id)))
+            .collect();
+        let Some(api_key) = api_key else {
+            for (id, limits) in &sources {
+                if let Some(max_zoom) = limits.anonymous_max_zoom {
+                    if zoom > max_zoom {
+                        return Err(ErrorForbidden(format!(
+                            "An API key is required for the tiles of {id} above zoom {max_zoom}"
+                        )));
+                    }
+                }
+            }
+            return Ok(());
+        };
+        if let Some(api_keys) = &self.api_keys {
+            if !api_keys.contains(api_key) {
+                return Err(ErrorForbidden("Invalid API key"));
+            }
+        }
+
+        let mut usage = self.usage.lock().unwrap_or_else(PoisonError::into_inner);
+        if usage.day != day {
+            usage.day = day;
+            usage.tiles.clear();
+        }
+        for (id, limits) in &sources {
+            if let Some(max_tiles) = limits.max_tiles_per_day {
+                let key = (api_key.to_string(), (*id).to_string());
+                if usage.tiles.get(&key).copied().unwrap_or_default() >= max_tiles {
+                    return Err(ErrorPaymentRequired(format!(
+                        "The daily quota of {max_tiles} tiles of {id} has been used up"
+                    )));
+                }
+            }
+        }
+        for (id, limits) in sources {
+            if limits.max_tiles_per_day.is_some() {
+                *usage
+                    .tiles
+                    .entry((api_key.to_string(), id.to_string()))
+                    .or_default() += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail the tile request if it exceeds the `quotas` config of the server
+    pub fn check(req: &HttpRequest, source_ids: &str, zoom: u8) -> actix_web::Result<()> {
+        let Some(quotas) = req.app_data::<Data<Self>>() else {
+            return Ok(());
+        };
+        let day = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |v| v.as_secs() / SECONDS_PER_DAY);
+        quotas.check_tile(quotas.api_key(req).as_deref(), source_ids, zoom, day)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn source_quotas() {
+        let config: QuotasConfig = serde_yaml::from_str(indoc! {"
+            anonymous_max_zoom: 10
+            api_keys: [alpha, beta]
+            sources:
+              premium:
+                max_tiles_per_day: 2
+              open:
+                anonymous_max_zoom: 22
+        "})
+        .unwrap();
+        let quotas = Quotas::new(config);
+        let status = |v: actix_web::Result<()>| {
+            v.map_or_else(|e| e.as_response_error().status_code().as_u16(), |()| 200)
+        };
+
+        assert_eq!(status(quotas.check_tile(None, "premium", 10, 1)), 200);
+        assert_eq!(status(quotas.check_tile(None, "premium", 11, 1)), 403);
+        assert_eq!(status(quotas.check_tile(None, "open", 14, 1)), 200);
+        assert_eq!(status(quotas.check_tile(None, "open,premium", 14, 1)), 403);
+        assert_eq!(status(quotas.check_tile(Some("gamma"), "open", 0, 1)), 403);
+
+        assert_eq!(
+            status(quotas.check_tile(Some("alpha"), "premium", 14, 1)),
+            200
+        );
+        assert_eq!(
+            status(quotas.check_tile(Some("alpha"), "premium", 14, 1)),
+            200
+        );
+        assert_eq!(
+            status(quotas.check_tile(Some("alpha"), "premium", 14, 1)),
+            402
+        );
+        assert_eq!(status(quotas.check_tile(Some("alpha"), "open", 14, 1)), 200);
+        assert_eq!(
+            status(quotas.check_tile(Some("beta"), "premium", 14, 1)),
+            200
+        );
+        // The quota is reset every day
+        assert_eq!(
+            status(quotas.check_tile(Some("alpha"), "premium", 14, 2)),
+            200
+        );
+
+        let data = Data::new(quotas);
+        let req = TestRequest::with_uri("/premium/14/0/0?api_key=beta")
+            .app_data(data.clone())
+            .to_http_request();
+        assert_eq!(data.api_key(&req).as_deref(), Some("beta"));
+        let req = TestRequest::with_uri("/premium/14/0/0?api_key=beta")
+            .insert_header(("X-Api-Key", "alpha"))
+            .to_http_request();
+        assert_eq!(data.api_key(&req).as_deref(), Some("alpha"));
+        let req = TestRequest::with_uri("/premium/14/0/0").to_http_request();
+        assert_eq!(data.api_key(&req), None);
+        // Everything is allowed without the config
+        Quotas::check(&req, "premium", 22).unwrap();
+    }
+}
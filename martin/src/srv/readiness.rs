@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::MartinError::NotEnoughSourcesReady;
+use crate::MartinResult;
+
+/// Percentage of the sources that must be initialized before the server starts, e.g. `90%`.
+/// The other sources keep initializing in the background.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "ReadyWhenValue", into = "String")]
+pub struct ReadyWhen(u8);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ReadyWhenValue {
+    Number(u64),
+    Text(String),
+}
+
+impl ReadyWhen {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let number = value.trim().trim_end_matches('%').trim_end();
+        number
+            .parse::<u64>()
+            .map_err(|_| format!("Invalid ready_when `{value}`: expected a percentage, e.g. 90%"))
+            .and_then(Self::try_from_percent)
+    }
+
+    fn try_from_percent(percent: u64) -> Result<Self, String> {
+        match u8::try_from(percent) {
+            Ok(v) if v <= 100 => Ok(Self(v)),
+            _ => Err(format!(
+                "ready_when must be from 0% to 100%, got {percent}%"
+            )),
+        }
+    }
+
+    #[must_use]
+    pub fn percent(self) -> u8 {
+        self.0
+    }
+}
+
+impl Display for ReadyWhen {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}%", self.0)
+    }
+}
+
+impl TryFrom<ReadyWhenValue> for ReadyWhen {
+    type Error = String;
+
+    fn try_from(value: ReadyWhenValue) -> Result<Self, Self::Error> {
+        match value {
+            ReadyWhenValue::Number(v) => Self::try_from_percent(v),
+            ReadyWhenValue::Text(v) => Self::parse(&v),
+        }
+    }
+}
+
+impl From<ReadyWhen> for String {
+    fn from(value: ReadyWhen) -> Self {
+        value.to_string()
+    }
+}
+
+/// State of a group of sources initialized together, e.g. a single file or a PostgreSQL connection
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum InitState {
+    Initializing,
+    Ready,
+    Failed { error: String },
+}
+
+#[derive(Debug, Default)]
+struct InitProgress {
+    units: BTreeMap<String, InitState>,
+    finished: bool,
+}
+
+impl InitProgress {
+    fn ready(&self) -> usize {
+        self.units
+            .values()
+            .filter(|v| **v == InitState::Ready)
+            .count()
+    }
+
+    fn is_ready(&self, ready_when: ReadyWhen) -> bool {
+        self.ready() * 100 >= self.units.len() * usize::from(ready_when.percent())
+    }
+}
+
+/// Progress of the background source initialization, see [`SrvConfig::ready_when`](crate::srv::SrvConfig::ready_when)
+#[derive(Debug)]
+pub struct Readiness {
+    ready_when: ReadyWhen,
+    progress: watch::Sender<InitProgress>,
+}
+
+impl Readiness {
+    #[must_use]
+    pub fn new(ready_when: ReadyWhen, units: impl IntoIterator<Item = String>) -> Self {
+        let units = units
+            .into_iter()
+            .map(|v| (v, InitState::Initializing))
+            .collect();
+        let (progress, _) = watch::channel(InitProgress {
+            units,
+            finished: false,
+        });
+        Self {
+            ready_when,
+            progress,
+        }
+    }
+
+    pub fn set(&self, unit: &str, state: InitState) {
+        self.progress.send_modify(|v| {
+            v.units.insert(unit.to_string(), state);
+        });
+    }
+
+    /// Mark the initialization as complete, whether or not enough units are ready
+    pub fn finish(&self) {
+        self.progress.send_modify(|v| v.finished = true);
+    }
+
+    /// Wait until enough units are ready. Fails if the initialization has finished without reaching the threshold.
+    pub async fn wait(&self) -> MartinResult<()> {
+        let ready_when = self.ready_when;
+        let mut rx = self.progress.subscribe();
+        let progress = rx
+            .wait_for(|v| v.finished || v.is_ready(ready_when))
+            .await
+            .expect("the sender outlives the readiness");
+        if progress.is_ready(ready_when) {
+            Ok(())
+        } else {
+            Err(NotEnoughSourcesReady(
+                progress.ready(),
+                progress.units.len(),
+                ready_when,
+            ))
+        }
+    }
+
+    /// Units that are still initializing or have failed, as reported by the `/status` endpoint
+    #[must_use]
+    pub fn status(&self) -> BTreeMap<String, InitState> {
+        self.progress
+            .borrow()
+            .units
+            .iter()
+            .filter(|(_, state)| **state != InitState::Ready)
+            .map(|(unit, state)| (unit.clone(), state.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn parse_ready_when() {
+        let parse = |v: &str| serde_yaml::from_str::<ReadyWhen>(v).map(ReadyWhen::percent);
+        assert_eq!(parse("90%").unwrap(), 90);
+        assert_eq!(parse("'75 %'").unwrap(), 75);
+        assert_eq!(parse("100").unwrap(), 100);
+        assert!(parse("101%").is_err());
+        assert!(parse("most").is_err());
+        assert_eq!(serde_yaml::to_string(&ReadyWhen(90)).unwrap(), "90%\n");
+    }
+
+    #[actix_rt::test]
+    async fn wait_for_sources() {
+        let units = ["a", "b", "c", "d"].map(String::from);
+        let readiness = Arc::new(Readiness::new(ReadyWhen(75), units));
+        let waiting = actix_rt::spawn({
+            let readiness = readiness.clone();
+            async move { readiness.wait().await }
+        });
+        readiness.set("a", InitState::Ready);
+        readiness.set("b", InitState::Ready);
+        readiness.set(
+            "c",
+            InitState::Failed {
+                error: "bad file".to_string(),
+            },
+        );
+        actix_rt::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        readiness.set("d", InitState::Ready);
+        waiting.await.unwrap().unwrap();
+        assert_eq!(
+            serde_json::to_value(readiness.status()).unwrap(),
+            serde_json::json!({"c": {"state": "failed", "error": "bad file"}})
+        );
+
+        let units = ["a", "b"].map(String::from);
+        let readiness = Readiness::new(ReadyWhen(100), units);
+        readiness.set("a", InitState::Ready);
+        readiness.finish();
+        let err = readiness.wait().await.unwrap_err().to_string();
+        assert!(err.contains("1 of 2"), "{err}");
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::Instant;
+
+use actix_web::http::header::{HeaderMap, CONTENT_ENCODING, CONTENT_TYPE};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::srv::routing::fnv1a;
+use crate::MartinError::{RecordingFileError, RecordingParseError};
+use crate::{decode_brotli, decode_gzip, decode_zstd, MartinResult, TileCoord};
+
+pub const SAMPLES_PER_SOURCE_DEFAULT: usize = 10;
+
+/// A single tile request, as stored in a request recording file (one JSON object per line)
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    /// Milliseconds since the start of the recording
+    pub time_ms: u64,
+    /// Source ID, or comma-separated list of source IDs for composite sources
+    pub source: String,
+    pub z: u8,
+    pub x: u32,
+    pub y: u32,
+    /// URL query string without the leading `?`
+    pub query: Option<String>,
+    /// Value of the `Accept-Encoding` request header
+    pub encoding: Option<String>,
+    /// Response of the server, only stored by [`SampleRecorder`]
+    pub response: Option<RecordedResponse>,
+}
+
+impl RecordedRequest {
+    fn new(
+        start: Instant,
+        source: &str,
+        xyz: TileCoord,
+        query: &str,
+        encoding: Option<&str>,
+    ) -> Self {
+        Self {
+            time_ms: u64::try_from(start.elapsed().as_millis()).unwrap_or(u64::MAX),
+            source: source.to_string(),
+            z: xyz.z,
+            x: xyz.x,
+            y: xyz.y,
+            query: (!query.is_empty()).then(|| query.to_string()),
+            encoding: encoding.map(ToString::to_string),
+            response: None,
+        }
+    }
+
+    #[must_use]
+    pub fn xyz(&self) -> TileCoord {
+        TileCoord {
+            z: self.z,
+            x: self.x,
+            y: self.y,
+        }
+    }
+}
+
+/// Writes all incoming tile requests to a file, so that they can be replayed with `martin replay`
+#[derive(Debug)]
+pub struct RequestRecorder {
+    path: PathBuf,
+    start: Instant,
+    file: Mutex<File>,
+}
+
+impl RequestRecorder {
+    pub fn new(path: PathBuf) -> MartinResult<Self> {
+        let file = File::create(&path).map_err(|e| RecordingFileError(e, path.clone()))?;
+        Ok(Self {
+            path,
+            start: Instant::now(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, source: &str, xyz: TileCoord, query: &str, encoding: Option<&str>) {
+        let request = RecordedRequest::new(self.start, source, xyz, query, encoding);
+        // A poisoned lock only means another thread panicked mid-write, the file is still usable
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        write_request(&mut file, &request, &self.path);
+    }
+}
+
+fn write_request(file: &mut File, request: &RecordedRequest, path: &Path) {
+    let mut line = serde_json::to_string(request).expect("request is serializable");
+    line.push('\n');
+    if let Err(e) = file.write_all(line.as_bytes()) {
+        warn!("Unable to record request to {}: {e}", path.display());
+    }
+}
+
+/// Summary of a tile response, to detect a change of the tiles when the request is replayed
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub content_type: Option<String>,
+    /// Size of the decoded tile in bytes
+    pub size: usize,
+    /// Hash of the decoded tile, so that a different compression of the same tile is not a change
+    pub hash: String,
+}
+
+impl RecordedResponse {
+    #[must_use]
+    pub fn new(status: u16, headers: &HeaderMap, body: &[u8]) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(ToString::to_string)
+        };
+        let decoded = match header(CONTENT_ENCODING).as_deref() {
+            Some("gzip") => decode_gzip(body).ok(),
+            Some("br") => decode_brotli(body).ok(),
+            Some("zstd") => decode_zstd(body).ok(),
+            _ => None,
+        };
+        let body = decoded.as_deref().unwrap_or(body);
+        Self {
+            status,
+            content_type: header(CONTENT_TYPE),
+            size: body.len(),
+            hash: format!("{:016x}", fnv1a(body)),
+        }
+    }
+}
+
+/// Writes the first distinct tile requests of each source with their responses, one file per source,
+/// so that they can be replayed with `martin replay --compare` to detect changes of the tiles, e.g. after an upgrade.
+#[derive(Debug)]
+pub struct SampleRecorder {
+    dir: PathBuf,
+    per_source: usize,
+    start: Instant,
+    sources: Mutex<HashMap<String, SourceSamples>>,
+}
+
+#[derive(Debug)]
+struct SourceSamples {
+    file: File,
+    path: PathBuf,
+    tiles: HashSet<(TileCoord, String)>,
+}
+
+impl SampleRecorder {
+    pub fn new(dir: PathBuf, per_source: usize) -> MartinResult<Self> {
+        fs::create_dir_all(&dir).map_err(|e| RecordingFileError(e, dir.clone()))?;
+        Ok(Self {
+            dir,
+            per_source,
+            start: Instant::now(),
+            sources: Mutex::default(),
+        })
+    }
+
+    /// Whether the response to this request should be recorded, so that it is only read when needed
+    #[must_use]
+    pub fn wants(&self, source: &str, xyz: TileCoord, query: &str) -> bool {
+        let sources = self.sources.lock().unwrap_or_else(PoisonError::into_inner);
+        sources.get(source).map_or(self.per_source > 0, |v| {
+            v.tiles.len() < self.per_source && !v.tiles.contains(&(xyz, query.to_string()))
+        })
+    }
+
+    pub fn record(
+        &self,
+        source: &str,
+        xyz: TileCoord,
+        query: &str,
+        encoding: Option<&str>,
+        response: RecordedResponse,
+    ) {
+        if !self.wants(source, xyz, query) {
+            return;
+        }
+        let mut sources = self.sources.lock().unwrap_or_else(PoisonError::into_inner);
+        let samples = if let Some(samples) = sources.get_mut(source) {
+            samples
+        } else {
+            let path = self.dir.join(format!("{source}.jsonl"));
+            let file = match File::create(&path) {
+                Ok(file) => file,
+                Err(e) => {
+                    warn!("Unable to record samples to {}: {e}", path.display());
+                    return;
+                }
+            };
+            let tiles = HashSet::new();
+            sources
+                .entry(source.to_string())
+                .or_insert(SourceSamples { file, path, tiles })
+        };
+        if samples.tiles.len() >= self.per_source || !samples.tiles.insert((xyz, query.to_string()))
+        {
+            return;
+        }
+        let request = RecordedRequest {
+            response: Some(response),
+            ..RecordedRequest::new(self.start, source, xyz, query, encoding)
+        };
+        write_request(&mut samples.file, &request, &samples.path);
+    }
+}
+
+/// Read all requests from a recording file created by [`RequestRecorder`],
+/// or from all `.jsonl` files of a directory created by [`SampleRecorder`]
+pub fn read_recording(path: &Path) -> MartinResult<Vec<RecordedRequest>> {
+    if path.is_dir() {
+        let entries = fs::read_dir(path).map_err(|e| RecordingFileError(e, path.to_path_buf()))?;
+        let mut files = Vec::new();
+        for entry in entries {
+            let file = entry
+                .map_err(|e| RecordingFileError(e, path.to_path_buf()))?
+                .path();
+            if file.extension().map_or(false, |v| v == "jsonl") {
+                files.push(file);
+            }
+        }
+        files.sort();
+        let mut requests = Vec::new();
+        for file in files {
+            requests.extend(read_recording(&file)?);
+        }
+        return Ok(requests);
+    }
+    let file = File::open(path).map_err(|e| RecordingFileError(e, path.to_path_buf()))?;
+    let mut requests = Vec::new();
+    for (idx, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| RecordingFileError(e, path.to_path_buf()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = serde_json::from_str(&line)
+            .map_err(|e| RecordingParseError(e, path.to_path_buf(), idx + 1))?;
+        requests.push(request);
+    }
+    Ok(requests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_and_read() {
+        let path =
+            std::env::temp_dir().join(format!("martin-recording-{}.jsonl", std::process::id()));
+        let recorder = RequestRecorder::new(path.clone()).unwrap();
+        recorder.record("points", TileCoord { z: 1, x: 2, y: 3 }, "", None);
+        recorder.record(
+            "a,b",
+            TileCoord { z: 4, x: 5, y: 6 },
+            "foo=bar",
+            Some("gzip"),
+        );
+        drop(recorder);
+
+        let requests = read_recording(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].source, "points");
+        assert_eq!(requests[0].xyz(), TileCoord { z: 1, x: 2, y: 3 });
+        assert_eq!(requests[0].query, None);
+        assert_eq!(requests[0].encoding, None);
+        assert_eq!(requests[1].source, "a,b");
+        assert_eq!(requests[1].query.as_deref(), Some("foo=bar"));
+        assert_eq!(requests[1].encoding.as_deref(), Some("gzip"));
+        assert!(requests[0].time_ms <= requests[1].time_ms);
+    }
+
+    #[test]
+    fn record_samples() {
+        let dir = std::env::temp_dir().join(format!("martin-samples-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let samples = SampleRecorder::new(dir.clone(), 2).unwrap();
+        let tile = b"tile content";
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, "application/x-protobuf".parse().unwrap());
+        let plain = RecordedResponse::new(200, &headers, tile);
+        headers.insert(CONTENT_ENCODING, "gzip".parse().unwrap());
+        let gzipped = RecordedResponse::new(200, &headers, &crate::encode_gzip(tile).unwrap());
+        assert_eq!(plain.hash, gzipped.hash);
+        assert_eq!(gzipped.size, tile.len());
+
+        let xyz = |x| TileCoord { z: 3, x, y: 1 };
+        samples.record("points", xyz(0), "", Some("gzip"), gzipped.clone());
+        assert!(!samples.wants("points", xyz(0), ""));
+        assert!(samples.wants("points", xyz(0), "year=2024"));
+        samples.record("points", xyz(0), "", None, plain.clone());
+        samples.record("points", xyz(1), "", None, plain.clone());
+        samples.record("points", xyz(2), "", None, plain.clone());
+        assert!(!samples.wants("points", xyz(3), ""));
+        samples.record("lines", xyz(5), "", None, plain.clone());
+        drop(samples);
+
+        let requests = read_recording(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let tiles: Vec<_> = requests.iter().map(|r| (r.source.as_str(), r.x)).collect();
+        assert_eq!(tiles, vec![("lines", 5), ("points", 0), ("points", 1)]);
+        assert_eq!(requests[1].response, Some(gzipped));
+        assert_eq!(requests[1].encoding.as_deref(), Some("gzip"));
+    }
+}
@@ -0,0 +1,178 @@
+use std::str::FromStr;
+
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+use crate::{MartinError, MartinResult, TileCoord};
+
+pub const TILE_HASH_HEADER_DEFAULT: &str = "X-Tile-Hash";
+pub const TILE_NODE_HEADER_DEFAULT: &str = "X-Tile-Node";
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct CacheRoutingConfig {
+    /// Response header with the hash of the tile key [default: `X-Tile-Hash`]
+    pub header: Option<String>,
+    /// Names of the servers of the fleet. If set, the server chosen by rendezvous hashing
+    /// of the tile key is returned in the `node_header`.
+    pub nodes: Option<Vec<String>>,
+    /// Response header with the server that should handle the tile [default: `X-Tile-Node`]
+    pub node_header: Option<String>,
+}
+
+/// Adds headers to the tile responses so that load balancers in front of a fleet of servers
+/// can send the requests of the same tile to the same server, whose caches are then more likely to have it.
+/// The hash does not depend on the server or on its version, so all servers of a fleet agree on it.
+#[derive(Debug)]
+pub struct CacheRouting {
+    header: HeaderName,
+    node_header: HeaderName,
+    /// Name of each node, with the hash of its name
+    nodes: Vec<(HeaderValue, u64)>,
+}
+
+impl CacheRouting {
+    pub fn new(config: &CacheRoutingConfig) -> MartinResult<Self> {
+        let header_name = |name: Option<&str>, default| {
+            let name = name.unwrap_or(default);
+            HeaderName::from_str(name)
+                .map_err(|_| MartinError::InvalidHeaderName(name.to_string(), "cache_routing"))
+        };
+        let nodes = config
+            .nodes
+            .iter()
+            .flatten()
+            .map(|name| {
+                HeaderValue::from_str(name)
+                    .map(|v| (v, fnv1a(name.as_bytes())))
+                    .map_err(|_| {
+                        MartinError::InvalidHeaderName(name.clone(), "cache_routing.nodes")
+                    })
+            })
+            .collect::<MartinResult<_>>()?;
+        Ok(Self {
+            header: header_name(config.header.as_deref(), TILE_HASH_HEADER_DEFAULT)?,
+            node_header: header_name(config.node_header.as_deref(), TILE_NODE_HEADER_DEFAULT)?,
+            nodes,
+        })
+    }
+
+    /// Names of the response headers, which browsers may only read if CORS exposes them
+    #[must_use]
+    pub fn header_names(&self) -> Vec<HeaderName> {
+        if self.nodes.is_empty() {
+            vec![self.header.clone()]
+        } else {
+            vec![self.header.clone(), self.node_header.clone()]
+        }
+    }
+
+    /// Stable hash of a tile request, including its URL query or the cache key of its body
+    #[must_use]
+    pub fn tile_hash(source_ids: &str, xyz: TileCoord, query: &str) -> u64 {
+        let mut key = format!("{source_ids}/{}/{}/{}", xyz.z, xyz.x, xyz.y);
+        if !query.is_empty() {
+            key.push('?');
+            key.push_str(query);
+        }
+        fnv1a(key.as_bytes())
+    }
+
+    /// Node with the highest score for the tile, so that only the tiles of a removed node move to other nodes
+    #[must_use]
+    pub fn node(&self, tile_hash: u64) -> Option<&HeaderValue> {
+        self.nodes
+            .iter()
+            .max_by_key(|(_, node_hash)| mix(tile_hash ^ node_hash))
+            .map(|(name, _)| name)
+    }
+
+    pub fn add_headers(
+        &self,
+        headers: &mut HeaderMap,
+        source_ids: &str,
+        xyz: TileCoord,
+        query: &str,
+    ) {
+        let hash = Self::tile_hash(source_ids, xyz, query);
+        if let Ok(value) = HeaderValue::from_str(&format!("{hash:016x}")) {
+            headers.insert(self.header.clone(), value);
+        }
+        if let Some(node) = self.node(hash) {
+            headers.insert(self.node_header.clone(), node.clone());
+        }
+    }
+}
+
+/// 64-bit FNV-1a hash, which unlike the standard hasher is the same for all builds
+pub(crate) fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Finalizer of splitmix64, so that similar keys get unrelated node scores
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn rendezvous_nodes() {
+        let config: CacheRoutingConfig = serde_yaml::from_str(indoc! {"
+            nodes: [martin-0, martin-1, martin-2, martin-3]
+        "})
+        .unwrap();
+        let routing = CacheRouting::new(&config).unwrap();
+        let tiles: Vec<_> = (0..1000)
+            .map(|x| CacheRouting::tile_hash("points", TileCoord { z: 10, x, y: 7 }, ""))
+            .collect();
+        let nodes: Vec<_> = tiles.iter().map(|h| routing.node(*h).unwrap()).collect();
+        for name in &config.nodes.unwrap() {
+            let count = nodes.iter().filter(|v| **v == name.as_str()).count();
+            assert!((180..320).contains(&count), "{name} has {count} tiles");
+        }
+
+        // Only the tiles of the removed node are moved
+        let smaller = CacheRouting::new(&CacheRoutingConfig {
+            nodes: Some(vec![
+                "martin-0".into(),
+                "martin-1".into(),
+                "martin-2".into(),
+            ]),
+            ..Default::default()
+        })
+        .unwrap();
+        for (hash, node) in tiles.iter().zip(&nodes) {
+            if *node != "martin-3" {
+                assert_eq!(smaller.node(*hash), Some(*node));
+            }
+        }
+
+        let xyz = TileCoord { z: 1, x: 0, y: 1 };
+        let mut headers = HeaderMap::new();
+        routing.add_headers(&mut headers, "points", xyz, "");
+        assert_eq!(headers.get("x-tile-hash").unwrap(), "61b13e39a474a609");
+        assert!(headers.contains_key("x-tile-node"));
+        assert_ne!(
+            CacheRouting::tile_hash("points", xyz, ""),
+            CacheRouting::tile_hash("points", xyz, "year=2024")
+        );
+
+        let routing = CacheRouting::new(&CacheRoutingConfig::default()).unwrap();
+        assert_eq!(routing.node(1), None);
+        assert_eq!(routing.header_names(), vec!["x-tile-hash"]);
+        assert!(CacheRouting::new(&CacheRoutingConfig {
+            header: Some("bad header".into()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
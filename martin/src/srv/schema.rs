@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::source::{FieldStats, Source};
+use crate::MartinResult;
+
+/// Vector layers and fields of a source, as returned by the `/{source_ids}/schema` endpoint
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceSchema {
+    pub layers: Vec<LayerSchema>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LayerSchema {
+    pub id: String,
+    /// ID of the source that provides this layer
+    pub source: String,
+    pub description: Option<String>,
+    pub minzoom: Option<u8>,
+    pub maxzoom: Option<u8>,
+    pub fields: BTreeMap<String, FieldSchema>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldSchema {
+    /// Field type or description, as listed in the TileJSON `vector_layers`
+    #[serde(rename = "type")]
+    pub typ: String,
+    /// Value statistics, only included if requested and supported by the source
+    pub stats: Option<FieldStats>,
+}
+
+/// Collect the vector layers of all sources, optionally with the value statistics of their fields
+pub async fn get_schema(sources: &[&dyn Source], with_stats: bool) -> MartinResult<SourceSchema> {
+    let mut layers = Vec::new();
+    for src in sources {
+        let mut stats = if with_stats {
+            src.get_field_stats().await?.unwrap_or_default()
+        } else {
+            BTreeMap::new()
+        };
+        for layer in src.get_tilejson().vector_layers.iter().flatten() {
+            let mut layer_stats = stats.remove(&layer.id).unwrap_or_default();
+            let fields = layer
+                .fields
+                .iter()
+                .map(|(name, typ)| {
+                    let field = FieldSchema {
+                        typ: typ.clone(),
+                        stats: layer_stats.remove(name),
+                    };
+                    (name.clone(), field)
+                })
+                .collect();
+            layers.push(LayerSchema {
+                id: layer.id.clone(),
+                source: src.get_id().to_string(),
+                description: layer.description.clone(),
+                minzoom: layer.minzoom,
+                maxzoom: layer.maxzoom,
+                fields,
+            });
+        }
+    }
+    Ok(SourceSchema { layers })
+}
+
+#[cfg(test)]
+mod tests {
+    use tilejson::{tilejson, VectorLayer};
+
+    use super::*;
+    use crate::test_utils::TestSource;
+
+    #[actix_rt::test]
+    async fn source_schema() {
+        let fields = BTreeMap::from([
+            ("name".to_string(), "text".to_string()),
+            ("rank".to_string(), "int4".to_string()),
+        ]);
+        let stats = FieldStats {
+            min: Some("1".to_string()),
+            max: Some("9".to_string()),
+            samples: vec!["1".to_string(), "9".to_string()],
+        };
+        let src = TestSource {
+            id: "points",
+            tj: tilejson! {
+                tiles: vec![],
+                vector_layers: vec![VectorLayer::new("points".to_string(), fields)],
+            },
+            field_stats: Some(BTreeMap::from([(
+                "points".to_string(),
+                BTreeMap::from([("rank".to_string(), stats)]),
+            )])),
+            ..Default::default()
+        };
+
+        let schema = get_schema(&[&src], false).await.unwrap();
+        assert_eq!(schema.layers.len(), 1);
+        let layer = &schema.layers[0];
+        assert_eq!(layer.source, "points");
+        assert_eq!(layer.fields["rank"].typ, "int4");
+        assert_eq!(layer.fields["rank"].stats, None);
+
+        let schema = get_schema(&[&src], true).await.unwrap();
+        let fields = &schema.layers[0].fields;
+        assert_eq!(fields["name"].stats, None);
+        let stats = fields["rank"].stats.as_ref().unwrap();
+        assert_eq!(stats.min.as_deref(), Some("1"));
+        assert_eq!(stats.samples.len(), 2);
+    }
+}
@@ -1,45 +1,113 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::hash::{Hash, Hasher};
 use std::string::ToString;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use actix_cors::Cors;
 use actix_http::ContentEncoding;
-use actix_web::dev::Server;
-use actix_web::error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound};
+use actix_web::body::{BodySize, BoxBody, MessageBody as _, SizedStream};
+use actix_web::dev::{Server, Service as _};
+use actix_web::error::{
+    ErrorBadRequest, ErrorConflict, ErrorGatewayTimeout, ErrorInternalServerError, ErrorNotFound,
+    ErrorPayloadTooLarge, ErrorServiceUnavailable,
+};
 use actix_web::http::header::{
-    AcceptEncoding, ContentType, Encoding as HeaderEnc, HeaderValue, Preference, CACHE_CONTROL,
-    CONTENT_ENCODING,
+    AcceptEncoding, ContentType, Encoding as HeaderEnc, HeaderName, HeaderValue, Preference,
+    ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, VARY,
 };
-use actix_web::http::Uri;
+use actix_web::http::{StatusCode, Uri};
 use actix_web::middleware::TrailingSlash;
 use actix_web::web::{Data, Path, Query};
 use actix_web::{
-    middleware, route, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder,
-    Result as ActixResult,
+    middleware, route, web, App, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder,
+    HttpServer, Responder, Result as ActixResult,
 };
 use futures::future::try_join_all;
+use futures::StreamExt as _;
 use itertools::Itertools as _;
-use log::error;
+use log::{debug, error, info};
 use martin_tile_utils::{Encoding, Format, TileInfo};
 use serde::{Deserialize, Serialize};
 use tilejson::{tilejson, TileJSON};
 
 use crate::config::ServerState;
+use crate::derived::upscale_png;
 use crate::fonts::{FontCatalog, FontError, FontSources};
-use crate::source::{Source, TileCatalog, TileSources, UrlQuery};
+use crate::pg::PgError;
+use crate::source::{
+    record_timing, with_deadline, with_feature_counts, with_headers, with_timings, FeatureCounts,
+    FeatureFilter, RequestTimings, Source, TileCatalog, TileData, TileSources, UrlQuery,
+};
 use crate::sprites::{SpriteCatalog, SpriteError, SpriteSources};
-use crate::srv::config::{SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT};
-use crate::utils::{decode_brotli, decode_gzip, encode_brotli, encode_gzip};
+use crate::srv::assets::{asset_response, AssetMaxAge};
+use crate::srv::config::{
+    SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT, POST_BODY_LIMIT_DEFAULT,
+    STREAM_THRESHOLD_DEFAULT,
+};
+use crate::srv::empty_tiles::{
+    purge_empty_tiles, refresh_empty_tiles, EmptyTileCache, EmptyTileRefreshConfig,
+};
+use crate::srv::endpoints::{EndpointGroup, EndpointsConfig};
+use crate::srv::events::{EventSink, TileEvent, TileEventKind};
+use crate::srv::faults::{FaultConfig, FaultInjection};
+use crate::srv::features::{Collection, Collections, ItemsQuery, ItemsResponse, Link};
+use crate::srv::identify::{identify, tile_to_geojson, TilePosition, IDENTIFY_RADIUS_DEFAULT};
+use crate::srv::memory::{is_low_priority, MemoryBudget};
+use crate::srv::mirror::{MirrorRequest, TrafficMirror};
+use crate::srv::quotas::Quotas;
+use crate::srv::readiness::{InitState, Readiness};
+use crate::srv::recorder::{
+    RecordedResponse, RequestRecorder, SampleRecorder, SAMPLES_PER_SOURCE_DEFAULT,
+};
+use crate::srv::routing::CacheRouting;
+use crate::srv::schema::get_schema;
+use crate::srv::source_errors::{SourceErrors, SourceErrorsConfig, SourceStatus};
+use crate::srv::source_log::{LogLevel, SourceLogLevels};
+use crate::srv::staging::ConfigStaging;
+use crate::srv::style::validate_style;
+use crate::srv::validation::InvalidTile;
+use crate::utils::{
+    decode_brotli, decode_gzip, decode_zstd, encode_brotli, encode_gzip, encode_source_id,
+    mvt_decode,
+};
 use crate::MartinError::BindingError;
-use crate::{MartinResult, Tile, TileCoord};
+use crate::{MartinError, MartinResult, Tile, TileCoord};
 
 /// List of keywords that cannot be used as source IDs. Some of these are reserved for future use.
 /// Reserved keywords must never end in a "dot number" (e.g. ".1").
 /// This list is documented in the `docs/src/using.md` file, which should be kept in sync.
 pub const RESERVED_KEYWORDS: &[&str] = &[
-    "_", "catalog", "config", "font", "health", "help", "index", "manifest", "metrics", "refresh",
-    "reload", "sprite", "status",
+    "_",
+    "catalog",
+    "collections",
+    "config",
+    "font",
+    "health",
+    "help",
+    "index",
+    "manifest",
+    "metrics",
+    "refresh",
+    "reload",
+    "sprite",
+    "status",
+    "tilejson",
 ];
 
+const SERVER_TIMING: HeaderName = HeaderName::from_static("server-timing");
+const TIMING_ALLOW_ORIGIN: HeaderName = HeaderName::from_static("timing-allow-origin");
+
+/// Total number of features in a vector tile, see [`Source::include_feature_count`]
+pub const FEATURE_COUNT_HEADER: &str = "X-Feature-Count";
+/// Comma-separated list of `layer=count` pairs of a vector tile
+pub const LAYER_FEATURE_COUNT_HEADER: &str = "X-Feature-Count-Layers";
+
+/// Minimum tile size in pixels that can be served as a high-DPI `@2x` tile without upscaling
+const HIGH_DPI_TILE_SIZE: u16 = 512;
+
 static SUPPORTED_ENCODINGS: &[HeaderEnc] = &[
     HeaderEnc::brotli(),
     HeaderEnc::gzip(),
@@ -51,6 +119,101 @@ pub struct Catalog {
     pub tiles: TileCatalog,
     pub sprites: SpriteCatalog,
     pub fonts: FontCatalog,
+    /// IDs of the sources whose requested names had to be normalized, see [`TileSources::get_source_ids`]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub source_ids: BTreeMap<String, String>,
+}
+
+/// Maximum time to generate a tile, see [`SrvConfig::request_timeout`]
+struct RequestTimeout(Duration);
+
+/// Add the `Server-Timing` header to tile responses, see [`SrvConfig::server_timing`]
+struct ServerTiming;
+
+/// Format the duration of each request phase, followed by the total, in milliseconds
+fn server_timing_header(timings: &RequestTimings, total: Duration) -> String {
+    timings
+        .iter()
+        .chain([&("total", total)])
+        .map(|(name, dur)| format!("{name};dur={:.1}", dur.as_secs_f64() * 1000.0))
+        .join(", ")
+}
+
+/// Maximum size of a POST tile request body, see [`SrvConfig::post_body_limit`]
+struct PostBodyLimit(usize);
+
+/// Minimum size of the tiles that are sent in chunks, see [`SrvConfig::stream_threshold`]
+struct StreamThreshold(usize);
+
+/// Size of the chunks of streamed tiles
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Send a large tile in chunks, so that it is not copied into the write buffer of the connection at once.
+/// The chunks share the memory of the tile, which for PMTiles files is only read from the file as it is sent.
+fn stream_large_tile(req: &HttpRequest, response: HttpResponse) -> HttpResponse {
+    let threshold = req
+        .app_data::<Data<StreamThreshold>>()
+        .map_or(STREAM_THRESHOLD_DEFAULT, |v| v.0);
+    if !matches!(response.body().size(), BodySize::Sized(size) if size > threshold as u64) {
+        return response;
+    }
+    let (response, body) = response.into_parts();
+    let body = match body.try_into_bytes() {
+        Ok(data) => {
+            let size = data.len() as u64;
+            let chunks = (0..data.len())
+                .step_by(STREAM_CHUNK_SIZE)
+                .map(move |start| {
+                    let end = data.len().min(start + STREAM_CHUNK_SIZE);
+                    Ok::<_, Infallible>(data.slice(start..end))
+                });
+            BoxBody::new(SizedStream::new(size, futures::stream::iter(chunks)))
+        }
+        Err(body) => body,
+    };
+    response.set_body(body)
+}
+
+/// Query parameters passed to the tile sources
+enum TileParams<'a> {
+    /// URL query string of a GET request
+    Query(&'a str),
+    /// JSON object in the body of a POST request
+    Body(UrlQuery),
+}
+
+impl TileParams<'_> {
+    fn parse(self) -> ActixResult<Option<UrlQuery>> {
+        Ok(match self {
+            Self::Query("") => None,
+            Self::Query(v) => Some(Query::<UrlQuery>::from_query(v)?.into_inner()),
+            Self::Body(v) => Some(v),
+        })
+    }
+
+    /// Identify the parameters in the empty tile cache. A body is identified by the hash
+    /// of its sorted parameters, so that the order of the JSON keys does not matter.
+    fn cache_key(&self) -> String {
+        match self {
+            Self::Query(v) => (*v).to_string(),
+            Self::Body(v) => {
+                let mut hasher = DefaultHasher::new();
+                v.iter().sorted().for_each(|kv| kv.hash(&mut hasher));
+                format!("body={:016x}", hasher.finish())
+            }
+        }
+    }
+}
+
+/// Convert the JSON object of a POST tile request to the same parameters as a URL query.
+/// Values are kept as JSON, so strings that look like numbers are not converted to numbers.
+fn parse_body_params(body: &[u8]) -> ActixResult<UrlQuery> {
+    let params: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(body)
+        .map_err(|e| ErrorBadRequest(format!("Tile request body must be a JSON object: {e}")))?;
+    Ok(params
+        .into_iter()
+        .map(|(k, v)| (k, v.to_string()))
+        .collect())
 }
 
 impl Catalog {
@@ -59,6 +222,7 @@ impl Catalog {
             tiles: state.tiles.get_catalog(),
             sprites: state.sprites.get_catalog()?,
             fonts: state.fonts.get_catalog(),
+            source_ids: state.tiles.get_source_ids(),
         })
     }
 }
@@ -68,6 +232,33 @@ struct TileJsonRequest {
     source_ids: String,
 }
 
+#[derive(Deserialize)]
+struct FeatureQuery {
+    lon: f64,
+    lat: f64,
+    zoom: u8,
+    /// Search radius in pixels of a 256px tile
+    radius: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct SchemaQuery {
+    /// Include the value statistics of each field, if supported by the source
+    #[serde(default)]
+    stats: bool,
+}
+
+#[derive(Deserialize)]
+struct BulkTileJsonRequest {
+    /// Comma-separated list of source IDs
+    sources: String,
+}
+
+#[derive(Deserialize)]
+struct CollectionRequest {
+    source_id: String,
+}
+
 #[derive(Deserialize, Clone)]
 pub struct TileRequest {
     source_ids: String,
@@ -76,11 +267,66 @@ pub struct TileRequest {
     y: u32,
 }
 
+#[derive(Deserialize, Clone)]
+pub struct QuadkeyRequest {
+    source_ids: String,
+    key: String,
+}
+
+/// Tile row numbering scheme, set with the `?scheme=tms` URL parameter or the `tile_schemes` config
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TileScheme {
+    /// Y starts at the top (north), used by most web maps
+    #[default]
+    Xyz,
+    /// Y starts at the bottom (south), used by TMS and some legacy clients
+    Tms,
+}
+
+/// Tile schemes of the sources, see [`SrvConfig::tile_schemes`]
+#[derive(Clone, Debug, Default)]
+pub struct TileSchemes(pub BTreeMap<String, TileScheme>);
+
+#[derive(Deserialize, Default)]
+struct TileSchemeQuery {
+    scheme: Option<TileScheme>,
+}
+
+/// Scheme of the `?scheme` URL parameter, or the configured scheme of the source.
+/// Composite sources use the scheme of their first source.
+fn get_tile_scheme(req: &HttpRequest, source_ids: &str) -> ActixResult<TileScheme> {
+    let query = Query::<TileSchemeQuery>::from_query(req.query_string())
+        .map_err(|e| ErrorBadRequest(format!("Invalid tile scheme: {e}")))?;
+    Ok(query.scheme.unwrap_or_else(|| {
+        let id = source_ids.split(',').next().unwrap_or_default();
+        req.app_data::<Data<TileSchemes>>()
+            .and_then(|v| v.0.get(id).copied())
+            .unwrap_or_default()
+    }))
+}
+
+/// The URL query without the given parameter, e.g. to not pass the `scheme` parameter on to the sources
+fn query_without(query: &str, key: &str) -> String {
+    query
+        .split('&')
+        .filter(|v| !v.is_empty() && *v != key && v.split_once('=').map_or(true, |(k, _)| k != key))
+        .join("&")
+}
+
 pub fn map_internal_error<T: std::fmt::Display>(e: T) -> actix_web::Error {
     error!("{e}");
     ErrorInternalServerError(e.to_string())
 }
 
+fn map_tile_error(e: MartinError) -> actix_web::Error {
+    match e {
+        MartinError::PostgresError(PgError::InvalidUrlQuery(..)) => ErrorBadRequest(e.to_string()),
+        MartinError::SourceUnavailable(_) => ErrorServiceUnavailable(e.to_string()),
+        _ => map_internal_error(e),
+    }
+}
+
 pub fn map_sprite_error(e: SpriteError) -> actix_web::Error {
     use SpriteError::SpriteNotFound;
     match e {
@@ -105,11 +351,14 @@ pub fn map_font_error(e: FontError) -> actix_web::Error {
 /// Root path will eventually have a web front. For now, just a stub.
 #[route("/", method = "GET", method = "HEAD")]
 #[allow(clippy::unused_async)]
-async fn get_index() -> &'static str {
+async fn get_index(req: HttpRequest) -> ActixResult<&'static str> {
+    EndpointsConfig::check(&req, EndpointGroup::WebUi)?;
     // todo: once this becomes more substantial, add wrap = "middleware::Compress::default()"
-    "Martin server is running. Eventually this will be a nice web front.\n\n\
+    Ok(
+        "Martin server is running. Eventually this will be a nice web front.\n\n\
     A list of all available sources is at /catalog\n\n\
-    See documentation https://github.com/maplibre/martin"
+    See documentation https://github.com/maplibre/martin",
+    )
 }
 
 /// Return 200 OK if healthy. Used for readiness and liveness probes.
@@ -121,6 +370,159 @@ async fn get_health() -> impl Responder {
         .message_body("OK")
 }
 
+/// Entry of the `/status` response
+#[derive(Serialize)]
+#[serde(untagged)]
+enum StatusEntry {
+    Source(SourceStatus),
+    Init(InitState),
+}
+
+/// Error statistics of each source if enabled with the `source_errors` config,
+/// and the sources that are still initializing or have failed with the `ready_when` config
+#[route("/status", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_status(req: HttpRequest) -> ActixResult<HttpResponse> {
+    EndpointsConfig::check(&req, EndpointGroup::Admin)?;
+    let errors = req.app_data::<Data<SourceErrors>>();
+    let readiness = req.app_data::<Data<Readiness>>();
+    if errors.is_none() && readiness.is_none() {
+        return Err(ErrorNotFound("Source error tracking is not enabled"));
+    }
+    let mut status = BTreeMap::new();
+    if let Some(errors) = errors {
+        status.extend(
+            errors
+                .status()
+                .into_iter()
+                .map(|(id, v)| (id, StatusEntry::Source(v))),
+        );
+    }
+    if let Some(readiness) = readiness {
+        status.extend(
+            readiness
+                .status()
+                .into_iter()
+                .map(|(unit, v)| (unit, StatusEntry::Init(v))),
+        );
+    }
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .json(status))
+}
+
+#[derive(Deserialize)]
+struct LogLevelRequest {
+    source_id: String,
+}
+
+fn log_levels(req: &HttpRequest) -> ActixResult<&Data<SourceLogLevels>> {
+    EndpointsConfig::check(req, EndpointGroup::Admin)?;
+    req.app_data::<Data<SourceLogLevels>>()
+        .ok_or_else(|| ErrorNotFound("Source log levels are not enabled"))
+}
+
+fn config_staging(req: &HttpRequest) -> ActixResult<&Data<ConfigStaging>> {
+    EndpointsConfig::check(req, EndpointGroup::Admin)?;
+    req.app_data::<Data<ConfigStaging>>()
+        .ok_or_else(|| ErrorNotFound("Config staging is not enabled"))
+}
+
+/// State of the configuration loaded with `POST /_/config/stage`
+#[route("/_/config/stage", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_config_stage(req: HttpRequest) -> ActixResult<HttpResponse> {
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .json(config_staging(&req)?.status()))
+}
+
+/// Load the configuration again in the background, and switch to it once all of its sources are initialized
+#[route("/_/config/stage", method = "POST")]
+#[allow(clippy::unused_async)]
+async fn post_config_stage(req: HttpRequest) -> ActixResult<HttpResponse> {
+    let staging = config_staging(&req)?;
+    let empty_tiles = req.app_data::<Data<EmptyTileCache>>().cloned();
+    ConfigStaging::stage(staging, empty_tiles).map_err(ErrorConflict)?;
+    Ok(HttpResponse::Accepted().json(staging.status()))
+}
+
+fn traffic_mirror(req: &HttpRequest) -> ActixResult<&Data<TrafficMirror>> {
+    EndpointsConfig::check(req, EndpointGroup::Admin)?;
+    req.app_data::<Data<TrafficMirror>>()
+        .ok_or_else(|| ErrorNotFound("Request mirroring is not enabled"))
+}
+
+/// Replica receiving a copy of the requests, if any, with the counters of the mirrored requests
+#[route("/_/mirror", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_mirror(req: HttpRequest) -> ActixResult<HttpResponse> {
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .json(traffic_mirror(&req)?.status()))
+}
+
+/// Start sending a copy of the requests to a replica for a limited time
+#[route("/_/mirror", method = "POST")]
+#[allow(clippy::unused_async)]
+async fn post_mirror(
+    req: HttpRequest,
+    body: web::Json<MirrorRequest>,
+) -> ActixResult<HttpResponse> {
+    let status = traffic_mirror(&req)?
+        .start(&body)
+        .map_err(ErrorBadRequest)?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
+/// Stop mirroring the requests before the end of the time window
+#[route("/_/mirror", method = "DELETE")]
+#[allow(clippy::unused_async)]
+async fn delete_mirror(req: HttpRequest) -> ActixResult<HttpResponse> {
+    traffic_mirror(&req)?.stop();
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Log level overrides of the sources, if enabled with the `log_levels` config
+#[route("/_/log", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_log_levels(req: HttpRequest) -> ActixResult<HttpResponse> {
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .json(log_levels(&req)?.levels()))
+}
+
+/// Change the log level of a source, given as plain text in the request body, e.g. `debug`
+#[route("/_/log/{source_id}", method = "PUT")]
+#[allow(clippy::unused_async)]
+async fn put_log_level(
+    req: HttpRequest,
+    path: Path<LogLevelRequest>,
+    body: String,
+    sources: Data<TileSources>,
+) -> ActixResult<HttpResponse> {
+    let levels = log_levels(&req)?;
+    sources.get_source(&path.source_id)?;
+    let level = LogLevel::parse(&body).map_err(ErrorBadRequest)?;
+    info!(
+        "Setting the log level of source {} to {body}",
+        path.source_id
+    );
+    levels.set(&path.source_id, Some(level));
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Use the global log level for the source again
+#[route("/_/log/{source_id}", method = "DELETE")]
+#[allow(clippy::unused_async)]
+async fn delete_log_level(
+    req: HttpRequest,
+    path: Path<LogLevelRequest>,
+) -> ActixResult<HttpResponse> {
+    log_levels(&req)?.set(&path.source_id, None);
+    Ok(HttpResponse::NoContent().finish())
+}
+
 #[route(
     "/catalog",
     method = "GET",
@@ -128,22 +530,55 @@ async fn get_health() -> impl Responder {
     wrap = "middleware::Compress::default()"
 )]
 #[allow(clippy::unused_async)]
-async fn get_catalog(catalog: Data<Catalog>) -> impl Responder {
-    HttpResponse::Ok().json(catalog)
+async fn get_catalog(
+    req: HttpRequest,
+    catalog: Data<Catalog>,
+    sources: Data<TileSources>,
+) -> ActixResult<HttpResponse> {
+    EndpointsConfig::check(&req, EndpointGroup::Catalog)?;
+    // Sources initialized in the background are added after the server has started
+    Ok(HttpResponse::Ok().json(Catalog {
+        tiles: sources.get_catalog(),
+        source_ids: sources.get_source_ids(),
+        ..Catalog::clone(&catalog)
+    }))
+}
+
+/// Check that an uploaded style only references the sources, sprites and fonts of this server
+#[route("/style/validate", method = "POST")]
+#[allow(clippy::unused_async)]
+async fn post_style_validate(
+    req: HttpRequest,
+    body: web::Bytes,
+    sources: Data<TileSources>,
+    catalog: Data<Catalog>,
+) -> ActixResult<HttpResponse> {
+    let style = serde_json::from_slice(&body)
+        .map_err(|e| ErrorBadRequest(format!("Invalid style JSON: {e}")))?;
+    let info = req.connection_info();
+    let report = validate_style(
+        &style,
+        &sources,
+        &catalog.sprites,
+        &catalog.fonts,
+        Some(info.host()),
+    );
+    Ok(HttpResponse::Ok().json(report))
 }
 
 #[route("/sprite/{source_ids}.png", method = "GET", method = "HEAD")]
 async fn get_sprite_png(
+    req: HttpRequest,
     path: Path<TileJsonRequest>,
     sprites: Data<SpriteSources>,
 ) -> ActixResult<HttpResponse> {
+    EndpointsConfig::check(&req, EndpointGroup::Sprites)?;
     let sheet = sprites
         .get_sprites(&path.source_ids)
         .await
         .map_err(map_sprite_error)?;
-    Ok(HttpResponse::Ok()
-        .content_type(ContentType::png())
-        .body(sheet.encode_png().map_err(map_internal_error)?))
+    let png = sheet.encode_png().map_err(map_internal_error)?;
+    Ok(asset_response(&req, ContentType::png().essence_str(), png))
 }
 
 #[route(
@@ -153,14 +588,17 @@ async fn get_sprite_png(
     wrap = "middleware::Compress::default()"
 )]
 async fn get_sprite_json(
+    req: HttpRequest,
     path: Path<TileJsonRequest>,
     sprites: Data<SpriteSources>,
 ) -> ActixResult<HttpResponse> {
+    EndpointsConfig::check(&req, EndpointGroup::Sprites)?;
     let sheet = sprites
         .get_sprites(&path.source_ids)
         .await
         .map_err(map_sprite_error)?;
-    Ok(HttpResponse::Ok().json(sheet.get_index()))
+    let json = serde_json::to_vec(sheet.get_index()).map_err(map_internal_error)?;
+    Ok(asset_response(&req, "application/json", json))
 }
 
 #[derive(Deserialize, Debug)]
@@ -176,13 +614,16 @@ struct FontRequest {
     wrap = "middleware::Compress::default()"
 )]
 #[allow(clippy::unused_async)]
-async fn get_font(path: Path<FontRequest>, fonts: Data<FontSources>) -> ActixResult<HttpResponse> {
+async fn get_font(
+    req: HttpRequest,
+    path: Path<FontRequest>,
+    fonts: Data<FontSources>,
+) -> ActixResult<HttpResponse> {
+    EndpointsConfig::check(&req, EndpointGroup::Fonts)?;
     let data = fonts
         .get_font_range(&path.fontstack, path.start, path.end)
         .map_err(map_font_error)?;
-    Ok(HttpResponse::Ok()
-        .content_type("application/x-protobuf")
-        .body(data))
+    Ok(asset_response(&req, "application/x-protobuf", data))
 }
 
 #[route(
@@ -197,12 +638,202 @@ async fn git_source_info(
     path: Path<TileJsonRequest>,
     sources: Data<TileSources>,
 ) -> ActixResult<HttpResponse> {
+    EndpointsConfig::check(&req, EndpointGroup::TileJson)?;
     let sources = sources.get_sources(&path.source_ids, None)?.0;
     let info = req.connection_info();
     let tiles_path = get_request_path(&req);
     let tiles_url = get_tiles_url(info.scheme(), info.host(), req.query_string(), &tiles_path)?;
+    let mut tilejson = merge_tilejson(&sources, tiles_url);
+    if get_tile_scheme(&req, &path.source_ids)? == TileScheme::Tms {
+        tilejson.scheme = Some("tms".to_string());
+    }
+
+    Ok(HttpResponse::Ok().json(tilejson))
+}
 
-    Ok(HttpResponse::Ok().json(merge_tilejson(&sources, tiles_url)))
+/// TileJSON of several sources at once, keyed by source ID
+#[route(
+    "/tilejson",
+    method = "GET",
+    method = "HEAD",
+    wrap = "middleware::Compress::default()"
+)]
+#[allow(clippy::unused_async)]
+async fn get_bulk_tilejson(
+    req: HttpRequest,
+    query: Query<BulkTileJsonRequest>,
+    sources: Data<TileSources>,
+) -> ActixResult<HttpResponse> {
+    EndpointsConfig::check(&req, EndpointGroup::TileJson)?;
+    let info = req.connection_info();
+    let request_path = get_request_path(&req);
+    let prefix = request_path.strip_suffix("/tilejson").unwrap_or_default();
+    // All other query parameters are passed on to the tile URLs, same as for a single source
+    let query_string = query_without(req.query_string(), "sources");
+
+    let mut result = BTreeMap::new();
+    for id in query.sources.split(',').filter(|v| !v.is_empty()) {
+        let src = sources.get_source(id)?;
+        let tiles_path = format!("{prefix}/{}", encode_source_id(id));
+        let tiles_url = get_tiles_url(info.scheme(), info.host(), &query_string, &tiles_path)?;
+        let mut tilejson = merge_tilejson(&[src], tiles_url);
+        if get_tile_scheme(&req, id)? == TileScheme::Tms {
+            tilejson.scheme = Some("tms".to_string());
+        }
+        result.insert(id.to_string(), tilejson);
+    }
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// Vector layers and fields of the sources, optionally with value statistics
+#[route(
+    "/{source_ids}/schema",
+    method = "GET",
+    method = "HEAD",
+    wrap = "middleware::Compress::default()"
+)]
+async fn get_source_schema(
+    path: Path<TileJsonRequest>,
+    query: Query<SchemaQuery>,
+    sources: Data<TileSources>,
+) -> ActixResult<HttpResponse> {
+    let sources = sources.get_sources(&path.source_ids, None)?.0;
+    let schema = get_schema(&sources, query.stats)
+        .await
+        .map_err(map_tile_error)?;
+    Ok(HttpResponse::Ok().json(schema))
+}
+
+/// Features of the vector tile at the given point, as `GeoJSON`
+#[route("/{source_ids}/query", method = "GET", method = "HEAD")]
+async fn get_features(
+    path: Path<TileJsonRequest>,
+    query: Query<FeatureQuery>,
+    sources: Data<TileSources>,
+) -> ActixResult<HttpResponse> {
+    let pos = TilePosition::new(query.lon, query.lat, query.zoom).ok_or_else(|| {
+        ErrorBadRequest(format!(
+            "Invalid location {},{} at zoom {}",
+            query.lon, query.lat, query.zoom
+        ))
+    })?;
+    let (sources, _, info) = sources.get_sources(&path.source_ids, Some(query.zoom))?;
+    if info.format != Format::Mvt {
+        return Err(ErrorBadRequest(format!(
+            "Features can only be queried in vector tiles, but the source has {info} tiles"
+        )));
+    }
+    let tile = get_tile_content(sources.as_slice(), info, &pos.xyz, None, None).await?;
+    if tile.info.encoding != Encoding::Uncompressed {
+        return Err(ErrorBadRequest(format!(
+            "Features cannot be queried in {} tiles",
+            tile.info
+        )));
+    }
+    let layers = mvt_decode(&tile.data).map_err(map_internal_error)?;
+    let radius = query.radius.unwrap_or(IDENTIFY_RADIUS_DEFAULT);
+    Ok(HttpResponse::Ok()
+        .content_type("application/geo+json")
+        .json(identify(&layers, pos, radius)))
+}
+
+/// Absolute URL of the request path, without the query string
+fn get_request_url(req: &HttpRequest) -> String {
+    let info = req.connection_info();
+    format!(
+        "{}://{}{}",
+        info.scheme(),
+        info.host(),
+        get_request_path(req)
+    )
+}
+
+/// Get a source for the OGC API Features endpoints, ensuring that it can return features
+fn get_feature_source<'a>(sources: &'a TileSources, id: &str) -> ActixResult<&'a dyn Source> {
+    let src = sources.get_source(id)?;
+    if src.supports_features() {
+        Ok(src)
+    } else {
+        Err(ErrorNotFound(format!(
+            "Source {id} does not support feature requests"
+        )))
+    }
+}
+
+/// OGC API Features list of the sources that can return individual features
+#[route(
+    "/collections",
+    method = "GET",
+    method = "HEAD",
+    wrap = "middleware::Compress::default()"
+)]
+#[allow(clippy::unused_async)]
+async fn get_collections(
+    req: HttpRequest,
+    sources: Data<TileSources>,
+) -> ActixResult<HttpResponse> {
+    let url = get_request_url(&req);
+    let mut collections: Vec<_> = sources
+        .iter()
+        .filter(|src| src.supports_features())
+        .map(|src| Collection::new(src, &url))
+        .collect();
+    collections.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(HttpResponse::Ok().json(Collections {
+        collections,
+        links: vec![Link::new(url, "self", "application/json")],
+    }))
+}
+
+#[route(
+    "/collections/{source_id}",
+    method = "GET",
+    method = "HEAD",
+    wrap = "middleware::Compress::default()"
+)]
+#[allow(clippy::unused_async)]
+async fn get_collection(
+    req: HttpRequest,
+    path: Path<CollectionRequest>,
+    sources: Data<TileSources>,
+) -> ActixResult<HttpResponse> {
+    let src = get_feature_source(&sources, &path.source_id)?;
+    let url = get_request_url(&req);
+    let base_url = url.strip_suffix(&format!("/{}", encode_source_id(&path.source_id)));
+    Ok(HttpResponse::Ok().json(Collection::new(src, base_url.unwrap_or_default())))
+}
+
+/// OGC API Features of a source as `GeoJSON`, with `bbox`, `datetime`, `limit`, and `offset` filters
+#[route(
+    "/collections/{source_id}/items",
+    method = "GET",
+    method = "HEAD",
+    wrap = "middleware::Compress::default()"
+)]
+async fn get_collection_items(
+    req: HttpRequest,
+    path: Path<CollectionRequest>,
+    query: Query<ItemsQuery>,
+    sources: Data<TileSources>,
+) -> ActixResult<HttpResponse> {
+    let src = get_feature_source(&sources, &path.source_id)?;
+    let filter = query.to_filter().map_err(ErrorBadRequest)?;
+    // Request one more feature to find out if there is a next page
+    let page = FeatureFilter {
+        limit: filter.limit + 1,
+        ..filter.clone()
+    };
+    let features = src
+        .get_features(&page)
+        .await
+        .map_err(map_tile_error)?
+        .unwrap_or_default();
+    let url = get_request_url(&req);
+    let items = ItemsResponse::new(features, &filter, &url, req.query_string());
+    Ok(HttpResponse::Ok()
+        .content_type("application/geo+json")
+        .json(items))
 }
 
 fn get_request_path(req: &HttpRequest) -> String {
@@ -326,23 +957,403 @@ pub fn merge_tilejson(sources: &[&dyn Source], tiles_url: String) -> TileJSON {
     result
 }
 
+fn get_tile_coord(req: &HttpRequest, path: &TileRequest) -> ActixResult<TileCoord> {
+    let xyz = TileCoord {
+        z: path.z,
+        x: path.x,
+        y: path.y,
+    };
+    Ok(match get_tile_scheme(req, &path.source_ids)? {
+        TileScheme::Xyz => xyz,
+        // Tiles outside of the tile grid are rejected by `check_tile` in both schemes
+        TileScheme::Tms => xyz.flip_y().unwrap_or(xyz),
+    })
+}
+
+/// Reject the tiles outside of the tile grid or of the zoom ranges of the sources before any source is queried,
+/// counting the rejections of each source if the `source_errors` tracking is enabled
+fn check_tile(
+    req: &HttpRequest,
+    sources: &TileSources,
+    source_ids: &str,
+    xyz: TileCoord,
+) -> ActixResult<()> {
+    let srcs = sources.get_sources(source_ids, None)?.0;
+    let Err((reason, message)) = InvalidTile::check(&srcs, xyz) else {
+        return Ok(());
+    };
+    debug!("Rejected tile {source_ids}/{xyz:#}: {message}");
+    if let Some(errors) = req.app_data::<Data<SourceErrors>>() {
+        for id in source_ids.split(',') {
+            errors.record_invalid(id, reason);
+        }
+    }
+    Err(match reason {
+        InvalidTile::Zoom => ErrorBadRequest(message),
+        InvalidTile::Coordinates | InvalidTile::ZoomRange => ErrorNotFound(message),
+    })
+}
+
 #[route("/{source_ids}/{z}/{x}/{y}", method = "GET", method = "HEAD")]
 async fn get_tile(
     req: HttpRequest,
     path: Path<TileRequest>,
     sources: Data<TileSources>,
 ) -> ActixResult<HttpResponse> {
-    let xyz = TileCoord {
-        z: path.z,
-        x: path.x,
-        y: path.y,
-    };
+    let xyz = get_tile_coord(&req, &path)?;
+    let query = query_without(req.query_string(), "scheme");
+    serve_tile(
+        &req,
+        sources.as_ref(),
+        &path.source_ids,
+        xyz,
+        TileParams::Query(&query),
+    )
+    .await
+}
 
-    let source_ids = &path.source_ids;
-    let query = req.query_string();
+/// Vector tile decoded to `GeoJSON` with WGS84 coordinates, e.g. for debugging
+#[route(
+    "/{source_ids}/{z}/{x}/{y}.geojson",
+    method = "GET",
+    method = "HEAD",
+    wrap = "middleware::Compress::default()"
+)]
+async fn get_tile_geojson(
+    req: HttpRequest,
+    path: Path<TileRequest>,
+    sources: Data<TileSources>,
+) -> ActixResult<HttpResponse> {
+    let xyz = get_tile_coord(&req, &path)?;
+    check_tile(&req, &sources, &path.source_ids, xyz)?;
+    let (srcs, use_url_query, info) = sources.get_sources(&path.source_ids, Some(xyz.z))?;
+    Quotas::check(&req, &path.source_ids, xyz.z)?;
+    if info.format != Format::Mvt {
+        return Err(ErrorBadRequest(format!(
+            "Only vector tiles can be converted to GeoJSON, but the source has {info} tiles"
+        )));
+    }
+    let query = query_without(req.query_string(), "scheme");
+    let query = use_url_query.then_some(query.as_str());
+    let tile = get_tile_content(srcs.as_slice(), info, &xyz, query, None).await?;
+    let layers = mvt_decode(&tile.data).map_err(map_internal_error)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/geo+json")
+        .json(tile_to_geojson(&layers, xyz)))
+}
+
+/// High-DPI raster tile: the native tile if the source has 512px or larger tiles,
+/// or the regular tile upscaled to twice its size otherwise (PNG only).
+#[route("/{source_ids}/{z}/{x}/{y}@2x", method = "GET", method = "HEAD")]
+async fn get_tile_2x(
+    req: HttpRequest,
+    path: Path<TileRequest>,
+    sources: Data<TileSources>,
+) -> ActixResult<HttpResponse> {
+    let xyz = get_tile_coord(&req, &path)?;
+    check_tile(&req, &sources, &path.source_ids, xyz)?;
+    let (srcs, _, info) = sources.get_sources(&path.source_ids, Some(xyz.z))?;
+    if !matches!(
+        info.format,
+        Format::Png | Format::Jpeg | Format::Webp | Format::Gif
+    ) || path.source_ids.contains(',')
+    {
+        return Err(ErrorBadRequest(
+            "High-DPI @2x tiles are only available for a single raster source",
+        ));
+    }
+    if srcs
+        .first()
+        .map_or(true, |src| src.get_tile_size() >= HIGH_DPI_TILE_SIZE)
+    {
+        let query = query_without(req.query_string(), "scheme");
+        let params = TileParams::Query(&query);
+        return serve_tile(&req, sources.as_ref(), &path.source_ids, xyz, params).await;
+    }
+    if info.format != Format::Png {
+        return Err(ErrorBadRequest(format!(
+            "Source {} has {}px tiles, and upscaling {} tiles is not supported",
+            path.source_ids,
+            srcs[0].get_tile_size(),
+            info.format
+        )));
+    }
+
+    Quotas::check(&req, &path.source_ids, xyz.z)?;
+    let tile = get_tile_content(srcs.as_slice(), info, &xyz, None, None).await?;
+    if tile.data.is_empty() {
+        return Ok(HttpResponse::NoContent().finish());
+    }
+    let data = upscale_png(&tile.data, 2).map_err(map_internal_error)?;
+    Ok(HttpResponse::Ok()
+        .content_type(info.format.content_type())
+        .body(data))
+}
+
+/// Tile with the source parameters given as a JSON object in the request body instead of the URL query
+#[route("/{source_ids}/{z}/{x}/{y}", method = "POST")]
+async fn post_tile(
+    req: HttpRequest,
+    path: Path<TileRequest>,
+    mut body: web::Payload,
+    sources: Data<TileSources>,
+) -> ActixResult<HttpResponse> {
+    let limit = req
+        .app_data::<Data<PostBodyLimit>>()
+        .map_or(POST_BODY_LIMIT_DEFAULT, |v| v.0);
+    let mut data = web::BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        if data.len() + chunk.len() > limit {
+            return Err(ErrorPayloadTooLarge(format!(
+                "Tile request body is larger than {limit} bytes"
+            )));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    let xyz = get_tile_coord(&req, &path)?;
+    let params = TileParams::Body(parse_body_params(&data)?);
+    serve_tile(&req, sources.as_ref(), &path.source_ids, xyz, params).await
+}
+
+#[route("/{source_ids}/quadkey/{key}", method = "GET", method = "HEAD")]
+async fn get_tile_quadkey(
+    req: HttpRequest,
+    path: Path<QuadkeyRequest>,
+    sources: Data<TileSources>,
+) -> ActixResult<HttpResponse> {
+    let xyz = TileCoord::from_quadkey(&path.key)
+        .ok_or_else(|| ErrorBadRequest(format!("Invalid quadkey {}", path.key)))?;
+    let query = query_without(req.query_string(), "scheme");
+    serve_tile(
+        &req,
+        sources.as_ref(),
+        &path.source_ids,
+        xyz,
+        TileParams::Query(&query),
+    )
+    .await
+}
+
+async fn serve_tile(
+    req: &HttpRequest,
+    sources: &TileSources,
+    source_ids: &str,
+    xyz: TileCoord,
+    params: TileParams<'_>,
+) -> ActixResult<HttpResponse> {
+    check_tile(req, sources, source_ids, xyz)?;
+    Quotas::check(req, source_ids, xyz.z)?;
     let encodings = req.get_header::<AcceptEncoding>();
+    let query = params.cache_key();
+    let vary = vary_headers(sources, source_ids)?;
+    let cache_key = vary_cache_key(req, &query, &vary);
+
+    // Only GET requests can be replayed
+    let replayable = matches!(params, TileParams::Query(_));
+    if let Some(recorder) = req
+        .app_data::<Data<RequestRecorder>>()
+        .filter(|_| replayable)
+    {
+        let encoding = req
+            .headers()
+            .get(ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok());
+        recorder.record(source_ids, xyz, &query, encoding);
+    }
+
+    let events = req.app_data::<Data<EventSink>>();
+    let empty_tiles = req.app_data::<Data<EmptyTileCache>>();
+    if let Some(empty_tiles) = empty_tiles {
+        let is_empty = empty_tiles.is_empty(source_ids, xyz, &cache_key);
+        if let Some(events) = events {
+            let cache = "empty_tiles".to_string();
+            let kind = if is_empty {
+                TileEventKind::CacheHit { cache }
+            } else {
+                TileEventKind::CacheMiss { cache }
+            };
+            events.emit(TileEvent::new(source_ids, xyz, &query, kind));
+        }
+        if is_empty {
+            let response = add_vary_headers(HttpResponse::NoContent().finish(), &vary);
+            return Ok(add_routing_headers(req, response, source_ids, xyz, &query));
+        }
+    }
+    let reservation = match req.app_data::<Data<MemoryBudget>>() {
+        Some(memory) => Some(
+            memory
+                .reserve_tile(is_low_priority(req.headers()))
+                .await
+                .map_err(ErrorServiceUnavailable)?,
+        ),
+        None => None,
+    };
+    let started = Instant::now();
+    let response = generate_tile(req, sources, source_ids, xyz, params, encodings).await;
+    if let Some(events) = events {
+        let kind = tile_event_kind(&response, started.elapsed());
+        events.emit(TileEvent::new(source_ids, xyz, &query, kind));
+    }
+    let mut response = response?;
+    if replayable {
+        response = record_sample(req, response, source_ids, xyz, &query);
+    }
+    if let Some(empty_tiles) = empty_tiles {
+        if response.status() == StatusCode::NO_CONTENT {
+            empty_tiles.insert(source_ids, xyz, &cache_key);
+        }
+    }
+    let response = add_vary_headers(stream_large_tile(req, response), &vary);
+    let response = add_routing_headers(req, response, source_ids, xyz, &query);
+    Ok(match reservation {
+        Some(reservation) => response.map_body(|_, body| reservation.attach(body)),
+        None => response,
+    })
+}
+
+/// Request headers that change the content of the tiles of the sources
+fn vary_headers<'a>(sources: &'a TileSources, source_ids: &str) -> ActixResult<Vec<&'a str>> {
+    let srcs = sources.get_sources(source_ids, None)?.0;
+    Ok(srcs
+        .iter()
+        .filter_map(|v| v.vary_header())
+        .unique()
+        .collect())
+}
+
+/// Identify the parameters and the values of the headers that change the tile in the empty tile cache
+fn vary_cache_key(req: &HttpRequest, query: &str, vary: &[&str]) -> String {
+    let mut key = query.to_string();
+    for name in vary {
+        if let Some(value) = req.headers().get(*name).and_then(|v| v.to_str().ok()) {
+            key.push('&');
+            key.push_str(&name.to_ascii_lowercase());
+            key.push('=');
+            key.push_str(value);
+        }
+    }
+    key
+}
+
+fn add_vary_headers(mut response: HttpResponse, vary: &[&str]) -> HttpResponse {
+    for name in vary {
+        if let Ok(value) = HeaderValue::from_str(name) {
+            response.headers_mut().append(VARY, value);
+        }
+    }
+    response
+}
+
+/// Record the response with the `record_samples` config, if enabled and the source has too few samples
+fn record_sample(
+    req: &HttpRequest,
+    response: HttpResponse,
+    source_ids: &str,
+    xyz: TileCoord,
+    query: &str,
+) -> HttpResponse {
+    let Some(samples) = req.app_data::<Data<SampleRecorder>>() else {
+        return response;
+    };
+    if !samples.wants(source_ids, xyz, query) {
+        return response;
+    }
+    let (response, body) = response.into_parts();
+    match body.try_into_bytes() {
+        Ok(body) => {
+            let encoding = req
+                .headers()
+                .get(ACCEPT_ENCODING)
+                .and_then(|v| v.to_str().ok());
+            let recorded =
+                RecordedResponse::new(response.status().as_u16(), response.headers(), &body);
+            samples.record(source_ids, xyz, query, encoding, recorded);
+            response.set_body(BoxBody::new(body))
+        }
+        Err(body) => response.set_body(body),
+    }
+}
+
+/// Add the headers of the `cache_routing` config to a tile response, if enabled
+fn add_routing_headers(
+    req: &HttpRequest,
+    mut response: HttpResponse,
+    source_ids: &str,
+    xyz: TileCoord,
+    query: &str,
+) -> HttpResponse {
+    if let Some(routing) = req.app_data::<Data<CacheRouting>>() {
+        routing.add_headers(response.headers_mut(), source_ids, xyz, query);
+    }
+    response
+}
+
+/// Generate the tile response within the request timeout, adding the `Server-Timing` header if enabled
+async fn generate_tile(
+    req: &HttpRequest,
+    sources: &TileSources,
+    source_ids: &str,
+    xyz: TileCoord,
+    params: TileParams<'_>,
+    encodings: Option<AcceptEncoding>,
+) -> ActixResult<HttpResponse> {
+    let started = Instant::now();
+    let response = with_timings(with_headers(
+        req.headers().clone(),
+        get_tile_params_response(sources, xyz, source_ids, params, encodings),
+    ));
+    let (response, timings) = if let Some(timeout) = req.app_data::<Data<RequestTimeout>>() {
+        let deadline = Instant::now() + timeout.0;
+        tokio::time::timeout_at(deadline.into(), with_deadline(deadline, response))
+            .await
+            .map_err(|_| {
+                ErrorGatewayTimeout(format!(
+                    "Tile {source_ids}/{xyz:#} was not generated within {:?}",
+                    timeout.0
+                ))
+            })?
+    } else {
+        response.await
+    };
+    let mut response = response?;
+    if req.app_data::<Data<ServerTiming>>().is_some() {
+        let value = server_timing_header(&timings, started.elapsed());
+        let headers = response.headers_mut();
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(SERVER_TIMING, value);
+            // Browsers only show the details of cross-origin requests with this header
+            headers.insert(TIMING_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        }
+    }
+    Ok(response)
+}
 
-    get_tile_response(sources.as_ref(), xyz, source_ids, query, encodings).await
+fn tile_event_kind(response: &ActixResult<HttpResponse>, elapsed: Duration) -> TileEventKind {
+    match response {
+        Ok(response) => TileEventKind::TileServed {
+            status: response.status().as_u16(),
+            size: match response.body().size() {
+                BodySize::Sized(size) => size,
+                BodySize::None | BodySize::Stream => 0,
+            },
+            encoding: response
+                .headers()
+                .get(CONTENT_ENCODING)
+                .and_then(|v| v.to_str().ok())
+                .map(ToString::to_string),
+            duration_ms: elapsed.as_secs_f64() * 1000.0,
+        },
+        Err(e) => {
+            let status = e.as_response_error().status_code();
+            let (status, error) = (status.as_u16(), e.to_string());
+            if (400..500).contains(&status) {
+                TileEventKind::RequestError { status, error }
+            } else {
+                TileEventKind::SourceError { status, error }
+            }
+        }
+    }
 }
 
 pub async fn get_tile_response(
@@ -351,16 +1362,42 @@ pub async fn get_tile_response(
     source_ids: &str,
     query: &str,
     encodings: Option<AcceptEncoding>,
+) -> ActixResult<HttpResponse> {
+    let params = TileParams::Query(query);
+    get_tile_params_response(sources, xyz, source_ids, params, encodings).await
+}
+
+async fn get_tile_params_response(
+    sources: &TileSources,
+    xyz: TileCoord,
+    source_ids: &str,
+    params: TileParams<'_>,
+    encodings: Option<AcceptEncoding>,
 ) -> ActixResult<HttpResponse> {
     let (sources, use_url_query, info) = sources.get_sources(source_ids, Some(xyz.z))?;
 
-    let query = use_url_query.then_some(query);
-    let tile = get_tile_content(sources.as_slice(), info, &xyz, query, encodings.as_ref()).await?;
+    let query = if use_url_query { params.parse()? } else { None };
+    let include_feature_count = sources.iter().any(|s| s.include_feature_count());
+    let tile = get_parsed_tile_content(sources.as_slice(), info, &xyz, query, encodings.as_ref());
+    let (tile, counts) = if include_feature_count {
+        with_feature_counts(tile).await
+    } else {
+        (tile.await, Vec::new())
+    };
+    let tile = tile?;
+
+    let mut response = if tile.data.is_empty() {
+        HttpResponse::NoContent()
+    } else {
+        HttpResponse::Ok()
+    };
+    if include_feature_count {
+        add_feature_count_headers(&mut response, &counts);
+    }
 
     Ok(if tile.data.is_empty() {
-        HttpResponse::NoContent().finish()
+        response.finish()
     } else {
-        let mut response = HttpResponse::Ok();
         response.content_type(tile.info.format.content_type());
         if let Some(val) = tile.info.encoding.content_encoding() {
             response.insert_header((CONTENT_ENCODING, val));
@@ -369,24 +1406,49 @@ pub async fn get_tile_response(
     })
 }
 
+/// Add the total and per-layer feature counts recorded by the sources while computing the tile
+fn add_feature_count_headers(response: &mut HttpResponseBuilder, counts: &FeatureCounts) {
+    let total = counts.iter().map(|(_, count)| count).sum::<usize>();
+    response.insert_header((FEATURE_COUNT_HEADER, total));
+    if !counts.is_empty() {
+        let per_layer = counts
+            .iter()
+            .map(|(name, count)| format!("{name}={count}"))
+            .join(", ");
+        response.insert_header((LAYER_FEATURE_COUNT_HEADER, per_layer));
+    }
+}
+
 pub async fn get_tile_content(
     sources: &[&dyn Source],
     info: TileInfo,
     xyz: &TileCoord,
     query: Option<&str>,
     encodings: Option<&AcceptEncoding>,
+) -> ActixResult<Tile> {
+    let query = match query {
+        Some(v) => TileParams::Query(v).parse()?,
+        None => None,
+    };
+    get_parsed_tile_content(sources, info, xyz, query, encodings).await
+}
+
+async fn get_parsed_tile_content(
+    sources: &[&dyn Source],
+    info: TileInfo,
+    xyz: &TileCoord,
+    query: Option<UrlQuery>,
+    encodings: Option<&AcceptEncoding>,
 ) -> ActixResult<Tile> {
     if sources.is_empty() {
         return Err(ErrorNotFound("No valid sources found"));
     }
-    let query = match query {
-        Some(v) if !v.is_empty() => Some(Query::<UrlQuery>::from_query(v)?.into_inner()),
-        _ => None,
-    };
 
+    let started = Instant::now();
     let mut tiles = try_join_all(sources.iter().map(|s| s.get_tile(xyz, &query)))
         .await
-        .map_err(map_internal_error)?;
+        .map_err(map_tile_error)?;
+    record_timing("fetch", started.elapsed());
 
     // Make sure tiles can be concatenated, or if not, that there is only one non-empty tile for each zoom level
     // TODO: can zlib, brotli, or zstd be concatenated?
@@ -404,8 +1466,13 @@ pub async fn get_tile_content(
     // Minor optimization to prevent concatenation if there are less than 2 tiles
     let data = match layer_count {
         1 => tiles.swap_remove(0),
-        0 => return Ok(Tile::new(Vec::new(), info)),
-        _ => tiles.concat(),
+        0 => return Ok(Tile::new(TileData::new(), info)),
+        _ => {
+            let started = Instant::now();
+            let data = tiles.concat();
+            record_timing("merge", started.elapsed());
+            TileData::from(data)
+        }
     };
 
     // decide if (re-)encoding of the tile data is needed, and recompress if so
@@ -444,7 +1511,8 @@ fn recompress(mut tile: Tile, accept_enc: Option<&AcceptEncoding>) -> ActixResul
 }
 
 fn encode(tile: Tile, enc: ContentEncoding) -> ActixResult<Tile> {
-    Ok(match enc {
+    let started = Instant::now();
+    let tile = match enc {
         ContentEncoding::Brotli => Tile::new(
             encode_brotli(&tile.data)?,
             tile.info.encoding(Encoding::Brotli),
@@ -453,12 +1521,15 @@ fn encode(tile: Tile, enc: ContentEncoding) -> ActixResult<Tile> {
             Tile::new(encode_gzip(&tile.data)?, tile.info.encoding(Encoding::Gzip))
         }
         _ => tile,
-    })
+    };
+    record_timing("encode", started.elapsed());
+    Ok(tile)
 }
 
 fn decode(tile: Tile) -> ActixResult<Tile> {
     let info = tile.info;
-    Ok(if info.encoding.is_encoded() {
+    let started = Instant::now();
+    let tile = if info.encoding.is_encoded() {
         match info.encoding {
             Encoding::Gzip => Tile::new(
                 decode_gzip(&tile.data)?,
@@ -468,13 +1539,19 @@ fn decode(tile: Tile) -> ActixResult<Tile> {
                 decode_brotli(&tile.data)?,
                 info.encoding(Encoding::Uncompressed),
             ),
+            Encoding::Zstd => Tile::new(
+                decode_zstd(&tile.data)?,
+                info.encoding(Encoding::Uncompressed),
+            ),
             _ => Err(ErrorBadRequest(format!(
                 "Tile is is stored as {info}, but the client does not accept this encoding"
             )))?,
         }
     } else {
-        tile
-    })
+        return Ok(tile);
+    };
+    record_timing("decode", started.elapsed());
+    Ok(tile)
 }
 
 fn to_encoding(val: ContentEncoding) -> Option<Encoding> {
@@ -482,6 +1559,7 @@ fn to_encoding(val: ContentEncoding) -> Option<Encoding> {
         ContentEncoding::Identity => Encoding::Uncompressed,
         ContentEncoding::Gzip => Encoding::Gzip,
         ContentEncoding::Brotli => Encoding::Brotli,
+        ContentEncoding::Zstd => Encoding::Zstd,
         // TODO: Deflate => Encoding::Zstd or Encoding::Zlib ?
         _ => None?,
     })
@@ -491,14 +1569,35 @@ pub fn router(cfg: &mut web::ServiceConfig) {
     cfg.service(get_health)
         .service(get_index)
         .service(get_catalog)
+        .service(get_status)
+        .service(get_log_levels)
+        .service(put_log_level)
+        .service(delete_log_level)
+        .service(get_config_stage)
+        .service(post_config_stage)
+        .service(get_mirror)
+        .service(post_mirror)
+        .service(delete_mirror)
+        .service(get_bulk_tilejson)
+        .service(get_collections)
+        .service(get_collection_items)
+        .service(get_collection)
         .service(git_source_info)
+        .service(get_source_schema)
+        .service(get_features)
+        .service(get_tile_2x)
+        .service(get_tile_geojson)
         .service(get_tile)
+        .service(post_tile)
+        .service(get_tile_quadkey)
         .service(get_sprite_json)
         .service(get_sprite_png)
-        .service(get_font);
+        .service(get_font)
+        .service(post_style_validate);
 }
 
 /// Create a new initialized Actix `App` instance together with the listening address.
+#[allow(clippy::too_many_lines)]
 pub fn new_server(config: SrvConfig, state: ServerState) -> MartinResult<(Server, String)> {
     let catalog = Catalog::new(&state)?;
     let keep_alive = Duration::from_secs(config.keep_alive.unwrap_or(KEEP_ALIVE_DEFAULT));
@@ -506,17 +1605,132 @@ pub fn new_server(config: SrvConfig, state: ServerState) -> MartinResult<(Server
     let listen_addresses = config
         .listen_addresses
         .unwrap_or_else(|| LISTEN_ADDRESSES_DEFAULT.to_owned());
+    let recorder = match config.record_requests {
+        Some(path) => {
+            info!("Recording all tile requests to {}", path.display());
+            Some(Data::new(RequestRecorder::new(path)?))
+        }
+        None => None,
+    };
+    let samples = match config.record_samples {
+        Some(dir) => {
+            let per_source = config
+                .samples_per_source
+                .unwrap_or(SAMPLES_PER_SOURCE_DEFAULT);
+            info!(
+                "Recording {per_source} tile requests of each source to {}",
+                dir.display()
+            );
+            Some(Data::new(SampleRecorder::new(dir, per_source)?))
+        }
+        None => None,
+    };
+    let refresh = config
+        .empty_tile_cache
+        .as_ref()
+        .and_then(|v| v.refresh.clone());
+    let memory = config
+        .memory_budget
+        .as_ref()
+        .map(|v| Arc::new(MemoryBudget::new(v)));
+    let empty_tiles = config
+        .empty_tile_cache
+        .map(|cfg| Data::new(EmptyTileCache::new(cfg).with_memory_budget(memory.clone())));
+    let memory = memory.map(Data::from);
+    let request_timeout = config
+        .request_timeout
+        .map(|v| Data::new(RequestTimeout(Duration::from_secs(v))));
+    let server_timing = config
+        .server_timing
+        .unwrap_or_default()
+        .then(|| Data::new(ServerTiming));
+    let post_body_limit = config.post_body_limit.map(|v| Data::new(PostBodyLimit(v)));
+    let mirror = config
+        .request_mirroring
+        .unwrap_or_default()
+        .then(|| Data::new(TrafficMirror::default()));
+    let stream_threshold = config
+        .stream_threshold
+        .map(|v| Data::new(StreamThreshold(v)));
+    let asset_max_age = config.asset_max_age.map(|v| Data::new(AssetMaxAge(v)));
+    let tile_schemes = config.tile_schemes.map(|v| Data::new(TileSchemes(v)));
+    let events = config
+        .events
+        .as_ref()
+        .map(EventSink::new)
+        .transpose()?
+        .map(Data::new);
+    let tiles = inject_faults(&state.tiles, config.fault_injection.as_ref());
+    let (tiles, source_errors) = monitor_sources(&tiles, config.source_errors.as_ref());
+    let (tiles, log_levels) = log_sources(tiles, config.log_levels.as_ref());
+    let readiness = state.readiness.clone().map(Data::from);
+    let endpoints = config.endpoints.map(Data::new);
+    let quotas = config.quotas.map(|v| Data::new(Quotas::new(v)));
+    let cache_routing = config
+        .cache_routing
+        .as_ref()
+        .map(CacheRouting::new)
+        .transpose()?
+        .map(Data::new);
+    if let Some(empty_tiles) = &empty_tiles {
+        start_empty_tile_tasks(empty_tiles, refresh, &tiles);
+    }
+    let staging = state.staging.clone().map(Data::from);
+    if let Some(staging) = &staging {
+        staging.serve(ServerState {
+            tiles: tiles.clone(),
+            sprites: state.sprites.clone(),
+            fonts: state.fonts.clone(),
+            readiness: None,
+            staging: None,
+        })?;
+    }
 
     let server = HttpServer::new(move || {
-        let cors_middleware = Cors::default()
-            .allow_any_origin()
-            .allowed_methods(vec!["GET"]);
+        let cors_middleware = cors_middleware(cache_routing.as_ref().map(|v| v.as_ref()));
 
         App::new()
-            .app_data(Data::new(state.tiles.clone()))
-            .app_data(Data::new(state.sprites.clone()))
-            .app_data(Data::new(state.fonts.clone()))
-            .app_data(Data::new(catalog.clone()))
+            .configure(|cfg| {
+                optional_app_data(cfg, recorder.as_ref());
+                optional_app_data(cfg, samples.as_ref());
+                optional_app_data(cfg, empty_tiles.as_ref());
+                optional_app_data(cfg, request_timeout.as_ref());
+                optional_app_data(cfg, server_timing.as_ref());
+                optional_app_data(cfg, post_body_limit.as_ref());
+                optional_app_data(cfg, stream_threshold.as_ref());
+                optional_app_data(cfg, asset_max_age.as_ref());
+                optional_app_data(cfg, tile_schemes.as_ref());
+                optional_app_data(cfg, source_errors.as_ref());
+                optional_app_data(cfg, events.as_ref());
+                optional_app_data(cfg, readiness.as_ref());
+                optional_app_data(cfg, log_levels.as_ref());
+                optional_app_data(cfg, endpoints.as_ref());
+                optional_app_data(cfg, memory.as_ref());
+                optional_app_data(cfg, quotas.as_ref());
+                optional_app_data(cfg, cache_routing.as_ref());
+                optional_app_data(cfg, staging.as_ref());
+                optional_app_data(cfg, mirror.as_ref());
+                // With staging, the sources are added to each request, so that they can be replaced
+                if staging.is_none() {
+                    cfg.app_data(Data::new(tiles.clone()))
+                        .app_data(Data::new(state.sprites.clone()))
+                        .app_data(Data::new(state.fonts.clone()))
+                        .app_data(Data::new(catalog.clone()));
+                }
+            })
+            .wrap_fn(|mut req, srv| {
+                let staged = req.app_data::<Data<ConfigStaging>>();
+                if let Some(data) = staged.and_then(|v| v.data_container()) {
+                    req.add_data_container(data);
+                }
+                srv.call(req)
+            })
+            .wrap_fn(|req, srv| {
+                if let Some(mirror) = req.app_data::<Data<TrafficMirror>>() {
+                    mirror.mirror(req.request());
+                }
+                srv.call(req)
+            })
             .wrap(cors_middleware)
             .wrap(middleware::NormalizePath::new(TrailingSlash::MergeOnly))
             .wrap(middleware::Logger::default())
@@ -532,6 +1746,94 @@ pub fn new_server(config: SrvConfig, state: ServerState) -> MartinResult<(Server
     Ok((server, listen_addresses))
 }
 
+/// Allow requests from any origin, and let browsers read the headers added to tile responses
+fn cors_middleware(cache_routing: Option<&CacheRouting>) -> Cors {
+    Cors::default()
+        .allow_any_origin()
+        .allowed_methods(vec!["GET", "POST"])
+        .expose_headers(
+            [
+                FEATURE_COUNT_HEADER,
+                LAYER_FEATURE_COUNT_HEADER,
+                "Server-Timing",
+            ]
+            .into_iter()
+            .map(ToString::to_string)
+            .chain(
+                cache_routing
+                    .iter()
+                    .flat_map(|v| v.header_names())
+                    .map(|v| v.to_string()),
+            ),
+        )
+}
+
+/// Register the app data of an optional feature if it is enabled
+fn optional_app_data<T: 'static>(cfg: &mut web::ServiceConfig, data: Option<&Data<T>>) {
+    if let Some(data) = data {
+        cfg.app_data(data.clone());
+    }
+}
+
+/// Delay and fail the requests of some sources if enabled with the `fault_injection` config.
+/// The faults are injected before the errors are tracked, so that they also trip the circuit breaker.
+fn inject_faults(
+    tiles: &TileSources,
+    config: Option<&BTreeMap<String, FaultConfig>>,
+) -> TileSources {
+    let Some(faults) = config.and_then(FaultInjection::new) else {
+        return tiles.clone();
+    };
+    tiles.clone().wrap(move |src| faults.wrap(src))
+}
+
+/// Track the errors of all sources if enabled with the `source_errors` config
+fn monitor_sources(
+    tiles: &TileSources,
+    config: Option<&SourceErrorsConfig>,
+) -> (TileSources, Option<Data<SourceErrors>>) {
+    let Some(config) = config else {
+        return (tiles.clone(), None);
+    };
+    let errors = Data::new(SourceErrors::new(config));
+    let monitor = errors.clone();
+    let tiles = tiles.clone().wrap(move |src| monitor.monitor(src));
+    (tiles, Some(errors))
+}
+
+/// Apply the log level overrides of the sources if enabled with the `log_levels` config
+fn log_sources(
+    tiles: TileSources,
+    config: Option<&BTreeMap<String, LogLevel>>,
+) -> (TileSources, Option<Data<SourceLogLevels>>) {
+    let Some(config) = config else {
+        return (tiles, None);
+    };
+    let levels = Arc::new(SourceLogLevels::new(config));
+    let wrapper = levels.clone();
+    let tiles = tiles.wrap(move |src| SourceLogLevels::wrap(&wrapper, src));
+    (tiles, Some(Data::from(levels)))
+}
+
+/// Start the background tasks of the empty tile cache: scheduled purges and refreshes of frequently requested tiles.
+/// Tile responses are not `Send`, so the tasks run on the current thread, like the request handlers.
+fn start_empty_tile_tasks(
+    empty_tiles: &Data<EmptyTileCache>,
+    refresh: Option<EmptyTileRefreshConfig>,
+    tiles: &TileSources,
+) {
+    if empty_tiles.has_purge_schedule() {
+        actix_rt::spawn(purge_empty_tiles(empty_tiles.clone()));
+    }
+    if let Some(refresh) = refresh {
+        actix_rt::spawn(refresh_empty_tiles(
+            empty_tiles.clone(),
+            tiles.clone(),
+            refresh,
+        ));
+    }
+}
+
 fn parse_x_rewrite_url(header: &HeaderValue) -> Option<String> {
     header
         .to_str()
@@ -544,43 +1846,10 @@ fn parse_x_rewrite_url(header: &HeaderValue) -> Option<String> {
 mod tests {
     use std::collections::BTreeMap;
 
-    use async_trait::async_trait;
     use tilejson::{tilejson, Bounds, VectorLayer};
 
     use super::*;
-    use crate::source::{Source, TileData};
-
-    #[derive(Debug, Clone)]
-    struct TestSource {
-        tj: TileJSON,
-    }
-
-    #[async_trait]
-    impl Source for TestSource {
-        fn get_id(&self) -> &str {
-            "id"
-        }
-
-        fn get_tilejson(&self) -> &TileJSON {
-            &self.tj
-        }
-
-        fn get_tile_info(&self) -> TileInfo {
-            unimplemented!()
-        }
-
-        fn clone_source(&self) -> Box<dyn Source> {
-            unimplemented!()
-        }
-
-        async fn get_tile(
-            &self,
-            _xyz: &TileCoord,
-            _url_query: &Option<UrlQuery>,
-        ) -> MartinResult<TileData> {
-            unimplemented!()
-        }
-    }
+    use crate::test_utils::TestSource;
 
     #[test]
     fn test_merge_tilejson() {
@@ -599,6 +1868,7 @@ mod tests {
                     ]))
                 ],
             },
+            ..Default::default()
         };
         let tj = merge_tilejson(&[&src1], url.clone());
         assert_eq!(
@@ -623,6 +1893,7 @@ mod tests {
                     ]))
                 ],
             },
+            ..Default::default()
         };
 
         let tj = merge_tilejson(&[&src1, &src2], url.clone());
@@ -645,4 +1916,79 @@ mod tests {
             ])
         );
     }
+
+    #[test]
+    fn test_server_timing_header() {
+        let timings = vec![
+            ("queue", Duration::from_micros(250)),
+            ("fetch", Duration::from_millis(12)),
+        ];
+        assert_eq!(
+            server_timing_header(&timings, Duration::from_millis(15)),
+            "queue;dur=0.2, fetch;dur=12.0, total;dur=15.0"
+        );
+    }
+
+    #[test]
+    fn test_body_params() {
+        let params = parse_body_params(br#"{"name": "a", "num": "1", "ids": [1, 2]}"#).unwrap();
+        assert_eq!(params["name"], r#""a""#);
+        assert_eq!(params["num"], r#""1""#);
+        assert_eq!(params["ids"], "[1,2]");
+        assert!(parse_body_params(b"[1, 2]").is_err());
+        assert!(parse_body_params(b"not json").is_err());
+
+        let key = TileParams::Body(params.clone()).cache_key();
+        let reordered = parse_body_params(br#"{"ids": [1, 2], "num": "1", "name": "a"}"#).unwrap();
+        assert_eq!(TileParams::Body(reordered).cache_key(), key);
+        let other = parse_body_params(br#"{"ids": [1, 3], "num": "1", "name": "a"}"#).unwrap();
+        assert_ne!(TileParams::Body(other).cache_key(), key);
+        assert_eq!(TileParams::Query("a=1").cache_key(), "a=1");
+    }
+
+    #[test]
+    fn test_query_without() {
+        assert_eq!(query_without("scheme=tms&a=1", "scheme"), "a=1");
+        assert_eq!(query_without("a=1&scheme&b=2", "scheme"), "a=1&b=2");
+        assert_eq!(query_without("schemes=1&a=&&b", "scheme"), "schemes=1&a=&b");
+        assert_eq!(query_without("scheme=xyz", "scheme"), "");
+        assert_eq!(query_without("", "scheme"), "");
+    }
+
+    #[test]
+    fn test_feature_count_headers() {
+        let counts = vec![("roads".to_string(), 3), ("points".to_string(), 0)];
+        let mut response = HttpResponse::Ok();
+        add_feature_count_headers(&mut response, &counts);
+        let response = response.finish();
+        let header = |name| response.headers().get(name).unwrap().to_str().unwrap();
+        assert_eq!(header(FEATURE_COUNT_HEADER), "3");
+        assert_eq!(header(LAYER_FEATURE_COUNT_HEADER), "roads=3, points=0");
+
+        let mut response = HttpResponse::NoContent();
+        add_feature_count_headers(&mut response, &Vec::new());
+        let response = response.finish();
+        assert_eq!(response.headers().get(FEATURE_COUNT_HEADER).unwrap(), "0");
+        assert!(response.headers().get(LAYER_FEATURE_COUNT_HEADER).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn stream_large_tiles() {
+        let req = actix_web::test::TestRequest::default()
+            .app_data(Data::new(StreamThreshold(100)))
+            .to_http_request();
+        let data = TileData::from(vec![7_u8; STREAM_CHUNK_SIZE * 2 + 1]);
+        let response = stream_large_tile(&req, HttpResponse::Ok().body(data.clone()));
+        let body = response.into_body();
+        assert_eq!(body.size(), BodySize::Sized(data.len() as u64));
+        assert!(body.try_into_bytes().is_err(), "not a single buffer");
+
+        let response = stream_large_tile(&req, HttpResponse::Ok().body(data.clone()));
+        let body = actix_web::body::to_bytes(response.into_body()).await;
+        assert_eq!(body.unwrap(), data);
+
+        let small = HttpResponse::Ok().body(vec![7_u8; 100]);
+        let body = stream_large_tile(&req, small).into_body();
+        assert_eq!(body.try_into_bytes().unwrap().len(), 100);
+    }
 }
@@ -0,0 +1,443 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use deadpool_postgres::tokio_postgres::error::SqlState;
+use deadpool_postgres::tokio_postgres::Error as TokioPgError;
+use deadpool_postgres::PoolError;
+use log::{info, warn};
+use martin_tile_utils::TileInfo;
+use serde::{Deserialize, Serialize};
+use tilejson::TileJSON;
+
+use crate::derived::DerivedError;
+use crate::file_config::FileError;
+use crate::pg::PgError;
+use crate::source::{
+    CatalogSourceEntry, FeatureFilter, Source, SourceFieldStats, TileData, UrlQuery,
+};
+use crate::srv::validation::InvalidTile;
+use crate::{MartinError, MartinResult, TileCoord};
+
+pub const ERROR_WINDOW_DEFAULT: u64 = 60;
+pub const ERROR_RATE_DEFAULT: f64 = 0.5;
+pub const MIN_REQUESTS_DEFAULT: u64 = 20;
+pub const COOLDOWN_DEFAULT: u64 = 30;
+
+/// Kind of backend failure, used to group the errors of each source
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorClass {
+    /// The backend did not respond in time, e.g. a statement or pool timeout
+    Timeout,
+    /// The connection to the backend could not be established or was lost
+    Connection,
+    /// The backend rejected the query
+    Sql,
+    /// The tile data returned by the backend could not be decoded
+    Decode,
+    Other,
+}
+
+impl ErrorClass {
+    #[must_use]
+    pub fn classify(e: &MartinError) -> Self {
+        match e {
+            MartinError::PostgresError(e) => match e {
+                PgError::PostgresPoolConnError(PoolError::Timeout(_), _) => Self::Timeout,
+                PgError::PostgresPoolConnError(..) => Self::Connection,
+                PgError::PostgresError(e, _)
+                | PgError::PrepareQueryError(e, ..)
+                | PgError::GetTileError(e, ..)
+                | PgError::GetTileWithQueryError(e, ..) => Self::classify_pg(e),
+                _ => Self::Other,
+            },
+            MartinError::MbtilesError(_) => Self::Sql,
+            MartinError::FileError(FileError::AquireConnError(_) | FileError::IoError(..)) => {
+                Self::Connection
+            }
+            MartinError::DerivedError(
+                DerivedError::RasterDecodeError(_) | DerivedError::VectorDecodeError(_),
+            ) => Self::Decode,
+            _ => Self::Other,
+        }
+    }
+
+    fn classify_pg(e: &TokioPgError) -> Self {
+        match e.code() {
+            Some(code) if *code == SqlState::QUERY_CANCELED => Self::Timeout,
+            Some(_) => Self::Sql,
+            // Errors without a SQL state come from the client, e.g. a closed connection
+            None => Self::Connection,
+        }
+    }
+}
+
+/// Tracks the errors of each tile source, and optionally stops querying failing sources
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct SourceErrorsConfig {
+    /// Length of the window used to compute the error rate, in seconds
+    pub window: Option<u64>,
+    /// Respond with `503 Service Unavailable` for a while if a source fails too often
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct CircuitBreakerConfig {
+    /// Fraction of failed requests within the window that opens the circuit, from 0 to 1
+    pub error_rate: Option<f64>,
+    /// Minimum number of requests within the window before the circuit can open
+    pub min_requests: Option<u64>,
+    /// How long to reject requests to a failing source, in seconds
+    pub cooldown: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CircuitState {
+    /// Requests are passed to the source
+    Closed,
+    /// Requests are rejected without querying the source
+    Open,
+}
+
+/// Error statistics of a single source, as reported by the `/status` endpoint
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SourceStatus {
+    pub state: CircuitState,
+    /// Number of requests passed to the source
+    pub requests: u64,
+    /// Number of failed requests by error class
+    pub errors: BTreeMap<ErrorClass, u64>,
+    /// Number of requests rejected while the circuit was open
+    pub rejected: u64,
+    /// Number of tile requests rejected without querying the source, by reason
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub invalid: BTreeMap<InvalidTile, u64>,
+    /// Fraction of failed requests in the current window
+    pub error_rate: f64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Limits {
+    window: Duration,
+    /// error rate, min requests, and cooldown of the circuit breaker
+    breaker: Option<(f64, u64, Duration)>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    requests: u64,
+    errors: BTreeMap<ErrorClass, u64>,
+    rejected: u64,
+    invalid: BTreeMap<InvalidTile, u64>,
+    window_start: Option<Instant>,
+    window_requests: u64,
+    window_errors: u64,
+    open_until: Option<Instant>,
+}
+
+impl Counters {
+    #[allow(clippy::cast_precision_loss)]
+    fn error_rate(&self) -> f64 {
+        if self.window_requests == 0 {
+            0.0
+        } else {
+            self.window_errors as f64 / self.window_requests as f64
+        }
+    }
+
+    fn reset_window(&mut self, now: Instant) {
+        self.window_start = Some(now);
+        self.window_requests = 0;
+        self.window_errors = 0;
+    }
+}
+
+#[derive(Debug)]
+struct SourceHealth {
+    id: String,
+    limits: Limits,
+    counters: Mutex<Counters>,
+}
+
+impl SourceHealth {
+    /// True if the request must be rejected because the circuit is open
+    fn reject(&self, now: Instant) -> bool {
+        let mut c = self.counters.lock().unwrap_or_else(PoisonError::into_inner);
+        match c.open_until {
+            Some(until) if until > now => {
+                c.rejected += 1;
+                true
+            }
+            Some(_) => {
+                info!("Resuming requests to source {}", self.id);
+                c.open_until = None;
+                c.reset_window(now);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn record(&self, error: Option<ErrorClass>, now: Instant) {
+        let mut c = self.counters.lock().unwrap_or_else(PoisonError::into_inner);
+        if c.window_start
+            .map_or(true, |start| now >= start + self.limits.window)
+        {
+            c.reset_window(now);
+        }
+        c.requests += 1;
+        c.window_requests += 1;
+        let Some(error) = error else {
+            return;
+        };
+        *c.errors.entry(error).or_default() += 1;
+        c.window_errors += 1;
+        if let Some((error_rate, min_requests, cooldown)) = self.limits.breaker {
+            if c.open_until.is_none()
+                && c.window_requests >= min_requests
+                && c.error_rate() >= error_rate
+            {
+                warn!(
+                    "Source {} failed {} of the last {} requests, rejecting requests for {cooldown:?}",
+                    self.id, c.window_errors, c.window_requests
+                );
+                c.open_until = Some(now + cooldown);
+            }
+        }
+    }
+
+    fn status(&self, now: Instant) -> SourceStatus {
+        let c = self.counters.lock().unwrap_or_else(PoisonError::into_inner);
+        SourceStatus {
+            state: if c.open_until.map_or(false, |until| until > now) {
+                CircuitState::Open
+            } else {
+                CircuitState::Closed
+            },
+            requests: c.requests,
+            errors: c.errors.clone(),
+            rejected: c.rejected,
+            invalid: c.invalid.clone(),
+            error_rate: c.error_rate(),
+        }
+    }
+}
+
+/// Error statistics of all monitored sources
+#[derive(Debug)]
+pub struct SourceErrors {
+    limits: Limits,
+    sources: Mutex<BTreeMap<String, Arc<SourceHealth>>>,
+}
+
+impl SourceErrors {
+    #[must_use]
+    pub fn new(config: &SourceErrorsConfig) -> Self {
+        let breaker = config.circuit_breaker.as_ref().map(|cfg| {
+            (
+                cfg.error_rate.unwrap_or(ERROR_RATE_DEFAULT),
+                cfg.min_requests.unwrap_or(MIN_REQUESTS_DEFAULT),
+                Duration::from_secs(cfg.cooldown.unwrap_or(COOLDOWN_DEFAULT)),
+            )
+        });
+        Self {
+            limits: Limits {
+                window: Duration::from_secs(config.window.unwrap_or(ERROR_WINDOW_DEFAULT)),
+                breaker,
+            },
+            sources: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Wrap the source so that all of its errors are tracked
+    #[must_use]
+    pub fn monitor(&self, source: Box<dyn Source>) -> Box<dyn Source> {
+        let id = source.get_id().to_string();
+        let health = Arc::new(SourceHealth {
+            id: id.clone(),
+            limits: self.limits,
+            counters: Mutex::new(Counters::default()),
+        });
+        let mut sources = self.sources.lock().unwrap_or_else(PoisonError::into_inner);
+        sources.insert(id, health.clone());
+        Box::new(MonitoredSource { source, health })
+    }
+
+    /// Count a tile request of the source that was rejected before it was passed to the source
+    pub fn record_invalid(&self, id: &str, reason: InvalidTile) {
+        let sources = self.sources.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(health) = sources.get(id) {
+            let mut c = health
+                .counters
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            *c.invalid.entry(reason).or_default() += 1;
+        }
+    }
+
+    #[must_use]
+    pub fn status(&self) -> BTreeMap<String, SourceStatus> {
+        let now = Instant::now();
+        let sources = self.sources.lock().unwrap_or_else(PoisonError::into_inner);
+        sources
+            .iter()
+            .map(|(id, health)| (id.clone(), health.status(now)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MonitoredSource {
+    source: Box<dyn Source>,
+    health: Arc<SourceHealth>,
+}
+
+#[async_trait]
+impl Source for MonitoredSource {
+    fn get_id(&self) -> &str {
+        self.source.get_id()
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        self.source.get_tilejson()
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.source.get_tile_info()
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.source.support_url_query()
+    }
+
+    fn get_tile_size(&self) -> u16 {
+        self.source.get_tile_size()
+    }
+
+    fn include_feature_count(&self) -> bool {
+        self.source.include_feature_count()
+    }
+
+    fn vary_header(&self) -> Option<&str> {
+        self.source.vary_header()
+    }
+
+    async fn get_tile(&self, xyz: &TileCoord, query: &Option<UrlQuery>) -> MartinResult<TileData> {
+        if self.health.reject(Instant::now()) {
+            return Err(MartinError::SourceUnavailable(self.get_id().to_string()));
+        }
+        let result = self.source.get_tile(xyz, query).await;
+        let error = result.as_ref().err().map(ErrorClass::classify);
+        self.health.record(error, Instant::now());
+        result
+    }
+
+    async fn get_field_stats(&self) -> MartinResult<Option<SourceFieldStats>> {
+        self.source.get_field_stats().await
+    }
+
+    fn supports_features(&self) -> bool {
+        self.source.supports_features()
+    }
+
+    async fn get_features(
+        &self,
+        filter: &FeatureFilter,
+    ) -> MartinResult<Option<Vec<serde_json::Value>>> {
+        self.source.get_features(filter).await
+    }
+
+    fn is_valid_zoom(&self, zoom: u8) -> bool {
+        self.source.is_valid_zoom(zoom)
+    }
+
+    fn get_catalog_entry(&self) -> CatalogSourceEntry {
+        self.source.get_catalog_entry()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health(breaker: Option<(f64, u64, Duration)>) -> SourceHealth {
+        SourceHealth {
+            id: "points".to_string(),
+            limits: Limits {
+                window: Duration::from_secs(60),
+                breaker,
+            },
+            counters: Mutex::new(Counters::default()),
+        }
+    }
+
+    #[test]
+    fn classify_errors() {
+        let e = MartinError::DerivedError(DerivedError::RasterDecodeError("bad".to_string()));
+        assert_eq!(ErrorClass::classify(&e), ErrorClass::Decode);
+        let e = MartinError::FileError(FileError::AquireConnError("busy".to_string()));
+        assert_eq!(ErrorClass::classify(&e), ErrorClass::Connection);
+        let e = MartinError::PostgresError(PgError::InvalidUrlQuery(
+            "points".to_string(),
+            "foo".to_string(),
+        ));
+        assert_eq!(ErrorClass::classify(&e), ErrorClass::Other);
+    }
+
+    #[test]
+    fn error_counts() {
+        let h = health(None);
+        let now = Instant::now();
+        h.record(None, now);
+        h.record(Some(ErrorClass::Timeout), now);
+        h.record(Some(ErrorClass::Timeout), now);
+        h.record(Some(ErrorClass::Sql), now);
+        assert!(!h.reject(now), "no circuit breaker");
+        let status = h.status(now);
+        assert_eq!(status.state, CircuitState::Closed);
+        assert_eq!(status.requests, 4);
+        assert_eq!(
+            status.errors,
+            BTreeMap::from([(ErrorClass::Timeout, 2), (ErrorClass::Sql, 1)])
+        );
+        assert!((status.error_rate - 0.75).abs() < f64::EPSILON);
+
+        // A new window starts after the old one expires
+        h.record(None, now + Duration::from_secs(61));
+        let status = h.status(now);
+        assert_eq!(status.requests, 5);
+        assert!(status.error_rate.abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn circuit_breaker() {
+        let h = health(Some((0.5, 4, Duration::from_secs(30))));
+        let now = Instant::now();
+        h.record(Some(ErrorClass::Connection), now);
+        h.record(Some(ErrorClass::Connection), now);
+        h.record(Some(ErrorClass::Connection), now);
+        assert!(!h.reject(now), "too few requests to open the circuit");
+        h.record(None, now);
+        assert!(!h.reject(now), "success does not open the circuit");
+        h.record(Some(ErrorClass::Connection), now);
+        assert!(h.reject(now));
+        assert!(h.reject(now + Duration::from_secs(29)));
+        assert_eq!(h.status(now).state, CircuitState::Open);
+        assert_eq!(h.status(now).rejected, 2);
+
+        let later = now + Duration::from_secs(30);
+        assert!(!h.reject(later), "circuit closes after the cooldown");
+        assert_eq!(h.status(later).state, CircuitState::Closed);
+        h.record(Some(ErrorClass::Connection), later);
+        assert!(!h.reject(later), "error rate is computed for a new window");
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use async_trait::async_trait;
+use log::{LevelFilter, Log, Metadata, Record};
+use martin_tile_utils::TileInfo;
+use serde::{Deserialize, Serialize};
+use tilejson::TileJSON;
+
+use crate::source::{
+    CatalogSourceEntry, FeatureFilter, Source, SourceFieldStats, TileData, UrlQuery,
+};
+use crate::{MartinResult, TileCoord};
+
+tokio::task_local! {
+    /// Log level of the source that is processing the current request, see [`SourceLogLevels`]
+    static SOURCE_LOG_LEVEL: LevelFilter;
+}
+
+/// Log level of a single source
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        serde_yaml::from_str(value.trim()).map_err(|_| {
+            format!("Invalid log level `{value}`, expected off, error, warn, info, debug, or trace")
+        })
+    }
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(value: LogLevel) -> Self {
+        match value {
+            LogLevel::Off => Self::Off,
+            LogLevel::Error => Self::Error,
+            LogLevel::Warn => Self::Warn,
+            LogLevel::Info => Self::Info,
+            LogLevel::Debug => Self::Debug,
+            LogLevel::Trace => Self::Trace,
+        }
+    }
+}
+
+/// Log levels of individual sources, used instead of the global level while they process requests.
+/// The levels can be changed while the server is running.
+#[derive(Debug, Default)]
+pub struct SourceLogLevels {
+    levels: RwLock<BTreeMap<String, LogLevel>>,
+}
+
+impl SourceLogLevels {
+    #[must_use]
+    pub fn new(levels: &BTreeMap<String, LogLevel>) -> Self {
+        let result = Self::default();
+        for (id, level) in levels {
+            result.set(id, Some(*level));
+        }
+        result
+    }
+
+    /// Override the log level of the source, or use the global level again with `None`
+    pub fn set(&self, source_id: &str, level: Option<LogLevel>) {
+        let mut levels = self.levels.write().unwrap_or_else(PoisonError::into_inner);
+        match level {
+            Some(level) => {
+                // Log macros skip the records above the maximum level before asking the logger
+                if LevelFilter::from(level) > log::max_level() {
+                    log::set_max_level(level.into());
+                }
+                levels.insert(source_id.to_string(), level);
+            }
+            None => {
+                levels.remove(source_id);
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn get(&self, source_id: &str) -> Option<LogLevel> {
+        let levels = self.levels.read().unwrap_or_else(PoisonError::into_inner);
+        levels.get(source_id).copied()
+    }
+
+    #[must_use]
+    pub fn levels(&self) -> BTreeMap<String, LogLevel> {
+        self.levels
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Wrap the source so that its log level override is applied to all of its requests
+    #[must_use]
+    pub fn wrap(levels: &Arc<Self>, source: Box<dyn Source>) -> Box<dyn Source> {
+        Box::new(LoggedSource {
+            source,
+            levels: levels.clone(),
+        })
+    }
+}
+
+/// Logger that uses the level of the source processing the current request if it has one,
+/// and the `RUST_LOG` filters otherwise
+pub struct SourceLogger {
+    filter: env_logger::filter::Filter,
+    inner: env_logger::Logger,
+}
+
+impl SourceLogger {
+    /// Install the logger, with the default filters used if `RUST_LOG` is not set
+    pub fn init(default_filters: &str) {
+        let filters = std::env::var(env_logger::DEFAULT_FILTER_ENV)
+            .unwrap_or_else(|_| default_filters.to_string());
+        let filter = env_logger::filter::Builder::new().parse(&filters).build();
+        // Filtering is done by this logger, so the inner one writes everything it gets
+        let mut builder = env_logger::Builder::new();
+        builder.filter_level(LevelFilter::Trace);
+        if let Ok(style) = std::env::var(env_logger::DEFAULT_WRITE_STYLE_ENV) {
+            builder.parse_write_style(&style);
+        }
+        let max_level = filter.filter();
+        let inner = builder.build();
+        if log::set_boxed_logger(Box::new(Self { filter, inner })).is_ok() {
+            log::set_max_level(max_level);
+        }
+    }
+}
+
+impl Log for SourceLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match SOURCE_LOG_LEVEL.try_with(|v| *v) {
+            Ok(level) => metadata.level() <= level,
+            Err(_) => self.filter.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LoggedSource {
+    source: Box<dyn Source>,
+    levels: Arc<SourceLogLevels>,
+}
+
+impl LoggedSource {
+    async fn with_level<F: Future>(&self, fut: F) -> F::Output {
+        match self.levels.get(self.get_id()) {
+            Some(level) => SOURCE_LOG_LEVEL.scope(level.into(), fut).await,
+            None => fut.await,
+        }
+    }
+}
+
+#[async_trait]
+impl Source for LoggedSource {
+    fn get_id(&self) -> &str {
+        self.source.get_id()
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        self.source.get_tilejson()
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.source.get_tile_info()
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.source.support_url_query()
+    }
+
+    fn get_tile_size(&self) -> u16 {
+        self.source.get_tile_size()
+    }
+
+    fn include_feature_count(&self) -> bool {
+        self.source.include_feature_count()
+    }
+
+    fn vary_header(&self) -> Option<&str> {
+        self.source.vary_header()
+    }
+
+    async fn get_tile(&self, xyz: &TileCoord, query: &Option<UrlQuery>) -> MartinResult<TileData> {
+        self.with_level(self.source.get_tile(xyz, query)).await
+    }
+
+    async fn get_field_stats(&self) -> MartinResult<Option<SourceFieldStats>> {
+        self.with_level(self.source.get_field_stats()).await
+    }
+
+    fn supports_features(&self) -> bool {
+        self.source.supports_features()
+    }
+
+    async fn get_features(
+        &self,
+        filter: &FeatureFilter,
+    ) -> MartinResult<Option<Vec<serde_json::Value>>> {
+        self.with_level(self.source.get_features(filter)).await
+    }
+
+    fn is_valid_zoom(&self, zoom: u8) -> bool {
+        self.source.is_valid_zoom(zoom)
+    }
+
+    fn get_catalog_entry(&self) -> CatalogSourceEntry {
+        self.source.get_catalog_entry()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_levels() {
+        assert_eq!(LogLevel::parse("debug"), Ok(LogLevel::Debug));
+        assert_eq!(LogLevel::parse(" trace\n"), Ok(LogLevel::Trace));
+        assert!(LogLevel::parse("verbose").is_err());
+
+        let levels = SourceLogLevels::new(&[("points".to_string(), LogLevel::Debug)].into());
+        assert_eq!(levels.get("points"), Some(LogLevel::Debug));
+        assert!(log::max_level() >= LevelFilter::Debug);
+        levels.set("lines", Some(LogLevel::Error));
+        levels.set("points", None);
+        assert_eq!(levels.get("points"), None);
+        assert_eq!(
+            levels.levels(),
+            [("lines".to_string(), LogLevel::Error)].into()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn source_logger() {
+        let logger = SourceLogger {
+            filter: env_logger::filter::Builder::new()
+                .parse("martin=info")
+                .build(),
+            inner: env_logger::Builder::new().build(),
+        };
+        let metadata = |level| {
+            Metadata::builder()
+                .level(level)
+                .target("martin::pg")
+                .build()
+        };
+        assert!(!logger.enabled(&metadata(log::Level::Debug)));
+        assert!(logger.enabled(&metadata(log::Level::Info)));
+        let debug = SOURCE_LOG_LEVEL.scope(LevelFilter::Debug, async {
+            logger.enabled(&metadata(log::Level::Debug))
+        });
+        assert!(debug.await);
+        let off = SOURCE_LOG_LEVEL.scope(LevelFilter::Off, async {
+            logger.enabled(&metadata(log::Level::Error))
+        });
+        assert!(!off.await);
+    }
+}
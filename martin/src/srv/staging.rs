@@ -0,0 +1,249 @@
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+use actix_web::dev::Extensions;
+use actix_web::web::Data;
+use futures::future::LocalBoxFuture;
+use log::{error, info};
+use serde::Serialize;
+
+use crate::config::ServerState;
+use crate::fonts::FontSources;
+use crate::source::TileSources;
+use crate::sprites::SpriteSources;
+use crate::srv::empty_tiles::EmptyTileCache;
+use crate::srv::server::Catalog;
+use crate::MartinResult;
+
+/// Load the configuration again, and initialize all of its sources
+pub type ConfigLoader =
+    Arc<dyn Fn() -> LocalBoxFuture<'static, MartinResult<ServerState>> + Send + Sync>;
+
+/// State of the staged configuration
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum StagingState {
+    /// No configuration is being loaded
+    Idle,
+    /// The sources of the staged configuration are being initialized
+    Loading,
+    /// The last staged configuration could not be loaded, the previous one is still served
+    Failed { error: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct StagingStatus {
+    #[serde(flatten)]
+    pub state: StagingState,
+    /// Number of switchovers since the server has started
+    pub generation: u64,
+}
+
+/// Sources served to the requests, all replaced at once by a switchover
+#[derive(Clone)]
+struct Registries {
+    tiles: Data<TileSources>,
+    sprites: Data<SpriteSources>,
+    fonts: Data<FontSources>,
+    catalog: Data<Catalog>,
+}
+
+impl Registries {
+    fn new(state: ServerState) -> MartinResult<Self> {
+        Ok(Self {
+            catalog: Data::new(Catalog::new(&state)?),
+            tiles: Data::new(state.tiles),
+            sprites: Data::new(state.sprites),
+            fonts: Data::new(state.fonts),
+        })
+    }
+}
+
+/// Loads a new configuration in the background while the current one is served, see [`SrvConfig::config_staging`](crate::srv::SrvConfig::config_staging).
+/// Once all of its sources are initialized, new requests use them, and the previous sources are dropped
+/// as soon as the requests still using them have finished.
+pub struct ConfigStaging {
+    loader: ConfigLoader,
+    current: RwLock<Option<Arc<Registries>>>,
+    status: Mutex<StagingStatus>,
+}
+
+impl ConfigStaging {
+    #[must_use]
+    pub fn new(loader: ConfigLoader) -> Self {
+        Self {
+            loader,
+            current: RwLock::default(),
+            status: Mutex::new(StagingStatus {
+                state: StagingState::Idle,
+                generation: 0,
+            }),
+        }
+    }
+
+    /// Serve these sources until the next switchover
+    pub(crate) fn serve(&self, state: ServerState) -> MartinResult<()> {
+        let registries = Arc::new(Registries::new(state)?);
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = Some(registries);
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn status(&self) -> StagingStatus {
+        self.status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// App data with the current sources, added to each request so that the handlers use them
+    pub(crate) fn data_container(&self) -> Option<Rc<Extensions>> {
+        let current = self
+            .current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()?;
+        let mut data = Extensions::new();
+        data.insert(current.tiles.clone());
+        data.insert(current.sprites.clone());
+        data.insert(current.fonts.clone());
+        data.insert(current.catalog.clone());
+        Some(Rc::new(data))
+    }
+
+    /// Start loading the configuration in the background, unless it is already being loaded.
+    /// The empty tile cache is cleared by the switchover, as the new sources may have different data.
+    pub fn stage(
+        staging: &Data<Self>,
+        empty_tiles: Option<Data<EmptyTileCache>>,
+    ) -> Result<(), String> {
+        {
+            let mut status = staging
+                .status
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if status.state == StagingState::Loading {
+                return Err("A staged configuration is already being loaded".to_string());
+            }
+            status.state = StagingState::Loading;
+        }
+        info!("Loading the staged configuration");
+        let staging = staging.clone();
+        actix_rt::spawn(async move {
+            let result = (staging.loader)().await;
+            let state = match result.and_then(|state| staging.switch(state)) {
+                Ok(generation) => {
+                    if let Some(empty_tiles) = empty_tiles {
+                        empty_tiles.clear();
+                    }
+                    info!("Switched to the staged configuration, generation {generation}");
+                    StagingState::Idle
+                }
+                Err(e) => {
+                    error!("Unable to load the staged configuration: {e}");
+                    StagingState::Failed {
+                        error: e.to_string(),
+                    }
+                }
+            };
+            staging
+                .status
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .state = state;
+        });
+        Ok(())
+    }
+
+    /// Replace the served sources with the initialized ones, wrapped like the current ones
+    fn switch(&self, state: ServerState) -> MartinResult<u64> {
+        let mut current = self.current.write().unwrap_or_else(PoisonError::into_inner);
+        let tiles = match current.as_ref() {
+            Some(current) => current.tiles.wrap_like(state.tiles),
+            None => state.tiles,
+        };
+        let registries = Registries::new(ServerState { tiles, ..state })?;
+        *current = Some(Arc::new(registries));
+        drop(current);
+        let mut status = self.status.lock().unwrap_or_else(PoisonError::into_inner);
+        status.generation += 1;
+        Ok(status.generation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::MartinError;
+
+    fn empty_state() -> ServerState {
+        ServerState {
+            tiles: TileSources::default(),
+            sprites: SpriteSources::default(),
+            fonts: FontSources::default(),
+            readiness: None,
+            staging: None,
+        }
+    }
+
+    async fn wait_while_loading(staging: &ConfigStaging) -> StagingStatus {
+        for _ in 0..100 {
+            let status = staging.status();
+            if status.state != StagingState::Loading {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the staged configuration was not loaded");
+    }
+
+    #[actix_rt::test]
+    async fn switchover() {
+        let fail = Arc::new(AtomicBool::new(false));
+        let should_fail = fail.clone();
+        let loader: ConfigLoader = Arc::new(move || {
+            let should_fail = should_fail.load(Ordering::Relaxed);
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                if should_fail {
+                    Err(MartinError::InternalError("invalid config".into()))
+                } else {
+                    Ok(empty_state())
+                }
+            })
+        });
+        let staging = Data::new(ConfigStaging::new(loader));
+        assert!(staging.data_container().is_none());
+        staging.serve(empty_state()).unwrap();
+        let container = staging.data_container().unwrap();
+        assert!(container.contains::<Data<TileSources>>());
+        assert!(container.contains::<Data<Catalog>>());
+
+        ConfigStaging::stage(&staging, None).unwrap();
+        assert_eq!(staging.status().state, StagingState::Loading);
+        assert!(ConfigStaging::stage(&staging, None).is_err());
+        let status = wait_while_loading(&staging).await;
+        assert_eq!(
+            status,
+            StagingStatus {
+                state: StagingState::Idle,
+                generation: 1
+            }
+        );
+
+        fail.store(true, Ordering::Relaxed);
+        ConfigStaging::stage(&staging, None).unwrap();
+        let status = wait_while_loading(&staging).await;
+        assert_eq!(
+            status.state,
+            StagingState::Failed {
+                error: "Internal error: invalid config".to_string()
+            }
+        );
+        assert_eq!(status.generation, 1, "the previous config is still served");
+        assert!(staging.data_container().is_some());
+    }
+}
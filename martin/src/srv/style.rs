@@ -0,0 +1,326 @@
+use std::collections::HashSet;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::fonts::FontCatalog;
+use crate::source::TileSources;
+use crate::sprites::SpriteCatalog;
+
+/// Expression operators that may appear as the first item of a `text-font` array
+const FONT_EXPRESSIONS: &[&str] = &["case", "coalesce", "concat", "get", "match", "step"];
+
+/// Result of validating a `MapLibre` style against the sources served by Martin
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StyleValidation {
+    /// True if no errors were found
+    pub valid: bool,
+    /// References that cannot be resolved by this server
+    pub errors: Vec<String>,
+    /// References that were not checked, e.g. sources hosted elsewhere
+    pub warnings: Vec<String>,
+}
+
+/// Checks that a `MapLibre` style only references sources, layers, sprites and fonts
+/// available on this server. URLs whose host differs from `host` are not checked.
+#[must_use]
+pub fn validate_style(
+    style: &Value,
+    sources: &TileSources,
+    sprites: &SpriteCatalog,
+    fonts: &FontCatalog,
+    host: Option<&str>,
+) -> StyleValidation {
+    let mut res = StyleValidation::default();
+    let Some(style) = style.as_object() else {
+        res.errors.push("Style must be a JSON object".to_string());
+        return res;
+    };
+
+    // Vector layer names of each style source, or None if they cannot be checked
+    let mut style_sources = Vec::new();
+    if let Some(srcs) = style.get("sources").and_then(Value::as_object) {
+        for (name, src) in srcs {
+            let layers = validate_source(name, src, sources, host, &mut res);
+            style_sources.push((name.as_str(), layers));
+        }
+    }
+
+    let glyphs = style.get("glyphs").and_then(Value::as_str);
+    if let Some(glyphs) = glyphs {
+        if !glyphs.contains("{fontstack}") || !glyphs.contains("{range}") {
+            res.errors.push(format!(
+                "Glyphs URL {glyphs} must contain {{fontstack}} and {{range}} placeholders"
+            ));
+        }
+    }
+    let check_fonts = glyphs.map_or(false, |v| martin_path(v, host).is_some());
+
+    if let Some(layers) = style.get("layers").and_then(Value::as_array) {
+        for (idx, layer) in layers.iter().enumerate() {
+            let id = layer
+                .get("id")
+                .and_then(Value::as_str)
+                .map_or_else(|| format!("#{idx}"), ToString::to_string);
+            if let Some(src) = layer.get("source").and_then(Value::as_str) {
+                match style_sources.iter().find(|(name, _)| *name == src) {
+                    None => res
+                        .errors
+                        .push(format!("Layer {id} uses undefined source {src}")),
+                    Some((_, Some(names))) => {
+                        if let Some(src_layer) = layer.get("source-layer").and_then(Value::as_str) {
+                            if !names.contains(src_layer) {
+                                res.errors.push(format!(
+                                    "Layer {id} uses source layer {src_layer} which does not exist in source {src}"
+                                ));
+                            }
+                        }
+                    }
+                    Some((_, None)) => {}
+                }
+            }
+            if let Some(fontstack) = layer.pointer("/layout/text-font") {
+                if glyphs.is_none() {
+                    res.errors.push(format!(
+                        "Layer {id} uses text-font, but the style has no glyphs"
+                    ));
+                } else if check_fonts {
+                    for font in literal_fonts(fontstack) {
+                        if !fonts.contains_key(font) {
+                            res.errors
+                                .push(format!("Layer {id} uses font {font} which does not exist"));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(sprite) = style.get("sprite") {
+        let urls = match sprite {
+            Value::String(url) => vec![url.as_str()],
+            Value::Array(items) => items
+                .iter()
+                .filter_map(|v| v.get("url").and_then(Value::as_str))
+                .collect(),
+            _ => vec![],
+        };
+        for url in urls {
+            validate_sprite(url, sprites, host, &mut res);
+        }
+    }
+
+    res.valid = res.errors.is_empty();
+    res
+}
+
+/// Validate a single style source, and return the names of its vector layers if known
+fn validate_source(
+    name: &str,
+    src: &Value,
+    sources: &TileSources,
+    host: Option<&str>,
+    res: &mut StyleValidation,
+) -> Option<HashSet<String>> {
+    let source_ids = if let Some(url) = src.get("url").and_then(Value::as_str) {
+        martin_path(url, host).map(|path| path.rsplit('/').next().unwrap_or_default())
+    } else if let Some(url) = src
+        .get("tiles")
+        .and_then(Value::as_array)
+        .and_then(|v| v.first())
+        .and_then(Value::as_str)
+    {
+        martin_path(url, host).and_then(|path| {
+            path.rsplit('/')
+                .skip_while(|v| v.starts_with('{'))
+                .find(|v| !v.is_empty())
+        })
+    } else {
+        // GeoJSON, image, and other sources that do not use tiles
+        return None;
+    };
+    let Some(source_ids) = source_ids else {
+        res.warnings.push(format!(
+            "Source {name} is not served by this server and was not checked"
+        ));
+        return None;
+    };
+
+    let mut layers = HashSet::new();
+    let mut has_vector_layers = false;
+    let mut found = true;
+    for id in source_ids.split(',') {
+        if let Ok(src) = sources.get_source(id) {
+            if let Some(vl) = &src.get_tilejson().vector_layers {
+                has_vector_layers = true;
+                layers.extend(vl.iter().map(|l| l.id.clone()));
+            }
+        } else {
+            res.errors.push(format!(
+                "Source {name} uses tile source {id} which does not exist"
+            ));
+            found = false;
+        }
+    }
+    (found && has_vector_layers).then_some(layers)
+}
+
+fn validate_sprite(
+    url: &str,
+    sprites: &SpriteCatalog,
+    host: Option<&str>,
+    res: &mut StyleValidation,
+) {
+    let Some(path) = martin_path(url, host) else {
+        res.warnings.push(format!(
+            "Sprite {url} is not served by this server and was not checked"
+        ));
+        return;
+    };
+    let Some((_, ids)) = path.rsplit_once("sprite/") else {
+        res.errors
+            .push(format!("Sprite {url} is not a valid sprite URL"));
+        return;
+    };
+    for id in ids.trim_end_matches("@2x").split(',') {
+        if !sprites.contains_key(id) {
+            res.errors.push(format!(
+                "Sprite {url} uses sprite source {id} which does not exist"
+            ));
+        }
+    }
+}
+
+/// Font names of a `text-font` value, unless it is computed with an expression
+fn literal_fonts(value: &Value) -> Vec<&str> {
+    let value = match value.as_array().map(Vec::as_slice) {
+        Some([Value::String(op), v]) if op == "literal" => v,
+        _ => value,
+    };
+    let fonts = value
+        .as_array()
+        .map(|v| v.iter().filter_map(Value::as_str).collect::<Vec<_>>())
+        .unwrap_or_default();
+    match fonts.first() {
+        Some(op) if FONT_EXPRESSIONS.contains(op) => vec![],
+        _ => fonts,
+    }
+}
+
+/// Path of a URL without the query, if the URL is relative or points to the given host
+fn martin_path<'a>(url: &'a str, host: Option<&str>) -> Option<&'a str> {
+    let path = if url.starts_with('/') {
+        url
+    } else {
+        let (_, rest) = url.split_once("://")?;
+        let (url_host, path) = rest.split_once('/').unwrap_or((rest, ""));
+        if Some(url_host) != host {
+            return None;
+        }
+        path
+    };
+    let path = path.split('?').next().unwrap_or_default();
+    Some(path.trim_end_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_json::json;
+    use tilejson::{tilejson, VectorLayer};
+
+    use super::*;
+    use crate::fonts::CatalogFontEntry;
+    use crate::sprites::CatalogSpriteEntry;
+    use crate::test_utils::TestSource;
+
+    fn test_sources() -> TileSources {
+        let src = TestSource {
+            id: "points",
+            tj: tilejson! {
+                tiles: vec![],
+                vector_layers: vec![VectorLayer::new("points".to_string(), BTreeMap::default())],
+            },
+            ..Default::default()
+        };
+        TileSources::new(vec![vec![Box::new(src)]])
+    }
+
+    #[test]
+    fn valid_style() {
+        let style = json!({
+            "version": 8,
+            "sources": {
+                "a": {"type": "vector", "url": "http://localhost:3000/points"},
+                "b": {"type": "vector", "tiles": ["/points/{z}/{x}/{y}?foo=bar"]},
+                "ext": {"type": "vector", "url": "https://example.com/tiles.json"},
+            },
+            "sprite": "http://localhost:3000/sprite/icons",
+            "glyphs": "http://localhost:3000/font/{fontstack}/{range}",
+            "layers": [
+                {"id": "bg", "type": "background"},
+                {"id": "p1", "type": "circle", "source": "a", "source-layer": "points"},
+                {"id": "p2", "type": "symbol", "source": "b", "source-layer": "points",
+                 "layout": {"text-font": ["literal", ["Overpass Mono Light"]]}},
+                {"id": "p3", "type": "symbol", "source": "a", "source-layer": "points",
+                 "layout": {"text-font": ["get", "font"]}},
+                {"id": "e", "type": "line", "source": "ext", "source-layer": "anything"},
+            ],
+        });
+        let sprites = SpriteCatalog::from([("icons".to_string(), CatalogSpriteEntry::default())]);
+        let fonts = FontCatalog::from([(
+            "Overpass Mono Light".to_string(),
+            CatalogFontEntry::default(),
+        )]);
+        let res = validate_style(
+            &style,
+            &test_sources(),
+            &sprites,
+            &fonts,
+            Some("localhost:3000"),
+        );
+        assert!(res.valid, "{res:?}");
+        assert_eq!(res.warnings.len(), 1, "{res:?}");
+    }
+
+    #[test]
+    fn invalid_style() {
+        let style = json!({
+            "version": 8,
+            "sources": {
+                "a": {"type": "vector", "url": "http://localhost:3000/points,missing"},
+                "b": {"type": "vector", "tiles": ["http://localhost:3000/points/{z}/{x}/{y}"]},
+            },
+            "sprite": [{"id": "default", "url": "/sprite/nope@2x"}],
+            "layers": [
+                {"id": "l1", "type": "line", "source": "undefined"},
+                {"id": "l2", "type": "line", "source": "b", "source-layer": "lines"},
+                {"id": "l3", "type": "symbol", "source": "b", "source-layer": "points",
+                 "layout": {"text-font": ["Open Sans"]}},
+            ],
+        });
+        let res = validate_style(
+            &style,
+            &test_sources(),
+            &SpriteCatalog::new(),
+            &FontCatalog::new(),
+            Some("localhost:3000"),
+        );
+        assert_eq!(
+            res,
+            StyleValidation {
+                valid: false,
+                errors: vec![
+                    "Source a uses tile source missing which does not exist".to_string(),
+                    "Layer l1 uses undefined source undefined".to_string(),
+                    "Layer l2 uses source layer lines which does not exist in source b".to_string(),
+                    "Layer l3 uses text-font, but the style has no glyphs".to_string(),
+                    "Sprite /sprite/nope@2x uses sprite source nope which does not exist"
+                        .to_string(),
+                ],
+                warnings: vec![],
+            }
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use serde::Serialize;
+
+use crate::source::Source;
+use crate::TileCoord;
+
+/// Highest zoom level of a tile request, as not all backends can compute the tiles of higher ones
+pub const MAX_ZOOM: u8 = 30;
+
+/// Reason why a tile request was rejected without querying its sources
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidTile {
+    /// The zoom level is above [`MAX_ZOOM`], answered with `400 Bad Request`
+    Zoom,
+    /// X or Y is not below `2^zoom`, so the tile does not exist, answered with `404 Not Found`
+    Coordinates,
+    /// None of the sources has tiles at the zoom level, answered with `404 Not Found`
+    ZoomRange,
+}
+
+impl InvalidTile {
+    /// Check that the tile is part of the tile grid, and that at least one of the sources has tiles at its zoom level.
+    /// Returns the reason and a message for the client otherwise.
+    pub fn check(sources: &[&dyn Source], xyz: TileCoord) -> Result<(), (Self, String)> {
+        if xyz.z > MAX_ZOOM {
+            return Err((
+                Self::Zoom,
+                format!("Zoom {} is above the highest zoom level {MAX_ZOOM}", xyz.z),
+            ));
+        }
+        let size = 1_u32 << xyz.z;
+        if xyz.x >= size || xyz.y >= size {
+            return Err((
+                Self::Coordinates,
+                format!(
+                    "Tile {xyz:#} is out of range, x and y must be below {size} at zoom {}",
+                    xyz.z
+                ),
+            ));
+        }
+        if !sources.is_empty() && !sources.iter().any(|src| src.is_valid_zoom(xyz.z)) {
+            let ranges = sources
+                .iter()
+                .map(|src| {
+                    let tj = src.get_tilejson();
+                    let min = tj.minzoom.unwrap_or(0);
+                    let max = tj.maxzoom.unwrap_or(MAX_ZOOM);
+                    format!("{} has zoom levels {min} to {max}", src.get_id())
+                })
+                .collect::<Vec<_>>();
+            return Err((
+                Self::ZoomRange,
+                format!("Zoom {} is not available, {}", xyz.z, ranges.join(", ")),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tilejson::tilejson;
+
+    use super::*;
+    use crate::test_utils::TestSource;
+
+    #[test]
+    fn check_tiles() {
+        let src = TestSource {
+            id: "points",
+            tj: tilejson! { tiles: vec![], minzoom: 2, maxzoom: 10 },
+            ..Default::default()
+        };
+        let sources: [&dyn Source; 1] = [&src];
+        let check = |z, x, y| InvalidTile::check(&sources, TileCoord { z, x, y });
+        assert!(check(2, 3, 3).is_ok());
+        assert!(check(10, 1023, 0).is_ok());
+        assert_eq!(check(31, 0, 0).unwrap_err().0, InvalidTile::Zoom);
+        assert_eq!(check(2, 4, 0).unwrap_err().0, InvalidTile::Coordinates);
+        assert_eq!(check(2, 0, 4).unwrap_err().0, InvalidTile::Coordinates);
+        assert_eq!(
+            check(11, 0, 0).unwrap_err(),
+            (
+                InvalidTile::ZoomRange,
+                "Zoom 11 is not available, points has zoom levels 2 to 10".to_string()
+            )
+        );
+        assert!(InvalidTile::check(&[], TileCoord { z: 0, x: 0, y: 0 }).is_ok());
+    }
+}
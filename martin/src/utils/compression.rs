@@ -0,0 +1,200 @@
+use std::io::{Read as _, Write as _};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+/// Gzip implementation used to (re-)compress tiles when the stored encoding
+/// does not match the encodings accepted by the client.
+pub trait GzipBackend {
+    /// Name used in logs and benchmarks
+    const NAME: &'static str;
+
+    fn encode(data: &[u8]) -> Result<Vec<u8>, std::io::Error>;
+
+    fn decode(data: &[u8]) -> Result<Vec<u8>, std::io::Error>;
+}
+
+/// Gzip backend used by [`encode_gzip`] and [`decode_gzip`]: libdeflate if the `libdeflate` feature is enabled,
+/// or `flate2` otherwise.
+#[cfg(feature = "libdeflate")]
+pub type DefaultGzip = LibdeflateGzip;
+#[cfg(not(feature = "libdeflate"))]
+pub type DefaultGzip = Flate2Gzip;
+
+/// Streaming gzip implementation of `flate2`. It uses the pure Rust `miniz_oxide`,
+/// or the SIMD-optimized `zlib-ng` library if the `zlib-ng` feature is enabled.
+#[derive(Debug, Clone, Copy)]
+pub struct Flate2Gzip;
+
+impl GzipBackend for Flate2Gzip {
+    #[cfg(feature = "zlib-ng")]
+    const NAME: &'static str = "flate2 (zlib-ng)";
+    #[cfg(not(feature = "zlib-ng"))]
+    const NAME: &'static str = "flate2 (miniz_oxide)";
+
+    fn encode(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data)?;
+        encoder.finish()
+    }
+
+    fn decode(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let mut decoder = GzDecoder::new(data);
+        let mut decompressed = Vec::new();
+        decoder.read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    }
+}
+
+/// Whole-buffer gzip implementation of the `libdeflate` library, which is usually faster
+/// than streaming implementations for small inputs such as tiles.
+#[cfg(feature = "libdeflate")]
+#[derive(Debug, Clone, Copy)]
+pub struct LibdeflateGzip;
+
+#[cfg(feature = "libdeflate")]
+mod libdeflate {
+    use std::cell::RefCell;
+
+    use libdeflater::{CompressionLvl, Compressor, DecompressionError, Decompressor};
+
+    use super::{Flate2Gzip, GzipBackend, LibdeflateGzip};
+
+    thread_local! {
+        // Allocating a (de)compressor is relatively expensive, so each thread reuses its own
+        static COMPRESSOR: RefCell<Compressor> = RefCell::new(Compressor::new(CompressionLvl::default()));
+        static DECOMPRESSOR: RefCell<Decompressor> = RefCell::new(Decompressor::new());
+    }
+
+    impl GzipBackend for LibdeflateGzip {
+        const NAME: &'static str = "libdeflate";
+
+        fn encode(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+            COMPRESSOR.with(|compressor| {
+                let mut compressor = compressor.borrow_mut();
+                let mut compressed = vec![0; compressor.gzip_compress_bound(data.len())];
+                let size = compressor
+                    .gzip_compress(data, &mut compressed)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                compressed.truncate(size);
+                Ok(compressed)
+            })
+        }
+
+        fn decode(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+            // The gzip trailer ends with the size of the uncompressed data modulo 2^32
+            let mut size = data
+                .len()
+                .checked_sub(4)
+                .and_then(|i| data[i..].try_into().ok())
+                .map_or(0, |v| u32::from_le_bytes(v) as usize);
+            DECOMPRESSOR.with(|decompressor| {
+                let mut decompressor = decompressor.borrow_mut();
+                loop {
+                    let mut decompressed = vec![0; size];
+                    match decompressor.gzip_decompress(data, &mut decompressed) {
+                        Ok(size) => {
+                            decompressed.truncate(size);
+                            return Ok(decompressed);
+                        }
+                        Err(DecompressionError::InsufficientSpace) => {
+                            size = (size * 2).max(4096);
+                        }
+                        // libdeflate only supports a single gzip member, but merged tiles
+                        // may contain several, so let the streaming decoder handle those
+                        Err(DecompressionError::BadData) => return Flate2Gzip::decode(data),
+                    }
+                }
+            })
+        }
+    }
+}
+
+pub fn decode_gzip(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    DefaultGzip::decode(data)
+}
+
+pub fn encode_gzip(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    DefaultGzip::encode(data)
+}
+
+pub fn decode_brotli(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    let mut decoder = brotli::Decompressor::new(data, 4096);
+    let mut decompressed = Vec::new();
+    decoder.read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+pub fn encode_brotli(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    encode_brotli_level(data, 11)
+}
+
+/// Gzip the data with the given level from 0 to 9, using `flate2` regardless of the default backend
+pub fn encode_gzip_level(data: &[u8], level: u32) -> Result<Vec<u8>, std::io::Error> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::new(level));
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Compress the data with brotli, using the given quality from 0 to 11
+pub fn encode_brotli_level(data: &[u8], quality: u32) -> Result<Vec<u8>, std::io::Error> {
+    let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, quality, 22);
+    encoder.write_all(data)?;
+    Ok(encoder.into_inner())
+}
+
+pub fn decode_zstd(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    zstd::decode_all(data)
+}
+
+/// Compress the data with zstd, using the given level, e.g. from 1 to 22
+pub fn encode_zstd(data: &[u8], level: i32) -> Result<Vec<u8>, std::io::Error> {
+    zstd::encode_all(data, level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: GzipBackend>() {
+        let data = b"martin".repeat(1000);
+        let compressed = T::encode(&data).unwrap();
+        assert!(compressed.len() < data.len(), "{}", T::NAME);
+        assert_eq!(T::decode(&compressed).unwrap(), data, "{}", T::NAME);
+        // Compressed data must be compatible with the other backends
+        assert_eq!(
+            Flate2Gzip::decode(&compressed).unwrap(),
+            data,
+            "{}",
+            T::NAME
+        );
+        assert_eq!(
+            T::decode(&Flate2Gzip::encode(&data).unwrap()).unwrap(),
+            data
+        );
+        assert_eq!(T::decode(&T::encode(b"").unwrap()).unwrap(), b"");
+        assert!(T::decode(b"not gzip").is_err(), "{}", T::NAME);
+    }
+
+    #[test]
+    fn gzip_backends() {
+        round_trip::<Flate2Gzip>();
+        #[cfg(feature = "libdeflate")]
+        round_trip::<LibdeflateGzip>();
+        round_trip::<DefaultGzip>();
+    }
+
+    #[test]
+    fn compression_levels() {
+        let data = b"martin".repeat(1000);
+        let fast = encode_gzip_level(&data, 1).unwrap();
+        assert_eq!(decode_gzip(&fast).unwrap(), data);
+        assert!(encode_gzip_level(&data, 9).unwrap().len() <= fast.len());
+        let brotli = encode_brotli_level(&data, 5).unwrap();
+        assert_eq!(decode_brotli(&brotli).unwrap(), data);
+        let zstd = encode_zstd(&data, 19).unwrap();
+        assert!(zstd.starts_with(b"\x28\xb5\x2f\xfd"));
+        assert_eq!(decode_zstd(&zstd).unwrap(), data);
+        assert!(decode_zstd(b"not zstd").is_err());
+    }
+}
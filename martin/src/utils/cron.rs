@@ -0,0 +1,202 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+/// A cron expression with five fields: minute, hour, day of month, month, and day of week (0 or 7 is Sunday).
+/// Each field is `*`, a number, a range `a-b`, a list `a,b`, or any of these with a step, e.g. `*/15`.
+/// The `@hourly`, `@daily`, `@weekly`, `@monthly`, and `@yearly` shortcuts are supported as well.
+/// Times are in UTC.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CronSchedule {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Standard cron matches either the day of month or the day of week if both are restricted
+    any_day: bool,
+}
+
+impl CronSchedule {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let fields = match expression.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            v => v,
+        };
+        let fields: Vec<_> = fields.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields.as_slice() else {
+            return Err(format!(
+                "Invalid cron expression `{expression}`: expected 5 fields (minute hour day month weekday)"
+            ));
+        };
+        let field = |value: &str, min: u64, max: u64| {
+            parse_field(value, min, max)
+                .map_err(|e| format!("Invalid cron expression `{expression}`: {e}"))
+        };
+        let mut weekday_bits = field(weekdays, 0, 7)?;
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits |= 1;
+        }
+        Ok(Self {
+            expression: expression.to_string(),
+            minutes: field(minutes, 0, 59)?,
+            hours: field(hours, 0, 23)?,
+            days: field(days, 1, 31)?,
+            months: field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            any_day: *days != "*" && *weekdays != "*",
+        })
+    }
+
+    /// True if the schedule fires during the minute that contains this time, given in seconds since the Unix epoch
+    #[must_use]
+    pub fn matches(&self, unix_secs: u64) -> bool {
+        let minute = unix_secs / 60 % 60;
+        let hour = unix_secs / 3600 % 24;
+        let days = unix_secs / 86400;
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 4) % 7;
+        let (_, month, day) = civil_from_days(days);
+        let is_set = |bits: u64, v: u64| bits & (1 << v) != 0;
+        let day_matches = if self.any_day {
+            is_set(self.days, day) || is_set(self.weekdays, weekday)
+        } else {
+            is_set(self.days, day) && is_set(self.weekdays, weekday)
+        };
+        is_set(self.minutes, minute)
+            && is_set(self.hours, hour)
+            && is_set(self.months, month)
+            && day_matches
+    }
+}
+
+impl Display for CronSchedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+impl TryFrom<String> for CronSchedule {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::parse(&value)
+    }
+}
+
+impl From<CronSchedule> for String {
+    fn from(value: CronSchedule) -> Self {
+        value.expression
+    }
+}
+
+/// Parse a single cron field into a bit set of the matching values
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step = step
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|v| *v > 0)
+                    .ok_or_else(|| format!("invalid step in `{part}`"))?;
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let number = |v: &str| {
+            v.parse::<u64>()
+                .ok()
+                .filter(|v| (min..=max).contains(v))
+                .ok_or_else(|| format!("`{v}` must be a number from {min} to {max}"))
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (number(start)?, number(end)?),
+            // `5/10` means every 10 starting at 5
+            None if step > 1 => (number(range)?, max),
+            None => {
+                let v = number(range)?;
+                (v, v)
+            }
+        };
+        if start > end {
+            return Err(format!("invalid range in `{part}`"));
+        }
+        for v in (start..=end).step_by(usize::try_from(step).unwrap_or(usize::MAX)) {
+            bits |= 1 << v;
+        }
+    }
+    Ok(bits)
+}
+
+/// Convert days since the Unix epoch to a (year, month, day) date, see <https://howardhinnant.github.io/date_algorithms.html>
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-02-29 03:00:00 UTC, a Thursday
+    const LEAP_DAY: u64 = 1_709_175_600;
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(LEAP_DAY / 86400), (2024, 2, 29));
+        assert_eq!(civil_from_days(LEAP_DAY / 86400 + 1), (2024, 3, 1));
+    }
+
+    #[test]
+    fn cron_schedule() {
+        let cron = |v: &str| CronSchedule::parse(v).unwrap();
+        assert!(cron("0 3 * * *").matches(LEAP_DAY));
+        assert!(cron("0 3 * * *").matches(LEAP_DAY + 59));
+        assert!(!cron("0 3 * * *").matches(LEAP_DAY + 60));
+        assert!(cron("*/15 1-5 29 2 *").matches(LEAP_DAY + 15 * 60));
+        assert!(!cron("*/15 1-5 29 2 *").matches(LEAP_DAY + 16 * 60));
+        assert!(cron("0 3 * * 4").matches(LEAP_DAY));
+        assert!(!cron("0 3 * * 1-3,5").matches(LEAP_DAY));
+        assert!(cron("0 3 * * 7").matches(LEAP_DAY + 3 * 86400));
+        // Either the day of month or the day of week
+        assert!(cron("0 3 1 * 4").matches(LEAP_DAY));
+        assert!(cron("0 3 1 * 4").matches(LEAP_DAY + 86400));
+        assert!(!cron("0 3 1 * 4").matches(LEAP_DAY + 2 * 86400));
+        assert!(cron("@daily").matches(LEAP_DAY - 3 * 3600));
+        assert!(cron("5/20 * * * *").matches(LEAP_DAY + 45 * 60));
+        assert!(!cron("5/20 * * * *").matches(LEAP_DAY));
+
+        assert_eq!(cron(" 0 3 * * * ").to_string(), " 0 3 * * * ");
+        for invalid in [
+            "",
+            "0 3 * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+        ] {
+            assert!(CronSchedule::parse(invalid).is_err(), "{invalid}");
+        }
+        let parsed: CronSchedule = serde_yaml::from_str("'30 2 * * 1'").unwrap();
+        assert_eq!(parsed, cron("30 2 * * 1"));
+        assert!(serde_yaml::from_str::<CronSchedule>("'every day'").is_err());
+    }
+}
@@ -5,10 +5,14 @@ use std::path::PathBuf;
 
 use mbtiles::MbtError;
 
+use crate::args::MigrateFrom;
+use crate::derived::DerivedError;
 use crate::file_config::FileError;
 use crate::fonts::FontError;
 use crate::pg::PgError;
 use crate::sprites::SpriteError;
+use crate::srv::ReadyWhen;
+use crate::variants::VariantError;
 
 /// A convenience [`Result`] for Martin crate.
 pub type MartinResult<T> = Result<T, MartinError>;
@@ -53,9 +57,42 @@ pub enum MartinError {
     #[error("No tile sources found. Set sources by giving a database connection string on command line, env variable, or a config file.")]
     NoSources,
 
+    #[error("{0}")]
+    InvalidSourceIds(String),
+
+    #[error("{0} of {1} tile sources failed the self-test")]
+    SourceTestsFailed(usize, usize),
+
+    #[error("Unable to access request recording {}: {0}", .1.display())]
+    RecordingFileError(io::Error, PathBuf),
+
+    #[error("Unable to parse request recording {}, line {2}: {0}", .1.display())]
+    RecordingParseError(serde_json::Error, PathBuf, usize),
+
+    #[error("{0} of {1} replayed responses differ from the recorded ones")]
+    ReplayChanged(usize, usize),
+
+    #[error("Unable to parse {1} config file {}: {0}", .2.display())]
+    MigrationParseError(String, MigrateFrom, PathBuf),
+
+    #[error("Unable to read GeoJSON mask {}: {0}", .1.display())]
+    MaskLoadError(io::Error, PathBuf),
+
+    #[error("Unable to parse GeoJSON mask {}: {0}", .1.display())]
+    MaskParseError(String, PathBuf),
+
+    #[error("Multiple sources use the same ID, set on_duplicate_id to resolve the conflict automatically: {}", elide_vec(.0, 3, 15))]
+    DuplicateSourceIds(Vec<String>),
+
+    #[error("Only {0} of {1} source groups could be initialized, fewer than ready_when {2}")]
+    NotEnoughSourcesReady(usize, usize, ReadyWhen),
+
     #[error("Unrecognizable connection strings: {0:?}")]
     UnrecognizableConnections(Vec<String>),
 
+    #[error("Invalid header name or value {0} in the {1} configuration")]
+    InvalidHeaderName(String, &'static str),
+
     #[error(transparent)]
     PostgresError(#[from] PgError),
 
@@ -71,9 +108,33 @@ pub enum MartinError {
     #[error(transparent)]
     FontError(#[from] FontError),
 
+    #[error(transparent)]
+    DerivedError(#[from] DerivedError),
+
+    #[error(transparent)]
+    VariantError(#[from] VariantError),
+
     #[error(transparent)]
     WebError(#[from] actix_web::Error),
 
+    #[error("Martin was built without the {0} feature, so events cannot be published to {0}. Rebuild Martin with `--features {0}`, or remove `events.{0}` from the configuration")]
+    EventSinkNotSupported(&'static str),
+
+    #[error("Source {0} is temporarily unavailable because of too many errors")]
+    SourceUnavailable(String),
+
+    #[error("Injected a fault into the request of source {0}")]
+    InjectedFault(String),
+
+    #[error("Unable to send a notification to {0}: {1}")]
+    WebhookError(String, String),
+
+    #[error("Unable to upload {0}: {1}")]
+    S3Error(String, String),
+
+    #[error("Unable to get {0}: {1}")]
+    RemoteSourceError(String, String),
+
     #[error("Internal error: {0}")]
     InternalError(Box<dyn Error>),
 }
@@ -0,0 +1,166 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use reqwest::{Client, Method};
+use url::Url;
+
+use crate::MartinError::WebhookError;
+use crate::MartinResult;
+
+/// How long to wait for a connection to the server
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for the complete response, including the connection and the body
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Send a JSON document to a webhook with a `POST` request over HTTP or HTTPS, and return the response status.
+pub async fn post_json(url: &str, body: &serde_json::Value) -> MartinResult<u16> {
+    let err = |e: String| WebhookError(url.to_string(), e);
+    let parsed = Url::parse(url).map_err(|e| err(e.to_string()))?;
+    let body = body.to_string();
+    let headers = [("Content-Type", "application/json")];
+    let response = send_request("POST", &parsed, &headers, body.as_bytes())
+        .await
+        .map_err(err)?;
+    Ok(response.status)
+}
+
+/// Status, headers with lowercase names, and body of a response
+#[derive(Debug)]
+pub(crate) struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Value of the `Host` header of the URL, with the port unless it is the default one of the scheme
+pub(crate) fn host_header(url: &Url) -> String {
+    match url.port() {
+        Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+        None => url.host_str().unwrap_or_default().to_string(),
+    }
+}
+
+/// Client shared by all requests, so that the connections are reused,
+/// and the system root certificates are only loaded once
+fn client() -> Result<&'static Client, String> {
+    static CLIENT: OnceLock<Result<Client, String>> = OnceLock::new();
+    CLIENT
+        .get_or_init(|| {
+            Client::builder()
+                .user_agent(concat!("martin/", env!("CARGO_PKG_VERSION")))
+                .connect_timeout(CONNECT_TIMEOUT)
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .map_err(|e| e.to_string())
+        })
+        .as_ref()
+        .map_err(Clone::clone)
+}
+
+/// Send a request, and return the response. The body is returned as is, without decoding its `Content-Encoding`.
+/// The `User-Agent` header is added to the given ones.
+pub(crate) async fn send_request(
+    method: &str,
+    url: &Url,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<HttpResponse, String> {
+    if !matches!(url.scheme(), "http" | "https") {
+        Err(format!("the {} scheme is not supported", url.scheme()))?;
+    }
+    let method = Method::from_bytes(method.as_bytes()).map_err(|e| e.to_string())?;
+    let mut request = client()?.request(method, url.clone());
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    if !body.is_empty() {
+        request = request.body(body.to_vec());
+    }
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    let status = response.status().as_u16();
+    let headers = response
+        .headers()
+        .iter()
+        .filter_map(|(k, v)| Some((k.as_str().to_string(), v.to_str().ok()?.to_string())))
+        .collect();
+    let body = response.bytes().await.map_err(|e| e.to_string())?.to_vec();
+    Ok(HttpResponse {
+        status,
+        headers,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn post_to_webhook() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/hooks/copy?token=abc",
+            listener.local_addr().unwrap()
+        );
+        let server = tokio::spawn(async move {
+            // Both requests are sent over the same connection
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut requests = Vec::new();
+            for response in [
+                "HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n",
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Type: text/plain\r\n\r\n4\r\ntile\r\n3;ext\r\ns 1\r\n0\r\n\r\n",
+            ] {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"}") && !request.ends_with(b"\r\n\r\n") {
+                    let len = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..len]);
+                }
+                stream.write_all(response.as_bytes()).await.unwrap();
+                requests.push(String::from_utf8(request).unwrap());
+            }
+            requests
+        });
+
+        let status = post_json(&url, &serde_json::json!({"text": "done"}))
+            .await
+            .unwrap();
+        assert_eq!(status, 202);
+        let response = send_request("GET", &Url::parse(&url).unwrap(), &[], &[])
+            .await
+            .unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("content-type"), Some("text/plain"));
+        assert_eq!(response.body, b"tiles 1");
+
+        let requests = server.await.unwrap();
+        let request = requests[0].to_ascii_lowercase();
+        assert!(request.starts_with("post /hooks/copy?token=abc http/1.1\r\n"));
+        assert!(request.contains("\r\ncontent-type: application/json\r\n"));
+        assert!(request.contains(concat!(
+            "\r\nuser-agent: martin/",
+            env!("CARGO_PKG_VERSION")
+        )));
+        assert!(request.ends_with("\r\n\r\n{\"text\":\"done\"}"));
+        assert!(requests[1].starts_with("GET /hooks/copy?token=abc HTTP/1.1\r\n"));
+
+        assert!(post_json("ftp://localhost/", &serde_json::json!({}))
+            .await
+            .is_err());
+        assert!(post_json("not a url", &serde_json::json!({}))
+            .await
+            .is_err());
+    }
+}
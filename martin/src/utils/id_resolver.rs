@@ -1,16 +1,171 @@
+use std::borrow::Cow;
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
 use std::sync::{Arc, Mutex};
 
-use log::warn;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use url::form_urlencoded::byte_serialize;
+
+/// How to resolve a conflict when several sources want to use the same ID
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateIdStrategy {
+    /// Append `.1`, `.2`, etc. to the ID of each additional source
+    #[default]
+    Suffix,
+    /// Refuse to start if any source ID is used more than once
+    Error,
+    /// Prefix the ID of each additional source with its PostgreSQL schema, e.g. `osm.points`
+    PrefixWithSchema,
+    /// Prefix the ID of each additional source with its PostgreSQL connection ID, e.g. `db2.points`
+    PrefixWithPoolId,
+    /// Only publish the first source with the given ID, and ignore all others
+    KeepFirst,
+}
+
+/// How source IDs are normalized before they are checked for conflicts, set with the `source_ids` config
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdRules {
+    /// IDs that sources may not use in addition to the routes of Martin, e.g. the paths of a proxy in front of it.
+    /// Sources that want to use them get a numeric suffix.
+    pub reserved: Option<Vec<String>>,
+    /// Used instead of each character other than letters, digits, and `_-.` [default: `-`]
+    pub replacement: Option<char>,
+    /// Also replace the dots of the requested IDs, e.g. of table names [default: false]
+    pub replace_dots: Option<bool>,
+    /// Convert the IDs to lowercase [default: false]
+    pub lowercase: Option<bool>,
+    /// Transliterate the accented Latin letters to ASCII before the other characters are replaced,
+    /// e.g. `Straße` to `Strasse` [default: false]
+    pub slugify: Option<bool>,
+    /// Keep the letters and digits of all scripts, e.g. of non-ASCII table names.
+    /// They are percent-encoded in the tile URLs [default: false]
+    pub unicode: Option<bool>,
+}
+
+impl IdRules {
+    /// Check that the replacement keeps the IDs valid
+    pub fn validate(&self) -> Result<(), String> {
+        match self.replacement {
+            Some(c) if !is_id_char(c) || (c == '.' && self.replace_dots.unwrap_or_default()) => {
+                Err(format!("source_ids.replacement must be a letter, a digit, or one of `_-.` other than a replaced dot, got `{c}`"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn is_id_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-'
+}
+
+/// ASCII spelling of the accented Latin letters, see [`IdRules::slugify`]
+const TRANSLITERATIONS: &[(&str, &str)] = &[
+    ("ÀÁÂÃÄÅĀĂĄ", "A"),
+    ("àáâãäåāăą", "a"),
+    ("ÇĆĈĊČ", "C"),
+    ("çćĉċč", "c"),
+    ("ÐĎĐ", "D"),
+    ("ðďđ", "d"),
+    ("ÈÉÊËĒĔĖĘĚ", "E"),
+    ("èéêëēĕėęě", "e"),
+    ("ĜĞĠĢ", "G"),
+    ("ĝğġģ", "g"),
+    ("ĤĦ", "H"),
+    ("ĥħ", "h"),
+    ("ÌÍÎÏĨĪĬĮİ", "I"),
+    ("ìíîïĩīĭįı", "i"),
+    ("Ĵ", "J"),
+    ("ĵ", "j"),
+    ("Ķ", "K"),
+    ("ķ", "k"),
+    ("ĹĻĽĿŁ", "L"),
+    ("ĺļľŀł", "l"),
+    ("ÑŃŅŇ", "N"),
+    ("ñńņň", "n"),
+    ("ÒÓÔÕÖØŌŎŐ", "O"),
+    ("òóôõöøōŏő", "o"),
+    ("ŔŖŘ", "R"),
+    ("ŕŗř", "r"),
+    ("ŚŜŞŠ", "S"),
+    ("śŝşš", "s"),
+    ("ŢŤŦ", "T"),
+    ("ţťŧ", "t"),
+    ("ÙÚÛÜŨŪŬŮŰŲ", "U"),
+    ("ùúûüũūŭůűų", "u"),
+    ("Ŵ", "W"),
+    ("ŵ", "w"),
+    ("ÝŶŸ", "Y"),
+    ("ýÿŷ", "y"),
+    ("ŹŻŽ", "Z"),
+    ("źżž", "z"),
+    ("Æ", "AE"),
+    ("æ", "ae"),
+    ("Œ", "OE"),
+    ("œ", "oe"),
+    ("Þ", "TH"),
+    ("þ", "th"),
+    ("ß", "ss"),
+];
+
+fn slugify(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    for c in name.chars() {
+        match TRANSLITERATIONS.iter().find(|(from, _)| from.contains(c)) {
+            Some((_, to)) => result.push_str(to),
+            None => result.push(c),
+        }
+    }
+    result
+}
+
+/// Percent-encode the characters of a source ID that cannot be used in a URL path as is, e.g. of a Unicode ID
+#[must_use]
+pub fn encode_source_id(id: &str) -> Cow<'_, str> {
+    if id.chars().all(is_id_char) {
+        Cow::Borrowed(id)
+    } else {
+        Cow::Owned(byte_serialize(id.as_bytes()).collect())
+    }
+}
+
+/// Optional prefixes used by the [`DuplicateIdStrategy::PrefixWithSchema`]
+/// and [`DuplicateIdStrategy::PrefixWithPoolId`] strategies.
+/// If a prefix is not available, the source ID will get a numeric suffix instead.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IdPrefixes<'a> {
+    pub schema: Option<&'a str>,
+    pub pool_id: Option<&'a str>,
+}
+
+/// A source ID that could not be used as is
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RenamedId {
+    /// The requested source ID
+    pub name: String,
+    /// The unique name of the source, e.g. its file path
+    pub unique_name: String,
+    /// The new source ID, or `None` if the source was ignored
+    pub new_name: Option<String>,
+    /// True if another source already uses the requested ID
+    pub conflict: bool,
+}
 
 #[derive(Debug, Default, Clone)]
 pub struct IdResolver {
     /// name -> unique name
     names: Arc<Mutex<HashMap<String, String>>>,
     /// reserved names
-    reserved: HashSet<&'static str>,
+    reserved: HashSet<String>,
+    /// how to handle ID conflicts
+    strategy: DuplicateIdStrategy,
+    /// how to normalize the names
+    rules: IdRules,
+    /// all source IDs that were changed or ignored, in the order of resolution
+    renamed: Arc<Mutex<Vec<RenamedId>>>,
 }
 
 impl IdResolver {
@@ -18,50 +173,167 @@ impl IdResolver {
     pub fn new(reserved_keywords: &[&'static str]) -> Self {
         Self {
             names: Arc::new(Mutex::new(HashMap::new())),
-            reserved: reserved_keywords.iter().copied().collect(),
+            reserved: reserved_keywords.iter().map(ToString::to_string).collect(),
+            strategy: DuplicateIdStrategy::default(),
+            rules: IdRules::default(),
+            renamed: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
+    #[must_use]
+    pub fn with_strategy(mut self, strategy: DuplicateIdStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Normalize the names with the rules, and also reserve their additional IDs
+    #[must_use]
+    pub fn with_rules(mut self, rules: &IdRules) -> Self {
+        self.reserved
+            .extend(rules.reserved.iter().flatten().cloned());
+        self.rules = rules.clone();
+        self
+    }
+
+    #[must_use]
+    pub fn strategy(&self) -> DuplicateIdStrategy {
+        self.strategy
+    }
+
     /// If source name already exists in the self.names structure,
-    /// try appending it with ".1", ".2", etc. until the name is unique.
-    /// Only alphanumeric characters plus dashes/dots/underscores are allowed.
+    /// resolve the conflict using the configured [`DuplicateIdStrategy`].
+    /// Only alphanumeric characters plus dashes/dots/underscores are allowed, see [`IdRules`] for the other ones.
+    /// Returns `None` if the source should not be published.
     #[must_use]
-    pub fn resolve(&self, name: &str, unique_name: String) -> String {
-        let info = if name == unique_name {
-            None
-        } else {
-            Some(unique_name.clone())
-        };
-        let new_name = self.resolve_int(name, unique_name);
-        if name != new_name {
-            warn!("Source `{name}`{info} was renamed to `{new_name}`. Source IDs must be unique, cannot be reserved, and must contain alpha-numeric characters or `._-`",
-                 info = info.map_or(String::new(), |v| format!(" ({v})"))
-            );
+    pub fn resolve(&self, name: &str, unique_name: String) -> Option<String> {
+        self.resolve_with_prefixes(name, unique_name, IdPrefixes::default())
+    }
+
+    /// Same as [`IdResolver::resolve`], but allows the prefixing strategies to be used
+    #[must_use]
+    pub fn resolve_with_prefixes(
+        &self,
+        name: &str,
+        unique_name: String,
+        prefixes: IdPrefixes,
+    ) -> Option<String> {
+        let (new_name, conflict) = self.resolve_int(name, unique_name.clone(), prefixes);
+        if new_name.as_deref() != Some(name) {
+            if let Some(new_name) = &new_name {
+                debug!("Source `{name}` ({unique_name}) was renamed to `{new_name}`. Source IDs must be unique, cannot be reserved, and must contain alpha-numeric characters or `._-`");
+            } else {
+                warn!("Source `{name}` ({unique_name}) was ignored because another source already uses this ID");
+            }
+            let renamed = RenamedId {
+                name: name.to_string(),
+                unique_name,
+                new_name: new_name.clone(),
+                conflict,
+            };
+            let mut all = self.renamed.lock().expect("IdResolver panicked");
+            if !all.contains(&renamed) {
+                all.push(renamed);
+            }
         }
         new_name
     }
 
+    /// All source IDs that were changed or ignored so far
     #[must_use]
-    fn resolve_int(&self, name: &str, unique_name: String) -> String {
+    pub fn renamed(&self) -> Vec<RenamedId> {
+        self.renamed.lock().expect("IdResolver panicked").clone()
+    }
+
+    /// Log all source IDs that were changed or ignored during the source discovery
+    pub fn report(&self) {
+        let renamed = self.renamed();
+        if renamed.is_empty() {
+            return;
+        }
+        let mut report = String::new();
+        for r in &renamed {
+            let new_name = r
+                .new_name
+                .as_deref()
+                .map_or_else(|| "ignored".to_string(), |v| format!("renamed to `{v}`"));
+            let reason = if r.conflict {
+                "the ID is already used"
+            } else {
+                "the ID is reserved or has invalid characters"
+            };
+            let _ = write!(
+                report,
+                "\n  `{}` ({}) was {new_name} because {reason}",
+                r.name, r.unique_name
+            );
+        }
+        warn!(
+            "{} source IDs were changed. Clients using the original IDs may need to be updated. Use the `on_duplicate_id` and `source_ids` config to control this behavior.{report}",
+            renamed.len()
+        );
+    }
+
+    /// Returns the new name (if any), and true if the name conflicted with another source
+    #[must_use]
+    fn resolve_int(
+        &self,
+        name: &str,
+        unique_name: String,
+        prefixes: IdPrefixes,
+    ) -> (Option<String>, bool) {
         // Ensure name has no prohibited characters like spaces, commas, slashes, or non-unicode etc.
-        // Underscores, dashes, and dots are OK. All other characters will be replaced with dashes.
+        // Underscores, dashes, and dots are OK. All other characters will be replaced with dashes by default.
+        let name = if self.rules.slugify.unwrap_or_default() {
+            Cow::Owned(slugify(name))
+        } else {
+            Cow::Borrowed(name)
+        };
+        let replace_dots = self.rules.replace_dots.unwrap_or_default();
+        let unicode = self.rules.unicode.unwrap_or_default();
+        let replacement = self.rules.replacement.unwrap_or('-');
         let mut name = name.replace(
-            |c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '.' && c != '-',
-            "-",
+            |c: char| {
+                !(is_id_char(c) || (unicode && c.is_alphanumeric())) || (replace_dots && c == '.')
+            },
+            replacement.encode_utf8(&mut [0; 4]),
         );
+        if self.rules.lowercase.unwrap_or_default() {
+            name = name.to_lowercase();
+        }
 
         let mut names = self.names.lock().expect("IdResolver panicked");
+        let mut conflict = false;
         if !self.reserved.contains(name.as_str()) {
-            match names.entry(name) {
+            match names.entry(name.clone()) {
                 Entry::Vacant(e) => {
-                    let id = e.key().clone();
                     e.insert(unique_name);
-                    return id;
+                    return (Some(name), false);
                 }
                 Entry::Occupied(e) => {
-                    name = e.key().clone();
                     if e.get() == &unique_name {
-                        return name;
+                        return (Some(name), false);
+                    }
+                    conflict = true;
+                }
+            }
+
+            let prefix = match self.strategy {
+                DuplicateIdStrategy::KeepFirst => return (None, true),
+                DuplicateIdStrategy::PrefixWithSchema => prefixes.schema,
+                DuplicateIdStrategy::PrefixWithPoolId => prefixes.pool_id,
+                DuplicateIdStrategy::Suffix | DuplicateIdStrategy::Error => None,
+            };
+            if let Some(prefix) = prefix {
+                name = format!("{prefix}.{name}");
+                match names.entry(name.clone()) {
+                    Entry::Vacant(e) => {
+                        e.insert(unique_name);
+                        return (Some(name), true);
+                    }
+                    Entry::Occupied(e) => {
+                        if e.get() == &unique_name {
+                            return (Some(name), true);
+                        }
                     }
                 }
             }
@@ -77,11 +349,11 @@ impl IdResolver {
             match names.entry(new_name.clone()) {
                 Entry::Vacant(e) => {
                     e.insert(unique_name);
-                    return new_name;
+                    return (Some(new_name), conflict);
                 }
                 Entry::Occupied(e) => {
                     if e.get() == &unique_name {
-                        return new_name;
+                        return (Some(new_name), conflict);
                     }
                 }
             }
@@ -96,16 +368,135 @@ mod tests {
     #[test]
     fn id_resolve() {
         let r = IdResolver::default();
-        assert_eq!(r.resolve("a", "a".to_string()), "a");
-        assert_eq!(r.resolve("a", "a".to_string()), "a");
-        assert_eq!(r.resolve("a", "b".to_string()), "a.1");
-        assert_eq!(r.resolve("a", "b".to_string()), "a.1");
-        assert_eq!(r.resolve("b", "a".to_string()), "b");
-        assert_eq!(r.resolve("b", "a".to_string()), "b");
-        assert_eq!(r.resolve("a.1", "a".to_string()), "a.1.1");
-        assert_eq!(r.resolve("a.1", "b".to_string()), "a.1");
-
-        assert_eq!(r.resolve("a b", "a b".to_string()), "a-b");
-        assert_eq!(r.resolve("a b", "ab2".to_string()), "a-b.1");
+        let resolve = |name: &str, unique: &str| r.resolve(name, unique.to_string()).unwrap();
+        assert_eq!(resolve("a", "a"), "a");
+        assert_eq!(resolve("a", "a"), "a");
+        assert_eq!(resolve("a", "b"), "a.1");
+        assert_eq!(resolve("a", "b"), "a.1");
+        assert_eq!(resolve("b", "a"), "b");
+        assert_eq!(resolve("b", "a"), "b");
+        assert_eq!(resolve("a.1", "a"), "a.1.1");
+        assert_eq!(resolve("a.1", "b"), "a.1");
+
+        assert_eq!(resolve("a b", "a b"), "a-b");
+        assert_eq!(resolve("a b", "ab2"), "a-b.1");
+
+        let renamed = r.renamed();
+        assert_eq!(renamed.len(), 4, "{renamed:?}");
+        assert_eq!(
+            renamed[0],
+            RenamedId {
+                name: "a".to_string(),
+                unique_name: "b".to_string(),
+                new_name: Some("a.1".to_string()),
+                conflict: true,
+            }
+        );
+        assert!(!renamed[2].conflict, "a b -> a-b is not a conflict");
+    }
+
+    #[test]
+    fn id_resolve_reserved() {
+        let r = IdResolver::new(&["catalog"]).with_strategy(DuplicateIdStrategy::KeepFirst);
+        let res = r.resolve("catalog", "a".to_string());
+        assert_eq!(res.as_deref(), Some("catalog.1"));
+        assert!(!r.renamed()[0].conflict);
+    }
+
+    #[test]
+    fn id_resolve_rules() {
+        let rules = IdRules {
+            reserved: Some(vec!["api".to_string()]),
+            replacement: Some('_'),
+            replace_dots: Some(true),
+            lowercase: Some(true),
+            ..Default::default()
+        };
+        assert!(rules.validate().is_ok());
+        let r = IdResolver::new(&["catalog"]).with_rules(&rules);
+        let resolve = |name: &str| r.resolve(name, name.to_string()).unwrap();
+        assert_eq!(resolve("Public.Roads Main"), "public_roads_main");
+        assert_eq!(resolve("API"), "api.1");
+        assert_eq!(resolve("catalog"), "catalog.1");
+        assert_eq!(resolve("a-b"), "a-b");
+
+        for replacement in [' ', '/', 'é'] {
+            let rules = IdRules {
+                replacement: Some(replacement),
+                ..Default::default()
+            };
+            assert!(rules.validate().is_err(), "{replacement}");
+        }
+        assert!(rules.validate().is_ok());
+        let dots = IdRules {
+            replacement: Some('.'),
+            replace_dots: Some(true),
+            ..Default::default()
+        };
+        assert!(dots.validate().is_err());
+    }
+
+    #[test]
+    fn id_resolve_unicode() {
+        let r = IdResolver::default();
+        let resolve = |name: &str| r.resolve(name, name.to_string()).unwrap();
+        assert_eq!(resolve("Straße"), "Stra-e");
+
+        let unicode = IdRules {
+            unicode: Some(true),
+            lowercase: Some(true),
+            ..Default::default()
+        };
+        let r = IdResolver::default().with_rules(&unicode);
+        let resolve = |name: &str| r.resolve(name, name.to_string()).unwrap();
+        assert_eq!(resolve("Straße Ämter"), "straße-ämter");
+        assert_eq!(resolve("道路/2024"), "道路-2024");
+        assert_eq!(encode_source_id("straße-ämter"), "stra%C3%9Fe-%C3%A4mter");
+        assert_eq!(encode_source_id("a-b.c_1"), "a-b.c_1");
+
+        let slugify = IdRules {
+            slugify: Some(true),
+            ..unicode
+        };
+        let r = IdResolver::default().with_rules(&slugify);
+        let resolve = |name: &str| r.resolve(name, name.to_string()).unwrap();
+        assert_eq!(resolve("Straße Ämter"), "strasse-amter");
+        assert_eq!(resolve("Œuvres d'Łódź"), "oeuvres-d-lodz");
+        assert_eq!(resolve("道路"), "道路");
+    }
+
+    #[test]
+    fn id_resolve_keep_first() {
+        let r = IdResolver::default().with_strategy(DuplicateIdStrategy::KeepFirst);
+        assert_eq!(r.resolve("a", "a".to_string()).as_deref(), Some("a"));
+        assert_eq!(r.resolve("a", "b".to_string()), None);
+        assert_eq!(r.resolve("a", "a".to_string()).as_deref(), Some("a"));
+        assert_eq!(r.renamed().len(), 1);
+    }
+
+    #[test]
+    fn id_resolve_prefixes() {
+        let prefixes = IdPrefixes {
+            schema: Some("osm"),
+            pool_id: Some("db2"),
+        };
+        let r = IdResolver::default().with_strategy(DuplicateIdStrategy::PrefixWithSchema);
+        let resolve = |name: &str, unique: &str, prefixes| {
+            r.resolve_with_prefixes(name, unique.to_string(), prefixes)
+                .unwrap()
+        };
+        assert_eq!(resolve("a", "a", prefixes), "a");
+        assert_eq!(resolve("a", "b", prefixes), "osm.a");
+        assert_eq!(resolve("a", "b", prefixes), "osm.a");
+        assert_eq!(resolve("a", "c", prefixes), "osm.a.1");
+        assert_eq!(resolve("a", "d", IdPrefixes::default()), "a.1");
+
+        let r = IdResolver::default().with_strategy(DuplicateIdStrategy::PrefixWithPoolId);
+        let resolve = |name: &str, unique: &str| {
+            r.resolve_with_prefixes(name, unique.to_string(), prefixes)
+                .unwrap()
+        };
+        assert_eq!(resolve("a", "a"), "a");
+        assert_eq!(resolve("a", "b"), "db2.a");
     }
 }
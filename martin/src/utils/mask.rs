@@ -0,0 +1,260 @@
+use std::f64::consts::PI;
+use std::fs;
+use std::path::Path;
+
+use martin_tile_utils::MAX_LATITUDE;
+use serde_json::Value;
+
+use crate::MartinError::{MaskLoadError, MaskParseError};
+use crate::{MartinResult, TileRect};
+
+/// Polygon area to limit tile ranges to, e.g. a country boundary.
+/// The rings of all polygons are combined with the even-odd rule, so holes are excluded.
+/// Coordinates are stored in the Web Mercator tile space of zoom 0, from `(0, 0)` at the top left to `(1, 1)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoMask {
+    rings: Vec<Vec<(f64, f64)>>,
+}
+
+impl GeoMask {
+    /// Read the polygons of a GeoJSON file with a geometry, a feature, or a feature collection
+    pub fn read(path: &Path) -> MartinResult<Self> {
+        let text = fs::read_to_string(path).map_err(|e| MaskLoadError(e, path.to_path_buf()))?;
+        Self::parse(&text).map_err(|e| MaskParseError(e, path.to_path_buf()))
+    }
+
+    pub fn parse(geojson: &str) -> Result<Self, String> {
+        let value: Value = serde_json::from_str(geojson).map_err(|e| e.to_string())?;
+        let mut rings = Vec::new();
+        collect_rings(&value, &mut rings)?;
+        if rings.is_empty() {
+            return Err("no Polygon or MultiPolygon geometries found".to_string());
+        }
+        Ok(Self { rings })
+    }
+
+    /// Tile ranges of each zoom level, covering all tiles whose extent intersects the mask.
+    /// Each range is a run of tiles in a single row.
+    #[must_use]
+    pub fn tile_ranges(&self, zooms: &[u8]) -> Vec<TileRect> {
+        let mut ranges = Vec::new();
+        for &zoom in zooms {
+            let n = 1_u32 << zoom;
+            let (min_y, max_y) = self.rows(n);
+            for y in min_y..=max_y {
+                for (min_x, max_x) in self.row_runs(n, y) {
+                    ranges.push(TileRect::new(zoom, min_x, y, max_x, y));
+                }
+            }
+        }
+        ranges
+    }
+
+    /// Range of tile rows covered by the mask
+    fn rows(&self, n: u32) -> (u32, u32) {
+        let (min, max) = self
+            .rings
+            .iter()
+            .flatten()
+            .fold((f64::MAX, f64::MIN), |(min, max), &(_, y)| {
+                (min.min(y), max.max(y))
+            });
+        (tile(min, n), tile(max, n))
+    }
+
+    /// Sorted, non-overlapping runs of tile columns in the row that intersect the mask
+    fn row_runs(&self, n: u32, row: u32) -> Vec<(u32, u32)> {
+        let scale = f64::from(n);
+        let top = f64::from(row) / scale;
+        let bottom = f64::from(row + 1) / scale;
+        let middle = (top + bottom) / 2.0;
+        let mut runs = Vec::new();
+        let mut crossings = Vec::new();
+        for ring in &self.rings {
+            for (&a, &b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+                // Tiles crossed by the boundary
+                if let Some((x1, x2)) = clip_to_row(a, b, top, bottom) {
+                    runs.push((tile(x1.min(x2), n), tile(x1.max(x2), n)));
+                }
+                // Tiles inside the polygon are found along the middle of the row
+                if (a.1 > middle) != (b.1 > middle) {
+                    crossings.push(a.0 + (middle - a.1) / (b.1 - a.1) * (b.0 - a.0));
+                }
+            }
+        }
+        crossings.sort_by(f64::total_cmp);
+        for span in crossings.chunks_exact(2) {
+            runs.push((tile(span[0], n), tile(span[1], n)));
+        }
+        runs.sort_unstable();
+        let mut merged: Vec<(u32, u32)> = Vec::with_capacity(runs.len());
+        for (min, max) in runs {
+            match merged.last_mut() {
+                Some(last) if min <= last.1 + 1 => last.1 = last.1.max(max),
+                _ => merged.push((min, max)),
+            }
+        }
+        merged
+    }
+}
+
+/// Horizontal extent of the part of the segment between the top and the bottom of a row, if any
+fn clip_to_row(a: (f64, f64), b: (f64, f64), top: f64, bottom: f64) -> Option<(f64, f64)> {
+    if a.1.max(b.1) < top || a.1.min(b.1) > bottom {
+        return None;
+    }
+    if (b.1 - a.1).abs() < f64::EPSILON {
+        return Some((a.0, b.0));
+    }
+    let x_at = |y: f64| a.0 + (y - a.1) / (b.1 - a.1) * (b.0 - a.0);
+    let (y1, y2) = (a.1.clamp(top, bottom), b.1.clamp(top, bottom));
+    Some((x_at(y1), x_at(y2)))
+}
+
+/// Index of the tile containing the coordinate of the zoom 0 tile space
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn tile(value: f64, n: u32) -> u32 {
+    ((value * f64::from(n)).floor().max(0.0) as u32).min(n - 1)
+}
+
+/// Project a GeoJSON position to the zoom 0 tile space
+fn project(position: &Value) -> Result<(f64, f64), String> {
+    let coord = |idx: usize| position.get(idx).and_then(Value::as_f64);
+    let (Some(lon), Some(lat)) = (coord(0), coord(1)) else {
+        return Err(format!("invalid position {position}"));
+    };
+    let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+    let x = (lon.clamp(-180.0, 180.0) + 180.0) / 360.0;
+    let y = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0;
+    Ok((x, y))
+}
+
+fn collect_polygon(coordinates: &Value, rings: &mut Vec<Vec<(f64, f64)>>) -> Result<(), String> {
+    let polygon = coordinates
+        .as_array()
+        .ok_or("Polygon coordinates must be an array of rings")?;
+    for ring in polygon {
+        let ring = ring
+            .as_array()
+            .ok_or("Polygon rings must be arrays of positions")?
+            .iter()
+            .map(project)
+            .collect::<Result<Vec<_>, _>>()?;
+        if ring.len() >= 3 {
+            rings.push(ring);
+        }
+    }
+    Ok(())
+}
+
+fn collect_rings(value: &Value, rings: &mut Vec<Vec<(f64, f64)>>) -> Result<(), String> {
+    let field = |name: &str| {
+        value
+            .get(name)
+            .ok_or_else(|| format!("GeoJSON object is missing `{name}`"))
+    };
+    match value.get("type").and_then(Value::as_str) {
+        Some("FeatureCollection") => {
+            let features = field("features")?
+                .as_array()
+                .ok_or("`features` must be an array")?;
+            for feature in features {
+                collect_rings(feature, rings)?;
+            }
+        }
+        Some("Feature") => {
+            let geometry = field("geometry")?;
+            if !geometry.is_null() {
+                collect_rings(geometry, rings)?;
+            }
+        }
+        Some("GeometryCollection") => {
+            let geometries = field("geometries")?
+                .as_array()
+                .ok_or("`geometries` must be an array")?;
+            for geometry in geometries {
+                collect_rings(geometry, rings)?;
+            }
+        }
+        Some("Polygon") => collect_polygon(field("coordinates")?, rings)?,
+        Some("MultiPolygon") => {
+            let polygons = field("coordinates")?
+                .as_array()
+                .ok_or("MultiPolygon coordinates must be an array of polygons")?;
+            for polygon in polygons {
+                collect_polygon(polygon, rings)?;
+            }
+        }
+        Some(other) => return Err(format!("{other} geometries cannot be used as a mask")),
+        None => return Err("GeoJSON object is missing `type`".to_string()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use insta::assert_yaml_snapshot;
+
+    use super::*;
+
+    #[test]
+    fn parse_mask() {
+        assert!(GeoMask::parse("{}").is_err());
+        assert!(GeoMask::parse(r#"{"type": "Point", "coordinates": [0, 0]}"#).is_err());
+        assert!(GeoMask::parse(r#"{"type": "FeatureCollection", "features": []}"#).is_err());
+        let mask = GeoMask::parse(
+            r#"{"type": "Feature", "properties": {}, "geometry": {"type": "MultiPolygon", "coordinates": [
+                [[[-180, 0], [0, 0], [0, 85.1], [-180, 85.1], [-180, 0]]],
+                [[[10, -10], [20, -10], [20, -20], [10, -10]]]
+            ]}}"#,
+        )
+        .unwrap();
+        assert_eq!(mask.rings.len(), 2);
+        let near = |(x1, y1): (f64, f64), (x2, y2): (f64, f64)| {
+            (x1 - x2).abs() < 1e-9 && (y1 - y2).abs() < 1e-9
+        };
+        assert!(near(mask.rings[0][0], (0.0, 0.5)));
+        // Clamped to the top of the tiles
+        assert!(near(mask.rings[0][2], (0.5, 0.0)));
+    }
+
+    #[test]
+    fn mask_tile_ranges() {
+        // A triangle over the top left quarter of the world
+        let triangle = GeoMask::parse(
+            r#"{"type": "Polygon", "coordinates": [[[-179, 1], [-1, 1], [-179, 84], [-179, 1]]]}"#,
+        )
+        .unwrap();
+        assert_yaml_snapshot!(triangle.tile_ranges(&[0, 1, 3]), @r###"
+        ---
+        - "0: (0,0) - (0,0)"
+        - "1: (0,0) - (0,0)"
+        - "3: (0,0) - (0,0)"
+        - "3: (0,1) - (1,1)"
+        - "3: (0,2) - (2,2)"
+        - "3: (0,3) - (3,3)"
+        "###);
+
+        // A square with a hole that contains whole tiles
+        let square = GeoMask::parse(
+            r#"{"type": "Polygon", "coordinates": [
+                [[-179, -84], [179, -84], [179, 84], [-179, 84], [-179, -84]],
+                [[-89, -60], [89, -60], [89, 60], [-89, 60], [-89, -60]]
+            ]}"#,
+        )
+        .unwrap();
+        assert_yaml_snapshot!(square.tile_ranges(&[3]), @r###"
+        ---
+        - "3: (0,0) - (7,0)"
+        - "3: (0,1) - (7,1)"
+        - "3: (0,2) - (7,2)"
+        - "3: (0,3) - (2,3)"
+        - "3: (5,3) - (7,3)"
+        - "3: (0,4) - (2,4)"
+        - "3: (5,4) - (7,4)"
+        - "3: (0,5) - (7,5)"
+        - "3: (0,6) - (7,6)"
+        - "3: (0,7) - (7,7)"
+        "###);
+    }
+}
@@ -1,17 +1,44 @@
 mod cfg_containers;
 pub use cfg_containers::{OptBoolObj, OptOneMany};
 
+mod compression;
+#[cfg(feature = "libdeflate")]
+pub use compression::LibdeflateGzip;
+pub use compression::{
+    decode_brotli, decode_gzip, decode_zstd, encode_brotli, encode_brotli_level, encode_gzip,
+    encode_gzip_level, encode_zstd, DefaultGzip, Flate2Gzip, GzipBackend,
+};
+
+mod cron;
+pub use cron::CronSchedule;
+
 mod error;
 pub use error::*;
 
+mod http_client;
+pub use http_client::post_json;
+pub(crate) use http_client::{host_header, send_request};
+
 mod id_resolver;
-pub use id_resolver::IdResolver;
+pub use id_resolver::{
+    encode_source_id, DuplicateIdStrategy, IdPrefixes, IdResolver, IdRules, RenamedId,
+};
+
+mod mask;
+pub use mask::GeoMask;
+
+mod mvt;
+pub(crate) use mvt::write_varint;
+pub use mvt::{mvt_decode, mvt_encode, mvt_feature_counts, MvtFeature, MvtFilter, MvtLayer};
 
 mod rectangle;
-pub use rectangle::{append_rect, TileRect};
+pub use rectangle::{append_rect, compute_tile_ranges, iterate_tiles, TileRect};
+
+mod s3;
+pub use s3::S3Bucket;
 
 mod utilities;
 pub use utilities::*;
 
 mod xyz;
-pub use xyz::TileCoord;
+pub use xyz::{tile_index, TileCoord};
@@ -0,0 +1,787 @@
+use serde_json::{Map, Value};
+use tilejson::VectorLayer;
+
+/// Which layers and feature properties to keep when re-writing a vector tile
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MvtFilter {
+    /// Names of the layers to remove from the tile
+    pub drop_layers: Vec<String>,
+    /// If set, remove all feature properties except these
+    pub keep_properties: Option<Vec<String>>,
+}
+
+impl MvtFilter {
+    /// True if the filter would not change any tiles
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.drop_layers.is_empty() && self.keep_properties.is_none()
+    }
+
+    /// Remove the dropped layers and properties from the uncompressed vector tile.
+    /// All other tile content, including the geometries, is copied as is.
+    pub fn apply(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut tile = Vec::with_capacity(data.len());
+        for (field, value, raw) in proto_fields(data)? {
+            match (field, value) {
+                (3, Some(layer)) => {
+                    if let Some(layer) = self.filter_layer(layer)? {
+                        write_bytes_field(&mut tile, 3, &layer);
+                    }
+                }
+                (3, None) => return Err("layers must be length-delimited".to_string()),
+                _ => tile.extend_from_slice(raw),
+            }
+        }
+        Ok(tile)
+    }
+
+    /// Apply the same filtering to the `vector_layers` of a `TileJSON`
+    pub fn apply_vector_layers(&self, layers: &mut Vec<VectorLayer>) {
+        layers.retain(|l| !self.drop_layers.contains(&l.id));
+        if let Some(keep) = &self.keep_properties {
+            for layer in layers {
+                layer.fields.retain(|k, _| keep.contains(k));
+            }
+        }
+    }
+
+    fn filter_layer(&self, layer: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let fields = proto_fields(layer)?;
+        let name = fields
+            .iter()
+            .find_map(|(f, v, _)| (*f == 1).then_some(*v).flatten());
+        let Some(name) = name else {
+            return Err("layer has no name".to_string());
+        };
+        if self.drop_layers.iter().any(|v| v.as_bytes() == name) {
+            return Ok(None);
+        }
+        let Some(keep) = &self.keep_properties else {
+            return Ok(Some(layer.to_vec()));
+        };
+
+        // Map old key indexes to the new ones, skipping the removed keys
+        let mut key_map = Vec::new();
+        let mut kept_keys = 0;
+        for (_, value, _) in fields.iter().filter(|(f, _, _)| *f == 3) {
+            let key = value.ok_or("keys must be length-delimited")?;
+            if keep.iter().any(|v| v.as_bytes() == key) {
+                key_map.push(Some(kept_keys));
+                kept_keys += 1;
+            } else {
+                key_map.push(None);
+            }
+        }
+
+        // Re-write feature tags, keeping track of which values are still in use
+        let values_count = fields.iter().filter(|(f, _, _)| *f == 4).count();
+        let mut used_values = vec![false; values_count];
+        let mut features = Vec::new();
+        for (_, value, _) in fields.iter().filter(|(f, _, _)| *f == 2) {
+            let feature = value.ok_or("features must be length-delimited")?;
+            let mut tags = Vec::new();
+            let mut other = Vec::new();
+            for (field, value, raw) in proto_fields(feature)? {
+                if field != 2 {
+                    other.push(raw);
+                    continue;
+                }
+                let mut pairs = packed_varints(value.ok_or("tags must be packed")?)?.into_iter();
+                while let (Some(key), Some(val)) = (pairs.next(), pairs.next()) {
+                    let new_key = usize::try_from(key)
+                        .ok()
+                        .and_then(|k| key_map.get(k).copied())
+                        .ok_or_else(|| format!("tag key {key} does not exist"))?;
+                    let Some(new_key) = new_key else {
+                        continue;
+                    };
+                    let val = usize::try_from(val)
+                        .ok()
+                        .filter(|v| *v < values_count)
+                        .ok_or_else(|| format!("tag value {val} does not exist"))?;
+                    used_values[val] = true;
+                    tags.push((new_key, val));
+                }
+            }
+            features.push((tags, other));
+        }
+
+        let mut value_map = Vec::with_capacity(values_count);
+        let mut kept_values = 0;
+        for used in &used_values {
+            value_map.push(kept_values);
+            if *used {
+                kept_values += 1;
+            }
+        }
+
+        let mut result = Vec::with_capacity(layer.len());
+        let (mut key_idx, mut value_idx, mut features) = (0, 0, features.into_iter());
+        for (field, _, raw) in &fields {
+            match field {
+                2 => {
+                    let (tags, other) = features.next().expect("each feature was parsed");
+                    let mut feature = Vec::new();
+                    if !tags.is_empty() {
+                        let mut packed = Vec::new();
+                        for (key, val) in tags {
+                            write_varint(&mut packed, key);
+                            write_varint(&mut packed, value_map[val]);
+                        }
+                        write_bytes_field(&mut feature, 2, &packed);
+                    }
+                    for raw in other {
+                        feature.extend_from_slice(raw);
+                    }
+                    write_bytes_field(&mut result, 2, &feature);
+                }
+                3 => {
+                    if key_map[key_idx].is_some() {
+                        result.extend_from_slice(raw);
+                    }
+                    key_idx += 1;
+                }
+                4 => {
+                    if used_values[value_idx] {
+                        result.extend_from_slice(raw);
+                    }
+                    value_idx += 1;
+                }
+                _ => result.extend_from_slice(raw),
+            }
+        }
+        Ok(Some(result))
+    }
+}
+
+/// Field number, the length-delimited value if any, and the raw bytes of the whole field
+type ProtoField<'a> = (u64, Option<&'a [u8]>, &'a [u8]);
+
+/// Parse all fields of a protobuf message
+fn proto_fields(data: &[u8]) -> Result<Vec<ProtoField<'_>>, String> {
+    let mut fields = ProtoFields::new(data);
+    let mut result = Vec::new();
+    while !fields.data.is_empty() {
+        let start = fields.data;
+        let (field, value) = fields.next_field()?;
+        result.push((field, value, &start[..start.len() - fields.data.len()]));
+    }
+    Ok(result)
+}
+
+fn packed_varints(data: &[u8]) -> Result<Vec<u64>, String> {
+    let mut fields = ProtoFields::new(data);
+    let mut result = Vec::new();
+    while !fields.data.is_empty() {
+        result.push(fields.varint()?);
+    }
+    Ok(result)
+}
+
+#[allow(clippy::cast_possible_truncation)]
+pub(crate) fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_bytes_field(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+    write_varint(buf, field << 3 | 2);
+    write_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+/// A vector tile layer with all of its features decoded
+#[derive(Debug, Clone, PartialEq)]
+pub struct MvtLayer {
+    pub name: String,
+    pub extent: u32,
+    pub features: Vec<MvtFeature>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MvtFeature {
+    pub id: Option<u64>,
+    /// 1 = point, 2 = line string, 3 = polygon, as defined by the MVT spec
+    pub geom_type: u64,
+    pub properties: Map<String, Value>,
+    /// Points, lines, or rings of the geometry in tile coordinates. Rings are closed.
+    pub parts: Vec<Vec<(i64, i64)>>,
+}
+
+/// Decode all layers, features, properties, and geometries of an uncompressed vector tile
+pub fn mvt_decode(data: &[u8]) -> Result<Vec<MvtLayer>, String> {
+    let mut layers = Vec::new();
+    for (field, value) in proto_values(data)? {
+        match (field, value) {
+            (3, ProtoValue::Bytes(layer)) => layers.push(decode_layer(layer)?),
+            (field, _) => return Err(format!("unexpected tile field {field}")),
+        }
+    }
+    Ok(layers)
+}
+
+fn decode_layer(data: &[u8]) -> Result<MvtLayer, String> {
+    let mut name = None;
+    let mut extent = 4096;
+    let mut features = Vec::new();
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+    for (field, value) in proto_values(data)? {
+        match (field, value) {
+            (1, ProtoValue::Bytes(v)) => name = Some(String::from_utf8_lossy(v).into_owned()),
+            (2, ProtoValue::Bytes(v)) => features.push(v),
+            (3, ProtoValue::Bytes(v)) => keys.push(String::from_utf8_lossy(v).into_owned()),
+            (4, ProtoValue::Bytes(v)) => values.push(decode_value(v)?),
+            (5, ProtoValue::Varint(v)) => {
+                extent = u32::try_from(v).map_err(|_| format!("invalid extent {v}"))?;
+            }
+            _ => {}
+        }
+    }
+    let name = name.ok_or("layer has no name")?;
+    let features = features
+        .into_iter()
+        .map(|v| decode_feature(v, &keys, &values))
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("layer {name}: {e}"))?;
+    Ok(MvtLayer {
+        name,
+        extent,
+        features,
+    })
+}
+
+fn decode_feature(data: &[u8], keys: &[String], values: &[Value]) -> Result<MvtFeature, String> {
+    let mut feature = MvtFeature {
+        id: None,
+        geom_type: 0,
+        properties: Map::new(),
+        parts: Vec::new(),
+    };
+    for (field, value) in proto_values(data)? {
+        match (field, value) {
+            (1, ProtoValue::Varint(v)) => feature.id = Some(v),
+            (2, ProtoValue::Bytes(v)) => {
+                for tag in packed_varints(v)?.chunks(2) {
+                    let (Some(key), Some(value)) = (
+                        tag.first()
+                            .and_then(|k| keys.get(usize::try_from(*k).ok()?)),
+                        tag.get(1)
+                            .and_then(|v| values.get(usize::try_from(*v).ok()?)),
+                    ) else {
+                        return Err("invalid feature tag".to_string());
+                    };
+                    feature.properties.insert(key.clone(), value.clone());
+                }
+            }
+            (3, ProtoValue::Varint(v)) => feature.geom_type = v,
+            (4, ProtoValue::Bytes(v)) => feature.parts = decode_geometry(&packed_varints(v)?)?,
+            _ => {}
+        }
+    }
+    Ok(feature)
+}
+
+fn decode_value(data: &[u8]) -> Result<Value, String> {
+    let mut result = Value::Null;
+    for (field, value) in proto_values(data)? {
+        result = match (field, value) {
+            (1, ProtoValue::Bytes(v)) => Value::String(String::from_utf8_lossy(v).into_owned()),
+            (2, ProtoValue::Fixed32(v)) => Value::from(f64::from(f32::from_bits(v))),
+            (3, ProtoValue::Fixed64(v)) => Value::from(f64::from_bits(v)),
+            #[allow(clippy::cast_possible_wrap)]
+            (4, ProtoValue::Varint(v)) => Value::from(v as i64),
+            (5, ProtoValue::Varint(v)) => Value::from(v),
+            (6, ProtoValue::Varint(v)) => Value::from(zigzag(v)),
+            (7, ProtoValue::Varint(v)) => Value::Bool(v != 0),
+            _ => continue,
+        };
+    }
+    Ok(result)
+}
+
+/// Decode the `MoveTo`, `LineTo`, and `ClosePath` commands of a feature geometry
+fn decode_geometry(commands: &[u64]) -> Result<Vec<Vec<(i64, i64)>>, String> {
+    let mut parts: Vec<Vec<(i64, i64)>> = Vec::new();
+    let (mut x, mut y) = (0_i64, 0_i64);
+    let mut iter = commands.iter();
+    while let Some(cmd) = iter.next() {
+        let (id, count) = (cmd & 0x7, cmd >> 3);
+        match id {
+            1 | 2 => {
+                for _ in 0..count {
+                    let (Some(dx), Some(dy)) = (iter.next(), iter.next()) else {
+                        return Err("truncated geometry".to_string());
+                    };
+                    x += zigzag(*dx);
+                    y += zigzag(*dy);
+                    if id == 1 {
+                        parts.push(vec![(x, y)]);
+                    } else {
+                        let part = parts.last_mut().ok_or("LineTo before MoveTo")?;
+                        part.push((x, y));
+                    }
+                }
+            }
+            7 => {
+                let part = parts.last_mut().ok_or("ClosePath before MoveTo")?;
+                part.push(part[0]);
+            }
+            _ => return Err(format!("unknown geometry command {id}")),
+        }
+    }
+    Ok(parts)
+}
+
+#[allow(clippy::cast_possible_wrap)]
+fn zigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+/// Encode the layers as an uncompressed vector tile, the reverse of [`mvt_decode`].
+/// Null properties are skipped, and arrays and objects are encoded as JSON strings.
+#[must_use]
+pub fn mvt_encode(layers: &[MvtLayer]) -> Vec<u8> {
+    let mut tile = Vec::new();
+    for layer in layers {
+        write_bytes_field(&mut tile, 3, &encode_layer(layer));
+    }
+    tile
+}
+
+fn encode_layer(layer: &MvtLayer) -> Vec<u8> {
+    let mut keys: Vec<&String> = Vec::new();
+    let mut values: Vec<Vec<u8>> = Vec::new();
+    let mut result = Vec::new();
+    write_bytes_field(&mut result, 1, layer.name.as_bytes());
+    for feature in &layer.features {
+        let mut tags = Vec::new();
+        for (key, value) in &feature.properties {
+            let Some(value) = encode_value(value) else {
+                continue;
+            };
+            let key_idx = keys.iter().position(|v| *v == key).unwrap_or_else(|| {
+                keys.push(key);
+                keys.len() - 1
+            });
+            let value_idx = values.iter().position(|v| *v == value).unwrap_or_else(|| {
+                values.push(value);
+                values.len() - 1
+            });
+            write_varint(&mut tags, key_idx as u64);
+            write_varint(&mut tags, value_idx as u64);
+        }
+        let mut data = Vec::new();
+        if let Some(id) = feature.id {
+            write_varint(&mut data, 1 << 3);
+            write_varint(&mut data, id);
+        }
+        if !tags.is_empty() {
+            write_bytes_field(&mut data, 2, &tags);
+        }
+        write_varint(&mut data, 3 << 3);
+        write_varint(&mut data, feature.geom_type);
+        write_bytes_field(&mut data, 4, &encode_geometry(feature));
+        write_bytes_field(&mut result, 2, &data);
+    }
+    for key in keys {
+        write_bytes_field(&mut result, 3, key.as_bytes());
+    }
+    for value in values {
+        write_bytes_field(&mut result, 4, &value);
+    }
+    write_varint(&mut result, 5 << 3);
+    write_varint(&mut result, u64::from(layer.extent));
+    // version 2 of the MVT spec
+    result.extend_from_slice(&[0x78, 0x02]);
+    result
+}
+
+fn encode_value(value: &Value) -> Option<Vec<u8>> {
+    let mut result = Vec::new();
+    match value {
+        Value::Null => return None,
+        Value::String(v) => write_bytes_field(&mut result, 1, v.as_bytes()),
+        Value::Bool(v) => {
+            write_varint(&mut result, 7 << 3);
+            write_varint(&mut result, u64::from(*v));
+        }
+        Value::Number(v) => {
+            if let Some(v) = v.as_u64() {
+                write_varint(&mut result, 5 << 3);
+                write_varint(&mut result, v);
+            } else if let Some(v) = v.as_i64() {
+                write_varint(&mut result, 6 << 3);
+                write_varint(&mut result, unzigzag(v));
+            } else {
+                write_varint(&mut result, 3 << 3 | 1);
+                result.extend_from_slice(&v.as_f64().unwrap_or_default().to_bits().to_le_bytes());
+            }
+        }
+        Value::Array(_) | Value::Object(_) => {
+            write_bytes_field(&mut result, 1, value.to_string().as_bytes());
+        }
+    }
+    Some(result)
+}
+
+/// Encode the parts of a geometry as `MoveTo`, `LineTo`, and `ClosePath` commands.
+/// Each point of a multi-point is a separate part, and the rings of a polygon are closed.
+fn encode_geometry(feature: &MvtFeature) -> Vec<u8> {
+    let mut result = Vec::new();
+    let (mut x, mut y) = (0_i64, 0_i64);
+    let mut write_points = |result: &mut Vec<u8>, cmd: u64, points: &[(i64, i64)]| {
+        if points.is_empty() {
+            return;
+        }
+        write_varint(result, cmd | (points.len() as u64) << 3);
+        for &(px, py) in points {
+            write_varint(result, unzigzag(px - x));
+            write_varint(result, unzigzag(py - y));
+            (x, y) = (px, py);
+        }
+    };
+    if feature.geom_type == 1 {
+        let points: Vec<_> = feature
+            .parts
+            .iter()
+            .filter_map(|v| v.first().copied())
+            .collect();
+        write_points(&mut result, 1, &points);
+        return result;
+    }
+    for part in &feature.parts {
+        let Some((first, rest)) = part.split_first() else {
+            continue;
+        };
+        write_points(&mut result, 1, &[*first]);
+        if feature.geom_type == 3 {
+            write_points(&mut result, 2, rest.strip_suffix(&[*first]).unwrap_or(rest));
+            write_varint(&mut result, 7 | 1 << 3);
+        } else {
+            write_points(&mut result, 2, rest);
+        }
+    }
+    result
+}
+
+#[allow(clippy::cast_sign_loss)]
+fn unzigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// Walk the protobuf encoding of a vector tile, returning the name and the number of features of each layer.
+/// Per the MVT spec, a tile may only contain layers (field 3), each having a name (field 1),
+/// and any number of features (field 2).
+pub fn mvt_feature_counts(data: &[u8]) -> Result<Vec<(String, usize)>, String> {
+    let mut layers = Vec::new();
+    for (field, value) in ProtoFields::new(data) {
+        match (field?, value) {
+            (3, Some(layer)) => {
+                let mut name = None;
+                let mut features = 0;
+                for (field, value) in ProtoFields::new(layer) {
+                    match (field?, value) {
+                        (1, Some(v)) => name = Some(String::from_utf8_lossy(v).into_owned()),
+                        (2, Some(_)) => features += 1,
+                        _ => {}
+                    }
+                }
+                let Some(name) = name else {
+                    return Err(format!("layer #{} has no name", layers.len()));
+                };
+                layers.push((name, features));
+            }
+            (field, _) => return Err(format!("unexpected tile field {field}")),
+        }
+    }
+    Ok(layers)
+}
+
+/// Value of a protobuf field, depending on its wire type
+#[derive(Clone, Copy, Debug)]
+enum ProtoValue<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Fixed32(u32),
+    Bytes(&'a [u8]),
+}
+
+fn to_array<const N: usize>(data: &[u8]) -> Result<[u8; N], String> {
+    data.try_into().map_err(|_| "truncated field".to_string())
+}
+
+/// Parse all fields of a protobuf message, including the scalar values
+fn proto_values(data: &[u8]) -> Result<Vec<(u64, ProtoValue<'_>)>, String> {
+    let mut fields = ProtoFields::new(data);
+    let mut result = Vec::new();
+    while !fields.data.is_empty() {
+        result.push(fields.next_value()?);
+    }
+    Ok(result)
+}
+
+/// Iterator over top-level protobuf fields, yielding the field number and the length-delimited value if any
+struct ProtoFields<'a> {
+    data: &'a [u8],
+    failed: bool,
+}
+
+impl<'a> ProtoFields<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            failed: false,
+        }
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut result = 0_u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.data.split_first().ok_or("truncated varint")?;
+            self.data = rest;
+            result |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+        }
+        Err("varint is too long".to_string())
+    }
+
+    fn skip(&mut self, len: u64) -> Result<&'a [u8], String> {
+        let len = usize::try_from(len).map_err(|e| e.to_string())?;
+        if len > self.data.len() {
+            return Err("truncated field".to_string());
+        }
+        let (value, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(value)
+    }
+
+    fn next_field(&mut self) -> Result<(u64, Option<&'a [u8]>), String> {
+        let (field, value) = self.next_value()?;
+        match value {
+            ProtoValue::Bytes(v) => Ok((field, Some(v))),
+            _ => Ok((field, None)),
+        }
+    }
+
+    fn next_value(&mut self) -> Result<(u64, ProtoValue<'a>), String> {
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            0 => ProtoValue::Varint(self.varint()?),
+            1 => ProtoValue::Fixed64(u64::from_le_bytes(to_array(self.skip(8)?)?)),
+            2 => {
+                let len = self.varint()?;
+                ProtoValue::Bytes(self.skip(len)?)
+            }
+            5 => ProtoValue::Fixed32(u32::from_le_bytes(to_array(self.skip(4)?)?)),
+            v => Err(format!("unsupported protobuf wire type {v}"))?,
+        };
+        Ok((key >> 3, value))
+    }
+}
+
+impl<'a> Iterator for ProtoFields<'a> {
+    type Item = (Result<u64, String>, Option<&'a [u8]>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.data.is_empty() {
+            return None;
+        }
+        Some(match self.next_field() {
+            Ok((field, value)) => (Ok(field), value),
+            Err(e) => {
+                self.failed = true;
+                (Err(e), None)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feature_counts() {
+        // Layer "a" with two empty features, and layer "bc" with none
+        let tile = [
+            0x1A, 0x09, 0x0A, 0x01, b'a', 0x12, 0x00, 0x12, 0x00, 0x78, 0x02, //
+            0x1A, 0x06, 0x0A, 0x02, b'b', b'c', 0x78, 0x02,
+        ];
+        assert_eq!(
+            mvt_feature_counts(&tile),
+            Ok(vec![("a".to_string(), 2), ("bc".to_string(), 0)])
+        );
+        assert_eq!(mvt_feature_counts(&[]), Ok(vec![]));
+        assert!(mvt_feature_counts(&tile[..5]).is_err());
+        assert!(mvt_feature_counts(&[0x1A, 0x02, 0x78, 0x02]).is_err());
+    }
+
+    /// Encode a layer with the given keys, string values, and features with tags
+    fn layer(name: &str, keys: &[&str], values: &[&str], features: &[&[u64]]) -> Vec<u8> {
+        let mut layer = Vec::new();
+        write_bytes_field(&mut layer, 1, name.as_bytes());
+        for tags in features {
+            let mut packed = Vec::new();
+            for tag in *tags {
+                write_varint(&mut packed, *tag);
+            }
+            let mut feature = Vec::new();
+            if !packed.is_empty() {
+                write_bytes_field(&mut feature, 2, &packed);
+            }
+            // geometry type = point
+            feature.extend_from_slice(&[0x18, 0x01]);
+            write_bytes_field(&mut layer, 2, &feature);
+        }
+        for key in keys {
+            write_bytes_field(&mut layer, 3, key.as_bytes());
+        }
+        for value in values {
+            let mut val = Vec::new();
+            write_bytes_field(&mut val, 1, value.as_bytes());
+            write_bytes_field(&mut layer, 4, &val);
+        }
+        layer.extend_from_slice(&[0x78, 0x02]);
+        layer
+    }
+
+    fn tile(layers: &[Vec<u8>]) -> Vec<u8> {
+        let mut tile = Vec::new();
+        for layer in layers {
+            write_bytes_field(&mut tile, 3, layer);
+        }
+        tile
+    }
+
+    #[test]
+    fn filter_tile() {
+        let src = tile(&[
+            layer(
+                "a",
+                &["x", "y", "z"],
+                &["1", "2", "3"],
+                &[&[0, 0, 1, 1], &[2, 2, 1, 1]],
+            ),
+            layer("b", &[], &[], &[&[]]),
+        ]);
+
+        let filter = MvtFilter::default();
+        assert!(filter.is_empty());
+        assert_eq!(filter.apply(&src), Ok(src.clone()));
+
+        let filter = MvtFilter {
+            drop_layers: vec!["b".to_string()],
+            keep_properties: None,
+        };
+        let res = filter.apply(&src).unwrap();
+        assert_eq!(mvt_feature_counts(&res), Ok(vec![("a".to_string(), 2)]));
+
+        let filter = MvtFilter {
+            drop_layers: vec![],
+            keep_properties: Some(vec!["z".to_string()]),
+        };
+        let expected = tile(&[
+            layer("a", &["z"], &["3"], &[&[], &[0, 0]]),
+            layer("b", &[], &[], &[&[]]),
+        ]);
+        assert_eq!(filter.apply(&src), Ok(expected));
+
+        assert!(filter
+            .apply(&tile(&[layer("a", &[], &[], &[&[5, 0]])]))
+            .is_err());
+    }
+
+    #[test]
+    fn encode_tile() {
+        let layers = vec![
+            MvtLayer {
+                name: "points".to_string(),
+                extent: 4096,
+                features: vec![MvtFeature {
+                    id: Some(7),
+                    geom_type: 1,
+                    properties: Map::from_iter([
+                        ("name".to_string(), Value::from("x")),
+                        ("rank".to_string(), Value::from(-2)),
+                        ("height".to_string(), Value::from(1.5)),
+                        ("open".to_string(), Value::from(true)),
+                    ]),
+                    parts: vec![vec![(10, 20)], vec![(-5, 4200)]],
+                }],
+            },
+            MvtLayer {
+                name: "shapes".to_string(),
+                extent: 512,
+                features: vec![
+                    MvtFeature {
+                        id: None,
+                        geom_type: 2,
+                        properties: Map::from_iter([("name".to_string(), Value::from("x"))]),
+                        parts: vec![vec![(0, 0), (10, 10), (20, 0)], vec![(5, 5), (6, 6)]],
+                    },
+                    MvtFeature {
+                        id: Some(1),
+                        geom_type: 3,
+                        properties: Map::new(),
+                        parts: vec![vec![(1, 1), (1, 3), (3, 3), (3, 1), (1, 1)]],
+                    },
+                ],
+            },
+        ];
+        let data = mvt_encode(&layers);
+        assert_eq!(mvt_decode(&data), Ok(layers));
+        assert_eq!(
+            mvt_feature_counts(&data),
+            Ok(vec![("points".to_string(), 1), ("shapes".to_string(), 2)])
+        );
+    }
+
+    #[test]
+    fn decode_tile() {
+        let data = tile(&[layer("a", &["name", "rank"], &["x"], &[&[0, 0]])]);
+        assert_eq!(
+            mvt_decode(&data),
+            Ok(vec![MvtLayer {
+                name: "a".to_string(),
+                extent: 4096,
+                features: vec![MvtFeature {
+                    id: None,
+                    geom_type: 1,
+                    properties: Map::from_iter([("name".to_string(), Value::from("x"))]),
+                    parts: vec![],
+                }],
+            }])
+        );
+
+        // A layer with a square polygon feature having id=3 and rank=-2 as a sint value
+        let mut square = Vec::new();
+        write_bytes_field(&mut square, 1, b"b");
+        let mut feature = vec![0x08, 0x03, 0x18, 0x03];
+        // MoveTo(1,1), LineTo(+2,0 +0,+2 -2,0), ClosePath
+        let mut geom = Vec::new();
+        for v in [9, 2, 2, 26, 4, 0, 0, 4, 3, 0, 15] {
+            write_varint(&mut geom, v);
+        }
+        write_bytes_field(&mut feature, 2, &[0, 0]);
+        write_bytes_field(&mut feature, 4, &geom);
+        write_bytes_field(&mut square, 2, &feature);
+        write_bytes_field(&mut square, 3, b"rank");
+        write_bytes_field(&mut square, 4, &[0x30, 0x03]);
+        let layers = mvt_decode(&tile(&[square])).unwrap();
+        let feature = &layers[0].features[0];
+        assert_eq!(feature.id, Some(3));
+        assert_eq!(feature.properties["rank"], Value::from(-2));
+        assert_eq!(
+            feature.parts,
+            vec![vec![(1, 1), (3, 1), (3, 3), (1, 3), (1, 1)]]
+        );
+
+        assert!(mvt_decode(&tile(&[layer("a", &[], &[], &[&[5, 0]])])).is_err());
+    }
+}
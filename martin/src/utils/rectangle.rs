@@ -1,4 +1,9 @@
+use std::fmt::{Display, Formatter};
+
 use serde::Serialize;
+use tilejson::Bounds;
+
+use crate::{tile_index, TileCoord};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct TileRect {
@@ -14,10 +19,17 @@ impl Serialize for TileRect {
     where
         S: serde::ser::Serializer,
     {
-        serializer.collect_str(&format!(
+        serializer.collect_str(self)
+    }
+}
+
+impl Display for TileRect {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
             "{}: ({},{}) - ({},{})",
             self.zoom, self.min_x, self.min_y, self.max_x, self.max_y
-        ))
+        )
     }
 }
 
@@ -94,6 +106,35 @@ pub fn append_rect(rectangles: &mut Vec<TileRect>, new_rect: TileRect) {
     rectangles.push(new_rect);
 }
 
+/// Compute the non-overlapping tile ranges covering the given bounds at each of the zoom levels.
+/// If no bounds are given, the whole world is covered.
+#[must_use]
+pub fn compute_tile_ranges(bounds: &[Bounds], zooms: &[u8]) -> Vec<TileRect> {
+    let mut ranges = Vec::new();
+    let world = [Bounds::MAX_TILED];
+    let boxes = if bounds.is_empty() { &world } else { bounds };
+    for zoom in zooms {
+        for bbox in boxes {
+            let (min_x, min_y) = tile_index(bbox.left, bbox.top, *zoom);
+            let (max_x, max_y) = tile_index(bbox.right, bbox.bottom, *zoom);
+            append_rect(
+                &mut ranges,
+                TileRect::new(*zoom, min_x, min_y, max_x, max_y),
+            );
+        }
+    }
+    ranges
+}
+
+/// Given a list of tile ranges, iterate over all tiles in the ranges
+pub fn iterate_tiles(tiles: Vec<TileRect>) -> impl Iterator<Item = TileCoord> {
+    tiles.into_iter().flat_map(|t| {
+        let z = t.zoom;
+        (t.min_x..=t.max_x)
+            .flat_map(move |x| (t.min_y..=t.max_y).map(move |y| TileCoord { z, x, y }))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
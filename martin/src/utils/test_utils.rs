@@ -1,10 +1,13 @@
 // This file is included from multiple projects, so we need to make sure
-// that `crate::Env` is always available, both when it is part of the lib or external to the test.
+// that `crate::Env` and the source types are always available, both when it is part of the lib or external to the test.
 use std::ffi::OsString;
 
+use async_trait::async_trait;
+use martin_tile_utils::{Format, TileInfo};
 use subst::VariableMap;
+use tilejson::{tilejson, TileJSON};
 
-use crate::Env;
+use crate::{Env, MartinResult, Source, SourceFieldStats, TileCoord, TileData, UrlQuery};
 
 #[allow(clippy::unnecessary_wraps)]
 #[must_use]
@@ -39,6 +42,55 @@ impl<'a> Env<'a> for FauxEnv {
     }
 }
 
+/// A source with the given `TileJSON` that returns the same tile for all coordinates
+#[derive(Clone, Debug)]
+pub struct TestSource {
+    pub id: &'static str,
+    pub tj: TileJSON,
+    pub format: Format,
+    pub data: TileData,
+    pub field_stats: Option<SourceFieldStats>,
+}
+
+impl Default for TestSource {
+    fn default() -> Self {
+        Self {
+            id: "id",
+            tj: tilejson! { tiles: vec![] },
+            format: Format::Mvt,
+            data: TileData::new(),
+            field_stats: None,
+        }
+    }
+}
+
+#[async_trait]
+impl Source for TestSource {
+    fn get_id(&self) -> &str {
+        self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tj
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        TileInfo::from(self.format)
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    async fn get_tile(&self, _: &TileCoord, _: &Option<UrlQuery>) -> MartinResult<TileData> {
+        Ok(self.data.clone())
+    }
+
+    async fn get_field_stats(&self) -> MartinResult<Option<SourceFieldStats>> {
+        Ok(self.field_stats.clone())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,12 +1,58 @@
+use std::f64::consts::PI;
 use std::fmt::{Display, Formatter};
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct TileCoord {
     pub z: u8,
     pub x: u32,
     pub y: u32,
 }
 
+impl TileCoord {
+    /// Parse a [Bing Maps quadkey](https://learn.microsoft.com/en-us/bingmaps/articles/bing-maps-tile-system),
+    /// where the number of digits is the zoom level.
+    #[must_use]
+    pub fn from_quadkey(key: &str) -> Option<Self> {
+        if key.len() > 32 {
+            return None;
+        }
+        let mut xyz = Self { z: 0, x: 0, y: 0 };
+        for digit in key.bytes() {
+            let digit = match digit {
+                b'0'..=b'3' => u32::from(digit - b'0'),
+                _ => return None,
+            };
+            xyz.z += 1;
+            xyz.x = (xyz.x << 1) | (digit & 1);
+            xyz.y = (xyz.y << 1) | (digit >> 1);
+        }
+        Some(xyz)
+    }
+
+    #[must_use]
+    pub fn to_quadkey(&self) -> String {
+        (1..=self.z)
+            .rev()
+            .map(|i| {
+                let mask = 1 << (i - 1);
+                let digit = u8::from(self.x & mask != 0) + 2 * u8::from(self.y & mask != 0);
+                char::from(b'0' + digit)
+            })
+            .collect()
+    }
+
+    /// Convert the Y coordinate between XYZ and TMS schemes (the conversion is symmetric).
+    /// Returns `None` if Y is out of range for the zoom level.
+    #[must_use]
+    pub fn flip_y(self) -> Option<Self> {
+        let max = 1_u32.checked_shl(u32::from(self.z))?.checked_sub(1)?;
+        Some(Self {
+            y: max.checked_sub(self.y)?,
+            ..self
+        })
+    }
+}
+
 impl Display for TileCoord {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         if f.alternate() {
@@ -16,3 +62,50 @@ impl Display for TileCoord {
         }
     }
 }
+
+/// Convert longitude and latitude to tile index
+#[must_use]
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+pub fn tile_index(lon: f64, lat: f64, zoom: u8) -> (u32, u32) {
+    let n = f64::from(1_u32 << zoom);
+    let x = ((lon + 180.0) / 360.0 * n).floor() as u32;
+    let y = ((1.0 - (lat.to_radians().tan() + 1.0 / lat.to_radians().cos()).ln() / PI) / 2.0 * n)
+        .floor() as u32;
+    let max_value = (1_u32 << zoom) - 1;
+    (x.min(max_value), y.min(max_value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tile_index() {
+        assert_eq!((0, 0), tile_index(-180.0, 85.0511, 0));
+    }
+
+    #[test]
+    fn test_quadkey() {
+        let xyz = |z, x, y| TileCoord { z, x, y };
+        assert_eq!(TileCoord::from_quadkey(""), Some(xyz(0, 0, 0)));
+        assert_eq!(TileCoord::from_quadkey("3"), Some(xyz(1, 1, 1)));
+        // Example from the Bing Maps documentation
+        assert_eq!(TileCoord::from_quadkey("213"), Some(xyz(3, 3, 5)));
+        assert_eq!(xyz(3, 3, 5).to_quadkey(), "213");
+        assert_eq!(xyz(0, 0, 0).to_quadkey(), "");
+        let deep = "0123".repeat(8);
+        assert_eq!(TileCoord::from_quadkey(&deep).unwrap().to_quadkey(), deep);
+        assert_eq!(TileCoord::from_quadkey("014"), None);
+        assert_eq!(TileCoord::from_quadkey(&"1".repeat(33)), None);
+    }
+
+    #[test]
+    fn test_flip_y() {
+        let xyz = |z, x, y| TileCoord { z, x, y };
+        assert_eq!(xyz(0, 0, 0).flip_y(), Some(xyz(0, 0, 0)));
+        assert_eq!(xyz(2, 1, 0).flip_y(), Some(xyz(2, 1, 3)));
+        assert_eq!(xyz(2, 1, 3).flip_y(), Some(xyz(2, 1, 0)));
+        assert_eq!(xyz(2, 1, 4).flip_y(), None);
+        assert_eq!(xyz(40, 0, 0).flip_y(), None);
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use log::{debug, info};
+use martin_tile_utils::TileInfo;
+use serde::{Deserialize, Serialize};
+use tilejson::TileJSON;
+
+use crate::source::{
+    request_header, FeatureFilter, Source, SourceFieldStats, TileData, TileInfoSource,
+    TileInfoSources, TileSources, UrlQuery,
+};
+use crate::variants::VariantError::{MismatchedTileInfo, UnknownDefaultVariant, UnknownSource};
+use crate::{IdResolver, MartinResult, TileCoord};
+
+/// URL query parameter used to pick a variant, e.g. `/basemap/0/0/0?variant=dark`
+pub const VARIANT_QUERY_PARAM: &str = "variant";
+
+pub type VariantResult<T> = Result<T, VariantError>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum VariantError {
+    #[error("Variant {1} of source {0} refers to source {2}, which does not exist")]
+    UnknownSource(String, String, String),
+
+    #[error("Default variant {1} of source {0} is not one of its variants")]
+    UnknownDefaultVariant(String, String),
+
+    #[error(
+        "All variants of source {0} must have the same tile format, but {1} is {2} and {3} is {4}"
+    )]
+    MismatchedTileInfo(String, String, TileInfo, String, TileInfo),
+}
+
+/// Variant sources keyed by their public source ID
+pub type VariantConfig = BTreeMap<String, VariantSourceConfig>;
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VariantSourceConfig {
+    /// Variant to use when the request has no `variant` query parameter or header, or it is not known
+    pub default: String,
+    /// HTTP header to pick the variant from, e.g. `X-Theme`, if the request has no `variant` query parameter
+    pub header: Option<String>,
+    /// Variant name to source ID mapping
+    pub sources: BTreeMap<String, String>,
+}
+
+/// Create variant sources on top of the already resolved tile sources
+pub fn resolve_variants(
+    config: &VariantConfig,
+    sources: &TileSources,
+    idr: &IdResolver,
+) -> VariantResult<TileInfoSources> {
+    let mut results = TileInfoSources::new();
+    for (id, cfg) in config {
+        if !cfg.sources.contains_key(&cfg.default) {
+            return Err(UnknownDefaultVariant(id.clone(), cfg.default.clone()));
+        }
+
+        let mut variants = BTreeMap::new();
+        for (name, src_id) in &cfg.sources {
+            let src = sources
+                .get_source(src_id)
+                .map_err(|_| UnknownSource(id.clone(), name.clone(), src_id.clone()))?;
+            variants.insert(name.clone(), src.clone_source());
+        }
+
+        let default = &variants[&cfg.default];
+        let info = default.get_tile_info();
+        if let Some((name, src)) = variants.iter().find(|(_, v)| v.get_tile_info() != info) {
+            return Err(MismatchedTileInfo(
+                id.clone(),
+                cfg.default.clone(),
+                info,
+                name.clone(),
+                src.get_tile_info(),
+            ));
+        }
+
+        let names = cfg.sources.keys().cloned().collect::<Vec<_>>().join(",");
+        let Some(id) = idr.resolve(id, format!("variant.{id}.{names}")) else {
+            continue;
+        };
+        info!("Configured source {id} with variants {names}");
+        results.push(Box::new(VariantSource {
+            id,
+            tilejson: default.get_tilejson().clone(),
+            tile_info: info,
+            default: cfg.default.clone(),
+            header: cfg.header.clone(),
+            variants,
+        }));
+    }
+    Ok(results)
+}
+
+/// A source that forwards each request to one of several sources with the same tile format
+#[derive(Clone, Debug)]
+pub struct VariantSource {
+    id: String,
+    tilejson: TileJSON,
+    tile_info: TileInfo,
+    default: String,
+    header: Option<String>,
+    variants: BTreeMap<String, TileInfoSource>,
+}
+
+impl VariantSource {
+    fn get_variant(&self, query: Option<&UrlQuery>) -> &dyn Source {
+        let name = query
+            .and_then(|q| q.get(VARIANT_QUERY_PARAM).cloned())
+            .or_else(|| request_header(self.header.as_ref()?));
+        let variant = name.as_ref().and_then(|v| self.variants.get(v));
+        if let (Some(name), None) = (name, variant) {
+            debug!(
+                "Unknown variant {name} of source {}, using {}",
+                self.id, self.default
+            );
+        }
+        variant.unwrap_or(&self.variants[&self.default]).as_ref()
+    }
+}
+
+#[async_trait]
+impl Source for VariantSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.tile_info
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        true
+    }
+
+    fn include_feature_count(&self) -> bool {
+        self.variants.values().any(|v| v.include_feature_count())
+    }
+
+    fn vary_header(&self) -> Option<&str> {
+        self.header.as_deref()
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: &TileCoord,
+        url_query: &Option<UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let src = self.get_variant(url_query.as_ref());
+        let query = if src.support_url_query() {
+            url_query.as_ref().map(|q| {
+                let mut q = q.clone();
+                q.remove(VARIANT_QUERY_PARAM);
+                q
+            })
+        } else {
+            None
+        };
+        src.get_tile(xyz, &query).await
+    }
+
+    async fn get_field_stats(&self) -> MartinResult<Option<SourceFieldStats>> {
+        self.variants[&self.default].get_field_stats().await
+    }
+
+    fn supports_features(&self) -> bool {
+        self.variants[&self.default].supports_features()
+    }
+
+    async fn get_features(
+        &self,
+        filter: &FeatureFilter,
+    ) -> MartinResult<Option<Vec<serde_json::Value>>> {
+        self.variants[&self.default].get_features(filter).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+    use indoc::indoc;
+    use martin_tile_utils::Format;
+
+    use super::*;
+    use crate::config::tests::parse_cfg;
+    use crate::source::with_headers;
+    use crate::test_utils::TestSource;
+
+    fn test_sources() -> TileSources {
+        let src = |id, data| -> TileInfoSource {
+            Box::new(TestSource {
+                id,
+                format: Format::Png,
+                data,
+                ..Default::default()
+            })
+        };
+        TileSources::new(vec![vec![
+            src("light", vec![1].into()),
+            src("dark", vec![2].into()),
+        ]])
+    }
+
+    #[test]
+    fn parse_variant_config() {
+        let cfg = parse_cfg(indoc! {"
+            variants:
+              basemap:
+                default: light
+                header: X-Theme
+                sources:
+                  light: basemap_light
+                  dark: basemap_dark
+        "});
+        assert!(cfg.unrecognized.is_empty());
+        assert_eq!(
+            cfg.variants,
+            VariantConfig::from([(
+                "basemap".to_string(),
+                VariantSourceConfig {
+                    default: "light".to_string(),
+                    header: Some("X-Theme".to_string()),
+                    sources: BTreeMap::from([
+                        ("light".to_string(), "basemap_light".to_string()),
+                        ("dark".to_string(), "basemap_dark".to_string()),
+                    ]),
+                }
+            )])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn select_variant() {
+        let cfg = VariantConfig::from([(
+            "basemap".to_string(),
+            VariantSourceConfig {
+                default: "light".to_string(),
+                header: Some("X-Theme".to_string()),
+                sources: BTreeMap::from([
+                    ("light".to_string(), "light".to_string()),
+                    ("dark".to_string(), "dark".to_string()),
+                ]),
+            },
+        )]);
+        let res = resolve_variants(&cfg, &test_sources(), &IdResolver::default()).unwrap();
+        let src = &res[0];
+        assert_eq!(src.get_id(), "basemap");
+
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let query = |v: &str| Some(UrlQuery::from([("variant".to_string(), v.to_string())]));
+        assert_eq!(src.get_tile(&xyz, &None).await.unwrap(), vec![1]);
+        assert_eq!(src.get_tile(&xyz, &query("dark")).await.unwrap(), vec![2]);
+        assert_eq!(src.get_tile(&xyz, &query("light")).await.unwrap(), vec![1]);
+        assert_eq!(src.get_tile(&xyz, &query("sepia")).await.unwrap(), vec![1]);
+
+        let header = |v| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                HeaderName::from_static("x-theme"),
+                HeaderValue::from_static(v),
+            );
+            headers
+        };
+        let tile = with_headers(header("dark"), src.get_tile(&xyz, &None)).await;
+        assert_eq!(tile.unwrap(), vec![2]);
+        // The query parameter takes precedence over the header
+        let tile = with_headers(header("dark"), src.get_tile(&xyz, &query("light"))).await;
+        assert_eq!(tile.unwrap(), vec![1]);
+        let tile = with_headers(header("sepia"), src.get_tile(&xyz, &None)).await;
+        assert_eq!(tile.unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn resolve_errors() {
+        let cfg = |default: &str, dark: &str| {
+            VariantConfig::from([(
+                "basemap".to_string(),
+                VariantSourceConfig {
+                    default: default.to_string(),
+                    header: None,
+                    sources: BTreeMap::from([
+                        ("light".to_string(), "light".to_string()),
+                        ("dark".to_string(), dark.to_string()),
+                    ]),
+                },
+            )])
+        };
+        let idr = IdResolver::default();
+        let res = resolve_variants(&cfg("sepia", "dark"), &test_sources(), &idr);
+        assert!(matches!(res, Err(UnknownDefaultVariant(..))));
+        let res = resolve_variants(&cfg("light", "missing"), &test_sources(), &idr);
+        assert!(matches!(res, Err(UnknownSource(..))));
+    }
+}
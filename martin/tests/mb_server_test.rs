@@ -1,9 +1,13 @@
+use std::collections::BTreeMap;
+
 use actix_web::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE};
+use actix_web::http::StatusCode;
 use actix_web::test::{call_service, read_body, read_body_json, TestRequest};
 use ctor::ctor;
 use indoc::indoc;
 use insta::assert_yaml_snapshot;
 use martin::decode_gzip;
+use martin::srv::SourceSchema;
 use tilejson::TileJSON;
 
 pub mod utils;
@@ -118,6 +122,134 @@ async fn mbt_get_tilejson() {
     assert_eq!(body.maxzoom, Some(6));
 }
 
+#[actix_rt::test]
+async fn mbt_get_bulk_tilejson() {
+    let app = create_app! { CONFIG };
+    let req = test_get("/tilejson?sources=m_mvt,m_webp&scheme=tms").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let body: BTreeMap<String, TileJSON> = read_body_json(response).await;
+    assert_eq!(body.len(), 2);
+    assert_eq!(body["m_mvt"].maxzoom, Some(6));
+    assert_eq!(body["m_mvt"].scheme.as_deref(), Some("tms"));
+    assert_eq!(
+        body["m_webp"].tiles,
+        vec!["http://localhost:8080/m_webp/{z}/{x}/{y}?scheme=tms"]
+    );
+
+    let req = test_get("/tilejson?sources=m_mvt,missing").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn mbt_get_schema() {
+    let app = create_app! { CONFIG };
+    let req = test_get("/m_mvt/schema?stats=true").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let body: SourceSchema = read_body_json(response).await;
+    assert_eq!(body.layers.len(), 1);
+    let layer = &body.layers[0];
+    assert_eq!(layer.id, "cities");
+    assert_eq!(layer.source, "m_mvt");
+    assert_eq!(layer.fields["name"].typ, "String");
+    assert_eq!(
+        layer.fields["name"].stats, None,
+        "MBTiles has no field stats"
+    );
+}
+
+#[actix_rt::test]
+async fn mbt_get_features() {
+    let app = create_app! { CONFIG };
+    let req = test_get("/m_mvt/query?lon=0&lat=0&zoom=0&radius=256").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let headers = response.headers();
+    assert_eq!(headers.get(CONTENT_TYPE).unwrap(), "application/geo+json");
+    let body: serde_json::Value = read_body_json(response).await;
+    assert_eq!(body["type"], "FeatureCollection");
+    let features = body["features"].as_array().unwrap();
+    assert!(!features.is_empty());
+    assert_eq!(features[0]["layer"], "cities");
+    assert_eq!(features[0]["geometry"]["type"], "Point");
+
+    let req = test_get("/m_mvt/query?lon=0&lat=89&zoom=0").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let req = test_get("/m_webp/query?lon=0&lat=0&zoom=0").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_rt::test]
+async fn mbt_post_tile() {
+    let app = create_app! { CONFIG };
+    let req = TestRequest::post()
+        .uri("/m_mvt/0/0/0")
+        .set_payload(r#"{"filter": {"name": "a"}}"#)
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get(CONTENT_TYPE).unwrap(),
+        "application/x-protobuf"
+    );
+
+    let req = TestRequest::post()
+        .uri("/m_mvt/0/0/0")
+        .set_payload("[1]")
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let req = TestRequest::post()
+        .uri("/m_mvt/0/0/0")
+        .set_payload(format!(r#"{{"a": "{}"}}"#, "x".repeat(70_000)))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[actix_rt::test]
+async fn mbt_get_tile_geojson() {
+    let app = create_app! { CONFIG };
+    let req = test_get("/m_mvt/0/0/0.geojson").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let headers = response.headers();
+    assert_eq!(headers.get(CONTENT_TYPE).unwrap(), "application/geo+json");
+    let body: serde_json::Value = read_body_json(response).await;
+    assert_eq!(body["type"], "FeatureCollection");
+    let features = body["features"].as_array().unwrap();
+    assert!(!features.is_empty());
+    assert!(features.iter().all(|f| f["layer"] == "cities"));
+
+    let req = test_get("/m_webp/0/0/0.geojson").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_rt::test]
+async fn mbt_get_collections() {
+    let app = create_app! { CONFIG };
+    let req = test_get("/collections").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let body: serde_json::Value = read_body_json(response).await;
+    assert_eq!(
+        body["collections"],
+        serde_json::json!([]),
+        "MBTiles cannot return individual features"
+    );
+
+    let req = test_get("/collections/m_mvt/items").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
 #[actix_rt::test]
 async fn mbt_get_tilejson_gzip() {
     let app = create_app! { CONFIG };
@@ -174,6 +306,103 @@ async fn mbt_get_mvt() {
     assert_eq!(body.len(), 1828);
 }
 
+/// get the same MVT tile using the XYZ, TMS, and quadkey coordinates
+#[actix_rt::test]
+async fn mbt_get_mvt_schemes() {
+    let app = create_app! { CONFIG };
+    let mut bodies = Vec::new();
+    for path in [
+        "/m_mvt/1/1/0",
+        "/m_mvt/1/1/1?scheme=tms",
+        "/m_mvt/quadkey/1",
+    ] {
+        let response = call_service(&app, test_get(path).to_request()).await;
+        assert!(response.status().is_success(), "{path}");
+        bodies.push(read_body(response).await);
+    }
+    assert!(!bodies[0].is_empty());
+    assert_eq!(bodies[0], bodies[1]);
+    assert_eq!(bodies[0], bodies[2]);
+
+    for (path, status) in [
+        ("/m_mvt/1/1/2?scheme=tms", 404),
+        ("/m_mvt/1/1/0?scheme=foo", 400),
+        ("/m_mvt/quadkey/14", 400),
+    ] {
+        let response = call_service(&app, test_get(path).to_request()).await;
+        assert_eq!(response.status().as_u16(), status, "{path}");
+    }
+}
+
+/// use the configured TMS scheme of a source unless the URL overrides it
+#[actix_rt::test]
+async fn mbt_get_mvt_configured_scheme() {
+    let state = mock_sources(mock_cfg(CONFIG)).await.0;
+    let schemes = [("m_mvt".to_string(), martin::srv::TileScheme::Tms)];
+    let app = ::actix_web::test::init_service(
+        ::actix_web::App::new()
+            .app_data(actix_web::web::Data::new(state.tiles))
+            .app_data(actix_web::web::Data::new(martin::srv::TileSchemes(
+                schemes.into(),
+            )))
+            .configure(::martin::srv::router),
+    )
+    .await;
+
+    let mut bodies = Vec::new();
+    for path in ["/m_mvt/1/1/1", "/m_mvt/1/1/0?scheme=xyz"] {
+        let response = call_service(&app, test_get(path).to_request()).await;
+        assert!(response.status().is_success(), "{path}");
+        bodies.push(read_body(response).await);
+    }
+    assert!(!bodies[0].is_empty());
+    assert_eq!(bodies[0], bodies[1]);
+
+    let response = call_service(&app, test_get("/m_mvt").to_request()).await;
+    let body: TileJSON = read_body_json(response).await;
+    assert_eq!(body.scheme.as_deref(), Some("tms"));
+    let response = call_service(&app, test_get("/m_webp").to_request()).await;
+    let body: TileJSON = read_body_json(response).await;
+    assert_eq!(body.scheme, None);
+}
+
+/// get high-DPI raster tiles, either native or upscaled
+#[actix_rt::test]
+async fn mbt_get_raster_2x() {
+    let app = create_app! { indoc! {"
+        mbtiles:
+            sources:
+                m_png: ../tests/fixtures/mbtiles/geography-class-png.mbtiles
+                m_jpg: ../tests/fixtures/mbtiles/geography-class-jpg.mbtiles
+                m_jpg_hd:
+                    path: ../tests/fixtures/mbtiles/geography-class-jpg.mbtiles
+                    tile_size: 512
+                m_mvt: ../tests/fixtures/mbtiles/world_cities.mbtiles
+    "} };
+
+    let response = call_service(&app, test_get("/m_jpg_hd").to_request()).await;
+    let tj: TileJSON = read_body_json(response).await;
+    assert_eq!(tj.other.get("tileSize"), Some(&serde_json::json!(512)));
+
+    let response = call_service(&app, test_get("/m_png/0/0/0@2x").to_request()).await;
+    assert!(response.status().is_success());
+    assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "image/png");
+    let body = read_body(response).await;
+    // PNG width and height are stored in the IHDR chunk
+    assert_eq!(&body[16..24], &[0, 0, 2, 0, 0, 0, 2, 0]);
+
+    let response = call_service(&app, test_get("/m_jpg_hd/0/0/0@2x").to_request()).await;
+    assert!(response.status().is_success());
+    let hd = read_body(response).await;
+    let response = call_service(&app, test_get("/m_jpg_hd/0/0/0").to_request()).await;
+    assert_eq!(hd, read_body(response).await);
+
+    for path in ["/m_jpg/0/0/0@2x", "/m_mvt/0/0/0@2x"] {
+        let response = call_service(&app, test_get(path).to_request()).await;
+        assert_eq!(response.status().as_u16(), 400, "{path}");
+    }
+}
+
 /// get an MVT tile with accepted gzip enc
 #[actix_rt::test]
 async fn mbt_get_mvt_gzip() {
@@ -305,3 +534,80 @@ async fn mbt_get_json_gzip() {
     let body = decode_gzip(&body).unwrap();
     assert_eq!(body.len(), 13);
 }
+
+/// A bad file does not prevent the other sources from being served
+#[actix_rt::test]
+async fn mbt_ready_when() {
+    let cfg = mock_cfg(indoc! {"
+        ready_when: 50%
+        mbtiles:
+            sources:
+                m_json: ../tests/fixtures/mbtiles/json.mbtiles
+                m_bad: ../tests/fixtures/mbtiles/missing.mbtiles
+    "});
+    let ready_when = cfg.srv.ready_when.unwrap();
+    let (state, background) = cfg
+        .resolve_in_background(martin::IdResolver::default(), ready_when)
+        .unwrap();
+    let readiness = state.readiness.clone().unwrap();
+    readiness.wait().await.unwrap();
+    background.await.unwrap();
+
+    let app = ::actix_web::test::init_service(
+        ::actix_web::App::new()
+            .app_data(actix_web::web::Data::new(
+                ::martin::srv::Catalog::new(&state).unwrap(),
+            ))
+            .app_data(actix_web::web::Data::new(state.tiles))
+            .app_data(actix_web::web::Data::from(readiness))
+            .configure(::martin::srv::router),
+    )
+    .await;
+
+    let req = test_get("/m_json/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    let req = test_get("/catalog").to_request();
+    let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+    assert!(body["tiles"]["m_json"].is_object());
+
+    let req = test_get("/status").to_request();
+    let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(body["m_bad"]["state"], "failed");
+    assert!(body.get("m_json").is_none());
+}
+
+/// Disabled endpoint groups are rejected, while tiles are still served
+#[actix_rt::test]
+async fn mbt_disabled_endpoints() {
+    let cfg = mock_cfg(indoc! {"
+        endpoints:
+            catalog: false
+            tilejson: false
+        mbtiles:
+            sources:
+                m_json: ../tests/fixtures/mbtiles/json.mbtiles
+    "});
+    let endpoints = cfg.srv.endpoints.clone().unwrap();
+    let state = mock_sources(cfg).await.0;
+    let app = ::actix_web::test::init_service(
+        ::actix_web::App::new()
+            .app_data(actix_web::web::Data::new(
+                ::martin::srv::Catalog::new(&state).unwrap(),
+            ))
+            .app_data(actix_web::web::Data::new(state.tiles))
+            .app_data(actix_web::web::Data::new(endpoints))
+            .configure(::martin::srv::router),
+    )
+    .await;
+
+    for path in ["/catalog", "/m_json", "/tilejson?sources=m_json"] {
+        let response = call_service(&app, test_get(path).to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+    let response = call_service(&app, test_get("/m_json/0/0/0").to_request()).await;
+    assert!(response.status().is_success());
+    let response = call_service(&app, test_get("/").to_request()).await;
+    assert!(response.status().is_success());
+}
@@ -60,3 +60,52 @@ async fn function_source_schemas() {
       description: a function source with MixedCase name
     "###);
 }
+
+#[actix_rt::test]
+async fn function_source_margin() {
+    let cfg = mock_pgcfg(indoc! {"
+        connection_string: $DATABASE_URL
+        functions:
+          with_margin:
+            schema: public
+            function: function_zxy_margin
+            margin: 0.5
+          without_margin:
+            schema: public
+            function: function_zxy_margin
+    "});
+    let mock = mock_sources(cfg).await;
+    // The points near 142.84,11.93 are in the tile 10/918/477, close to its western edge
+    let inside = TileCoord {
+        z: 10,
+        x: 918,
+        y: 477,
+    };
+    let neighbor = TileCoord {
+        z: 10,
+        x: 917,
+        y: 477,
+    };
+    let with_margin = source(&mock, "with_margin");
+    let without_margin = source(&mock, "without_margin");
+    assert!(!with_margin
+        .get_tile(&inside, &None)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(!without_margin
+        .get_tile(&inside, &None)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(!with_margin
+        .get_tile(&neighbor, &None)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(without_margin
+        .get_tile(&neighbor, &None)
+        .await
+        .unwrap()
+        .is_empty());
+}
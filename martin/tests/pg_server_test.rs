@@ -82,6 +82,9 @@ postgres:
       function_zxy2:
         content_type: application/x-protobuf
         description: public.function_zxy2
+      function_zxy_margin:
+        content_type: application/x-protobuf
+        description: public.function_zxy_margin
       function_zxy_query:
         content_type: application/x-protobuf
       function_zxy_query_jsonb:
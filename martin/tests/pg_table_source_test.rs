@@ -46,6 +46,9 @@ async fn table_source() {
     function_zxy2:
       content_type: application/x-protobuf
       description: public.function_zxy2
+    function_zxy_margin:
+      content_type: application/x-protobuf
+      description: public.function_zxy_margin
     function_zxy_query:
       content_type: application/x-protobuf
     function_zxy_query_jsonb:
@@ -183,3 +186,34 @@ async fn table_source_schemas() {
       description: a description from comment on table
     "###);
 }
+
+#[actix_rt::test]
+async fn abandoned_query_connection() {
+    let pool = martin::pg::PgPool::new(&martin::pg::PgConfig {
+        connection_string: std::env::var("DATABASE_URL").ok(),
+        pool_size: Some(1),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let pid_sql = "SELECT pg_backend_pid()";
+    let conn = pool.cancel_on_drop(pool.get().await.unwrap());
+    let pid: i32 = conn.query_one(pid_sql, &[]).await.unwrap().get(0);
+    let query = conn.batch_execute("SELECT pg_sleep(10)");
+    let timeout = tokio::time::timeout(std::time::Duration::from_millis(200), query);
+    assert!(timeout.await.is_err());
+    drop(conn);
+
+    // The abandoned connection is not reused, so its cancel request cannot stop the query of another request
+    let conn = pool.cancel_on_drop(pool.get().await.unwrap());
+    let new_pid: i32 = conn.query_one(pid_sql, &[]).await.unwrap().get(0);
+    assert_ne!(pid, new_pid);
+    conn.batch_execute("SELECT pg_sleep(0.5)").await.unwrap();
+    conn.disarm();
+
+    // A finished query returns the connection to the pool
+    let conn = pool.cancel_on_drop(pool.get().await.unwrap());
+    let pid: i32 = conn.query_one(pid_sql, &[]).await.unwrap().get(0);
+    assert_eq!(pid, new_pid);
+    conn.disarm();
+}
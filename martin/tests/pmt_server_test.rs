@@ -51,6 +51,8 @@ async fn pmt_get_catalog() {
     assert_yaml_snapshot!(body, @r###"
     ---
     fonts: {}
+    source_ids:
+      stamen_toner__raster_CC-BY+ODbL_z3: stamen_toner__raster_CC-BY-ODbL_z3
     sprites: {}
     tiles:
       stamen_toner__raster_CC-BY-ODbL_z3:
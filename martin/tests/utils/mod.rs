@@ -8,6 +8,10 @@ use log::warn;
 use martin::Config;
 pub use pg_utils::*;
 
+// test_utils uses these as `crate::*`, the same as from the tests of the lib.
+// Some tests import them from martin too, so this must not be a public re-export.
+#[allow(unused_imports)]
+pub(crate) use martin::{MartinResult, Source, SourceFieldStats, TileCoord, TileData, UrlQuery};
 #[path = "../../src/utils/test_utils.rs"]
 mod test_utils;
 #[allow(clippy::wildcard_imports)]
@@ -1,6 +1,6 @@
 use std::path::{Path, PathBuf};
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use log::error;
 use mbtiles::{apply_patch, AggHashType, IntegrityCheckType, MbtResult, Mbtiles, MbtilesCopier};
 
@@ -22,7 +22,18 @@ pub struct Args {
 enum Commands {
     /// Show MBTiels file summary statistics
     #[command(name = "summary", alias = "info")]
-    Summary { file: PathBuf },
+    Summary {
+        file: PathBuf,
+        /// Output format of the summary
+        #[arg(long, value_enum, default_value_t = SummaryOutput::default())]
+        output: SummaryOutput,
+        /// Also validate the file, and include the results in the summary
+        #[arg(long)]
+        validate: bool,
+        /// Value to specify the extent of the SQLite integrity check performed with --validate
+        #[arg(long, value_enum, default_value_t=IntegrityCheckType::default(), requires = "validate")]
+        integrity_check: IntegrityCheckType,
+    },
     /// Prints all values in the metadata table in a free-style, unstable YAML format
     #[command(name = "meta-all")]
     MetaAll {
@@ -46,6 +57,9 @@ enum Commands {
         key: String,
         /// Value to set, or nothing if the key should be deleted.
         value: Option<String>,
+        /// Modify the file even if it is locked by another process, e.g. after that process crashed.
+        #[arg(long)]
+        force: bool,
     },
     /// Copy tiles from one mbtiles file to another.
     #[command(name = "copy")]
@@ -57,6 +71,25 @@ enum Commands {
         src_file: PathBuf,
         /// Diff file
         diff_file: PathBuf,
+        /// Before applying the diff, save a copy of the MBTiles file to this new file.
+        #[arg(long, value_name = "FILE")]
+        backup: Option<PathBuf>,
+        /// Modify the file even if it is locked by another process, e.g. after that process crashed.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Store the large tiles in a content-addressed directory next to the file, and only keep references to them in the file.
+    /// Tiles added later are stored the same way. Reading the tiles with Martin or the `mbtiles` crate is unchanged.
+    #[command(name = "externalize")]
+    Externalize {
+        /// MBTiles file to modify
+        file: PathBuf,
+        /// Store the tiles of at least this many bytes outside of the file
+        #[arg(long, value_name = "BYTES")]
+        min_size: usize,
+        /// Modify the file even if it is locked by another process, e.g. after that process crashed.
+        #[arg(long)]
+        force: bool,
     },
     /// Validate tile data if hash of tile data exists in file
     #[command(name = "validate")]
@@ -72,9 +105,22 @@ enum Commands {
         /// How should the aggregate tiles hash be checked or updated.
         #[arg(long, value_enum)]
         agg_hash: Option<AggHashType>,
+        /// Update the file even if it is locked by another process, e.g. after that process crashed.
+        /// Only used when the aggregate tiles hash is updated.
+        #[arg(long)]
+        force: bool,
     },
 }
 
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, ValueEnum)]
+enum SummaryOutput {
+    /// Human-readable text
+    #[default]
+    Text,
+    /// Machine-readable JSON, stable across versions
+    Json,
+}
+
 #[tokio::main]
 async fn main() {
     let env = env_logger::Env::default().default_filter_or("info");
@@ -100,23 +146,48 @@ async fn main_int() -> anyhow::Result<()> {
         Commands::MetaGetValue { file, key } => {
             meta_get_value(file.as_path(), &key).await?;
         }
-        Commands::MetaSetValue { file, key, value } => {
-            meta_set_value(file.as_path(), &key, value.as_deref()).await?;
+        Commands::MetaSetValue {
+            file,
+            key,
+            value,
+            force,
+        } => {
+            meta_set_value(file.as_path(), &key, value.as_deref(), force).await?;
         }
         Commands::Copy(opts) => {
-            opts.run().await?;
+            with_env_passphrases(opts)?.run().await?;
         }
         Commands::ApplyPatch {
             src_file,
             diff_file,
+            backup,
+            force,
         } => {
+            let mbt = new_mbtiles(&src_file)?;
+            let _lock = mbt.lock_for_writing(force)?;
+            if let Some(backup) = backup {
+                let mut conn = mbt.open_readonly().await?;
+                mbt.backup_to(&mut conn, &backup).await?;
+            }
             apply_patch(src_file, diff_file).await?;
         }
+        Commands::Externalize {
+            file,
+            min_size,
+            force,
+        } => {
+            let mbt = new_mbtiles(&file)?;
+            let _lock = mbt.lock_for_writing(force)?;
+            let mut conn = mbt.open().await?;
+            let mbt_type = mbt.detect_type(&mut conn).await?;
+            mbt.externalize(&mut conn, mbt_type, min_size).await?;
+        }
         Commands::Validate {
             file,
             integrity_check,
             update_agg_tiles_hash,
             agg_hash,
+            force,
         } => {
             if update_agg_tiles_hash && agg_hash.is_some() {
                 anyhow::bail!("Cannot use both --agg-hash and --update-agg-tiles-hash");
@@ -128,22 +199,70 @@ async fn main_int() -> anyhow::Result<()> {
                     AggHashType::default()
                 }
             });
-            let mbt = Mbtiles::new(file.as_path())?;
+            let mbt = new_mbtiles(file.as_path())?;
+            let _lock = if agg_hash == AggHashType::Update {
+                Some(mbt.lock_for_writing(force)?)
+            } else {
+                None
+            };
             mbt.validate(integrity_check, agg_hash).await?;
         }
-        Commands::Summary { file } => {
-            let mbt = Mbtiles::new(file.as_path())?;
+        Commands::Summary {
+            file,
+            output,
+            validate,
+            integrity_check,
+        } => {
+            let mbt = new_mbtiles(file.as_path())?;
             let mut conn = mbt.open_readonly().await?;
-            println!("MBTiles file summary for {mbt}");
-            println!("{}", mbt.summary(&mut conn).await?);
+            let mut summary = mbt.summary(&mut conn).await?;
+            if validate {
+                summary.validation = Some(mbt.validation_status(&mut conn, integrity_check).await?);
+            }
+            match output {
+                SummaryOutput::Text => {
+                    println!("MBTiles file summary for {mbt}");
+                    println!("{summary}");
+                }
+                SummaryOutput::Json => println!("{}", serde_json::to_string_pretty(&summary)?),
+            }
+            if summary.validation.map_or(false, |v| !v.is_valid) {
+                anyhow::bail!("Validation failed for {mbt}");
+            }
         }
     }
 
     Ok(())
 }
 
-async fn meta_print_all(file: &Path) -> anyhow::Result<()> {
+/// Create the `Mbtiles` of the file, using the passphrase from the environment if built with `SQLCipher`
+fn new_mbtiles(file: &Path) -> MbtResult<Mbtiles> {
     let mbt = Mbtiles::new(file)?;
+    #[cfg(feature = "sqlcipher")]
+    let mbt = mbt.with_passphrase(mbtiles::Passphrase::from_env(mbtiles::PASSPHRASE_ENV)?);
+    Ok(mbt)
+}
+
+/// Set the passphrases of the copied files from the environment if built with `SQLCipher`
+#[cfg_attr(not(feature = "sqlcipher"), allow(clippy::unnecessary_wraps))]
+fn with_env_passphrases(opts: MbtilesCopier) -> MbtResult<MbtilesCopier> {
+    #[cfg(feature = "sqlcipher")]
+    let opts = {
+        use mbtiles::{Passphrase, DST_PASSPHRASE_ENV, PASSPHRASE_ENV};
+        let src_passphrase = Passphrase::from_env(PASSPHRASE_ENV)?;
+        let dst_passphrase =
+            Passphrase::from_env(DST_PASSPHRASE_ENV)?.or_else(|| src_passphrase.clone());
+        MbtilesCopier {
+            src_passphrase,
+            dst_passphrase,
+            ..opts
+        }
+    };
+    Ok(opts)
+}
+
+async fn meta_print_all(file: &Path) -> anyhow::Result<()> {
+    let mbt = new_mbtiles(file)?;
     let mut conn = mbt.open_readonly().await?;
     let metadata = mbt.get_metadata(&mut conn).await?;
     println!("{}", serde_yaml::to_string(&metadata)?);
@@ -151,7 +270,7 @@ async fn meta_print_all(file: &Path) -> anyhow::Result<()> {
 }
 
 async fn meta_get_value(file: &Path, key: &str) -> MbtResult<()> {
-    let mbt = Mbtiles::new(file)?;
+    let mbt = new_mbtiles(file)?;
     let mut conn = mbt.open_readonly().await?;
     if let Some(s) = mbt.get_metadata_value(&mut conn, key).await? {
         println!("{s}");
@@ -159,8 +278,9 @@ async fn meta_get_value(file: &Path, key: &str) -> MbtResult<()> {
     Ok(())
 }
 
-async fn meta_set_value(file: &Path, key: &str, value: Option<&str>) -> MbtResult<()> {
-    let mbt = Mbtiles::new(file)?;
+async fn meta_set_value(file: &Path, key: &str, value: Option<&str>, force: bool) -> MbtResult<()> {
+    let mbt = new_mbtiles(file)?;
+    let _lock = mbt.lock_for_writing(force)?;
     let mut conn = mbt.open().await?;
     if let Some(value) = value {
         mbt.set_metadata_value(&mut conn, key, value).await
@@ -178,7 +298,7 @@ mod tests {
     use mbtiles::{CopyDuplicateMode, MbtilesCopier};
 
     use super::*;
-    use crate::Commands::{ApplyPatch, Copy, MetaGetValue, MetaSetValue, Validate};
+    use crate::Commands::{ApplyPatch, Copy, MetaGetValue, MetaSetValue, Summary, Validate};
     use crate::{Args, IntegrityCheckType};
 
     #[test]
@@ -411,7 +531,8 @@ mod tests {
                 command: MetaSetValue {
                     file: PathBuf::from("src_file"),
                     key: "key".to_string(),
-                    value: None
+                    value: None,
+                    force: false,
                 }
             }
         );
@@ -426,7 +547,8 @@ mod tests {
                 command: MetaSetValue {
                     file: PathBuf::from("src_file"),
                     key: "key".to_string(),
-                    value: Some("value".to_string())
+                    value: Some("value".to_string()),
+                    force: false,
                 }
             }
         );
@@ -435,12 +557,14 @@ mod tests {
     #[test]
     fn test_apply_diff_with_arguments() {
         assert_eq!(
-            Args::parse_from(["mbtiles", "apply-diff", "src_file", "diff_file"]),
+            Args::parse_from(["mbtiles", "apply-diff", "src_file", "diff_file", "--force"]),
             Args {
                 verbose: false,
                 command: ApplyPatch {
                     src_file: PathBuf::from("src_file"),
                     diff_file: PathBuf::from("diff_file"),
+                    backup: None,
+                    force: true,
                 }
             }
         );
@@ -457,8 +581,28 @@ mod tests {
                     integrity_check: IntegrityCheckType::Quick,
                     update_agg_tiles_hash: false,
                     agg_hash: Some(AggHashType::Off),
+                    force: false,
                 }
             }
         );
     }
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(
+            Args::parse_from(["mbtiles", "summary", "src_file", "--output", "json"]),
+            Args {
+                verbose: false,
+                command: Summary {
+                    file: PathBuf::from("src_file"),
+                    output: SummaryOutput::Json,
+                    validate: false,
+                    integrity_check: IntegrityCheckType::Quick,
+                }
+            }
+        );
+        assert!(
+            Args::try_parse_from(["mbtiles", "summary", "f", "--integrity-check", "full"]).is_err()
+        );
+    }
 }
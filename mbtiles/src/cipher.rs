@@ -0,0 +1,121 @@
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::path::Path;
+
+use sqlx::sqlite::SqliteConnectOptions;
+
+use crate::errors::{MbtError, MbtResult};
+use crate::Mbtiles;
+
+/// Passphrase of the files read or modified by the `mbtiles` tool
+pub const PASSPHRASE_ENV: &str = "MBTILES_PASSPHRASE";
+/// Passphrase of the destination file of `mbtiles copy`, if different from [`PASSPHRASE_ENV`]
+pub const DST_PASSPHRASE_ENV: &str = "MBTILES_DST_PASSPHRASE";
+
+/// Passphrase of an encrypted `SQLCipher` database. An empty passphrase is used for unencrypted files.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Passphrase(String);
+
+impl Debug for Passphrase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Passphrase(***)")
+    }
+}
+
+impl Passphrase {
+    #[must_use]
+    pub fn new(passphrase: impl Into<String>) -> Self {
+        Self(passphrase.into())
+    }
+
+    /// Read the passphrase from the first line of a file, e.g. a mounted secret
+    pub fn from_file(path: &Path) -> MbtResult<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| MbtError::PassphraseFileError(path.to_path_buf(), e))?;
+        Ok(Self::new(text.lines().next().unwrap_or_default()))
+    }
+
+    /// Get the passphrase from the environment variable, or from the file set by the same variable with a `_FILE` suffix
+    pub fn from_env(var: &str) -> MbtResult<Option<Self>> {
+        if let Ok(value) = std::env::var(var) {
+            return Ok(Some(Self::new(value)));
+        }
+        match std::env::var_os(format!("{var}_FILE")) {
+            Some(path) => Self::from_file(Path::new(&path)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The passphrase as an SQL string literal, for the `key` pragma and the `ATTACH ... KEY` statement
+    pub(crate) fn to_sql(&self) -> String {
+        format!("'{}'", self.0.replace('\'', "''"))
+    }
+}
+
+impl Mbtiles {
+    /// Open the file with this passphrase, and encrypt it if the file is created.
+    /// Unencrypted files must be opened without a passphrase, or with an empty one.
+    #[must_use]
+    pub fn with_passphrase(mut self, passphrase: Option<Passphrase>) -> Self {
+        self.passphrase = passphrase;
+        self
+    }
+
+    pub(crate) fn apply_passphrase(&self, opt: SqliteConnectOptions) -> SqliteConnectOptions {
+        match &self.passphrase {
+            Some(passphrase) => opt.pragma("key", passphrase.to_sql()),
+            None => opt,
+        }
+    }
+
+    /// Value of the `KEY` clause when attaching this file to a connection.
+    /// Without it, `SQLCipher` would use the key of the main database.
+    pub(crate) fn attach_key(&self) -> String {
+        self.passphrase
+            .as_ref()
+            .map_or_else(|| "''".to_string(), Passphrase::to_sql)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn encrypted_file() -> MbtResult<()> {
+        let dir = std::env::temp_dir().join(format!("mbtiles-cipher-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("encrypted.mbtiles");
+        let _ = fs::remove_file(&path);
+        let passphrase = Some(Passphrase::new("it's secret"));
+        assert_eq!(format!("{passphrase:?}"), "Some(Passphrase(***))");
+
+        let mbt = Mbtiles::new(&path)?.with_passphrase(passphrase.clone());
+        let mut conn = mbt.open_or_new().await?;
+        crate::init_mbtiles_schema(&mut conn, crate::MbtType::Flat).await?;
+        mbt.set_metadata_value(&mut conn, "name", "licensed")
+            .await?;
+        drop(conn);
+        let content = fs::read(&path).unwrap();
+        assert!(!content.starts_with(b"SQLite format 3"));
+
+        let mut conn = mbt.open_readonly().await?;
+        let name = mbt.get_metadata_value(&mut conn, "name").await?;
+        assert_eq!(name.as_deref(), Some("licensed"));
+
+        // The key is only checked when the file is read
+        for passphrase in [None, Some(Passphrase::new("wrong"))] {
+            let wrong = Mbtiles::new(&path)?.with_passphrase(passphrase);
+            let mut conn = wrong.open_readonly().await?;
+            assert!(wrong.get_metadata_value(&mut conn, "name").await.is_err());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+}
@@ -7,7 +7,7 @@ use enum_display::EnumDisplay;
 use log::{debug, info};
 use serde::{Deserialize, Serialize};
 use sqlite_hashes::rusqlite::{params_from_iter, Connection};
-use sqlx::{query, Executor as _, Row, SqliteConnection};
+use sqlx::{query, Connection as _, Executor as _, Row, SqliteConnection};
 
 use crate::errors::MbtResult;
 use crate::queries::{
@@ -85,6 +85,20 @@ pub struct MbtilesCopier {
     /// Skip generating a global hash for mbtiles validation. By default, `mbtiles` will compute `agg_tiles_hash` metadata value.
     #[cfg_attr(feature = "cli", arg(long))]
     pub skip_agg_tiles_hash: bool,
+    /// Write to the destination file even if it is locked by another process, e.g. after that process crashed.
+    #[cfg_attr(feature = "cli", arg(long))]
+    pub force: bool,
+    /// Before modifying an existing destination file, save a copy of it to this new file.
+    #[cfg_attr(feature = "cli", arg(long, value_name = "FILE"))]
+    pub backup: Option<PathBuf>,
+    /// Passphrase of the source file, also used for the diff and patch files
+    #[cfg(feature = "sqlcipher")]
+    #[cfg_attr(feature = "cli", arg(skip))]
+    pub src_passphrase: Option<crate::Passphrase>,
+    /// Passphrase of the destination file, which is encrypted with it when created
+    #[cfg(feature = "sqlcipher")]
+    #[cfg_attr(feature = "cli", arg(skip))]
+    pub dst_passphrase: Option<crate::Passphrase>,
 }
 
 #[derive(Clone, Debug)]
@@ -109,6 +123,12 @@ impl MbtilesCopier {
             diff_with_file: None,
             apply_patch: None,
             skip_agg_tiles_hash: false,
+            force: false,
+            backup: None,
+            #[cfg(feature = "sqlcipher")]
+            src_passphrase: None,
+            #[cfg(feature = "sqlcipher")]
+            dst_passphrase: None,
         }
     }
 
@@ -147,9 +167,16 @@ impl MbtileCopierInt {
             }
         }
 
+        let src_mbtiles = Mbtiles::new(&options.src_file)?;
+        let dst_mbtiles = Mbtiles::new(&options.dst_file)?;
+        #[cfg(feature = "sqlcipher")]
+        let src_mbtiles = src_mbtiles.with_passphrase(options.src_passphrase.clone());
+        #[cfg(feature = "sqlcipher")]
+        let dst_mbtiles = dst_mbtiles.with_passphrase(options.dst_passphrase.clone());
+
         Ok(MbtileCopierInt {
-            src_mbtiles: Mbtiles::new(&options.src_file)?,
-            dst_mbtiles: Mbtiles::new(&options.dst_file)?,
+            src_mbtiles,
+            dst_mbtiles,
             options,
         })
     }
@@ -158,6 +185,8 @@ impl MbtileCopierInt {
         let dif = match (&self.options.diff_with_file, &self.options.apply_patch) {
             (Some(dif_file), None) | (None, Some(dif_file)) => {
                 let dif_mbt = Mbtiles::new(dif_file)?;
+                #[cfg(feature = "sqlcipher")]
+                let dif_mbt = dif_mbt.with_passphrase(self.options.src_passphrase.clone());
                 let dif_type = dif_mbt.open_and_detect_type().await?;
                 Some((dif_mbt, dif_type, dif_type))
             }
@@ -169,7 +198,22 @@ impl MbtileCopierInt {
         let src_mbt = &self.src_mbtiles;
         let dst_mbt = &self.dst_mbtiles;
 
+        let _lock = dst_mbt.lock_for_writing(self.options.force)?;
         let src_type = src_mbt.open_and_detect_type().await?;
+        let mut src_conn = src_mbt.open_readonly().await?;
+        if src_mbt
+            .get_external_min_size(&mut src_conn)
+            .await?
+            .is_some()
+        {
+            return Err(MbtError::UnsupportedCopyOperation {
+                reason: format!(
+                    "{src_mbt} stores some tiles in {}, copy the file together with that directory instead",
+                    src_mbt.external_dir().display()
+                ),
+            });
+        }
+        drop(src_conn);
         let mut conn = dst_mbt.open_or_new().await?;
         let is_empty_db = is_empty_database(&mut conn).await?;
         src_mbt.attach_to(&mut conn, "sourceDb").await?;
@@ -193,6 +237,9 @@ impl MbtileCopierInt {
         } else {
             dst_type = self.validate_dst_type(dst_mbt.detect_type(&mut conn).await?)?;
             info!("Copying {src_mbt} ({src_type}) to an existing file {dst_mbt} ({dst_type})");
+            if let Some(backup) = &self.options.backup {
+                dst_mbt.backup_to(&mut conn, backup).await?;
+            }
         }
 
         if is_empty_db {
@@ -216,10 +263,13 @@ impl MbtileCopierInt {
 
         debug!("Copying tiles with 'INSERT {on_dupl}' {src_type} -> {dst_type} ({sql_cond})");
 
+        // Tiles and metadata are copied in a single transaction, so that a failed or interrupted copy
+        // is rolled back by SQLite instead of leaving an existing destination file partially modified
+        let mut tx = conn.begin().await?;
         {
             // SAFETY: This must be scoped to make sure the handle is dropped before we continue using conn
             // Make sure not to execute any other queries while the handle is locked
-            let mut handle_lock = conn.lock_handle().await?;
+            let mut handle_lock = tx.lock_handle().await?;
             let handle = handle_lock.as_raw_handle().as_ptr();
 
             // SAFETY: this is safe as long as handle_lock is valid. We will drop the lock.
@@ -238,8 +288,9 @@ impl MbtileCopierInt {
         }
 
         if !self.options.skip_agg_tiles_hash {
-            dst_mbt.update_agg_tiles_hash(&mut conn).await?;
+            dst_mbt.update_agg_tiles_hash(&mut *tx).await?;
         }
+        tx.commit().await?;
 
         detach_db(&mut conn, "sourceDb").await?;
         // Ignore error because we might not have attached diffDb
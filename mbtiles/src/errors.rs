@@ -68,6 +68,25 @@ pub enum MbtError {
 
     #[error("The MBTiles file {0} has data of type {1}, but the desired type was set to {2}")]
     MismatchedTargetType(PathBuf, MbtType, MbtType),
+
+    #[error("Unable to parse metadata value {1}={2:?} in MBTiles file {0}: {3}")]
+    InvalidMetadataValue(String, String, String, String),
+
+    #[error("Invalid metadata in MBTiles file {0}:\n    {}", .1.join("\n    "))]
+    InvalidMetadata(String, Vec<String>),
+
+    #[error("Another process is writing to this MBTiles file: {1}\n    If that process is no longer running, delete the lock file {} or use --force.", .0.display())]
+    FileLocked(PathBuf, String),
+
+    #[error("Unable to create the lock file {}: {1}", .0.display())]
+    LockFileError(PathBuf, std::io::Error),
+
+    #[error("Unable to access the external tile {}: {1}", .0.display())]
+    ExternalTileError(PathBuf, std::io::Error),
+
+    #[cfg(feature = "sqlcipher")]
+    #[error("Unable to read the passphrase from {}: {1}", .0.display())]
+    PassphraseFileError(PathBuf, std::io::Error),
 }
 
 pub type MbtResult<T> = Result<T, MbtError>;
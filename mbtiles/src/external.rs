@@ -0,0 +1,262 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use log::{debug, info};
+use sqlx::{query, query_as, query_scalar, Connection as _, SqliteConnection, SqliteExecutor};
+
+use crate::errors::{MbtError, MbtResult};
+use crate::{calc_tile_hash, invert_y_value, MbtType, Mbtiles};
+
+/// Metadata key with the minimum size of the tiles stored outside of the `SQLite` file, in bytes
+pub const EXTERNAL_TILES_MIN_SIZE: &str = "external_tiles_min_size";
+
+/// Stored instead of the data of an external tile, followed by the upper-case MD5 hash of the data
+const EXTERNAL_PREFIX: &[u8] = b"mbtiles:external:md5:";
+/// Length of the value stored for an external tile, used to only fetch the values that may be references
+pub(crate) const EXTERNAL_REF_LEN: usize = EXTERNAL_PREFIX.len() + 32;
+
+/// MD5 hash of the tile data if the stored value is a reference to an external tile
+pub(crate) fn external_hash(stored: &[u8]) -> Option<&str> {
+    let hash = stored.strip_prefix(EXTERNAL_PREFIX)?;
+    if hash.len() == 32 && hash.iter().all(u8::is_ascii_hexdigit) {
+        std::str::from_utf8(hash).ok()
+    } else {
+        None
+    }
+}
+
+impl Mbtiles {
+    /// Directory with the content-addressed tiles stored outside of the `SQLite` file, next to it
+    #[must_use]
+    pub fn external_dir(&self) -> PathBuf {
+        PathBuf::from(format!("{}.blobs", self.filepath()))
+    }
+
+    fn external_path(&self, hash: &str) -> PathBuf {
+        self.external_dir().join(&hash[..2]).join(hash)
+    }
+
+    /// Minimum size of the tiles stored outside of the `SQLite` file, or `None` if all tiles are stored in the file
+    pub async fn get_external_min_size<T>(&self, conn: &mut T) -> MbtResult<Option<usize>>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        self.get_metadata_typed(conn, EXTERNAL_TILES_MIN_SIZE).await
+    }
+
+    /// Return the data of the tile, reading it from the external directory if the stored value is a reference
+    pub fn resolve_tile_data(&self, stored: Vec<u8>) -> MbtResult<Vec<u8>> {
+        let Some(hash) = external_hash(&stored) else {
+            return Ok(stored);
+        };
+        let path = self.external_path(hash);
+        fs::read(&path).map_err(|e| MbtError::ExternalTileError(path, e))
+    }
+
+    /// Write the tile data to the external directory unless it is already there, and return the reference to store
+    pub(crate) async fn store_external<T>(&self, conn: &mut T, data: &[u8]) -> MbtResult<Vec<u8>>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        let hash = calc_tile_hash(conn, data).await?;
+        let path = self.external_path(&hash);
+        let io_err = |e| MbtError::ExternalTileError(path.clone(), e);
+        if !path.exists() {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).map_err(io_err)?;
+            }
+            // Readers never see a partially written tile, even if several processes store the same one
+            let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+            fs::write(&tmp, data).map_err(|e| MbtError::ExternalTileError(tmp.clone(), e))?;
+            match fs::rename(&tmp, &path) {
+                Err(e) if e.kind() != ErrorKind::AlreadyExists => Err(io_err(e))?,
+                _ => {}
+            }
+        }
+        let mut reference = EXTERNAL_PREFIX.to_vec();
+        reference.extend_from_slice(hash.as_bytes());
+        Ok(reference)
+    }
+
+    /// Store the tiles of at least `min_size` bytes outside of the `SQLite` file from now on,
+    /// and move the existing ones. Returns the number of tile blobs that were moved.
+    /// The stored hashes and the `agg_tiles_hash` are those of the stored references,
+    /// which are the same for the same data, so that the files can still be compared and validated.
+    pub async fn externalize(
+        &self,
+        conn: &mut SqliteConnection,
+        mbt_type: MbtType,
+        min_size: usize,
+    ) -> MbtResult<u64> {
+        let mut tx = conn.begin().await?;
+        self.set_metadata_value(&mut *tx, EXTERNAL_TILES_MIN_SIZE, min_size)
+            .await?;
+        let min_size = i64::try_from(min_size).unwrap_or(i64::MAX);
+        let mut moved = 0;
+        if let MbtType::Normalized { .. } = mbt_type {
+            let ids: Vec<String> =
+                query_scalar("SELECT tile_id FROM images WHERE length(tile_data) >= ?")
+                    .bind(min_size)
+                    .fetch_all(&mut *tx)
+                    .await?;
+            for old_id in ids {
+                let data: Vec<u8> = query_scalar("SELECT tile_data FROM images WHERE tile_id = ?")
+                    .bind(&old_id)
+                    .fetch_one(&mut *tx)
+                    .await?;
+                if external_hash(&data).is_some() {
+                    continue;
+                }
+                let reference = self.store_external(&mut *tx, &data).await?;
+                let new_id = calc_tile_hash(&mut *tx, &reference).await?;
+                query("INSERT OR IGNORE INTO images (tile_id, tile_data) VALUES (?, ?)")
+                    .bind(&new_id)
+                    .bind(&reference)
+                    .execute(&mut *tx)
+                    .await?;
+                query("UPDATE map SET tile_id = ? WHERE tile_id = ?")
+                    .bind(&new_id)
+                    .bind(&old_id)
+                    .execute(&mut *tx)
+                    .await?;
+                query("DELETE FROM images WHERE tile_id = ?")
+                    .bind(&old_id)
+                    .execute(&mut *tx)
+                    .await?;
+                moved += 1;
+            }
+        } else {
+            let (table, hash) = if mbt_type == MbtType::FlatWithHash {
+                ("tiles_with_hash", ", tile_hash = md5_hex(?1)")
+            } else {
+                ("tiles", "")
+            };
+            let coords: Vec<(u8, u32, u32)> = query_as(&format!(
+                "SELECT zoom_level, tile_column, tile_row FROM {table} WHERE length(tile_data) >= ?"
+            ))
+            .bind(min_size)
+            .fetch_all(&mut *tx)
+            .await?;
+            let select = format!(
+                "SELECT tile_data FROM {table} WHERE zoom_level = ? AND tile_column = ? AND tile_row = ?"
+            );
+            let update = format!(
+                "UPDATE {table} SET tile_data = ?1{hash} WHERE zoom_level = ?2 AND tile_column = ?3 AND tile_row = ?4"
+            );
+            for (z, x, y) in coords {
+                let data: Vec<u8> = query_scalar(&select)
+                    .bind(z)
+                    .bind(x)
+                    .bind(y)
+                    .fetch_one(&mut *tx)
+                    .await?;
+                if external_hash(&data).is_some() {
+                    continue;
+                }
+                let reference = self.store_external(&mut *tx, &data).await?;
+                debug!(
+                    "Moving tile {z}/{x}/{} to the external directory",
+                    invert_y_value(z, y)
+                );
+                query(&update)
+                    .bind(&reference)
+                    .bind(z)
+                    .bind(x)
+                    .bind(y)
+                    .execute(&mut *tx)
+                    .await?;
+                moved += 1;
+            }
+        }
+        if self.get_agg_tiles_hash(&mut *tx).await?.is_some() {
+            self.update_agg_tiles_hash(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        info!(
+            "Moved {moved} tiles of {self} to {}",
+            self.external_dir().display()
+        );
+        Ok(moved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_flat_with_hash_tables, create_metadata_table, CopyDuplicateMode};
+
+    #[test]
+    fn external_references() {
+        let hash = "0123456789ABCDEF0123456789ABCDEF";
+        let reference = [EXTERNAL_PREFIX, hash.as_bytes()].concat();
+        assert_eq!(reference.len(), EXTERNAL_REF_LEN);
+        assert_eq!(external_hash(&reference), Some(hash));
+        assert_eq!(external_hash(&reference[..EXTERNAL_REF_LEN - 1]), None);
+        assert_eq!(external_hash(b"\x1f\x8b\x08\x00"), None);
+        let mut invalid = reference.clone();
+        invalid[EXTERNAL_PREFIX.len()] = b'x';
+        assert_eq!(external_hash(&invalid), None);
+    }
+
+    #[actix_rt::test]
+    async fn externalize_tiles() -> MbtResult<()> {
+        let dir = std::env::temp_dir().join(format!("mbtiles-external-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("external.mbtiles");
+        let _ = fs::remove_file(&path);
+        let mbt = Mbtiles::new(&path)?;
+        let mut conn = mbt.open_or_new().await?;
+        let _ = fs::remove_dir_all(mbt.external_dir());
+        create_metadata_table(&mut conn).await?;
+        create_flat_with_hash_tables(&mut conn).await?;
+        let batch = vec![
+            (0, 0, 0, vec![1_u8; 100]),
+            (1, 0, 0, vec![2_u8; 10]),
+            (1, 1, 0, vec![1_u8; 100]),
+        ];
+        mbt.insert_tiles(
+            &mut conn,
+            MbtType::FlatWithHash,
+            CopyDuplicateMode::Override,
+            &batch,
+        )
+        .await?;
+
+        assert_eq!(
+            mbt.externalize(&mut conn, MbtType::FlatWithHash, 50)
+                .await?,
+            2
+        );
+        assert_eq!(mbt.get_external_min_size(&mut conn).await?, Some(50));
+        // Both large tiles have the same content, so it is stored once
+        let blobs = fs::read_dir(mbt.external_dir()).unwrap().count();
+        assert_eq!(blobs, 1);
+        assert_eq!(mbt.get_tile(&mut conn, 0, 0, 0).await?, Some(vec![1; 100]));
+        assert_eq!(mbt.get_tile(&mut conn, 1, 0, 0).await?, Some(vec![2; 10]));
+        mbt.check_each_tile_hash(&mut conn).await?;
+
+        // New tiles are stored externally too, and their hash is that of their data
+        mbt.insert_tiles(
+            &mut conn,
+            MbtType::FlatWithHash,
+            CopyDuplicateMode::Override,
+            &[(1, 0, 1, vec![3_u8; 60])],
+        )
+        .await?;
+        assert_eq!(mbt.get_tile(&mut conn, 1, 0, 1).await?, Some(vec![3; 60]));
+        let hash = calc_tile_hash(&mut conn, &[3; 60]).await?;
+        let stored = mbt
+            .get_tile_hash(&mut conn, MbtType::FlatWithHash, 1, 0, 1)
+            .await?;
+        assert_eq!(stored, Some(hash));
+        assert_eq!(
+            mbt.externalize(&mut conn, MbtType::FlatWithHash, 50)
+                .await?,
+            0
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+}
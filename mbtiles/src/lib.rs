@@ -3,12 +3,23 @@
 // Re-export sqlx
 pub use sqlx;
 
+#[cfg(feature = "sqlcipher")]
+mod cipher;
+#[cfg(feature = "sqlcipher")]
+pub use cipher::{Passphrase, DST_PASSPHRASE_ENV, PASSPHRASE_ENV};
+
 mod copier;
 pub use copier::{CopyDuplicateMode, MbtilesCopier};
 
 mod errors;
 pub use errors::{MbtError, MbtResult};
 
+mod external;
+pub use external::EXTERNAL_TILES_MIN_SIZE;
+
+mod lock;
+pub use lock::WriteLock;
+
 mod mbtiles;
 pub use mbtiles::{MbtTypeCli, Mbtiles};
 
@@ -25,10 +36,11 @@ mod queries;
 pub use queries::*;
 
 mod summary;
+pub use summary::{Summary, ValidationStatus, ZoomInfo};
 
 mod validation;
 pub use validation::{
-    calc_agg_tiles_hash, AggHashType, IntegrityCheckType, MbtType, AGG_TILES_HASH,
+    calc_agg_tiles_hash, calc_tile_hash, AggHashType, IntegrityCheckType, MbtType, AGG_TILES_HASH,
     AGG_TILES_HASH_IN_DIFF,
 };
 
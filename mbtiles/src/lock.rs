@@ -0,0 +1,112 @@
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write as _};
+use std::path::PathBuf;
+
+use log::{debug, warn};
+
+use crate::errors::{MbtError, MbtResult};
+use crate::Mbtiles;
+
+/// Advisory lock preventing several processes from writing to the same `MBTiles` file at the same time.
+/// The lock is a `<file>.lock` file next to the `MBTiles` file, created when the lock is acquired,
+/// and removed when the [`WriteLock`] is dropped.
+/// A process that is killed may leave a stale lock behind, which can be taken over with `force`.
+#[derive(Debug)]
+#[must_use = "the lock is released as soon as it is dropped"]
+pub struct WriteLock {
+    path: Option<PathBuf>,
+}
+
+impl WriteLock {
+    /// Path of the lock file, or `None` for in-memory databases and other non-file `SQLite` URIs
+    #[must_use]
+    pub fn path(&self) -> Option<&PathBuf> {
+        self.path.as_ref()
+    }
+}
+
+impl Drop for WriteLock {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Unable to remove lock file {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+impl Mbtiles {
+    /// Acquire the advisory write lock of this file, failing with [`MbtError::FileLocked`]
+    /// if another process holds it. With `force`, the existing lock is taken over instead.
+    pub fn lock_for_writing(&self, force: bool) -> MbtResult<WriteLock> {
+        let filepath = self.filepath();
+        if filepath.starts_with("file:") || filepath == ":memory:" || filepath.is_empty() {
+            return Ok(WriteLock { path: None });
+        }
+        let path = PathBuf::from(format!("{filepath}.lock"));
+        let owner = format!(
+            "{} (pid {})",
+            std::env::args().next().unwrap_or_default(),
+            std::process::id()
+        );
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                let holder = fs::read_to_string(&path).unwrap_or_default();
+                let holder = match holder.trim() {
+                    "" => "unknown process".to_string(),
+                    v => v.to_string(),
+                };
+                if !force {
+                    return Err(MbtError::FileLocked(path, holder));
+                }
+                warn!("Taking over the lock of {self} held by {holder} because of --force");
+                OpenOptions::new()
+                    .write(true)
+                    .truncate(true)
+                    .open(&path)
+                    .map_err(|e| MbtError::LockFileError(path.clone(), e))?
+            }
+            Err(e) => return Err(MbtError::LockFileError(path, e)),
+        };
+        file.write_all(owner.as_bytes())
+            .map_err(|e| MbtError::LockFileError(path.clone(), e))?;
+        debug!("Locked {self} for writing with {}", path.display());
+        Ok(WriteLock { path: Some(path) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_lock() {
+        let dir = std::env::temp_dir().join(format!("mbtiles-lock-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mbt = Mbtiles::new(dir.join("locked.mbtiles")).unwrap();
+
+        let lock = mbt.lock_for_writing(false).unwrap();
+        let path = lock.path().unwrap().clone();
+        assert!(fs::read_to_string(&path).unwrap().contains("pid"));
+        assert!(matches!(
+            mbt.lock_for_writing(false),
+            Err(MbtError::FileLocked(..))
+        ));
+        drop(lock);
+        assert!(!path.exists());
+
+        // A stale lock left by a killed process
+        fs::write(&path, "martin-cp (pid 1)").unwrap();
+        let err = mbt.lock_for_writing(false).unwrap_err().to_string();
+        assert!(err.contains("martin-cp (pid 1)"), "{err}");
+        let lock = mbt.lock_for_writing(true).unwrap();
+        assert!(!fs::read_to_string(&path).unwrap().contains("pid 1)"));
+        drop(lock);
+        assert!(!path.exists());
+
+        let mem = Mbtiles::new("file:lock_mem_db?mode=memory&cache=shared").unwrap();
+        assert!(mem.lock_for_writing(false).unwrap().path().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::ffi::OsStr;
 use std::fmt::{Display, Formatter};
 use std::path::Path;
@@ -5,13 +6,17 @@ use std::path::Path;
 #[cfg(feature = "cli")]
 use clap::ValueEnum;
 use enum_display::EnumDisplay;
-use log::debug;
+use futures::TryStreamExt;
+use log::{debug, info};
 use serde::{Deserialize, Serialize};
 use sqlite_hashes::register_md5_function;
 use sqlx::sqlite::SqliteConnectOptions;
-use sqlx::{query, Connection as _, Executor, SqliteConnection, SqliteExecutor, Statement};
+use sqlx::{
+    query, query_as, Connection as _, Executor, SqliteConnection, SqliteExecutor, Statement,
+};
 
 use crate::errors::{MbtError, MbtResult};
+use crate::external::{external_hash, EXTERNAL_REF_LEN};
 use crate::{invert_y_value, CopyDuplicateMode, MbtType};
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize, EnumDisplay)]
@@ -27,6 +32,8 @@ pub enum MbtTypeCli {
 pub struct Mbtiles {
     filepath: String,
     filename: String,
+    #[cfg(feature = "sqlcipher")]
+    pub(crate) passphrase: Option<crate::Passphrase>,
 }
 
 impl Display for Mbtiles {
@@ -48,13 +55,15 @@ impl Mbtiles {
                 .unwrap_or_else(|| OsStr::new("unknown"))
                 .to_string_lossy()
                 .to_string(),
+            #[cfg(feature = "sqlcipher")]
+            passphrase: None,
         })
     }
 
     pub async fn open(&self) -> MbtResult<SqliteConnection> {
         debug!("Opening w/ defaults {self}");
         let opt = SqliteConnectOptions::new().filename(self.filepath());
-        Self::open_int(&opt).await
+        self.open_int(opt).await
     }
 
     pub async fn open_or_new(&self) -> MbtResult<SqliteConnection> {
@@ -62,7 +71,7 @@ impl Mbtiles {
         let opt = SqliteConnectOptions::new()
             .filename(self.filepath())
             .create_if_missing(true);
-        Self::open_int(&opt).await
+        self.open_int(opt).await
     }
 
     pub async fn open_readonly(&self) -> MbtResult<SqliteConnection> {
@@ -70,11 +79,13 @@ impl Mbtiles {
         let opt = SqliteConnectOptions::new()
             .filename(self.filepath())
             .read_only(true);
-        Self::open_int(&opt).await
+        self.open_int(opt).await
     }
 
-    async fn open_int(opt: &SqliteConnectOptions) -> Result<SqliteConnection, MbtError> {
-        let mut conn = SqliteConnection::connect_with(opt).await?;
+    async fn open_int(&self, opt: SqliteConnectOptions) -> Result<SqliteConnection, MbtError> {
+        #[cfg(feature = "sqlcipher")]
+        let opt = self.apply_passphrase(opt);
+        let mut conn = SqliteConnection::connect_with(&opt).await?;
         attach_hash_fn(&mut conn).await?;
         Ok(conn)
     }
@@ -95,8 +106,26 @@ impl Mbtiles {
         for<'e> &'e mut T: SqliteExecutor<'e>,
     {
         debug!("Attaching {self} as {name}");
-        query(&format!("ATTACH DATABASE ? AS {name}"))
-            .bind(self.filepath())
+        #[cfg(not(feature = "sqlcipher"))]
+        let sql = format!("ATTACH DATABASE ? AS {name}");
+        #[cfg(feature = "sqlcipher")]
+        let sql = format!("ATTACH DATABASE ? AS {name} KEY {}", self.attach_key());
+        query(&sql).bind(self.filepath()).execute(conn).await?;
+        Ok(())
+    }
+
+    /// Save a consistent copy of this `MBTiles` file to a new file using `VACUUM INTO`,
+    /// e.g. to keep the original content before modifying the file in place.
+    pub async fn backup_to<T>(&self, conn: &mut T, path: &Path) -> MbtResult<()>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        let backup = path
+            .to_str()
+            .ok_or_else(|| MbtError::UnsupportedCharsInFilepath(path.to_path_buf()))?;
+        info!("Saving a backup of {self} to {backup}");
+        query("VACUUM main INTO ?")
+            .bind(backup)
             .execute(conn)
             .await?;
         Ok(())
@@ -117,7 +146,7 @@ impl Mbtiles {
         let row = query.fetch_optional(conn).await?;
         if let Some(row) = row {
             if let Some(tile_data) = row.tile_data {
-                return Ok(Some(tile_data));
+                return self.resolve_tile_data(tile_data).map(Some);
             }
         }
         Ok(None)
@@ -135,6 +164,20 @@ impl Mbtiles {
             batch.len()
         );
         let mut tx = conn.begin().await?;
+        let mut external = Vec::new();
+        let batch = if let Some(min_size) = self.get_external_min_size(&mut *tx).await? {
+            for (z, x, y, tile_data) in batch {
+                let tile_data = if tile_data.len() >= min_size {
+                    self.store_external(&mut *tx, tile_data).await?
+                } else {
+                    tile_data.clone()
+                };
+                external.push((*z, *x, *y, tile_data));
+            }
+            external.as_slice()
+        } else {
+            batch
+        };
         let (sql1, sql2) = Self::get_insert_sql(mbt_type, on_duplicate);
         if let Some(sql2) = sql2 {
             let sql2 = tx.prepare(&sql2).await?;
@@ -157,6 +200,103 @@ impl Mbtiles {
         Ok(())
     }
 
+    /// Get the MD5 hash of a tile in the same format as the `tiles_with_hash` view, or `None` if there is no such tile.
+    /// The stored hash is used if the file has one. The hash of an external tile is that of its data.
+    pub async fn get_tile_hash(
+        &self,
+        conn: &mut SqliteConnection,
+        mbt_type: MbtType,
+        z: u8,
+        x: u32,
+        y: u32,
+    ) -> MbtResult<Option<String>> {
+        let (hash, table) = match mbt_type {
+            MbtType::FlatWithHash | MbtType::Normalized { hash_view: true } => {
+                ("tile_hash", "tiles_with_hash")
+            }
+            MbtType::Flat | MbtType::Normalized { hash_view: false } => {
+                ("md5_hex(tile_data)", "tiles")
+            }
+        };
+        // Only fetch the values that may be a reference to an external tile
+        let sql = format!(
+            "SELECT {hash}, CASE WHEN length(tile_data) = {EXTERNAL_REF_LEN} THEN tile_data END
+             FROM {table} WHERE zoom_level = ? AND tile_column = ? AND tile_row = ?"
+        );
+        let row = query_as::<_, (Option<String>, Option<Vec<u8>>)>(&sql)
+            .bind(z)
+            .bind(x)
+            .bind(invert_y_value(z, y))
+            .fetch_optional(conn)
+            .await?;
+        Ok(row.and_then(
+            |(hash, stored)| match stored.as_deref().and_then(external_hash) {
+                Some(external) => Some(external.to_string()),
+                None => hash,
+            },
+        ))
+    }
+
+    /// Delete the tiles with the given XYZ coordinates. The data of normalized tiles is kept, even if it is no longer used.
+    pub async fn delete_tiles(
+        &self,
+        conn: &mut SqliteConnection,
+        mbt_type: MbtType,
+        batch: &[(u8, u32, u32)],
+    ) -> MbtResult<()> {
+        debug!("Deleting a batch of {} tiles from {mbt_type}", batch.len());
+        let table = match mbt_type {
+            MbtType::Flat => "tiles",
+            MbtType::FlatWithHash => "tiles_with_hash",
+            MbtType::Normalized { .. } => "map",
+        };
+        let mut tx = conn.begin().await?;
+        let sql = format!(
+            "DELETE FROM {table} WHERE zoom_level = ? AND tile_column = ? AND tile_row = ?"
+        );
+        let sql = tx.prepare(&sql).await?;
+        for (z, x, y) in batch {
+            sql.query()
+                .bind(z)
+                .bind(x)
+                .bind(invert_y_value(*z, *y))
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Get the XYZ coordinates of all tiles of the given zoom levels. Tiles outside of the zoom level grid are ignored.
+    pub async fn get_tile_coords(
+        &self,
+        conn: &mut SqliteConnection,
+        mbt_type: MbtType,
+        zooms: &[u8],
+    ) -> MbtResult<HashSet<(u8, u32, u32)>> {
+        let table = match mbt_type {
+            MbtType::Flat => "tiles",
+            MbtType::FlatWithHash => "tiles_with_hash",
+            MbtType::Normalized { .. } => "map",
+        };
+        let sql = format!(
+            "SELECT tile_column, tile_row FROM {table} WHERE zoom_level = ? AND tile_column >= 0 AND tile_row >= 0"
+        );
+        let mut coords = HashSet::new();
+        for &z in zooms {
+            let max = 1_u64 << z;
+            let mut rows = query_as::<_, (i64, i64)>(&sql).bind(z).fetch(&mut *conn);
+            while let Some((x, y)) = rows.try_next().await? {
+                if let (Ok(x), Ok(y)) = (u32::try_from(x), u32::try_from(y)) {
+                    if u64::from(x) < max && u64::from(y) < max {
+                        coords.insert((z, x, invert_y_value(z, y)));
+                    }
+                }
+            }
+        }
+        Ok(coords)
+    }
+
     fn get_insert_sql(
         src_type: MbtType,
         on_duplicate: CopyDuplicateMode,
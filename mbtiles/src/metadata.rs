@@ -3,15 +3,19 @@ use std::str::FromStr;
 
 use futures::TryStreamExt;
 use log::{info, warn};
-use martin_tile_utils::TileInfo;
+use martin_tile_utils::{Format, TileInfo, MAX_LATITUDE};
 use serde::ser::SerializeStruct;
 use serde::{Serialize, Serializer};
 use serde_json::{json, Value as JSONValue, Value};
 use sqlx::{query, SqliteExecutor};
-use tilejson::{tilejson, Bounds, Center, TileJSON};
+use tilejson::{tilejson, Bounds, Center, TileJSON, VectorLayer};
 
 use crate::errors::MbtResult;
-use crate::Mbtiles;
+use crate::MbtError::{InvalidMetadata, InvalidMetadataValue};
+use crate::{Mbtiles, AGG_TILES_HASH};
+
+/// Maximum zoom level supported by the `MBTiles` and `TileJSON` specifications
+const MAX_ZOOM: u8 = 30;
 
 #[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, PartialEq, Serialize)]
@@ -25,6 +29,81 @@ pub struct Metadata {
     pub agg_tiles_hash: Option<String>,
 }
 
+impl Metadata {
+    /// Check that the bounds, center, zoom levels, and vector layers are consistent with each other
+    /// and with the [MBTiles specification](https://github.com/mapbox/mbtiles-spec/blob/master/1.3/spec.md).
+    pub fn validate(&self) -> MbtResult<()> {
+        let issues = self.validation_issues();
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(InvalidMetadata(self.id.clone(), issues))
+        }
+    }
+
+    /// Same as [`Metadata::validate`], but returns a list of all found problems
+    #[must_use]
+    pub fn validation_issues(&self) -> Vec<String> {
+        let tj = &self.tilejson;
+        let mut issues = Vec::new();
+        if let Some(b) = tj.bounds {
+            let lon = -180.0..=180.0;
+            let lat = -90.0..=90.0;
+            if !lon.contains(&b.left) || !lon.contains(&b.right) {
+                issues.push(format!("bounds {b} longitude must be between -180 and 180"));
+            }
+            if !lat.contains(&b.bottom) || !lat.contains(&b.top) {
+                issues.push(format!("bounds {b} latitude must be between -90 and 90"));
+            }
+            if b.bottom > b.top {
+                issues.push(format!("bounds {b} bottom must not be greater than top"));
+            }
+        }
+        for (name, zoom) in [("minzoom", tj.minzoom), ("maxzoom", tj.maxzoom)] {
+            if let Some(zoom) = zoom.filter(|z| *z > MAX_ZOOM) {
+                issues.push(format!("{name} {zoom} must not exceed {MAX_ZOOM}"));
+            }
+        }
+        if let (Some(min), Some(max)) = (tj.minzoom, tj.maxzoom) {
+            if min > max {
+                issues.push(format!(
+                    "minzoom {min} must not be greater than maxzoom {max}"
+                ));
+            }
+        }
+        if let Some(c) = tj.center {
+            if !(-180.0..=180.0).contains(&c.longitude)
+                || !(-MAX_LATITUDE..=MAX_LATITUDE).contains(&c.latitude)
+            {
+                issues.push(format!("center {c} is outside of the Web Mercator range"));
+            }
+            if let Some(b) = tj.bounds {
+                let inside_lon = if b.left <= b.right {
+                    (b.left..=b.right).contains(&c.longitude)
+                } else {
+                    // Bounds crossing the antimeridian
+                    c.longitude >= b.left || c.longitude <= b.right
+                };
+                if !inside_lon || !(b.bottom..=b.top).contains(&c.latitude) {
+                    issues.push(format!("center {c} is outside of the bounds {b}"));
+                }
+            }
+            if tj.minzoom.map_or(false, |min| c.zoom < min)
+                || tj.maxzoom.map_or(false, |max| c.zoom > max)
+            {
+                issues.push(format!(
+                    "center zoom {} is outside of the zoom range",
+                    c.zoom
+                ));
+            }
+        }
+        if self.tile_info.format == Format::Mvt && tj.vector_layers.is_none() {
+            issues.push("vector tiles must have json.vector_layers".to_string());
+        }
+        issues
+    }
+}
+
 #[allow(clippy::trivially_copy_pass_by_ref)]
 fn serialize_ti<S: Serializer>(ti: &TileInfo, serializer: S) -> Result<S::Ok, S::Error> {
     let mut s = serializer.serialize_struct("TileInfo", 2)?;
@@ -89,6 +168,150 @@ impl Mbtiles {
         Ok(())
     }
 
+    /// Get a single metadata value parsed as the given type.
+    /// Returns an error if the value exists but cannot be parsed.
+    pub async fn get_metadata_typed<T, V>(&self, conn: &mut T, key: &str) -> MbtResult<Option<V>>
+    where
+        V: FromStr,
+        V::Err: Display,
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        match self.get_metadata_value(conn, key).await? {
+            Some(value) if !value.is_empty() => match value.parse() {
+                Ok(v) => Ok(Some(v)),
+                Err(e) => Err(InvalidMetadataValue(
+                    self.filename().to_string(),
+                    key.to_string(),
+                    value,
+                    e.to_string(),
+                )),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    pub async fn get_bounds<T>(&self, conn: &mut T) -> MbtResult<Option<Bounds>>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        self.get_metadata_typed(conn, "bounds").await
+    }
+
+    pub async fn set_bounds<T>(&self, conn: &mut T, bounds: Bounds) -> MbtResult<()>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        self.set_metadata_value(conn, "bounds", bounds).await
+    }
+
+    pub async fn get_center<T>(&self, conn: &mut T) -> MbtResult<Option<Center>>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        self.get_metadata_typed(conn, "center").await
+    }
+
+    pub async fn set_center<T>(&self, conn: &mut T, center: Center) -> MbtResult<()>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        self.set_metadata_value(conn, "center", center).await
+    }
+
+    /// Get the `minzoom` and `maxzoom` metadata values
+    pub async fn get_zooms<T>(&self, conn: &mut T) -> MbtResult<(Option<u8>, Option<u8>)>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        let min = self.get_metadata_typed(&mut *conn, "minzoom").await?;
+        let max = self.get_metadata_typed(&mut *conn, "maxzoom").await?;
+        Ok((min, max))
+    }
+
+    /// Set the `minzoom` and `maxzoom` metadata values
+    pub async fn set_zooms<T>(&self, conn: &mut T, minzoom: u8, maxzoom: u8) -> MbtResult<()>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        self.set_metadata_value(&mut *conn, "minzoom", minzoom)
+            .await?;
+        self.set_metadata_value(&mut *conn, "maxzoom", maxzoom)
+            .await
+    }
+
+    /// Get the `vector_layers` value stored inside of the `json` metadata value
+    pub async fn get_vector_layers<T>(&self, conn: &mut T) -> MbtResult<Option<Vec<VectorLayer>>>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        let Some(json) = self
+            .get_metadata_typed::<_, JSONValue>(conn, "json")
+            .await?
+        else {
+            return Ok(None);
+        };
+        match json.get("vector_layers") {
+            Some(value) => serde_json::from_value(value.clone())
+                .map(Some)
+                .map_err(|e| {
+                    InvalidMetadataValue(
+                        self.filename().to_string(),
+                        "json".to_string(),
+                        value.to_string(),
+                        e.to_string(),
+                    )
+                }),
+            None => Ok(None),
+        }
+    }
+
+    /// Set the `vector_layers` value inside of the `json` metadata value, keeping all other `json` keys
+    pub async fn set_vector_layers<T>(&self, conn: &mut T, layers: &[VectorLayer]) -> MbtResult<()>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        let json = self.get_metadata_typed(&mut *conn, "json").await?;
+        let json = merge_vector_layers(json, Some(layers))?;
+        self.set_metadata_value(conn, "json", serde_json::to_string(&json)?)
+            .await
+    }
+
+    /// Replace the content of the metadata table with the given metadata.
+    /// The metadata is validated first, and the `agg_tiles_hash` value is kept as is.
+    /// Reading the metadata back with [`Mbtiles::get_metadata`] returns the same values.
+    pub async fn update_metadata<T>(&self, conn: &mut T, metadata: &Metadata) -> MbtResult<()>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        metadata.validate()?;
+        query("DELETE FROM metadata WHERE name != ?")
+            .bind(AGG_TILES_HASH)
+            .execute(&mut *conn)
+            .await?;
+
+        let mut tj = metadata.tilejson.clone();
+        let vector_layers = tj.vector_layers.take();
+        if !tj.other.contains_key("format") {
+            let fmt = match metadata.tile_info.format {
+                Format::Mvt => "pbf".to_string(),
+                fmt => fmt.to_string(),
+            };
+            tj.other.insert("format".to_string(), Value::String(fmt));
+        }
+        self.insert_metadata(&mut *conn, &tj).await?;
+
+        if let Some(layer_type) = &metadata.layer_type {
+            self.set_metadata_value(&mut *conn, "type", layer_type)
+                .await?;
+        }
+        let json = merge_vector_layers(metadata.json.clone(), vector_layers.as_deref())?;
+        if !json.as_object().map_or(false, serde_json::Map::is_empty) {
+            self.set_metadata_value(&mut *conn, "json", serde_json::to_string(&json)?)
+                .await?;
+        }
+        Ok(())
+    }
+
     pub async fn get_metadata<T>(&self, conn: &mut T) -> MbtResult<Metadata>
     where
         for<'e> &'e mut T: SqliteExecutor<'e>,
@@ -208,6 +431,21 @@ impl Mbtiles {
     }
 }
 
+/// Store the vector layers in the `vector_layers` key of the `json` metadata value
+fn merge_vector_layers(
+    json: Option<JSONValue>,
+    layers: Option<&[VectorLayer]>,
+) -> MbtResult<JSONValue> {
+    let mut json = match json {
+        Some(JSONValue::Object(obj)) => obj,
+        _ => serde_json::Map::new(),
+    };
+    if let Some(layers) = layers {
+        json.insert("vector_layers".to_string(), serde_json::to_value(layers)?);
+    }
+    Ok(JSONValue::Object(json))
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::BTreeMap;
@@ -218,6 +456,7 @@ mod tests {
 
     use super::*;
     use crate::mbtiles::tests::open;
+    use crate::MbtilesCopier;
 
     #[actix_rt::test]
     async fn mbtiles_meta() -> MbtResult<()> {
@@ -325,4 +564,90 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn metadata_typed() -> MbtResult<()> {
+        let (mut conn, mbt) = open("file:metadata_typed_mem_db?mode=memory&cache=shared").await?;
+        conn.execute("CREATE TABLE metadata (name text NOT NULL PRIMARY KEY, value text);")
+            .await?;
+
+        assert_eq!(mbt.get_bounds(&mut conn).await?, None);
+        let bounds = Bounds::new(-123.5, -37.8, 174.5, 59.25);
+        mbt.set_bounds(&mut conn, bounds).await?;
+        assert_eq!(mbt.get_bounds(&mut conn).await?, Some(bounds));
+
+        let center = Center::new(10.5, 20.25, 3);
+        mbt.set_center(&mut conn, center).await?;
+        assert_eq!(mbt.get_center(&mut conn).await?, Some(center));
+
+        mbt.set_zooms(&mut conn, 2, 7).await?;
+        assert_eq!(mbt.get_zooms(&mut conn).await?, (Some(2), Some(7)));
+
+        mbt.set_metadata_value(&mut conn, "json", r#"{"tilestats":{}}"#)
+            .await?;
+        let layers = vec![VectorLayer::new("a".to_string(), BTreeMap::new())];
+        mbt.set_vector_layers(&mut conn, &layers).await?;
+        assert_eq!(mbt.get_vector_layers(&mut conn).await?, Some(layers));
+        let json = mbt.get_metadata_value(&mut conn, "json").await?.unwrap();
+        assert!(json.contains("tilestats"), "{json}");
+
+        mbt.set_metadata_value(&mut conn, "maxzoom", "abc").await?;
+        assert!(matches!(
+            mbt.get_zooms(&mut conn).await,
+            Err(InvalidMetadataValue(..))
+        ));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn metadata_round_trip() -> MbtResult<()> {
+        let src = "../tests/fixtures/mbtiles/world_cities.mbtiles";
+        let dst = "file:metadata_round_trip_mem_db?mode=memory&cache=shared";
+        let mut conn = MbtilesCopier::new(src.into(), dst.into()).run().await?;
+        let mbt = Mbtiles::new(dst)?;
+
+        let mut metadata = mbt.get_metadata(&mut conn).await?;
+        metadata.tilejson.name = Some("New name".to_string());
+        metadata.tilejson.minzoom = Some(1);
+        metadata.json = Some(json!({ "tilestats": { "layerCount": 1 } }));
+        mbt.update_metadata(&mut conn, &metadata).await?;
+
+        let res = mbt.get_metadata(&mut conn).await?;
+        assert_eq!(res, metadata);
+        assert_eq!(res.tilejson.name.as_deref(), Some("New name"));
+
+        metadata.tilejson.minzoom = Some(10);
+        assert!(matches!(
+            mbt.update_metadata(&mut conn, &metadata).await,
+            Err(InvalidMetadata(..))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_validation() {
+        let mut metadata = Metadata {
+            id: "test".to_string(),
+            tile_info: Format::Png.into(),
+            layer_type: None,
+            tilejson: tilejson! { tiles: vec![] },
+            json: None,
+            agg_tiles_hash: None,
+        };
+        assert!(metadata.validate().is_ok());
+
+        let tj = &mut metadata.tilejson;
+        tj.bounds = Some(Bounds::new(170.0, -10.0, -170.0, 10.0));
+        tj.center = Some(Center::new(175.0, 0.0, 2));
+        tj.minzoom = Some(0);
+        tj.maxzoom = Some(5);
+        assert!(metadata.validate().is_ok());
+
+        let tj = &mut metadata.tilejson;
+        tj.bounds = Some(Bounds::new(-200.0, 10.0, 0.0, -10.0));
+        tj.center = Some(Center::new(10.0, 0.0, 7));
+        tj.maxzoom = Some(31);
+        metadata.tile_info = Format::Mvt.into();
+        assert_eq!(metadata.validation_issues().len(), 5);
+    }
 }
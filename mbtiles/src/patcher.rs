@@ -1,7 +1,7 @@
 use std::path::PathBuf;
 
 use log::{debug, info};
-use sqlx::query;
+use sqlx::{query, Connection as _};
 
 use crate::queries::detach_db;
 use crate::MbtType::{Flat, FlatWithHash, Normalized};
@@ -20,13 +20,17 @@ pub async fn apply_patch(src_file: PathBuf, patch_file: PathBuf) -> MbtResult<()
     let select_from = get_select_from(src_type, patch_type);
     let (main_table, insert1, insert2) = get_insert_sql(src_type, select_from);
 
+    // All changes are made in a single transaction, so that a failed or interrupted run
+    // is rolled back by SQLite instead of leaving a partially patched file
+    let mut tx = conn.begin().await?;
+
     query(&format!("{insert1} WHERE tile_data NOTNULL"))
-        .execute(&mut conn)
+        .execute(&mut *tx)
         .await?;
 
     if let Some(insert2) = insert2 {
         query(&format!("{insert2} WHERE tile_data NOTNULL"))
-            .execute(&mut conn)
+            .execute(&mut *tx)
             .await?;
     }
 
@@ -37,13 +41,13 @@ pub async fn apply_patch(src_file: PathBuf, patch_file: PathBuf) -> MbtResult<()
         SELECT zoom_level, tile_column, tile_row FROM ({select_from} WHERE tile_data ISNULL)
     )"
     ))
-    .execute(&mut conn)
+    .execute(&mut *tx)
     .await?;
 
     if src_type.is_normalized() {
         debug!("Removing unused tiles from the images table (normalized schema)");
         query("DELETE FROM images WHERE tile_id NOT IN (SELECT tile_id FROM map)")
-            .execute(&mut conn)
+            .execute(&mut *tx)
             .await?;
     }
 
@@ -58,7 +62,7 @@ pub async fn apply_patch(src_file: PathBuf, patch_file: PathBuf) -> MbtResult<()
     FROM patchDb.metadata
     WHERE name NOTNULL AND name != '{AGG_TILES_HASH}';"
     ))
-    .execute(&mut conn)
+    .execute(&mut *tx)
     .await?;
 
     query(
@@ -66,9 +70,11 @@ pub async fn apply_patch(src_file: PathBuf, patch_file: PathBuf) -> MbtResult<()
     DELETE FROM metadata
     WHERE name IN (SELECT name FROM patchDb.metadata WHERE value ISNULL);",
     )
-    .execute(&mut conn)
+    .execute(&mut *tx)
     .await?;
 
+    tx.commit().await?;
+
     detach_db(&mut conn, "patchDb").await
 }
 
@@ -196,4 +202,47 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn backup_before_patch() -> MbtResult<()> {
+        let dir = std::env::temp_dir().join(format!("mbtiles-backup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("world_cities.mbtiles");
+        let backup = dir.join("world_cities.bak.mbtiles");
+        let src_file = PathBuf::from("../tests/fixtures/mbtiles/world_cities.mbtiles");
+        MbtilesCopier::new(src_file.clone(), src.clone())
+            .run()
+            .await?;
+
+        let src_mbt = Mbtiles::new(&src)?;
+        src_mbt
+            .backup_to(&mut src_mbt.open().await?, &backup)
+            .await?;
+        // An existing backup is never overwritten
+        assert!(src_mbt
+            .backup_to(&mut src_mbt.open().await?, &backup)
+            .await
+            .is_err());
+
+        let patch_file = PathBuf::from("../tests/fixtures/mbtiles/world_cities_diff.mbtiles");
+        apply_patch(src.clone(), patch_file).await?;
+
+        // The backup keeps the original content
+        let mut conn = Mbtiles::new(&backup)?.open().await?;
+        Mbtiles::new(&src_file)?
+            .attach_to(&mut conn, "testOtherDb")
+            .await?;
+        assert!(conn
+            .fetch_optional("SELECT * FROM tiles EXCEPT SELECT * FROM testOtherDb.tiles;")
+            .await?
+            .is_none());
+        assert!(conn
+            .fetch_optional("SELECT * FROM testOtherDb.tiles EXCEPT SELECT * FROM tiles;")
+            .await?
+            .is_none());
+        drop(conn);
+
+        std::fs::remove_dir_all(dir).unwrap();
+        Ok(())
+    }
 }
@@ -1,5 +1,7 @@
 use std::path::Path;
+use std::str::FromStr as _;
 
+use sqlx::sqlite::SqliteConnectOptions;
 use sqlx::{Pool, Sqlite, SqlitePool};
 
 use crate::errors::MbtResult;
@@ -13,8 +15,15 @@ pub struct MbtilesPool {
 
 impl MbtilesPool {
     pub async fn new<P: AsRef<Path>>(filepath: P) -> MbtResult<Self> {
-        let mbtiles = Mbtiles::new(filepath)?;
-        let pool = SqlitePool::connect(mbtiles.filepath()).await?;
+        Self::open(Mbtiles::new(filepath)?).await
+    }
+
+    /// Create a pool of connections to the file, using its passphrase if it has one
+    pub async fn open(mbtiles: Mbtiles) -> MbtResult<Self> {
+        let opt = SqliteConnectOptions::from_str(mbtiles.filepath())?;
+        #[cfg(feature = "sqlcipher")]
+        let opt = mbtiles.apply_passphrase(opt);
+        let pool = SqlitePool::connect_with(opt).await?;
         Ok(Self { mbtiles, pool })
     }
 
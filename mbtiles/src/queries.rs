@@ -271,7 +271,17 @@ where
     for<'e> &'e mut T: SqliteExecutor<'e>,
 {
     debug!("Resetting PRAGMA settings and vacuuming");
-    query!("PRAGMA page_size = 512").execute(&mut *conn).await?;
+    // SQLCipher pages must keep the size of the cipher pages, which readers expect to be the default
+    #[cfg(feature = "sqlcipher")]
+    let encrypted = query("PRAGMA cipher_provider")
+        .fetch_optional(&mut *conn)
+        .await?
+        .is_some();
+    #[cfg(not(feature = "sqlcipher"))]
+    let encrypted = false;
+    if !encrypted {
+        query!("PRAGMA page_size = 512").execute(&mut *conn).await?;
+    }
     query!("PRAGMA encoding = 'UTF-8'")
         .execute(&mut *conn)
         .await?;
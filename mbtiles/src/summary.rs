@@ -8,13 +8,14 @@ use std::fmt::{Display, Formatter};
 use std::path::PathBuf;
 use std::str::FromStr;
 
-use martin_tile_utils::{EARTH_CIRCUMFERENCE, EARTH_RADIUS};
+use martin_tile_utils::{webmercator_to_wgs84, EARTH_CIRCUMFERENCE};
 use serde::Serialize;
 use size_format::SizeFormatterBinary;
 use sqlx::{query, SqliteExecutor};
 use tilejson::Bounds;
 
-use crate::{MbtResult, MbtType, Mbtiles};
+use crate::MbtError::{AggHashMismatch, AggHashValueNotFound};
+use crate::{calc_agg_tiles_hash, IntegrityCheckType, MbtResult, MbtType, Mbtiles};
 
 #[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct ZoomInfo {
@@ -40,6 +41,20 @@ pub struct Summary {
     pub min_zoom: Option<u8>,
     pub max_zoom: Option<u8>,
     pub zoom_info: Vec<ZoomInfo>,
+    /// The `agg_tiles_hash` value stored in the metadata table
+    pub agg_tiles_hash: Option<String>,
+    /// Validation results, only computed when requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub validation: Option<ValidationStatus>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ValidationStatus {
+    pub is_valid: bool,
+    /// The aggregate tiles hash computed from the tile data
+    pub computed_agg_tiles_hash: String,
+    /// All problems found during the validation
+    pub errors: Vec<String>,
 }
 
 impl Display for Summary {
@@ -55,6 +70,16 @@ impl Display for Summary {
         let page_size = SizeFormatterBinary::new(self.page_size);
         writeln!(f, "Page size: {page_size:.2}B")?;
         writeln!(f, "Page count: {:.2}", self.page_count)?;
+        if let Some(hash) = &self.agg_tiles_hash {
+            writeln!(f, "Agg tiles hash: {hash}")?;
+        }
+        if let Some(v) = &self.validation {
+            let status = if v.is_valid { "passed" } else { "failed" };
+            writeln!(f, "Validation: {status}")?;
+            for err in &v.errors {
+                writeln!(f, "    {err}")?;
+            }
+        }
         writeln!(f)?;
         writeln!(
             f,
@@ -182,6 +207,40 @@ impl Mbtiles {
             min_zoom: zoom_info.iter().map(|l| l.zoom).reduce(u8::min),
             max_zoom: zoom_info.iter().map(|l| l.zoom).reduce(u8::max),
             zoom_info,
+            agg_tiles_hash: self.get_agg_tiles_hash(&mut *conn).await?,
+            validation: None,
+        })
+    }
+
+    /// Same as [`Mbtiles::validate`], but collects all problems instead of stopping at the first one
+    pub async fn validation_status<T>(
+        &self,
+        conn: &mut T,
+        integrity_check: IntegrityCheckType,
+    ) -> MbtResult<ValidationStatus>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        let mut errors = Vec::new();
+        if let Err(e) = self.check_integrity(&mut *conn, integrity_check).await {
+            errors.push(e.to_string());
+        }
+        if let Err(e) = self.check_each_tile_hash(&mut *conn).await {
+            errors.push(e.to_string());
+        }
+        let computed = calc_agg_tiles_hash(&mut *conn).await?;
+        let file = self.filepath().to_string();
+        match self.get_agg_tiles_hash(&mut *conn).await? {
+            None => errors.push(AggHashValueNotFound(file).to_string()),
+            Some(stored) if stored != computed => {
+                errors.push(AggHashMismatch(computed.clone(), stored, file).to_string());
+            }
+            Some(_) => {}
+        }
+        Ok(ValidationStatus {
+            is_valid: errors.is_empty(),
+            computed_agg_tiles_hash: computed,
+            errors,
         })
     }
 }
@@ -211,21 +270,15 @@ fn get_zoom_precision(zoom: u8) -> usize {
     }
 }
 
-fn webmercator_to_wgs84(x: f64, y: f64) -> (f64, f64) {
-    let lng = (x / EARTH_RADIUS).to_degrees();
-    let lat = (f64::atan(f64::sinh(y / EARTH_RADIUS))).to_degrees();
-    (lng, lat)
-}
-
 #[cfg(test)]
 mod tests {
     #![allow(clippy::unreadable_literal)]
 
     use approx::assert_relative_eq;
     use insta::assert_yaml_snapshot;
+    use martin_tile_utils::webmercator_to_wgs84;
 
-    use crate::summary::webmercator_to_wgs84;
-    use crate::{init_mbtiles_schema, MbtResult, MbtType, Mbtiles};
+    use crate::{init_mbtiles_schema, IntegrityCheckType, MbtResult, MbtType, Mbtiles};
 
     #[actix_rt::test]
     async fn meter_to_lng_lat() {
@@ -267,6 +320,7 @@ mod tests {
         min_zoom: ~
         max_zoom: ~
         zoom_info: []
+        agg_tiles_hash: ~
         "###);
 
         Ok(())
@@ -367,8 +421,24 @@ mod tests {
               - -40.97989806962013
               - 180
               - 61.60639637138627
+        agg_tiles_hash: ~
         "###);
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn summary_validation() -> MbtResult<()> {
+        let mbt = Mbtiles::new("../tests/fixtures/mbtiles/world_cities.mbtiles")?;
+        let mut conn = mbt.open_readonly().await?;
+
+        let res = mbt
+            .validation_status(&mut conn, IntegrityCheckType::Quick)
+            .await?;
+        assert!(!res.is_valid);
+        assert_eq!(res.errors.len(), 1, "{:?}", res.errors);
+        assert!(res.errors[0].contains("agg_tiles_hash"));
+        assert_eq!(res.computed_agg_tiles_hash.len(), 32);
+        Ok(())
+    }
 }
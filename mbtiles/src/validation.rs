@@ -8,7 +8,7 @@ use martin_tile_utils::{Format, TileInfo};
 use serde::Serialize;
 use serde_json::Value;
 use sqlx::sqlite::SqliteRow;
-use sqlx::{query, Row, SqliteExecutor};
+use sqlx::{query, query_scalar, Row, SqliteExecutor};
 use tilejson::TileJSON;
 
 use crate::errors::{MbtError, MbtResult};
@@ -181,6 +181,13 @@ impl Mbtiles {
         tile: Option<Vec<u8>>,
     ) -> Option<TileInfo> {
         if let (Some(z), Some(x), Some(y), Some(tile)) = (z, x, y, tile) {
+            let tile = match self.resolve_tile_data(tile) {
+                Ok(tile) => tile,
+                Err(e) => {
+                    warn!("Unable to detect the format of tile {z}/{x}/{y}: {e}");
+                    return None;
+                }
+            };
             let info = TileInfo::detect(&tile);
             if let Some(info) = info {
                 debug!(
@@ -441,6 +448,17 @@ pub(crate) mod tests {
     }
 }
 
+/// Compute the MD5 hash of the tile data, in the same format as the `tiles_with_hash` view
+pub async fn calc_tile_hash<T>(conn: &mut T, tile_data: &[u8]) -> MbtResult<String>
+where
+    for<'e> &'e mut T: SqliteExecutor<'e>,
+{
+    Ok(query_scalar("SELECT md5_hex(?)")
+        .bind(tile_data)
+        .fetch_one(conn)
+        .await?)
+}
+
 /// Compute the hash of the combined tiles in the mbtiles file tiles table/view.
 /// This should work on all mbtiles files perf `MBTiles` specification.
 pub async fn calc_agg_tiles_hash<T>(conn: &mut T) -> MbtResult<String>
@@ -0,0 +1,17 @@
+DROP FUNCTION IF EXISTS public.function_zxy_margin;
+
+CREATE OR REPLACE FUNCTION public.function_zxy_margin(z integer, x integer, y integer, margin double precision) RETURNS bytea AS $$
+DECLARE
+  mvt bytea;
+BEGIN
+  -- Keep the features within the margin, so that the tile includes the neighboring features
+  SELECT INTO mvt ST_AsMVT(tile, 'public.function_zxy_margin', 4096, 'geom') FROM (
+    SELECT
+      ST_AsMVTGeom(ST_Transform(ST_CurveToLine(geom), 3857), ST_TileEnvelope(z, x, y), 4096, (4096 * margin)::integer, true) AS geom
+    FROM public.table_source
+    WHERE geom && ST_Transform(ST_TileEnvelope(z, x, y, margin => margin), 4326)
+  ) as tile WHERE geom IS NOT NULL;
+
+  RETURN mvt;
+END
+$$ LANGUAGE plpgsql IMMUTABLE STRICT PARALLEL SAFE;